@@ -1,5 +1,5 @@
-pub use add_origin::AddOrigin;
 use futures::{Future, Poll};
+pub use simulation::interop::grpc::AddOrigin;
 use simulation::Environment;
 use std::{io, net, pin::Pin, task::Context};
 
@@ -36,55 +36,3 @@ where
         Box::pin(async move { handle.connect(addr).await })
     }
 }
-
-mod add_origin {
-    use http::{Request, Uri};
-    use std::task::{Context, Poll};
-    use tower_service::Service;
-
-    // From tonic/src/transport/service/add_origin.rs
-    #[derive(Debug)]
-    pub struct AddOrigin<T> {
-        inner: T,
-        origin: Uri,
-    }
-
-    impl<T> AddOrigin<T> {
-        pub fn new(inner: T, origin: Uri) -> Self {
-            Self { inner, origin }
-        }
-    }
-
-    impl<T, ReqBody> Service<Request<ReqBody>> for AddOrigin<T>
-    where
-        T: Service<Request<ReqBody>>,
-    {
-        type Response = T::Response;
-        type Error = T::Error;
-        type Future = T::Future;
-
-        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-            self.inner.poll_ready(cx)
-        }
-
-        fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
-            // Split the request into the head and the body.
-            let (mut head, body) = req.into_parts();
-
-            // Split the request URI into parts.
-            let mut uri: http::uri::Parts = head.uri.into();
-            let set_uri = self.origin.clone().into_parts();
-
-            // Update the URI parts, setting http scheme and authority
-            uri.scheme = Some(set_uri.scheme.expect("expected scheme"));
-            uri.authority = Some(set_uri.authority.expect("expected authority"));
-
-            // Update the the request URI
-            head.uri = http::Uri::from_parts(uri).expect("valid uri");
-
-            let request = Request::from_parts(head, body);
-
-            self.inner.call(request)
-        }
-    }
-}
@@ -0,0 +1,79 @@
+use simulation::{deterministic::DeterministicRuntime, Environment, TcpListener};
+use std::{env, net::SocketAddr, time};
+
+type Err = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// Measure the connect and accept throughput of the simulated network while an increasing
+/// number of connections are held open, churning a window of the most recent connections.
+/// Churn throughput should stay flat as the number of open connections grows.
+///
+/// Usage: `cargo run --release --example connection_churn [churned] [open...]`
+fn main() -> Result<(), Err> {
+    let mut args = env::args().skip(1).map(|arg| arg.parse::<usize>());
+    let churned = args.next().unwrap_or(Ok(100_000))?;
+    let mut opens = args.collect::<Result<Vec<_>, _>>()?;
+    if opens.is_empty() {
+        opens = vec![1_000, 10_000, 50_000];
+    }
+
+    let mut rates = vec![];
+    for open in opens.iter().cloned() {
+        let rate = churn(open, churned)?;
+        println!(
+            "churned {} connections alongside {} open connections ({:.0}/s)",
+            churned, open, rate
+        );
+        rates.push(rate);
+    }
+    if let (Some(first), Some(last)) = (rates.first(), rates.last()) {
+        println!(
+            "throughput with {} open connections is {:.2}x that with {}",
+            opens[opens.len() - 1],
+            last / first,
+            opens[0]
+        );
+    }
+    Ok(())
+}
+
+/// Opens `open` connections and holds them while churning `churned` connections, returning
+/// the churned connections per second.
+fn churn(open: usize, churned: usize) -> Result<f64, Err> {
+    let mut runtime = DeterministicRuntime::new_with_seed(1)?;
+    let addr: SocketAddr = "10.0.0.1:9092".parse()?;
+    let server = runtime.handle(addr.ip());
+    let client = runtime.handle("10.0.0.2".parse()?);
+    let rate = runtime.block_on(async move {
+        let mut listener = server.bind(addr).await.unwrap();
+        server.spawn(async move {
+            let mut accepted = vec![];
+            while let Ok((socket, _)) = listener.accept().await {
+                accepted.push(socket);
+                // Keep the server halves of the open connections, dropping churned ones.
+                if accepted.len() > open {
+                    accepted.pop();
+                }
+            }
+        });
+
+        let start = time::Instant::now();
+        let mut held = Vec::with_capacity(open);
+        for _ in 0..open {
+            held.push(client.connect(addr).await.unwrap());
+        }
+        let opened = start.elapsed();
+        println!(
+            "opened {} connections in {:?} ({:.0}/s)",
+            open,
+            opened,
+            open as f64 / opened.as_secs_f64()
+        );
+
+        let start = time::Instant::now();
+        for _ in 0..churned {
+            drop(client.connect(addr).await.unwrap());
+        }
+        churned as f64 / start.elapsed().as_secs_f64()
+    });
+    Ok(rate)
+}
@@ -0,0 +1,288 @@
+//! Calendar time utilities for testing code which parses or schedules against civil time.
+//!
+//! [`DateTime`] converts between `SystemTime` and UTC calendar dates, and [`TimeZone`] maps
+//! UTC to local time using a fixed set of offset transitions, allowing DST changes to be
+//! modeled without consulting the host's timezone database.
+//!
+//! [`DateTime`]:`DateTime`
+//! [`TimeZone`]:`TimeZone`
+use std::{fmt, time};
+
+const SECONDS_PER_DAY: i64 = 86_400;
+
+/// A calendar date and time of day, accurate to the second.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DateTime {
+    year: i32,
+    month: u32,
+    day: u32,
+    hour: u32,
+    minute: u32,
+    second: u32,
+}
+
+impl DateTime {
+    /// Returns a new `DateTime`, or `None` if the provided fields do not form a valid date.
+    pub fn new(
+        year: i32,
+        month: u32,
+        day: u32,
+        hour: u32,
+        minute: u32,
+        second: u32,
+    ) -> Option<Self> {
+        if month < 1 || month > 12 || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        Some(Self {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        })
+    }
+
+    /// Returns the UTC date and time of the provided `SystemTime`.
+    pub fn from_system_time(system_time: time::SystemTime) -> Self {
+        Self::from_unix_seconds(unix_seconds(system_time))
+    }
+
+    /// Returns the date and time at the provided number of seconds since the Unix epoch.
+    pub fn from_unix_seconds(seconds: i64) -> Self {
+        let days = seconds.div_euclid(SECONDS_PER_DAY);
+        let seconds_of_day = seconds.rem_euclid(SECONDS_PER_DAY) as u32;
+        let (year, month, day) = civil_from_days(days);
+        Self {
+            year,
+            month,
+            day,
+            hour: seconds_of_day / 3600,
+            minute: seconds_of_day % 3600 / 60,
+            second: seconds_of_day % 60,
+        }
+    }
+
+    /// Returns the number of seconds since the Unix epoch, treating this date and time as UTC.
+    pub fn unix_seconds(&self) -> i64 {
+        days_from_civil(self.year, self.month, self.day) * SECONDS_PER_DAY
+            + i64::from(self.hour * 3600 + self.minute * 60 + self.second)
+    }
+
+    /// Returns the `SystemTime` of this date and time, treating it as UTC.
+    pub fn to_system_time(&self) -> time::SystemTime {
+        let seconds = self.unix_seconds();
+        if seconds >= 0 {
+            time::UNIX_EPOCH + time::Duration::from_secs(seconds as u64)
+        } else {
+            time::UNIX_EPOCH - time::Duration::from_secs(seconds.abs() as u64)
+        }
+    }
+
+    pub fn year(&self) -> i32 {
+        self.year
+    }
+
+    pub fn month(&self) -> u32 {
+        self.month
+    }
+
+    pub fn day(&self) -> u32 {
+        self.day
+    }
+
+    pub fn hour(&self) -> u32 {
+        self.hour
+    }
+
+    pub fn minute(&self) -> u32 {
+        self.minute
+    }
+
+    pub fn second(&self) -> u32 {
+        self.second
+    }
+
+    /// Returns the day of the week, where 0 is Sunday.
+    pub fn weekday(&self) -> u32 {
+        // 1970-01-01 was a Thursday.
+        (days_from_civil(self.year, self.month, self.day) + 4).rem_euclid(7) as u32
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+/// Maps UTC to local time using a standard offset and a set of offset transitions.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TimeZone {
+    offset: i32,
+    transitions: Vec<(time::SystemTime, i32)>,
+}
+
+impl TimeZone {
+    /// Returns the UTC time zone.
+    pub fn utc() -> Self {
+        Self::default()
+    }
+
+    /// Returns a time zone with a fixed offset from UTC, in seconds.
+    pub fn fixed(offset: i32) -> Self {
+        Self {
+            offset,
+            transitions: vec![],
+        }
+    }
+
+    /// Changes the offset from UTC to the provided number of seconds at the provided time.
+    pub fn with_transition(mut self, at: time::SystemTime, offset: i32) -> Self {
+        self.transitions.push((at, offset));
+        self.transitions.sort_by_key(|(at, _)| *at);
+        self
+    }
+
+    /// Adds a daylight saving period to this time zone, during which the offset is advanced by
+    /// one hour.
+    pub fn with_dst(self, start: time::SystemTime, end: time::SystemTime) -> Self {
+        let standard = self.offset_at(start);
+        self.with_transition(start, standard + 3600)
+            .with_transition(end, standard)
+    }
+
+    /// Returns the offset from UTC in seconds in effect at the provided time.
+    pub fn offset_at(&self, system_time: time::SystemTime) -> i32 {
+        self.transitions
+            .iter()
+            .take_while(|(at, _)| *at <= system_time)
+            .last()
+            .map(|(_, offset)| *offset)
+            .unwrap_or(self.offset)
+    }
+
+    /// Returns the local date and time of the provided `SystemTime`.
+    pub fn local(&self, system_time: time::SystemTime) -> DateTime {
+        let offset = i64::from(self.offset_at(system_time));
+        DateTime::from_unix_seconds(unix_seconds(system_time) + offset)
+    }
+}
+
+/// Returns the number of whole seconds between the Unix epoch and the provided time, rounding
+/// towards negative infinity.
+fn unix_seconds(system_time: time::SystemTime) -> i64 {
+    match system_time.duration_since(time::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        Err(e) => {
+            let duration = e.duration();
+            let seconds = -(duration.as_secs() as i64);
+            if duration.subsec_nanos() > 0 {
+                seconds - 1
+            } else {
+                seconds
+            }
+        }
+    }
+}
+
+fn is_leap_year(year: i32) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i32, month: u32) -> u32 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Returns the number of days since the Unix epoch for the provided date.
+fn days_from_civil(year: i32, month: u32, day: u32) -> i64 {
+    let year = i64::from(year) - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = i64::from(month);
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + i64::from(day) - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Returns the date for the provided number of days since the Unix epoch.
+fn civil_from_days(days: i64) -> (i32, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as i32, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that dates round trip through Unix timestamps.
+    fn round_trip() {
+        let epoch = DateTime::new(1970, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(epoch.unix_seconds(), 0);
+        assert_eq!(epoch.to_system_time(), time::UNIX_EPOCH);
+        let leap_day = DateTime::new(2020, 2, 29, 12, 30, 15).unwrap();
+        assert_eq!(leap_day.unix_seconds(), 1_582_979_415);
+        assert_eq!(
+            DateTime::from_system_time(leap_day.to_system_time()),
+            leap_day
+        );
+        let before_epoch = DateTime::new(1969, 12, 31, 23, 59, 59).unwrap();
+        assert_eq!(before_epoch.unix_seconds(), -1);
+        assert_eq!(DateTime::from_unix_seconds(-1), before_epoch);
+        assert_eq!(
+            leap_day.weekday(),
+            6,
+            "expected 2020-02-29 to be a Saturday"
+        );
+        assert_eq!(leap_day.to_string(), "2020-02-29T12:30:15");
+    }
+
+    #[test]
+    /// Test that invalid dates are rejected.
+    fn invalid_dates() {
+        assert!(DateTime::new(2019, 2, 29, 0, 0, 0).is_none());
+        assert!(DateTime::new(2020, 13, 1, 0, 0, 0).is_none());
+        assert!(DateTime::new(2020, 1, 1, 24, 0, 0).is_none());
+    }
+
+    #[test]
+    /// Test that local time follows DST transitions.
+    fn dst_transitions() {
+        let start = DateTime::new(2021, 3, 14, 7, 0, 0)
+            .unwrap()
+            .to_system_time();
+        let end = DateTime::new(2021, 11, 7, 6, 0, 0)
+            .unwrap()
+            .to_system_time();
+        let zone = TimeZone::fixed(-5 * 3600).with_dst(start, end);
+        let before = start - time::Duration::from_secs(1);
+        assert_eq!(zone.local(before).to_string(), "2021-03-14T01:59:59");
+        assert_eq!(zone.local(start).to_string(), "2021-03-14T03:00:00");
+        assert_eq!(zone.local(end).to_string(), "2021-11-07T01:00:00");
+        assert_eq!(zone.offset_at(end), -5 * 3600);
+    }
+}
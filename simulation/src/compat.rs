@@ -0,0 +1,204 @@
+//! Compatibility adapters between the `tokio::io` and `futures::io` traits.
+//!
+//! Simulated streams implement the Tokio IO traits, allowing them to be used directly with
+//! `tokio::codec`. Codebases built on top of `futures::io` can wrap a simulated stream with
+//! [`TokioAsyncReadCompatExt::compat`], and types implementing the `futures::io` traits can
+//! be wrapped with [`FuturesAsyncReadCompatExt::compat`] for use with Tokio codecs.
+//!
+//! [`TokioAsyncReadCompatExt::compat`]:`TokioAsyncReadCompatExt::compat`
+//! [`FuturesAsyncReadCompatExt::compat`]:`FuturesAsyncReadCompatExt::compat`
+use futures::{io as futures_io, Poll};
+use std::{io, pin::Pin, task::Context};
+use tokio::io as tokio_io;
+
+/// Compat wraps either a `tokio::io` or `futures::io` type, implementing the opposite set of
+/// IO traits.
+#[derive(Debug)]
+pub struct Compat<T> {
+    inner: T,
+}
+
+impl<T> Compat<T> {
+    pub fn new(inner: T) -> Self {
+        Self { inner }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+/// Extension trait allowing `tokio::io::AsyncRead` types to be used as `futures::io::AsyncRead`.
+pub trait TokioAsyncReadCompatExt: tokio_io::AsyncRead {
+    fn compat(self) -> Compat<Self>
+    where
+        Self: Sized,
+    {
+        Compat::new(self)
+    }
+}
+
+impl<T: tokio_io::AsyncRead> TokioAsyncReadCompatExt for T {}
+
+/// Extension trait allowing `tokio::io::AsyncWrite` types to be used as `futures::io::AsyncWrite`.
+pub trait TokioAsyncWriteCompatExt: tokio_io::AsyncWrite {
+    fn compat_write(self) -> Compat<Self>
+    where
+        Self: Sized,
+    {
+        Compat::new(self)
+    }
+}
+
+impl<T: tokio_io::AsyncWrite> TokioAsyncWriteCompatExt for T {}
+
+/// Extension trait allowing `futures::io::AsyncRead` types to be used as `tokio::io::AsyncRead`.
+pub trait FuturesAsyncReadCompatExt: futures_io::AsyncRead {
+    fn compat(self) -> Compat<Self>
+    where
+        Self: Sized,
+    {
+        Compat::new(self)
+    }
+}
+
+impl<T: futures_io::AsyncRead> FuturesAsyncReadCompatExt for T {}
+
+/// Extension trait allowing `futures::io::AsyncWrite` types to be used as `tokio::io::AsyncWrite`.
+pub trait FuturesAsyncWriteCompatExt: futures_io::AsyncWrite {
+    fn compat_write(self) -> Compat<Self>
+    where
+        Self: Sized,
+    {
+        Compat::new(self)
+    }
+}
+
+impl<T: futures_io::AsyncWrite> FuturesAsyncWriteCompatExt for T {}
+
+impl<T> futures_io::AsyncRead for Compat<T>
+where
+    T: tokio_io::AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio_io::AsyncRead::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+impl<T> futures_io::AsyncWrite for Compat<T>
+where
+    T: tokio_io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        tokio_io::AsyncWrite::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio_io::AsyncWrite::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        tokio_io::AsyncWrite::poll_shutdown(Pin::new(&mut self.inner), cx)
+    }
+}
+
+impl<T> tokio_io::AsyncRead for Compat<T>
+where
+    T: futures_io::AsyncRead + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        futures_io::AsyncRead::poll_read(Pin::new(&mut self.inner), cx, buf)
+    }
+}
+
+impl<T> tokio_io::AsyncWrite for Compat<T>
+where
+    T: futures_io::AsyncWrite + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        futures_io::AsyncWrite::poll_write(Pin::new(&mut self.inner), cx, buf)
+    }
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures_io::AsyncWrite::poll_flush(Pin::new(&mut self.inner), cx)
+    }
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures_io::AsyncWrite::poll_close(Pin::new(&mut self.inner), cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment, TcpListener};
+    use futures::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        SinkExt, StreamExt,
+    };
+    use std::net;
+    use tokio::codec::{Framed, LinesCodec};
+
+    #[test]
+    /// Test that simulated streams can be driven using the `futures::io` extension traits.
+    fn futures_io_round_trip() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = TokioAsyncReadCompatExt::compat(socket);
+                socket.write_all(b"hello").await.unwrap();
+                socket.flush().await.unwrap();
+            });
+            let socket = handle.connect(addr).await.unwrap();
+            let mut socket = TokioAsyncReadCompatExt::compat(socket);
+            let mut buf = [0u8; 5];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+        });
+    }
+
+    #[test]
+    /// Test that wrapping a stream in both directions allows it to be used with Tokio codecs.
+    fn double_compat_codec() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                transport.send(String::from("ping")).await.unwrap();
+            });
+            let socket = handle.connect(addr).await.unwrap();
+            let socket = FuturesAsyncReadCompatExt::compat(TokioAsyncReadCompatExt::compat(socket));
+            let mut transport = Framed::new(socket, LinesCodec::new());
+            let result = transport.next().await.unwrap().unwrap();
+            assert_eq!(result, "ping");
+        });
+    }
+}
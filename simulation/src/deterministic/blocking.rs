@@ -0,0 +1,236 @@
+//! Detection of tasks which block the executor thread.
+//!
+//! Calls such as `std::thread::sleep` or synchronous IO block the single executor thread,
+//! stalling every other task while virtual time stands still. Blocking calls cannot be
+//! intercepted directly, so polls are timed against the real clock and polls which take longer
+//! than a threshold are reported. Polls which spawn OS threads are reported as well, where the
+//! number of threads in the process can be observed.
+use super::TaskId;
+use std::{fmt, sync, time};
+use tracing::warn;
+
+/// The way a poll interfered with the executor thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockingKind {
+    /// The poll took the provided amount of real time.
+    Blocked(time::Duration),
+    /// The poll spawned the provided number of threads.
+    SpawnedThreads(usize),
+}
+
+/// A poll which blocked the executor thread or spawned threads.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockingCall {
+    task: TaskId,
+    name: Option<String>,
+    poll: u64,
+    at: time::Instant,
+    kind: BlockingKind,
+}
+
+impl BlockingCall {
+    pub fn task(&self) -> TaskId {
+        self.task
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(String::as_str)
+    }
+
+    /// Returns the number of the offending poll of the task, starting from 1. Together with the
+    /// virtual time of the poll this locates the call within the task.
+    pub fn poll(&self) -> u64 {
+        self.poll
+    }
+
+    /// Returns the virtual time at which the offending poll started.
+    pub fn at(&self) -> time::Instant {
+        self.at
+    }
+
+    pub fn kind(&self) -> BlockingKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for BlockingCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} {}", self.task, name)?,
+            None => write!(f, "{}", self.task)?,
+        }
+        match self.kind {
+            BlockingKind::Blocked(elapsed) => write!(
+                f,
+                " blocked the executor for {:?} during poll {}",
+                elapsed, self.poll
+            ),
+            BlockingKind::SpawnedThreads(threads) => {
+                write!(f, " spawned {} threads during poll {}", threads, self.poll)
+            }
+        }
+    }
+}
+
+/// Measurements taken at the start of a poll.
+pub(crate) struct Probe {
+    started: time::Instant,
+    threads: Option<usize>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    threshold: Option<time::Duration>,
+    calls: Vec<BlockingCall>,
+}
+
+/// Shared configuration and findings of blocking detection.
+#[derive(Debug, Clone)]
+pub(crate) struct BlockingDetector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl Default for BlockingDetector {
+    fn default() -> Self {
+        Self {
+            inner: sync::Arc::new(sync::Mutex::new(Inner {
+                threshold: None,
+                calls: vec![],
+            })),
+        }
+    }
+}
+
+impl BlockingDetector {
+    /// Enables detection of polls which take longer than `threshold` in real time, or
+    /// disables detection if `None` is provided.
+    pub(crate) fn set_threshold(&self, threshold: Option<time::Duration>) {
+        self.inner.lock().unwrap().threshold = threshold;
+    }
+
+    pub(crate) fn calls(&self) -> Vec<BlockingCall> {
+        self.inner.lock().unwrap().calls.clone()
+    }
+
+    /// Takes measurements before a poll, if detection is enabled.
+    pub(crate) fn start(&self) -> Option<Probe> {
+        self.inner.lock().unwrap().threshold?;
+        Some(Probe {
+            started: time::Instant::now(),
+            threads: thread_count(),
+        })
+    }
+
+    /// Compares measurements after a poll against those taken by `start`, recording the poll
+    /// if it blocked or spawned threads.
+    pub(crate) fn finish(
+        &self,
+        probe: Probe,
+        task: TaskId,
+        name: Option<String>,
+        poll: u64,
+        at: time::Instant,
+    ) {
+        let elapsed = probe.started.elapsed();
+        let spawned = match (probe.threads, thread_count()) {
+            (Some(before), Some(after)) if after > before => Some(after - before),
+            _ => None,
+        };
+        let mut lock = self.inner.lock().unwrap();
+        let threshold = match lock.threshold {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        let mut kinds = vec![];
+        if elapsed > threshold {
+            kinds.push(BlockingKind::Blocked(elapsed));
+        }
+        if let Some(spawned) = spawned {
+            kinds.push(BlockingKind::SpawnedThreads(spawned));
+        }
+        for kind in kinds {
+            let call = BlockingCall {
+                task,
+                name: name.clone(),
+                poll,
+                at,
+                kind,
+            };
+            warn!("{}", call);
+            lock.calls.push(call);
+        }
+    }
+}
+
+/// Returns the number of threads in the current process, where it can be determined. Threads
+/// spawned or exiting concurrently on other threads are indistinguishable from threads spawned
+/// by the polled task.
+#[cfg(target_os = "linux")]
+pub(crate) fn thread_count() -> Option<usize> {
+    std::fs::read_dir("/proc/self/task")
+        .ok()
+        .map(|entries| entries.count())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn thread_count() -> Option<usize> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+
+    #[test]
+    /// Test that tasks which block the executor thread are reported.
+    fn blocking_sleep() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.detect_blocking(Some(time::Duration::from_millis(20)));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let delay_handle = handle.clone();
+            handle.spawn_named("sleeper", async move {
+                delay_handle.delay_from(time::Duration::from_secs(1)).await;
+                std::thread::sleep(time::Duration::from_millis(50));
+            });
+            handle.delay_from(time::Duration::from_secs(2)).await;
+        });
+        // Threads spawned concurrently by the test harness may also be reported.
+        let calls: Vec<_> = runtime
+            .blocking_calls()
+            .into_iter()
+            .filter(|call| match call.kind() {
+                BlockingKind::Blocked(elapsed) => elapsed >= time::Duration::from_millis(50),
+                BlockingKind::SpawnedThreads(_) => false,
+            })
+            .collect();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].name(), Some("sleeper"));
+        assert_eq!(calls[0].poll(), 2);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that tasks which spawn threads are reported.
+    fn thread_spawn() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.detect_blocking(Some(time::Duration::from_secs(10)));
+        let (tx, rx) = sync::mpsc::channel::<()>();
+        runtime.block_on(async move {
+            std::thread::spawn(move || {
+                let _ = rx.recv();
+            });
+        });
+        let calls = runtime.blocking_calls();
+        drop(tx);
+        assert!(calls.iter().any(|call| {
+            let spawned = match call.kind() {
+                BlockingKind::SpawnedThreads(threads) => threads > 0,
+                BlockingKind::Blocked(_) => false,
+            };
+            spawned && call.name() == Some("main")
+        }));
+    }
+}
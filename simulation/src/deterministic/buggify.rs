@@ -0,0 +1,191 @@
+//! Seeded hooks for application defined faults.
+//!
+//! The fault injectors of the runtime can only perturb what the runtime sees, such as latency
+//! and disconnects. Buggify points let application code perturb itself, taking early returns,
+//! using tiny buffers or waking spuriously, in the style of FoundationDB's `BUGGIFY`. Each point
+//! is identified by a site, usually its source location as provided by [`buggify!`]. A site is
+//! activated or left dormant for the whole run based on the seed, so a run only exercises some
+//! of the rare paths of the application, and an active site fires with its own probability each
+//! time it is evaluated. Decisions at active sites are recorded so a failing run can be related
+//! back to the rare paths it took.
+//!
+//! [`buggify!`]:`crate::buggify`
+use super::observer::Observers;
+use super::{DecisionPoint, DeterministicRandomHandle, RngAlgorithm, Seed, TaskId};
+use std::{collections, sync, time};
+
+/// Probability that a site is active for a run.
+const ACTIVATION_PROBABILITY: f64 = 0.25;
+
+/// An evaluation of an active buggify site.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BuggifyDecision {
+    pub site: String,
+    pub at: time::Instant,
+    /// Task which evaluated the site, if it was evaluated from within a task.
+    pub task: Option<TaskId>,
+    pub fired: bool,
+}
+
+#[derive(Debug)]
+struct Site {
+    active: bool,
+    random: DeterministicRandomHandle,
+}
+
+#[derive(Debug)]
+struct State {
+    enabled: bool,
+    sites: collections::HashMap<String, Site>,
+    decisions: Vec<BuggifyDecision>,
+}
+
+/// Buggify sites evaluated by the hosts of a runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct Buggify {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Default for Buggify {
+    fn default() -> Self {
+        Self {
+            state: sync::Arc::new(sync::Mutex::new(State {
+                enabled: true,
+                sites: collections::HashMap::new(),
+                decisions: vec![],
+            })),
+        }
+    }
+}
+
+impl Buggify {
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.state.lock().unwrap().enabled = enabled;
+    }
+
+    /// Evaluates the site, returning true if it fires and reporting the decision to
+    /// `observers` if the site is active. Sites draw from their own substream of the seed, so
+    /// evaluating one site does not change the decisions of another.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn evaluate(
+        &self,
+        site: &str,
+        probability: f64,
+        seed: Seed,
+        algorithm: RngAlgorithm,
+        at: time::Instant,
+        task: Option<TaskId>,
+        observers: &Observers,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if !state.enabled {
+            return false;
+        }
+        let site_state = state.sites.entry(String::from(site)).or_insert_with(|| {
+            let random = seed.derive("buggify").derive(site).random_with(algorithm);
+            Site {
+                active: random.should_fault(ACTIVATION_PROBABILITY),
+                random,
+            }
+        });
+        if !site_state.active {
+            return false;
+        }
+        let fired = site_state.random.should_fault(probability);
+        state.decisions.push(BuggifyDecision {
+            site: String::from(site),
+            at,
+            task,
+            fired,
+        });
+        drop(state);
+        observers.decision(DecisionPoint::Buggify {
+            site: String::from(site),
+            fired,
+        });
+        fired
+    }
+
+    /// Returns the sites evaluated so far and whether each is active, ordered by site.
+    pub(crate) fn sites(&self) -> Vec<(String, bool)> {
+        let state = self.state.lock().unwrap();
+        let mut sites: Vec<_> = state
+            .sites
+            .iter()
+            .map(|(site, state)| (site.clone(), state.active))
+            .collect();
+        sites.sort();
+        sites
+    }
+
+    pub(crate) fn decisions(&self) -> Vec<BuggifyDecision> {
+        self.state.lock().unwrap().decisions.clone()
+    }
+}
+
+/// Evaluates a buggify point at the current source location, returning true if application
+/// code should take its rare path. Takes a [`DeterministicRuntimeHandle`] and optionally the
+/// probability of firing once the site is active, which defaults to 5%.
+///
+/// ```rust,ignore
+/// let len = if buggify!(handle) { 1 } else { 64 * 1024 };
+/// ```
+///
+/// [`DeterministicRuntimeHandle`]:`crate::deterministic::DeterministicRuntimeHandle`
+#[macro_export]
+macro_rules! buggify {
+    ($handle:expr) => {
+        $crate::buggify!($handle, 0.05)
+    };
+    ($handle:expr, $probability:expr) => {
+        $handle.buggify_site(concat!(file!(), ":", line!()), $probability)
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+
+    fn fired(seed: u64) -> (Vec<(String, bool)>, Vec<bool>) {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.localhost_handle();
+        let mut fired = vec![];
+        let sites: Vec<_> = (0..30).map(|site| format!("site-{}", site)).collect();
+        runtime.block_on(async {
+            for _ in 0..50 {
+                for site in sites.iter() {
+                    fired.push(handle.buggify_site(site, 0.5));
+                }
+                fired.push(crate::buggify!(handle, 0.5));
+            }
+        });
+        let decisions = runtime.buggify_decisions();
+        assert_eq!(
+            decisions.iter().filter(|decision| decision.fired).count(),
+            fired.iter().filter(|fired| **fired).count()
+        );
+        (runtime.buggify_sites(), fired)
+    }
+
+    #[test]
+    /// Test that the sites activated and the decisions made at them are reproduced by the seed,
+    /// that dormant sites never fire, and that disabling buggify silences every site.
+    fn seeded_sites() {
+        let (sites, decisions) = fired(3);
+        assert_eq!((sites.clone(), decisions.clone()), fired(3));
+        assert_eq!(sites.len(), 31);
+        let active: Vec<_> = sites.iter().filter(|(_, active)| *active).collect();
+        assert!(
+            !active.is_empty() && active.len() < sites.len(),
+            "{:?}",
+            sites
+        );
+        assert!(decisions.iter().any(|fired| *fired));
+
+        let runtime = DeterministicRuntime::new_with_seed(3).unwrap();
+        runtime.set_buggify_enabled(false);
+        let handle = runtime.localhost_handle();
+        assert!((0..100).all(|_| !handle.buggify(1.0)));
+        assert!(runtime.buggify_decisions().is_empty());
+    }
+}
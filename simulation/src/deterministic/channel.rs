@@ -0,0 +1,334 @@
+//! Instrumented channels between tasks on the same host.
+//!
+//! Many bugs attributed to the network are queueing bugs inside a single process: a pipeline
+//! stage falls behind, messages sit in an internal queue, and timeouts elsewhere fire. Channels
+//! created with [`DeterministicRuntimeHandle::channel`] behave like bounded mpsc channels, but
+//! the simulation can delay, drop or stall the messages sent over them through
+//! [`ChannelFaults`]. Tasks parked on a channel are reported as waiting on
+//! [`WaitResource::Channel`] in stall reports and the wait-for graph.
+//!
+//! [`DeterministicRuntimeHandle::channel`]:`super::DeterministicRuntimeHandle::channel`
+//! [`ChannelFaults`]:`ChannelFaults`
+//! [`WaitResource::Channel`]:`super::WaitResource::Channel`
+use super::task::{self, WaitResource};
+use super::watermark::{Buffer, Watermarks};
+use super::{DeterministicRandomHandle, DeterministicTimeHandle};
+use futures::{channel::mpsc, task::Waker, FutureExt, Poll, SinkExt, Stream, StreamExt};
+use std::{fmt, pin::Pin, sync, task::Context, time};
+use tracing::trace;
+
+/// Error returned when sending on a channel whose receiver has been dropped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelClosed;
+
+impl fmt::Display for ChannelClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel receiver dropped")
+    }
+}
+
+impl std::error::Error for ChannelClosed {}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    delay: time::Duration,
+    jitter: time::Duration,
+    drop_probability: f64,
+    clogged: bool,
+    clog_waker: Option<Waker>,
+    /// Delivery time of the most recently sent message, used to keep delivery in order.
+    last_delivery: Option<time::Instant>,
+    sent: u64,
+    dropped: u64,
+    /// Number of messages sent which have not been received.
+    queued: usize,
+}
+
+/// Handle used to inject faults into a channel.
+#[derive(Debug, Clone)]
+pub struct ChannelFaults {
+    name: sync::Arc<str>,
+    state: sync::Arc<sync::Mutex<FaultState>>,
+    watermarks: Watermarks,
+}
+
+impl ChannelFaults {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Delays each message by `delay` plus a uniformly sampled amount of up to `jitter`,
+    /// modeling time spent queued behind an overloaded stage. Messages are still delivered in
+    /// the order they were sent.
+    pub fn set_delay(&self, delay: time::Duration, jitter: time::Duration) {
+        let mut lock = self.state.lock().unwrap();
+        lock.delay = delay;
+        lock.jitter = jitter;
+    }
+
+    /// Drops each message sent from now on with the provided probability.
+    pub fn set_drop_probability(&self, probability: f64) {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "drop probability must be within [0, 1]"
+        );
+        self.state.lock().unwrap().drop_probability = probability;
+    }
+
+    /// Stops delivering messages until `unclog` is called. Senders continue until the channel
+    /// is full.
+    pub fn clog(&self) {
+        self.state.lock().unwrap().clogged = true;
+    }
+
+    pub fn unclog(&self) {
+        let mut lock = self.state.lock().unwrap();
+        lock.clogged = false;
+        if let Some(waker) = lock.clog_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Returns the number of messages sent, including dropped messages.
+    pub fn sent(&self) -> u64 {
+        self.state.lock().unwrap().sent
+    }
+
+    /// Returns the number of messages dropped by fault injection.
+    pub fn dropped(&self) -> u64 {
+        self.state.lock().unwrap().dropped
+    }
+
+    /// Returns the number of messages sent which have not yet been received.
+    pub fn queued(&self) -> usize {
+        self.state.lock().unwrap().queued
+    }
+
+    /// Adjusts the number of queued messages, reporting the new level.
+    fn adjust_queued(&self, sent: bool) {
+        let queued = {
+            let mut lock = self.state.lock().unwrap();
+            if sent {
+                lock.queued += 1;
+            } else {
+                lock.queued -= 1;
+            }
+            lock.queued
+        };
+        self.watermarks
+            .record(Buffer::Channel(self.name.to_string()), queued);
+    }
+}
+
+/// Sending half of an instrumented channel.
+pub struct FaultySender<T> {
+    tx: mpsc::Sender<(T, time::Instant)>,
+    faults: ChannelFaults,
+    time_handle: DeterministicTimeHandle,
+    random: DeterministicRandomHandle,
+}
+
+impl<T> Clone for FaultySender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            tx: self.tx.clone(),
+            faults: self.faults.clone(),
+            time_handle: self.time_handle.clone(),
+            random: self.random.clone(),
+        }
+    }
+}
+
+impl<T> fmt::Debug for FaultySender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FaultySender {{ name: {} }}", self.faults.name)
+    }
+}
+
+impl<T> FaultySender<T> {
+    pub fn faults(&self) -> ChannelFaults {
+        self.faults.clone()
+    }
+
+    /// Sends a message, waiting for capacity if the channel is full. Messages dropped by fault
+    /// injection are reported as sent.
+    pub async fn send(&mut self, item: T) -> Result<(), ChannelClosed> {
+        let resource = WaitResource::Channel(self.faults.name.to_string());
+        let deliver_at = {
+            let mut lock = self.faults.state.lock().unwrap();
+            lock.sent += 1;
+            if lock.drop_probability > 0.0 && self.random.should_fault(lock.drop_probability) {
+                trace!("dropped message on channel {}", self.faults.name);
+                lock.dropped += 1;
+                return Ok(());
+            }
+            let mut delay = lock.delay;
+            if lock.jitter > time::Duration::from_secs(0) {
+                let jitter = self.random.gen_range(0..lock.jitter.as_nanos() as u64 + 1);
+                delay += time::Duration::from_nanos(jitter);
+            }
+            let mut deliver_at = self.time_handle.now() + delay;
+            if let Some(last) = lock.last_delivery {
+                deliver_at = std::cmp::max(deliver_at, last);
+            }
+            lock.last_delivery.replace(deliver_at);
+            deliver_at
+        };
+        task::hold(resource.clone());
+        task::WaitingOn::new(resource, self.tx.send((item, deliver_at)))
+            .await
+            .map_err(|_| ChannelClosed)?;
+        self.faults.adjust_queued(true);
+        Ok(())
+    }
+}
+
+/// Receiving half of an instrumented channel.
+pub struct FaultyReceiver<T> {
+    rx: mpsc::Receiver<(T, time::Instant)>,
+    staged: Option<(T, tokio_timer::Delay)>,
+    faults: ChannelFaults,
+    time_handle: DeterministicTimeHandle,
+}
+
+impl<T> fmt::Debug for FaultyReceiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FaultyReceiver {{ name: {} }}", self.faults.name)
+    }
+}
+
+impl<T> FaultyReceiver<T> {
+    pub fn faults(&self) -> ChannelFaults {
+        self.faults.clone()
+    }
+}
+
+impl<T> Unpin for FaultyReceiver<T> {}
+
+impl<T> Stream for FaultyReceiver<T> {
+    type Item = T;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        let resource = WaitResource::Channel(this.faults.name.to_string());
+        loop {
+            if let Some((_, delay)) = this.staged.as_mut() {
+                {
+                    let mut lock = this.faults.state.lock().unwrap();
+                    if lock.clogged {
+                        lock.clog_waker.replace(cx.waker().clone());
+                        task::wait_on(resource);
+                        return Poll::Pending;
+                    }
+                }
+                if delay.poll_unpin(cx).is_pending() {
+                    task::wait_on(resource);
+                    return Poll::Pending;
+                }
+                let (item, _) = this.staged.take().unwrap();
+                this.faults.adjust_queued(false);
+                return Poll::Ready(Some(item));
+            }
+            match this.rx.poll_next_unpin(cx) {
+                Poll::Ready(Some((item, deliver_at))) => {
+                    let delay = this.time_handle.delay(deliver_at);
+                    this.staged.replace((item, delay));
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => {
+                    task::wait_on(resource);
+                    return Poll::Pending;
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn channel<T>(
+    name: String,
+    capacity: usize,
+    time_handle: DeterministicTimeHandle,
+    random: DeterministicRandomHandle,
+    watermarks: Watermarks,
+) -> (FaultySender<T>, FaultyReceiver<T>) {
+    let (tx, rx) = mpsc::channel(capacity);
+    let faults = ChannelFaults {
+        name: sync::Arc::from(name),
+        state: sync::Arc::default(),
+        watermarks,
+    };
+    let sender = FaultySender {
+        tx,
+        faults: faults.clone(),
+        time_handle: time_handle.clone(),
+        random,
+    };
+    let receiver = FaultyReceiver {
+        rx,
+        staged: None,
+        faults,
+        time_handle,
+    };
+    (sender, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment};
+    use futures::StreamExt;
+    use std::time::Duration;
+
+    #[test]
+    /// Test that injected delays hold messages in order, and that clogged channels stop
+    /// delivering until unclogged.
+    fn delay_and_clog() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = handle.channel("pipeline", 8);
+            let faults = rx.faults();
+            faults.set_delay(Duration::from_secs(5), Duration::from_secs(5));
+            let start = handle.now();
+            for i in 0..4 {
+                tx.send(i).await.unwrap();
+            }
+            let mut received = vec![];
+            for _ in 0..4 {
+                received.push(rx.next().await.unwrap());
+            }
+            assert_eq!(received, vec![0, 1, 2, 3]);
+            assert!(handle.now() - start >= Duration::from_secs(5));
+
+            faults.set_delay(Duration::from_secs(0), Duration::from_secs(0));
+            faults.clog();
+            tx.send(4).await.unwrap();
+            let unclog = faults.clone();
+            let unclog_handle = handle.clone();
+            handle.spawn(async move {
+                unclog_handle.delay_from(Duration::from_secs(30)).await;
+                unclog.unclog();
+            });
+            let clogged_at = handle.now();
+            assert_eq!(rx.next().await, Some(4));
+            assert_eq!(handle.now() - clogged_at, Duration::from_secs(30));
+        });
+    }
+
+    #[test]
+    /// Test that dropped messages are never delivered.
+    fn drops() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = handle.channel("pipeline", 8);
+            let faults = tx.faults();
+            faults.set_drop_probability(1.0);
+            tx.send("lost").await.unwrap();
+            faults.set_drop_probability(0.0);
+            tx.send("delivered").await.unwrap();
+            drop(tx);
+            assert_eq!(rx.next().await, Some("delivered"));
+            assert_eq!(rx.next().await, None);
+            assert_eq!((faults.sent(), faults.dropped()), (2, 1));
+        });
+    }
+}
@@ -0,0 +1,289 @@
+//! Export of event logs to the Chrome trace event format.
+//!
+//! Text logs stop being readable once a run has tens of thousands of events. An [`EventLog`]
+//! can be exported with [`EventLog::to_chrome_trace`] as a Chrome `trace_event` JSON document,
+//! which can be opened in Perfetto or `chrome://tracing` to inspect a failing run visually.
+//!
+//! Each task gets a track of its own under the `tasks` process, holding a span for each of its
+//! polls. Simulated time does not advance while a task is polled, so poll spans have no
+//! duration and polls made at the same instant are ordered by their position in the log. Each
+//! host gets a process holding its connections, accepts and writes, while timers, faults and
+//! other events are grouped under the `simulation` process. Faults are marked across every
+//! track, so the behavior of each task can be followed around them.
+//!
+//! [`EventLog`]:`EventLog`
+//! [`EventLog::to_chrome_trace`]:`EventLog::to_chrome_trace`
+use super::{EventLog, LogEntry, LogEvent};
+use std::{collections, fmt::Write, fs, io, net, path};
+
+/// Process holding a track per task.
+const TASKS_PID: u64 = 0;
+/// Process holding the timers, faults and other events of the simulation.
+const SIMULATION_PID: u64 = 1;
+const TIMERS_TID: u64 = 0;
+const FAULTS_TID: u64 = 1;
+const EVENTS_TID: u64 = 2;
+/// Track of the network activity of each host process.
+const NETWORK_TID: u64 = 0;
+
+/// Escapes `value` for inclusion in a JSON string.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(escaped, "\\u{:04x}", c as u32);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Builds the events of a trace, assigning a process to each host as it first appears.
+struct Trace {
+    events: Vec<String>,
+    hosts: collections::HashMap<net::IpAddr, u64>,
+}
+
+impl Trace {
+    fn new() -> Self {
+        let mut trace = Self {
+            events: vec![],
+            hosts: collections::HashMap::new(),
+        };
+        trace.name_process(TASKS_PID, "tasks");
+        trace.name_process(SIMULATION_PID, "simulation");
+        trace.name_thread(SIMULATION_PID, TIMERS_TID, "timers");
+        trace.name_thread(SIMULATION_PID, FAULTS_TID, "faults");
+        trace.name_thread(SIMULATION_PID, EVENTS_TID, "events");
+        trace
+    }
+
+    fn name_process(&mut self, pid: u64, name: &str) {
+        self.events.push(format!(
+            r#"{{"name":"process_name","ph":"M","pid":{},"tid":0,"args":{{"name":"{}"}}}}"#,
+            pid,
+            escape(name)
+        ));
+    }
+
+    fn name_thread(&mut self, pid: u64, tid: u64, name: &str) {
+        self.events.push(format!(
+            r#"{{"name":"thread_name","ph":"M","pid":{},"tid":{},"args":{{"name":"{}"}}}}"#,
+            pid,
+            tid,
+            escape(name)
+        ));
+    }
+
+    /// Returns the process of the provided host.
+    fn host(&mut self, addr: net::SocketAddr) -> u64 {
+        if let Some(pid) = self.hosts.get(&addr.ip()) {
+            return *pid;
+        }
+        let pid = SIMULATION_PID + 1 + self.hosts.len() as u64;
+        self.hosts.insert(addr.ip(), pid);
+        self.name_process(pid, &format!("host {}", addr.ip()));
+        self.name_thread(pid, NETWORK_TID, "network");
+        pid
+    }
+
+    /// Adds an event of the entry to a track. `args` holds the JSON members describing the
+    /// event, which are followed by the position of the entry in the log.
+    fn push(&mut self, entry: &LogEntry, name: &str, phase: &str, track: (u64, u64), args: &str) {
+        let ts = entry.elapsed.as_nanos() as f64 / 1000.0;
+        let mut event = format!(
+            r#"{{"name":"{}","ph":"{}","ts":{},"pid":{},"tid":{}"#,
+            escape(name),
+            phase,
+            ts,
+            track.0,
+            track.1
+        );
+        match phase {
+            "X" => event.push_str(r#","dur":0"#),
+            "i" if track == (SIMULATION_PID, FAULTS_TID) => event.push_str(r#","s":"g""#),
+            "i" => event.push_str(r#","s":"t""#),
+            _ => {}
+        }
+        let separator = if args.is_empty() { "" } else { "," };
+        let _ = write!(
+            event,
+            r#","args":{{{}{}"seq":{}}}}}"#,
+            args, separator, entry.seq
+        );
+        self.events.push(event);
+    }
+
+    fn add(&mut self, entry: &LogEntry) {
+        match &entry.event {
+            LogEvent::TaskSpawned { task, name } => {
+                let name = match name {
+                    Some(name) => format!("{} {}", task, name),
+                    None => task.to_string(),
+                };
+                self.name_thread(TASKS_PID, task.0, &name);
+                self.push(entry, "spawned", "i", (TASKS_PID, task.0), "");
+            }
+            LogEvent::TaskPolled { task, ready } => {
+                let args = format!(r#""ready":{}"#, ready);
+                self.push(entry, "poll", "X", (TASKS_PID, task.0), &args);
+            }
+            LogEvent::TaskDropped { task } => {
+                self.push(entry, "dropped", "i", (TASKS_PID, task.0), "");
+            }
+            LogEvent::TimerArmed { deadline } => {
+                let args = format!(r#""deadline_us":{}"#, deadline.as_micros());
+                self.push(
+                    entry,
+                    "timer armed",
+                    "i",
+                    (SIMULATION_PID, TIMERS_TID),
+                    &args,
+                );
+            }
+            LogEvent::TimerFired { deadline } => {
+                let args = format!(r#""deadline_us":{}"#, deadline.as_micros());
+                self.push(
+                    entry,
+                    "timer fired",
+                    "i",
+                    (SIMULATION_PID, TIMERS_TID),
+                    &args,
+                );
+            }
+            LogEvent::Connected { id, source, dest } => {
+                let pid = self.host(*source);
+                let args = format!(
+                    r#""id":"{}","source":"{}","dest":"{}""#,
+                    escape(&format!("{:?}", id)),
+                    source,
+                    dest
+                );
+                self.push(entry, "connect", "i", (pid, NETWORK_TID), &args);
+            }
+            LogEvent::ConnectionRefused { dest } => {
+                let pid = self.host(*dest);
+                let args = format!(r#""dest":"{}""#, dest);
+                self.push(entry, "refused", "i", (pid, NETWORK_TID), &args);
+            }
+            LogEvent::Accepted { id, local, peer } => {
+                let pid = self.host(*local);
+                let args = format!(
+                    r#""id":"{}","local":"{}","peer":"{}""#,
+                    escape(&format!("{:?}", id)),
+                    local,
+                    peer
+                );
+                self.push(entry, "accept", "i", (pid, NETWORK_TID), &args);
+            }
+            LogEvent::BytesSent { from, to, bytes } => {
+                let pid = self.host(*from);
+                let args = format!(r#""to":"{}","bytes":{}"#, to, bytes);
+                self.push(entry, "send", "i", (pid, NETWORK_TID), &args);
+            }
+            LogEvent::FaultInjected { fault } => {
+                let args = format!(r#""fault":"{}""#, escape(fault));
+                self.push(entry, "fault", "i", (SIMULATION_PID, FAULTS_TID), &args);
+            }
+            LogEvent::Other { event } => {
+                // Name the event after its variant, dropping the fields of its representation.
+                let name = event
+                    .split(|c: char| !c.is_alphanumeric() && c != '_')
+                    .next()
+                    .unwrap_or("event");
+                let args = format!(r#""event":"{}""#, escape(event));
+                let track = (SIMULATION_PID, EVENTS_TID);
+                self.push(entry, name, "i", track, &args);
+            }
+        }
+    }
+
+    fn finish(self) -> String {
+        format!(
+            r#"{{"traceEvents":[{}],"displayTimeUnit":"ns"}}"#,
+            self.events.join(",")
+        )
+    }
+}
+
+impl EventLog {
+    /// Returns the entries recorded so far as a Chrome trace event JSON document, which can be
+    /// opened in Perfetto. Tasks, hosts and the simulation get tracks of their own.
+    pub fn to_chrome_trace(&self) -> String {
+        let mut trace = Trace::new();
+        for entry in self.entries() {
+            trace.add(&entry);
+        }
+        trace.finish()
+    }
+
+    /// Writes the entries recorded so far to the provided path as a Chrome trace event JSON
+    /// document.
+    pub fn save_chrome_trace(&self, path: impl AsRef<path::Path>) -> io::Result<()> {
+        fs::write(path, self.to_chrome_trace())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, FaultScheduleBuilder, PartitionMode};
+    use crate::{Environment, TcpListener};
+    use serde_json::Value;
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that the exported trace is valid JSON holding a named track per task with its
+    /// polls, a process per host with its network activity, and global fault markers.
+    fn export() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let log = runtime.record_events();
+        let handle = runtime.localhost_handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let (a, b) = ("127.0.0.1".parse().unwrap(), "10.0.0.1".parse().unwrap());
+        let partition = FaultScheduleBuilder::new().partition(
+            time::Duration::from_secs(2),
+            time::Duration::from_secs(1),
+            &[a],
+            &[b],
+            PartitionMode::Stall,
+        );
+        let injector = runtime.schedule_faults(partition);
+        runtime.block_on(async {
+            handle.spawn(injector.run());
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn_named("server \"echo\"", async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(b"hello").await.unwrap();
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.read_exact(&mut [0u8; 5]).await.unwrap();
+            handle.delay_from(time::Duration::from_secs(5)).await;
+        });
+
+        let trace: Value = serde_json::from_str(&log.to_chrome_trace()).unwrap();
+        let events = trace["traceEvents"].as_array().unwrap();
+        let find = |f: &dyn Fn(&Value) -> bool| events.iter().find(|event| f(event)).cloned();
+        let server = find(&|event| {
+            event["name"] == "thread_name"
+                && event["args"]["name"]
+                    .as_str()
+                    .map_or(false, |name| name.ends_with("server \"echo\""))
+        })
+        .unwrap();
+        let poll = find(&|event| event["name"] == "poll" && event["tid"] == server["tid"]);
+        assert_eq!(poll.unwrap()["ph"], "X");
+        let host = find(&|event| event["args"]["name"] == "host 127.0.0.1").unwrap();
+        let send = find(&|event| event["name"] == "send").unwrap();
+        assert_eq!(send["pid"], host["pid"]);
+        assert_eq!(send["args"]["bytes"], 5);
+        let fault = find(&|event| event["name"] == "fault").unwrap();
+        assert_eq!(fault["s"], "g");
+        assert!(fault["ts"].as_f64().unwrap() >= 2_000_000.0);
+    }
+}
@@ -0,0 +1,289 @@
+//! Skewed and drifting clocks of simulated hosts.
+//!
+//! Every task of a runtime observes the same perfectly synchronized simulated time, which hides
+//! bugs in lease expiry and timestamp ordering. A [`HostClock`] layers a host's own view of
+//! time over simulated time: its wall clock can be offset from simulated wall time, both of its
+//! clocks can run fast or slow by a drift rate, and its wall clock can be stepped or smeared
+//! mid-test as a time daemon would. Monotonic time is never stepped, though it is slewed by
+//! smears and drift.
+//!
+//! [`HostClock`]:`HostClock`
+use super::DeterministicTimeHandle;
+use std::{cmp, collections, net, sync, time};
+
+/// Signed offset of a host clock from simulated time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockOffset {
+    Ahead(time::Duration),
+    Behind(time::Duration),
+}
+
+impl ClockOffset {
+    fn from_nanos(nanos: i128) -> Self {
+        let duration = time::Duration::from_nanos(nanos.abs() as u64);
+        if nanos < 0 {
+            ClockOffset::Behind(duration)
+        } else {
+            ClockOffset::Ahead(duration)
+        }
+    }
+
+    fn nanos(self) -> i128 {
+        match self {
+            ClockOffset::Ahead(duration) => duration.as_nanos() as i128,
+            ClockOffset::Behind(duration) => -(duration.as_nanos() as i128),
+        }
+    }
+}
+
+/// Adjustment of a clock applied gradually between two instants.
+#[derive(Debug, Clone, Copy)]
+struct Smear {
+    start: time::Instant,
+    end: time::Instant,
+    nanos: i128,
+}
+
+impl Smear {
+    /// Returns the portion of the adjustment applied by the provided instant.
+    fn applied(&self, at: time::Instant) -> i128 {
+        if at >= self.end {
+            self.nanos
+        } else if at <= self.start {
+            0
+        } else {
+            let elapsed = (at - self.start).as_nanos() as i128;
+            let total = (self.end - self.start).as_nanos() as i128;
+            self.nanos * elapsed / total
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct State {
+    /// Offsets of the wall and monotonic clocks at `anchor`.
+    wall: i128,
+    monotonic: i128,
+    anchor: time::Instant,
+    /// Parts per million the clocks gain on simulated time since `anchor`.
+    drift_ppm: f64,
+    smear: Option<Smear>,
+}
+
+impl State {
+    fn new(now: time::Instant) -> Self {
+        Self {
+            wall: 0,
+            monotonic: 0,
+            anchor: now,
+            drift_ppm: 0.0,
+            smear: None,
+        }
+    }
+
+    /// Returns the adjustment applied to both clocks between `anchor` and `at`.
+    fn slew(&self, at: time::Instant) -> i128 {
+        let elapsed = at.saturating_duration_since(self.anchor).as_nanos() as f64;
+        let drift = (elapsed * self.drift_ppm / 1_000_000.0) as i128;
+        drift + self.smear.map_or(0, |smear| smear.applied(at))
+    }
+
+    /// Folds the adjustments made up to `now` into the offsets at a new anchor, so that the
+    /// drift rate and smears can be changed from `now` on.
+    fn reanchor(&mut self, now: time::Instant) {
+        let slew = self.slew(now);
+        self.wall += slew;
+        self.monotonic += slew;
+        self.anchor = now;
+        self.smear = self.smear.and_then(|smear| {
+            if now >= smear.end {
+                return None;
+            }
+            Some(Smear {
+                start: cmp::max(now, smear.start),
+                end: smear.end,
+                nanos: smear.nanos - smear.applied(now),
+            })
+        });
+    }
+}
+
+/// Clocks of the hosts of a runtime.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Clocks {
+    hosts: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, State>>>,
+}
+
+impl Clocks {
+    fn state(&self, host: net::IpAddr, now: time::Instant) -> State {
+        self.hosts
+            .lock()
+            .unwrap()
+            .get(&host)
+            .cloned()
+            .unwrap_or_else(|| State::new(now))
+    }
+
+    fn update<F>(&self, host: net::IpAddr, now: time::Instant, f: F)
+    where
+        F: FnOnce(&mut State),
+    {
+        let mut hosts = self.hosts.lock().unwrap();
+        let state = hosts.entry(host).or_insert_with(|| State::new(now));
+        state.reanchor(now);
+        f(state);
+    }
+}
+
+/// The clocks of a simulated host, offset from and drifting relative to simulated time.
+#[derive(Debug, Clone)]
+pub struct HostClock {
+    host: net::IpAddr,
+    clocks: Clocks,
+    time_handle: DeterministicTimeHandle,
+}
+
+impl HostClock {
+    pub(crate) fn new(
+        host: net::IpAddr,
+        clocks: Clocks,
+        time_handle: DeterministicTimeHandle,
+    ) -> Self {
+        Self {
+            host,
+            clocks,
+            time_handle,
+        }
+    }
+
+    /// Returns the monotonic time of the host.
+    pub fn now(&self) -> time::Instant {
+        let now = self.time_handle.now();
+        let state = self.clocks.state(self.host, now);
+        offset_instant(now, state.monotonic + state.slew(now))
+    }
+
+    /// Returns the wall clock time of the host.
+    pub fn system_time(&self) -> time::SystemTime {
+        let now = self.time_handle.now();
+        let state = self.clocks.state(self.host, now);
+        let nanos = state.wall + state.slew(now);
+        let system_time = self.time_handle.system_time();
+        match ClockOffset::from_nanos(nanos) {
+            ClockOffset::Ahead(offset) => system_time + offset,
+            ClockOffset::Behind(offset) => system_time - offset,
+        }
+    }
+
+    /// Returns the offset of the wall clock of the host from simulated wall clock time.
+    pub fn offset(&self) -> ClockOffset {
+        let now = self.time_handle.now();
+        let state = self.clocks.state(self.host, now);
+        ClockOffset::from_nanos(state.wall + state.slew(now))
+    }
+
+    /// Sets the offset of the wall clock of the host, stepping it immediately.
+    pub fn set_skew(&self, offset: ClockOffset) {
+        self.clocks
+            .update(self.host, self.time_handle.now(), |state| {
+                state.wall = offset.nanos()
+            });
+    }
+
+    /// Sets the rate at which both clocks of the host drift from simulated time, in parts per
+    /// million. Positive rates run fast and negative rates run slow.
+    pub fn set_drift(&self, ppm: f64) {
+        self.clocks
+            .update(self.host, self.time_handle.now(), |state| {
+                state.drift_ppm = ppm
+            });
+    }
+
+    /// Steps the wall clock of the host by the provided offset, as when a time daemon corrects
+    /// a large error. Monotonic time is unaffected.
+    pub fn step(&self, offset: ClockOffset) {
+        self.clocks
+            .update(self.host, self.time_handle.now(), |state| {
+                state.wall += offset.nanos()
+            });
+    }
+
+    /// Adjusts both clocks of the host by the provided offset, spread evenly over `duration` of
+    /// simulated time. Replaces any smear in progress, keeping the adjustment it has already
+    /// applied.
+    pub fn smear(&self, offset: ClockOffset, duration: time::Duration) {
+        let now = self.time_handle.now();
+        self.clocks.update(self.host, now, |state| {
+            state.smear.replace(Smear {
+                start: now,
+                end: now + duration,
+                nanos: offset.nanos(),
+            });
+        });
+    }
+
+    /// Returns a delay which completes once `duration` has elapsed on the monotonic clock of
+    /// the host, according to its current drift rate.
+    pub fn delay_from(&self, duration: time::Duration) -> tokio_timer::Delay {
+        let now = self.time_handle.now();
+        let rate = 1.0 + self.clocks.state(self.host, now).drift_ppm / 1_000_000.0;
+        let nanos = duration.as_nanos() as f64 / rate;
+        self.time_handle
+            .delay_from(time::Duration::from_nanos(nanos as u64))
+    }
+}
+
+fn offset_instant(instant: time::Instant, nanos: i128) -> time::Instant {
+    match ClockOffset::from_nanos(nanos) {
+        ClockOffset::Ahead(offset) => instant + offset,
+        ClockOffset::Behind(offset) => instant - offset,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+
+    #[test]
+    /// Test that host clocks follow their skew, drift, steps and smears, independently of each
+    /// other and of simulated time.
+    fn skew_and_drift() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let a = runtime.handle("10.0.0.1".parse().unwrap());
+        let b = runtime.handle("10.0.0.2".parse().unwrap());
+        let secs = time::Duration::from_secs;
+        let ms = time::Duration::from_millis;
+        runtime.block_on(async {
+            let start = a.now();
+            let wall = a.system_time();
+            a.clock().set_skew(ClockOffset::Ahead(ms(100)));
+            a.clock().set_drift(1000.0);
+            b.clock().set_skew(ClockOffset::Behind(ms(50)));
+            a.delay_from(secs(10)).await;
+
+            assert_eq!(a.clock().offset(), ClockOffset::Ahead(ms(110)));
+            assert_eq!(a.clock().system_time(), wall + secs(10) + ms(110));
+            assert_eq!(a.clock().now(), start + secs(10) + ms(10));
+            assert_eq!(b.clock().system_time(), wall + secs(10) - ms(50));
+            assert_eq!(b.clock().now(), start + secs(10));
+
+            // A fast clock considers a lease expired early.
+            let before = a.now();
+            a.clock().delay_from(secs(1)).await;
+            assert!(a.now() - before < secs(1));
+
+            a.clock().set_drift(0.0);
+            a.clock().step(ClockOffset::Behind(secs(1)));
+            let offset = a.clock().offset();
+            let monotonic = a.clock().now() - a.now();
+            a.clock().smear(ClockOffset::Ahead(secs(1)), secs(10));
+            a.delay_from(secs(5)).await;
+            assert_eq!(a.clock().now() - a.now(), monotonic + ms(500));
+            a.delay_from(secs(10)).await;
+            let expected = offset.nanos() + secs(1).as_nanos() as i128;
+            assert_eq!(a.clock().offset(), ClockOffset::from_nanos(expected));
+        });
+    }
+}
@@ -0,0 +1,414 @@
+//! Compression modelled over simulated connections.
+//!
+//! A [`CompressedStream`] wraps both ends of a simulated connection in a framing layer which
+//! behaves like a compressor without compressing anything. Payload bytes are passed through
+//! unchanged, while the link paces them as if they had been shrunk by the configured ratio and
+//! each frame charges compression or decompression work to the CPU of the local host in virtual
+//! time. This allows logic trading bandwidth for CPU, such as deciding when to enable
+//! compression, to be tested against constrained network profiles and contended hosts.
+//!
+//! Frames carry a checksum of their payload, so corrupted frames are reported to the reader as
+//! `InvalidData` errors where a real decompressor would fail to decode them. Corruption can be
+//! injected with [`Compression::corruption`].
+//!
+//! [`CompressedStream`]:`CompressedStream`
+//! [`Compression::corruption`]:`Compression::corruption`
+use super::{ConnectionId, ConnectionIdExt, DeterministicRuntimeHandle, Socket};
+use futures::{FutureExt, Poll};
+use std::{cmp, io, net, pin::Pin, task::Context, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::timer::Delay;
+use tracing::trace;
+
+/// Largest payload carried by a single frame.
+const MAX_FRAME: usize = 64 * 1024;
+/// Length of the frame header, holding the payload length and checksum.
+const HEADER: usize = 8;
+
+/// Model of a compression algorithm applied by a [`CompressedStream`].
+///
+/// [`CompressedStream`]:`CompressedStream`
+#[derive(Debug, Clone)]
+pub struct Compression {
+    ratio: f64,
+    compress_cost: time::Duration,
+    decompress_cost: time::Duration,
+    corruption: f64,
+}
+
+impl Compression {
+    /// Creates a model which shrinks payloads to `ratio` of their size on the wire, at no CPU
+    /// cost. Ratios above 1 model incompressible payloads which grow when compressed.
+    pub fn new(ratio: f64) -> Self {
+        assert!(ratio > 0.0, "compression ratio must be greater than zero");
+        Self {
+            ratio,
+            compress_cost: time::Duration::from_millis(0),
+            decompress_cost: time::Duration::from_millis(0),
+            corruption: 0.0,
+        }
+    }
+
+    /// Sets the CPU time taken to compress each KiB of payload.
+    pub fn compress_cost(mut self, per_kib: time::Duration) -> Self {
+        self.compress_cost = per_kib;
+        self
+    }
+
+    /// Sets the CPU time taken to decompress each KiB of payload.
+    pub fn decompress_cost(mut self, per_kib: time::Duration) -> Self {
+        self.decompress_cost = per_kib;
+        self
+    }
+
+    /// Sets the probability that each written frame has a byte of its payload flipped, causing
+    /// the reader to reject it.
+    pub fn corruption(mut self, probability: f64) -> Self {
+        self.corruption = probability;
+        self
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+}
+
+/// Returns the CPU time taken to process `len` bytes at `per_kib`.
+fn cost(per_kib: time::Duration, len: usize) -> time::Duration {
+    time::Duration::from_nanos((per_kib.as_nanos() * len as u128 / 1024) as u64)
+}
+
+/// FNV-1a hash of the payload. Any single corrupted byte changes the checksum.
+fn checksum(payload: &[u8]) -> u32 {
+    payload.iter().fold(0x811c_9dc5, |hash: u32, byte| {
+        (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+    })
+}
+
+#[derive(Debug)]
+enum WriteState {
+    Idle,
+    /// Waiting for `len` bytes of the caller's buffer to be compressed.
+    Compressing {
+        len: usize,
+        delay: Delay,
+    },
+    /// Writing an encoded frame to the underlying stream.
+    Flushing {
+        frame: Vec<u8>,
+        written: usize,
+    },
+}
+
+#[derive(Debug)]
+struct ReadState {
+    header: [u8; HEADER],
+    header_read: usize,
+    payload: Vec<u8>,
+    payload_read: usize,
+    /// Work decompressing the current payload, if it has been read in full.
+    decompressing: Option<Delay>,
+    decoded: Vec<u8>,
+    decoded_read: usize,
+}
+
+/// A simulated connection with modelled compression. Both ends of the connection must be
+/// wrapped with the same [`Compression`].
+///
+/// Writes are accepted once the written bytes have been compressed, and the resulting frame is
+/// written to the connection before further bytes are accepted or the stream is flushed.
+///
+/// [`Compression`]:`Compression`
+#[derive(Debug)]
+pub struct CompressedStream {
+    handle: DeterministicRuntimeHandle,
+    inner: Socket,
+    compression: Compression,
+    write: WriteState,
+    read: ReadState,
+}
+
+impl CompressedStream {
+    /// Wraps a stream created by `handle`, charging compression work to the host it is scoped
+    /// to.
+    pub fn new(
+        handle: DeterministicRuntimeHandle,
+        inner: Socket,
+        compression: Compression,
+    ) -> Self {
+        inner.set_wire_ratio(compression.ratio);
+        Self {
+            handle,
+            inner,
+            compression,
+            write: WriteState::Idle,
+            read: ReadState {
+                header: [0; HEADER],
+                header_read: 0,
+                payload: vec![],
+                payload_read: 0,
+                decompressing: None,
+                decoded: vec![],
+                decoded_read: 0,
+            },
+        }
+    }
+
+    pub fn get_ref(&self) -> &Socket {
+        &self.inner
+    }
+
+    /// Encodes a frame carrying `payload`, corrupting it if a corruption fault is injected.
+    fn encode(&self, payload: &[u8]) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        frame.extend_from_slice(&checksum(payload).to_be_bytes());
+        frame.extend_from_slice(payload);
+        let random = self.handle.random_handle();
+        if !payload.is_empty()
+            && self.compression.corruption > 0.0
+            && random.should_fault(self.compression.corruption)
+        {
+            let offset = HEADER + random.gen_range(0..payload.len());
+            trace!("corrupting compressed frame at offset {}", offset);
+            frame[offset] ^= 0xff;
+        }
+        frame
+    }
+
+    /// Writes any pending frame to the underlying stream.
+    fn poll_write_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let WriteState::Flushing { frame, written } = &mut self.write {
+            while *written < frame.len() {
+                let n =
+                    futures::ready!(Pin::new(&mut self.inner).poll_write(cx, &frame[*written..]))?;
+                if n == 0 {
+                    return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                }
+                *written += n;
+            }
+            self.write = WriteState::Idle;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for CompressedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match &mut this.write {
+                WriteState::Flushing { .. } => futures::ready!(this.poll_write_frame(cx))?,
+                WriteState::Idle => {
+                    if buf.is_empty() {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let len = cmp::min(buf.len(), MAX_FRAME);
+                    let delay = this
+                        .handle
+                        .consume_cpu(cost(this.compression.compress_cost, len));
+                    this.write = WriteState::Compressing { len, delay };
+                }
+                WriteState::Compressing { len, delay } => {
+                    futures::ready!(delay.poll_unpin(cx));
+                    let len = cmp::min(*len, buf.len());
+                    let frame = this.encode(&buf[..len]);
+                    this.write = WriteState::Flushing { frame, written: 0 };
+                    return Poll::Ready(Ok(len));
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_write_frame(cx))?;
+        Pin::new(&mut this.inner).poll_shutdown(cx)
+    }
+}
+
+impl AsyncRead for CompressedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let read = &mut this.read;
+        loop {
+            if read.decoded_read < read.decoded.len() {
+                let decoded = &read.decoded[read.decoded_read..];
+                let n = cmp::min(buf.len(), decoded.len());
+                buf[..n].copy_from_slice(&decoded[..n]);
+                read.decoded_read += n;
+                return Poll::Ready(Ok(n));
+            }
+            if let Some(delay) = read.decompressing.as_mut() {
+                futures::ready!(delay.poll_unpin(cx));
+                read.decompressing = None;
+                read.decoded = std::mem::replace(&mut read.payload, vec![]);
+                read.decoded_read = 0;
+                continue;
+            }
+            if read.header_read < HEADER {
+                let n =
+                    futures::ready!(Pin::new(&mut this.inner)
+                        .poll_read(cx, &mut read.header[read.header_read..]))?;
+                if n == 0 {
+                    if read.header_read == 0 {
+                        return Poll::Ready(Ok(0));
+                    }
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame");
+                    return Poll::Ready(Err(err));
+                }
+                read.header_read += n;
+                if read.header_read == HEADER {
+                    let mut len = [0; 4];
+                    len.copy_from_slice(&read.header[..4]);
+                    let len = u32::from_be_bytes(len) as usize;
+                    if len > MAX_FRAME {
+                        let err =
+                            io::Error::new(io::ErrorKind::InvalidData, "corrupt frame length");
+                        return Poll::Ready(Err(err));
+                    }
+                    read.payload = vec![0; len];
+                    read.payload_read = 0;
+                }
+                continue;
+            }
+            if read.payload_read < read.payload.len() {
+                let n =
+                    futures::ready!(Pin::new(&mut this.inner)
+                        .poll_read(cx, &mut read.payload[read.payload_read..]))?;
+                if n == 0 {
+                    let err = io::Error::new(io::ErrorKind::UnexpectedEof, "truncated frame");
+                    return Poll::Ready(Err(err));
+                }
+                read.payload_read += n;
+                continue;
+            }
+            let mut expected = [0; 4];
+            expected.copy_from_slice(&read.header[4..]);
+            if checksum(&read.payload) != u32::from_be_bytes(expected) {
+                let err = io::Error::new(io::ErrorKind::InvalidData, "corrupt compressed frame");
+                return Poll::Ready(Err(err));
+            }
+            read.header_read = 0;
+            let work = cost(this.compression.decompress_cost, read.payload.len());
+            read.decompressing = Some(this.handle.consume_cpu(work));
+        }
+    }
+}
+
+impl ConnectionIdExt for CompressedStream {
+    fn connection_id(&self) -> Option<ConnectionId> {
+        self.inner.connection_id()
+    }
+}
+
+impl crate::TcpStream for CompressedStream {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, NetworkProfile};
+    use crate::{Environment, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Sends `len` bytes from a client to a server over a 1MB/s network, returning the virtual
+    /// time taken and the result of the server reading them.
+    fn transfer(
+        compression: Option<Compression>,
+        len: usize,
+    ) -> (time::Duration, io::Result<Vec<u8>>) {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let profile = NetworkProfile::new(time::Duration::from_millis(0)).bandwidth(1_000_000);
+        runtime.set_network_profile(Some(profile));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let start = handle.now();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let server_handle = handle.clone();
+            let server_compression = compression.clone();
+            handle.spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; len];
+                let result = match server_compression {
+                    Some(compression) => {
+                        CompressedStream::new(server_handle, socket, compression)
+                            .read_exact(&mut buf)
+                            .await
+                    }
+                    None => {
+                        let mut socket = socket;
+                        socket.read_exact(&mut buf).await
+                    }
+                };
+                let _ = tx.send(result.map(|_| buf));
+            });
+            let socket = handle.connect(addr).await.unwrap();
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            // Keep the client stream open until the server has read the payload.
+            let result = match compression {
+                Some(compression) => {
+                    let mut socket = CompressedStream::new(handle.clone(), socket, compression);
+                    socket.write_all(&payload).await.unwrap();
+                    socket.flush().await.unwrap();
+                    rx.await.unwrap()
+                }
+                None => {
+                    let mut socket = socket;
+                    socket.write_all(&payload).await.unwrap();
+                    rx.await.unwrap()
+                }
+            };
+            (handle.now() - start, result)
+        })
+    }
+
+    #[test]
+    /// Test that compressed payloads arrive intact, and that the transfer time reflects both the
+    /// reduced bandwidth and the CPU cost of compression.
+    fn tradeoff() {
+        let len = 200_000;
+        let (uncompressed, result) = transfer(None, len);
+        assert_eq!(result.unwrap().len(), len);
+        let (compressed, result) = transfer(Some(Compression::new(0.25)), len);
+        let expected: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        assert_eq!(result.unwrap(), expected);
+        assert!(compressed * 3 < uncompressed);
+
+        let expensive = Compression::new(0.25)
+            .compress_cost(time::Duration::from_millis(1))
+            .decompress_cost(time::Duration::from_millis(1));
+        let (slow, result) = transfer(Some(expensive), len);
+        assert!(result.is_ok());
+        assert!(slow > uncompressed);
+    }
+
+    #[test]
+    /// Test that corrupted frames are rejected by the reader.
+    fn corruption() {
+        let (_, result) = transfer(Some(Compression::new(0.5).corruption(1.0)), 1000);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
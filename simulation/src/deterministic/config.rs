@@ -0,0 +1,292 @@
+//! Simulations constructed from declarative specs.
+//!
+//! Scenarios maintained by people who do not write Rust are easier to keep as data. A
+//! [`SimulationSpec`] describes the hosts of a simulation, the network profiles of the links
+//! between them and a schedule of faults, and can be deserialized from any serde format.
+//! [`Simulation::from_config`] parses a spec from TOML:
+//!
+//! ```toml
+//! seed = 7
+//!
+//! [[hosts]]
+//! name = "client"
+//!
+//! [[hosts]]
+//! name = "server"
+//! ip = "10.0.1.1"
+//!
+//! [[links]]
+//! a = "client"
+//! b = "server"
+//! latency_ms = 20
+//! jitter_ms = 5
+//!
+//! [[faults]]
+//! kind = "partition"
+//! at_ms = 10000
+//! duration_ms = 30000
+//! a = ["client"]
+//! b = ["server"]
+//! ```
+//!
+//! Hosts without an address are allocated one, and are referred to by name from links and
+//! faults, which also accept addresses. Building the simulation uses the regular builder API,
+//! so the runtime can be configured further before running. The fault schedule is applied from
+//! the start of the first run. Requires the `config` feature.
+//!
+//! [`SimulationSpec`]:`SimulationSpec`
+//! [`Simulation::from_config`]:`Simulation::from_config`
+use super::{FaultScheduleBuilder, NetworkProfile, PartitionMode, Simulation};
+use serde::Deserialize;
+use std::{collections, error, fmt, net, time};
+
+/// The hosts, links and faults of a simulation, see the [module documentation](self).
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SimulationSpec {
+    #[serde(default)]
+    pub seed: u64,
+    #[serde(default)]
+    pub hosts: Vec<HostSpec>,
+    #[serde(default)]
+    pub links: Vec<LinkSpec>,
+    #[serde(default)]
+    pub faults: Vec<FaultSpec>,
+}
+
+/// A host registered under its name, at the provided address or a newly allocated one.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HostSpec {
+    pub name: String,
+    pub ip: Option<net::IpAddr>,
+}
+
+/// The network profile of the connections between hosts `a` and `b`, in either direction.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LinkSpec {
+    pub a: String,
+    pub b: String,
+    #[serde(default)]
+    pub latency_ms: u64,
+    pub jitter_ms: Option<u64>,
+    pub bandwidth: Option<u64>,
+    pub loss: Option<f64>,
+}
+
+/// A fault applied at an offset from the start of the run.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum FaultSpec {
+    /// Partitions the hosts of `a` from the hosts of `b`, stalling their connections unless
+    /// `reset` is set, and heals the partition once `duration_ms` has elapsed.
+    Partition {
+        at_ms: u64,
+        duration_ms: u64,
+        a: Vec<String>,
+        b: Vec<String>,
+        #[serde(default)]
+        reset: bool,
+    },
+    /// Resets every connection to or from the host.
+    ResetHost { at_ms: u64, host: String },
+}
+
+/// Error returned when a simulation cannot be built from a spec.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The spec could not be parsed.
+    Parse { source: toml::de::Error },
+    /// A link or fault refers to a host which is neither declared nor an address.
+    UnknownHost { name: String },
+    /// Two hosts are declared with the same name.
+    DuplicateHost { name: String },
+    /// The runtime of the simulation could not be created.
+    Runtime { source: crate::Error },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Parse { source } => write!(f, "invalid simulation spec: {}", source),
+            ConfigError::UnknownHost { name } => write!(f, "unknown host {:?}", name),
+            ConfigError::DuplicateHost { name } => write!(f, "host {:?} declared twice", name),
+            ConfigError::Runtime { source } => write!(f, "{}", source),
+        }
+    }
+}
+
+impl error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            ConfigError::Parse { source } => Some(source),
+            ConfigError::Runtime { source } => Some(source),
+            ConfigError::UnknownHost { .. } | ConfigError::DuplicateHost { .. } => None,
+        }
+    }
+}
+
+fn millis(ms: u64) -> time::Duration {
+    time::Duration::from_millis(ms)
+}
+
+impl LinkSpec {
+    fn profile(&self) -> NetworkProfile {
+        let mut profile = NetworkProfile::new(millis(self.latency_ms));
+        if let Some(jitter) = self.jitter_ms {
+            profile = profile.jitter(millis(jitter));
+        }
+        if let Some(bandwidth) = self.bandwidth {
+            profile = profile.bandwidth(bandwidth);
+        }
+        if let Some(loss) = self.loss {
+            profile = profile.loss(loss);
+        }
+        profile
+    }
+}
+
+impl Simulation {
+    /// Parses a [`SimulationSpec`] from TOML and builds a simulation from it, see
+    /// [`from_spec`].
+    ///
+    /// [`SimulationSpec`]:`SimulationSpec`
+    /// [`from_spec`]:`Simulation::from_spec`
+    pub fn from_config(config: &str) -> Result<Self, ConfigError> {
+        let spec = toml::from_str(config).map_err(|source| ConfigError::Parse { source })?;
+        Self::from_spec(&spec)
+    }
+
+    /// Builds a simulation from the provided spec, seeded with its seed. Hosts are registered
+    /// by name, link profiles are applied and the fault schedule is applied from the start of
+    /// the first run.
+    pub fn from_spec(spec: &SimulationSpec) -> Result<Self, ConfigError> {
+        let mut simulation =
+            Simulation::new(spec.seed).map_err(|source| ConfigError::Runtime { source })?;
+        let handle = simulation.handle();
+        let mut hosts = collections::HashMap::new();
+        for host in spec.hosts.iter() {
+            let addr = match host.ip {
+                Some(ip) => {
+                    let handle = simulation.runtime().handle(ip);
+                    handle.network_handle.name_host(host.name.clone(), ip);
+                    ip
+                }
+                None => handle.spawn_host(host.name.clone()).handle().local_addr(),
+            };
+            if hosts.insert(host.name.as_str(), addr).is_some() {
+                return Err(ConfigError::DuplicateHost {
+                    name: host.name.clone(),
+                });
+            }
+        }
+        let resolve = |name: &String| {
+            hosts
+                .get(name.as_str())
+                .cloned()
+                .or_else(|| name.parse().ok())
+                .ok_or_else(|| ConfigError::UnknownHost { name: name.clone() })
+        };
+        let resolve_all =
+            |names: &[String]| names.iter().map(&resolve).collect::<Result<Vec<_>, _>>();
+
+        for link in spec.links.iter() {
+            let (a, b) = (resolve(&link.a)?, resolve(&link.b)?);
+            simulation
+                .runtime()
+                .set_link_profile(a, b, Some(link.profile()));
+        }
+        let mut schedule = FaultScheduleBuilder::new();
+        for fault in spec.faults.iter() {
+            schedule = match fault {
+                FaultSpec::Partition {
+                    at_ms,
+                    duration_ms,
+                    a,
+                    b,
+                    reset,
+                } => {
+                    let mode = if *reset {
+                        PartitionMode::Reset
+                    } else {
+                        PartitionMode::Stall
+                    };
+                    let (a, b) = (resolve_all(a)?, resolve_all(b)?);
+                    schedule.partition(millis(*at_ms), millis(*duration_ms), &a, &b, mode)
+                }
+                FaultSpec::ResetHost { at_ms, host } => {
+                    schedule.reset_host(millis(*at_ms), resolve(host)?)
+                }
+            };
+        }
+        if !spec.faults.is_empty() {
+            let injector = simulation.runtime().schedule_faults(schedule);
+            simulation.inject_on_run(injector);
+        }
+        Ok(simulation)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    const SPEC: &str = r#"
+        seed = 3
+
+        [[hosts]]
+        name = "client"
+
+        [[hosts]]
+        name = "server"
+        ip = "10.0.1.1"
+
+        [[links]]
+        a = "client"
+        b = "server"
+        latency_ms = 20
+
+        [[faults]]
+        kind = "reset_host"
+        at_ms = 5000
+        host = "server"
+    "#;
+
+    #[test]
+    /// Test that a simulation built from a TOML spec registers its hosts by name, applies the
+    /// latency of its links and injects its faults, and that unknown hosts are reported.
+    fn from_config() {
+        let mut simulation = Simulation::from_config(SPEC).unwrap();
+        assert_eq!(simulation.seed().value(), 3);
+        let client = simulation.host("client").unwrap();
+        let server = simulation.host("server").unwrap();
+        assert_eq!(
+            server.local_addr(),
+            "10.0.1.1".parse::<net::IpAddr>().unwrap()
+        );
+        simulation.run(async move {
+            let addr = net::SocketAddr::new(server.local_addr(), 9092);
+            let mut listener = server.bind(addr).await.unwrap();
+            server.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(b"a").await.unwrap();
+                futures::future::pending::<()>().await;
+            });
+            let start = client.now();
+            let mut socket = client.connect(addr).await.unwrap();
+            socket.read_exact(&mut [0u8; 1]).await.unwrap();
+            assert!(client.now() - start >= time::Duration::from_millis(20));
+            assert!(socket.read(&mut [0u8; 1]).await.is_err());
+            assert!(client.now() - start >= time::Duration::from_secs(5));
+        });
+
+        let spec = SPEC.replace(r#"host = "server""#, r#"host = "missing""#);
+        match Simulation::from_config(&spec) {
+            Err(ConfigError::UnknownHost { name }) => assert_eq!(name, "missing"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Coverage of the decision points of a run.
+//!
+//! Most seeds drive a simulation down the same happy path, so running more seeds mostly
+//! re-explores what earlier seeds already exercised. The runtime reports each decision it
+//! makes which can steer a run onto a rare path, such as an active buggify site firing, a fault
+//! being injected into a connection or shuffled scheduling yielding a task, to the
+//! [`Observer::decision`] hook. A [`CoverageRecorder`] collects the distinct decision points of
+//! a run into a [`Coverage`], which can be compared with the coverage of other runs to find the
+//! seeds which exercised something new.
+//!
+//! [`Observer::decision`]:`super::Observer::decision`
+//! [`CoverageRecorder`]:`CoverageRecorder`
+//! [`Coverage`]:`Coverage`
+use super::{FaultKind, Observer};
+use std::{collections, sync, time};
+
+/// A decision made by the runtime which can steer a run onto a rare path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DecisionPoint {
+    /// An active buggify site was evaluated, taking its rare path if `fired` is set.
+    Buggify { site: String, fired: bool },
+    /// A fault of the provided kind was injected into a connection.
+    Fault(FaultKind),
+    /// Shuffled scheduling decided whether a task yields to the other runnable tasks, which are
+    /// identified by the name the task was spawned with.
+    Yield { task: Option<String>, yielded: bool },
+}
+
+/// The distinct decision points exercised by one or more runs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Coverage {
+    points: collections::BTreeSet<DecisionPoint>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the decision points, ordered by kind and then by site or task.
+    pub fn points(&self) -> impl Iterator<Item = &DecisionPoint> {
+        self.points.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    pub fn contains(&self, point: &DecisionPoint) -> bool {
+        self.points.contains(point)
+    }
+
+    /// Returns true if the point was not covered before.
+    pub fn insert(&mut self, point: DecisionPoint) -> bool {
+        self.points.insert(point)
+    }
+
+    /// Returns the decision points covered by this coverage which `seen` does not cover.
+    pub fn novel(&self, seen: &Coverage) -> Coverage {
+        let points = self.points.difference(&seen.points).cloned().collect();
+        Coverage { points }
+    }
+
+    /// Adds the decision points of `other`, returning the number which were not covered before.
+    pub fn merge(&mut self, other: &Coverage) -> usize {
+        let before = self.points.len();
+        self.points.extend(other.points.iter().cloned());
+        self.points.len() - before
+    }
+}
+
+/// Observer collecting the coverage of the runtime it is registered with.
+#[derive(Debug, Default)]
+pub struct CoverageRecorder {
+    coverage: sync::Mutex<Coverage>,
+}
+
+impl CoverageRecorder {
+    pub fn new() -> sync::Arc<Self> {
+        sync::Arc::default()
+    }
+
+    /// Returns the decision points exercised since the recorder was registered.
+    pub fn coverage(&self) -> Coverage {
+        self.coverage.lock().unwrap().clone()
+    }
+}
+
+impl Observer for CoverageRecorder {
+    fn decision(&self, _: time::Instant, point: &DecisionPoint) {
+        let mut coverage = self.coverage.lock().unwrap();
+        if !coverage.contains(point) {
+            coverage.insert(point.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::net;
+
+    fn run(seed: u64) -> Coverage {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let recorder = CoverageRecorder::new();
+        runtime.add_observer(recorder.clone());
+        runtime.set_shuffle_scheduling(true);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            for site in 0..8 {
+                handle.buggify_site(&format!("site-{}", site), 0.5);
+            }
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            for name in ["a", "b"].iter() {
+                handle.spawn_named(*name, async {});
+            }
+            let socket = handle.connect(addr).await.unwrap();
+            let _accepted = listener.accept().await.unwrap();
+            socket.fault_handle().disconnect();
+            handle.delay_from(time::Duration::from_secs(1)).await;
+        });
+        recorder.coverage()
+    }
+
+    #[test]
+    /// Test that the recorder collects buggify, fault and scheduling decisions, and that the
+    /// coverage of a seed is reproduced by rerunning it.
+    fn record_decisions() {
+        let coverage = run(3);
+        assert_eq!(coverage, run(3));
+        assert!(coverage.contains(&DecisionPoint::Fault(FaultKind::Disconnect)));
+        assert!(coverage.points().any(|point| match point {
+            DecisionPoint::Yield { task, .. } => task.as_ref().map(String::as_str) == Some("a"),
+            _ => false,
+        }));
+
+        let mut seen = Coverage::new();
+        let mut buggified = 0;
+        for seed in 0..16 {
+            let coverage = run(seed);
+            let novel = coverage.novel(&seen);
+            assert_eq!(seen.merge(&coverage), novel.len());
+            buggified += novel
+                .points()
+                .filter(|point| match point {
+                    DecisionPoint::Buggify { .. } => true,
+                    _ => false,
+                })
+                .count();
+        }
+        assert!(
+            buggified > 0,
+            "expected some seeds to activate buggify sites"
+        );
+    }
+}
@@ -0,0 +1,180 @@
+//! Contention for the virtual cores of a simulated host.
+//!
+//! Work charged with [`DeterministicRuntimeHandle::consume_cpu`] occupies one of the virtual cores
+//! of the host the handle is scoped to. Once every core is busy, further charges queue behind
+//! the earliest finishing core, so workloads sharing a host slow each other down. Hosts which
+//! have not been assigned a number of cores have an unlimited number.
+//!
+//! Hosts can also be assigned a cost charged for every poll of their tasks with
+//! [`DeterministicRuntimeHandle::set_poll_cost`]. A woken task is then only polled once the
+//! cost of the poll has run on one of the cores of its host, so tasks which are polled often
+//! delay the other tasks of their host.
+//!
+//! [`DeterministicRuntimeHandle::consume_cpu`]:`super::DeterministicRuntimeHandle::consume_cpu`
+//! [`DeterministicRuntimeHandle::set_poll_cost`]:`super::DeterministicRuntimeHandle::set_poll_cost`
+use std::{collections, net, sync, time};
+
+#[derive(Debug)]
+struct HostCpu {
+    /// Time at which each core finishes the work queued on it.
+    cores: Vec<time::Instant>,
+    /// Total time charged to the host.
+    consumed: time::Duration,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Cpus {
+    hosts: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, HostCpu>>>,
+    /// Cost charged for each poll of the tasks of a host.
+    poll_costs: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, time::Duration>>>,
+}
+
+impl Cpus {
+    pub(crate) fn set_cores(&self, host: net::IpAddr, cores: Option<usize>, now: time::Instant) {
+        let mut hosts = self.hosts.lock().unwrap();
+        match cores {
+            Some(cores) => {
+                assert!(cores > 0, "hosts must have at least one core");
+                let host = hosts.entry(host).or_insert_with(|| HostCpu {
+                    cores: vec![],
+                    consumed: time::Duration::from_secs(0),
+                });
+                // Work already queued on removed cores keeps running to completion.
+                host.cores.sort();
+                host.cores.resize(cores, now);
+            }
+            None => {
+                hosts.remove(&host);
+            }
+        }
+    }
+
+    /// Queues `duration` of work on the host, returning the time at which it completes.
+    pub(crate) fn charge(
+        &self,
+        host: net::IpAddr,
+        duration: time::Duration,
+        now: time::Instant,
+    ) -> time::Instant {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = match hosts.get_mut(&host) {
+            Some(host) => host,
+            None => return now + duration,
+        };
+        host.consumed += duration;
+        let core = host
+            .cores
+            .iter_mut()
+            .min()
+            .expect("hosts have at least one core");
+        let finish = std::cmp::max(*core, now) + duration;
+        *core = finish;
+        finish
+    }
+
+    pub(crate) fn set_poll_cost(&self, host: net::IpAddr, cost: Option<time::Duration>) {
+        let mut poll_costs = self.poll_costs.lock().unwrap();
+        match cost {
+            Some(cost) => poll_costs.insert(host, cost),
+            None => poll_costs.remove(&host),
+        };
+    }
+
+    /// Queues the cost of a poll of a task of the host, returning the time at which the poll
+    /// can run, or `None` if it can run now.
+    pub(crate) fn charge_poll(
+        &self,
+        host: net::IpAddr,
+        now: time::Instant,
+    ) -> Option<time::Instant> {
+        let cost = *self.poll_costs.lock().unwrap().get(&host)?;
+        let finish = self.charge(host, cost, now);
+        if finish > now {
+            Some(finish)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the total time charged to the host, if it has been assigned cores.
+    pub(crate) fn consumed(&self, host: net::IpAddr) -> Option<time::Duration> {
+        self.hosts
+            .lock()
+            .unwrap()
+            .get(&host)
+            .map(|host| host.consumed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::{sync, time::Duration};
+
+    #[test]
+    /// Test that charges beyond the number of cores queue, slowing down co-located work.
+    fn contention() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let neighbor = handle.add_host();
+        handle.set_cores(Some(2));
+        let finished = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async {
+            let start = handle.now();
+            for _ in 0..4 {
+                let handle = handle.clone();
+                let finished = sync::Arc::clone(&finished);
+                handle.clone().spawn(async move {
+                    handle.consume_cpu(Duration::from_millis(100)).await;
+                    finished.lock().unwrap().push(handle.now() - start);
+                });
+            }
+            neighbor.consume_cpu(Duration::from_millis(100)).await;
+            assert_eq!(neighbor.now() - start, Duration::from_millis(100));
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(handle.cpu_consumed(), Some(Duration::from_millis(400)));
+            assert_eq!(neighbor.cpu_consumed(), None);
+        });
+        let finished = finished.lock().unwrap();
+        assert_eq!(
+            *finished,
+            vec![
+                Duration::from_millis(100),
+                Duration::from_millis(100),
+                Duration::from_millis(200),
+                Duration::from_millis(200),
+            ]
+        );
+    }
+
+    #[test]
+    /// Test that the cost of polls queues on the cores of a host, so that a busy task delays
+    /// the polls of the other tasks of its host.
+    fn poll_cost() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let host = handle.add_host();
+        host.set_cores(Some(1));
+        host.set_poll_cost(Some(Duration::from_millis(10)));
+        let finished = sync::Arc::new(sync::Mutex::new(None));
+        runtime.block_on(async {
+            let start = handle.now();
+            let slow = host.clone();
+            host.spawn(async move {
+                slow.consume_cpu(Duration::from_millis(100)).await;
+            });
+            let fast = host.clone();
+            let fast_finished = sync::Arc::clone(&finished);
+            host.spawn(async move {
+                fast.delay_from(Duration::from_millis(50)).await;
+                fast_finished.lock().unwrap().replace(fast.now() - start);
+            });
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(host.cpu_consumed(), Some(Duration::from_millis(140)));
+        });
+        // The fast task is woken at 70ms, but waits for the slow task's work to finish at 120ms
+        // before its poll runs.
+        assert_eq!(*finished.lock().unwrap(), Some(Duration::from_millis(130)));
+    }
+}
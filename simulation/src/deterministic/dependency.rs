@@ -0,0 +1,463 @@
+//! Simulated external dependencies, such as blob stores and mail relays.
+//!
+//! Most systems under test talk to services they do not own. Rather than each test hand rolling a
+//! flaky stub, an [`ExternalService`] can be added to the simulation as a named host with
+//! [`DeterministicRuntimeHandle::add_dependency`]. The host is registered under the service name,
+//! so clients on any host can find it with [`DeterministicRuntimeHandle::resolve_host`], and
+//! requests reach it over the simulated network, subject to the same partitions and latency as
+//! any other connection. Latency, injected errors and outages specific to the service are
+//! controlled through the returned [`DependencyFaults`].
+//!
+//! Services speak a line based protocol. Each request is a single line, answered with a line
+//! starting with `OK` or `ERR`. [`DependencyClient`] implements the client side for any
+//! [`Environment`], and [`BlobStore`] and [`MailSink`] provide services commonly depended on.
+//!
+//! [`ExternalService`]:`ExternalService`
+//! [`DeterministicRuntimeHandle::add_dependency`]:`super::DeterministicRuntimeHandle::add_dependency`
+//! [`DeterministicRuntimeHandle::resolve_host`]:`super::DeterministicRuntimeHandle::resolve_host`
+//! [`DependencyFaults`]:`DependencyFaults`
+//! [`DependencyClient`]:`DependencyClient`
+//! [`Environment`]:`crate::Environment`
+//! [`BlobStore`]:`BlobStore`
+//! [`MailSink`]:`MailSink`
+use super::{DeterministicRandomHandle, DeterministicRuntimeHandle};
+use crate::{Environment, TcpListener};
+use futures::{SinkExt, StreamExt};
+use std::{collections, io, net, sync, time};
+use tokio::codec::{Framed, LinesCodec};
+use tracing::trace;
+
+/// A simulated external service, handling one request line at a time.
+pub trait ExternalService: Send + 'static {
+    /// Handles a request, returning the response payload or an error message.
+    fn handle(&mut self, request: &str) -> Result<String, String>;
+
+    /// Provides a stream of randomness derived from the seed and the name of the service, for
+    /// services which make random decisions of their own. Called once before any request is
+    /// handled.
+    fn set_random(&mut self, _random: DeterministicRandomHandle) {}
+
+    /// Returns the time taken to serve the request, which is added to the latency configured
+    /// through [`DependencyFaults`]. Requests are served immediately by default.
+    ///
+    /// [`DependencyFaults`]:`DependencyFaults`
+    fn latency(&self, _request: &str) -> time::Duration {
+        time::Duration::from_secs(0)
+    }
+}
+
+#[derive(Debug, Default)]
+struct FaultState {
+    latency: time::Duration,
+    jitter: time::Duration,
+    error_rate: f64,
+    unavailable: bool,
+    requests: u64,
+    injected_errors: u64,
+}
+
+/// Handle used to control the faults of a simulated external dependency.
+#[derive(Debug, Clone)]
+pub struct DependencyFaults {
+    addr: net::SocketAddr,
+    state: sync::Arc<sync::Mutex<FaultState>>,
+}
+
+impl DependencyFaults {
+    /// Returns the address the dependency is listening on.
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Delays each response by `latency` plus a uniformly sampled amount of up to `jitter`.
+    pub fn set_latency(&self, latency: time::Duration, jitter: time::Duration) {
+        let mut lock = self.state.lock().unwrap();
+        lock.latency = latency;
+        lock.jitter = jitter;
+    }
+
+    /// Fails each request with the provided probability, without passing it to the service.
+    pub fn set_error_rate(&self, probability: f64) {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "error rate must be within [0, 1]"
+        );
+        self.state.lock().unwrap().error_rate = probability;
+    }
+
+    /// Simulates an outage. While unavailable, connections are closed without a response as
+    /// soon as a request arrives.
+    pub fn set_unavailable(&self, unavailable: bool) {
+        self.state.lock().unwrap().unavailable = unavailable;
+    }
+
+    /// Returns the number of requests received, including failed requests.
+    pub fn requests(&self) -> u64 {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Returns the number of requests failed by fault injection.
+    pub fn injected_errors(&self) -> u64 {
+        self.state.lock().unwrap().injected_errors
+    }
+}
+
+/// Outcome of applying the dependency faults to a request.
+enum Admission {
+    Handle(time::Duration),
+    Fail(time::Duration),
+    Drop,
+}
+
+impl DependencyFaults {
+    fn admit(&self, random: &DeterministicRandomHandle) -> Admission {
+        let mut lock = self.state.lock().unwrap();
+        lock.requests += 1;
+        if lock.unavailable {
+            return Admission::Drop;
+        }
+        let mut delay = lock.latency;
+        if lock.jitter > time::Duration::from_secs(0) {
+            delay += random.gen_range(time::Duration::from_secs(0)..lock.jitter);
+        }
+        if lock.error_rate > 0.0 && random.should_fault(lock.error_rate) {
+            lock.injected_errors += 1;
+            return Admission::Fail(delay);
+        }
+        Admission::Handle(delay)
+    }
+}
+
+/// Adds a host running the provided service, see
+/// [`DeterministicRuntimeHandle::add_dependency`].
+///
+/// [`DeterministicRuntimeHandle::add_dependency`]:`super::DeterministicRuntimeHandle::add_dependency`
+pub(crate) async fn serve<S>(
+    host: DeterministicRuntimeHandle,
+    name: String,
+    port: u16,
+    mut service: S,
+) -> io::Result<DependencyFaults>
+where
+    S: ExternalService,
+{
+    service.set_random(host.derive_random(&format!("dependency/{}/service", name)));
+    let addr = net::SocketAddr::new(host.local_addr(), port);
+    let mut listener = host.bind(addr).await?;
+    let faults = DependencyFaults {
+        addr,
+        state: sync::Arc::default(),
+    };
+    let service = sync::Arc::new(sync::Mutex::new(service));
+    let random = host.derive_random(&format!("dependency/{}", name));
+    let accept_faults = faults.clone();
+    let accept_host = host.clone();
+    host.spawn_named(name.clone(), async move {
+        while let Ok((socket, peer)) = listener.accept().await {
+            trace!("dependency {} accepted {}", name, peer);
+            let connection = serve_connection(
+                accept_host.clone(),
+                socket,
+                sync::Arc::clone(&service),
+                accept_faults.clone(),
+                random.clone(),
+            );
+            accept_host.spawn_named(format!("{}/{}", name, peer), connection);
+        }
+    });
+    Ok(faults)
+}
+
+async fn serve_connection<S>(
+    host: DeterministicRuntimeHandle,
+    socket: <DeterministicRuntimeHandle as Environment>::TcpStream,
+    service: sync::Arc<sync::Mutex<S>>,
+    faults: DependencyFaults,
+    random: DeterministicRandomHandle,
+) where
+    S: ExternalService,
+{
+    let mut transport = Framed::new(socket, LinesCodec::new());
+    while let Some(Ok(request)) = transport.next().await {
+        let response = match faults.admit(&random) {
+            Admission::Drop => return,
+            Admission::Fail(delay) => {
+                host.delay_from(delay).await;
+                String::from("ERR injected fault")
+            }
+            Admission::Handle(delay) => {
+                let delay = delay + service.lock().unwrap().latency(&request);
+                host.delay_from(delay).await;
+                let result = service.lock().unwrap().handle(&request);
+                match result {
+                    Ok(payload) if payload.is_empty() => String::from("OK"),
+                    Ok(payload) => format!("OK {}", payload),
+                    Err(message) => format!("ERR {}", message),
+                }
+            }
+        };
+        if transport.send(response).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Error returned by a [`DependencyClient`] request.
+///
+/// [`DependencyClient`]:`DependencyClient`
+#[derive(Debug)]
+pub enum DependencyError {
+    /// The connection to the dependency failed or was closed.
+    Io(io::Error),
+    /// The dependency responded with an error.
+    Service(String),
+}
+
+impl std::fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DependencyError::Io(source) => write!(f, "dependency connection failed: {}", source),
+            DependencyError::Service(message) => write!(f, "dependency error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for DependencyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DependencyError::Io(source) => Some(source),
+            DependencyError::Service(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for DependencyError {
+    fn from(source: io::Error) -> Self {
+        DependencyError::Io(source)
+    }
+}
+
+/// Client of a simulated external dependency, generic over the environment so application code
+/// can be pointed at the simulated service.
+pub struct DependencyClient<E: Environment> {
+    transport: Framed<E::TcpStream, LinesCodec>,
+}
+
+impl<E: Environment> std::fmt::Debug for DependencyClient<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "DependencyClient")
+    }
+}
+
+impl<E: Environment> DependencyClient<E> {
+    pub async fn connect(env: &E, addr: net::SocketAddr) -> io::Result<Self> {
+        let socket = env.connect(addr).await?;
+        Ok(Self {
+            transport: Framed::new(socket, LinesCodec::new()),
+        })
+    }
+
+    /// Sends a request line, returning the response payload.
+    pub async fn request(&mut self, request: &str) -> Result<String, DependencyError> {
+        let closed = || io::Error::new(io::ErrorKind::ConnectionAborted, "dependency closed");
+        self.transport
+            .send(request.to_string())
+            .await
+            .map_err(|_| closed())?;
+        let response = match self.transport.next().await {
+            Some(Ok(response)) => response,
+            _ => return Err(closed().into()),
+        };
+        if response == "OK" {
+            Ok(String::new())
+        } else if response.starts_with("OK ") {
+            Ok(response[3..].to_string())
+        } else {
+            let message = response.trim_start_matches("ERR").trim_start();
+            Err(DependencyError::Service(message.to_string()))
+        }
+    }
+}
+
+/// An S3-like blob store. Keys may not contain whitespace and values may not contain newlines.
+///
+/// Supports `PUT {key} {value}`, `GET {key}`, `DELETE {key}` and `LIST {prefix}`, where `LIST`
+/// responds with the matching keys separated by spaces. Clones share the stored objects,
+/// allowing tests to inspect the store.
+#[derive(Debug, Clone, Default)]
+pub struct BlobStore {
+    objects: sync::Arc<sync::Mutex<collections::BTreeMap<String, String>>>,
+}
+
+impl BlobStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the value stored under the provided key.
+    pub fn object(&self, key: &str) -> Option<String> {
+        self.objects.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.objects.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl ExternalService for BlobStore {
+    fn handle(&mut self, request: &str) -> Result<String, String> {
+        let mut parts = request.splitn(3, ' ');
+        let mut objects = self.objects.lock().unwrap();
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("PUT"), Some(key), Some(value)) => {
+                objects.insert(key.to_string(), value.to_string());
+                Ok(String::new())
+            }
+            (Some("GET"), Some(key), None) => objects
+                .get(key)
+                .cloned()
+                .ok_or_else(|| String::from("not found")),
+            (Some("DELETE"), Some(key), None) => objects
+                .remove(key)
+                .map(|_| String::new())
+                .ok_or_else(|| String::from("not found")),
+            (Some("LIST"), prefix, None) => {
+                let prefix = prefix.unwrap_or("");
+                let keys: Vec<&str> = objects
+                    .keys()
+                    .filter(|key| key.starts_with(prefix))
+                    .map(String::as_str)
+                    .collect();
+                Ok(keys.join(" "))
+            }
+            _ => Err(format!("malformed request: {}", request)),
+        }
+    }
+}
+
+/// A mail delivered to a [`MailSink`].
+///
+/// [`MailSink`]:`MailSink`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mail {
+    pub to: String,
+    pub body: String,
+}
+
+/// An SMTP-like endpoint recording delivered mail. Supports `SEND {to} {body}`. Clones share
+/// the delivered mail, allowing tests to inspect it.
+#[derive(Debug, Clone, Default)]
+pub struct MailSink {
+    delivered: sync::Arc<sync::Mutex<Vec<Mail>>>,
+}
+
+impl MailSink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the mail delivered so far, in the order it was received.
+    pub fn delivered(&self) -> Vec<Mail> {
+        self.delivered.lock().unwrap().clone()
+    }
+}
+
+impl ExternalService for MailSink {
+    fn handle(&mut self, request: &str) -> Result<String, String> {
+        let mut parts = request.splitn(3, ' ');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("SEND"), Some(to), Some(body)) => {
+                self.delivered.lock().unwrap().push(Mail {
+                    to: to.to_string(),
+                    body: body.to_string(),
+                });
+                Ok(String::new())
+            }
+            _ => Err(format!("malformed request: {}", request)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that dependencies are reachable by name from other hosts and honor their faults.
+    fn blob_store() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let store = BlobStore::new();
+        runtime.block_on(async {
+            let faults = handle
+                .add_dependency("s3", 443, store.clone())
+                .await
+                .unwrap();
+            let client_host = handle.add_host();
+            let addr = net::SocketAddr::new(client_host.resolve_host("s3").unwrap(), 443);
+            assert_eq!(addr, faults.addr());
+            let mut client = DependencyClient::connect(&client_host, addr).await.unwrap();
+
+            faults.set_latency(
+                time::Duration::from_millis(50),
+                time::Duration::from_secs(0),
+            );
+            let start = client_host.now();
+            client.request("PUT a/1 hello world").await.unwrap();
+            assert_eq!(client_host.now() - start, time::Duration::from_millis(50));
+            client.request("PUT a/2 x").await.unwrap();
+            assert_eq!(client.request("GET a/1").await.unwrap(), "hello world");
+            assert_eq!(client.request("LIST a/").await.unwrap(), "a/1 a/2");
+            match client.request("GET missing").await {
+                Err(DependencyError::Service(message)) => assert_eq!(message, "not found"),
+                other => panic!("expected not found, got {:?}", other),
+            }
+
+            faults.set_error_rate(1.0);
+            assert!(client.request("GET a/1").await.is_err());
+            assert_eq!(faults.injected_errors(), 1);
+            faults.set_error_rate(0.0);
+
+            faults.set_unavailable(true);
+            match client.request("GET a/1").await {
+                Err(DependencyError::Io(_)) => {}
+                other => panic!("expected the connection to close, got {:?}", other),
+            }
+            assert_eq!(faults.requests(), 7);
+        });
+        assert_eq!(store.object("a/2"), Some(String::from("x")));
+    }
+
+    #[test]
+    /// Test that the mail sink records delivered mail.
+    fn mail_sink() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let sink = MailSink::new();
+        runtime.block_on(async {
+            let faults = handle
+                .add_dependency("smtp", 25, sink.clone())
+                .await
+                .unwrap();
+            let mut client = DependencyClient::connect(&handle, faults.addr())
+                .await
+                .unwrap();
+            client
+                .request("SEND ops@example.com disk full")
+                .await
+                .unwrap();
+        });
+        assert_eq!(
+            sink.delivered(),
+            vec![Mail {
+                to: String::from("ops@example.com"),
+                body: String::from("disk full"),
+            }]
+        );
+    }
+}
@@ -0,0 +1,226 @@
+//! Hostname resolution through a simulated resolver.
+//!
+//! Services usually find each other by name, and many outages start with a lookup going wrong:
+//! a cached record pointing at a replica which has since failed over, a name which briefly
+//! fails to resolve, or a resolver which takes seconds to answer. Hosts registered by name,
+//! such as those added with [`spawn_host`], can be looked up with [`lookup_host`] and connected
+//! to as `"name:port"` with [`connect_host`]. Lookups are answered according to the
+//! [`DnsFaults`] configured for the runtime or for the name being looked up. A stale answer is
+//! the address the name resolved to before it was last re-registered, migrated or removed.
+//! Fault decisions are drawn from a stream derived from the seed of the runtime, so they do not
+//! disturb the other decisions of a run.
+//!
+//! [`spawn_host`]:`super::DeterministicRuntimeHandle::spawn_host`
+//! [`lookup_host`]:`super::DeterministicRuntimeHandle::lookup_host`
+//! [`connect_host`]:`super::DeterministicRuntimeHandle::connect_host`
+//! [`DnsFaults`]:`DnsFaults`
+use super::{DeterministicNetworkHandle, DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{collections, io, net, sync, time};
+use tracing::trace;
+
+/// Faults injected into lookups of host names.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DnsFaults {
+    /// Probability that a lookup returns the address the name resolved to before it was last
+    /// re-registered, migrated or removed. Names which never changed resolve as usual.
+    pub stale_record: f64,
+    /// Probability that a lookup fails as if the name did not exist.
+    pub nxdomain: f64,
+    /// Probability that a lookup is answered after `slow_latency` rather than `latency`.
+    pub slow_response: f64,
+    /// Simulated time taken to answer each lookup.
+    pub latency: time::Duration,
+    /// Simulated time taken to answer slow lookups.
+    pub slow_latency: time::Duration,
+}
+
+impl Default for DnsFaults {
+    fn default() -> Self {
+        Self {
+            stale_record: 0.0,
+            nxdomain: 0.0,
+            slow_response: 0.0,
+            latency: time::Duration::from_millis(0),
+            slow_latency: time::Duration::from_secs(5),
+        }
+    }
+}
+
+/// The record a lookup answers with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Record {
+    Current,
+    Stale,
+    NxDomain,
+}
+
+#[derive(Debug)]
+struct State {
+    faults: DnsFaults,
+    /// Faults of names configured individually, replacing the faults of the runtime.
+    names: collections::HashMap<String, DnsFaults>,
+    random: DeterministicRandomHandle,
+}
+
+/// Resolver shared by the hosts of a runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct Dns {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Dns {
+    pub(crate) fn new(random: DeterministicRandomHandle) -> Self {
+        let state = State {
+            faults: DnsFaults::default(),
+            names: collections::HashMap::new(),
+            random,
+        };
+        Self {
+            state: sync::Arc::new(sync::Mutex::new(state)),
+        }
+    }
+
+    pub(crate) fn set_faults(&self, faults: DnsFaults) {
+        self.state.lock().unwrap().faults = faults;
+    }
+
+    pub(crate) fn set_name_faults(&self, name: String, faults: DnsFaults) {
+        self.state.lock().unwrap().names.insert(name, faults);
+    }
+
+    /// Decides how a lookup of the provided name is answered, and how long the answer takes.
+    fn answer(&self, name: &str) -> (Record, time::Duration) {
+        let state = self.state.lock().unwrap();
+        let faults = state.names.get(name).unwrap_or(&state.faults);
+        let record = if state.random.should_fault(faults.nxdomain) {
+            trace!("injecting nxdomain for {}", name);
+            Record::NxDomain
+        } else if state.random.should_fault(faults.stale_record) {
+            trace!("injecting stale record for {}", name);
+            Record::Stale
+        } else {
+            Record::Current
+        };
+        let latency = if state.random.should_fault(faults.slow_response) {
+            trace!("injecting slow response for {}", name);
+            faults.slow_latency
+        } else {
+            faults.latency
+        };
+        (record, latency)
+    }
+
+    /// Resolves the provided name once the resolver has answered. Records are read when the
+    /// answer arrives, so slow answers reflect changes made while the lookup was in flight.
+    pub(crate) async fn lookup(
+        &self,
+        name: &str,
+        network: &DeterministicNetworkHandle,
+        time_handle: &DeterministicTimeHandle,
+    ) -> io::Result<net::IpAddr> {
+        let (record, latency) = self.answer(name);
+        if latency > time::Duration::from_millis(0) {
+            time_handle.delay_from(latency).await;
+        }
+        let addr = match record {
+            Record::Current => network.resolve_host(name),
+            Record::Stale => network
+                .resolve_stale_host(name)
+                .or_else(|| network.resolve_host(name)),
+            Record::NxDomain => None,
+        };
+        addr.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("failed to resolve {}: no such host", name),
+            )
+        })
+    }
+}
+
+/// Splits an address of the form `host:port`, stripping the brackets of IPv6 literals.
+pub(crate) fn split_host_port(addr: &str) -> io::Result<(&str, u16)> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid address {}, expected host:port", addr),
+        )
+    };
+    let colon = addr.rfind(':').ok_or_else(invalid)?;
+    let (host, port) = (&addr[..colon], &addr[colon + 1..]);
+    let port = port.parse().map_err(|_| invalid())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that hosts connect to each other by name through the resolver, and that the
+    /// resolver can be configured to return stale records, NXDOMAIN and slow responses.
+    fn resolve_names() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            for reply in vec!["old", "new"] {
+                let replica = handle.spawn_host("replica-3");
+                let addr = net::SocketAddr::new(replica.addr(), 9000);
+                let mut listener = replica.handle().bind(addr).await.unwrap();
+                replica.spawn(async move {
+                    while let Ok((mut socket, _)) = listener.accept().await {
+                        socket.write_all(reply.as_bytes()).await.unwrap();
+                    }
+                });
+            }
+            let read = |mut socket: crate::deterministic::Socket| async move {
+                let mut buf = [0u8; 3];
+                socket.read_exact(&mut buf).await.unwrap();
+                buf
+            };
+
+            let socket = handle.connect_host("replica-3:9000").await.unwrap();
+            assert_eq!(&read(socket).await, b"new");
+            let err = handle.connect_host("replica-4:9000").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+            assert!(handle.connect_host("replica-3").await.is_err());
+
+            handle.set_name_faults(
+                "replica-3",
+                DnsFaults {
+                    stale_record: 1.0,
+                    ..DnsFaults::default()
+                },
+            );
+            let socket = handle.connect_host("replica-3:9000").await.unwrap();
+            assert_eq!(&read(socket).await, b"old");
+
+            handle.set_name_faults(
+                "replica-3",
+                DnsFaults {
+                    nxdomain: 1.0,
+                    ..DnsFaults::default()
+                },
+            );
+            let err = handle.lookup_host("replica-3").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+            handle.set_name_faults("replica-3", DnsFaults::default());
+            handle.set_dns_faults(DnsFaults {
+                slow_response: 1.0,
+                slow_latency: time::Duration::from_secs(2),
+                ..DnsFaults::default()
+            });
+            let start = handle.now();
+            handle.lookup_host("replica-3").await.unwrap();
+            assert_eq!(handle.now() - start, time::Duration::from_secs(2));
+        });
+    }
+}
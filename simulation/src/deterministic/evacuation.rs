@@ -0,0 +1,300 @@
+//! Region evacuation drills.
+//!
+//! Evacuating a region is a common disaster recovery drill: each host of the region is taken
+//! out of rotation, refusing new connections while its existing connections drain, and is
+//! killed once drained or once its drain timeout elapses. An [`Evacuation`] packages these steps
+//! for the hosts of a network domain, evacuating them one after another while an availability
+//! probe checks that the system keeps serving requests throughout the drill.
+//!
+//! [`Evacuation`]:`Evacuation`
+use super::DeterministicRuntimeHandle;
+use crate::Environment;
+use futures::{future, Future};
+use std::{cell, cmp, fmt, net, pin::Pin, time};
+use tracing::trace;
+
+/// Interval at which draining hosts are checked for open connections.
+const DRAIN_POLL_INTERVAL: time::Duration = time::Duration::from_millis(100);
+
+type Probe =
+    Box<dyn FnMut(DeterministicRuntimeHandle) -> Pin<Box<dyn Future<Output = Result<(), String>>>>>;
+
+/// Builder for a drill evacuating every host of a network domain.
+pub struct Evacuation {
+    domain: String,
+    step_interval: time::Duration,
+    drain_timeout: time::Duration,
+    probe: Option<(time::Duration, Probe)>,
+}
+
+impl fmt::Debug for Evacuation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Evacuation")
+            .field("domain", &self.domain)
+            .field("step_interval", &self.step_interval)
+            .field("drain_timeout", &self.drain_timeout)
+            .field("probe_interval", &self.probe.as_ref().map(|(i, _)| i))
+            .finish()
+    }
+}
+
+impl Evacuation {
+    /// Creates a drill evacuating the hosts of the provided network domain.
+    pub fn new(domain: impl Into<String>) -> Self {
+        Self {
+            domain: domain.into(),
+            step_interval: time::Duration::from_secs(0),
+            drain_timeout: time::Duration::from_secs(30),
+            probe: None,
+        }
+    }
+
+    /// Waits for the provided duration after evacuating a host before draining the next.
+    pub fn step_interval(mut self, interval: time::Duration) -> Self {
+        self.step_interval = interval;
+        self
+    }
+
+    /// Kills a draining host once the provided duration has elapsed, even if connections to
+    /// it remain open. Defaults to 30 seconds.
+    pub fn drain_timeout(mut self, timeout: time::Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Runs the provided probe every `interval` while the drill is in progress, and once more
+    /// after the last host has been killed. The probe is invoked with a handle scoped to the
+    /// host running the drill, and failures are collected into the report.
+    pub fn availability<F, U>(mut self, interval: time::Duration, mut probe: F) -> Self
+    where
+        F: FnMut(DeterministicRuntimeHandle) -> U + 'static,
+        U: Future<Output = Result<(), String>> + 'static,
+    {
+        self.probe.replace((
+            interval,
+            Box::new(move |handle| {
+                Box::pin(probe(handle)) as Pin<Box<dyn Future<Output = Result<(), String>>>>
+            }),
+        ));
+        self
+    }
+
+    /// Evacuates each host of the domain in address order, returning once every host has been
+    /// killed.
+    pub async fn run(self, handle: &DeterministicRuntimeHandle) -> EvacuationReport {
+        let Evacuation {
+            domain,
+            step_interval,
+            drain_timeout,
+            probe,
+        } = self;
+        let started = handle.now();
+        let done = cell::Cell::new(false);
+        let evacuate = async {
+            let mut evacuated = vec![];
+            for (index, addr) in handle.hosts_in(&domain).into_iter().enumerate() {
+                if index > 0 {
+                    handle.delay_from(step_interval).await;
+                }
+                trace!("evacuating {} from {}", addr, domain);
+                handle.drain_host(addr);
+                let drain_started = handle.now();
+                let deadline = drain_started + drain_timeout;
+                while open_connections(handle, addr) > 0 && handle.now() < deadline {
+                    let remaining = deadline - handle.now();
+                    handle
+                        .delay_from(cmp::min(DRAIN_POLL_INTERVAL, remaining))
+                        .await;
+                }
+                let killed_connections = open_connections(handle, addr);
+                handle.remove_host(addr);
+                evacuated.push(EvacuatedHost {
+                    addr,
+                    drained_for: handle.now() - drain_started,
+                    killed_connections,
+                });
+            }
+            done.set(true);
+            evacuated
+        };
+        let monitor = async {
+            let mut failures = vec![];
+            if let Some((interval, mut probe)) = probe {
+                loop {
+                    if let Err(error) = probe(handle.clone()).await {
+                        trace!("availability probe failed: {}", error);
+                        failures.push((handle.now() - started, error));
+                    }
+                    if done.get() {
+                        break;
+                    }
+                    handle.delay_from(interval).await;
+                }
+            }
+            failures
+        };
+        let (hosts, failures) = future::join(evacuate, monitor).await;
+        EvacuationReport { hosts, failures }
+    }
+}
+
+/// Returns the number of open connections to or from the provided host.
+fn open_connections(handle: &DeterministicRuntimeHandle, addr: net::IpAddr) -> usize {
+    handle
+        .connections()
+        .iter()
+        .filter(|c| c.source().ip() == addr || c.dest().ip() == addr)
+        .count()
+}
+
+/// Outcome of evacuating a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvacuatedHost {
+    addr: net::IpAddr,
+    drained_for: time::Duration,
+    killed_connections: usize,
+}
+
+impl EvacuatedHost {
+    pub fn addr(&self) -> net::IpAddr {
+        self.addr
+    }
+
+    /// Returns the time between the host being drained and killed.
+    pub fn drained_for(&self) -> time::Duration {
+        self.drained_for
+    }
+
+    /// Returns the number of connections which were still open when the host was killed.
+    pub fn killed_connections(&self) -> usize {
+        self.killed_connections
+    }
+}
+
+/// Outcome of an [`Evacuation`].
+///
+/// [`Evacuation`]:`Evacuation`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvacuationReport {
+    hosts: Vec<EvacuatedHost>,
+    failures: Vec<(time::Duration, String)>,
+}
+
+impl EvacuationReport {
+    /// Returns the evacuated hosts, in the order they were evacuated.
+    pub fn hosts(&self) -> &[EvacuatedHost] {
+        &self.hosts
+    }
+
+    /// Returns the time since the start of the drill and the error of each failed probe.
+    pub fn availability_failures(&self) -> &[(time::Duration, String)] {
+        &self.failures
+    }
+
+    /// Returns true if every availability probe succeeded.
+    pub fn is_available(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::TcpListener;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Binds an echo server on port 80 of the provided host.
+    async fn echo_server(host: DeterministicRuntimeHandle) {
+        let addr = net::SocketAddr::new(host.local_addr(), 80);
+        let mut listener = host.bind(addr).await.unwrap();
+        let server = host.clone();
+        host.spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                server.spawn(async move {
+                    let mut buf = [0u8; 1];
+                    while let Ok(1) = socket.read(&mut buf).await {
+                        if socket.write_all(&buf).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+    }
+
+    #[test]
+    /// Test that hosts are drained and killed one after another, and that a service with a
+    /// replica outside of the region stays available throughout.
+    fn evacuate_region() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            // Every host is reachable through the edge domain.
+            let east = vec![handle.add_host_in("east"), handle.add_host_in("east")];
+            let west = handle.add_host_in("west");
+            for host in east.iter().chain(Some(&west)) {
+                host.join_domain("edge");
+            }
+            let client = handle.add_host_in("edge");
+            let servers: Vec<net::SocketAddr> = vec![east[0].local_addr(), east[1].local_addr()]
+                .into_iter()
+                .chain(Some(west.local_addr()))
+                .map(|ip| net::SocketAddr::new(ip, 80))
+                .collect();
+            for host in east.iter().chain(Some(&west)) {
+                echo_server(host.clone()).await;
+            }
+
+            // A short lived connection to the first host, and a connection to the second host
+            // which is never closed.
+            let mut short = client.connect(servers[0]).await.unwrap();
+            let _held = client.connect(servers[1]).await.unwrap();
+            let closer = client.clone();
+            client.spawn(async move {
+                short.write_all(b"x").await.unwrap();
+                closer.delay_from(time::Duration::from_secs(1)).await;
+                drop(short);
+            });
+
+            let probe_servers = servers.clone();
+            let report = Evacuation::new("east")
+                .step_interval(time::Duration::from_secs(5))
+                .drain_timeout(time::Duration::from_secs(10))
+                .availability(time::Duration::from_millis(500), move |handle| {
+                    let servers = probe_servers.clone();
+                    async move {
+                        for server in servers {
+                            if let Ok(mut socket) = handle.connect(server).await {
+                                socket.write_all(b"?").await.map_err(|e| e.to_string())?;
+                                socket
+                                    .read_exact(&mut [0u8; 1])
+                                    .await
+                                    .map_err(|e| e.to_string())?;
+                                return Ok(());
+                            }
+                        }
+                        Err(String::from("no servers available"))
+                    }
+                })
+                .run(&client)
+                .await;
+
+            assert!(
+                report.is_available(),
+                "{:?}",
+                report.availability_failures()
+            );
+            let hosts = report.hosts();
+            assert_eq!(hosts.len(), 2);
+            assert_eq!(hosts[0].addr(), east[0].local_addr());
+            assert_eq!(hosts[0].killed_connections(), 0);
+            assert!(hosts[0].drained_for() < time::Duration::from_secs(10));
+            assert_eq!(hosts[1].addr(), east[1].local_addr());
+            assert_eq!(hosts[1].drained_for(), time::Duration::from_secs(10));
+            assert_eq!(hosts[1].killed_connections(), 1);
+            assert_eq!(handle.hosts_in("east"), vec![]);
+            assert_eq!(handle.hosts_in("west"), vec![west.local_addr()]);
+        });
+    }
+}
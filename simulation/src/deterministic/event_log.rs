@@ -0,0 +1,278 @@
+//! Structured log of everything a simulation did.
+//!
+//! Working out why a seed failed usually means reconstructing what each task was doing when
+//! things went wrong. An [`EventLog`] is an [`Observer`] which records every task spawn, poll
+//! and drop, every timer armed and fired, and every connection, accept, write and fault in the
+//! order they occurred. Each entry carries a logical timestamp, its position in the log, along
+//! with the simulated time elapsed since the log was installed, so entries of two runs of the
+//! same seed can be compared line by line. With the `serde` feature enabled the log serializes
+//! to JSON for inspection with external tools.
+//!
+//! [`EventLog`]:`EventLog`
+//! [`Observer`]:`super::Observer`
+use super::network::ConnectionId;
+use super::{Observer, SimulationEvent, TaskId};
+use std::{net, sync, time};
+
+/// An action taken by the simulation.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum LogEvent {
+    TaskSpawned {
+        task: TaskId,
+        name: Option<String>,
+    },
+    TaskPolled {
+        task: TaskId,
+        ready: bool,
+    },
+    TaskDropped {
+        task: TaskId,
+    },
+    /// A timer was armed, firing once `deadline` has elapsed since the log was installed.
+    TimerArmed {
+        deadline: time::Duration,
+    },
+    TimerFired {
+        deadline: time::Duration,
+    },
+    Connected {
+        id: ConnectionId,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    },
+    ConnectionRefused {
+        dest: net::SocketAddr,
+    },
+    Accepted {
+        id: Option<ConnectionId>,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+    },
+    BytesSent {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+        bytes: usize,
+    },
+    /// A fault was applied, described by its debug representation.
+    FaultInjected {
+        fault: String,
+    },
+    /// Any other simulation event, described by its debug representation.
+    Other {
+        event: String,
+    },
+}
+
+impl LogEvent {
+    fn from_event(event: &SimulationEvent) -> Self {
+        match event {
+            SimulationEvent::ConnectionEstablished { id, source, dest } => LogEvent::Connected {
+                id: *id,
+                source: *source,
+                dest: *dest,
+            },
+            SimulationEvent::ConnectionRefused { dest } => {
+                LogEvent::ConnectionRefused { dest: *dest }
+            }
+            SimulationEvent::ConnectionAccepted { id, local, peer } => LogEvent::Accepted {
+                id: *id,
+                local: *local,
+                peer: *peer,
+            },
+            SimulationEvent::BytesSent { from, to, bytes } => LogEvent::BytesSent {
+                from: *from,
+                to: *to,
+                bytes: *bytes,
+            },
+            SimulationEvent::FaultApplied(action) => LogEvent::FaultInjected {
+                fault: format!("{:?}", action),
+            },
+            event => LogEvent::Other {
+                event: format!("{:?}", event),
+            },
+        }
+    }
+}
+
+/// An entry of an event log.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LogEntry {
+    /// Position of the entry in the log, which orders entries made at the same simulated time.
+    pub seq: u64,
+    /// Simulated time elapsed since the log was installed.
+    pub elapsed: time::Duration,
+    pub event: LogEvent,
+}
+
+/// Records the actions of a simulation in the order they occurred.
+#[derive(Debug)]
+pub struct EventLog {
+    start: time::Instant,
+    entries: sync::Mutex<Vec<LogEntry>>,
+}
+
+impl EventLog {
+    pub(crate) fn new(start: time::Instant) -> Self {
+        Self {
+            start,
+            entries: sync::Mutex::default(),
+        }
+    }
+
+    fn elapsed(&self, at: time::Instant) -> time::Duration {
+        at.saturating_duration_since(self.start)
+    }
+
+    fn push(&self, at: time::Instant, event: LogEvent) {
+        let elapsed = self.elapsed(at);
+        let mut entries = self.entries.lock().unwrap();
+        let seq = entries.len() as u64;
+        entries.push(LogEntry {
+            seq,
+            elapsed,
+            event,
+        });
+    }
+
+    /// Returns every entry recorded so far, in the order they were recorded.
+    pub fn entries(&self) -> Vec<LogEntry> {
+        self.entries.lock().unwrap().clone()
+    }
+
+    /// Returns the entries concerning the provided task.
+    pub fn task_entries(&self, task: TaskId) -> Vec<LogEntry> {
+        self.filter(|event| match event {
+            LogEvent::TaskSpawned { task: t, .. }
+            | LogEvent::TaskPolled { task: t, .. }
+            | LogEvent::TaskDropped { task: t } => *t == task,
+            _ => false,
+        })
+    }
+
+    /// Returns the entries whose events match the provided predicate.
+    pub fn filter<F>(&self, predicate: F) -> Vec<LogEntry>
+    where
+        F: Fn(&LogEvent) -> bool,
+    {
+        self.entries
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|entry| predicate(&entry.event))
+            .cloned()
+            .collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Observer for EventLog {
+    fn task_spawned(&self, at: time::Instant, task: TaskId, name: Option<&str>) {
+        let name = name.map(String::from);
+        self.push(at, LogEvent::TaskSpawned { task, name });
+    }
+
+    fn task_polled(&self, at: time::Instant, task: TaskId, ready: bool) {
+        self.push(at, LogEvent::TaskPolled { task, ready });
+    }
+
+    fn task_dropped(&self, at: time::Instant, task: TaskId) {
+        self.push(at, LogEvent::TaskDropped { task });
+    }
+
+    fn timer_armed(&self, at: time::Instant, deadline: time::Instant) {
+        let deadline = self.elapsed(deadline);
+        self.push(at, LogEvent::TimerArmed { deadline });
+    }
+
+    fn timer_fired(&self, deadline: time::Instant) {
+        let event = LogEvent::TimerFired {
+            deadline: self.elapsed(deadline),
+        };
+        self.push(deadline, event);
+    }
+
+    fn event(&self, at: time::Instant, event: &SimulationEvent) {
+        self.push(at, LogEvent::from_event(event));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn record(seed: u64) -> Vec<LogEntry> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let log = runtime.record_events();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn_named("server", async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(b"hello").await.unwrap();
+            });
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.read_exact(&mut [0u8; 5]).await.unwrap();
+        });
+        log.entries()
+    }
+
+    #[test]
+    /// Test that the log records tasks, timers, connections and writes with logical
+    /// timestamps, and that the same seed produces the same log.
+    fn record_run() {
+        let entries = record(1);
+        assert_eq!(entries, record(1));
+        assert!(entries
+            .iter()
+            .enumerate()
+            .all(|(seq, entry)| entry.seq == seq as u64));
+        let kinds: Vec<&LogEvent> = entries.iter().map(|entry| &entry.event).collect();
+        let position = |f: &dyn Fn(&LogEvent) -> bool| kinds.iter().position(|event| f(event));
+        let spawned = position(&|event| match event {
+            LogEvent::TaskSpawned { name, .. } => {
+                name.as_ref().map(String::as_str) == Some("server")
+            }
+            _ => false,
+        });
+        let fired = position(&|event| match event {
+            LogEvent::TimerFired { .. } => true,
+            _ => false,
+        });
+        let connected = position(&|event| match event {
+            LogEvent::Connected { .. } => true,
+            _ => false,
+        });
+        let accepted = position(&|event| match event {
+            LogEvent::Accepted { .. } => true,
+            _ => false,
+        });
+        let sent = position(&|event| match event {
+            LogEvent::BytesSent { bytes, .. } => *bytes == 5,
+            _ => false,
+        });
+        assert!(spawned.is_some() && spawned < fired && fired < connected);
+        assert!(accepted.is_some() && accepted < sent);
+        assert!(entries[connected.unwrap()].elapsed >= time::Duration::from_secs(1));
+
+        #[cfg(feature = "serde")]
+        {
+            let json = serde_json::to_string(&entries).unwrap();
+            let restored: Vec<LogEntry> = serde_json::from_str(&json).unwrap();
+            assert_eq!(restored, entries);
+        }
+    }
+}
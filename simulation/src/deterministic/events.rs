@@ -0,0 +1,318 @@
+//! Simulation events and breakpoints.
+//!
+//! Components of the deterministic runtime emit [`SimulationEvent`]s as the simulation
+//! progresses. Breakpoints match events, suspending the simulation and invoking an inspection
+//! callback before the simulation resumes.
+//!
+//! Events are emitted from within the runtime while internal locks may be held, so matched
+//! events are queued and dispatched once the emitting task yields back to the executor. No
+//! other task runs between an event being emitted and its breakpoint callback being invoked.
+//!
+//! [`SimulationEvent`]:`SimulationEvent`
+use super::network::{ConnectionId, FaultAction};
+use super::observer::Observers;
+use super::watermark::Buffer;
+use std::{fmt, net, sync, time};
+
+/// An event which occurred during the simulation.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimulationEvent {
+    /// Simulated time reached the instant of a time breakpoint.
+    TimeReached(time::Instant),
+    /// A listener was bound to the provided address.
+    ListenerBound(net::SocketAddr),
+    /// The listener bound to the provided address was dropped.
+    ListenerClosed(net::SocketAddr),
+    /// A connection was established from `source` to `dest`.
+    ConnectionEstablished {
+        id: ConnectionId,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+    },
+    /// A connection to `dest` was refused.
+    ConnectionRefused { dest: net::SocketAddr },
+    /// A connection was reset by an injected fault. Resets applied to both halves of a
+    /// connection are emitted once for each half.
+    ConnectionReset { id: Option<ConnectionId> },
+    /// A listener bound to `local` accepted a connection from `peer`.
+    ConnectionAccepted {
+        id: Option<ConnectionId>,
+        local: net::SocketAddr,
+        peer: net::SocketAddr,
+    },
+    /// The connection half bound to `from` wrote `bytes` to its peer at `to`.
+    BytesSent {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+        bytes: usize,
+    },
+    /// A host migrated from the address `from` to the address `to`.
+    HostMigrated { from: net::IpAddr, to: net::IpAddr },
+    /// A fault was applied to the network.
+    FaultApplied(FaultAction),
+    /// A fault targeting a connection was not applied as no connection matched its target, as
+    /// when a replayed schedule targets a connection which the run never established.
+    FaultTargetMissing(FaultAction),
+    /// A buffer has stayed above its high watermark since `since`, peaking at `peak` bytes or
+    /// messages so far.
+    HighWatermark {
+        buffer: Buffer,
+        peak: usize,
+        since: time::Instant,
+    },
+    /// A connection half was closed while `bytes` received from its peer remained unread.
+    UnconsumedData {
+        local_addr: net::SocketAddr,
+        peer_addr: net::SocketAddr,
+        bytes: usize,
+    },
+}
+
+/// Identifies a registered breakpoint.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BreakpointId(u64);
+
+type Predicate = Box<dyn Fn(&SimulationEvent) -> bool + Send>;
+type Callback = sync::Arc<sync::Mutex<dyn FnMut(&SimulationEvent) + Send>>;
+
+enum Condition {
+    Time(time::Instant),
+    Event(Predicate),
+}
+
+/// Condition on which the simulation is suspended.
+pub struct Breakpoint {
+    condition: Condition,
+}
+
+impl fmt::Debug for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.condition {
+            Condition::Time(at) => write!(f, "Breakpoint {{ at: {:?} }}", at),
+            Condition::Event(_) => write!(f, "Breakpoint {{ event }}"),
+        }
+    }
+}
+
+impl Breakpoint {
+    /// Suspends the simulation once simulated time reaches the provided instant. Time
+    /// breakpoints are removed once triggered.
+    pub fn at(instant: time::Instant) -> Self {
+        Self {
+            condition: Condition::Time(instant),
+        }
+    }
+
+    /// Suspends the simulation whenever an event matching the provided predicate occurs.
+    /// Predicates must not call back into the runtime.
+    pub fn on<F>(predicate: F) -> Self
+    where
+        F: Fn(&SimulationEvent) -> bool + Send + 'static,
+    {
+        Self {
+            condition: Condition::Event(Box::new(predicate)),
+        }
+    }
+
+    /// Suspends the simulation whenever a connection to the provided port is refused.
+    pub fn connection_refused(port: u16) -> Self {
+        Self::on(move |event| match event {
+            SimulationEvent::ConnectionRefused { dest } => dest.port() == port,
+            _ => false,
+        })
+    }
+
+    /// Suspends the simulation whenever a buffer stays above its high watermark, see
+    /// [`DeterministicRuntime::set_connection_watermark`].
+    ///
+    /// [`DeterministicRuntime::set_connection_watermark`]:`super::DeterministicRuntime::set_connection_watermark`
+    pub fn high_watermark() -> Self {
+        Self::on(|event| match event {
+            SimulationEvent::HighWatermark { .. } => true,
+            _ => false,
+        })
+    }
+}
+
+struct Registered {
+    id: BreakpointId,
+    breakpoint: Breakpoint,
+    callback: Callback,
+}
+
+#[derive(Default)]
+struct Inner {
+    next_id: u64,
+    breakpoints: Vec<Registered>,
+    pending: Vec<(SimulationEvent, Callback)>,
+}
+
+/// Shared registry of breakpoints, through which simulation events are emitted.
+#[derive(Clone)]
+pub(crate) struct Events {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    observers: Observers,
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        write!(f, "Events {{ breakpoints: {} }}", lock.breakpoints.len())
+    }
+}
+
+impl Events {
+    pub(crate) fn new(observers: Observers) -> Self {
+        Self {
+            inner: sync::Arc::default(),
+            observers,
+        }
+    }
+
+    /// Returns the observers notified of runtime activity, including emitted events.
+    pub(crate) fn observers(&self) -> &Observers {
+        &self.observers
+    }
+
+    pub(crate) fn add_breakpoint<F>(&self, breakpoint: Breakpoint, callback: F) -> BreakpointId
+    where
+        F: FnMut(&SimulationEvent) + Send + 'static,
+    {
+        let mut lock = self.inner.lock().unwrap();
+        let id = BreakpointId(lock.next_id);
+        lock.next_id += 1;
+        lock.breakpoints.push(Registered {
+            id,
+            breakpoint,
+            callback: sync::Arc::new(sync::Mutex::new(callback)),
+        });
+        id
+    }
+
+    pub(crate) fn remove_breakpoint(&self, id: BreakpointId) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.breakpoints.retain(|registered| registered.id != id);
+    }
+
+    /// Emit an event, queueing it for dispatch if it matches any breakpoints.
+    pub(crate) fn emit(&self, event: SimulationEvent) {
+        let mut lock = self.inner.lock().unwrap();
+        let matched: Vec<Callback> = lock
+            .breakpoints
+            .iter()
+            .filter(|registered| match &registered.breakpoint.condition {
+                Condition::Event(predicate) => predicate(&event),
+                Condition::Time(_) => false,
+            })
+            .map(|registered| sync::Arc::clone(&registered.callback))
+            .collect();
+        for callback in matched {
+            lock.pending.push((event.clone(), callback));
+        }
+        drop(lock);
+        self.observers
+            .notify(|observer, at| observer.event(at, &event));
+    }
+
+    /// Returns the earliest time breakpoint in `[from, until]`.
+    pub(crate) fn next_time_breakpoint(
+        &self,
+        from: time::Instant,
+        until: time::Instant,
+    ) -> Option<time::Instant> {
+        let lock = self.inner.lock().unwrap();
+        lock.breakpoints
+            .iter()
+            .filter_map(|registered| match registered.breakpoint.condition {
+                Condition::Time(at) if at >= from && at <= until => Some(at),
+                _ => None,
+            })
+            .min()
+    }
+
+    /// Queue all time breakpoints at the provided instant for dispatch, removing them.
+    pub(crate) fn reach_time(&self, instant: time::Instant) {
+        let mut lock = self.inner.lock().unwrap();
+        let (reached, remaining): (Vec<_>, Vec<_>) =
+            lock.breakpoints.drain(..).partition(|registered| {
+                match registered.breakpoint.condition {
+                    Condition::Time(at) => at == instant,
+                    Condition::Event(_) => false,
+                }
+            });
+        lock.breakpoints = remaining;
+        for registered in reached {
+            lock.pending
+                .push((SimulationEvent::TimeReached(instant), registered.callback));
+        }
+    }
+
+    /// Invoke the callbacks of all breakpoints matched since the last dispatch.
+    pub(crate) fn dispatch(&self) {
+        loop {
+            let pending: Vec<_> = self.inner.lock().unwrap().pending.drain(..).collect();
+            if pending.is_empty() {
+                return;
+            }
+            for (event, callback) in pending {
+                (&mut *callback.lock().unwrap())(&event);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, LimitPolicy, ListenerOptions};
+    use crate::Environment;
+    use std::time::Duration;
+
+    #[test]
+    /// Test that breakpoints are invoked when a connection to a port is refused.
+    fn connection_refused_breakpoint() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let refused = sync::Arc::new(sync::Mutex::new(vec![]));
+        let breakpoint_refused = sync::Arc::clone(&refused);
+        let breakpoint_handle = handle.clone();
+        runtime.add_breakpoint(Breakpoint::connection_refused(2380), move |event| {
+            assert!(breakpoint_handle.wait_for_graph().contains("digraph"));
+            breakpoint_refused.lock().unwrap().push(event.clone());
+        });
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:2380".parse().unwrap();
+            let options = ListenerOptions::new().max_connections(1, LimitPolicy::Refuse);
+            let _listener = handle.bind_with_options(addr, options).await.unwrap();
+            let _first = handle.connect(addr).await.unwrap();
+            assert!(handle.connect(addr).await.is_err());
+        });
+        assert_eq!(
+            *refused.lock().unwrap(),
+            vec![SimulationEvent::ConnectionRefused {
+                dest: "127.0.0.1:2380".parse().unwrap()
+            }]
+        );
+    }
+
+    #[test]
+    /// Test that time breakpoints suspend the simulation at the requested instant.
+    fn time_breakpoint() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let reached = sync::Arc::new(sync::Mutex::new(None));
+        let breakpoint_reached = sync::Arc::clone(&reached);
+        let breakpoint_handle = handle.clone();
+        let at = handle.now() + Duration::from_secs(5);
+        runtime.add_breakpoint(Breakpoint::at(at), move |_| {
+            breakpoint_reached
+                .lock()
+                .unwrap()
+                .replace(breakpoint_handle.now());
+        });
+        runtime.block_on(async {
+            handle.delay_from(Duration::from_secs(10)).await;
+        });
+        assert_eq!(*reached.lock().unwrap(), Some(at));
+    }
+}
@@ -0,0 +1,460 @@
+//! Simulated filesystems of hosts.
+//!
+//! Storage engines recover from crashes by replaying whatever they made durable, so testing
+//! them needs a disk whose guarantees can be pushed to their limits. Each host has its own
+//! in-memory [`Filesystem`], which survives the host being killed and restarted. Writes to a
+//! [`File`] are visible immediately but only become durable once [`File::sync_all`] succeeds,
+//! and crashing the host discards the writes which were not synced, or with torn writes
+//! enabled persists an arbitrary prefix of them. [`FsFaults`] can additionally fail syncs and
+//! shorten writes. Every fault decision is drawn from a substream of the seed for the host.
+//!
+//! [`Filesystem`]:`Filesystem`
+//! [`File`]:`File`
+//! [`File::sync_all`]:`File::sync_all`
+//! [`FsFaults`]:`FsFaults`
+use super::{DeterministicRandomHandle, DeterministicTimeHandle, RngAlgorithm, Seed};
+use futures::Poll;
+use std::{cmp, collections, io, net, path, pin::Pin, sync, task::Context, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::trace;
+
+/// Faults injected into the filesystem of a host.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FsFaults {
+    /// Probability that a sync fails. The writes of a file which failed to sync remain
+    /// unsynced and are lost if the host crashes before a later sync succeeds.
+    pub sync_failure: f64,
+    /// Probability that a write only writes part of its buffer.
+    pub partial_write: f64,
+    /// Persist a prefix of the unsynced writes of each file on crash, tearing the last write
+    /// persisted, instead of discarding every unsynced write.
+    pub torn_writes: bool,
+    /// Simulated time taken by each sync.
+    pub sync_latency: time::Duration,
+}
+
+impl Default for FsFaults {
+    fn default() -> Self {
+        Self {
+            sync_failure: 0.0,
+            partial_write: 0.0,
+            torn_writes: false,
+            sync_latency: time::Duration::from_millis(0),
+        }
+    }
+}
+
+/// A modification of a file which has not been synced.
+#[derive(Debug, Clone)]
+enum Op {
+    Write { offset: usize, data: Vec<u8> },
+    SetLen(usize),
+}
+
+impl Op {
+    fn apply(&self, contents: &mut Vec<u8>) {
+        match self {
+            Op::Write { offset, data } => {
+                let end = offset + data.len();
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[*offset..end].copy_from_slice(data);
+            }
+            Op::SetLen(len) => contents.resize(*len, 0),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct FileState {
+    /// Contents as of the last successful sync, or none if the file was never synced.
+    durable: Option<Vec<u8>>,
+    contents: Vec<u8>,
+    /// Modifications applied to `contents` since the last successful sync, oldest first.
+    pending: Vec<Op>,
+}
+
+impl FileState {
+    fn modify(&mut self, op: Op) {
+        op.apply(&mut self.contents);
+        self.pending.push(op);
+    }
+
+    /// Returns the contents the file is left with by a crash, or none if it does not survive.
+    fn crash(&self, torn: bool, random: &DeterministicRandomHandle) -> Option<Vec<u8>> {
+        let persisted = if torn {
+            random.gen_range(0..self.pending.len() + 1)
+        } else {
+            0
+        };
+        if self.durable.is_none() && persisted == 0 {
+            return None;
+        }
+        let mut contents = self.durable.clone().unwrap_or_default();
+        for op in self.pending[..persisted].iter() {
+            op.apply(&mut contents);
+        }
+        if let Some(Op::Write { offset, data }) = self.pending.get(persisted) {
+            let torn = Op::Write {
+                offset: *offset,
+                data: data[..random.gen_range(0..data.len() + 1)].to_vec(),
+            };
+            torn.apply(&mut contents);
+        }
+        Some(contents)
+    }
+}
+
+#[derive(Debug)]
+struct Disk {
+    files: collections::BTreeMap<path::PathBuf, FileState>,
+    faults: FsFaults,
+    random: DeterministicRandomHandle,
+    /// Incremented by each crash, invalidating the files opened before it.
+    generation: u64,
+}
+
+/// Filesystems of the hosts of a runtime.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Filesystems {
+    disks: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, Disk>>>,
+}
+
+/// The filesystem of a simulated host.
+#[derive(Debug, Clone)]
+pub struct Filesystem {
+    host: net::IpAddr,
+    filesystems: Filesystems,
+    seed: Seed,
+    algorithm: RngAlgorithm,
+    time_handle: DeterministicTimeHandle,
+}
+
+impl Filesystem {
+    pub(crate) fn new(
+        host: net::IpAddr,
+        filesystems: Filesystems,
+        seed: Seed,
+        algorithm: RngAlgorithm,
+        time_handle: DeterministicTimeHandle,
+    ) -> Self {
+        Self {
+            host,
+            filesystems,
+            seed,
+            algorithm,
+            time_handle,
+        }
+    }
+
+    fn with_disk<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&mut Disk) -> R,
+    {
+        let mut disks = self.filesystems.disks.lock().unwrap();
+        let (seed, algorithm) = (self.seed, self.algorithm);
+        let disk = disks.entry(self.host).or_insert_with(|| Disk {
+            files: collections::BTreeMap::new(),
+            faults: FsFaults::default(),
+            random: seed
+                .derive("fs")
+                .derive(&self.host.to_string())
+                .random_with(algorithm),
+            generation: 0,
+        });
+        f(disk)
+    }
+
+    fn file(&self, path: path::PathBuf, generation: u64) -> File {
+        File {
+            filesystem: self.clone(),
+            path,
+            generation,
+            position: 0,
+        }
+    }
+
+    /// Creates a file, truncating it if it exists. A created file which is never synced does
+    /// not survive a crash.
+    pub fn create(&self, path: impl AsRef<path::Path>) -> io::Result<File> {
+        let path = path.as_ref().to_path_buf();
+        let generation = self.with_disk(|disk| {
+            disk.files
+                .entry(path.clone())
+                .or_default()
+                .modify(Op::SetLen(0));
+            disk.generation
+        });
+        Ok(self.file(path, generation))
+    }
+
+    /// Opens an existing file for reading and writing.
+    pub fn open(&self, path: impl AsRef<path::Path>) -> io::Result<File> {
+        let path = path.as_ref().to_path_buf();
+        let generation = self.with_disk(|disk| {
+            if disk.files.contains_key(&path) {
+                Ok(disk.generation)
+            } else {
+                Err(io::Error::from(io::ErrorKind::NotFound))
+            }
+        })?;
+        Ok(self.file(path, generation))
+    }
+
+    /// Removes a file. Removal is durable immediately.
+    pub fn remove_file(&self, path: impl AsRef<path::Path>) -> io::Result<()> {
+        self.with_disk(|disk| match disk.files.remove(path.as_ref()) {
+            Some(_) => Ok(()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        })
+    }
+
+    /// Returns the current contents of a file, including writes which were not synced.
+    pub fn read(&self, path: impl AsRef<path::Path>) -> io::Result<Vec<u8>> {
+        self.with_disk(|disk| match disk.files.get(path.as_ref()) {
+            Some(file) => Ok(file.contents.clone()),
+            None => Err(io::ErrorKind::NotFound.into()),
+        })
+    }
+
+    pub fn exists(&self, path: impl AsRef<path::Path>) -> bool {
+        self.with_disk(|disk| disk.files.contains_key(path.as_ref()))
+    }
+
+    /// Returns the paths of every file, in ascending order.
+    pub fn files(&self) -> Vec<path::PathBuf> {
+        self.with_disk(|disk| disk.files.keys().cloned().collect())
+    }
+
+    pub fn set_faults(&self, faults: FsFaults) {
+        self.with_disk(|disk| disk.faults = faults);
+    }
+
+    /// Crashes the disk, leaving each file with only what was synced, or a torn prefix of its
+    /// unsynced writes if torn writes are enabled. Files opened before the crash return errors
+    /// from then on. Killing a [`Host`] crashes its filesystem.
+    ///
+    /// [`Host`]:`super::Host`
+    pub fn crash(&self) {
+        self.with_disk(|disk| {
+            trace!("crashing filesystem of {}", self.host);
+            let (torn, random) = (disk.faults.torn_writes, disk.random.clone());
+            let files = std::mem::replace(&mut disk.files, collections::BTreeMap::new());
+            for (path, file) in files {
+                if let Some(contents) = file.crash(torn, &random) {
+                    let file = FileState {
+                        durable: Some(contents.clone()),
+                        contents,
+                        pending: vec![],
+                    };
+                    disk.files.insert(path, file);
+                }
+            }
+            disk.generation += 1;
+        });
+    }
+}
+
+/// A file of a simulated filesystem, supporting positioned reads and writes.
+#[derive(Debug)]
+pub struct File {
+    filesystem: Filesystem,
+    path: path::PathBuf,
+    generation: u64,
+    position: u64,
+}
+
+impl File {
+    fn with_file<F, R>(&self, f: F) -> io::Result<R>
+    where
+        F: FnOnce(&mut FileState, &FsFaults, &DeterministicRandomHandle) -> io::Result<R>,
+    {
+        self.filesystem.with_disk(|disk| {
+            if disk.generation != self.generation {
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "file was opened before the filesystem crashed",
+                ));
+            }
+            match disk.files.get_mut(&self.path) {
+                Some(file) => f(file, &disk.faults, &disk.random),
+                None => Err(io::ErrorKind::NotFound.into()),
+            }
+        })
+    }
+
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// Returns the current length of the file, including writes which were not synced.
+    pub fn len(&self) -> io::Result<u64> {
+        self.with_file(|file, _, _| Ok(file.contents.len() as u64))
+    }
+
+    pub fn is_empty(&self) -> io::Result<bool> {
+        self.len().map(|len| len == 0)
+    }
+
+    /// Moves the position from which the file is next read or written.
+    pub fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            io::SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            io::SeekFrom::Current(offset) => (self.position, offset),
+            io::SeekFrom::End(offset) => (self.len()?, offset),
+        };
+        let position = base as i64 + offset;
+        if position < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+        self.position = position as u64;
+        Ok(self.position)
+    }
+
+    /// Truncates or extends the file. Like writes, the new length is durable once synced.
+    pub fn set_len(&mut self, len: u64) -> io::Result<()> {
+        self.with_file(|file, _, _| {
+            file.modify(Op::SetLen(len as usize));
+            Ok(())
+        })
+    }
+
+    /// Makes the writes to the file durable, unless a sync failure is injected.
+    pub async fn sync_all(&mut self) -> io::Result<()> {
+        let latency = self.with_file(|_, faults, _| Ok(faults.sync_latency))?;
+        if latency > time::Duration::from_millis(0) {
+            self.filesystem.time_handle.delay_from(latency).await;
+        }
+        self.with_file(|file, faults, random| {
+            if random.should_fault(faults.sync_failure) {
+                trace!("injecting sync failure for {:?}", self.path);
+                return Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "injected sync failure",
+                ));
+            }
+            file.durable = Some(file.contents.clone());
+            file.pending.clear();
+            Ok(())
+        })
+    }
+}
+
+impl AsyncRead for File {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        dst: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let position = self.position as usize;
+        let read = self.with_file(|file, _, _| {
+            let start = cmp::min(position, file.contents.len());
+            let end = cmp::min(start + dst.len(), file.contents.len());
+            dst[..end - start].copy_from_slice(&file.contents[start..end]);
+            Ok(end - start)
+        });
+        if let Ok(read) = read {
+            self.position += read as u64;
+        }
+        Poll::Ready(read)
+    }
+}
+
+impl AsyncWrite for File {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<Result<usize, io::Error>> {
+        let offset = self.position as usize;
+        let written = self.with_file(|file, faults, random| {
+            let len = if buf.len() > 1 && random.should_fault(faults.partial_write) {
+                random.gen_range(1..buf.len())
+            } else {
+                buf.len()
+            };
+            file.modify(Op::Write {
+                offset,
+                data: buf[..len].to_vec(),
+            });
+            Ok(len)
+        });
+        if let Ok(written) = written {
+            self.position += written as u64;
+        }
+        Poll::Ready(written)
+    }
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that only synced writes survive a crash, that failed syncs leave writes unsynced,
+    /// and that torn writes persist a prefix of the unsynced writes.
+    fn crash_loses_unsynced_writes() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let fs = handle.fs();
+            let mut wal = fs.create("wal").unwrap();
+            wal.write_all(b"hello").await.unwrap();
+            wal.sync_all().await.unwrap();
+            wal.write_all(b" world").await.unwrap();
+            fs.create("scratch").unwrap();
+            assert_eq!(fs.read("wal").unwrap(), b"hello world");
+            fs.crash();
+            assert_eq!(fs.read("wal").unwrap(), b"hello");
+            assert!(!fs.exists("scratch"));
+            assert!(wal.write_all(b"!").await.is_err());
+
+            let mut wal = fs.open("wal").unwrap();
+            wal.seek(io::SeekFrom::End(0)).unwrap();
+            fs.set_faults(FsFaults {
+                sync_failure: 1.0,
+                ..FsFaults::default()
+            });
+            wal.write_all(b" again").await.unwrap();
+            assert!(wal.sync_all().await.is_err());
+            fs.crash();
+            let mut contents = vec![];
+            let mut wal = fs.open("wal").unwrap();
+            wal.read_to_end(&mut contents).await.unwrap();
+            assert_eq!(contents, b"hello");
+
+            fs.set_faults(FsFaults {
+                torn_writes: true,
+                ..FsFaults::default()
+            });
+            let mut torn = false;
+            for _ in 0..20 {
+                let mut wal = fs.open("wal").unwrap();
+                wal.set_len(5).unwrap();
+                wal.sync_all().await.unwrap();
+                wal.seek(io::SeekFrom::End(0)).unwrap();
+                wal.write_all(b" world").await.unwrap();
+                fs.crash();
+                let contents = fs.read("wal").unwrap();
+                assert!(b"hello world".starts_with(&contents), "{:?}", contents);
+                torn |= contents.len() > 5 && contents.len() < 11;
+            }
+            assert!(torn);
+        });
+    }
+}
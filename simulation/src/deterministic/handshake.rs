@@ -0,0 +1,436 @@
+//! Handshakes modelled over simulated connections.
+//!
+//! Protocols such as TLS exchange messages before a connection carries any application data,
+//! and clients usually treat failures of that exchange differently from later I/O errors, by
+//! retrying another replica or refusing to fall back to plaintext. A [`HandshakeStream`] wraps
+//! both ends of a simulated connection in a handshake phase without pulling in a real TLS
+//! stack: each end sends a hello message and waits for the hello of its peer before the stream
+//! becomes readable and writable.
+//!
+//! Failures of the handshake can be injected with [`Handshake::failure`]. They are observed by
+//! both ends of the connection, and are reported as `io::Error`s from which the
+//! [`HandshakeFailure`] can be recovered with [`HandshakeFailure::of`].
+//!
+//! [`HandshakeStream`]:`HandshakeStream`
+//! [`Handshake::failure`]:`Handshake::failure`
+//! [`HandshakeFailure`]:`HandshakeFailure`
+//! [`HandshakeFailure::of`]:`HandshakeFailure::of`
+use super::{ConnectionId, ConnectionIdExt, DeterministicRuntimeHandle, Sleep, Socket};
+use futures::{FutureExt, Poll};
+use std::{error, fmt, io, net, pin::Pin, task::Context, time};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::trace;
+
+/// Hello message sent by each end of a well-behaved handshake.
+const HELLO: [u8; 4] = *b"HSK1";
+/// Hello message sent by an end injecting a protocol error.
+const MALFORMED: [u8; 4] = [0; 4];
+
+/// The ways a handshake can fail.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum HandshakeFailure {
+    /// The handshake did not complete within its timeout, failing with `TimedOut`.
+    Timeout,
+    /// The connection was closed during the handshake. The closing end fails with
+    /// `ConnectionAborted` and its peer with `UnexpectedEof`.
+    AbruptClose,
+    /// A malformed handshake message was exchanged, failing with `InvalidData`.
+    ProtocolError,
+}
+
+impl HandshakeFailure {
+    /// Returns the handshake failure reported by `err`, or `None` if `err` is not a handshake
+    /// error.
+    pub fn of(err: &io::Error) -> Option<HandshakeFailure> {
+        err.get_ref()
+            .and_then(|inner| inner.downcast_ref::<HandshakeError>())
+            .map(|inner| inner.failure)
+    }
+}
+
+impl fmt::Display for HandshakeFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeFailure::Timeout => write!(f, "handshake timed out"),
+            HandshakeFailure::AbruptClose => write!(f, "connection closed during handshake"),
+            HandshakeFailure::ProtocolError => write!(f, "handshake protocol error"),
+        }
+    }
+}
+
+/// Error carried by the `io::Error`s returned by a [`HandshakeStream`] whose handshake failed.
+///
+/// [`HandshakeStream`]:`HandshakeStream`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct HandshakeError {
+    failure: HandshakeFailure,
+    kind: io::ErrorKind,
+}
+
+impl HandshakeError {
+    fn into_io(self) -> io::Error {
+        io::Error::new(self.kind, self)
+    }
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.failure)
+    }
+}
+
+impl error::Error for HandshakeError {}
+
+/// Model of the handshake performed by a [`HandshakeStream`].
+///
+/// [`HandshakeStream`]:`HandshakeStream`
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    timeout: time::Duration,
+    failures: Vec<(HandshakeFailure, f64)>,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handshake {
+    /// Creates a handshake which never fails unless its peer does, timing out after 10 seconds.
+    pub fn new() -> Self {
+        Self {
+            timeout: time::Duration::from_secs(10),
+            failures: vec![],
+        }
+    }
+
+    /// Sets the time allowed for the handshake to complete, including the time taken by the
+    /// peer to respond.
+    pub fn timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Injects `failure` into handshakes performed by this end with the provided probability.
+    /// Failures are sampled in the order they were added, and at most one is injected.
+    pub fn failure(mut self, failure: HandshakeFailure, probability: f64) -> Self {
+        self.failures.push((failure, probability));
+        self
+    }
+}
+
+#[derive(Debug)]
+enum State {
+    /// The handshake has not started.
+    Idle,
+    /// Writing the local hello message.
+    Sending {
+        hello: [u8; 4],
+        written: usize,
+    },
+    /// Waiting for the hello message of the peer.
+    Receiving {
+        hello: [u8; 4],
+        read: usize,
+    },
+    /// Waiting for the handshake to time out, without responding to the peer.
+    Stalled,
+    /// Closing the connection before failing the handshake.
+    Closing,
+    Failed(HandshakeError),
+    Established,
+}
+
+/// A simulated connection which performs a handshake before carrying data. Both ends of the
+/// connection must be wrapped.
+///
+/// The handshake is performed by [`HandshakeStream::handshake`], or by the first read or write
+/// of the stream. Once it has failed, every read and write fails with the same error.
+///
+/// [`HandshakeStream::handshake`]:`HandshakeStream::handshake`
+#[derive(Debug)]
+pub struct HandshakeStream {
+    handle: DeterministicRuntimeHandle,
+    inner: Socket,
+    handshake: Handshake,
+    state: State,
+    deadline: Option<Sleep>,
+}
+
+impl HandshakeStream {
+    pub fn new(handle: DeterministicRuntimeHandle, inner: Socket, handshake: Handshake) -> Self {
+        Self {
+            handle,
+            inner,
+            handshake,
+            state: State::Idle,
+            deadline: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &Socket {
+        &self.inner
+    }
+
+    /// Returns true once the handshake has completed successfully.
+    pub fn is_established(&self) -> bool {
+        match self.state {
+            State::Established => true,
+            _ => false,
+        }
+    }
+
+    /// Performs the handshake, returning once it has completed or failed.
+    pub async fn handshake(&mut self) -> io::Result<()> {
+        futures::future::poll_fn(|cx| self.poll_handshake(cx)).await
+    }
+
+    /// Samples the failure injected into the handshake by this end, if any.
+    fn sample_failure(&self) -> Option<HandshakeFailure> {
+        let random = self.handle.random_handle();
+        self.handshake
+            .failures
+            .iter()
+            .find(|(_, probability)| random.should_fault(*probability))
+            .map(|(failure, _)| *failure)
+    }
+
+    fn fail(&mut self, failure: HandshakeFailure, kind: io::ErrorKind) -> Poll<io::Result<()>> {
+        trace!(
+            "handshake of {:?} failed: {}",
+            self.connection_id(),
+            failure
+        );
+        let err = HandshakeError { failure, kind };
+        self.state = State::Failed(err);
+        self.deadline = None;
+        Poll::Ready(Err(err.into_io()))
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        loop {
+            match &mut self.state {
+                State::Established => return Poll::Ready(Ok(())),
+                State::Failed(err) => return Poll::Ready(Err(err.into_io())),
+                State::Idle => {
+                    self.deadline = Some(self.handle.delay_for(self.handshake.timeout));
+                    self.state = match self.sample_failure() {
+                        None => State::Sending {
+                            hello: HELLO,
+                            written: 0,
+                        },
+                        Some(HandshakeFailure::Timeout) => State::Stalled,
+                        Some(HandshakeFailure::AbruptClose) => State::Closing,
+                        Some(HandshakeFailure::ProtocolError) => State::Sending {
+                            hello: MALFORMED,
+                            written: 0,
+                        },
+                    };
+                }
+                State::Sending { hello, written } => {
+                    if *written == hello.len() {
+                        if *hello == MALFORMED {
+                            let kind = io::ErrorKind::InvalidData;
+                            return self.fail(HandshakeFailure::ProtocolError, kind);
+                        }
+                        self.state = State::Receiving {
+                            hello: [0; 4],
+                            read: 0,
+                        };
+                        continue;
+                    }
+                    let pending = &hello[*written..];
+                    match Pin::new(&mut self.inner).poll_write(cx, pending) {
+                        Poll::Ready(Ok(0)) => {
+                            return Poll::Ready(Err(io::ErrorKind::WriteZero.into()))
+                        }
+                        Poll::Ready(Ok(n)) => *written += n,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => break,
+                    }
+                }
+                State::Receiving { hello, read } => {
+                    if *read == hello.len() {
+                        if *hello != HELLO {
+                            let kind = io::ErrorKind::InvalidData;
+                            return self.fail(HandshakeFailure::ProtocolError, kind);
+                        }
+                        self.state = State::Established;
+                        self.deadline = None;
+                        continue;
+                    }
+                    match Pin::new(&mut self.inner).poll_read(cx, &mut hello[*read..]) {
+                        Poll::Ready(Ok(0)) => {
+                            let kind = io::ErrorKind::UnexpectedEof;
+                            return self.fail(HandshakeFailure::AbruptClose, kind);
+                        }
+                        Poll::Ready(Ok(n)) => *read += n,
+                        Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                        Poll::Pending => break,
+                    }
+                }
+                State::Closing => {
+                    futures::ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+                    let kind = io::ErrorKind::ConnectionAborted;
+                    return self.fail(HandshakeFailure::AbruptClose, kind);
+                }
+                State::Stalled => break,
+            }
+        }
+        let deadline = self.deadline.as_mut().expect("handshake deadline");
+        futures::ready!(deadline.poll_unpin(cx));
+        self.fail(HandshakeFailure::Timeout, io::ErrorKind::TimedOut)
+    }
+}
+
+impl AsyncWrite for HandshakeStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_handshake(cx))?;
+        Pin::new(&mut this.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_handshake(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        match this.state {
+            // Closing before the handshake started does not require one.
+            State::Idle | State::Established => Pin::new(&mut this.inner).poll_shutdown(cx),
+            _ => {
+                futures::ready!(this.poll_handshake(cx))?;
+                Pin::new(&mut this.inner).poll_shutdown(cx)
+            }
+        }
+    }
+}
+
+impl AsyncRead for HandshakeStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        futures::ready!(this.poll_handshake(cx))?;
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl ConnectionIdExt for HandshakeStream {
+    fn connection_id(&self) -> Option<ConnectionId> {
+        self.inner.connection_id()
+    }
+}
+
+impl crate::TcpStream for HandshakeStream {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.local_addr()
+    }
+    fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.inner.peer_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Connects a client and a server performing the provided handshakes, returning the result
+    /// of each handshake along with the virtual time taken by the client.
+    fn connect(
+        client: Handshake,
+        server: Handshake,
+    ) -> (io::Result<()>, io::Result<()>, time::Duration) {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9443".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let (tx, rx) = futures::channel::oneshot::channel();
+            let server_handle = handle.clone();
+            handle.spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut stream = HandshakeStream::new(server_handle, socket, server);
+                let result = stream.handshake().await;
+                if result.is_ok() {
+                    let mut buf = [0u8; 5];
+                    stream.read_exact(&mut buf).await.unwrap();
+                    stream.write_all(&buf).await.unwrap();
+                }
+                let _ = tx.send(result);
+                // Keep the server stream open until the client is done with it.
+                futures::future::pending::<()>().await;
+            });
+            let socket = handle.connect(addr).await.unwrap();
+            let mut stream = HandshakeStream::new(handle.clone(), socket, client);
+            let start = handle.now();
+            let result = stream.write_all(b"hello").await;
+            let elapsed = handle.now() - start;
+            if result.is_ok() {
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            }
+            (result, rx.await.unwrap(), elapsed)
+        })
+    }
+
+    #[test]
+    /// Test that data is exchanged once the handshake completes.
+    fn established() {
+        let (client, server, _) = connect(Handshake::new(), Handshake::new());
+        assert!(client.is_ok() && server.is_ok());
+    }
+
+    #[test]
+    /// Test that each injected failure is reported to both ends as a handshake error, with the
+    /// error kind expected of it.
+    fn injected_failures() {
+        let failing = |failure| Handshake::new().failure(failure, 1.0);
+        let timeout = time::Duration::from_secs(3);
+        let (client, server, elapsed) = connect(
+            failing(HandshakeFailure::Timeout).timeout(timeout),
+            Handshake::new().timeout(timeout),
+        );
+        for err in [client.unwrap_err(), server.unwrap_err()].iter() {
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(HandshakeFailure::of(err), Some(HandshakeFailure::Timeout));
+        }
+        assert!(elapsed >= timeout, "{:?}", elapsed);
+
+        let (client, server, _) = connect(failing(HandshakeFailure::AbruptClose), Handshake::new());
+        let (client, server) = (client.unwrap_err(), server.unwrap_err());
+        assert_eq!(client.kind(), io::ErrorKind::ConnectionAborted);
+        assert_eq!(server.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(
+            HandshakeFailure::of(&server),
+            Some(HandshakeFailure::AbruptClose)
+        );
+
+        let (client, server, _) =
+            connect(Handshake::new(), failing(HandshakeFailure::ProtocolError));
+        for err in [client.unwrap_err(), server.unwrap_err()].iter() {
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(
+                HandshakeFailure::of(err),
+                Some(HandshakeFailure::ProtocolError)
+            );
+        }
+        let plain = io::Error::new(io::ErrorKind::InvalidData, "other");
+        assert_eq!(HandshakeFailure::of(&plain), None);
+    }
+}
@@ -0,0 +1,366 @@
+//! Simulated machines.
+//!
+//! A [`Host`] bundles the pieces of a simulated machine which tests otherwise track by hand:
+//! an allocated address, a name the address can be resolved by, a runtime handle whose network
+//! operations originate from the address, and the set of tasks running on the machine. Killing
+//! a host aborts its tasks and removes it from the network, so a test can start five replicas
+//! and kill one without bookkeeping addresses. Restarting a host brings up a fresh instance at
+//! the same address, modeling a process which crashed and recovered. Shutting a host down
+//! instead cancels its tasks and waits until they have been dropped while leaving the host on
+//! the network, so that a rolling restart can stop one node's processes and start new ones.
+//!
+//! [`Host`]:`Host`
+use super::network::fault::FaultPermit;
+use super::{DeterministicRuntimeHandle, FaultKind};
+use futures::{channel::oneshot, future, Future, FutureExt, Poll};
+use std::{fmt, net, pin::Pin, sync, task::Context};
+use tracing::trace;
+
+/// Handle to a task spawned on a [`Host`], completing once the task has finished and been
+/// dropped, either after running to completion or after being aborted.
+///
+/// [`Host`]:`Host`
+#[derive(Clone)]
+pub struct HostTask {
+    abort: future::AbortHandle,
+    /// Resolves once the sender, held by the task until it has been dropped, is dropped.
+    finished: future::Shared<oneshot::Receiver<()>>,
+}
+
+impl HostTask {
+    /// Aborts the task, which is dropped the next time it is polled.
+    pub fn abort(&self) {
+        self.abort.abort();
+    }
+}
+
+impl fmt::Debug for HostTask {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostTask").finish()
+    }
+}
+
+impl Future for HostTask {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        self.finished.poll_unpin(cx).map(|_| ())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    killed: bool,
+    /// Permit of the kill, held against the fault budget until the host restarts.
+    kill_permit: Option<FaultPermit>,
+    tasks: Vec<HostTask>,
+}
+
+/// A simulated machine with its own address, tasks and view of the network.
+#[derive(Debug, Clone)]
+pub struct Host {
+    name: String,
+    handle: DeterministicRuntimeHandle,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Host {
+    pub(crate) fn new(name: String, handle: DeterministicRuntimeHandle) -> Self {
+        Self {
+            name,
+            handle,
+            state: sync::Arc::default(),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the address of the host, following any migrations.
+    pub fn addr(&self) -> net::IpAddr {
+        self.handle.current_addr()
+    }
+
+    /// Returns a handle scoped to the host. Connections and listeners created through the
+    /// handle use the address of the host.
+    pub fn handle(&self) -> &DeterministicRuntimeHandle {
+        &self.handle
+    }
+
+    /// Spawns a task on the host, named after the host, returning a handle to it. The task is
+    /// aborted when the host is killed or shut down, and tasks spawned on a killed host never
+    /// run.
+    pub fn spawn<F>(&self, future: F) -> HostTask
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let (future, abort) = future::abortable(future);
+        let (dropped, finished) = oneshot::channel::<()>();
+        let task = HostTask {
+            abort,
+            finished: finished.shared(),
+        };
+        let mut state = self.state.lock().unwrap();
+        if state.killed {
+            trace!("not spawning task on killed host {}", self.name);
+            return task;
+        }
+        state.tasks.push(task.clone());
+        drop(state);
+        self.handle.spawn_named(self.name.clone(), async move {
+            // The future is dropped once awaited, before the sender.
+            let _dropped = dropped;
+            let _ = future.await;
+        });
+        task
+    }
+
+    /// Cancels every task spawned on the host and waits until they have all been dropped,
+    /// releasing their listeners and connections. Unlike [`kill`], the host stays on the network
+    /// and new tasks can be spawned on it, as when its processes are stopped during a rolling
+    /// restart. Tasks are aborted in the order they were spawned, and tasks spawned while the
+    /// host is shutting down are aborted as well. Must not be awaited by a task of the host.
+    ///
+    /// [`kill`]:`Host::kill`
+    pub async fn shutdown(&self) {
+        loop {
+            let tasks: Vec<HostTask> = self.state.lock().unwrap().tasks.drain(..).collect();
+            if tasks.is_empty() {
+                return;
+            }
+            trace!("shutting down {} tasks of host {}", tasks.len(), self.name);
+            for task in tasks.iter() {
+                task.abort();
+            }
+            future::join_all(tasks).await;
+        }
+    }
+
+    /// Kills the host, aborting its tasks and removing it from the network. Connections to and
+    /// from the host are disconnected and further connections to it are refused, and its
+    /// filesystem is crashed, losing unsynced writes. Kills count against the [`FaultBudget`] as
+    /// [`FaultKind::HostKill`] faults, which stay active until the host restarts. Returns false
+    /// if the host was already killed, or if the budget refused the kill.
+    ///
+    /// [`FaultBudget`]:`super::FaultBudget`
+    /// [`FaultKind::HostKill`]:`FaultKind::HostKill`
+    pub fn kill(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.killed {
+            return false;
+        }
+        let permit = match self
+            .handle
+            .network_handle
+            .try_acquire_fault(FaultKind::HostKill)
+        {
+            Some(permit) => permit,
+            None => {
+                trace!("fault budget refused killing host {}", self.name);
+                return false;
+            }
+        };
+        trace!("killing host {}", self.name);
+        state.killed = true;
+        state.kill_permit.replace(permit);
+        for task in state.tasks.drain(..) {
+            task.abort();
+        }
+        drop(state);
+        self.handle.fs().crash();
+        self.handle.remove_host(self.addr());
+        true
+    }
+
+    /// Restarts the host as a fresh instance, as after a process crash. The host is killed if it
+    /// is alive, aborting its tasks, closing its listeners and disconnecting its connections.
+    /// Its address and name are then reinstated and the future returned by `start` is spawned
+    /// on the host. Nothing held by the tasks of the old instance survives other than what they
+    /// synced to the filesystem of the host, so any other state which should persist across
+    /// the crash must be owned outside of the host and handed to `start`. Returns false without
+    /// restarting the host if it is alive and the fault budget refused to kill it.
+    pub fn restart<F, T>(&self, start: F) -> bool
+    where
+        F: FnOnce(Host) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        if self.is_alive() && !self.kill() {
+            return false;
+        }
+        trace!("restarting host {}", self.name);
+        self.handle.reinstate_host(self.name.clone(), self.addr());
+        let mut state = self.state.lock().unwrap();
+        state.killed = false;
+        state.kill_permit.take();
+        drop(state);
+        self.spawn(start(self.clone()));
+        true
+    }
+
+    /// Returns true if the host has not been killed.
+    pub fn is_alive(&self) -> bool {
+        !self.state.lock().unwrap().killed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultBudget};
+    use crate::{Environment, TcpListener};
+    use std::{io, net};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that killing one of several replicas aborts its tasks and refuses connections to
+    /// it, while the other replicas keep serving.
+    fn kill_replica() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let replicas = runtime.block_on(async {
+            let replicas = handle.spawn_hosts("replica", 5);
+            for replica in replicas.iter() {
+                let addr = net::SocketAddr::new(replica.addr(), 80);
+                let mut listener = replica.handle().bind(addr).await.unwrap();
+                replica
+                    .spawn(async move { while let Ok((_socket, _)) = listener.accept().await {} });
+            }
+            assert_eq!(handle.resolve_host("replica-2"), Some(replicas[2].addr()));
+            assert!(replicas[2].kill());
+            assert!(!replicas[2].kill());
+            for replica in replicas.iter() {
+                let addr = net::SocketAddr::new(replica.addr(), 80);
+                match handle.connect(addr).await {
+                    Ok(_) => assert!(replica.is_alive()),
+                    Err(e) => {
+                        assert!(!replica.is_alive());
+                        assert_eq!(e.kind(), io::ErrorKind::ConnectionRefused);
+                    }
+                }
+            }
+            // Let the aborted task observe its abort.
+            handle.delay_from(std::time::Duration::from_millis(1)).await;
+            replicas
+        });
+        let report = runtime.leak_audit();
+        let mut running: Vec<&str> = report
+            .tasks()
+            .iter()
+            .filter_map(|(_, name)| name.as_ref().map(String::as_str))
+            .collect();
+        running.sort();
+        assert_eq!(
+            running,
+            vec!["replica-0", "replica-1", "replica-3", "replica-4"]
+        );
+        assert_eq!(replicas.iter().filter(|r| r.is_alive()).count(), 4);
+    }
+
+    /// Starts an instance of a server which counts its starts on a disk kept outside of the
+    /// host, replying to each connection with the count.
+    async fn serve(host: Host, disk: sync::Arc<sync::Mutex<u8>>) {
+        let starts = {
+            let mut starts = disk.lock().unwrap();
+            *starts += 1;
+            *starts
+        };
+        let addr = net::SocketAddr::new(host.addr(), 80);
+        let mut listener = host.handle().bind(addr).await.unwrap();
+        while let Ok((mut socket, _)) = listener.accept().await {
+            host.spawn(async move {
+                let _ = socket.write_all(&[starts]).await;
+                let _ = socket.read(&mut [0u8; 1]).await;
+            });
+        }
+    }
+
+    #[test]
+    /// Test that restarting a host resets the connections of the old instance and starts a
+    /// fresh instance at the same address, which keeps the state persisted outside the host.
+    fn crash_and_restart() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let host = handle.spawn_host("db");
+            let disk = sync::Arc::new(sync::Mutex::new(0));
+            let first = sync::Arc::clone(&disk);
+            host.spawn(serve(host.clone(), first));
+            let addr = net::SocketAddr::new(host.addr(), 80);
+            let mut socket = handle.connect(addr).await.unwrap();
+            let mut buf = [0u8; 1];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [1]);
+
+            let second = sync::Arc::clone(&disk);
+            host.restart(move |host| serve(host, second));
+            assert!(host.is_alive());
+            assert!(socket.read_exact(&mut buf).await.is_err());
+            assert_eq!(handle.resolve_host("db"), Some(addr.ip()));
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [2]);
+        });
+    }
+
+    #[test]
+    /// Test that shutting a host down drops its tasks and listeners while the host stays on the
+    /// network, so that a new instance can be started on it.
+    fn shutdown_tasks() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let host = handle.spawn_host("node");
+            let disk = sync::Arc::new(sync::Mutex::new(0));
+            host.spawn(serve(host.clone(), sync::Arc::clone(&disk)));
+            struct Guard(sync::Arc<sync::atomic::AtomicBool>);
+            impl Drop for Guard {
+                fn drop(&mut self) {
+                    self.0.store(true, sync::atomic::Ordering::SeqCst);
+                }
+            }
+            let dropped = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+            let guard = Guard(sync::Arc::clone(&dropped));
+            host.spawn(async move {
+                let _guard = guard;
+                future::pending::<()>().await;
+            });
+            host.spawn(async {}).await;
+            let addr = net::SocketAddr::new(host.addr(), 80);
+            let mut socket = handle.connect(addr).await.unwrap();
+            let mut buf = [0u8; 1];
+            socket.read_exact(&mut buf).await.unwrap();
+
+            host.shutdown().await;
+            assert!(host.is_alive());
+            let err = handle.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(dropped.load(sync::atomic::Ordering::SeqCst));
+
+            host.spawn(serve(host.clone(), sync::Arc::clone(&disk)));
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, [2]);
+        });
+    }
+
+    #[test]
+    /// Test that kills count against the fault budget until the killed host restarts, and that
+    /// restarting a live host is refused along with its kill.
+    fn budgeted_kills() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_fault_budget(FaultBudget::new().max_active(FaultKind::HostKill, 1));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let hosts = handle.spawn_hosts("node", 2);
+            assert!(hosts[0].kill());
+            assert!(
+                !hosts[1].kill(),
+                "expected a second concurrent kill to be refused"
+            );
+            assert!(hosts[1].is_alive());
+            assert!(!hosts[1].restart(|_| async {}));
+            assert!(hosts[0].restart(|_| async {}));
+            assert!(hosts[1].kill());
+        });
+    }
+}
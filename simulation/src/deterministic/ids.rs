@@ -0,0 +1,142 @@
+//! Deterministic generation of unique identifiers.
+//!
+//! Applications commonly tag requests with ids drawn from a global counter or a random UUID,
+//! both of which differ between runs and make traces from two runs of the same seed impossible
+//! to line up. An [`IdGenerator`] returned by [`DeterministicRuntimeHandle::id_generator`]
+//! produces sequential ids and UUID-shaped ids from a stream of randomness derived from the
+//! runtime seed and the generator name, so ids are identical across runs of a seed and drawing
+//! them does not perturb any other randomness in the simulation.
+//!
+//! [`IdGenerator`]:`IdGenerator`
+//! [`DeterministicRuntimeHandle::id_generator`]:`super::DeterministicRuntimeHandle::id_generator`
+use super::{DeterministicRandomHandle, RngAlgorithm, Seed};
+use std::{collections, fmt, sync};
+
+/// A UUID-shaped identifier. Generated ids are formatted as version 4 UUIDs, so they can be
+/// used wherever an application expects a random UUID.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Uuid([u8; 16]);
+
+impl Uuid {
+    pub fn from_bytes(bytes: [u8; 16]) -> Self {
+        Uuid(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        &self.0
+    }
+
+    pub fn as_u128(&self) -> u128 {
+        u128::from_be_bytes(self.0)
+    }
+}
+
+impl fmt::Display for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Uuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uuid({})", self)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    next: u64,
+    random: DeterministicRandomHandle,
+}
+
+/// Generator of deterministic sequential and UUID-shaped ids. Generators with the same name
+/// share a sequence.
+#[derive(Debug, Clone)]
+pub struct IdGenerator {
+    name: sync::Arc<str>,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl IdGenerator {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the next id in the sequence, starting from 1.
+    pub fn next_id(&self) -> u64 {
+        let mut lock = self.state.lock().unwrap();
+        let id = lock.next;
+        lock.next += 1;
+        id
+    }
+
+    /// Returns a UUID-shaped id drawn from the randomness of this generator.
+    pub fn next_uuid(&self) -> Uuid {
+        let lock = self.state.lock().unwrap();
+        let mut bytes = [0u8; 16];
+        bytes[..8].copy_from_slice(&lock.random.next_u64().to_be_bytes());
+        bytes[8..].copy_from_slice(&lock.random.next_u64().to_be_bytes());
+        // Set the version 4 and RFC 4122 variant bits.
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Uuid(bytes)
+    }
+}
+
+/// Registry of the id generators of a runtime, keyed by name.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct IdGenerators {
+    generators: sync::Arc<sync::Mutex<collections::HashMap<String, IdGenerator>>>,
+}
+
+impl IdGenerators {
+    pub(crate) fn get(&self, name: &str, seed: Seed, algorithm: RngAlgorithm) -> IdGenerator {
+        let mut generators = self.generators.lock().unwrap();
+        generators
+            .entry(name.to_string())
+            .or_insert_with(|| IdGenerator {
+                name: sync::Arc::from(name),
+                state: sync::Arc::new(sync::Mutex::new(State {
+                    next: 1,
+                    random: seed.derive("ids").derive(name).random_with(algorithm),
+                })),
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    fn draw(seed: u64) -> (Vec<u64>, Vec<Uuid>) {
+        let runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        let handle = runtime.localhost_handle();
+        let requests = handle.id_generator("requests");
+        let other_host = handle.add_host().id_generator("requests");
+        let ids = vec![requests.next_id(), other_host.next_id(), requests.next_id()];
+        let uuids = (0..2).map(|_| requests.next_uuid()).collect();
+        (ids, uuids)
+    }
+
+    #[test]
+    /// Test that generators sharing a name share a sequence, and that uuids are stable for a
+    /// seed.
+    fn ids() {
+        let (ids, uuids) = draw(5);
+        assert_eq!(ids, vec![1, 2, 3]);
+        assert_ne!(uuids[0], uuids[1]);
+        assert_eq!(draw(5).1, uuids);
+        assert_ne!(draw(6).1, uuids);
+        let formatted = uuids[0].to_string();
+        assert_eq!(formatted.len(), 36);
+        assert_eq!(&formatted[14..15], "4");
+    }
+}
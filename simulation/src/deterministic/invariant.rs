@@ -0,0 +1,140 @@
+//! Invariants checked while a simulation runs.
+//!
+//! Properties such as linearizability or the monotonicity of a counter are best checked from
+//! outside the tasks under test, at well-defined points of the run. Invariants registered with
+//! [`Simulation::register_invariant`] are evaluated after task polls according to their
+//! [`InvariantCheck`], and the first violated invariant fails the run with the seed of the
+//! simulation and the tail of its event log.
+//!
+//! [`Simulation::register_invariant`]:`super::Simulation::register_invariant`
+//! [`InvariantCheck`]:`InvariantCheck`
+use super::{EventLog, Seed, TaskId};
+use std::{fmt::Write, sync, time};
+
+/// Number of event log entries included in the report of a violated invariant.
+const LOG_TAIL: usize = 20;
+
+/// When an invariant is evaluated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvariantCheck {
+    /// After every poll of every task.
+    EveryStep,
+    /// After the first poll once each period of simulated time has elapsed.
+    Every(time::Duration),
+    /// After polls which leave no task runnable, before time is advanced.
+    OnQuiescence,
+}
+
+type Invariant = Box<dyn FnMut() -> Result<(), String> + Send>;
+
+struct Registered {
+    name: String,
+    check: InvariantCheck,
+    next_due: time::Instant,
+    invariant: Invariant,
+}
+
+#[derive(Default)]
+struct Inner {
+    invariants: Vec<Registered>,
+    seed: Option<Seed>,
+    log: Option<sync::Arc<EventLog>>,
+}
+
+/// Invariants registered with a runtime.
+#[derive(Clone, Default)]
+pub(crate) struct Invariants {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl std::fmt::Debug for Invariants {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let lock = self.inner.lock().unwrap();
+        let names: Vec<&str> = lock.invariants.iter().map(|i| i.name.as_str()).collect();
+        write!(f, "Invariants {{ {:?} }}", names)
+    }
+}
+
+impl Invariants {
+    /// Returns true if the seed and log used to report violations have been provided.
+    pub(crate) fn has_context(&self) -> bool {
+        self.inner.lock().unwrap().log.is_some()
+    }
+
+    /// Sets the seed and the event log included in reports of violated invariants.
+    pub(crate) fn set_context(&self, seed: Seed, log: sync::Arc<EventLog>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.seed = Some(seed);
+        lock.log = Some(log);
+    }
+
+    pub(crate) fn register<F>(&self, name: String, check: InvariantCheck, now: time::Instant, f: F)
+    where
+        F: FnMut() -> Result<(), String> + Send + 'static,
+    {
+        let next_due = match check {
+            InvariantCheck::Every(period) => now + period,
+            _ => now,
+        };
+        self.inner.lock().unwrap().invariants.push(Registered {
+            name,
+            check,
+            next_due,
+            invariant: Box::new(f),
+        });
+    }
+
+    /// Evaluates the invariants due after a poll of `task` at `now`, returning a report of the
+    /// first violated invariant. Violated invariants are removed, so each fails the run once.
+    pub(crate) fn check(
+        &self,
+        task: TaskId,
+        now: time::Instant,
+        quiescent: bool,
+    ) -> Option<String> {
+        let mut lock = self.inner.lock().unwrap();
+        let mut violation = None;
+        for (index, registered) in lock.invariants.iter_mut().enumerate() {
+            let due = match registered.check {
+                InvariantCheck::EveryStep => true,
+                InvariantCheck::OnQuiescence => quiescent,
+                InvariantCheck::Every(period) => {
+                    let due = now >= registered.next_due;
+                    while registered.next_due <= now && period > time::Duration::from_millis(0) {
+                        registered.next_due += period;
+                    }
+                    due
+                }
+            };
+            if !due {
+                continue;
+            }
+            if let Err(reason) = (registered.invariant)() {
+                violation = Some((index, reason));
+                break;
+            }
+        }
+        let (index, reason) = violation?;
+        let registered = lock.invariants.remove(index);
+        let mut report = format!(
+            "invariant {} violated after polling {}: {}",
+            registered.name, task, reason
+        );
+        if let Some(seed) = lock.seed {
+            let _ = write!(report, "\nseed {}", seed.value());
+        }
+        if let Some(log) = lock.log.as_ref() {
+            let entries = log.entries();
+            let tail = &entries[entries.len().saturating_sub(LOG_TAIL)..];
+            let _ = write!(report, "\nlast {} events:", tail.len());
+            for entry in tail {
+                let _ = write!(
+                    report,
+                    "\n  {} {:?} {:?}",
+                    entry.seq, entry.elapsed, entry.event
+                );
+            }
+        }
+        Some(report)
+    }
+}
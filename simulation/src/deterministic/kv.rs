@@ -0,0 +1,396 @@
+//! A simulated key-value store service.
+//!
+//! Systems under test often depend on an external store such as S3 or etcd, and tests
+//! otherwise stand in a hand written mock which neither crosses the network nor misbehaves the
+//! way a real store does. A [`KvStore`] is an [`ExternalService`], added to the simulation with
+//! [`DeterministicRuntimeHandle::add_dependency`], so requests are subject to partitions and
+//! the other faults of the network, and the returned [`DependencyFaults`] slow down, fail and
+//! drop requests as for any other dependency. On top of those, the store serves stale reads
+//! and takes separate time to serve reads and writes, see [`KvStore::set_stale_reads`] and
+//! [`KvStore::set_latency`].
+//!
+//! A [`KvClient`] speaks the protocol of the store over any [`Environment`]. Keys must not be
+//! empty or contain whitespace, and values are arbitrary bytes. Besides reads, writes and
+//! deletes, the store supports compare-and-swap. The contents of the store outlive outages of
+//! the dependency, modeling a durable store.
+//!
+//! [`KvStore`]:`KvStore`
+//! [`ExternalService`]:`super::ExternalService`
+//! [`DeterministicRuntimeHandle::add_dependency`]:`super::DeterministicRuntimeHandle::add_dependency`
+//! [`DependencyFaults`]:`super::DependencyFaults`
+//! [`KvStore::set_stale_reads`]:`KvStore::set_stale_reads`
+//! [`KvStore::set_latency`]:`KvStore::set_latency`
+//! [`KvClient`]:`KvClient`
+//! [`Environment`]:`crate::Environment`
+use super::{DependencyClient, DependencyError, DeterministicRandomHandle, ExternalService};
+use crate::Environment;
+use std::{collections, fmt, io, net, sync, time};
+
+/// A request made to the store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Request {
+    Get(String),
+    Put(String, Vec<u8>),
+    Delete(String),
+    CompareAndSwap(String, Option<Vec<u8>>, Vec<u8>),
+}
+
+impl Request {
+    fn is_write(&self) -> bool {
+        match self {
+            Request::Get(_) => false,
+            _ => true,
+        }
+    }
+
+    fn encode(&self) -> String {
+        match self {
+            Request::Get(key) => format!("GET {}", key),
+            Request::Put(key, value) => format!("PUT {} {}", key, encode_value(value)),
+            Request::Delete(key) => format!("DEL {}", key),
+            Request::CompareAndSwap(key, expected, value) => {
+                let expected = expected
+                    .as_ref()
+                    .map_or(String::from("-"), |e| encode_value(e));
+                format!("CAS {} {} {}", key, expected, encode_value(value))
+            }
+        }
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let parts: Vec<&str> = line.split(' ').collect();
+        let request = match parts.as_slice() {
+            ["GET", key] => Request::Get(key.to_string()),
+            ["PUT", key, value] => Request::Put(key.to_string(), decode_value(value)?),
+            ["DEL", key] => Request::Delete(key.to_string()),
+            ["CAS", key, "-", value] => {
+                Request::CompareAndSwap(key.to_string(), None, decode_value(value)?)
+            }
+            ["CAS", key, expected, value] => Request::CompareAndSwap(
+                key.to_string(),
+                Some(decode_value(expected)?),
+                decode_value(value)?,
+            ),
+            _ => return None,
+        };
+        Some(request)
+    }
+}
+
+/// Encodes a value as `x` followed by its bytes in hex, so that empty values are not empty.
+fn encode_value(value: &[u8]) -> String {
+    let mut encoded = String::with_capacity(1 + value.len() * 2);
+    encoded.push('x');
+    for byte in value {
+        encoded.push_str(&format!("{:02x}", byte));
+    }
+    encoded
+}
+
+fn decode_value(encoded: &str) -> Option<Vec<u8>> {
+    if !encoded.starts_with('x') || encoded.len() % 2 != 1 {
+        return None;
+    }
+    let hex = &encoded[1..];
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// The value of a key, along with the value it had before its latest write.
+#[derive(Debug, Clone, Default)]
+struct Entry {
+    current: Option<Vec<u8>>,
+    previous: Option<Vec<u8>>,
+}
+
+impl Entry {
+    fn write(&mut self, value: Option<Vec<u8>>) {
+        self.previous = std::mem::replace(&mut self.current, value);
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: collections::BTreeMap<String, Entry>,
+    stale_reads: f64,
+    read_latency: time::Duration,
+    write_latency: time::Duration,
+    random: Option<DeterministicRandomHandle>,
+}
+
+impl State {
+    /// Applies the request, returning the response payload. Reads respond with the encoded
+    /// value or `-` if the key has no value, and writes with whether they replaced, deleted or
+    /// swapped a value.
+    fn apply(&mut self, request: Request, stale: bool) -> String {
+        match request {
+            Request::Get(key) => {
+                let entry = self.entries.get(&key);
+                let value = entry.and_then(|entry| {
+                    if stale {
+                        entry.previous.as_ref()
+                    } else {
+                        entry.current.as_ref()
+                    }
+                });
+                value.map_or(String::from("-"), |value| encode_value(value))
+            }
+            Request::Put(key, value) => {
+                let entry = self.entries.entry(key).or_default();
+                let replaced = entry.current.is_some();
+                entry.write(Some(value));
+                replaced.to_string()
+            }
+            Request::Delete(key) => match self.entries.get_mut(&key) {
+                Some(entry) if entry.current.is_some() => {
+                    entry.write(None);
+                    true.to_string()
+                }
+                _ => false.to_string(),
+            },
+            Request::CompareAndSwap(key, expected, value) => {
+                let entry = self.entries.entry(key).or_default();
+                let swapped = entry.current == expected;
+                if swapped {
+                    entry.write(Some(value));
+                }
+                swapped.to_string()
+            }
+        }
+    }
+}
+
+/// A key-value store served as an [`ExternalService`], see the [module documentation](self).
+/// Clones share the contents of the store.
+///
+/// [`ExternalService`]:`super::ExternalService`
+#[derive(Debug, Clone, Default)]
+pub struct KvStore {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Serves each read with the provided probability from the value the key had before its
+    /// latest write, as when reading from a lagging replica.
+    pub fn set_stale_reads(&self, probability: f64) {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "stale read rate must be within [0, 1]"
+        );
+        self.state.lock().unwrap().stale_reads = probability;
+    }
+
+    /// Sets the time taken to serve each read and to apply and acknowledge each write, on top
+    /// of the latency configured through the faults of the dependency.
+    pub fn set_latency(&self, read: time::Duration, write: time::Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.read_latency = read;
+        state.write_latency = write;
+    }
+
+    /// Returns the current value of the key, bypassing the network and any faults.
+    pub fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .entries
+            .get(key)
+            .and_then(|entry| entry.current.clone())
+    }
+
+    /// Sets the value of the key, bypassing the network and any faults, as when seeding the
+    /// store before a test.
+    pub fn put(&self, key: impl Into<String>, value: impl Into<Vec<u8>>) {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entries.entry(key.into()).or_default();
+        entry.write(Some(value.into()));
+    }
+
+    /// Returns the keys with a value, in order.
+    pub fn keys(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let entries = state.entries.iter();
+        let keys = entries.filter(|(_, entry)| entry.current.is_some());
+        keys.map(|(key, _)| key.clone()).collect()
+    }
+}
+
+impl ExternalService for KvStore {
+    fn handle(&mut self, request: &str) -> Result<String, String> {
+        let request =
+            Request::decode(request).ok_or_else(|| format!("malformed request: {}", request))?;
+        let mut state = self.state.lock().unwrap();
+        let stale = !request.is_write()
+            && state.stale_reads > 0.0
+            && state
+                .random
+                .as_ref()
+                .map_or(false, |random| random.should_fault(state.stale_reads));
+        Ok(state.apply(request, stale))
+    }
+
+    fn set_random(&mut self, random: DeterministicRandomHandle) {
+        self.state.lock().unwrap().random = Some(random);
+    }
+
+    fn latency(&self, request: &str) -> time::Duration {
+        let state = self.state.lock().unwrap();
+        match Request::decode(request) {
+            Some(request) if request.is_write() => state.write_latency,
+            Some(_) => state.read_latency,
+            None => time::Duration::from_secs(0),
+        }
+    }
+}
+
+/// Client of a [`KvStore`], see the [module documentation](self).
+///
+/// [`KvStore`]:`KvStore`
+pub struct KvClient<E: Environment> {
+    client: DependencyClient<E>,
+}
+
+impl<E: Environment> fmt::Debug for KvClient<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KvClient").finish()
+    }
+}
+
+impl<E: Environment> KvClient<E> {
+    /// Connects to the store at the provided address.
+    pub async fn connect(env: &E, addr: net::SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            client: DependencyClient::connect(env, addr).await?,
+        })
+    }
+
+    /// Returns the value of the key, or `None` if it has no value.
+    pub async fn get(&mut self, key: &str) -> Result<Option<Vec<u8>>, DependencyError> {
+        let payload = self.request(Request::Get(check_key(key)?)).await?;
+        if payload == "-" {
+            return Ok(None);
+        }
+        decode_value(&payload)
+            .map(Some)
+            .ok_or_else(|| malformed(&payload))
+    }
+
+    /// Sets the value of the key, returning true if it replaced a value.
+    pub async fn put(&mut self, key: &str, value: &[u8]) -> Result<bool, DependencyError> {
+        let request = Request::Put(check_key(key)?, value.to_vec());
+        self.applied(request).await
+    }
+
+    /// Removes the value of the key, returning true if it had a value.
+    pub async fn delete(&mut self, key: &str) -> Result<bool, DependencyError> {
+        self.applied(Request::Delete(check_key(key)?)).await
+    }
+
+    /// Sets the value of the key if its current value is `expected`, where `None` expects the
+    /// key to have no value. Returns true if the value was set.
+    pub async fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&[u8]>,
+        value: &[u8],
+    ) -> Result<bool, DependencyError> {
+        let expected = expected.map(<[u8]>::to_vec);
+        let request = Request::CompareAndSwap(check_key(key)?, expected, value.to_vec());
+        self.applied(request).await
+    }
+
+    async fn applied(&mut self, request: Request) -> Result<bool, DependencyError> {
+        let payload = self.request(request).await?;
+        payload.parse().map_err(|_| malformed(&payload))
+    }
+
+    async fn request(&mut self, request: Request) -> Result<String, DependencyError> {
+        self.client.request(&request.encode()).await
+    }
+}
+
+fn check_key(key: &str) -> io::Result<String> {
+    if key.is_empty() || key.contains(char::is_whitespace) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "kv keys must not be empty or contain whitespace",
+        ));
+    }
+    Ok(String::from(key))
+}
+
+fn malformed(payload: &str) -> DependencyError {
+    let message = format!("malformed kv response {:?}", payload);
+    io::Error::new(io::ErrorKind::InvalidData, message).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that requests are served over the network, that the contents of the store outlive
+    /// outages of the dependency, and that faults slow down reads and writes separately and
+    /// serve stale reads.
+    fn serve_requests() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let store = KvStore::new();
+            let faults = handle
+                .add_dependency("etcd", 2379, store.clone())
+                .await
+                .unwrap();
+            let client = handle.add_host();
+            let mut kv = KvClient::connect(&client, faults.addr()).await.unwrap();
+
+            assert_eq!(kv.get("config").await.unwrap(), None);
+            assert!(!kv.put("config", b"v1").await.unwrap());
+            assert_eq!(kv.get("config").await.unwrap(), Some(b"v1".to_vec()));
+            assert!(kv
+                .compare_and_swap("config", Some(b"v1"), b"")
+                .await
+                .unwrap());
+            assert!(!kv.compare_and_swap("config", None, b"v3").await.unwrap());
+            assert_eq!(store.get("config"), Some(vec![]));
+            assert!(kv.delete("config").await.unwrap());
+            assert!(!kv.delete("config").await.unwrap());
+            match kv.get("two words").await {
+                Err(DependencyError::Io(e)) => assert_eq!(e.kind(), io::ErrorKind::InvalidInput),
+                other => panic!("expected an invalid key, got {:?}", other),
+            }
+
+            store.put("leader", "a");
+            kv.put("leader", b"b").await.unwrap();
+            store.set_stale_reads(1.0);
+            assert_eq!(kv.get("leader").await.unwrap(), Some(b"a".to_vec()));
+            store.set_stale_reads(0.0);
+
+            let ms = time::Duration::from_millis;
+            faults.set_latency(ms(5), ms(0));
+            store.set_latency(ms(10), ms(50));
+            let start = client.now();
+            kv.put("leader", b"c").await.unwrap();
+            assert_eq!(client.now() - start, ms(55));
+            let start = client.now();
+            assert_eq!(kv.get("leader").await.unwrap(), Some(b"c".to_vec()));
+            assert_eq!(client.now() - start, ms(15));
+
+            faults.set_error_rate(1.0);
+            assert!(kv.get("leader").await.is_err());
+            faults.set_error_rate(0.0);
+            faults.set_unavailable(true);
+            assert!(kv.get("leader").await.is_err());
+            faults.set_unavailable(false);
+            let mut kv = KvClient::connect(&client, faults.addr()).await.unwrap();
+            assert_eq!(kv.get("leader").await.unwrap(), Some(b"c".to_vec()));
+            assert_eq!(store.keys(), vec![String::from("leader")]);
+        });
+    }
+}
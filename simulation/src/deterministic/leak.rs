@@ -0,0 +1,139 @@
+//! Audit of the resources which remain once a simulation has completed.
+use super::TaskId;
+use std::{fmt, net, time};
+
+/// Resources left behind by a simulation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LeakReport {
+    tasks: Vec<(TaskId, Option<String>)>,
+    listeners: Vec<net::SocketAddr>,
+    connections: Vec<(net::SocketAddr, net::SocketAddr)>,
+    next_timer: Option<time::Duration>,
+}
+
+impl LeakReport {
+    pub(crate) fn new(
+        tasks: Vec<(TaskId, Option<String>)>,
+        listeners: Vec<net::SocketAddr>,
+        connections: Vec<(net::SocketAddr, net::SocketAddr)>,
+        next_timer: Option<time::Duration>,
+    ) -> Self {
+        Self {
+            tasks,
+            listeners,
+            connections,
+            next_timer,
+        }
+    }
+
+    /// Returns true if no resources were leaked.
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+            && self.listeners.is_empty()
+            && self.connections.is_empty()
+            && self.next_timer.is_none()
+    }
+
+    /// Returns the id and name of each task which has not completed.
+    pub fn tasks(&self) -> &[(TaskId, Option<String>)] {
+        &self.tasks
+    }
+
+    /// Returns the addresses of listeners which remain bound.
+    pub fn listeners(&self) -> &[net::SocketAddr] {
+        &self.listeners
+    }
+
+    /// Returns the source and destination of each connection which remains open.
+    pub fn connections(&self) -> &[(net::SocketAddr, net::SocketAddr)] {
+        &self.connections
+    }
+
+    /// Returns the duration until the next pending timer fires, if any timers are pending.
+    pub fn next_timer(&self) -> Option<time::Duration> {
+        self.next_timer
+    }
+}
+
+impl fmt::Display for LeakReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return write!(f, "no leaked resources");
+        }
+        for (id, name) in self.tasks.iter() {
+            match name {
+                Some(name) => writeln!(f, "task {} {} is still running", id, name)?,
+                None => writeln!(f, "task {} is still running", id)?,
+            }
+        }
+        for addr in self.listeners.iter() {
+            writeln!(f, "listener {} is still bound", addr)?;
+        }
+        for (source, dest) in self.connections.iter() {
+            writeln!(f, "connection {} -> {} is still open", source, dest)?;
+        }
+        if let Some(next_timer) = self.next_timer {
+            writeln!(f, "timer pending in {:?}", next_timer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::{net, time};
+
+    #[test]
+    /// Test that resources outliving the simulation are reported by name and that simulations
+    /// which clean up after themselves report no leaks.
+    fn leak_audit() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let (listener, client) = runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let accept_handle = handle.clone();
+            handle.spawn_named("acceptor", async move {
+                let _socket = listener.accept().await.unwrap();
+                accept_handle
+                    .delay_from(time::Duration::from_secs(30))
+                    .await;
+            });
+            let listener = handle.bind("127.0.0.1:9093".parse::<net::SocketAddr>().unwrap());
+            let listener = listener.await.unwrap();
+            let client = handle.connect(addr).await.unwrap();
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            (listener, client)
+        });
+        let report = runtime.leak_audit();
+        assert_eq!(report.tasks().len(), 1);
+        assert_eq!(report.tasks()[0].1.as_ref().unwrap(), "acceptor");
+        let listeners: Vec<net::SocketAddr> = vec![addr, "127.0.0.1:9093".parse().unwrap()];
+        assert_eq!(report.listeners(), &listeners[..]);
+        assert_eq!(report.connections().len(), 1);
+        assert_eq!(report.connections()[0].1, addr);
+        assert_eq!(report.next_timer(), Some(time::Duration::from_secs(29)));
+        assert!(report.to_string().contains("acceptor"));
+
+        drop((listener, client));
+        runtime.run().unwrap();
+        assert!(runtime.leak_audit().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "leaked resources")]
+    /// Test that enabling the leak check fails the simulation.
+    fn leak_check() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_leak_check(true);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let delay_handle = handle.clone();
+            handle.spawn_named("sleeper", async move {
+                delay_handle.delay_from(time::Duration::from_secs(10)).await;
+            });
+        });
+    }
+}
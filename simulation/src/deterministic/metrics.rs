@@ -0,0 +1,314 @@
+//! Metrics recorded against virtual time.
+//!
+//! Samples recorded through [`Metrics`] are stamped with the virtual time elapsed since the
+//! runtime was created. Because every run with the same seed produces the same samples, windows
+//! over a metric can be asserted on exactly, such as the p99 of request latency between 30s and
+//! 60s, allowing simulations to catch performance regressions alongside correctness bugs.
+//!
+//! [`Metrics`]:`Metrics`
+use super::DeterministicTimeHandle;
+use std::{collections, fmt, ops, sync, time};
+
+/// Statistic computed over the samples of a [`Window`].
+///
+/// [`Window`]:`Window`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Statistic {
+    Count,
+    Sum,
+    Mean,
+    Min,
+    Max,
+    /// The nearest-rank percentile, between 0 and 100.
+    Percentile(f64),
+    /// The number of samples per second of the window.
+    Rate,
+}
+
+impl fmt::Display for Statistic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Statistic::Count => write!(f, "count"),
+            Statistic::Sum => write!(f, "sum"),
+            Statistic::Mean => write!(f, "mean"),
+            Statistic::Min => write!(f, "min"),
+            Statistic::Max => write!(f, "max"),
+            Statistic::Percentile(p) => write!(f, "p{}", p),
+            Statistic::Rate => write!(f, "rate"),
+        }
+    }
+}
+
+/// Named series of samples recorded during a simulation, shared by every host of a runtime.
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    handle: DeterministicTimeHandle,
+    start: time::Instant,
+    series: sync::Arc<sync::Mutex<collections::HashMap<String, Vec<(time::Duration, f64)>>>>,
+}
+
+impl Metrics {
+    pub(crate) fn new(handle: DeterministicTimeHandle) -> Self {
+        let start = handle.now();
+        Self {
+            handle,
+            start,
+            series: sync::Arc::default(),
+        }
+    }
+
+    /// Records a sample for the named metric at the current virtual time.
+    pub fn record(&self, name: &str, value: f64) {
+        let elapsed = self.handle.now() - self.start;
+        self.series
+            .lock()
+            .unwrap()
+            .entry(String::from(name))
+            .or_default()
+            .push((elapsed, value));
+    }
+
+    /// Records a duration for the named metric, stored in seconds.
+    pub fn record_duration(&self, name: &str, duration: time::Duration) {
+        self.record(name, duration.as_secs_f64())
+    }
+
+    /// Returns the samples of the named metric recorded within `range`, measured from the
+    /// creation of the runtime. The window is empty if the metric was never recorded.
+    pub fn window(&self, name: &str, range: ops::Range<time::Duration>) -> Window {
+        let values = self
+            .series
+            .lock()
+            .unwrap()
+            .get(name)
+            .map(|samples| {
+                samples
+                    .iter()
+                    .filter(|(at, _)| range.start <= *at && *at < range.end)
+                    .map(|(_, value)| *value)
+                    .collect()
+            })
+            .unwrap_or_default();
+        Window {
+            name: String::from(name),
+            range,
+            values,
+        }
+    }
+
+    /// Returns the samples of the named metric recorded so far.
+    pub fn all(&self, name: &str) -> Window {
+        let end = self.handle.now() - self.start + time::Duration::from_nanos(1);
+        self.window(name, time::Duration::from_secs(0)..end)
+    }
+}
+
+/// Samples of a metric recorded within a range of virtual time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Window {
+    name: String,
+    range: ops::Range<time::Duration>,
+    values: Vec<f64>,
+}
+
+impl Window {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn range(&self) -> ops::Range<time::Duration> {
+        self.range.clone()
+    }
+
+    /// Returns the samples in the order they were recorded.
+    pub fn values(&self) -> &[f64] {
+        &self.values
+    }
+
+    /// Computes the statistic over the window, returning `None` if the statistic is undefined
+    /// for an empty window.
+    pub fn get(&self, statistic: Statistic) -> Option<f64> {
+        let count = self.values.len();
+        let sum: f64 = self.values.iter().sum();
+        match statistic {
+            Statistic::Count => Some(count as f64),
+            Statistic::Sum => Some(sum),
+            Statistic::Rate => {
+                let secs = (self.range.end - self.range.start).as_secs_f64();
+                if secs > 0.0 {
+                    Some(count as f64 / secs)
+                } else {
+                    None
+                }
+            }
+            _ if count == 0 => None,
+            Statistic::Mean => Some(sum / count as f64),
+            Statistic::Min => self.values.iter().cloned().fold(None, |min, v| match min {
+                Some(min) if min <= v => Some(min),
+                _ => Some(v),
+            }),
+            Statistic::Max => self.values.iter().cloned().fold(None, |max, v| match max {
+                Some(max) if max >= v => Some(max),
+                _ => Some(v),
+            }),
+            Statistic::Percentile(p) => {
+                assert!(
+                    p >= 0.0 && p <= 100.0,
+                    "percentile must be between 0 and 100"
+                );
+                let mut sorted = self.values.clone();
+                sorted.sort_by(|a, b| a.partial_cmp(b).expect("metric value is NaN"));
+                let rank = (p / 100.0 * count as f64).ceil() as usize;
+                Some(sorted[rank.max(1) - 1])
+            }
+        }
+    }
+
+    /// Computes a statistic over a metric recorded with [`Metrics::record_duration`].
+    ///
+    /// [`Metrics::record_duration`]:`Metrics::record_duration`
+    pub fn duration(&self, statistic: Statistic) -> Option<time::Duration> {
+        self.get(statistic).map(time::Duration::from_secs_f64)
+    }
+
+    /// Checks that the statistic is defined and strictly below `limit`.
+    pub fn check_below(&self, statistic: Statistic, limit: f64) -> Result<(), MetricViolation> {
+        match self.get(statistic) {
+            Some(value) if value < limit => Ok(()),
+            value => Err(MetricViolation {
+                name: self.name.clone(),
+                range: self.range.clone(),
+                statistic,
+                value,
+                limit,
+            }),
+        }
+    }
+
+    /// Checks that the statistic is defined and strictly above `limit`.
+    pub fn check_above(&self, statistic: Statistic, limit: f64) -> Result<(), MetricViolation> {
+        match self.get(statistic) {
+            Some(value) if value > limit => Ok(()),
+            value => Err(MetricViolation {
+                name: self.name.clone(),
+                range: self.range.clone(),
+                statistic,
+                value,
+                limit,
+            }),
+        }
+    }
+
+    /// Panics unless the statistic is below `limit`.
+    pub fn assert_below(&self, statistic: Statistic, limit: f64) {
+        if let Err(violation) = self.check_below(statistic, limit) {
+            panic!("{}", violation)
+        }
+    }
+
+    /// Panics unless the statistic is above `limit`.
+    pub fn assert_above(&self, statistic: Statistic, limit: f64) {
+        if let Err(violation) = self.check_above(statistic, limit) {
+            panic!("{}", violation)
+        }
+    }
+
+    /// Panics unless the statistic of a duration metric is below `limit`.
+    pub fn assert_duration_below(&self, statistic: Statistic, limit: time::Duration) {
+        self.assert_below(statistic, limit.as_secs_f64())
+    }
+}
+
+/// A statistic over a window of a metric which did not meet its limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricViolation {
+    name: String,
+    range: ops::Range<time::Duration>,
+    statistic: Statistic,
+    value: Option<f64>,
+    limit: f64,
+}
+
+impl MetricViolation {
+    pub fn statistic(&self) -> Statistic {
+        self.statistic
+    }
+
+    /// Returns the value of the statistic, or `None` if the window had no samples.
+    pub fn value(&self) -> Option<f64> {
+        self.value
+    }
+
+    pub fn limit(&self) -> f64 {
+        self.limit
+    }
+}
+
+impl fmt::Display for MetricViolation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} between {:?} and {:?} ",
+            self.statistic, self.name, self.range.start, self.range.end
+        )?;
+        match self.value {
+            Some(value) => write!(f, "was {}, outside the limit of {}", value, self.limit),
+            None => write!(f, "had no samples, the limit was {}", self.limit),
+        }
+    }
+}
+
+impl std::error::Error for MetricViolation {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+
+    #[test]
+    /// Test that statistics only cover the samples recorded within their window, and that
+    /// violations describe the window which failed.
+    fn windowed_percentiles() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let metrics = runtime.metrics();
+        runtime.block_on(async {
+            // Latency degrades to 1s after 30s of requests.
+            for i in 0..60u64 {
+                let latency = if i < 30 { 10 } else { 1000 };
+                handle.delay_from(time::Duration::from_secs(1)).await;
+                metrics.record_duration("latency", time::Duration::from_millis(latency));
+            }
+        });
+        let secs = time::Duration::from_secs;
+        let early = metrics.window("latency", secs(0)..secs(30));
+        assert_eq!(early.get(Statistic::Count), Some(29.0));
+        early.assert_duration_below(
+            Statistic::Percentile(99.0),
+            time::Duration::from_millis(500),
+        );
+        assert_eq!(early.get(Statistic::Rate), Some(29.0 / 30.0));
+
+        let late = metrics.window("latency", secs(30)..secs(60));
+        let violation = late
+            .check_below(Statistic::Percentile(99.0), 0.5)
+            .unwrap_err();
+        assert_eq!(violation.value(), Some(1.0));
+        assert_eq!(
+            violation.to_string(),
+            "p99 of latency between 30s and 60s was 1, outside the limit of 0.5"
+        );
+        assert_eq!(
+            late.duration(Statistic::Min),
+            Some(time::Duration::from_millis(10))
+        );
+        assert_eq!(metrics.all("latency").get(Statistic::Count), Some(60.0));
+        assert_eq!(
+            metrics
+                .window("missing", secs(0)..secs(60))
+                .get(Statistic::Mean),
+            None
+        );
+    }
+}
@@ -10,22 +10,126 @@
 //! - `DeterministicNetwork` provides a process wide networking in memory networking implementation.
 //!
 //! `DeterministicRuntime` uses these to support deterministic task scheduling and fault injection.
-use crate::Error;
+use crate::{
+    calendar::{DateTime, TimeZone},
+    Error,
+};
 use async_trait::async_trait;
 use futures::Future;
 use std::{
-    io, net,
-    time::{Duration, Instant},
+    io, net, sync,
+    time::{Duration, Instant, SystemTime},
 };
 
+mod blocking;
+mod buggify;
+mod channel;
+mod chrome_trace;
+mod clock;
+mod compression;
+#[cfg(feature = "config")]
+mod config;
+mod coverage;
+mod cpu;
+mod dependency;
+mod dns;
+mod evacuation;
+mod event_log;
+mod events;
+mod fs;
+mod handshake;
+mod host;
+mod ids;
+mod invariant;
+mod kv;
+mod leak;
+mod metrics;
+mod nemesis;
 mod network;
+mod nondeterminism;
+mod observer;
+mod priority;
 mod random;
+mod replay;
+mod scenario;
+mod simulation;
+mod simulation_metrics;
+mod sleep;
+mod snapshot;
+mod stall;
+mod startup;
+#[cfg(feature = "proptest")]
+mod strategy;
+mod task;
+mod teardown;
+mod template;
 mod time;
+mod timeout;
+#[cfg(feature = "tracing-subscriber")]
+mod trace_layer;
+mod watchdog;
+mod watermark;
+mod worker_pool;
+pub use blocking::{BlockingCall, BlockingKind};
+pub use buggify::BuggifyDecision;
+pub use channel::{ChannelClosed, ChannelFaults, FaultyReceiver, FaultySender};
+pub use clock::{ClockOffset, HostClock};
+pub use compression::{CompressedStream, Compression};
+#[cfg(feature = "config")]
+pub use config::{ConfigError, FaultSpec, HostSpec, LinkSpec, SimulationSpec};
+pub use coverage::{Coverage, CoverageRecorder, DecisionPoint};
+pub use dependency::{
+    BlobStore, DependencyClient, DependencyError, DependencyFaults, ExternalService, Mail, MailSink,
+};
+pub use dns::DnsFaults;
+pub use evacuation::{EvacuatedHost, Evacuation, EvacuationReport};
+pub use event_log::{EventLog, LogEntry, LogEvent};
+pub use events::{Breakpoint, BreakpointId, SimulationEvent};
+pub use fs::{File, Filesystem, FsFaults};
+pub use handshake::{Handshake, HandshakeFailure, HandshakeStream};
+pub use host::{Host, HostTask};
+pub use ids::{IdGenerator, Uuid};
+pub use invariant::InvariantCheck;
+pub use kv::{KvClient, KvStore};
+pub use leak::LeakReport;
+pub use metrics::{MetricViolation, Metrics, Statistic, Window};
+pub use nemesis::{Nemesis, NemesisAction, NemesisOp};
+pub use network::{
+    AcceptFaults, ConnectOptions, ConnectionId, ConnectionIdExt, ConnectionInfo, ConnectionSide,
+    DatagramFaults, FaultAction, FaultBudget, FaultId, FaultKind, FaultProvenance,
+    FaultProvenanceExt, FaultSchedule, FaultScheduleBuilder, FaultTarget, FaultyTcpStreamHandle,
+    InjectionPoint, Keepalive, LatencyModel, LimitPolicy, LinkFaultHandle, Listener, ListenerInfo,
+    ListenerOptions, MessageTap, MigrationPolicy, NetworkProfile, PartitionMode, ResourceLimits,
+    ResourceUsage, ScheduledFault, SegmentFaults, Socket, SocketBuffers, TappedMessage, UdpSocket,
+    UnconsumedData, UnconsumedDataPolicy,
+};
 pub(crate) use network::{DeterministicNetwork, DeterministicNetworkHandle};
-pub use network::{Listener, Socket};
-pub(crate) use random::{DeterministicRandom, DeterministicRandomHandle};
+pub use nondeterminism::{check_determinism, Divergence, NondeterminismKind, NondeterministicCall};
+pub use observer::Observer;
+pub use priority::PriorityPolicy;
+pub(crate) use random::DeterministicRandom;
+pub use random::{DeterministicRandomHandle, RngAlgorithm, Seed};
+pub use replay::DecisionTrace;
+pub use scenario::{Phase, Scenario, ScenarioError, ScenarioReport};
+pub use simulation::Simulation;
+pub use simulation_metrics::{ConnectionMetrics, HostMetrics, MetricsRecorder, SimulationMetrics};
+pub use sleep::{Elapsed, Interval, Sleep, Timeout};
+pub use snapshot::Snapshot;
+pub use stall::{StallCondition, StallReport};
+pub use startup::{ServiceOutcome, StartupError, StartupGraph, StartupOrder, StartupReport};
+#[cfg(feature = "proptest")]
+pub use strategy::{topology, topology_with_schedule, FaultStrategy, Topology};
+pub(crate) use task::choose_waiter;
+pub use task::{ProfileReport, TaskId, TaskProfile, WaitResource};
+pub use template::HostTemplate;
 pub(crate) use time::{DeterministicTime, DeterministicTimeHandle};
+pub use timeout::{SimTimeout, SimTimeoutError, WakeSource};
 use tokio_net::driver;
+#[cfg(feature = "tracing-subscriber")]
+pub use trace_layer::{check_log_determinism, LogMismatch, SimulationLayer};
+use tracing::trace;
+pub use watermark::{Buffer, HighWatermark};
+pub use worker_pool::BlockingPool;
 
 #[derive(Debug, Clone)]
 pub struct DeterministicRuntimeHandle {
@@ -33,33 +137,656 @@ pub struct DeterministicRuntimeHandle {
     network_handle: DeterministicNetworkHandle,
     executor_handle: tokio_executor::current_thread::Handle,
     random_handle: DeterministicRandomHandle,
+    seed: Seed,
+    tasks: task::Tasks,
+    cpus: cpu::Cpus,
+    clocks: clock::Clocks,
+    filesystems: fs::Filesystems,
+    dns: dns::Dns,
+    ids: ids::IdGenerators,
+    substreams: random::Substreams,
+    buggify: buggify::Buggify,
+    metrics: metrics::Metrics,
+    teardowns: teardown::Teardowns,
+    worker_pools: worker_pool::WorkerPools,
 }
 
 impl DeterministicRuntimeHandle {
     pub fn now(&self) -> Instant {
         self.time_handle.now()
     }
+    /// Returns the local date and time according to the runtime's time zone.
+    pub fn local_time(&self) -> DateTime {
+        self.time_handle.local_time()
+    }
     pub fn time_handle(&self) -> time::DeterministicTimeHandle {
         self.time_handle.clone()
     }
     pub fn random_handle(&self) -> DeterministicRandomHandle {
         self.random_handle.clone()
     }
+    /// Returns the seed the runtime was created with.
+    pub fn seed(&self) -> Seed {
+        self.seed
+    }
+    /// Runs the provided future until it completes or the timeout elapses. Unlike
+    /// [`Environment::timeout`], the returned error describes what the future was last waiting
+    /// on, see [`SimTimeoutError`].
+    ///
+    /// [`Environment::timeout`]:`crate::Environment::timeout`
+    /// [`SimTimeoutError`]:`SimTimeoutError`
+    pub fn sim_timeout<F>(&self, timeout: Duration, future: F) -> SimTimeout<F>
+    where
+        F: Future,
+    {
+        SimTimeout::new(self.time_handle.clone(), timeout, future)
+    }
+    /// Returns a future which completes once `duration` has elapsed in simulated time. Timers
+    /// sharing a deadline fire in the order they were created.
+    pub fn delay_for(&self, duration: Duration) -> Sleep {
+        self.time_handle.sleep_until(self.now() + duration)
+    }
+    /// Returns a stream which ticks immediately and then every `period` of simulated time.
+    pub fn interval(&self, period: Duration) -> Interval {
+        Interval::new(self.time_handle.sleep_until(self.now()), period)
+    }
+    /// Runs the provided future until it completes or `timeout` elapses in simulated time,
+    /// ordered with the timers of [`delay_for`] and [`interval`]. This takes precedence over
+    /// [`Environment::timeout`], which is built on the tokio timer.
+    ///
+    /// [`delay_for`]:`DeterministicRuntimeHandle::delay_for`
+    /// [`interval`]:`DeterministicRuntimeHandle::interval`
+    /// [`Environment::timeout`]:`crate::Environment::timeout`
+    pub fn timeout<F>(&self, future: F, timeout: Duration) -> Timeout<F>
+    where
+        F: Future,
+    {
+        Timeout::new(future, self.time_handle.sleep_until(self.now() + timeout))
+    }
+    /// Returns a source of randomness derived from the runtime seed and the provided label, see
+    /// [`Seed::derive`].
+    ///
+    /// [`Seed::derive`]:`Seed::derive`
+    pub fn derive_random(&self, label: &str) -> DeterministicRandomHandle {
+        self.seed
+            .derive(label)
+            .random_with(self.random_handle.algorithm())
+    }
+    /// Returns a source of randomness for application code, in place of `rand::thread_rng`.
+    /// Each task of each host draws from its own substream of the runtime seed, so the values
+    /// drawn by a task do not depend on the number of values drawn by other tasks. Outside of
+    /// a task, the substream of the host is returned.
+    pub fn rng(&self) -> DeterministicRandomHandle {
+        self.substreams.get(
+            self.local_addr(),
+            task::current(),
+            self.seed,
+            self.random_handle.algorithm(),
+        )
+    }
+    /// Takes a snapshot of the run at this point, from which runs exploring different decisions
+    /// can be branched with [`DeterministicRuntime::new_branching`]. Returns `None` unless the
+    /// runtime is recording or branching.
+    ///
+    /// [`DeterministicRuntime::new_branching`]:`DeterministicRuntime::new_branching`
+    pub fn snapshot(&self) -> Option<Snapshot> {
+        let trace = replay::Tape::current()?.recorded()?;
+        Some(Snapshot::new(trace, self.now()))
+    }
+    /// Returns true if application code should take a rare path, see [`buggify!`]. Every call
+    /// shares a single site, which is active for some seeds and dormant for others.
+    ///
+    /// [`buggify!`]:`crate::buggify`
+    pub fn buggify(&self, probability: f64) -> bool {
+        self.buggify_site("handle", probability)
+    }
+    /// Evaluates the buggify point identified by `site`, returning true if it is active for
+    /// this run and fires with the provided probability.
+    pub fn buggify_site(&self, site: &str, probability: f64) -> bool {
+        self.buggify.evaluate(
+            site,
+            probability,
+            self.seed,
+            self.random_handle.algorithm(),
+            self.now(),
+            task::current(),
+            self.time_handle.events().observers(),
+        )
+    }
+    /// Returns the id generator with the provided name. Generators are shared by every host of
+    /// the runtime, and their ids only depend on the runtime seed and the generator name.
+    pub fn id_generator(&self, name: &str) -> IdGenerator {
+        self.ids
+            .get(name, self.seed, self.random_handle.algorithm())
+    }
+    /// Returns the metrics of the runtime, which are shared by every host.
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+    /// Spawn a task with the provided name, which is used to identify the task in diagnostics.
+    pub fn spawn_named<F>(&self, name: impl Into<String>, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let future = self.tasks.instrument(Some(name.into()), future);
+        self.tasks.set_host(future.id(), self.local_addr());
+        self.executor_handle.spawn(future).expect("failed to spawn");
+    }
+    /// Creates a bounded channel between tasks on this host. Faults such as queueing delay and
+    /// dropped messages can be injected through the [`ChannelFaults`] of either half.
+    ///
+    /// [`ChannelFaults`]:`ChannelFaults`
+    pub fn channel<T>(
+        &self,
+        name: impl Into<String>,
+        capacity: usize,
+    ) -> (FaultySender<T>, FaultyReceiver<T>) {
+        channel::channel(
+            name.into(),
+            capacity,
+            self.time_handle.clone(),
+            self.random_handle.clone(),
+            self.network_handle.watermarks(),
+        )
+    }
+    /// Spawn a named task into the provided priority class. Classes are assigned priorities with
+    /// [`DeterministicRuntime::set_priority_class`].
+    ///
+    /// [`DeterministicRuntime::set_priority_class`]:`DeterministicRuntime::set_priority_class`
+    pub fn spawn_in_class<F>(&self, class: impl Into<String>, name: impl Into<String>, future: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let future = self.tasks.instrument(Some(name.into()), future);
+        self.tasks.set_class(future.id(), class.into());
+        self.tasks.set_host(future.id(), self.local_addr());
+        self.executor_handle.spawn(future).expect("failed to spawn");
+    }
+    /// Export the wait-for graph of all tasks in DOT format.
+    pub fn wait_for_graph(&self) -> String {
+        self.tasks.wait_for_graph()
+    }
+    /// Returns the virtual time profile of all tasks spawned on the runtime.
+    pub fn profile(&self) -> ProfileReport {
+        self.tasks.profile()
+    }
+    /// Register a breakpoint. The provided callback is invoked with the matching event while
+    /// the simulation is suspended, and may inspect the simulation through a runtime handle.
+    pub fn add_breakpoint<F>(&self, breakpoint: Breakpoint, callback: F) -> BreakpointId
+    where
+        F: FnMut(&SimulationEvent) + Send + 'static,
+    {
+        self.time_handle
+            .events()
+            .add_breakpoint(breakpoint, callback)
+    }
+    /// Remove a previously registered breakpoint.
+    pub fn remove_breakpoint(&self, id: BreakpointId) {
+        self.time_handle.events().remove_breakpoint(id)
+    }
+    /// Registers an observer notified of the activity of every host of the runtime.
+    pub fn add_observer(&self, observer: sync::Arc<dyn Observer>) {
+        self.time_handle.events().observers().add(observer)
+    }
+    /// Advances time by `duration`, returning once it has elapsed. While time is paused, see
+    /// [`set_time_paused`], this is how time is moved forward, and timers up to the new time
+    /// fire in order of their deadlines. Otherwise time advances on its own, and this behaves
+    /// as a delay.
+    ///
+    /// [`set_time_paused`]:`DeterministicRuntimeHandle::set_time_paused`
+    pub async fn advance(&self, duration: Duration) {
+        self.time_handle.advance_paused(duration).await;
+    }
+    /// Waits until no tasks are runnable, then advances time to the deadline of the next timer,
+    /// returning the duration advanced by, or `None` if no timers are pending. Timers which
+    /// were dropped before their deadline are still counted.
+    pub async fn advance_to_next_timer(&self) -> Option<Duration> {
+        let next_timer = self.time_handle.wait_idle().await.unwrap_or(None)?;
+        self.advance(next_timer).await;
+        Some(next_timer)
+    }
+    /// Pauses or resumes time. While paused, time only moves when advanced with [`advance`] or
+    /// [`advance_to_next_timer`], allowing tests to step to exact instants such as the expiry
+    /// of a lease. A paused simulation whose tasks are all waiting on timers beyond the time
+    /// advanced to is deadlocked. Time advances on its own by default.
+    ///
+    /// [`advance`]:`DeterministicRuntimeHandle::advance`
+    /// [`advance_to_next_timer`]:`DeterministicRuntimeHandle::advance_to_next_timer`
+    pub fn set_time_paused(&self, paused: bool) {
+        self.time_handle.set_paused(paused);
+    }
+    /// Returns true if time is paused, see [`set_time_paused`].
+    ///
+    /// [`set_time_paused`]:`DeterministicRuntimeHandle::set_time_paused`
+    pub fn is_time_paused(&self) -> bool {
+        self.time_handle.is_paused()
+    }
+    /// Waits until no tasks are runnable, then reports the conditions every other task is
+    /// waiting on along with the time until the next timer fires. Time is not advanced while
+    /// waiting.
+    pub async fn explain_stall(&self) -> StallReport {
+        let next_timer = self.time_handle.wait_idle().await.unwrap_or(None);
+        let waiting = self.tasks.waiting(task::current());
+        StallReport::new(waiting, next_timer, |resource| {
+            describe_resource(resource, |addr| self.network_handle.is_bound(addr))
+        })
+    }
+    /// Assigns this host the provided number of virtual cores, or an unlimited number if
+    /// `None`. Charges made with [`consume_cpu`] queue once every core is busy.
+    ///
+    /// [`consume_cpu`]:`DeterministicRuntimeHandle::consume_cpu`
+    pub fn set_cores(&self, cores: Option<usize>) {
+        self.cpus.set_cores(self.local_addr(), cores, self.now());
+    }
+    /// Returns a delay which completes once `duration` of work has run on one of the cores of
+    /// this host. The work is queued when this is called, behind any work already occupying
+    /// every core.
+    pub fn consume_cpu(&self, duration: Duration) -> tokio_timer::Delay {
+        let finish = self.cpus.charge(self.local_addr(), duration, self.now());
+        self.time_handle.delay(finish)
+    }
+    /// Charges the provided cost for every poll of the tasks spawned through handles scoped to
+    /// this host, or removes the cost if `None`. A woken task is only polled once the cost of
+    /// the poll has run on one of the cores of the host, queueing behind the polls and the
+    /// work charged with [`consume_cpu`] of the other tasks of the host. Polls are free by
+    /// default.
+    ///
+    /// [`consume_cpu`]:`DeterministicRuntimeHandle::consume_cpu`
+    pub fn set_poll_cost(&self, cost: Option<Duration>) {
+        self.cpus.set_poll_cost(self.local_addr(), cost);
+    }
+    /// Returns the total CPU time charged to this host, if it has been assigned cores.
+    pub fn cpu_consumed(&self) -> Option<Duration> {
+        self.cpus.consumed(self.local_addr())
+    }
+    /// Models the thread pool this host runs [`spawn_blocking`] calls on, or completes them
+    /// immediately if `None`, see [`BlockingPool`].
+    ///
+    /// [`spawn_blocking`]:`DeterministicRuntimeHandle::spawn_blocking`
+    /// [`BlockingPool`]:`BlockingPool`
+    pub fn set_blocking_pool(&self, pool: Option<BlockingPool>) {
+        self.worker_pools.set(self.local_addr(), pool, self.now());
+    }
+    /// Runs the provided blocking closure inline on the executor thread, returning its result
+    /// once the blocking pool of this host would have completed it. No real threads are
+    /// spawned, so the closure observes the simulation deterministically. Closures which block
+    /// on real time are still reported by blocking detection.
+    pub async fn spawn_blocking<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let finish = self.worker_pools.schedule(self.local_addr(), self.now());
+        let result = f();
+        if finish > self.now() {
+            self.time_handle.delay(finish).await;
+        }
+        result
+    }
+    /// Limits the connections this host can hold open and the bytes its connections can
+    /// buffer, see [`ResourceLimits`]. Passing `None` removes the limits.
+    ///
+    /// [`ResourceLimits`]:`ResourceLimits`
+    pub fn set_resource_limits(&self, limits: Option<ResourceLimits>) {
+        self.network_handle.set_resource_limits(limits);
+    }
+    /// Slows, fails and sheds the accepts of the listeners of this host, see [`AcceptFaults`].
+    /// Passing `None` removes the faults.
+    ///
+    /// [`AcceptFaults`]:`AcceptFaults`
+    pub fn set_accept_faults(&self, faults: Option<AcceptFaults>) {
+        self.network_handle
+            .set_accept_faults(faults, self.random_handle.clone());
+    }
+    /// Returns the connections and buffered bytes in use by this host.
+    pub fn resource_usage(&self) -> ResourceUsage {
+        self.network_handle.resource_usage()
+    }
+    /// Returns the clocks of this host, which can be skewed and drift from simulated time.
+    pub fn clock(&self) -> HostClock {
+        HostClock::new(
+            self.local_addr(),
+            self.clocks.clone(),
+            self.time_handle.clone(),
+        )
+    }
+    /// Returns the filesystem of this host, which persists across kills and restarts of the
+    /// host, see [`Filesystem`].
+    ///
+    /// [`Filesystem`]:`Filesystem`
+    pub fn fs(&self) -> Filesystem {
+        self.fs_of(self.local_addr())
+    }
+    pub(crate) fn fs_of(&self, host: net::IpAddr) -> Filesystem {
+        Filesystem::new(
+            host,
+            self.filesystems.clone(),
+            self.seed,
+            self.random_handle.algorithm(),
+            self.time_handle.clone(),
+        )
+    }
+    /// Returns the address of the host this handle is scoped to.
+    pub fn local_addr(&self) -> net::IpAddr {
+        self.network_handle.local_addr()
+    }
+    /// Adds a host to the network with a newly allocated address, returning a handle scoped to
+    /// the new host. Hosts can be added while the simulation is running.
+    pub fn add_host(&self) -> DeterministicRuntimeHandle {
+        DeterministicRuntimeHandle {
+            network_handle: self.network_handle.add_host(),
+            ..self.clone()
+        }
+    }
+    /// Adds a host with a newly allocated address, registered under the provided name. Unlike
+    /// [`add_host`], the returned [`Host`] tracks the tasks spawned on it, so the host can be
+    /// killed as a unit.
+    ///
+    /// [`add_host`]:`DeterministicRuntimeHandle::add_host`
+    /// [`Host`]:`Host`
+    pub fn spawn_host(&self, name: impl Into<String>) -> Host {
+        let name = name.into();
+        let handle = self.add_host();
+        self.network_handle
+            .name_host(name.clone(), handle.local_addr());
+        Host::new(name, handle)
+    }
+    /// Adds `count` hosts named `{prefix}-{n}`, see [`spawn_host`].
+    ///
+    /// [`spawn_host`]:`DeterministicRuntimeHandle::spawn_host`
+    pub fn spawn_hosts(&self, prefix: &str, count: usize) -> Vec<Host> {
+        (0..count)
+            .map(|n| self.spawn_host(format!("{}-{}", prefix, n)))
+            .collect()
+    }
+    /// Adds a host to the provided network domain, see [`join_domain`].
+    ///
+    /// [`join_domain`]:`DeterministicRuntimeHandle::join_domain`
+    pub fn add_host_in(&self, domain: impl Into<String>) -> DeterministicRuntimeHandle {
+        let host = self.add_host();
+        host.join_domain(domain);
+        host
+    }
+    /// Joins this host to a network domain. Hosts can only connect to hosts which share at
+    /// least one domain, so a host which joins several domains acts as a gateway between them.
+    /// Hosts which have not joined any domain are members of the `"default"` domain. Joining a
+    /// domain does not affect established connections.
+    pub fn join_domain(&self, domain: impl Into<String>) {
+        self.network_handle.join_domain(domain.into());
+    }
+    /// Removes this host from a network domain.
+    pub fn leave_domain(&self, domain: &str) {
+        self.network_handle.leave_domain(domain);
+    }
+    /// Returns the network domains this host is a member of.
+    pub fn domains(&self) -> Vec<String> {
+        self.network_handle.domains()
+    }
+    /// Removes a host from the network, disconnecting its connections and closing its
+    /// listeners. Connections to the host are refused once it has been removed. Returns false
+    /// if no host with the provided address exists.
+    pub fn remove_host(&self, addr: net::IpAddr) -> bool {
+        self.network_handle.remove_host(addr)
+    }
+    /// Adds a removed host back to the network under its address and name.
+    pub(crate) fn reinstate_host(&self, name: String, addr: net::IpAddr) {
+        self.network_handle.register_host(addr);
+        self.network_handle.name_host(name, addr);
+    }
+    /// Partitions the hosts of `a` from the hosts of `b`, see [`PartitionMode`]. Partitions
+    /// apply to established connections as well as new ones, and remain until [`heal`] is
+    /// called. Partitions count against the [`FaultBudget`] until they heal, and the returned
+    /// value is false if the budget refused the partition.
+    ///
+    /// [`PartitionMode`]:`PartitionMode`
+    /// [`heal`]:`DeterministicRuntimeHandle::heal`
+    /// [`FaultBudget`]:`FaultBudget`
+    pub fn partition(&self, a: &[net::IpAddr], b: &[net::IpAddr], mode: PartitionMode) -> bool {
+        self.network_handle.partition(a, b, mode)
+    }
+    /// Heals every partition. Stalled connections resume, while reset connections remain
+    /// failed.
+    pub fn heal(&self) {
+        self.network_handle.heal();
+    }
+    /// Refuses new connections to a host while its established connections drain, as when a
+    /// host is taken out of a load balancer. Returns false if no host with the provided address
+    /// exists.
+    pub fn drain_host(&self, addr: net::IpAddr) -> bool {
+        self.network_handle.drain_host(addr)
+    }
+    /// Returns the hosts which are members of the provided network domain, in address order.
+    pub fn hosts_in(&self, domain: &str) -> Vec<net::IpAddr> {
+        self.network_handle.hosts_in(domain)
+    }
+    /// Registers a hook which tears down a component of this host, such as a background
+    /// service. The hook runs when the host is stopped or the simulation is torn down, after
+    /// the hooks of every component on this host which lists `name` among its `dependencies`.
+    pub fn on_teardown<F, U>(&self, name: impl Into<String>, dependencies: &[&str], hook: F)
+    where
+        F: FnOnce() -> U + Send + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        self.teardowns
+            .register(self.local_addr(), name.into(), dependencies, hook);
+    }
+    /// Runs the teardown hooks of this host in dependency order, then removes the host from
+    /// the network, see [`remove_host`].
+    ///
+    /// [`remove_host`]:`DeterministicRuntimeHandle::remove_host`
+    pub async fn stop_host(&self) {
+        self.teardowns.run(Some(self.local_addr())).await;
+        self.remove_host(self.current_addr());
+    }
+    /// Moves the host `from` to the address `to`, as when a virtual IP fails over. Established
+    /// connections of the host are migrated or reset according to `policy`, and its listeners
+    /// move to the new address. Connections to the old address are refused once the host has
+    /// migrated, while handles scoped to the host bind and connect from the new address. Returns
+    /// false if no host with the address `from` exists, or `to` is already in use.
+    pub fn migrate_host(
+        &self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        policy: MigrationPolicy,
+    ) -> bool {
+        self.network_handle.migrate_host(from, to, policy)
+    }
+    /// Returns the address this host currently uses, following any migrations since the handle
+    /// was created.
+    pub fn current_addr(&self) -> net::IpAddr {
+        self.network_handle.current_addr()
+    }
+    /// Returns the IPv6 address this host currently uses. Hosts with IPv4 addresses are
+    /// dual-stack: `127.0.0.1` is also reachable at `::1`, and other IPv4 addresses at `fd00::`
+    /// followed by the IPv4 address, with ports bound separately for each family.
+    pub fn current_addr_v6(&self) -> Option<net::Ipv6Addr> {
+        self.network_handle.current_addr_v6()
+    }
+    /// Returns the addresses of all hosts on the network, in ascending order.
+    pub fn hosts(&self) -> Vec<net::IpAddr> {
+        self.network_handle.hosts()
+    }
+    /// Returns the connections which are currently open, in the order they were established.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        self.network_handle.connections()
+    }
+    /// Returns the listener addresses of the network, including addresses with connections
+    /// queued for a listener which has not yet been bound, in ascending order.
+    pub fn listeners(&self) -> Vec<ListenerInfo> {
+        self.network_handle.listeners()
+    }
+    /// Picks a host matching the predicate uniformly at random, such as a host which is not the
+    /// current leader. Selections are drawn from the runtime randomness, so they are stable for
+    /// a seed.
+    pub fn pick_host<F>(&self, predicate: F) -> Option<net::IpAddr>
+    where
+        F: Fn(net::IpAddr) -> bool,
+    {
+        let hosts: Vec<net::IpAddr> = self.hosts().into_iter().filter(|h| predicate(*h)).collect();
+        self.random_handle.choose(&hosts).cloned()
+    }
+    /// Picks an open connection with probability proportional to the provided weight.
+    /// Connections with a weight of zero are never picked.
+    pub fn pick_connection<F>(&self, weight: F) -> Option<ConnectionInfo>
+    where
+        F: Fn(&ConnectionInfo) -> f64,
+    {
+        let connections = self.connections();
+        self.random_handle
+            .choose_weighted(&connections, weight)
+            .cloned()
+    }
+    /// Returns the open connection which has carried the most traffic, preferring the earliest
+    /// established connection on ties.
+    pub fn busiest_connection(&self) -> Option<ConnectionInfo> {
+        self.connections()
+            .into_iter()
+            .rev()
+            .max_by_key(ConnectionInfo::traffic)
+    }
+    /// Instantiates a host from the provided template, returning a handle scoped to the new
+    /// host. The instance is registered by name and its setup future is spawned.
+    pub fn instantiate(&self, template: &HostTemplate) -> DeterministicRuntimeHandle {
+        let host = self.add_host();
+        let name = template.next_instance_name();
+        self.network_handle
+            .name_host(name.clone(), host.local_addr());
+        if let Some(profile) = template.network_profile() {
+            host.network_handle
+                .set_host_profile(Some(profile), self.random_handle.clone());
+        }
+        host.spawn_named(name, template.setup(host.clone()));
+        host
+    }
+    /// Adds a host running the provided external service, registered under `name` and
+    /// listening on `port`. Returns a handle through which the latency, errors and availability
+    /// of the service can be controlled.
+    pub async fn add_dependency<S>(
+        &self,
+        name: &str,
+        port: u16,
+        service: S,
+    ) -> io::Result<DependencyFaults>
+    where
+        S: ExternalService,
+    {
+        let host = self.add_host();
+        self.network_handle
+            .name_host(name.to_string(), host.local_addr());
+        dependency::serve(host, name.to_string(), port, service).await
+    }
+    /// Returns the address of the host registered with the provided name.
+    pub fn resolve_host(&self, name: &str) -> Option<net::IpAddr> {
+        self.network_handle.resolve_host(name)
+    }
+    /// Looks up the address of the host registered with the provided name through the
+    /// simulated resolver, which may answer slowly, with a stale record, or not at all
+    /// according to the configured [`DnsFaults`]. Names which do not resolve fail with
+    /// `NotFound`.
+    ///
+    /// [`DnsFaults`]:`DnsFaults`
+    pub async fn lookup_host(&self, name: &str) -> io::Result<net::IpAddr> {
+        self.dns
+            .lookup(name, &self.network_handle, &self.time_handle)
+            .await
+    }
+    /// Connects to an address of the form `host:port`, looking up `host` with [`lookup_host`]
+    /// unless it is an IP address.
+    ///
+    /// [`lookup_host`]:`DeterministicRuntimeHandle::lookup_host`
+    pub async fn connect_host(&self, addr: &str) -> io::Result<network::Socket> {
+        let addr = match addr.parse() {
+            Ok(addr) => addr,
+            Err(_) => {
+                let (host, port) = dns::split_host_port(addr)?;
+                let ip = match host.parse() {
+                    Ok(ip) => ip,
+                    Err(_) => self.lookup_host(host).await?,
+                };
+                net::SocketAddr::new(ip, port)
+            }
+        };
+        self.network_handle.connect(addr).await
+    }
+    /// Configures the faults of lookups of names without faults of their own.
+    pub fn set_dns_faults(&self, faults: DnsFaults) {
+        self.dns.set_faults(faults);
+    }
+    /// Configures the faults of lookups of the provided name, replacing those configured with
+    /// [`set_dns_faults`].
+    ///
+    /// [`set_dns_faults`]:`DeterministicRuntimeHandle::set_dns_faults`
+    pub fn set_name_faults(&self, name: impl Into<String>, faults: DnsFaults) {
+        self.dns.set_name_faults(name.into(), faults);
+    }
+    /// Applies a network profile to connections between this host and `peer`.
+    pub fn set_link_profile(&self, peer: net::IpAddr, profile: Option<NetworkProfile>) {
+        self.network_handle
+            .set_link_profile(peer, profile, self.random_handle.clone());
+    }
+    /// Returns a handle configuring the faults of the data sent from `source` to `dest`, see
+    /// [`DeterministicRuntime::fault_link`].
+    ///
+    /// [`DeterministicRuntime::fault_link`]:`DeterministicRuntime::fault_link`
+    pub fn fault_link(&self, source: net::IpAddr, dest: net::IpAddr) -> LinkFaultHandle {
+        let random = self.derive_random(&format!("link {} {}", source, dest));
+        self.network_handle.fault_link(source, dest, random)
+    }
+    /// Binds a listener to the provided address, configured with the provided `ListenerOptions`.
+    pub async fn bind_with_options<A>(
+        &self,
+        addr: A,
+        options: ListenerOptions,
+    ) -> io::Result<Listener>
+    where
+        A: Into<net::SocketAddr>,
+    {
+        self.network_handle
+            .bind_with_options(addr.into(), options)
+            .await
+    }
+    /// Connects to the provided address from the source address configured by the provided
+    /// `ConnectOptions`, failing with `AddrInUse` if the source address is occupied.
+    pub async fn connect_with_options<A>(
+        &self,
+        addr: A,
+        options: ConnectOptions,
+    ) -> io::Result<Socket>
+    where
+        A: Into<net::SocketAddr>,
+    {
+        self.network_handle
+            .connect_with_options(addr.into(), options)
+            .await
+    }
+    /// Binds a UDP socket to the provided address.
+    pub async fn bind_udp<A>(&self, addr: A) -> io::Result<UdpSocket>
+    where
+        A: Into<net::SocketAddr>,
+    {
+        self.network_handle.bind_udp(addr.into()).await
+    }
 }
 
 #[async_trait]
 impl crate::Environment for DeterministicRuntimeHandle {
     type TcpStream = network::Socket;
     type TcpListener = network::Listener;
+    type UdpSocket = network::UdpSocket;
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static,
     {
+        let future = self.tasks.instrument(None, future);
+        self.tasks.set_host(future.id(), self.local_addr());
         self.executor_handle.spawn(future).expect("failed to spawn");
     }
     fn now(&self) -> Instant {
         self.time_handle.now()
     }
+    fn system_time(&self) -> SystemTime {
+        self.time_handle.system_time()
+    }
     fn delay(&self, deadline: Instant) -> tokio_timer::Delay {
         self.time_handle.delay(deadline)
     }
@@ -78,15 +805,70 @@ impl crate::Environment for DeterministicRuntimeHandle {
     {
         self.network_handle.connect(addr.into()).await
     }
+    async fn bind_udp<A>(&self, addr: A) -> io::Result<Self::UdpSocket>
+    where
+        A: Into<net::SocketAddr> + Send + Sync,
+    {
+        self.network_handle.bind_udp(addr.into()).await
+    }
+    async fn spawn_blocking<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        DeterministicRuntimeHandle::spawn_blocking(self, f).await
+    }
+}
+
+impl crate::Runtime for DeterministicRuntime {
+    type Handle = DeterministicRuntimeHandle;
+    fn localhost_handle(&self) -> Self::Handle {
+        DeterministicRuntime::localhost_handle(self)
+    }
+    fn block_on<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        DeterministicRuntime::block_on(self, future)
+    }
 }
 
 type Executor = tokio_executor::current_thread::CurrentThread<DeterministicTime<driver::Reactor>>;
 
+/// Describes the state of a resource tasks are stalled on, for resources whose state explains
+/// the stall.
+fn describe_resource<F>(resource: &WaitResource, is_bound: F) -> Option<String>
+where
+    F: Fn(net::SocketAddr) -> bool,
+{
+    match resource {
+        WaitResource::Listener(addr) if !is_bound(*addr) => Some(String::from("is not bound")),
+        WaitResource::Listener(_) => Some(String::from("is not accepting connections")),
+        _ => None,
+    }
+}
+
 pub struct DeterministicRuntime {
     executor: Executor,
     time_handle: DeterministicTimeHandle,
     network: DeterministicNetwork,
     random: DeterministicRandom,
+    seed: Seed,
+    tasks: task::Tasks,
+    cpus: cpu::Cpus,
+    clocks: clock::Clocks,
+    filesystems: fs::Filesystems,
+    dns: dns::Dns,
+    ids: ids::IdGenerators,
+    substreams: random::Substreams,
+    buggify: buggify::Buggify,
+    metrics: metrics::Metrics,
+    teardowns: teardown::Teardowns,
+    worker_pools: worker_pool::WorkerPools,
+    /// Fail `block_on` if resources remain once the future completes.
+    leak_check: bool,
+    /// Records or replays the random decisions of the runtime.
+    tape: Option<replay::Tape>,
 }
 
 impl DeterministicRuntime {
@@ -94,18 +876,85 @@ impl DeterministicRuntime {
         DeterministicRuntime::new_with_seed(0)
     }
     pub fn new_with_seed(seed: u64) -> Result<Self, Error> {
+        DeterministicRuntime::new_with_rng(seed, RngAlgorithm::default())
+    }
+    /// Creates a runtime generating randomness from the seed with the provided algorithm. Seeds
+    /// only reproduce a run when used with the algorithm they were found with, see
+    /// [`RngAlgorithm`].
+    ///
+    /// [`RngAlgorithm`]:`RngAlgorithm`
+    pub fn new_with_rng(seed: u64, algorithm: RngAlgorithm) -> Result<Self, Error> {
+        DeterministicRuntime::new_with_tape(seed, algorithm, None)
+    }
+    /// Creates a runtime which records every random decision it makes, see
+    /// [`decision_trace`].
+    ///
+    /// [`decision_trace`]:`DeterministicRuntime::decision_trace`
+    pub fn new_recording(seed: u64) -> Result<Self, Error> {
+        let algorithm = RngAlgorithm::default();
+        let tape = replay::Tape::record(Seed::new(seed), algorithm);
+        DeterministicRuntime::new_with_tape(seed, algorithm, Some(tape))
+    }
+    /// Creates a runtime which takes its random decisions from a recorded trace, see
+    /// [`DecisionTrace`]. Streams of randomness created on the thread of the runtime while it
+    /// is alive draw from the trace, as they are recorded by a recording runtime.
+    ///
+    /// [`DecisionTrace`]:`DecisionTrace`
+    pub fn new_replaying(trace: DecisionTrace) -> Result<Self, Error> {
+        let (seed, algorithm) = (trace.seed().value(), trace.algorithm());
+        let tape = replay::Tape::replay(trace);
+        DeterministicRuntime::new_with_tape(seed, algorithm, Some(tape))
+    }
+    /// Creates a runtime which replays the decisions made up to `snapshot`, then makes
+    /// decisions derived from `branch`, see [`Snapshot`]. Running the future which took the
+    /// snapshot rebuilds the state of the simulation at the snapshot, after which each branch
+    /// diverges.
+    ///
+    /// [`Snapshot`]:`Snapshot`
+    pub fn new_branching(snapshot: &Snapshot, branch: u64) -> Result<Self, Error> {
+        let trace = snapshot.trace().clone();
+        let (seed, algorithm) = (trace.seed().value(), trace.algorithm());
+        let tape = replay::Tape::branch(trace, Seed::new(branch));
+        DeterministicRuntime::new_with_tape(seed, algorithm, Some(tape))
+    }
+    fn new_with_tape(
+        seed: u64,
+        algorithm: RngAlgorithm,
+        tape: Option<replay::Tape>,
+    ) -> Result<Self, Error> {
+        trace!("creating runtime with seed {} using {}", seed, algorithm);
+        if let Some(tape) = tape.as_ref() {
+            tape.install();
+        }
         let reactor = driver::Reactor::new().map_err(|source| Error::RuntimeBuild { source })?;
 
         let time = DeterministicTime::new_with_park(reactor);
         let time_handle = time.handle();
         let network = DeterministicNetwork::new(time_handle.clone());
+        let cpus = cpu::Cpus::default();
+        let tasks = task::Tasks::new(time_handle.clone(), cpus.clone());
+        let metrics = metrics::Metrics::new(time_handle.clone());
         let executor = tokio_executor::current_thread::CurrentThread::new_with_park(time);
-        let random = DeterministicRandom::new_with_seed(seed);
+        let random = DeterministicRandom::new_with_algorithm(seed, algorithm);
         Ok(DeterministicRuntime {
             executor,
             time_handle,
             network,
             random,
+            seed: Seed::new(seed),
+            tasks,
+            cpus,
+            clocks: clock::Clocks::default(),
+            filesystems: fs::Filesystems::default(),
+            dns: dns::Dns::new(Seed::new(seed).derive("dns").random_with(algorithm)),
+            ids: ids::IdGenerators::default(),
+            substreams: random::Substreams::default(),
+            buggify: buggify::Buggify::default(),
+            metrics,
+            teardowns: teardown::Teardowns::default(),
+            worker_pools: worker_pool::WorkerPools::default(),
+            leak_check: false,
+            tape,
         })
     }
 
@@ -115,9 +964,70 @@ impl DeterministicRuntime {
             network_handle: self.network.scoped(addr),
             executor_handle: self.executor.handle(),
             random_handle: self.random.handle(),
+            seed: self.seed,
+            tasks: self.tasks.clone(),
+            cpus: self.cpus.clone(),
+            clocks: self.clocks.clone(),
+            filesystems: self.filesystems.clone(),
+            dns: self.dns.clone(),
+            ids: self.ids.clone(),
+            substreams: self.substreams.clone(),
+            buggify: self.buggify.clone(),
+            metrics: self.metrics.clone(),
+            teardowns: self.teardowns.clone(),
+            worker_pools: self.worker_pools.clone(),
         }
     }
 
+    /// Returns the seed the runtime was created with.
+    pub fn seed(&self) -> Seed {
+        self.seed
+    }
+    /// Returns the algorithm generating the randomness of this runtime.
+    pub fn rng_algorithm(&self) -> RngAlgorithm {
+        self.random.handle().algorithm()
+    }
+    /// Returns the decisions recorded so far by a runtime created with [`new_recording`] or
+    /// [`new_branching`], or the trace being replayed by a replaying runtime.
+    ///
+    /// [`new_recording`]:`DeterministicRuntime::new_recording`
+    /// [`new_branching`]:`DeterministicRuntime::new_branching`
+    pub fn decision_trace(&self) -> Option<DecisionTrace> {
+        self.tape.as_ref().map(replay::Tape::trace)
+    }
+    /// Returns the number of random values a replaying runtime drew beyond its trace, which
+    /// is zero while the run follows the recorded decisions.
+    pub fn replay_overruns(&self) -> Option<u64> {
+        self.tape.as_ref().map(replay::Tape::overruns)
+    }
+    /// Returns the id generator with the provided name, see
+    /// [`DeterministicRuntimeHandle::id_generator`].
+    ///
+    /// [`DeterministicRuntimeHandle::id_generator`]:`DeterministicRuntimeHandle::id_generator`
+    pub fn id_generator(&self, name: &str) -> IdGenerator {
+        self.ids.get(name, self.seed, self.rng_algorithm())
+    }
+    /// Returns the metrics recorded by the hosts of the runtime, see [`Metrics`].
+    ///
+    /// [`Metrics`]:`Metrics`
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+    /// Enables or disables buggify points. Points are enabled by default; disabled points never
+    /// fire and are not recorded.
+    pub fn set_buggify_enabled(&self, enabled: bool) {
+        self.buggify.set_enabled(enabled);
+    }
+    /// Returns the buggify sites evaluated so far, ordered by site, along with whether each is
+    /// active for this run.
+    pub fn buggify_sites(&self) -> Vec<(String, bool)> {
+        self.buggify.sites()
+    }
+    /// Returns every evaluation of an active buggify site, in the order they were made.
+    pub fn buggify_decisions(&self) -> Vec<BuggifyDecision> {
+        self.buggify.decisions()
+    }
+
     pub fn latency_fault(&self) -> network::fault::LatencyFaultInjector {
         let network_inner = self.network.clone_inner();
         network::fault::LatencyFaultInjector::new(
@@ -127,6 +1037,304 @@ impl DeterministicRuntime {
         )
     }
 
+    /// Applies a network profile to all connections, emulating a class of network link such as
+    /// [`NetworkProfile::wan`]. Link specific profiles take precedence. Passing `None` removes
+    /// the profile.
+    ///
+    /// [`NetworkProfile::wan`]:`NetworkProfile::wan`
+    pub fn set_network_profile(&self, profile: Option<NetworkProfile>) {
+        self.network.set_profile(profile, self.random.handle());
+    }
+
+    /// Drops, duplicates and reorders datagrams sent between UDP sockets, see
+    /// [`DatagramFaults`]. Passing `None` removes the faults.
+    ///
+    /// [`DatagramFaults`]:`DatagramFaults`
+    pub fn set_datagram_faults(&self, faults: Option<DatagramFaults>) {
+        self.network
+            .set_datagram_faults(faults, self.random.handle());
+    }
+
+    /// Splits the bytes received by connections into randomly sized segments and holds
+    /// segments back, see [`SegmentFaults`]. Passing `None` removes the faults.
+    ///
+    /// [`SegmentFaults`]:`SegmentFaults`
+    pub fn set_segment_faults(&self, faults: Option<SegmentFaults>) {
+        self.network
+            .set_segment_faults(faults, self.random.handle());
+    }
+
+    /// Slows, fails and sheds the accepts of the listeners of the provided host, see
+    /// [`AcceptFaults`]. Passing `None` removes the faults.
+    ///
+    /// [`AcceptFaults`]:`AcceptFaults`
+    pub fn set_accept_faults(&self, host: net::IpAddr, faults: Option<AcceptFaults>) {
+        self.network
+            .set_accept_faults(host, faults, self.random.handle());
+    }
+
+    /// Applies a network profile to connections between hosts `a` and `b`, in either direction.
+    pub fn set_link_profile(
+        &self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        profile: Option<NetworkProfile>,
+    ) {
+        self.network
+            .set_link_profile(a, b, profile, self.random.handle());
+    }
+
+    /// Limits the bandwidth of the link between hosts `a` and `b` to `bytes_per_second` in each
+    /// direction. Unlike the bandwidth of a [`NetworkProfile`], which applies to each connection
+    /// separately, the bandwidth is shared by every connection between the hosts, so adding
+    /// connections slows down the existing ones. Passing `None` removes the limit.
+    ///
+    /// [`NetworkProfile`]:`NetworkProfile`
+    pub fn set_link_bandwidth(
+        &self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        bytes_per_second: Option<u64>,
+    ) {
+        self.network.set_link_bandwidth(a, b, bytes_per_second);
+    }
+
+    /// Applies a network profile to data sent from `source` to `dest`, such as an asymmetric
+    /// link with a slow uplink. Directed profiles take precedence over profiles applying to
+    /// the link in either direction, and do not affect data sent from `dest` to `source`.
+    pub fn set_directed_link_profile(
+        &self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        profile: Option<NetworkProfile>,
+    ) {
+        self.network
+            .set_directed_link_profile(source, dest, profile, self.random.handle());
+    }
+
+    /// Returns a handle configuring the delay, drop and reset faults of the data sent from
+    /// `source` to `dest`. The faults apply to every current and future connection between the
+    /// hosts, including connections opened by libraries which never expose their streams.
+    /// Handles returned for the same pair of hosts share their settings.
+    pub fn fault_link(&self, source: net::IpAddr, dest: net::IpAddr) -> LinkFaultHandle {
+        self.localhost_handle().fault_link(source, dest)
+    }
+
+    /// Sets the resolution of timers created through runtime handles. Deadlines are rounded up
+    /// to the next multiple of the resolution, so code which assumes coarse timer resolution
+    /// observes the same behavior as under Tokio's timer wheel.
+    pub fn set_timer_resolution(&self, resolution: Option<Duration>) {
+        self.time_handle.set_resolution(resolution);
+    }
+
+    /// Pauses or resumes time, see [`DeterministicRuntimeHandle::set_time_paused`].
+    ///
+    /// [`DeterministicRuntimeHandle::set_time_paused`]:`DeterministicRuntimeHandle::set_time_paused`
+    pub fn set_time_paused(&self, paused: bool) {
+        self.time_handle.set_paused(paused);
+    }
+
+    /// Sets the simulated wall clock to the provided time. The wall clock starts at
+    /// 2020-01-01T00:00:00Z and advances alongside simulated time.
+    pub fn set_system_time(&self, system_time: SystemTime) {
+        self.time_handle.set_system_time(system_time);
+    }
+
+    /// Schedules a leap second. Once the simulated wall clock reaches the provided time, it
+    /// is stepped back by one second.
+    pub fn insert_leap_second(&self, at: SystemTime) {
+        self.time_handle.insert_leap_second(at);
+    }
+
+    /// Sets the time zone used to compute local time, allowing DST transitions to be
+    /// simulated.
+    pub fn set_time_zone(&self, time_zone: TimeZone) {
+        self.time_handle.set_time_zone(time_zone);
+    }
+
+    /// Assigns the provided host a number of virtual cores, see
+    /// [`DeterministicRuntimeHandle::set_cores`].
+    ///
+    /// [`DeterministicRuntimeHandle::set_cores`]:`DeterministicRuntimeHandle::set_cores`
+    pub fn set_cores(&self, host: net::IpAddr, cores: Option<usize>) {
+        self.cpus.set_cores(host, cores, self.time_handle.now());
+    }
+
+    /// Models the thread pool the provided host runs blocking calls on, see
+    /// [`DeterministicRuntimeHandle::set_blocking_pool`].
+    ///
+    /// [`DeterministicRuntimeHandle::set_blocking_pool`]:`DeterministicRuntimeHandle::set_blocking_pool`
+    pub fn set_blocking_pool(&self, host: net::IpAddr, pool: Option<BlockingPool>) {
+        self.worker_pools.set(host, pool, self.time_handle.now());
+    }
+
+    /// Charges a cost for every poll of the tasks of the provided host, see
+    /// [`DeterministicRuntimeHandle::set_poll_cost`].
+    ///
+    /// [`DeterministicRuntimeHandle::set_poll_cost`]:`DeterministicRuntimeHandle::set_poll_cost`
+    pub fn set_poll_cost(&self, host: net::IpAddr, cost: Option<Duration>) {
+        self.cpus.set_poll_cost(host, cost);
+    }
+
+    /// Returns the clocks of the provided host, see [`HostClock`].
+    ///
+    /// [`HostClock`]:`HostClock`
+    pub fn clock(&self, host: net::IpAddr) -> HostClock {
+        HostClock::new(host, self.clocks.clone(), self.time_handle.clone())
+    }
+
+    /// Returns the filesystem of the provided host, see [`Filesystem`].
+    ///
+    /// [`Filesystem`]:`Filesystem`
+    pub fn fs(&self, host: net::IpAddr) -> Filesystem {
+        Filesystem::new(
+            host,
+            self.filesystems.clone(),
+            self.seed,
+            self.rng_algorithm(),
+            self.time_handle.clone(),
+        )
+    }
+
+    /// Records the messages written to connections to the provided listener address,
+    /// decoded with `codec`. Only connections established after the tap is installed are
+    /// recorded.
+    pub fn tap<C>(&self, addr: net::SocketAddr, codec: C) -> MessageTap<C::Item>
+    where
+        C: tokio::codec::Decoder + Clone + Send + 'static,
+        C::Item: Send + 'static,
+    {
+        self.network.tap(addr, codec)
+    }
+
+    /// Sets the duration closed connections remain in TIME_WAIT, keeping their source address
+    /// occupied. Disabled by default.
+    pub fn set_time_wait(&self, time_wait: Option<Duration>) {
+        self.network.set_time_wait(time_wait);
+    }
+
+    /// Limits the bytes each half of new connections can write before its peer reads, so
+    /// writers block when their peer stops reading as with TCP flow control, see
+    /// [`SocketBuffers`]. By default writes are buffered without limit.
+    ///
+    /// [`SocketBuffers`]:`SocketBuffers`
+    pub fn set_socket_buffers(&self, buffers: Option<SocketBuffers>) {
+        self.network.set_socket_buffers(buffers);
+    }
+
+    /// Limits the connections the provided host can hold open and the bytes its connections
+    /// can buffer, see [`ResourceLimits`]. Passing `None` removes the limits.
+    ///
+    /// [`ResourceLimits`]:`ResourceLimits`
+    pub fn set_resource_limits(&self, host: net::IpAddr, limits: Option<ResourceLimits>) {
+        self.network.set_resource_limits(host, limits);
+    }
+
+    /// Refuses connections to ports without a bound listener with `ConnectionRefused` once
+    /// `grace` elapses without a listener being bound, exercising client retry logic. When
+    /// `None`, the default, connections wait for a listener to be bound indefinitely.
+    pub fn set_unbound_grace(&self, grace: Option<Duration>) {
+        self.network.set_unbound_grace(grace);
+    }
+
+    /// Sets the range of source ports allocated to outgoing connections, which defaults to
+    /// every port. Connects fail with `AddrNotAvailable` once every port in the range is
+    /// occupied by open connections of the connecting host.
+    pub fn set_ephemeral_ports(&self, range: std::ops::RangeInclusive<u16>) {
+        self.network.set_ephemeral_ports(range);
+    }
+
+    /// Caps the number of connects in progress across the network, modelling SYN queue and
+    /// connection tracking limits. Connects beyond the cap queue until an in-progress connect
+    /// completes, and are then delayed by `queue_delay`. Removed when `max` is `None`.
+    pub fn set_connect_limit(&self, max: Option<usize>, queue_delay: Duration) {
+        self.network.set_connect_limit(max, queue_delay);
+    }
+
+    /// Returns the number of connects which were queued behind the connect limit.
+    pub fn queued_connects(&self) -> u64 {
+        self.network.queued_connects()
+    }
+
+    /// Returns a fault injector which injects the provided payloads into randomly selected
+    /// connections at seeded points in the byte stream.
+    pub fn byzantine_fault(
+        &self,
+        payloads: Vec<bytes::Bytes>,
+    ) -> network::fault::ByzantineFaultInjector {
+        let network_inner = self.network.clone_inner();
+        network::fault::ByzantineFaultInjector::new(
+            network_inner,
+            self.random.handle(),
+            self.time_handle.clone(),
+            payloads,
+        )
+    }
+
+    /// Returns a fault injector which re-delivers previously read segments of randomly
+    /// selected connections.
+    pub fn replay_fault(&self) -> network::fault::ReplayFaultInjector {
+        let network_inner = self.network.clone_inner();
+        network::fault::ReplayFaultInjector::new(
+            network_inner,
+            self.random.handle(),
+            self.time_handle.clone(),
+        )
+    }
+
+    /// Returns a fault injector which periodically arms connection crossing, swapping the
+    /// server halves of connections established to the same listener within a short window.
+    pub fn crossing_fault(&self) -> network::fault::CrossingFaultInjector {
+        let network_inner = self.network.clone_inner();
+        network::fault::CrossingFaultInjector::new(
+            network_inner,
+            self.random.handle(),
+            self.time_handle.clone(),
+        )
+    }
+
+    /// Installs a fault budget, constraining every fault applied to the network for the
+    /// remainder of the run.
+    pub fn set_fault_budget(&self, budget: FaultBudget) {
+        self.network.set_fault_budget(budget);
+    }
+
+    /// Begins recording the faults applied by fault injectors. The recorded schedule can be
+    /// retrieved with `fault_schedule` and re-applied to another run with
+    /// `fault_schedule_injector`.
+    pub fn record_faults(&self) {
+        self.network.record_faults();
+    }
+
+    /// Returns the faults applied since recording began, with offsets relative to when
+    /// recording began.
+    pub fn fault_schedule(&self) -> FaultSchedule {
+        self.network.fault_schedule()
+    }
+
+    /// Builds the declared schedule from the seed of the runtime, returning a fault injector
+    /// which applies it, see [`FaultScheduleBuilder`].
+    ///
+    /// [`FaultScheduleBuilder`]:`FaultScheduleBuilder`
+    pub fn schedule_faults(
+        &self,
+        builder: FaultScheduleBuilder,
+    ) -> network::fault::FaultScheduleInjector {
+        self.fault_schedule_injector(builder.build(self.seed))
+    }
+
+    /// Returns a fault injector which re-applies the provided fault schedule.
+    pub fn fault_schedule_injector(
+        &self,
+        schedule: FaultSchedule,
+    ) -> network::fault::FaultScheduleInjector {
+        network::fault::FaultScheduleInjector::new(
+            self.network.clone_inner(),
+            self.time_handle.clone(),
+            schedule,
+        )
+    }
+
     pub fn localhost_handle(&self) -> DeterministicRuntimeHandle {
         self.handle(net::IpAddr::V4(net::Ipv4Addr::LOCALHOST))
     }
@@ -135,10 +1343,75 @@ impl DeterministicRuntime {
     where
         F: Future<Output = ()> + 'static,
     {
+        let future = self.tasks.instrument(None, future);
         self.executor.spawn(future);
         self
     }
 
+    /// Export the wait-for graph of all tasks in DOT format.
+    pub fn wait_for_graph(&self) -> String {
+        self.tasks.wait_for_graph()
+    }
+
+    /// Returns the virtual time profile of all tasks spawned on the runtime.
+    pub fn profile(&self) -> ProfileReport {
+        self.tasks.profile()
+    }
+
+    /// Register a breakpoint which suspends the simulation and invokes the provided callback
+    /// whenever it is triggered. See [`Breakpoint`] for the supported conditions.
+    ///
+    /// [`Breakpoint`]:`Breakpoint`
+    pub fn add_breakpoint<F>(&self, breakpoint: Breakpoint, callback: F) -> BreakpointId
+    where
+        F: FnMut(&SimulationEvent) + Send + 'static,
+    {
+        self.time_handle
+            .events()
+            .add_breakpoint(breakpoint, callback)
+    }
+
+    /// Remove a previously registered breakpoint.
+    pub fn remove_breakpoint(&self, id: BreakpointId) {
+        self.time_handle.events().remove_breakpoint(id)
+    }
+
+    /// Registers an observer notified as tasks are spawned and polled, as timers are armed and
+    /// fire, and as simulation events are emitted. See [`Observer`].
+    ///
+    /// [`Observer`]:`Observer`
+    pub fn add_observer(&self, observer: sync::Arc<dyn Observer>) {
+        self.time_handle.events().observers().add(observer)
+    }
+
+    /// Installs an [`EventLog`] recording every action of the simulation from now on.
+    ///
+    /// [`EventLog`]:`EventLog`
+    pub fn record_events(&self) -> sync::Arc<EventLog> {
+        let log = sync::Arc::new(EventLog::new(self.time_handle.now()));
+        self.add_observer(log.clone());
+        log
+    }
+
+    /// Returns a `tracing_subscriber` layer formatting spans and events with the simulated time
+    /// elapsed from now, and the host and task which emitted them. See [`SimulationLayer`].
+    ///
+    /// [`SimulationLayer`]:`SimulationLayer`
+    #[cfg(feature = "tracing-subscriber")]
+    pub fn tracing_layer(&self) -> SimulationLayer {
+        SimulationLayer::new(self.time_handle.clone())
+    }
+
+    /// Installs a [`MetricsRecorder`] counting the connections, bytes, faults, timers and polls
+    /// of the simulation from now on.
+    ///
+    /// [`MetricsRecorder`]:`MetricsRecorder`
+    pub fn record_metrics(&self) -> sync::Arc<MetricsRecorder> {
+        let recorder = sync::Arc::new(MetricsRecorder::new());
+        self.add_observer(recorder.clone());
+        recorder
+    }
+
     pub fn run(&mut self) -> Result<(), Error> {
         self.enter(|executor| executor.run())
             .map_err(|source| Error::CurrentThreadRun { source })
@@ -148,13 +1421,197 @@ impl DeterministicRuntime {
     where
         F: Future,
     {
-        self.enter(|executor| executor.block_on(f))
+        let f = self.tasks.instrument(Some(String::from("main")), f);
+        let output = self.enter(|executor| executor.block_on(f));
+        if self.leak_check {
+            self.teardown();
+            let report = self.leak_audit();
+            if !report.is_empty() {
+                panic!("simulation leaked resources:\n{}", report);
+            }
+        }
+        output
+    }
+
+    /// Sets the high watermark for the bytes buffered in each direction of every connection.
+    /// A [`SimulationEvent::HighWatermark`] is emitted whenever a connection buffers more than
+    /// the watermark for longer than its sustained duration.
+    ///
+    /// [`SimulationEvent::HighWatermark`]:`SimulationEvent::HighWatermark`
+    pub fn set_connection_watermark(&self, watermark: Option<HighWatermark>) {
+        self.network.watermarks().set_connection(watermark);
+    }
+
+    /// Sets the high watermark for the number of messages queued on instrumented channels, see
+    /// [`set_connection_watermark`].
+    ///
+    /// [`set_connection_watermark`]:`DeterministicRuntime::set_connection_watermark`
+    pub fn set_channel_watermark(&self, watermark: Option<HighWatermark>) {
+        self.network.watermarks().set_channel(watermark);
+    }
+
+    /// Sets the action taken when a connection is closed while bytes received from its peer
+    /// remain unread, surfacing protocols which silently drop trailing data.
+    pub fn set_unconsumed_data_policy(&self, policy: UnconsumedDataPolicy) {
+        self.network.set_unconsumed_data_policy(policy);
+    }
+
+    /// Returns the connection halves which were closed with unread bytes.
+    pub fn unconsumed_data(&self) -> Vec<UnconsumedData> {
+        self.network.unconsumed_data()
+    }
+
+    /// Assigns a priority to a class of tasks, see [`PriorityPolicy`]. Tasks with a higher
+    /// priority are polled first. Tasks outside of any class have a priority of zero.
+    ///
+    /// [`PriorityPolicy`]:`PriorityPolicy`
+    pub fn set_priority_class(&self, class: impl Into<String>, priority: i32) {
+        self.tasks.set_priority_class(class.into(), priority);
+    }
+
+    /// Sets the policy used to schedule tasks of different priorities. Priorities are ignored by
+    /// default.
+    pub fn set_priority_policy(&self, policy: PriorityPolicy) {
+        self.tasks.set_priority_policy(policy);
+    }
+
+    /// Enables or disables shuffling of the order in which runnable tasks are polled. By default
+    /// tasks are polled in the order they were woken. When enabled, the order is drawn from the
+    /// seed of the runtime, so each seed explores a different interleaving of tasks. Tasks
+    /// waiting on the channels of the [`sync`] module are then also woken in an order drawn from
+    /// the seed, rather than in the order they started waiting.
+    ///
+    /// [`sync`]:`crate::sync`
+    pub fn set_shuffle_scheduling(&self, enabled: bool) {
+        let random = if enabled {
+            Some(
+                self.seed
+                    .derive("scheduler")
+                    .random_with(self.rng_algorithm()),
+            )
+        } else {
+            None
+        };
+        self.tasks.set_shuffle(random);
+    }
+
+    /// Enables detection of tasks which block the executor thread, reporting polls which take
+    /// longer than `threshold` in real time or which spawn OS threads. Passing `None` disables
+    /// detection.
+    pub fn detect_blocking(&self, threshold: Option<Duration>) {
+        self.tasks.blocking().set_threshold(threshold);
+    }
+
+    /// Returns the polls which blocked the executor thread or spawned threads.
+    pub fn blocking_calls(&self) -> Vec<BlockingCall> {
+        self.tasks.blocking().calls()
+    }
+
+    /// Enables or disables detection of tasks which escape the simulation, reporting polls
+    /// which spawn OS threads or open real sockets. See [`check_determinism`] for detecting
+    /// reads of the real clock or of `thread_rng`.
+    ///
+    /// [`check_determinism`]:`check_determinism`
+    pub fn detect_nondeterminism(&self, enabled: bool) {
+        self.tasks.nondeterminism().set_enabled(enabled);
+    }
+
+    /// Returns the polls which spawned OS threads or opened real sockets.
+    pub fn nondeterministic_calls(&self) -> Vec<NondeterministicCall> {
+        self.tasks.nondeterminism().calls()
+    }
+
+    pub(crate) fn watchdog(&self) -> &watchdog::Watchdog {
+        self.tasks.watchdog()
+    }
+
+    pub(crate) fn invariants(&self) -> &invariant::Invariants {
+        self.tasks.invariants()
+    }
+
+    /// Runs the teardown hooks registered by every host, see
+    /// [`DeterministicRuntimeHandle::on_teardown`]. Hosts are torn down concurrently, while the
+    /// hooks of each host run in dependency order.
+    ///
+    /// [`DeterministicRuntimeHandle::on_teardown`]:`DeterministicRuntimeHandle::on_teardown`
+    pub fn teardown(&mut self) {
+        if self.teardowns.is_empty() {
+            return;
+        }
+        let teardowns = self.teardowns.clone();
+        let f = self
+            .tasks
+            .instrument(Some(String::from("teardown")), async move {
+                teardowns.run(None).await
+            });
+        self.enter(|executor| executor.block_on(f));
+    }
+
+    /// Enables or disables the leak check. When enabled, `block_on` tears down the simulation
+    /// once the provided future completes, then panics if any tasks are still running,
+    /// listeners remain bound, connections remain open or timers are pending.
+    pub fn set_leak_check(&mut self, enabled: bool) {
+        self.leak_check = enabled;
+    }
+
+    /// Sets the real time the runtime waits for wakes from outside the simulation, such as from
+    /// OS threads, once every task is waiting and no timers are pending. If no task has been
+    /// woken by then, the simulation is deadlocked and panics with a report of the resources
+    /// each task is waiting on. Passing `None` disables detection, leaving a deadlocked
+    /// simulation parked. Detection waits 100ms by default.
+    pub fn set_deadlock_detection(&self, grace: Option<Duration>) {
+        self.time_handle.set_deadlock_grace(grace);
+    }
+
+    /// Reports the tasks, listeners, connections and timers which remain in the simulation.
+    /// Time is not advanced by the audit.
+    pub fn leak_audit(&mut self) -> LeakReport {
+        let tasks = self
+            .tasks
+            .waiting(None)
+            .into_iter()
+            .map(|(id, name, _)| (id, name))
+            .collect();
+        LeakReport::new(
+            tasks,
+            self.network.bound_listeners(),
+            self.network.open_connections(),
+            self.next_timer(),
+        )
+    }
+
+    /// Returns the duration until the next timer fires by parking the executor while an idle
+    /// waiter is registered, which reports the next timer without advancing time.
+    fn next_timer(&mut self) -> Option<Duration> {
+        use tokio_executor::park::Park;
+        let mut idle = self.time_handle.wait_idle();
+        let _ = self.enter(|executor| executor.get_park_mut().park());
+        match idle.try_recv() {
+            Ok(Some(next_timer)) => next_timer,
+            _ => None,
+        }
     }
 
     fn enter<F, R>(&mut self, f: F) -> R
     where
         F: FnOnce(&mut Executor) -> R,
     {
+        let tasks = self.tasks.clone();
+        let network = self.network.clone_inner();
+        let reporter = time::DeadlockReporter(sync::Arc::new(move || {
+            if tasks.any_runnable() {
+                return None;
+            }
+            let report = StallReport::new(tasks.waiting(None), None, |resource| {
+                describe_resource(resource, |addr| network.lock().unwrap().is_bound(addr))
+            });
+            Some(report.to_string())
+        }));
+        if let Some(tape) = self.tape.as_ref() {
+            tape.install();
+        }
+        // The reporter refers back to the runtime, so it is only installed while entered.
+        self.time_handle.set_deadlock_reporter(Some(reporter));
         let DeterministicRuntime {
             ref mut time_handle,
             ref mut executor,
@@ -164,10 +1621,20 @@ impl DeterministicRuntime {
         let clock = tokio_timer::clock::Clock::new_with_now(time_handle.clone_now());
         let timer_handle = time_handle.clone_timer_handle();
         let _guard = tokio_timer::timer::set_default(&timer_handle);
-        tokio_timer::clock::with_default(&clock, || {
+        let result = tokio_timer::clock::with_default(&clock, || {
             let mut default_executor = tokio_executor::current_thread::TaskExecutor::current();
             tokio_executor::with_default(&mut default_executor, || f(executor))
-        })
+        });
+        time_handle.set_deadlock_reporter(None);
+        result
+    }
+}
+
+impl Drop for DeterministicRuntime {
+    fn drop(&mut self) {
+        if let Some(tape) = self.tape.as_ref() {
+            tape.uninstall();
+        }
     }
 }
 
@@ -217,6 +1684,129 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Test that a recorded fault schedule is re-applied to a run with a different seed.
+    fn fault_schedule_replay() {
+        fn run(seed: u64, schedule: Option<FaultSchedule>) -> FaultSchedule {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            let handle = runtime.localhost_handle();
+            runtime.record_faults();
+            match schedule {
+                Some(schedule) => handle.spawn(runtime.fault_schedule_injector(schedule).run()),
+                None => handle.spawn(
+                    runtime
+                        .byzantine_fault(vec![bytes::Bytes::from("evil")])
+                        .probability(1.0)
+                        .run(),
+                ),
+            }
+            runtime.block_on(async {
+                let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+                let mut listener = handle.bind(addr).await.unwrap();
+                let server_handle = handle.clone();
+                handle.spawn(async move {
+                    let (_socket, _) = crate::TcpListener::accept(&mut listener).await.unwrap();
+                    server_handle.delay_from(Duration::from_secs(60)).await;
+                });
+                let _socket = handle.connect(addr).await.unwrap();
+                handle.delay_from(Duration::from_secs(10)).await;
+            });
+            runtime.fault_schedule()
+        }
+        let recorded = run(1, None);
+        assert!(!recorded.is_empty(), "expected faults to be recorded");
+        let replayed = run(2, Some(recorded.clone()));
+        assert_eq!(
+            recorded, replayed,
+            "expected replayed faults to match the recorded schedule"
+        );
+    }
+
+    #[test]
+    /// Test that the wall clock can be stepped across DST transitions and leap seconds.
+    fn calendar_time() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let dst_start = DateTime::new(2021, 3, 14, 7, 0, 0).unwrap();
+        let dst_end = DateTime::new(2021, 11, 7, 6, 0, 0).unwrap();
+        runtime.set_time_zone(
+            TimeZone::fixed(-5 * 3600)
+                .with_dst(dst_start.to_system_time(), dst_end.to_system_time()),
+        );
+        runtime.set_system_time(dst_start.to_system_time() - Duration::from_secs(60));
+        let leap_second = DateTime::new(2022, 1, 1, 0, 0, 0).unwrap();
+        runtime.insert_leap_second(leap_second.to_system_time());
+        runtime.block_on(async {
+            assert_eq!(handle.local_time().to_string(), "2021-03-14T01:59:00");
+            handle.delay_from(Duration::from_secs(120)).await;
+            assert_eq!(handle.local_time().to_string(), "2021-03-14T03:01:00");
+
+            let before_leap = leap_second.to_system_time() - Duration::from_secs(1);
+            handle.time_handle().set_system_time(before_leap);
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(
+                handle.system_time(),
+                before_leap,
+                "expected the final second of the year to repeat"
+            );
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(handle.system_time(), leap_second.to_system_time());
+        });
+    }
+
+    #[test]
+    /// Test that timer deadlines are rounded up to the configured resolution.
+    fn timer_resolution() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.set_timer_resolution(Some(Duration::from_millis(10)));
+        runtime.block_on(async {
+            let start_time = handle.now();
+            let delay1 = handle.delay_from(Duration::from_millis(3));
+            let delay2 = handle.delay_from(Duration::from_millis(7));
+            assert_eq!(
+                delay1.deadline(),
+                delay2.deadline(),
+                "expected nearby deadlines to coalesce"
+            );
+            delay1.await;
+            delay2.await;
+            assert_eq!(handle.now() - start_time, Duration::from_millis(10));
+        });
+    }
+
+    #[test]
+    /// Test that paused time only moves when advanced, stepping exactly to the requested
+    /// instants and firing timers within the advanced interval.
+    fn paused_time() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_time_paused(true);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            assert!(handle.is_time_paused());
+            let start = handle.now();
+            let fired = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+            let lease = handle.clone();
+            let expired = fired.clone();
+            handle.spawn(async move {
+                lease.delay_from(Duration::from_secs(10)).await;
+                expired.store(true, sync::atomic::Ordering::SeqCst);
+            });
+            handle.advance(Duration::from_millis(9999)).await;
+            assert_eq!(handle.now() - start, Duration::from_millis(9999));
+            assert!(!fired.load(sync::atomic::Ordering::SeqCst));
+            let advanced = handle.advance_to_next_timer().await;
+            assert_eq!(advanced, Some(Duration::from_millis(1)));
+            assert_eq!(handle.now() - start, Duration::from_secs(10));
+            assert!(fired.load(sync::atomic::Ordering::SeqCst));
+            assert_eq!(handle.advance_to_next_timer().await, None);
+
+            handle.set_time_paused(false);
+            handle.delay_from(Duration::from_secs(1)).await;
+            assert_eq!(handle.now() - start, Duration::from_secs(11));
+        });
+    }
+
     #[test]
     /// Test that the Tokio global timer and clock are both set correctly.
     fn globals() {
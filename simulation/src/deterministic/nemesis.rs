@@ -0,0 +1,498 @@
+//! Randomized chaos over long simulated runs.
+//!
+//! Hand written fault schedules only cover the failures their authors thought of. A
+//! [`Nemesis`], modeled after the nemesis of Jepsen, instead keeps picking chaos actions for as
+//! long as it runs: partitioning the hosts it was given into two random groups, jumping the
+//! wall clock of a host, killing and restarting a host, or spiking the latency of the data a
+//! host sends. Each action is chosen with a configurable weight, held for a random duration and
+//! then reverted, followed by a random quiet period before the next action.
+//!
+//! Every choice is drawn from a substream of the runtime seed, so a seed replays the same chaos.
+//! Actions are subject to the [`FaultBudget`] of the runtime, and counted as active faults until
+//! they are reverted. Actions which the budget refuses are skipped, so a budget with
+//! [`FaultBudget::quiet_before_end`] leaves the final window of a run free of chaos. The actions
+//! taken are kept in the history of the nemesis, to explain a failing run. Dropping
+//! the future returned by [`Nemesis::run`], as when a [`Scenario`] stops its nemeses, reverts
+//! the action in progress, leaving the hosts healthy for validation.
+//!
+//! [`Nemesis`]:`Nemesis`
+//! [`Nemesis::run`]:`Nemesis::run`
+//! [`Scenario`]:`super::Scenario`
+//! [`FaultBudget`]:`super::FaultBudget`
+//! [`FaultBudget::quiet_before_end`]:`super::FaultBudget::quiet_before_end`
+use super::network::fault::FaultPermit;
+use super::{
+    ClockOffset, DeterministicRandomHandle, DeterministicRuntimeHandle, FaultAction, FaultKind,
+    Host, PartitionMode,
+};
+use crate::Environment;
+use futures::Future;
+use std::{fmt, net, ops, pin::Pin, sync, time};
+use tracing::trace;
+
+/// A kind of chaos action taken by a [`Nemesis`].
+///
+/// [`Nemesis`]:`Nemesis`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NemesisAction {
+    /// Partitions the hosts into two groups, stalling or resetting the connections between them.
+    Partition,
+    /// Steps the wall clock of a host ahead or behind, stepping it back once healed.
+    ClockJump,
+    /// Kills a host, restarting it once healed. Only taken when a restart is configured.
+    KillRestart,
+    /// Delays the data sent by a host.
+    LatencySpike,
+}
+
+/// An action taken by a [`Nemesis`], along with the hosts it targeted.
+///
+/// [`Nemesis`]:`Nemesis`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NemesisOp {
+    started: time::Duration,
+    duration: time::Duration,
+    action: NemesisAction,
+    hosts: Vec<net::IpAddr>,
+}
+
+impl NemesisOp {
+    /// Returns the offset from the start of the nemesis at which the action was taken.
+    pub fn started(&self) -> time::Duration {
+        self.started
+    }
+
+    /// Returns how long the action was held before being reverted.
+    pub fn duration(&self) -> time::Duration {
+        self.duration
+    }
+
+    pub fn action(&self) -> NemesisAction {
+        self.action
+    }
+
+    /// Returns the hosts targeted by the action. The hosts of a partition are those isolated
+    /// from the others.
+    pub fn hosts(&self) -> &[net::IpAddr] {
+        &self.hosts
+    }
+}
+
+type Restart = sync::Arc<dyn Fn(Host) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync>;
+
+/// Continuously injects randomly chosen chaos actions into a group of hosts, see the
+/// [module documentation](self).
+#[derive(Clone)]
+pub struct Nemesis {
+    hosts: Vec<Host>,
+    weights: Vec<(NemesisAction, f64)>,
+    interval: ops::Range<time::Duration>,
+    duration: ops::Range<time::Duration>,
+    max_clock_jump: time::Duration,
+    max_latency: time::Duration,
+    restart: Option<Restart>,
+    history: sync::Arc<sync::Mutex<Vec<NemesisOp>>>,
+}
+
+impl fmt::Debug for Nemesis {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Nemesis")
+            .field("hosts", &self.hosts)
+            .field("weights", &self.weights)
+            .field("interval", &self.interval)
+            .field("duration", &self.duration)
+            .finish()
+    }
+}
+
+impl Nemesis {
+    /// Creates a nemesis targeting the provided hosts, taking every action other than
+    /// [`KillRestart`] with equal weight. Actions are held for 1 to 10 seconds, with 1 to 10
+    /// seconds between them.
+    ///
+    /// [`KillRestart`]:`NemesisAction::KillRestart`
+    pub fn new(hosts: Vec<Host>) -> Self {
+        let second = time::Duration::from_secs(1);
+        Self {
+            hosts,
+            weights: vec![
+                (NemesisAction::Partition, 1.0),
+                (NemesisAction::ClockJump, 1.0),
+                (NemesisAction::KillRestart, 1.0),
+                (NemesisAction::LatencySpike, 1.0),
+            ],
+            interval: second..second * 10,
+            duration: second..second * 10,
+            max_clock_jump: second * 30,
+            max_latency: time::Duration::from_millis(500),
+            restart: None,
+            history: sync::Arc::default(),
+        }
+    }
+
+    /// Sets the relative weight with which the action is chosen. A weight of zero disables
+    /// the action.
+    pub fn weight(mut self, action: NemesisAction, weight: f64) -> Self {
+        for (existing, existing_weight) in self.weights.iter_mut() {
+            if *existing == action {
+                *existing_weight = weight;
+            }
+        }
+        self
+    }
+
+    /// Sets the range of the quiet period between actions.
+    pub fn interval(mut self, interval: ops::Range<time::Duration>) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the range of the duration each action is held for before being reverted.
+    pub fn duration(mut self, duration: ops::Range<time::Duration>) -> Self {
+        self.duration = duration;
+        self
+    }
+
+    /// Caps the offset by which a clock jump steps the wall clock of a host.
+    pub fn max_clock_jump(mut self, max: time::Duration) -> Self {
+        self.max_clock_jump = max;
+        self
+    }
+
+    /// Caps the latency added to the data sent by a host during a latency spike.
+    pub fn max_latency(mut self, max: time::Duration) -> Self {
+        self.max_latency = max;
+        self
+    }
+
+    /// Enables [`KillRestart`], restarting a killed host with the future returned by `start`,
+    /// see [`Host::restart`].
+    ///
+    /// [`KillRestart`]:`NemesisAction::KillRestart`
+    /// [`Host::restart`]:`Host::restart`
+    pub fn restart_with<F, T>(mut self, start: F) -> Self
+    where
+        F: Fn(Host) -> T + Send + Sync + 'static,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        self.restart.replace(sync::Arc::new(move |host| {
+            Box::pin(start(host)) as Pin<Box<dyn Future<Output = ()> + Send>>
+        }));
+        self
+    }
+
+    /// Returns the actions taken so far, in the order they were taken.
+    pub fn history(&self) -> Vec<NemesisOp> {
+        self.history.lock().unwrap().clone()
+    }
+
+    /// Returns the actions which can be taken, along with their weights.
+    fn actions(&self) -> Vec<(NemesisAction, f64)> {
+        self.weights
+            .iter()
+            .cloned()
+            .filter(|(action, _)| match action {
+                NemesisAction::Partition => self.hosts.len() > 1,
+                NemesisAction::KillRestart => self.restart.is_some(),
+                NemesisAction::ClockJump | NemesisAction::LatencySpike => true,
+            })
+            .collect()
+    }
+
+    /// Takes actions until the returned future is dropped, reverting the action in progress
+    /// when dropped. Returns once no action can be taken.
+    pub async fn run(self, handle: DeterministicRuntimeHandle) {
+        let random = handle.derive_random("nemesis");
+        let actions = self.actions();
+        let start = handle.now();
+        loop {
+            handle.delay_from(sample(&random, &self.interval)).await;
+            let alive: Vec<Host> = self
+                .hosts
+                .iter()
+                .filter(|h| h.is_alive())
+                .cloned()
+                .collect();
+            let action = match random.choose_weighted(&actions, |&(_, weight)| weight) {
+                Some((action, _)) => *action,
+                None => return,
+            };
+            let target = match random.choose(&alive) {
+                Some(host) => host.clone(),
+                None => return,
+            };
+            let duration = sample(&random, &self.duration);
+            let active = match action {
+                NemesisAction::Partition => {
+                    let mut addrs: Vec<net::IpAddr> = alive.iter().map(Host::addr).collect();
+                    shuffle(&random, &mut addrs);
+                    let isolated = random.gen_range(1..std::cmp::max(addrs.len(), 2));
+                    let rest = addrs.split_off(std::cmp::min(isolated, addrs.len()));
+                    let mode = if random.should_fault(0.5) {
+                        PartitionMode::Reset
+                    } else {
+                        PartitionMode::Stall
+                    };
+                    Active::Partition {
+                        a: addrs,
+                        b: rest,
+                        mode,
+                    }
+                }
+                NemesisAction::ClockJump => {
+                    let jump = sample(
+                        &random,
+                        &(time::Duration::from_millis(1)..self.max_clock_jump),
+                    );
+                    let offset = if random.should_fault(0.5) {
+                        ClockOffset::Ahead(jump)
+                    } else {
+                        ClockOffset::Behind(jump)
+                    };
+                    Active::ClockJump {
+                        host: target,
+                        offset,
+                    }
+                }
+                NemesisAction::KillRestart => Active::Kill { host: target },
+                NemesisAction::LatencySpike => {
+                    let latency =
+                        sample(&random, &(time::Duration::from_millis(1)..self.max_latency));
+                    Active::LatencySpike {
+                        host: target.addr(),
+                        latency,
+                    }
+                }
+            };
+            let op = NemesisOp {
+                started: handle.now() - start,
+                duration,
+                action,
+                hosts: active.hosts(),
+            };
+            let _guard = match Guard::apply(active, handle.clone(), self.restart.clone()) {
+                Some(guard) => guard,
+                None => {
+                    trace!("fault budget refused nemesis action {:?}", op.action);
+                    continue;
+                }
+            };
+            trace!("nemesis taking {:?} for {:?}", op.action, duration);
+            self.history.lock().unwrap().push(op);
+            handle.delay_from(duration).await;
+        }
+    }
+}
+
+/// Draws a duration uniformly from the range, at millisecond granularity.
+fn sample(
+    random: &DeterministicRandomHandle,
+    range: &ops::Range<time::Duration>,
+) -> time::Duration {
+    let (start, end) = (range.start.as_millis() as u64, range.end.as_millis() as u64);
+    if start >= end {
+        return range.start;
+    }
+    time::Duration::from_millis(random.gen_range(start..end))
+}
+
+fn shuffle<T>(random: &DeterministicRandomHandle, items: &mut [T]) {
+    for i in (1..items.len()).rev() {
+        items.swap(i, random.gen_range(0..i + 1));
+    }
+}
+
+/// An action which has been applied and not yet reverted.
+enum Active {
+    Partition {
+        a: Vec<net::IpAddr>,
+        b: Vec<net::IpAddr>,
+        mode: PartitionMode,
+    },
+    ClockJump {
+        host: Host,
+        offset: ClockOffset,
+    },
+    Kill {
+        host: Host,
+    },
+    LatencySpike {
+        host: net::IpAddr,
+        latency: time::Duration,
+    },
+}
+
+impl Active {
+    fn hosts(&self) -> Vec<net::IpAddr> {
+        match self {
+            Active::Partition { a, .. } => a.clone(),
+            Active::ClockJump { host, .. } | Active::Kill { host } => vec![host.addr()],
+            Active::LatencySpike { host, .. } => vec![*host],
+        }
+    }
+}
+
+/// Reverts the applied action once dropped.
+struct Guard {
+    active: Active,
+    handle: DeterministicRuntimeHandle,
+    restart: Option<Restart>,
+    /// Permit of the action against the fault budget, released once the action is reverted.
+    /// Partitions, latency spikes and kills instead hold their permits in the network and the
+    /// host, which release them as the action is reverted.
+    _permit: Option<FaultPermit>,
+}
+
+impl Guard {
+    /// Applies the action, returning `None` without applying it if the fault budget refused it.
+    fn apply(
+        active: Active,
+        handle: DeterministicRuntimeHandle,
+        restart: Option<Restart>,
+    ) -> Option<Self> {
+        let mut permit = None;
+        let applied = match &active {
+            Active::Partition { a, b, mode } => {
+                handle.network_handle.apply_fault(FaultAction::Partition {
+                    a: a.clone(),
+                    b: b.clone(),
+                    mode: *mode,
+                })
+            }
+            Active::ClockJump { host, offset } => {
+                permit = handle
+                    .network_handle
+                    .try_acquire_fault(FaultKind::ClockJump);
+                if permit.is_some() {
+                    host.handle().clock().step(*offset);
+                }
+                permit.is_some()
+            }
+            Active::Kill { host } => host.kill(),
+            Active::LatencySpike { host, latency } => {
+                handle.network_handle.apply_fault(FaultAction::SendLatency {
+                    host: *host,
+                    latency: *latency,
+                })
+            }
+        };
+        if !applied {
+            return None;
+        }
+        Some(Self {
+            active,
+            handle,
+            restart,
+            _permit: permit,
+        })
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        match &self.active {
+            Active::Partition { a, b, .. } => {
+                self.handle.network_handle.apply_fault(FaultAction::Heal {
+                    a: a.clone(),
+                    b: b.clone(),
+                });
+            }
+            Active::ClockJump { host, offset } => {
+                let back = match *offset {
+                    ClockOffset::Ahead(jump) => ClockOffset::Behind(jump),
+                    ClockOffset::Behind(jump) => ClockOffset::Ahead(jump),
+                };
+                host.handle().clock().step(back);
+            }
+            Active::Kill { host } => {
+                if let Some(restart) = self.restart.as_ref() {
+                    host.restart(|host| restart(host));
+                }
+            }
+            Active::LatencySpike { host, .. } => {
+                self.handle
+                    .network_handle
+                    .apply_fault(FaultAction::SendLatency {
+                        host: *host,
+                        latency: time::Duration::from_millis(0),
+                    });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{FaultBudget, Simulation};
+
+    /// Runs a nemesis against three hosts for ten simulated minutes, returning its history and
+    /// whether every host was alive afterwards.
+    fn chaos(seed: u64) -> (Vec<NemesisOp>, bool) {
+        let mut simulation = Simulation::new(seed).unwrap();
+        let handle = simulation.handle();
+        simulation.run(async move {
+            let hosts = handle.spawn_hosts("node", 3);
+            let nemesis = Nemesis::new(hosts.clone()).restart_with(|_| async {});
+            let history = nemesis.clone();
+            let run = nemesis.run(handle.clone());
+            let _ = handle.timeout(run, time::Duration::from_secs(600)).await;
+            (history.history(), hosts.iter().all(Host::is_alive))
+        })
+    }
+
+    #[test]
+    /// Test that a nemesis takes each kind of action over a long run, replays the same actions
+    /// for the same seed, and reverts the action in progress once stopped.
+    fn chaos_run() {
+        let (history, alive) = chaos(5);
+        assert!(alive);
+        assert!(history.len() > 20, "{:?}", history);
+        for action in [
+            NemesisAction::Partition,
+            NemesisAction::ClockJump,
+            NemesisAction::KillRestart,
+            NemesisAction::LatencySpike,
+        ]
+        .iter()
+        {
+            assert!(
+                history.iter().any(|op| op.action() == *action),
+                "{:?}",
+                action
+            );
+        }
+        assert_eq!(chaos(5).0, history);
+        assert_ne!(chaos(6).0, history);
+    }
+
+    #[test]
+    /// Test that the nemesis skips actions refused by the fault budget, taking no actions once
+    /// the quiet period at the end of the run begins.
+    fn budgeted_chaos() {
+        let (run_length, quiet) = (
+            time::Duration::from_secs(600),
+            time::Duration::from_secs(120),
+        );
+        let mut simulation = Simulation::new(5).unwrap();
+        simulation.runtime().set_fault_budget(
+            FaultBudget::new()
+                .quiet_before_end(run_length, quiet)
+                .max_faults(FaultKind::HostKill, 0),
+        );
+        let handle = simulation.handle();
+        let history = simulation.run(async move {
+            let hosts = handle.spawn_hosts("node", 3);
+            let nemesis = Nemesis::new(hosts).restart_with(|_| async {});
+            let history = nemesis.clone();
+            let run = nemesis.run(handle.clone());
+            let _ = handle.timeout(run, run_length).await;
+            history.history()
+        });
+        assert!(!history.is_empty());
+        for op in history.iter() {
+            assert!(op.started() < run_length - quiet, "{:?}", op);
+            assert_ne!(op.action(), NemesisAction::KillRestart, "{:?}", op);
+        }
+    }
+}
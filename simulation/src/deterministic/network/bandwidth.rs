@@ -0,0 +1,116 @@
+//! Bandwidth limits on simulated streams and links.
+//!
+//! A [`Throttle`] paces the bytes sent through it to a fixed number of bytes per second of
+//! virtual time. Each write reserves transmission time on the throttle after any writes which
+//! were already reserved, and the writer is not allowed to send again until its bytes have been
+//! transmitted. A throttle can be owned by a single stream, or shared by every connection
+//! between two hosts so that the connections compete for the bandwidth of the link.
+//!
+//! [`Throttle`]:`Throttle`
+use std::{cmp, sync, time};
+
+#[derive(Debug)]
+pub(crate) struct Throttle {
+    bytes_per_second: u64,
+    /// Time at which all bytes reserved so far will have been transmitted.
+    busy_until: sync::Mutex<Option<time::Instant>>,
+}
+
+impl Throttle {
+    pub(crate) fn new(bytes_per_second: u64) -> Self {
+        Self {
+            bytes_per_second,
+            busy_until: sync::Mutex::new(None),
+        }
+    }
+
+    /// Reserves transmission time for `len` bytes written at `now`, returning the time at which
+    /// the bytes will have been transmitted.
+    pub(crate) fn reserve(&self, now: time::Instant, len: usize) -> time::Instant {
+        let mut busy_until = self.busy_until.lock().unwrap();
+        let start = busy_until.map_or(now, |busy_until| cmp::max(now, busy_until));
+        let transmitted = start + self.transmission(len);
+        busy_until.replace(transmitted);
+        transmitted
+    }
+
+    fn transmission(&self, len: usize) -> time::Duration {
+        if self.bytes_per_second == 0 {
+            return time::Duration::from_millis(0);
+        }
+        let nanos = len as u128 * 1_000_000_000 / u128::from(self.bytes_per_second);
+        time::Duration::from_nanos(nanos as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, DeterministicRuntimeHandle};
+    use crate::{Environment, TcpListener};
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Writes `len` bytes to `addr` in 1KB chunks, returning the virtual time taken to flush
+    /// them. If set, the bandwidth of the stream is limited to `bandwidth`.
+    async fn upload(
+        handle: &DeterministicRuntimeHandle,
+        addr: net::SocketAddr,
+        len: usize,
+        bandwidth: Option<u64>,
+    ) -> time::Duration {
+        let mut socket = handle.connect(addr).await.unwrap();
+        socket.set_bandwidth(bandwidth);
+        let start = handle.now();
+        for _ in 0..len / 1000 {
+            socket.write_all(&[0u8; 1000]).await.unwrap();
+        }
+        socket.flush().await.unwrap();
+        handle.now() - start
+    }
+
+    #[test]
+    /// Test that connections over a shared link split its bandwidth, and that a stream with its
+    /// own bandwidth limit is paced independently of other links.
+    fn shared_link_bandwidth() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let a: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let c: net::IpAddr = "10.0.0.3".parse().unwrap();
+        runtime.set_link_bandwidth(a, b, Some(10_000));
+        let handle_a = runtime.handle(a);
+        let handle_b = runtime.handle(b);
+        let handle_c = runtime.handle(c);
+        runtime.block_on(async {
+            let addr = net::SocketAddr::new(b, 9092);
+            let mut listener = handle_b.bind(addr).await.unwrap();
+            let server = handle_b.clone();
+            handle_b.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    server.spawn(async move {
+                        let mut buf = [0u8; 1000];
+                        while let Ok(n) = socket.read(&mut buf).await {
+                            if n == 0 {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+
+            let alone = upload(&handle_a, addr, 10_000, None).await;
+            assert_eq!(alone, time::Duration::from_secs(1));
+
+            let (first, second) = futures::join!(
+                upload(&handle_a, addr, 10_000, None),
+                upload(&handle_a, addr, 10_000, None)
+            );
+            assert!(first >= time::Duration::from_millis(1900), "{:?}", first);
+            assert!(second >= time::Duration::from_millis(1900), "{:?}", second);
+
+            let throttled = upload(&handle_c, addr, 5_000, Some(5_000)).await;
+            assert_eq!(throttled, time::Duration::from_secs(1));
+            let unthrottled = upload(&handle_c, addr, 5_000, None).await;
+            assert_eq!(unthrottled, time::Duration::from_secs(0));
+        });
+    }
+}
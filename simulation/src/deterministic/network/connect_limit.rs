@@ -0,0 +1,219 @@
+//! Network wide cap on in-progress connects.
+//!
+//! Real fabrics bound the number of half-open connections through SYN queues and connection
+//! tracking tables, so a thundering herd of reconnecting clients is admitted gradually rather
+//! than all at once. While a cap is configured, connects beyond it wait in FIFO order for an
+//! in-progress connect to complete, and then pay a fixed queueing delay before proceeding.
+use futures::{task::Waker, Future, Poll};
+use std::{collections, pin::Pin, sync, task::Context, time};
+use tracing::trace;
+
+#[derive(Debug)]
+struct State {
+    max: Option<usize>,
+    queue_delay: time::Duration,
+    in_progress: usize,
+    /// Tickets of the connects waiting for a slot, in the order they arrived.
+    queue: collections::VecDeque<u64>,
+    wakers: collections::HashMap<u64, Waker>,
+    next_ticket: u64,
+    /// Number of connects which were queued behind the cap.
+    queued: u64,
+}
+
+impl State {
+    fn has_capacity(&self) -> bool {
+        self.max.map_or(true, |max| self.in_progress < max)
+    }
+
+    /// Wakes the connect at the front of the queue, if a slot is available for it.
+    fn wake_next(&mut self) {
+        if !self.has_capacity() {
+            return;
+        }
+        if let Some(waker) = self.queue.front().and_then(|t| self.wakers.remove(t)) {
+            waker.wake();
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectLimit {
+    handle: crate::deterministic::DeterministicTimeHandle,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl ConnectLimit {
+    pub(crate) fn new(handle: crate::deterministic::DeterministicTimeHandle) -> Self {
+        Self {
+            handle,
+            state: sync::Arc::new(sync::Mutex::new(State {
+                max: None,
+                queue_delay: time::Duration::from_millis(0),
+                in_progress: 0,
+                queue: collections::VecDeque::new(),
+                wakers: collections::HashMap::new(),
+                next_ticket: 0,
+                queued: 0,
+            })),
+        }
+    }
+
+    /// Caps the number of in-progress connects, removing the cap if `max` is `None`. Connects
+    /// which had to queue are delayed by `queue_delay` once admitted.
+    pub(crate) fn set(&self, max: Option<usize>, queue_delay: time::Duration) {
+        assert!(max != Some(0), "connect limit must be greater than zero");
+        let mut state = self.state.lock().unwrap();
+        state.max = max;
+        state.queue_delay = queue_delay;
+        state.wake_next();
+    }
+
+    pub(crate) fn queued(&self) -> u64 {
+        self.state.lock().unwrap().queued
+    }
+
+    /// Waits for a connect slot, returning a permit which holds the slot until dropped.
+    pub(crate) async fn acquire(&self) -> ConnectPermit {
+        let mut admission = Admission {
+            state: sync::Arc::clone(&self.state),
+            ticket: None,
+        };
+        let queued = (&mut admission).await;
+        let permit = ConnectPermit {
+            state: sync::Arc::clone(&admission.state),
+        };
+        if queued {
+            let delay = self.state.lock().unwrap().queue_delay;
+            self.handle.delay_from(delay).await;
+        }
+        permit
+    }
+}
+
+/// Future which resolves once a slot is available, returning true if the connect was queued.
+struct Admission {
+    state: sync::Arc<sync::Mutex<State>>,
+    ticket: Option<u64>,
+}
+
+impl Future for Admission {
+    type Output = bool;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock().unwrap();
+        let ticket = match self.ticket {
+            None if state.queue.is_empty() && state.has_capacity() => {
+                state.in_progress += 1;
+                return Poll::Ready(false);
+            }
+            None => {
+                let ticket = state.next_ticket;
+                state.next_ticket += 1;
+                state.queued += 1;
+                state.queue.push_back(ticket);
+                trace!("queueing connect behind {} in progress", state.in_progress);
+                ticket
+            }
+            Some(ticket) => ticket,
+        };
+        if state.queue.front() == Some(&ticket) && state.has_capacity() {
+            state.queue.pop_front();
+            state.in_progress += 1;
+            // The next queued connect may also fit if the cap was raised.
+            state.wake_next();
+            return Poll::Ready(true);
+        }
+        state.wakers.insert(ticket, cx.waker().clone());
+        drop(state);
+        self.ticket.replace(ticket);
+        Poll::Pending
+    }
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        // Abandoned connects give up their place in the queue.
+        if let Some(ticket) = self.ticket {
+            let mut state = self.state.lock().unwrap();
+            if state.queue.contains(&ticket) {
+                state.queue.retain(|t| *t != ticket);
+                state.wakers.remove(&ticket);
+                state.wake_next();
+            }
+        }
+    }
+}
+
+/// Slot held by an in-progress connect.
+#[derive(Debug)]
+pub(crate) struct ConnectPermit {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Drop for ConnectPermit {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        state.in_progress -= 1;
+        state.wake_next();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, LimitPolicy, ListenerOptions};
+    use crate::{Environment, TcpListener};
+    use futures::channel::oneshot;
+    use std::{net, time};
+
+    #[test]
+    /// Test that connects beyond the limit queue behind in-progress connects and are delayed
+    /// once admitted.
+    fn connect_limit() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_connect_limit(Some(1), time::Duration::from_millis(100));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let options = ListenerOptions::new().max_connections(1, LimitPolicy::Queue);
+            let mut listener = handle.bind_with_options(addr, options).await.unwrap();
+            let server = handle.clone();
+            handle.spawn(async move {
+                // Each connection is held for 10ms, so queued connects remain in progress.
+                while let Ok((socket, _)) = listener.accept().await {
+                    let delay = server.delay_from(time::Duration::from_millis(10));
+                    server.spawn(async move {
+                        delay.await;
+                        drop(socket);
+                    });
+                }
+            });
+            let start = handle.now();
+            let mut done = vec![];
+            for _ in 0..3 {
+                let (tx, rx) = oneshot::channel();
+                let client = handle.clone();
+                handle.spawn(async move {
+                    let socket = client.connect(addr).await.unwrap();
+                    let _ = tx.send((client.now() - start, socket));
+                });
+                done.push(rx);
+            }
+            let mut elapsed: Vec<_> = futures::future::join_all(done)
+                .await
+                .into_iter()
+                .map(|result| result.unwrap().0)
+                .collect();
+            elapsed.sort();
+            assert_eq!(
+                elapsed,
+                vec![
+                    time::Duration::from_millis(0),
+                    time::Duration::from_millis(10),
+                    time::Duration::from_millis(110)
+                ]
+            );
+            handle.delay_from(time::Duration::from_secs(1)).await;
+        });
+        assert_eq!(runtime.queued_connects(), 1);
+    }
+}
@@ -0,0 +1,230 @@
+//! Fault budgets constrain the faults injected over the course of a run.
+//!
+//! Every fault applied to the network acquires a permit from the network's budget, whether it
+//! was chosen by a fault injector, replayed from a schedule or taken by a nemesis, and faults
+//! which the budget refuses are not applied. Budgets can cap the total number of faults of a
+//! particular kind, the number of faults of a kind which are active at once, and keep the end of
+//! a run free of faults. Faults such as partitions stay active until
+//! they are reverted, while faults such as injected bytes are only active as they are applied.
+use super::FaultKind;
+use std::{collections, sync, time};
+use tracing::trace;
+
+/// Limits applied to the faults injected over the course of a run.
+#[derive(Debug, Clone, Default)]
+pub struct FaultBudget {
+    max_faults: collections::HashMap<FaultKind, usize>,
+    max_active: collections::HashMap<FaultKind, usize>,
+    /// Length of the run, and the final window of it during which no faults are injected.
+    quiet_before_end: Option<(time::Duration, time::Duration)>,
+}
+
+impl FaultBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the total number of faults of the provided kind injected during the run.
+    pub fn max_faults(mut self, kind: FaultKind, max: usize) -> Self {
+        self.max_faults.insert(kind, max);
+        self
+    }
+
+    /// Caps the number of faults of the provided kind which can be active at once.
+    pub fn max_active(mut self, kind: FaultKind, max: usize) -> Self {
+        self.max_active.insert(kind, max);
+        self
+    }
+
+    /// Prevents any faults from being injected during the final `quiet` of a run which ends
+    /// `run_length` after the budget is installed, leaving the system time to recover before
+    /// the run is validated.
+    pub fn quiet_before_end(mut self, run_length: time::Duration, quiet: time::Duration) -> Self {
+        self.quiet_before_end.replace((run_length, quiet));
+        self
+    }
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    budget: FaultBudget,
+    installed_at: time::Instant,
+    injected: collections::HashMap<FaultKind, usize>,
+    active: collections::HashMap<FaultKind, usize>,
+}
+
+/// Shared handle to the fault budget of a network. An unset budget permits all faults.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FaultBudgetHandle {
+    inner: sync::Arc<sync::Mutex<Option<BudgetState>>>,
+}
+
+impl FaultBudgetHandle {
+    /// Install a new budget, resetting the count of injected faults.
+    pub(crate) fn set(&self, budget: FaultBudget, now: time::Instant) {
+        let state = BudgetState {
+            budget,
+            installed_at: now,
+            injected: collections::HashMap::new(),
+            active: collections::HashMap::new(),
+        };
+        self.inner.lock().unwrap().replace(state);
+    }
+
+    /// Attempt to acquire a permit to inject a fault of the provided kind. The fault is
+    /// considered active until the returned permit is dropped.
+    pub(crate) fn try_acquire(&self, kind: FaultKind, now: time::Instant) -> Option<FaultPermit> {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(state) = lock.as_mut() {
+            if let Some((run_length, quiet)) = state.budget.quiet_before_end {
+                let deadline = state.installed_at + run_length;
+                if now + quiet >= deadline {
+                    trace!("fault budget denied {:?}, run is in quiet period", kind);
+                    return None;
+                }
+            }
+            let injected = state.injected.entry(kind).or_insert(0);
+            if let Some(max) = state.budget.max_faults.get(&kind) {
+                if *injected >= *max {
+                    trace!(
+                        "fault budget denied {:?}, {} faults injected",
+                        kind,
+                        injected
+                    );
+                    return None;
+                }
+            }
+            let active = state.active.entry(kind).or_insert(0);
+            if let Some(max) = state.budget.max_active.get(&kind) {
+                if *active >= *max {
+                    trace!("fault budget denied {:?}, {} faults active", kind, active);
+                    return None;
+                }
+            }
+            *state.injected.get_mut(&kind).unwrap() += 1;
+            *state.active.get_mut(&kind).unwrap() += 1;
+        }
+        Some(FaultPermit {
+            handle: self.clone(),
+            kind,
+        })
+    }
+
+    fn release(&self, kind: FaultKind) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(state) = lock.as_mut() {
+            if let Some(active) = state.active.get_mut(&kind) {
+                *active = active.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Permit to inject a fault. The fault is considered active until the permit is dropped.
+#[derive(Debug)]
+pub(crate) struct FaultPermit {
+    handle: FaultBudgetHandle,
+    kind: FaultKind,
+}
+
+impl Drop for FaultPermit {
+    fn drop(&mut self) {
+        self.handle.release(self.kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::FaultAction;
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, PartitionMode};
+
+    #[test]
+    fn max_faults() {
+        let now = time::Instant::now();
+        let handle = FaultBudgetHandle::default();
+        handle.set(FaultBudget::new().max_faults(FaultKind::Replay, 2), now);
+        assert!(handle.try_acquire(FaultKind::Replay, now).is_some());
+        assert!(handle.try_acquire(FaultKind::Replay, now).is_some());
+        assert!(
+            handle.try_acquire(FaultKind::Replay, now).is_none(),
+            "expected budget to be exhausted"
+        );
+        assert!(
+            handle.try_acquire(FaultKind::Latency, now).is_some(),
+            "expected other fault kinds to be unaffected"
+        );
+    }
+
+    #[test]
+    fn max_active() {
+        let now = time::Instant::now();
+        let handle = FaultBudgetHandle::default();
+        handle.set(FaultBudget::new().max_active(FaultKind::Crossing, 1), now);
+        let permit = handle.try_acquire(FaultKind::Crossing, now);
+        assert!(permit.is_some());
+        assert!(
+            handle.try_acquire(FaultKind::Crossing, now).is_none(),
+            "expected only one active fault"
+        );
+        drop(permit);
+        assert!(
+            handle.try_acquire(FaultKind::Crossing, now).is_some(),
+            "expected permit to be available once the active fault ended"
+        );
+    }
+
+    #[test]
+    fn quiet_period() {
+        let now = time::Instant::now();
+        let handle = FaultBudgetHandle::default();
+        let secs = time::Duration::from_secs;
+        handle.set(
+            FaultBudget::new().quiet_before_end(secs(100), secs(30)),
+            now,
+        );
+        assert!(handle.try_acquire(FaultKind::Latency, now).is_some());
+        assert!(handle
+            .try_acquire(FaultKind::Latency, now + secs(69))
+            .is_some());
+        assert!(
+            handle
+                .try_acquire(FaultKind::Latency, now + secs(70))
+                .is_none(),
+            "expected no faults during the final 30 seconds of the run"
+        );
+    }
+
+    #[test]
+    /// Test that the budget applies to faults applied to the network, refusing a second
+    /// concurrent partition until the first one heals.
+    fn concurrent_partitions() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_fault_budget(FaultBudget::new().max_active(FaultKind::Partition, 1));
+        let handle = runtime.localhost_handle();
+        let (a, b, c) = (
+            handle.add_host().local_addr(),
+            handle.add_host().local_addr(),
+            handle.add_host().local_addr(),
+        );
+        let network = &handle.network_handle;
+        let partition = |x, y| FaultAction::Partition {
+            a: vec![x],
+            b: vec![y],
+            mode: PartitionMode::Stall,
+        };
+        assert!(network.apply_fault(partition(a, b)));
+        assert!(
+            !network.apply_fault(partition(a, c)),
+            "expected a second concurrent partition to be refused"
+        );
+        assert!(network.apply_fault(FaultAction::Heal {
+            a: vec![a],
+            b: vec![b],
+        }));
+        assert!(
+            network.apply_fault(partition(a, c)),
+            "expected a partition once the first one healed"
+        );
+    }
+}
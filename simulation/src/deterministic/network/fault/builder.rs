@@ -0,0 +1,202 @@
+//! Declarative construction of fault schedules.
+//!
+//! Tests which script faults by hand thread fault handles through test code and sleep between
+//! faults. A [`FaultScheduleBuilder`] instead declares what happens when, such as partitioning
+//! two groups of hosts at 10s for 30s or resetting the connections of a host at 60s, along with
+//! rules which fire periodically with some probability. Building the schedule resolves the
+//! periodic rules against a seed, producing a [`FaultSchedule`] which the runtime applies with
+//! a [`FaultScheduleInjector`] like any recorded schedule. Scheduled faults are subject to the
+//! fault budget as they are applied, so declared partitions count as [`FaultKind::Partition`]
+//! faults until they heal and host resets count as [`FaultKind::HostKill`] faults.
+//!
+//! [`FaultScheduleBuilder`]:`FaultScheduleBuilder`
+//! [`FaultSchedule`]:`FaultSchedule`
+//! [`FaultScheduleInjector`]:`super::FaultScheduleInjector`
+//! [`FaultKind::Partition`]:`super::FaultKind::Partition`
+//! [`FaultKind::HostKill`]:`super::FaultKind::HostKill`
+use super::{FaultAction, FaultSchedule, ScheduledFault};
+use crate::deterministic::{PartitionMode, Seed};
+use std::{net, ops, time};
+
+/// A fault applied every period of a window with some probability.
+#[derive(Debug, Clone)]
+struct PeriodicFault {
+    window: ops::Range<time::Duration>,
+    period: time::Duration,
+    probability: f64,
+    action: FaultAction,
+}
+
+/// Builds a fault schedule from faults declared at offsets from the start of the run.
+#[derive(Debug, Clone, Default)]
+pub struct FaultScheduleBuilder {
+    faults: Vec<ScheduledFault>,
+    periodic: Vec<PeriodicFault>,
+}
+
+impl FaultScheduleBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Applies the action at the provided offset.
+    pub fn at(mut self, at: time::Duration, action: FaultAction) -> Self {
+        self.faults.push(ScheduledFault::new(at, action));
+        self
+    }
+
+    /// Partitions the hosts of `a` from the hosts of `b` at the provided offset, healing the
+    /// partition once `duration` has elapsed. Other partitions are unaffected by the heal.
+    pub fn partition(
+        self,
+        at: time::Duration,
+        duration: time::Duration,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+        mode: PartitionMode,
+    ) -> Self {
+        let (a, b) = (a.to_vec(), b.to_vec());
+        self.at(
+            at,
+            FaultAction::Partition {
+                a: a.clone(),
+                b: b.clone(),
+                mode,
+            },
+        )
+        .at(at + duration, FaultAction::Heal { a, b })
+    }
+
+    /// Resets every connection to or from the host at the provided offset.
+    pub fn reset_host(self, at: time::Duration, host: net::IpAddr) -> Self {
+        self.at(at, FaultAction::ResetHost { host })
+    }
+
+    /// Applies the action at every multiple of `period` within the window with the provided
+    /// probability. Each periodic rule draws from its own substream of the seed the schedule
+    /// is built with, so adding a rule does not change the faults of the others.
+    pub fn every(
+        mut self,
+        window: ops::Range<time::Duration>,
+        period: time::Duration,
+        probability: f64,
+        action: FaultAction,
+    ) -> Self {
+        assert!(
+            period > time::Duration::from_millis(0),
+            "period must be greater than zero"
+        );
+        self.periodic.push(PeriodicFault {
+            window,
+            period,
+            probability,
+            action,
+        });
+        self
+    }
+
+    /// Builds the schedule, deciding which periodic faults fire from the provided seed.
+    pub fn build(self, seed: Seed) -> FaultSchedule {
+        let mut faults = self.faults;
+        let seed = seed.derive("fault-schedule");
+        for (index, rule) in self.periodic.into_iter().enumerate() {
+            let random = seed.derive(&index.to_string()).random();
+            let mut at = rule.window.start;
+            while at < rule.window.end {
+                if random.should_fault(rule.probability) {
+                    faults.push(ScheduledFault::new(at, rule.action.clone()));
+                }
+                at += rule.period;
+            }
+        }
+        FaultSchedule::from(faults)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultBudget, FaultKind};
+    use crate::{Environment, TcpListener};
+    use std::io;
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    /// Test that a declared schedule partitions, heals and resets hosts at the declared times,
+    /// and that its periodic faults are reproduced by the seed.
+    fn declared_schedule() {
+        let secs = time::Duration::from_secs;
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let a = handle.add_host();
+        let b = handle.add_host();
+        let (a_ip, b_ip) = (a.local_addr(), b.local_addr());
+        let builder = FaultScheduleBuilder::new()
+            .partition(secs(10), secs(30), &[a_ip], &[b_ip], PartitionMode::Reset)
+            .reset_host(secs(60), b_ip)
+            .every(
+                secs(0)..secs(120),
+                secs(5),
+                0.1,
+                FaultAction::SendLatency {
+                    host: a_ip,
+                    latency: time::Duration::from_millis(200),
+                },
+            );
+        let schedule = builder.clone().build(runtime.seed());
+        assert_eq!(schedule, builder.clone().build(runtime.seed()));
+        let periodic = schedule
+            .iter()
+            .filter(|fault| match fault.action() {
+                FaultAction::SendLatency { .. } => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(schedule.len(), periodic + 3);
+        let injector = runtime.schedule_faults(builder);
+
+        runtime.block_on(async {
+            let start = handle.now();
+            handle.spawn(injector.run());
+            let addr = net::SocketAddr::new(b_ip, 80);
+            let mut listener = b.bind(addr).await.unwrap();
+            b.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((socket, _)) = listener.accept().await {
+                    accepted.push(socket);
+                }
+            });
+            a.delay_from(secs(20)).await;
+            let err = a.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            a.delay_from(secs(25)).await;
+            let mut socket = a.connect(addr).await.unwrap();
+            let err = socket.read(&mut [0u8; 1]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert!(handle.now() - start >= secs(60));
+        });
+    }
+
+    #[test]
+    /// Test that scheduled faults are subject to the fault budget, with host resets counting as
+    /// host kills.
+    fn budgeted_schedule() {
+        let secs = time::Duration::from_secs;
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_fault_budget(FaultBudget::new().max_faults(FaultKind::HostKill, 1));
+        runtime.record_faults();
+        let host = runtime.localhost_handle().add_host().local_addr();
+        let injector = runtime.schedule_faults(
+            FaultScheduleBuilder::new()
+                .reset_host(secs(1), host)
+                .reset_host(secs(2), host),
+        );
+        runtime.block_on(injector.run());
+        let applied: Vec<_> = runtime.fault_schedule().iter().map(|f| f.at()).collect();
+        assert_eq!(
+            applied,
+            vec![secs(1)],
+            "expected the second reset to be refused"
+        );
+    }
+}
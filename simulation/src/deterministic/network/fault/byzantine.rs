@@ -0,0 +1,101 @@
+//! Fault injector which injects attacker chosen bytes into connections at seeded points.
+use super::socket::InjectionPoint;
+use super::{Connection, ConnectionSide, FaultAction, Inner};
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use bytes::Bytes;
+use std::{sync, time};
+use tracing::trace;
+
+/// Upper bound on the stream offset chosen for mid-stream injections.
+const MAX_INJECTION_OFFSET: usize = 4096;
+
+pub struct ByzantineFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    time_handle: DeterministicTimeHandle,
+    payloads: Vec<Bytes>,
+    probability: f64,
+}
+
+impl ByzantineFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+        time_handle: DeterministicTimeHandle,
+        payloads: Vec<Bytes>,
+    ) -> Self {
+        Self {
+            inner,
+            random_handle,
+            time_handle,
+            payloads,
+            probability: 0.05,
+        }
+    }
+
+    /// Sets the probability that a payload is injected into a connection each second.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    /// Consumes this fault injector and begins injecting payloads into randomly selected connections.
+    pub async fn run(self) {
+        loop {
+            self.time_handle
+                .delay_from(time::Duration::from_secs(1))
+                .await;
+            if self.random_handle.should_fault(self.probability) {
+                self.inject_payload();
+            }
+        }
+    }
+
+    /// Choose a random injection point.
+    fn injection_point(&self) -> InjectionPoint {
+        match self.random_handle.gen_range(0..3) {
+            0 => InjectionPoint::Prepend,
+            1 => InjectionPoint::Offset(self.random_handle.gen_range(0..MAX_INJECTION_OFFSET)),
+            _ => InjectionPoint::AfterClose,
+        }
+    }
+
+    /// Pick a random connection and side, injecting a random payload at a random injection point.
+    fn inject_payload(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        // Dropped connections lingering in TIME_WAIT have no reader left to inject into.
+        let live: Vec<&Connection> = lock
+            .connections
+            .iter()
+            .filter(|c| !c.is_dropped())
+            .collect();
+        if live.is_empty() || self.payloads.is_empty() {
+            return;
+        }
+        let connection = live[self.random_handle.gen_range(0..live.len())];
+        let payload = self.payloads[self.random_handle.gen_range(0..self.payloads.len())].clone();
+        let point = self.injection_point();
+        trace!(
+            "injecting {} bytes into {} -> {} at {:?}",
+            payload.len(),
+            connection.source(),
+            connection.dest(),
+            point
+        );
+        let target = connection.target(self.side());
+        lock.apply_fault(FaultAction::Inject {
+            target,
+            bytes: payload,
+            point,
+        });
+    }
+
+    /// Choose a random side of a connection.
+    fn side(&self) -> ConnectionSide {
+        if self.random_handle.should_fault(0.5) {
+            ConnectionSide::Client
+        } else {
+            ConnectionSide::Server
+        }
+    }
+}
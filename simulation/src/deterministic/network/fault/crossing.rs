@@ -0,0 +1,124 @@
+//! Connection crossing fault, modeling load balancer connection confusion bugs.
+//!
+//! When connection crossing is armed, the first connection established to a listener is held
+//! for the crossing window. If a second connection to the same listener is established within
+//! the window, the server halves of the two connections are swapped. Each server half continues
+//! to report the peer address of the client it was created for, while exchanging bytes with the
+//! other client.
+use super::socket::{FaultyTcpStream, SocketHalf};
+use super::{FaultAction, Inner};
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use futures::{channel::oneshot, FutureExt};
+use std::{net, ops, sync, time};
+use tracing::trace;
+
+/// A server half waiting to be crossed with the next connection to the same listener.
+pub(crate) struct PendingCrossing {
+    pub(crate) source: net::SocketAddr,
+    pub(crate) server: FaultyTcpStream<SocketHalf>,
+    pub(crate) notify: oneshot::Sender<FaultyTcpStream<SocketHalf>>,
+}
+
+pub(crate) type CrossingSlot = sync::Arc<sync::Mutex<Option<PendingCrossing>>>;
+
+/// Delivery of a server half to a listener, which may be held for the crossing window.
+pub(crate) enum Delivery {
+    Now(FaultyTcpStream<SocketHalf>),
+    Held {
+        slot: CrossingSlot,
+        crossed: oneshot::Receiver<FaultyTcpStream<SocketHalf>>,
+        window: tokio::timer::Delay,
+    },
+}
+
+impl Delivery {
+    /// Resolves to the server half which should be delivered to the listener.
+    pub(crate) async fn server(self) -> FaultyTcpStream<SocketHalf> {
+        match self {
+            Delivery::Now(server) => server,
+            Delivery::Held {
+                slot,
+                crossed,
+                window,
+            } => {
+                let mut crossed = crossed.fuse();
+                let mut window = window.fuse();
+                futures::select! {
+                    server = crossed => server.expect("crossing slot dropped"),
+                    _ = window => {
+                        let pending = slot.lock().unwrap().take();
+                        match pending {
+                            Some(pending) => pending.server,
+                            // the server half was crossed as the window elapsed
+                            None => crossed.await.expect("crossing slot dropped"),
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+pub struct CrossingFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    time_handle: DeterministicTimeHandle,
+    window_range: ops::Range<time::Duration>,
+    probability: f64,
+}
+
+impl CrossingFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+        time_handle: DeterministicTimeHandle,
+    ) -> Self {
+        Self {
+            inner,
+            random_handle,
+            time_handle,
+            window_range: time::Duration::from_millis(1)..time::Duration::from_millis(500),
+            probability: 0.01,
+        }
+    }
+
+    /// Sets the probability that connection crossing is armed each second.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    /// Sets the range of crossing window durations.
+    pub fn window_range(mut self, range: ops::Range<time::Duration>) -> Self {
+        self.window_range = range;
+        self
+    }
+
+    /// Consumes this fault injector, periodically arming connection crossing for a random window.
+    pub async fn run(self) {
+        loop {
+            self.time_handle
+                .delay_from(time::Duration::from_secs(1))
+                .await;
+            if self.random_handle.should_fault(self.probability) {
+                let window = self.random_handle.gen_range(self.window_range.clone());
+                let armed = self
+                    .inner
+                    .lock()
+                    .unwrap()
+                    .apply_fault(FaultAction::Crossing {
+                        window: Some(window),
+                    });
+                if !armed {
+                    continue;
+                }
+                trace!("arming connection crossing for {:?}", window);
+                self.time_handle.delay_from(window).await;
+                self.inner
+                    .lock()
+                    .unwrap()
+                    .apply_fault(FaultAction::Crossing { window: None });
+            }
+        }
+    }
+}
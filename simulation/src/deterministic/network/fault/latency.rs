@@ -1,5 +1,5 @@
 //! Fault injector which periodically adjusts socket latency.
-use super::Inner;
+use super::{ConnectionSide, FaultAction, Inner};
 use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
 use std::{ops, sync, time};
 
@@ -74,19 +74,22 @@ impl LatencyFaultInjector {
     /// Iterate through all connections, setting a random latency value for both server and client send/receive calls.
     fn inject_latency(&self) {
         let mut lock = self.inner.lock().unwrap();
-        for connection in lock.connections.iter_mut() {
-            connection
-                .client_fault_handle
-                .set_receive_latency(self.client_latency());
-            connection
-                .client_fault_handle
-                .set_send_latency(self.client_latency());
-            connection
-                .server_fault_handle
-                .set_receive_latency(self.server_latency());
-            connection
-                .server_fault_handle
-                .set_send_latency(self.server_latency());
+        let connections: Vec<_> = lock
+            .connections
+            .iter()
+            .map(|connection| connection.target(ConnectionSide::Client))
+            .collect();
+        for target in connections {
+            lock.apply_fault(FaultAction::Latency {
+                target,
+                receive: self.client_latency(),
+                send: self.client_latency(),
+            });
+            lock.apply_fault(FaultAction::Latency {
+                target: target.with_side(ConnectionSide::Server),
+                receive: self.server_latency(),
+                send: self.server_latency(),
+            });
         }
     }
 }
@@ -1,37 +1,121 @@
 use super::socket;
 use super::Inner;
-use std::net;
+use futures::task::Waker;
+use std::{net, time};
+mod budget;
+mod builder;
+mod byzantine;
+mod crossing;
 mod latency;
+mod provenance;
+mod replay;
+mod schedule;
 mod swizzle;
+pub use budget::FaultBudget;
+pub(crate) use budget::{FaultBudgetHandle, FaultPermit};
+pub use builder::FaultScheduleBuilder;
+pub use byzantine::ByzantineFaultInjector;
+pub use crossing::CrossingFaultInjector;
+pub(crate) use crossing::{CrossingSlot, Delivery, PendingCrossing};
 pub use latency::{LatencyFaultInjector, LatencyFaultInjectorConfig};
+pub(crate) use provenance::FaultIds;
+pub use provenance::{ConnectionId, FaultId, FaultKind, FaultProvenance, FaultProvenanceExt};
+pub use replay::ReplayFaultInjector;
+pub(crate) use schedule::FaultRecorder;
+pub use schedule::{
+    ConnectionSide, FaultAction, FaultSchedule, FaultScheduleInjector, FaultTarget, ScheduledFault,
+};
 pub(crate) use swizzle::CloggedConnection;
 
 const SWIZZLE_START_PROBABILITY: f64 = 0.01;
 const SWIZZLE_SELECTION_PROBABILITY: f64 = 0.30;
 
+/// Snapshot of an open connection and the traffic it has carried, used to target faults at
+/// connections selected from the state of the simulation. With the `serde` feature enabled,
+/// snapshots can be serialized and compared against golden files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionInfo {
+    id: ConnectionId,
+    source: net::SocketAddr,
+    dest: net::SocketAddr,
+    target: FaultTarget,
+    bytes_sent: u64,
+    bytes_received: u64,
+}
+
+impl ConnectionInfo {
+    pub fn id(&self) -> ConnectionId {
+        self.id
+    }
+
+    /// Returns the address of the client which established the connection.
+    pub fn source(&self) -> net::SocketAddr {
+        self.source
+    }
+
+    /// Returns the address of the listener the connection was established to.
+    pub fn dest(&self) -> net::SocketAddr {
+        self.dest
+    }
+
+    /// Returns the number of bytes written by the client.
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent
+    }
+
+    /// Returns the number of bytes written by the server.
+    pub fn bytes_received(&self) -> u64 {
+        self.bytes_received
+    }
+
+    /// Returns the number of bytes written in either direction.
+    pub fn traffic(&self) -> u64 {
+        self.bytes_sent + self.bytes_received
+    }
+
+    /// Returns a fault target for the provided side of the connection.
+    pub fn target(&self, side: ConnectionSide) -> FaultTarget {
+        self.target.with_side(side)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Connection {
+    id: ConnectionId,
     source: net::SocketAddr,
     dest: net::SocketAddr,
+    /// Target of the client side of the connection, from the addresses it was established with.
+    target: FaultTarget,
     client_fault_handle: socket::FaultyTcpStreamHandle,
     server_fault_handle: socket::FaultyTcpStreamHandle,
 }
 
 impl Connection {
     pub(crate) fn new(
+        id: ConnectionId,
         source: net::SocketAddr,
         dest: net::SocketAddr,
+        ordinal: usize,
         client_fault_handle: socket::FaultyTcpStreamHandle,
         server_fault_handle: socket::FaultyTcpStreamHandle,
     ) -> Self {
+        client_fault_handle.set_connection_id(id);
+        server_fault_handle.set_connection_id(id);
         Self {
+            id,
             source,
             dest,
+            target: FaultTarget::new(source.ip(), dest, ordinal, ConnectionSide::Client),
             client_fault_handle,
             server_fault_handle,
         }
     }
 
+    pub(crate) fn id(&self) -> ConnectionId {
+        self.id
+    }
+
     pub(crate) fn source(&self) -> net::SocketAddr {
         self.source
     }
@@ -40,10 +124,80 @@ impl Connection {
         self.dest
     }
 
+    pub(crate) fn info(&self) -> ConnectionInfo {
+        ConnectionInfo {
+            id: self.id,
+            source: self.source,
+            dest: self.dest,
+            target: self.target,
+            bytes_sent: self.client_fault_handle.bytes_written(),
+            bytes_received: self.server_fault_handle.bytes_written(),
+        }
+    }
+
     pub(crate) fn is_dropped(&self) -> bool {
         self.client_fault_handle.is_dropped() || self.server_fault_handle.is_dropped()
     }
 
+    /// Returns the time at which the first side of the connection was closed.
+    pub(crate) fn closed_at(&self) -> Option<time::Instant> {
+        match (
+            self.client_fault_handle.closed_at(),
+            self.server_fault_handle.closed_at(),
+        ) {
+            (Some(client), Some(server)) => Some(std::cmp::min(client, server)),
+            (client, server) => client.or(server),
+        }
+    }
+
+    /// Swap the server halves of two connections.
+    pub(crate) fn swap_server(&mut self, other: &mut Connection) {
+        std::mem::swap(
+            &mut self.server_fault_handle,
+            &mut other.server_fault_handle,
+        );
+        // Each server half now belongs to the other connection.
+        self.server_fault_handle.set_connection_id(self.id);
+        other.server_fault_handle.set_connection_id(other.id);
+    }
+
+    /// Moves the endpoints of the connection bound to `from` to the address `to`, updating the
+    /// addresses reported by both streams.
+    pub(crate) fn migrate(&mut self, from: net::IpAddr, to: net::IpAddr) {
+        if self.source.ip() == from {
+            self.source.set_ip(to);
+        }
+        if self.dest.ip() == from {
+            self.dest.set_ip(to);
+        }
+        self.client_fault_handle.migrate(self.source, self.dest);
+        self.server_fault_handle.migrate(self.dest, self.source);
+    }
+
+    /// Returns a fault target for the provided side of the connection.
+    pub(crate) fn target(&self, side: ConnectionSide) -> FaultTarget {
+        self.target.with_side(side)
+    }
+
+    /// Returns true if the provided target refers to this connection, on either side.
+    pub(crate) fn is_target(&self, target: &FaultTarget) -> bool {
+        self.target == target.with_side(ConnectionSide::Client)
+    }
+
+    /// Returns the fault handle for the provided side of the connection.
+    pub(crate) fn fault_handle(&self, side: ConnectionSide) -> &socket::FaultyTcpStreamHandle {
+        match side {
+            ConnectionSide::Client => &self.client_fault_handle,
+            ConnectionSide::Server => &self.server_fault_handle,
+        }
+    }
+
+    /// Register a waker to be notified once either side of the connection is dropped.
+    pub(crate) fn register_drop_waker(&self, waker: &Waker) {
+        self.client_fault_handle.register_drop_waker(waker);
+        self.server_fault_handle.register_drop_waker(waker);
+    }
+
     pub(crate) fn is_clogged(&self) -> bool {
         self.client_fault_handle.is_fully_clogged() && self.server_fault_handle.is_fully_clogged()
     }
@@ -0,0 +1,161 @@
+//! Provenance records for IO errors caused by injected faults.
+//!
+//! Errors returned due to an injected fault carry a [`FaultProvenance`] describing the fault,
+//! which can be retrieved from the `io::Error` using [`FaultProvenanceExt::fault_provenance`].
+//!
+//! [`FaultProvenance`]:`FaultProvenance`
+//! [`FaultProvenanceExt::fault_provenance`]:`FaultProvenanceExt::fault_provenance`
+use std::{
+    error, fmt, io,
+    sync::{self, atomic},
+    time,
+};
+
+/// Identifier of an injected fault, unique within a simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct FaultId(u64);
+
+impl fmt::Display for FaultId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fault#{}", self.0)
+    }
+}
+
+/// Identifier of a simulated connection, unique within a simulation. Both halves of a connection
+/// share the same identifier.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionId(pub(crate) u64);
+
+impl fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "conn#{}", self.0)
+    }
+}
+
+/// The type of an injected fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum FaultKind {
+    /// The connection was disconnected.
+    Disconnect,
+    /// Connection latency was adjusted.
+    Latency,
+    /// Bytes were injected into a connection.
+    ByteInjection,
+    /// Previously delivered bytes were replayed on a connection.
+    Replay,
+    /// Connections were crossed.
+    Crossing,
+    /// The connection was reset, as when its host migrated to a new address.
+    Reset,
+    /// Writes to the connection were shut down.
+    Shutdown,
+    /// Hosts were partitioned from each other.
+    Partition,
+    /// A host was killed, or every connection of a host was reset.
+    HostKill,
+    /// The wall clock of a host was stepped.
+    ClockJump,
+}
+
+/// Record of the injected fault responsible for an IO error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FaultProvenance {
+    id: FaultId,
+    kind: FaultKind,
+    injected_at: time::Instant,
+    connection: Option<ConnectionId>,
+}
+
+impl FaultProvenance {
+    pub(crate) fn new(
+        id: FaultId,
+        kind: FaultKind,
+        injected_at: time::Instant,
+        connection: Option<ConnectionId>,
+    ) -> Self {
+        Self {
+            id,
+            kind,
+            injected_at,
+            connection,
+        }
+    }
+
+    pub fn id(&self) -> FaultId {
+        self.id
+    }
+
+    pub fn kind(&self) -> FaultKind {
+        self.kind
+    }
+
+    /// Returns the simulated time at which the fault was injected.
+    pub fn injected_at(&self) -> time::Instant {
+        self.injected_at
+    }
+
+    /// Returns the connection the fault was injected into.
+    pub fn connection_id(&self) -> Option<ConnectionId> {
+        self.connection
+    }
+
+    /// Returns the provenance of the same fault, attributed to the provided connection.
+    pub(crate) fn with_connection(self, connection: Option<ConnectionId>) -> Self {
+        Self { connection, ..self }
+    }
+
+    /// Returns a new `io::Error` of the provided kind, carrying this provenance record.
+    pub(crate) fn into_io_error(self, kind: io::ErrorKind) -> io::Error {
+        io::Error::new(kind, FaultError { provenance: self })
+    }
+}
+
+/// Error payload attached to IO errors caused by injected faults.
+#[derive(Debug)]
+struct FaultError {
+    provenance: FaultProvenance,
+}
+
+impl fmt::Display for FaultError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "injected {:?} fault ({})",
+            self.provenance.kind, self.provenance.id
+        )?;
+        if let Some(connection) = self.provenance.connection {
+            write!(f, " on {}", connection)?;
+        }
+        write!(f, " at {:?}", self.provenance.injected_at)
+    }
+}
+
+impl error::Error for FaultError {}
+
+/// Extension trait for retrieving the provenance of IO errors caused by injected faults.
+pub trait FaultProvenanceExt {
+    /// Returns the provenance record of the injected fault which caused this error, or `None`
+    /// if the error was not caused by an injected fault.
+    fn fault_provenance(&self) -> Option<&FaultProvenance>;
+}
+
+impl FaultProvenanceExt for io::Error {
+    fn fault_provenance(&self) -> Option<&FaultProvenance> {
+        self.get_ref()
+            .and_then(|e| e.downcast_ref::<FaultError>())
+            .map(|e| &e.provenance)
+    }
+}
+
+/// Generator for fault identifiers, shared by all connections in a network.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FaultIds {
+    next: sync::Arc<atomic::AtomicU64>,
+}
+
+impl FaultIds {
+    pub(crate) fn next(&self) -> FaultId {
+        FaultId(self.next.fetch_add(1, atomic::Ordering::SeqCst))
+    }
+}
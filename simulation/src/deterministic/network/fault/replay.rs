@@ -0,0 +1,83 @@
+//! Fault injector which re-delivers previously transmitted segments of a connection's byte
+//! stream, emulating middlebox and proxy bugs.
+use super::{Connection, ConnectionSide, FaultAction, Inner};
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
+use std::{ops, sync, time};
+use tracing::trace;
+
+pub struct ReplayFaultInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    random_handle: DeterministicRandomHandle,
+    time_handle: DeterministicTimeHandle,
+    replay_len_range: ops::Range<usize>,
+    probability: f64,
+}
+
+impl ReplayFaultInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        random_handle: DeterministicRandomHandle,
+        time_handle: DeterministicTimeHandle,
+    ) -> Self {
+        Self {
+            inner,
+            random_handle,
+            time_handle,
+            replay_len_range: 1..1024,
+            probability: 0.05,
+        }
+    }
+
+    /// Sets the probability that a segment is replayed on a connection each second.
+    pub fn probability(mut self, probability: f64) -> Self {
+        self.probability = probability;
+        self
+    }
+
+    /// Sets the range of segment lengths which will be replayed.
+    pub fn replay_len_range(mut self, range: ops::Range<usize>) -> Self {
+        self.replay_len_range = range;
+        self
+    }
+
+    /// Consumes this fault injector and begins replaying segments on randomly selected connections.
+    pub async fn run(self) {
+        loop {
+            self.time_handle
+                .delay_from(time::Duration::from_secs(1))
+                .await;
+            if self.random_handle.should_fault(self.probability) {
+                self.replay_segment();
+            }
+        }
+    }
+
+    /// Pick a random connection and side, replaying a random length of previously read bytes.
+    fn replay_segment(&self) {
+        let mut lock = self.inner.lock().unwrap();
+        // Dropped connections lingering in TIME_WAIT have no reader left to replay to.
+        let live: Vec<&Connection> = lock
+            .connections
+            .iter()
+            .filter(|c| !c.is_dropped())
+            .collect();
+        if live.is_empty() {
+            return;
+        }
+        let connection = live[self.random_handle.gen_range(0..live.len())];
+        let len = self.random_handle.gen_range(self.replay_len_range.clone());
+        let side = if self.random_handle.should_fault(0.5) {
+            ConnectionSide::Client
+        } else {
+            ConnectionSide::Server
+        };
+        trace!(
+            "replaying up to {} bytes on {} -> {}",
+            len,
+            connection.source(),
+            connection.dest()
+        );
+        let target = connection.target(side);
+        lock.apply_fault(FaultAction::Replay { target, len });
+    }
+}
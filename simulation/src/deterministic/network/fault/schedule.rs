@@ -0,0 +1,374 @@
+//! Fault schedules record the faults applied during a run, independent of task scheduling.
+//!
+//! A schedule recorded from one run can be re-applied to another run with
+//! [`FaultScheduleInjector`], subjecting modified code to the same sequence of faults even when
+//! its internal scheduling differs. Faults target connections by the host which established
+//! them, the listener they were established to and their position among the connections from
+//! that host to that listener, rather than by the ephemeral port of the client, so replayed
+//! faults are applied to the equivalent connection of the new run even when other connections
+//! were established before it. Replayed faults which find no equivalent connection are reported
+//! with a [`SimulationEvent::FaultTargetMissing`] rather than applied.
+//!
+//! [`FaultScheduleInjector`]:`FaultScheduleInjector`
+//! [`SimulationEvent::FaultTargetMissing`]:`crate::deterministic::SimulationEvent::FaultTargetMissing`
+use super::socket::InjectionPoint;
+use super::{FaultKind, Inner};
+use crate::deterministic::{DeterministicTimeHandle, PartitionMode};
+use bytes::Bytes;
+use std::{net, slice, sync, time};
+
+/// Side of a connection targeted by a fault.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ConnectionSide {
+    Client,
+    Server,
+}
+
+/// Connection side targeted by a fault, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FaultTarget {
+    source: net::IpAddr,
+    dest: net::SocketAddr,
+    ordinal: usize,
+    side: ConnectionSide,
+}
+
+impl FaultTarget {
+    pub fn new(
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+        ordinal: usize,
+        side: ConnectionSide,
+    ) -> Self {
+        Self {
+            source,
+            dest,
+            ordinal,
+            side,
+        }
+    }
+
+    /// Returns the address of the host which established the connection.
+    pub fn source(&self) -> net::IpAddr {
+        self.source
+    }
+
+    /// Returns the address of the listener the connection was established to.
+    pub fn dest(&self) -> net::SocketAddr {
+        self.dest
+    }
+
+    /// Returns the number of connections the host established to the listener before the
+    /// targeted connection.
+    pub fn ordinal(&self) -> usize {
+        self.ordinal
+    }
+
+    pub fn side(&self) -> ConnectionSide {
+        self.side
+    }
+
+    /// Returns the target of the provided side of the same connection.
+    pub(crate) fn with_side(self, side: ConnectionSide) -> Self {
+        Self { side, ..self }
+    }
+}
+
+/// A fault applied to the network.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FaultAction {
+    /// Set the send and receive latency of a connection.
+    Latency {
+        target: FaultTarget,
+        send: time::Duration,
+        receive: time::Duration,
+    },
+    /// Inject bytes into a connection.
+    Inject {
+        target: FaultTarget,
+        bytes: Bytes,
+        point: InjectionPoint,
+    },
+    /// Replay previously read bytes on a connection.
+    Replay { target: FaultTarget, len: usize },
+    /// Disconnect a connection.
+    Disconnect { target: FaultTarget },
+    /// Arm or disarm connection crossing.
+    Crossing { window: Option<time::Duration> },
+    /// Partition the hosts of `a` from the hosts of `b`.
+    Partition {
+        a: Vec<net::IpAddr>,
+        b: Vec<net::IpAddr>,
+        mode: PartitionMode,
+    },
+    /// Heal the partitions between the hosts of `a` and the hosts of `b`.
+    Heal {
+        a: Vec<net::IpAddr>,
+        b: Vec<net::IpAddr>,
+    },
+    /// Reset every connection to or from a host.
+    ResetHost { host: net::IpAddr },
+    /// Set the latency of the data written by a host on each of its connections.
+    SendLatency {
+        host: net::IpAddr,
+        latency: time::Duration,
+    },
+}
+
+impl FaultAction {
+    /// Returns the connection targeted by the action, if it targets a single connection.
+    pub fn target(&self) -> Option<&FaultTarget> {
+        match self {
+            FaultAction::Latency { target, .. }
+            | FaultAction::Inject { target, .. }
+            | FaultAction::Replay { target, .. }
+            | FaultAction::Disconnect { target } => Some(target),
+            _ => None,
+        }
+    }
+
+    /// Returns the kind of fault the action is budgeted as, see [`FaultBudget`]. Actions which
+    /// revert a fault, such as healing a partition, are not budgeted.
+    ///
+    /// [`FaultBudget`]:`super::FaultBudget`
+    pub fn kind(&self) -> Option<FaultKind> {
+        match self {
+            FaultAction::Latency { .. } => Some(FaultKind::Latency),
+            FaultAction::Inject { .. } => Some(FaultKind::ByteInjection),
+            FaultAction::Replay { .. } => Some(FaultKind::Replay),
+            FaultAction::Disconnect { .. } => Some(FaultKind::Disconnect),
+            FaultAction::Crossing { window: Some(_) } => Some(FaultKind::Crossing),
+            FaultAction::Partition { .. } => Some(FaultKind::Partition),
+            FaultAction::ResetHost { .. } => Some(FaultKind::HostKill),
+            FaultAction::SendLatency { latency, .. }
+                if *latency > time::Duration::from_millis(0) =>
+            {
+                Some(FaultKind::Latency)
+            }
+            FaultAction::Crossing { window: None }
+            | FaultAction::Heal { .. }
+            | FaultAction::SendLatency { .. } => None,
+        }
+    }
+}
+
+/// A fault along with the offset from the start of the run at which it was applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledFault {
+    at: time::Duration,
+    action: FaultAction,
+}
+
+impl ScheduledFault {
+    pub fn new(at: time::Duration, action: FaultAction) -> Self {
+        Self { at, action }
+    }
+
+    /// Returns the offset from the start of the run at which the fault is applied.
+    pub fn at(&self) -> time::Duration {
+        self.at
+    }
+
+    pub fn action(&self) -> &FaultAction {
+        &self.action
+    }
+}
+
+/// Ordered sequence of faults applied during a run.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultSchedule {
+    faults: Vec<ScheduledFault>,
+}
+
+impl FaultSchedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends a fault to the schedule. Faults must be pushed in the order they are applied.
+    pub fn push(&mut self, fault: ScheduledFault) {
+        debug_assert!(self.faults.last().map_or(true, |last| last.at <= fault.at));
+        self.faults.push(fault);
+    }
+
+    pub fn iter(&self) -> slice::Iter<'_, ScheduledFault> {
+        self.faults.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.faults.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faults.is_empty()
+    }
+}
+
+impl From<Vec<ScheduledFault>> for FaultSchedule {
+    fn from(mut faults: Vec<ScheduledFault>) -> Self {
+        faults.sort_by_key(|fault| fault.at);
+        Self { faults }
+    }
+}
+
+/// Records the faults applied to a network.
+#[derive(Debug)]
+pub(crate) struct FaultRecorder {
+    started_at: time::Instant,
+    schedule: FaultSchedule,
+}
+
+impl FaultRecorder {
+    pub(crate) fn new(started_at: time::Instant) -> Self {
+        Self {
+            started_at,
+            schedule: FaultSchedule::new(),
+        }
+    }
+
+    pub(crate) fn record(&mut self, now: time::Instant, action: FaultAction) {
+        let at = now - self.started_at;
+        self.schedule.push(ScheduledFault::new(at, action));
+    }
+
+    pub(crate) fn schedule(&self) -> FaultSchedule {
+        self.schedule.clone()
+    }
+}
+
+/// Fault injector which re-applies a recorded fault schedule.
+pub struct FaultScheduleInjector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    time_handle: DeterministicTimeHandle,
+    schedule: FaultSchedule,
+}
+
+impl FaultScheduleInjector {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        time_handle: DeterministicTimeHandle,
+        schedule: FaultSchedule,
+    ) -> Self {
+        Self {
+            inner,
+            time_handle,
+            schedule,
+        }
+    }
+
+    /// Consumes this fault injector and applies each fault in the schedule at its recorded
+    /// offset from the time the injector is started.
+    pub async fn run(self) {
+        let start = self.time_handle.now();
+        for fault in self.schedule.faults {
+            self.time_handle.delay(start + fault.at).await;
+            self.inner.lock().unwrap().apply_fault(fault.action);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{Breakpoint, DeterministicRuntime, SimulationEvent};
+    use crate::{Environment, TcpListener};
+    use tokio::io::AsyncReadExt;
+
+    /// Connects to a listener, first connecting to another listener if `extra` is set, and
+    /// disconnects the connection to the first listener unless a schedule is replayed. Returns
+    /// the recorded schedule, whether each connection was disconnected and the number of faults
+    /// which found no target.
+    fn run(schedule: Option<FaultSchedule>, extra: bool) -> (FaultSchedule, Vec<bool>, usize) {
+        let secs = time::Duration::from_secs;
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.record_faults();
+        let missing = sync::Arc::new(sync::Mutex::new(0));
+        let counter = sync::Arc::clone(&missing);
+        let target_missing = Breakpoint::on(|event| match event {
+            SimulationEvent::FaultTargetMissing(_) => true,
+            _ => false,
+        });
+        runtime.add_breakpoint(target_missing, move |_| *counter.lock().unwrap() += 1);
+        let replaying = schedule.is_some();
+        if let Some(schedule) = schedule {
+            handle.spawn(runtime.fault_schedule_injector(schedule).run());
+        }
+        let disconnected = runtime.block_on(async {
+            let addrs: Vec<net::SocketAddr> = vec![
+                "127.0.0.1:9092".parse().unwrap(),
+                "127.0.0.1:9093".parse().unwrap(),
+            ];
+            for addr in addrs.iter() {
+                let mut listener = handle.bind(*addr).await.unwrap();
+                handle.spawn(async move {
+                    let mut accepted = vec![];
+                    while let Ok((socket, _)) = listener.accept().await {
+                        accepted.push(socket);
+                    }
+                });
+            }
+            let mut sockets = vec![];
+            if extra {
+                sockets.push(handle.connect(addrs[1]).await.unwrap());
+            }
+            sockets.push(handle.connect(addrs[0]).await.unwrap());
+            if !replaying {
+                let info = handle
+                    .connections()
+                    .into_iter()
+                    .find(|c| c.dest() == addrs[0])
+                    .unwrap();
+                handle.delay_from(secs(1)).await;
+                handle.network_handle.apply_fault(FaultAction::Disconnect {
+                    target: info.target(ConnectionSide::Client),
+                });
+            }
+            let mut disconnected = vec![];
+            for socket in sockets.iter_mut() {
+                let read = handle.timeout(socket.read(&mut [0u8; 1]), secs(10)).await;
+                disconnected.push(match read {
+                    Ok(Err(_)) => true,
+                    _ => false,
+                });
+            }
+            disconnected
+        });
+        let missing = *missing.lock().unwrap();
+        (runtime.fault_schedule(), disconnected, missing)
+    }
+
+    #[test]
+    /// Test that a replayed fault targets the equivalent connection of a run which established
+    /// another connection first, and that faults without an equivalent connection are reported.
+    fn replay_shifted_connections() {
+        let (recorded, disconnected, _) = run(None, false);
+        assert_eq!(disconnected, vec![true]);
+        assert_eq!(recorded.len(), 1);
+
+        let (replayed, disconnected, missing) = run(Some(recorded.clone()), true);
+        assert_eq!(
+            disconnected,
+            vec![false, true],
+            "expected the fault to follow the equivalent connection"
+        );
+        assert_eq!(replayed, recorded);
+        assert_eq!(missing, 0);
+
+        let target = match recorded.iter().next().unwrap().action() {
+            FaultAction::Disconnect { target } => *target,
+            action => panic!("unexpected fault {:?}", action),
+        };
+        let absent = FaultTarget::new(target.source(), target.dest(), 1, target.side());
+        let schedule = FaultSchedule::from(vec![ScheduledFault::new(
+            time::Duration::from_secs(1),
+            FaultAction::Disconnect { target: absent },
+        )]);
+        let (replayed, disconnected, missing) = run(Some(schedule), false);
+        assert_eq!(disconnected, vec![false]);
+        assert!(replayed.is_empty());
+        assert_eq!(missing, 1, "expected the missing target to be reported");
+    }
+}
@@ -0,0 +1,460 @@
+//! Registry of the hosts participating in the simulated network.
+use std::{collections, net};
+
+/// First address allocated to hosts added while the simulation is running.
+const FIRST_ALLOCATED: u32 = 0x0a00_0001;
+
+/// Domain of hosts which have not joined any network domain.
+pub(crate) const DEFAULT_DOMAIN: &str = "default";
+
+/// First segment of the IPv6 addresses of hosts with IPv4 addresses, which carry the IPv4
+/// address in their low 32 bits.
+const V6_PREFIX: u16 = 0xfd00;
+
+/// Returns the address of the other family at which the host with the provided address is
+/// reachable. Hosts with IPv4 addresses are dual-stack: `127.0.0.1` is reachable at `::1`, and
+/// other addresses at `fd00::` followed by the IPv4 address. Hosts registered with other IPv6
+/// addresses only have IPv6 addresses.
+pub(crate) fn counterpart(addr: net::IpAddr) -> Option<net::IpAddr> {
+    match addr {
+        net::IpAddr::V4(v4) if v4.is_loopback() => Some(net::Ipv6Addr::LOCALHOST.into()),
+        net::IpAddr::V4(v4) => {
+            let [a, b, c, d] = v4.octets();
+            let (high, low) = (u16::from_be_bytes([a, b]), u16::from_be_bytes([c, d]));
+            Some(net::Ipv6Addr::new(V6_PREFIX, 0, 0, 0, 0, 0, high, low).into())
+        }
+        net::IpAddr::V6(v6) if v6.is_loopback() => Some(net::Ipv4Addr::LOCALHOST.into()),
+        net::IpAddr::V6(v6) => match v6.segments() {
+            [V6_PREFIX, 0, 0, 0, 0, 0, high, low] => {
+                let ([a, b], [c, d]) = (high.to_be_bytes(), low.to_be_bytes());
+                Some(net::Ipv4Addr::new(a, b, c, d).into())
+            }
+            _ => None,
+        },
+    }
+}
+
+/// Returns the address of the host registered as `host` in the family of `family`, if the host
+/// has an address in that family.
+pub(crate) fn address_in(host: net::IpAddr, family: net::IpAddr) -> Option<net::IpAddr> {
+    if host.is_ipv4() == family.is_ipv4() {
+        Some(host)
+    } else {
+        counterpart(host)
+    }
+}
+
+/// Returns the IPv4 address an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) maps, or the
+/// provided address if it is not IPv4-mapped.
+pub(crate) fn unmapped(addr: net::SocketAddr) -> net::SocketAddr {
+    match addr.ip() {
+        net::IpAddr::V6(v6) => match v6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => {
+                let ([a, b], [c, d]) = (high.to_be_bytes(), low.to_be_bytes());
+                net::SocketAddr::new(net::Ipv4Addr::new(a, b, c, d).into(), addr.port())
+            }
+            _ => addr,
+        },
+        net::IpAddr::V4(_) => addr,
+    }
+}
+
+/// Returns the IPv4-mapped IPv6 form of an IPv4 address, as reported by dual-stack listeners
+/// for connections over IPv4.
+pub(crate) fn mapped(addr: net::SocketAddr) -> net::SocketAddr {
+    match addr.ip() {
+        net::IpAddr::V4(v4) => net::SocketAddr::new(v4.to_ipv6_mapped().into(), addr.port()),
+        net::IpAddr::V6(_) => addr,
+    }
+}
+
+/// Behavior of established connections when their host migrates to a new address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MigrationPolicy {
+    /// Connections move to the new address without interruption. Both streams report the new
+    /// address from then on.
+    Transparent,
+    /// Connections are reset, failing further reads and writes with `ConnectionReset`.
+    Reset,
+}
+
+#[derive(Debug)]
+pub(crate) struct Hosts {
+    /// Next candidate address for allocation, as a 10.0.0.0/8 IPv4 address.
+    next: u32,
+    active: collections::BTreeSet<net::IpAddr>,
+    /// Hosts which have been removed. Removed addresses are not reallocated.
+    removed: collections::HashSet<net::IpAddr>,
+    /// Names of hosts which have been registered by name.
+    names: collections::HashMap<String, net::IpAddr>,
+    /// Addresses names resolved to before they were last re-registered, migrated or removed.
+    stale: collections::HashMap<String, net::IpAddr>,
+    /// Network domains hosts have joined. Hosts can only connect to hosts which share a domain,
+    /// hosts which have joined several domains act as gateways between them.
+    domains: collections::HashMap<net::IpAddr, collections::BTreeSet<String>>,
+    /// Addresses hosts have migrated away from, mapped to the address they migrated to.
+    migrated: collections::HashMap<net::IpAddr, net::IpAddr>,
+    /// Hosts which refuse new connections while their existing connections drain.
+    draining: collections::HashSet<net::IpAddr>,
+}
+
+impl Default for Hosts {
+    fn default() -> Self {
+        Self {
+            next: FIRST_ALLOCATED,
+            active: collections::BTreeSet::new(),
+            removed: collections::HashSet::new(),
+            names: collections::HashMap::new(),
+            stale: collections::HashMap::new(),
+            domains: collections::HashMap::new(),
+            migrated: collections::HashMap::new(),
+            draining: collections::HashSet::new(),
+        }
+    }
+}
+
+impl Hosts {
+    /// Registers a host with a caller provided address, reinstating it if it was removed.
+    pub(crate) fn register(&mut self, addr: net::IpAddr) {
+        self.removed.remove(&addr);
+        self.active.insert(addr);
+    }
+
+    /// Allocates an unused address for a new host.
+    pub(crate) fn allocate(&mut self) -> net::IpAddr {
+        loop {
+            let addr = net::IpAddr::V4(net::Ipv4Addr::from(self.next));
+            self.next += 1;
+            assert!(self.next < 0x0b00_0000, "exhausted host addresses");
+            if !self.active.contains(&addr) && !self.removed.contains(&addr) {
+                self.active.insert(addr);
+                return addr;
+            }
+        }
+    }
+
+    /// Removes a host, returning true if it was registered.
+    pub(crate) fn remove(&mut self, addr: net::IpAddr) -> bool {
+        if self.active.remove(&addr) {
+            self.removed.insert(addr);
+            let stale = &mut self.stale;
+            self.names.retain(|name, named| {
+                if *named == addr {
+                    stale.insert(name.clone(), addr);
+                }
+                *named != addr
+            });
+            self.domains.remove(&addr);
+            self.draining.remove(&addr);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Moves an active host to the address `to`, carrying over its names and domains. The old
+    /// address is retired, and is no longer reachable. Returns false if `from` is not active or
+    /// `to` is already in use.
+    pub(crate) fn migrate(&mut self, from: net::IpAddr, to: net::IpAddr) -> bool {
+        if !self.active.contains(&from) || self.active.contains(&to) {
+            return false;
+        }
+        self.active.remove(&from);
+        self.removed.insert(from);
+        self.removed.remove(&to);
+        self.active.insert(to);
+        for (name, addr) in self.names.iter_mut().filter(|(_, addr)| **addr == from) {
+            self.stale.insert(name.clone(), from);
+            *addr = to;
+        }
+        if let Some(domains) = self.domains.remove(&from) {
+            self.domains.insert(to, domains);
+        }
+        for current in self.migrated.values_mut().filter(|addr| **addr == from) {
+            *current = to;
+        }
+        self.migrated.insert(from, to);
+        true
+    }
+
+    /// Marks an active host as draining, returning false if the host is not active.
+    pub(crate) fn drain(&mut self, addr: net::IpAddr) -> bool {
+        if !self.active.contains(&addr) {
+            return false;
+        }
+        self.draining.insert(addr);
+        true
+    }
+
+    pub(crate) fn is_draining(&self, addr: net::IpAddr) -> bool {
+        self.draining.contains(&addr)
+    }
+
+    /// Returns the active hosts which are members of the provided domain, in address order.
+    pub(crate) fn members(&self, domain: &str) -> Vec<net::IpAddr> {
+        self.active
+            .iter()
+            .filter(|addr| self.domains(**addr).iter().any(|d| d == domain))
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the address the host reachable at `addr` was registered with, which differs from
+    /// `addr` when it is the address of the host in its other family.
+    pub(crate) fn host_of(&self, addr: net::IpAddr) -> net::IpAddr {
+        let known = |addr: &net::IpAddr| self.active.contains(addr) || self.removed.contains(addr);
+        if known(&addr) {
+            return addr;
+        }
+        counterpart(addr).filter(known).unwrap_or(addr)
+    }
+
+    /// Returns the address a host registered as `addr` currently uses, following migrations.
+    pub(crate) fn current(&self, addr: net::IpAddr) -> net::IpAddr {
+        self.migrated.get(&addr).cloned().unwrap_or(addr)
+    }
+
+    /// Associates a name with an active host, replacing any host previously registered with
+    /// the same name.
+    pub(crate) fn name(&mut self, name: String, addr: net::IpAddr) {
+        if let Some(previous) = self.names.insert(name.clone(), addr) {
+            if previous != addr {
+                self.stale.insert(name, previous);
+            }
+        }
+    }
+
+    /// Returns the address of the host registered with the provided name.
+    pub(crate) fn resolve(&self, name: &str) -> Option<net::IpAddr> {
+        self.names.get(name).cloned()
+    }
+
+    /// Returns the address the provided name resolved to before it was last re-registered,
+    /// migrated or removed.
+    pub(crate) fn resolve_stale(&self, name: &str) -> Option<net::IpAddr> {
+        self.stale.get(name).cloned()
+    }
+
+    /// Adds a host to the provided network domain.
+    pub(crate) fn join(&mut self, addr: net::IpAddr, domain: String) {
+        self.domains.entry(addr).or_default().insert(domain);
+    }
+
+    /// Removes a host from the provided network domain.
+    pub(crate) fn leave(&mut self, addr: net::IpAddr, domain: &str) {
+        if let Some(domains) = self.domains.get_mut(&addr) {
+            domains.remove(domain);
+            if domains.is_empty() {
+                self.domains.remove(&addr);
+            }
+        }
+    }
+
+    /// Returns the network domains of the provided host.
+    pub(crate) fn domains(&self, addr: net::IpAddr) -> Vec<String> {
+        match self.domains.get(&addr) {
+            Some(domains) => domains.iter().cloned().collect(),
+            None => vec![String::from(DEFAULT_DOMAIN)],
+        }
+    }
+
+    /// Returns true if `source` shares a network domain with `dest`.
+    pub(crate) fn is_reachable(&self, source: net::IpAddr, dest: net::IpAddr) -> bool {
+        if source == dest {
+            return true;
+        }
+        let dest_domains = self.domains(dest);
+        self.domains(source)
+            .iter()
+            .any(|domain| dest_domains.contains(domain))
+    }
+
+    pub(crate) fn is_removed(&self, addr: net::IpAddr) -> bool {
+        self.removed.contains(&addr)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = net::IpAddr> + '_ {
+        self.active.iter().cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, MigrationPolicy, NetworkProfile};
+    use crate::{Environment, TcpListener, TcpStream};
+    use std::{io, net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that hosts in separate network domains can only communicate through a gateway which
+    /// has joined both domains.
+    fn network_domains() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let backend = handle.add_host_in("internal");
+            let client = handle.add_host_in("public");
+            let gateway = handle.add_host_in("internal");
+            gateway.join_domain("public");
+            assert_eq!(gateway.domains(), vec!["internal", "public"]);
+            assert_eq!(handle.domains(), vec!["default"]);
+
+            let backend_addr = net::SocketAddr::new(backend.local_addr(), 8080);
+            let mut listener = backend.bind(backend_addr).await.unwrap();
+            backend.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    socket.write_all(b"internal").await.unwrap();
+                }
+            });
+
+            // The gateway proxies a single connection from the public domain to the backend.
+            let gateway_addr = net::SocketAddr::new(gateway.local_addr(), 80);
+            let mut listener = gateway.bind(gateway_addr).await.unwrap();
+            let proxy = gateway.clone();
+            gateway.spawn(async move {
+                let (mut public, _) = listener.accept().await.unwrap();
+                let mut internal = proxy.connect(backend_addr).await.unwrap();
+                let mut buf = [0u8; 8];
+                internal.read_exact(&mut buf).await.unwrap();
+                public.write_all(&buf).await.unwrap();
+            });
+
+            let err = client.connect(backend_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert!(handle.connect(backend_addr).await.is_err());
+
+            let mut socket = client.connect(gateway_addr).await.unwrap();
+            let mut buf = [0u8; 8];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"internal");
+        });
+    }
+
+    #[test]
+    /// Test that hosts added while running are allocated distinct addresses and can connect to
+    /// each other.
+    fn scale_out() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server = handle.add_host();
+            let client = handle.add_host();
+            assert_ne!(server.local_addr(), client.local_addr());
+            assert_eq!(
+                handle.hosts(),
+                vec![
+                    server.local_addr(),
+                    client.local_addr(),
+                    handle.local_addr()
+                ]
+            );
+
+            client.set_link_profile(
+                server.local_addr(),
+                Some(NetworkProfile::new(time::Duration::from_secs(1))),
+            );
+            let addr = net::SocketAddr::new(server.local_addr(), 9092);
+            let mut listener = server.bind(addr).await.unwrap();
+            server.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(b"hi").await.unwrap();
+                socket.write_all(b"!").await.unwrap();
+            });
+            let start = handle.now();
+            let mut socket = client.connect(addr).await.unwrap();
+            let mut buf = [0u8; 3];
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hi!");
+            assert!(handle.now() - start >= time::Duration::from_secs(1));
+        });
+    }
+
+    #[test]
+    /// Test that removing a host disconnects its connections, closes its listeners and refuses
+    /// new connections to it.
+    fn decommission() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server = handle.add_host();
+            let client = handle.add_host();
+            let addr = net::SocketAddr::new(server.local_addr(), 9092);
+            let mut listener = server.bind(addr).await.unwrap();
+            let mut socket = client.connect(addr).await.unwrap();
+            let (_accepted, _) = listener.accept().await.unwrap();
+
+            assert!(handle.remove_host(server.local_addr()));
+            assert!(!handle.remove_host(server.local_addr()));
+            assert!(!handle.hosts().contains(&server.local_addr()));
+
+            let mut buf = [0u8; 1];
+            assert!(socket.read(&mut buf).await.is_err());
+            assert!(listener.accept().await.is_err());
+            let err = client.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let err = server.bind(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+
+            let replacement = handle.add_host();
+            assert_ne!(replacement.local_addr(), server.local_addr());
+        });
+    }
+
+    #[test]
+    /// Test that connections follow a migrating host or are reset according to the migration
+    /// policy, and that its listeners move to the new address.
+    fn migration() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server = handle.add_host();
+            let client = handle.add_host();
+            let old_addr = net::SocketAddr::new(server.local_addr(), 9092);
+            let mut listener = server.bind(old_addr).await.unwrap();
+            let echo = server.clone();
+            server.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    echo.spawn(async move {
+                        let mut buf = [0u8; 1];
+                        while socket.read_exact(&mut buf).await.is_ok() {
+                            if socket.write_all(&buf).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+            let mut socket = client.connect(old_addr).await.unwrap();
+            let mut buf = [0u8; 1];
+            socket.write_all(b"a").await.unwrap();
+            socket.read_exact(&mut buf).await.unwrap();
+
+            let vip: net::IpAddr = "10.0.1.1".parse().unwrap();
+            assert!(handle.migrate_host(server.local_addr(), vip, MigrationPolicy::Transparent));
+            assert_eq!(server.current_addr(), vip);
+            let new_addr = net::SocketAddr::new(vip, 9092);
+            socket.write_all(b"b").await.unwrap();
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"b");
+            assert_eq!(socket.peer_addr().unwrap(), new_addr);
+            let err = client.connect(old_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let mut reconnected = client.connect(new_addr).await.unwrap();
+
+            let failover: net::IpAddr = "10.0.1.2".parse().unwrap();
+            assert!(!handle.migrate_host(vip, client.local_addr(), MigrationPolicy::Reset));
+            assert!(handle.migrate_host(vip, failover, MigrationPolicy::Reset));
+            let err = socket.write_all(b"c").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            let err = reconnected.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            let mut socket = client
+                .connect(net::SocketAddr::new(failover, 9092))
+                .await
+                .unwrap();
+            socket.write_all(b"d").await.unwrap();
+            socket.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"d");
+        });
+    }
+}
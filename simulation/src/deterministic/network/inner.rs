@@ -1,143 +1,1464 @@
-use super::fault::{CloggedConnection, Connection};
-use super::{socket, FaultyTcpStream, Listener, ListenerState, SocketHalf};
-use futures::{channel::mpsc, Future, SinkExt};
+use super::bandwidth::Throttle;
+use super::connect_limit::ConnectLimit;
+use super::fault::{
+    CloggedConnection, Connection, ConnectionId, ConnectionInfo, ConnectionSide, CrossingSlot,
+    Delivery, FaultAction, FaultBudgetHandle, FaultIds, FaultKind, FaultPermit, FaultProvenance,
+    FaultRecorder, FaultSchedule, FaultTarget, PendingCrossing,
+};
+use super::hosts::{self, Hosts, MigrationPolicy};
+use super::link::LinkFaults;
+use super::partition::{PartitionMode, Partitions};
+use super::ports::{ConnectOptions, EphemeralPorts};
+use super::profile::LinkConditions;
+use super::resources::{HostResources, ResourceLimits, ResourceUsage};
+use super::socket::ConnectionIdExt;
+use super::table::ConnectionTable;
+use super::tap::StreamTap;
+use super::udp::{Datagram, DatagramFaults, Datagrams};
+use super::unconsumed::CloseMonitor;
+use super::{
+    socket, AcceptFaultState, AcceptFaults, Accepted, FaultyTcpStream, Incoming, LimitPolicy,
+    Listener, ListenerInfo, ListenerOptions, ListenerState, SegmentFaults, SocketBuffers,
+    SocketHalf,
+};
+use crate::deterministic::{
+    events::SimulationEvent,
+    task::{self, WaitResource},
+    watermark::Watermarks,
+};
+use bytes::Bytes;
+use futures::{
+    channel::{mpsc, oneshot},
+    task::Waker,
+    Future, Poll, SinkExt,
+};
 use std::{
     collections::{self, hash_map::Entry},
     io, net,
+    sync::{self, atomic},
+    task::Context,
+    time,
 };
-use tracing::trace;
+use tokio::timer::Delay;
+use tracing::{trace, warn};
 
 #[derive(Debug)]
 pub(crate) struct Inner {
     handle: crate::deterministic::DeterministicTimeHandle,
-    pub(crate) connections: Vec<Connection>,
+    pub(crate) connections: ConnectionTable,
     clogged: collections::HashSet<CloggedConnection>,
     endpoints: collections::HashMap<net::SocketAddr, ListenerState>,
+    /// Duration for which closed connections keep their (source, dest) pair occupied,
+    /// modeling the TCP TIME_WAIT state.
+    time_wait: Option<time::Duration>,
+    /// Buffer sizes of new connections, which buffer writes without limit when unset.
+    socket_buffers: Option<SocketBuffers>,
+    /// Segment faults applied to the bytes received by each half of every connection.
+    segment_faults: Option<(
+        SegmentFaults,
+        crate::deterministic::DeterministicRandomHandle,
+    )>,
+    /// When set, connections to addresses without a bound listener are refused once the grace
+    /// period elapses, rather than waiting for a listener indefinitely.
+    unbound_grace: Option<time::Duration>,
+    /// Wakers of connects waiting for a listener to be bound to the provided address.
+    bind_wakers: collections::HashMap<net::SocketAddr, Vec<Waker>>,
+    /// When set, connections to the same listener established within the window are crossed.
+    crossing_window: Option<time::Duration>,
+    crossings: collections::HashMap<net::SocketAddr, CrossingSlot>,
+    fault_ids: FaultIds,
+    next_connection_id: u64,
+    /// Number of connections each host established to each listener address, identifying the
+    /// connections of a pair in the order they were established.
+    connection_ordinals: collections::HashMap<(net::IpAddr, net::SocketAddr), usize>,
+    next_listener_id: u64,
+    /// Addresses of listeners bound to port 0, whose ports are returned to the allocator of
+    /// their host once the listener is dropped.
+    ephemeral_listeners: collections::HashMap<u64, net::SocketAddr>,
+    /// Taps attached to new connections to the provided listener address.
+    taps: Vec<(net::SocketAddr, sync::Arc<dyn StreamTap>)>,
+    pub(crate) budget: FaultBudgetHandle,
+    /// Permits of the applied faults which stay active until they are reverted.
+    held_faults: Vec<(HeldFault, FaultPermit)>,
+    /// Cap on connects in progress across the network.
+    pub(crate) connect_limit: ConnectLimit,
+    recorder: Option<FaultRecorder>,
+    /// Conditions applied to connections which do not have link specific conditions.
+    conditions: Option<LinkConditions>,
+    /// Conditions applied to connections between pairs of hosts, keyed by the ordered pair.
+    link_conditions: collections::HashMap<(net::IpAddr, net::IpAddr), LinkConditions>,
+    /// Conditions applied to data sent from the first host of the pair to the second, taking
+    /// precedence over the conditions of the link in either direction.
+    directed_conditions: collections::HashMap<(net::IpAddr, net::IpAddr), LinkConditions>,
+    /// Conditions applied to all connections to or from a host, unless link specific
+    /// conditions exist.
+    host_conditions: collections::HashMap<net::IpAddr, LinkConditions>,
+    /// Bandwidth shared by the connections sending from the first host of the pair to the
+    /// second.
+    shared_links: collections::HashMap<(net::IpAddr, net::IpAddr), sync::Arc<Throttle>>,
+    /// Faults of the data sent from the first host of the pair to the second.
+    link_faults: collections::HashMap<(net::IpAddr, net::IpAddr), LinkFaults>,
+    hosts: Hosts,
+    /// Connections and memory in use by each host, keyed by the address it was registered with.
+    resources: collections::HashMap<net::IpAddr, HostResources>,
+    /// Faults of the accepts of the listeners of each host, keyed by the address it was
+    /// registered with.
+    accept_faults: collections::HashMap<net::IpAddr, AcceptFaultState>,
+    pub(crate) ports: EphemeralPorts,
+    datagrams: Datagrams,
+    partitions: Partitions,
+    pub(crate) close_monitor: CloseMonitor,
+    pub(crate) watermarks: Watermarks,
+}
+
+/// A fault which stays active, counting against the budget, until it is reverted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum HeldFault {
+    /// Partitioned pairs of hosts, held until every pair heals.
+    Partition(Vec<(net::IpAddr, net::IpAddr)>),
+    /// Armed connection crossing, held until disarmed.
+    Crossing,
+    /// Send latency of a host, held until reset to zero.
+    SendLatency(net::IpAddr),
+}
+
+/// Returns the address pairs connections between two hosts use, over IPv4 and over IPv6.
+fn family_pairs(a: net::IpAddr, b: net::IpAddr) -> Vec<(net::IpAddr, net::IpAddr)> {
+    let mut pairs = vec![(a, b)];
+    if let (Some(a), Some(b)) = (hosts::counterpart(a), hosts::counterpart(b)) {
+        pairs.push((a, b));
+    }
+    pairs
+}
+
+/// Returns the key identifying the link between two hosts, irrespective of direction.
+fn link_key(a: net::IpAddr, b: net::IpAddr) -> (net::IpAddr, net::IpAddr) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
 }
 
 impl Inner {
     pub(crate) fn new(handle: crate::deterministic::DeterministicTimeHandle) -> Self {
+        let close_monitor = CloseMonitor::new(handle.events().clone());
+        let connect_limit = ConnectLimit::new(handle.clone());
+        let watermarks = Watermarks::new(handle.clone());
         Inner {
             handle,
-            connections: vec![],
+            connections: ConnectionTable::default(),
             clogged: collections::HashSet::new(),
             endpoints: collections::HashMap::new(),
+            time_wait: None,
+            socket_buffers: None,
+            segment_faults: None,
+            unbound_grace: None,
+            bind_wakers: collections::HashMap::new(),
+            crossing_window: None,
+            crossings: collections::HashMap::new(),
+            fault_ids: FaultIds::default(),
+            next_connection_id: 0,
+            connection_ordinals: collections::HashMap::new(),
+            next_listener_id: 0,
+            ephemeral_listeners: collections::HashMap::new(),
+            taps: vec![],
+            budget: FaultBudgetHandle::default(),
+            held_faults: vec![],
+            connect_limit,
+            recorder: None,
+            conditions: None,
+            link_conditions: collections::HashMap::new(),
+            directed_conditions: collections::HashMap::new(),
+            shared_links: collections::HashMap::new(),
+            link_faults: collections::HashMap::new(),
+            host_conditions: collections::HashMap::new(),
+            hosts: Hosts::default(),
+            resources: collections::HashMap::new(),
+            accept_faults: collections::HashMap::new(),
+            ports: EphemeralPorts::default(),
+            datagrams: Datagrams::default(),
+            partitions: Partitions::default(),
+            close_monitor,
+            watermarks,
+        }
+    }
+
+    pub(crate) fn now(&self) -> time::Instant {
+        self.handle.now()
+    }
+
+    /// Begin recording applied faults, discarding any previously recorded faults.
+    pub(crate) fn record_faults(&mut self) {
+        self.recorder.replace(FaultRecorder::new(self.handle.now()));
+    }
+
+    /// Returns the faults recorded so far.
+    pub(crate) fn fault_schedule(&self) -> FaultSchedule {
+        self.recorder
+            .as_ref()
+            .map(FaultRecorder::schedule)
+            .unwrap_or_default()
+    }
+
+    /// Apply a fault to the network, recording it if recording is enabled. Every fault is
+    /// checked against the fault budget here, returning false without applying the fault if
+    /// the budget refuses it. Faults which stay active until reverted, such as partitions, hold
+    /// their permit until the reverting action is applied. Faults targeting connections which
+    /// do not exist are not applied, and are reported with a
+    /// [`SimulationEvent::FaultTargetMissing`] instead.
+    ///
+    /// [`SimulationEvent::FaultTargetMissing`]:`SimulationEvent::FaultTargetMissing`
+    pub(crate) fn apply_fault(&mut self, action: FaultAction) -> bool {
+        if let Some(target) = action.target() {
+            if self.target_fault_handle(target).is_none() {
+                warn!("no connection found for fault target {:?}", target);
+                self.handle
+                    .events()
+                    .emit(SimulationEvent::FaultTargetMissing(action));
+                return false;
+            }
+        }
+        let permit = match action.kind() {
+            Some(kind) => match self.budget.try_acquire(kind, self.now()) {
+                Some(permit) => Some(permit),
+                None => return false,
+            },
+            None => None,
+        };
+        if let Some(recorder) = self.recorder.as_mut() {
+            recorder.record(self.handle.now(), action.clone());
+        }
+        self.handle
+            .events()
+            .emit(SimulationEvent::FaultApplied(action.clone()));
+        match action {
+            FaultAction::Latency {
+                target,
+                send,
+                receive,
+            } => {
+                if let Some(handle) = self.target_fault_handle(&target) {
+                    handle.set_receive_latency(receive);
+                    handle.set_send_latency(send);
+                }
+            }
+            FaultAction::Inject {
+                target,
+                bytes,
+                point,
+            } => {
+                if let Some(handle) = self.target_fault_handle(&target) {
+                    handle.inject(bytes, point);
+                }
+            }
+            FaultAction::Replay { target, len } => {
+                if let Some(handle) = self.target_fault_handle(&target) {
+                    handle.replay(len);
+                }
+            }
+            FaultAction::Disconnect { target } => {
+                if let Some(handle) = self.target_fault_handle(&target) {
+                    handle.disconnect();
+                }
+            }
+            FaultAction::Crossing { window } => {
+                self.set_crossing_window(window);
+                self.release_held(|held| *held == HeldFault::Crossing);
+                if window.is_some() {
+                    self.hold(HeldFault::Crossing, permit);
+                }
+            }
+            FaultAction::Partition { a, b, mode } => self.partition_held(&a, &b, mode, permit),
+            FaultAction::Heal { a, b } => self.heal_between(&a, &b),
+            FaultAction::ResetHost { host } => {
+                for connection in self
+                    .connections
+                    .iter()
+                    .filter(|c| c.source().ip() == host || c.dest().ip() == host)
+                {
+                    connection.fault_handle(ConnectionSide::Client).reset_now();
+                }
+            }
+            FaultAction::SendLatency { host, latency } => {
+                self.release_held(|held| *held == HeldFault::SendLatency(host));
+                if latency > time::Duration::from_millis(0) {
+                    self.hold(HeldFault::SendLatency(host), permit);
+                }
+                for connection in self.connections.iter() {
+                    if connection.source().ip() == host {
+                        connection
+                            .fault_handle(ConnectionSide::Client)
+                            .set_send_latency(latency);
+                    }
+                    if connection.dest().ip() == host {
+                        connection
+                            .fault_handle(ConnectionSide::Server)
+                            .set_send_latency(latency);
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    /// Holds the permit of an applied fault until it is released by the reverting action.
+    fn hold(&mut self, fault: HeldFault, permit: Option<FaultPermit>) {
+        if let Some(permit) = permit {
+            self.held_faults.push((fault, permit));
+        }
+    }
+
+    /// Releases the permits of the held faults matching the predicate.
+    fn release_held<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&HeldFault) -> bool,
+    {
+        self.held_faults.retain(|(held, _)| !f(held));
+    }
+
+    /// Releases the permits of partitions whose pairs of hosts have all healed.
+    fn release_healed(&mut self) {
+        let partitions = &self.partitions;
+        self.held_faults.retain(|(held, _)| match held {
+            HeldFault::Partition(pairs) => pairs
+                .iter()
+                .any(|(source, dest)| partitions.mode(*source, *dest).is_some()),
+            _ => true,
+        });
+    }
+
+    /// Returns the fault handle for the provided target. Targets refer to the address the
+    /// listener was bound to when the connection was established, which may since have migrated.
+    fn target_fault_handle(&self, target: &FaultTarget) -> Option<&socket::FaultyTcpStreamHandle> {
+        let dest =
+            net::SocketAddr::new(self.hosts.current(target.dest().ip()), target.dest().port());
+        self.connections
+            .to_dest(dest)
+            .find(|c| c.is_target(target))
+            .map(|c| c.fault_handle(target.side()))
+    }
+
+    /// Arms or disarms connection crossing. While armed, the server halves of two connections
+    /// to the same listener established within the window are swapped.
+    pub(crate) fn set_crossing_window(&mut self, window: Option<time::Duration>) {
+        self.crossing_window = window;
+    }
+
+    /// Limits the connections and memory of a host, see [`ResourceLimits`].
+    ///
+    /// [`ResourceLimits`]:`ResourceLimits`
+    pub(crate) fn set_resource_limits(
+        &mut self,
+        host: net::IpAddr,
+        limits: Option<ResourceLimits>,
+    ) {
+        self.resources_of(host).set_limits(limits);
+    }
+
+    pub(crate) fn resource_usage(&mut self, host: net::IpAddr) -> ResourceUsage {
+        self.resources_of(host).usage()
+    }
+
+    /// Applies the provided faults to the accepts of the listeners of the host reachable at
+    /// `addr`, sampling fault decisions from `random`. Passing `None` removes the faults.
+    pub(crate) fn set_accept_faults(
+        &mut self,
+        addr: net::IpAddr,
+        faults: Option<AcceptFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let host = self.hosts.host_of(addr);
+        match faults {
+            Some(faults) => {
+                self.accept_faults.insert(host, (faults, random));
+            }
+            None => {
+                self.accept_faults.remove(&host);
+            }
+        }
+    }
+
+    pub(crate) fn accept_faults(&self, host: net::IpAddr) -> Option<AcceptFaultState> {
+        self.accept_faults.get(&host).cloned()
+    }
+
+    /// Returns the account of the resources of the host reachable at `addr`.
+    fn resources_of(&mut self, addr: net::IpAddr) -> HostResources {
+        let host = self.hosts.host_of(addr);
+        self.resources.entry(host).or_default().clone()
+    }
+
+    /// Determines how a new server half should be delivered to its listener. If connection
+    /// crossing is armed, the server half is either crossed with a pending server half, or held
+    /// until the crossing window elapses.
+    fn delivery(
+        &mut self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        mut server: FaultyTcpStream<SocketHalf>,
+    ) -> Delivery {
+        let window = match self.crossing_window {
+            Some(window) => window,
+            None => return Delivery::Now(server),
+        };
+        let slot = sync::Arc::clone(self.crossings.entry(dest).or_default());
+        let pending = slot.lock().unwrap().take();
+        match pending {
+            Some(mut pending) => {
+                trace!(
+                    "crossing connections {} and {} to {}",
+                    pending.source,
+                    source,
+                    dest
+                );
+                pending.server.get_mut().cross(server.get_mut());
+                self.swap_server_halves(pending.source, source);
+                // If the held connection was abandoned, the crossed server half is dropped.
+                let _ = pending.notify.send(pending.server);
+                Delivery::Now(server)
+            }
+            None => {
+                let (notify, crossed) = oneshot::channel();
+                slot.lock().unwrap().replace(PendingCrossing {
+                    source,
+                    server,
+                    notify,
+                });
+                Delivery::Held {
+                    slot,
+                    crossed,
+                    window: self.handle.delay_from(window),
+                }
+            }
+        }
+    }
+
+    /// Swap the server fault handles of the connections originating from `a` and `b`.
+    fn swap_server_halves(&mut self, a: net::SocketAddr, b: net::SocketAddr) {
+        self.connections.swap_server(a, b);
+    }
+
+    /// Registers a host with the provided address.
+    pub(crate) fn register_host(&mut self, addr: net::IpAddr) {
+        self.hosts.register(addr);
+    }
+
+    /// Adds a host to the network, returning its newly allocated address.
+    pub(crate) fn add_host(&mut self) -> net::IpAddr {
+        let addr = self.hosts.allocate();
+        trace!("added host {}", addr);
+        addr
+    }
+
+    /// Removes a host from the network. Connections to and from the host are disconnected,
+    /// its listeners are closed, and further connections to the host are refused. Returns
+    /// false if the host was not registered.
+    pub(crate) fn remove_host(&mut self, addr: net::IpAddr) -> bool {
+        if !self.hosts.remove(addr) {
+            return false;
+        }
+        trace!("removing host {}", addr);
+        let hosts = &self.hosts;
+        for connection in self.connections.iter().filter(|c| {
+            hosts.host_of(c.source().ip()) == addr || hosts.host_of(c.dest().ip()) == addr
+        }) {
+            connection.fault_handle(ConnectionSide::Client).disconnect();
+            connection.fault_handle(ConnectionSide::Server).disconnect();
+        }
+        self.endpoints
+            .retain(|endpoint, _| hosts.host_of(endpoint.ip()) != addr);
+        self.datagrams.unbind_host(addr);
+        self.link_conditions
+            .retain(|(a, b), _| *a != addr && *b != addr);
+        self.directed_conditions
+            .retain(|(a, b), _| *a != addr && *b != addr);
+        self.shared_links
+            .retain(|(a, b), _| *a != addr && *b != addr);
+        self.host_conditions.remove(&addr);
+        true
+    }
+
+    /// Moves a host to a new address. Established connections to and from the host either
+    /// follow it to the new address or are reset depending on `policy`, and its listeners are
+    /// rebound to the new address. Connections to the old address are refused from then on.
+    /// Returns false if the host was not registered or the new address is in use.
+    pub(crate) fn migrate_host(
+        &mut self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        policy: MigrationPolicy,
+    ) -> bool {
+        if !self.hosts.migrate(from, to) {
+            return false;
+        }
+        trace!("migrating host {} to {} with {:?}", from, to, policy);
+        self.gc_dropped();
+        // The addresses of the host in its other family move along with it.
+        let mut moves = vec![(from, to)];
+        if let (Some(from), Some(to)) = (hosts::counterpart(from), hosts::counterpart(to)) {
+            moves.push((from, to));
+        }
+        for (from, to) in moves {
+            self.connections.modify(|connection| {
+                if connection.source().ip() != from && connection.dest().ip() != from {
+                    return;
+                }
+                match policy {
+                    MigrationPolicy::Transparent => connection.migrate(from, to),
+                    MigrationPolicy::Reset => {
+                        connection.fault_handle(ConnectionSide::Client).reset();
+                        connection.fault_handle(ConnectionSide::Server).reset();
+                    }
+                }
+            });
+            // Reset connections keep their source address until they are collected, at which
+            // point their ports are returned to the old address.
+            if policy == MigrationPolicy::Transparent {
+                self.ports.migrate(from, to);
+            }
+            let endpoints: Vec<net::SocketAddr> = self
+                .endpoints
+                .keys()
+                .filter(|endpoint| endpoint.ip() == from)
+                .cloned()
+                .collect();
+            for mut endpoint in endpoints {
+                let state = self.endpoints.remove(&endpoint).unwrap();
+                endpoint.set_ip(to);
+                self.endpoints.insert(endpoint, state);
+            }
+        }
+        let migrated = |addr: net::IpAddr| if addr == from { to } else { addr };
+        self.link_conditions = self
+            .link_conditions
+            .drain()
+            .map(|((a, b), conditions)| (link_key(migrated(a), migrated(b)), conditions))
+            .collect();
+        self.directed_conditions = self
+            .directed_conditions
+            .drain()
+            .map(|((a, b), conditions)| ((migrated(a), migrated(b)), conditions))
+            .collect();
+        self.shared_links = self
+            .shared_links
+            .drain()
+            .map(|((a, b), link)| ((migrated(a), migrated(b)), link))
+            .collect();
+        if let Some(conditions) = self.host_conditions.remove(&from) {
+            self.host_conditions.insert(to, conditions);
+        }
+        self.apply_conditions(|connection| {
+            connection.source().ip() == to || connection.dest().ip() == to
+        });
+        self.handle
+            .events()
+            .emit(SimulationEvent::HostMigrated { from, to });
+        true
+    }
+
+    /// Partitions the hosts of `a` from the hosts of `b`. Connections between the groups,
+    /// established or not, either stall or are reset depending on `mode`, and datagrams between
+    /// them are dropped. Pairs of hosts which are already partitioned keep their mode. Returns
+    /// the pairs which were newly partitioned.
+    pub(crate) fn partition(
+        &mut self,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+        mode: PartitionMode,
+    ) -> Vec<(net::IpAddr, net::IpAddr)> {
+        trace!("partitioning {:?} from {:?} with {:?}", a, b, mode);
+        let provenance = FaultProvenance::new(
+            self.fault_ids.next(),
+            FaultKind::Partition,
+            self.now(),
+            None,
+        );
+        let added = self.partitions.add(a, b, mode, provenance.clone());
+        for (source, dest) in added.iter().cloned() {
+            match mode {
+                PartitionMode::Stall => {
+                    for (source, dest) in family_pairs(source, dest) {
+                        self.clog_connection(CloggedConnection::new(source, dest));
+                    }
+                }
+                PartitionMode::Reset => {
+                    let hosts = &self.hosts;
+                    for connection in self.connections.iter().filter(|c| {
+                        hosts.host_of(c.source().ip()) == source
+                            && hosts.host_of(c.dest().ip()) == dest
+                    }) {
+                        for side in [ConnectionSide::Client, ConnectionSide::Server].iter() {
+                            connection.fault_handle(*side).reset_by(provenance.clone());
+                        }
+                    }
+                }
+            }
+        }
+        added
+    }
+
+    /// Partitions the hosts as [`partition`] does if the fault budget permits it, holding the
+    /// permit until the partition heals. Returns false if the budget refused the partition.
+    ///
+    /// [`partition`]:`Inner::partition`
+    pub(crate) fn try_partition(
+        &mut self,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+        mode: PartitionMode,
+    ) -> bool {
+        match self.budget.try_acquire(FaultKind::Partition, self.now()) {
+            Some(permit) => {
+                self.partition_held(a, b, mode, Some(permit));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn partition_held(
+        &mut self,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+        mode: PartitionMode,
+        permit: Option<FaultPermit>,
+    ) {
+        let pairs = self.partition(a, b, mode);
+        if !pairs.is_empty() {
+            self.hold(HeldFault::Partition(pairs), permit);
+        }
+    }
+
+    /// Heals every partition, resuming stalled connections. Connections which were reset
+    /// remain failed.
+    pub(crate) fn heal(&mut self) {
+        trace!("healing partitions");
+        for ((source, dest), mode) in self.partitions.heal() {
+            if mode == PartitionMode::Stall {
+                for (source, dest) in family_pairs(source, dest) {
+                    self.unclog_connection(CloggedConnection::new(source, dest));
+                }
+            }
+        }
+        self.release_healed();
+    }
+
+    /// Heals the partitions between the hosts of `a` and the hosts of `b`, leaving other
+    /// partitions in place.
+    pub(crate) fn heal_between(&mut self, a: &[net::IpAddr], b: &[net::IpAddr]) {
+        trace!("healing partitions between {:?} and {:?}", a, b);
+        for ((source, dest), mode) in self.partitions.remove(a, b) {
+            if mode == PartitionMode::Stall {
+                for (source, dest) in family_pairs(source, dest) {
+                    self.unclog_connection(CloggedConnection::new(source, dest));
+                }
+            }
+        }
+        self.release_healed();
+    }
+
+    /// Returns the address the host registered as `addr` currently uses.
+    pub(crate) fn current_address(&self, addr: net::IpAddr) -> net::IpAddr {
+        self.hosts.current(addr)
+    }
+
+    /// Associates a name with a host, allowing it to be resolved by name.
+    pub(crate) fn name_host(&mut self, name: String, addr: net::IpAddr) {
+        self.hosts.name(name, addr);
+    }
+
+    pub(crate) fn resolve_host(&self, name: &str) -> Option<net::IpAddr> {
+        self.hosts.resolve(name)
+    }
+
+    pub(crate) fn resolve_stale_host(&self, name: &str) -> Option<net::IpAddr> {
+        self.hosts.resolve_stale(name)
+    }
+
+    /// Adds a host to a network domain. Hosts which have not joined any domain are members of
+    /// the default domain.
+    pub(crate) fn join_domain(&mut self, addr: net::IpAddr, domain: String) {
+        self.hosts.join(addr, domain);
+    }
+
+    pub(crate) fn leave_domain(&mut self, addr: net::IpAddr, domain: &str) {
+        self.hosts.leave(addr, domain);
+    }
+
+    pub(crate) fn domains(&self, addr: net::IpAddr) -> Vec<String> {
+        self.hosts.domains(addr)
+    }
+
+    /// Returns the addresses of all hosts on the network.
+    pub(crate) fn hosts(&self) -> Vec<net::IpAddr> {
+        self.hosts.iter().collect()
+    }
+
+    pub(crate) fn hosts_in(&self, domain: &str) -> Vec<net::IpAddr> {
+        self.hosts.members(domain)
+    }
+
+    /// Refuses new connections to a host while leaving its established connections intact.
+    /// Returns false if the host is not registered.
+    pub(crate) fn drain_host(&mut self, addr: net::IpAddr) -> bool {
+        trace!("draining host {}", addr);
+        self.hosts.drain(addr)
+    }
+
+    /// Sets the conditions applied to connections between hosts which do not have link
+    /// specific conditions, including connections which are already established.
+    pub(crate) fn set_conditions(&mut self, conditions: Option<LinkConditions>) {
+        self.conditions = conditions;
+        self.apply_conditions(|_| true);
+    }
+
+    /// Sets the conditions applied to connections between the provided hosts, including
+    /// connections which are already established.
+    pub(crate) fn set_link_conditions(
+        &mut self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        conditions: Option<LinkConditions>,
+    ) {
+        let key = link_key(a, b);
+        match conditions {
+            Some(conditions) => self.link_conditions.insert(key, conditions),
+            None => self.link_conditions.remove(&key),
+        };
+        self.apply_conditions(|connection| {
+            link_key(connection.source().ip(), connection.dest().ip()) == key
+        });
+    }
+
+    /// Sets the conditions applied to data sent from `source` to `dest`, including over
+    /// connections which are already established. Data sent in the opposite direction is
+    /// unaffected.
+    pub(crate) fn set_directed_link_conditions(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        conditions: Option<LinkConditions>,
+    ) {
+        match conditions {
+            Some(conditions) => self.directed_conditions.insert((source, dest), conditions),
+            None => self.directed_conditions.remove(&(source, dest)),
+        };
+        let key = link_key(source, dest);
+        self.apply_conditions(|connection| {
+            link_key(connection.source().ip(), connection.dest().ip()) == key
+        });
+    }
+
+    /// Limits the bandwidth of the link between hosts `a` and `b` to `bytes_per_second` in each
+    /// direction, shared by every connection between the hosts including connections which are
+    /// already established. Passing `None` removes the limit.
+    pub(crate) fn set_link_bandwidth(
+        &mut self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        bytes_per_second: Option<u64>,
+    ) {
+        for direction in &[(a, b), (b, a)] {
+            match bytes_per_second {
+                Some(bytes_per_second) => self
+                    .shared_links
+                    .insert(*direction, sync::Arc::new(Throttle::new(bytes_per_second))),
+                None => self.shared_links.remove(direction),
+            };
+        }
+        let key = link_key(a, b);
+        for connection in self
+            .connections
+            .iter()
+            .filter(|c| link_key(c.source().ip(), c.dest().ip()) == key)
+        {
+            let (source, dest) = (connection.source().ip(), connection.dest().ip());
+            connection
+                .fault_handle(ConnectionSide::Client)
+                .set_shared_link(self.shared_links.get(&(source, dest)).cloned());
+            connection
+                .fault_handle(ConnectionSide::Server)
+                .set_shared_link(self.shared_links.get(&(dest, source)).cloned());
+        }
+    }
+
+    /// Returns the faults of the data sent from `source` to `dest`, creating them with the
+    /// provided source of randomness if the link has not been faulted before. The faults apply
+    /// to connections which are already established as well as to future connections.
+    pub(crate) fn fault_link(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) -> LinkFaults {
+        let fault_ids = self.fault_ids.clone();
+        let faults = self
+            .link_faults
+            .entry((source, dest))
+            .or_insert_with(|| LinkFaults::new(random, fault_ids))
+            .clone();
+        for connection in self.connections.iter() {
+            let (client, server) = (connection.source().ip(), connection.dest().ip());
+            if (client, server) == (source, dest) {
+                connection
+                    .fault_handle(ConnectionSide::Client)
+                    .set_link_faults(Some(faults.clone()));
+            }
+            if (server, client) == (source, dest) {
+                connection
+                    .fault_handle(ConnectionSide::Server)
+                    .set_link_faults(Some(faults.clone()));
+            }
+        }
+        faults
+    }
+
+    /// Returns the conditions which apply to data sent from `source` to `dest`.
+    fn conditions_for(&self, source: net::IpAddr, dest: net::IpAddr) -> Option<LinkConditions> {
+        let (source, dest) = (self.hosts.host_of(source), self.hosts.host_of(dest));
+        self.directed_conditions
+            .get(&(source, dest))
+            .or_else(|| self.link_conditions.get(&link_key(source, dest)))
+            .or_else(|| self.host_conditions.get(&source))
+            .or_else(|| self.host_conditions.get(&dest))
+            .or_else(|| self.conditions.as_ref())
+            .cloned()
+    }
+
+    /// Sets the conditions applied to connections to or from the provided host.
+    pub(crate) fn set_host_conditions(
+        &mut self,
+        host: net::IpAddr,
+        conditions: Option<LinkConditions>,
+    ) {
+        match conditions {
+            Some(conditions) => self.host_conditions.insert(host, conditions),
+            None => self.host_conditions.remove(&host),
+        };
+        self.apply_conditions(|connection| {
+            connection.source().ip() == host || connection.dest().ip() == host
+        });
+    }
+
+    /// Reapplies link conditions to the established connections matching the provided filter.
+    fn apply_conditions<F>(&self, filter: F)
+    where
+        F: Fn(&Connection) -> bool,
+    {
+        for connection in self.connections.iter().filter(|c| filter(c)) {
+            let (source, dest) = (connection.source().ip(), connection.dest().ip());
+            connection
+                .fault_handle(ConnectionSide::Client)
+                .set_conditions(self.conditions_for(source, dest));
+            connection
+                .fault_handle(ConnectionSide::Server)
+                .set_conditions(self.conditions_for(dest, source));
+        }
+    }
+
+    /// Sets the duration closed connections will remain in TIME_WAIT. While in TIME_WAIT, the
+    /// source address of the connection cannot be reused.
+    pub(crate) fn set_time_wait(&mut self, time_wait: Option<time::Duration>) {
+        self.time_wait = time_wait;
+    }
+    pub(crate) fn set_socket_buffers(&mut self, buffers: Option<SocketBuffers>) {
+        self.socket_buffers = buffers;
+    }
+    /// Sets the segment faults of new connections and of connections which are already
+    /// established.
+    pub(crate) fn set_segment_faults(
+        &mut self,
+        faults: Option<SegmentFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        for connection in self.connections.iter() {
+            for side in [ConnectionSide::Client, ConnectionSide::Server].iter() {
+                connection
+                    .fault_handle(*side)
+                    .set_segment_faults(faults, random.clone());
+            }
         }
+        self.segment_faults = faults.map(|faults| (faults, random));
+    }
+    pub(crate) fn set_unbound_grace(&mut self, grace: Option<time::Duration>) {
+        self.unbound_grace = grace;
+    }
+    /// Returns a delay for the grace period a connect to `dest` waits for a listener to be
+    /// bound, if connects to unbound addresses are refused and no listener is bound to `dest`.
+    pub(crate) fn unbound_grace(&self, dest: net::SocketAddr) -> Option<Delay> {
+        self.unbound_grace
+            .filter(|_| !self.is_bound(dest) && !self.is_closed(dest))
+            .map(|grace| self.handle.delay_from(grace))
+    }
+    pub(crate) fn register_bind_waker(&mut self, addr: net::SocketAddr, waker: &Waker) {
+        let wakers = self.bind_wakers.entry(addr).or_default();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+    /// Refuses a connect to `dest`, which no listener was bound to within the grace period.
+    pub(crate) fn refuse_unbound(&mut self, dest: net::SocketAddr) -> io::Error {
+        trace!("refusing connection to {}, no listener is bound", dest);
+        self.handle
+            .events()
+            .emit(SimulationEvent::ConnectionRefused { dest });
+        io::ErrorKind::ConnectionRefused.into()
+    }
+    /// Attaches the provided tap to connections to `addr` established from now on.
+    pub(crate) fn add_tap(&mut self, addr: net::SocketAddr, tap: sync::Arc<dyn StreamTap>) {
+        self.taps.push((addr, tap));
     }
     fn register_new_connection_pair(
         &mut self,
         source: net::SocketAddr,
         dest: net::SocketAddr,
     ) -> Result<(FaultyTcpStream<SocketHalf>, FaultyTcpStream<SocketHalf>), io::Error> {
+        // Connections in TIME_WAIT only conflict with connects to the same destination, as
+        // other conflicts were ruled out when the source port was chosen.
         if self
             .connections
-            .iter()
-            .map(|c| c.source())
-            .any(|x| x == source)
+            .from_source(source)
+            .any(|c| c.dest() == dest || !c.is_dropped())
         {
             return Err(io::ErrorKind::AddrInUse.into());
         }
+        let descriptor = self.resources_of(source.ip()).open()?;
 
-        let (client, server) = socket::new_socket_pair(source, dest);
-        let (client, client_fault_handle) =
-            socket::FaultyTcpStream::wrap(self.handle.clone(), client);
-        let (server, server_fault_handle) =
-            socket::FaultyTcpStream::wrap(self.handle.clone(), server);
-        let mut connection =
-            Connection::new(source, dest, client_fault_handle, server_fault_handle);
+        let (mut client, mut server) =
+            socket::new_socket_pair_with_buffers(source, dest, self.socket_buffers);
+        client.set_descriptor(descriptor);
+        client.set_resources(self.resources_of(source.ip()));
+        server.set_resources(self.resources_of(dest.ip()));
+        client.set_close_monitor(self.close_monitor.clone());
+        server.set_close_monitor(self.close_monitor.clone());
+        client.set_watermarks(self.watermarks.clone());
+        server.set_watermarks(self.watermarks.clone());
+        client.set_events(self.handle.events().clone());
+        server.set_events(self.handle.events().clone());
+        let (client, client_fault_handle) = socket::FaultyTcpStream::wrap_with_fault_ids(
+            self.handle.clone(),
+            client,
+            self.fault_ids.clone(),
+        );
+        let (server, server_fault_handle) = socket::FaultyTcpStream::wrap_with_fault_ids(
+            self.handle.clone(),
+            server,
+            self.fault_ids.clone(),
+        );
+        client_fault_handle.set_peer(&server_fault_handle);
+        server_fault_handle.set_peer(&client_fault_handle);
+        client_fault_handle.set_conditions(self.conditions_for(source.ip(), dest.ip()));
+        server_fault_handle.set_conditions(self.conditions_for(dest.ip(), source.ip()));
+        if let Some((faults, random)) = self.segment_faults.as_ref() {
+            client_fault_handle.set_segment_faults(Some(*faults), random.clone());
+            server_fault_handle.set_segment_faults(Some(*faults), random.clone());
+        }
+        client_fault_handle
+            .set_shared_link(self.shared_links.get(&(source.ip(), dest.ip())).cloned());
+        server_fault_handle
+            .set_shared_link(self.shared_links.get(&(dest.ip(), source.ip())).cloned());
+        client_fault_handle
+            .set_link_faults(self.link_faults.get(&(source.ip(), dest.ip())).cloned());
+        server_fault_handle
+            .set_link_faults(self.link_faults.get(&(dest.ip(), source.ip())).cloned());
+        for (_, tap) in self.taps.iter().filter(|(addr, _)| *addr == dest) {
+            client_fault_handle.add_tap(sync::Arc::clone(tap));
+            server_fault_handle.add_tap(sync::Arc::clone(tap));
+        }
+        let id = ConnectionId(self.next_connection_id);
+        self.next_connection_id += 1;
+        let ordinal = self
+            .connection_ordinals
+            .entry((source.ip(), dest))
+            .or_insert(0);
+        let mut connection = Connection::new(
+            id,
+            source,
+            dest,
+            *ordinal,
+            client_fault_handle,
+            server_fault_handle,
+        );
+        *ordinal += 1;
+        trace!("registered {} {} -> {}", id, source, dest);
         if self.should_clog(source, dest) {
             connection.clog();
         }
         self.connections.push(connection);
         Ok((client, server))
     }
-    // find an unused socket port for the provided ipaddr.
-    fn unused_socket_port(&self, addr: net::IpAddr) -> u16 {
-        let mut start = 65535;
-        let occupied: collections::HashSet<u16> = self
-            .connections
-            .iter()
-            .filter(|v| v.source().ip() == addr)
-            .map(|v| v.source().port())
-            .collect();
-        loop {
-            if !occupied.contains(&start) {
-                return start;
-            }
-            if start == 0 {}
-            start -= 1;
+    /// Remove dropped connections, retaining any connections which are still in TIME_WAIT.
+    fn gc_dropped(&mut self) {
+        let collected = self.connections.collect(self.handle.now(), self.time_wait);
+        for source in collected {
+            self.free_port(source);
         }
     }
 
-    fn gc_dropped(&mut self) {
-        let mut connections = vec![];
-        for connection in self.connections.iter() {
-            if !connection.is_dropped() {
-                connections.push(connection.clone());
-            }
+    /// Returns the source port of a connection to its host, unless it has been reused by
+    /// another connection while in TIME_WAIT.
+    fn free_port(&mut self, source: net::SocketAddr) {
+        if self.connections.from_source(source).next().is_none() {
+            self.ports.free(source);
         }
-        self.connections = connections;
+    }
+
+    /// Chooses the source port of a connect from `source`, claiming the port requested by the
+    /// provided options or allocating an ephemeral port, see [`ConnectOptions`].
+    ///
+    /// [`ConnectOptions`]:`ConnectOptions`
+    fn source_port(
+        &mut self,
+        source: net::IpAddr,
+        dest: net::SocketAddr,
+        options: &ConnectOptions,
+    ) -> Result<u16, io::Error> {
+        let local = match options.local_addr {
+            Some(local) => local,
+            None => return self.ports.allocate(source),
+        };
+        if !local.ip().is_unspecified() && local.ip() != source {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        if local.port() == 0 {
+            return self.ports.allocate(source);
+        }
+        let addr = net::SocketAddr::new(source, local.port());
+        let occupied = self
+            .connections
+            .from_source(addr)
+            .any(|c| !c.is_dropped() || !options.reuse_address || c.dest() == dest);
+        if occupied || self.is_bound(addr) {
+            trace!("refusing connection from {}, address is in use", addr);
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        self.ports.claim(addr);
+        Ok(local.port())
     }
 
     pub fn connect(
         &mut self,
         source: net::IpAddr,
         dest: net::SocketAddr,
+        options: &ConnectOptions,
     ) -> impl Future<Output = Result<socket::FaultyTcpStream<SocketHalf>, io::Error>> {
         trace!("establishing new connection {} -> {}", source, dest);
         self.gc_dropped();
-        let free_socket_port = self.unused_socket_port(source);
-        let source_addr = net::SocketAddr::new(source, free_socket_port);
-        let registration = self.register_new_connection_pair(source_addr, dest);
+        let dest_host = self.hosts.host_of(dest.ip());
+        let source_ip = hosts::address_in(source, dest.ip());
+        let registration = if self.hosts.is_removed(source) {
+            Err(io::ErrorKind::AddrNotAvailable.into())
+        } else if source_ip.is_none() {
+            trace!(
+                "refusing connection to {}, host has no address of its family",
+                dest
+            );
+            Err(io::ErrorKind::AddrNotAvailable.into())
+        } else if self.hosts.is_removed(dest_host) || !self.hosts.is_reachable(source, dest_host) {
+            trace!("refusing connection to {}, host is unreachable", dest);
+            self.handle
+                .events()
+                .emit(SimulationEvent::ConnectionRefused { dest });
+            Err(io::ErrorKind::ConnectionRefused.into())
+        } else if self.partitions.mode(source, dest_host) == Some(PartitionMode::Reset) {
+            trace!("refusing connection to {}, host is partitioned", dest);
+            self.handle
+                .events()
+                .emit(SimulationEvent::ConnectionRefused { dest });
+            let provenance = self.partitions.provenance(source, dest_host).unwrap();
+            Err(provenance
+                .clone()
+                .into_io_error(io::ErrorKind::ConnectionRefused))
+        } else if self.is_closed(dest) {
+            trace!("refusing connection to {}, listener was closed", dest);
+            self.handle
+                .events()
+                .emit(SimulationEvent::ConnectionRefused { dest });
+            Err(io::ErrorKind::ConnectionRefused.into())
+        } else if self.hosts.is_draining(dest_host) {
+            trace!("refusing connection to {}, host is draining", dest);
+            self.handle
+                .events()
+                .emit(SimulationEvent::ConnectionRefused { dest });
+            Err(io::ErrorKind::ConnectionRefused.into())
+        } else {
+            let source = source_ip.unwrap();
+            self.source_port(source, dest, options).and_then(|port| {
+                let source_addr = net::SocketAddr::new(source, port);
+                match self.register_new_connection_pair(source_addr, dest) {
+                    Ok((client, server)) => {
+                        let delivery = self.delivery(source_addr, dest, server);
+                        Ok((client, delivery, source_addr))
+                    }
+                    Err(e) => {
+                        self.free_port(source_addr);
+                        Err(e)
+                    }
+                }
+            })
+        };
 
-        let mut channel;
-        match self.endpoints.entry(dest) {
+        let (mut channel, queued, backlog) = match self.endpoints.entry(dest) {
             Entry::Vacant(v) => {
                 let (tx, rx) = mpsc::channel(1);
-                let state = ListenerState::Unbound { tx: tx.clone(), rx };
-                channel = tx;
-                v.insert(state);
+                let queued = sync::Arc::default();
+                v.insert(ListenerState::Unbound {
+                    tx: tx.clone(),
+                    rx,
+                    queued: sync::Arc::clone(&queued),
+                });
+                (tx, queued, None)
             }
             Entry::Occupied(o) => match o.get() {
-                ListenerState::Bound { tx } => channel = tx.clone(),
-                ListenerState::Unbound { tx, .. } => channel = tx.clone(),
+                ListenerState::Bound {
+                    tx,
+                    options,
+                    queued,
+                    ..
+                } => (tx.clone(), sync::Arc::clone(queued), options.backlog),
+                ListenerState::Unbound { tx, queued, .. } => {
+                    (tx.clone(), sync::Arc::clone(queued), None)
+                }
+                // Connects to closed addresses fail to register, so the queue is never used.
+                ListenerState::Closed => {
+                    let (tx, _) = mpsc::channel(1);
+                    (tx, sync::Arc::default(), None)
+                }
             },
-        }
+        };
 
+        let events = self.handle.events().clone();
         async move {
-            let (client, server) = registration?;
-            match channel.send(server).await {
-                Ok(_) => Ok(client),
-                Err(_) => Err(io::ErrorKind::ConnectionRefused.into()),
+            let (client, delivery, source_addr) = registration?;
+            let server = delivery.server().await;
+            if backlog.map_or(false, |backlog| {
+                queued.load(atomic::Ordering::SeqCst) >= backlog
+            }) {
+                trace!("refusing connection to {}, accept backlog is full", dest);
+                events.emit(SimulationEvent::ConnectionRefused { dest });
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            queued.fetch_add(1, atomic::Ordering::SeqCst);
+            let send = channel.send((server, source_addr));
+            match task::WaitingOn::new(WaitResource::Listener(dest), send).await {
+                Ok(_) => {
+                    let id = client
+                        .connection_id()
+                        .expect("registered connections have an id");
+                    trace!("established {} {} -> {}", id, source_addr, dest);
+                    events.emit(SimulationEvent::ConnectionEstablished {
+                        id,
+                        source: source_addr,
+                        dest,
+                    });
+                    Ok(client)
+                }
+                Err(_) => {
+                    events.emit(SimulationEvent::ConnectionRefused { dest });
+                    Err(io::ErrorKind::ConnectionRefused.into())
+                }
             }
         }
     }
 
-    pub fn listen(&mut self, bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
-        trace!("registering listener for {}", bind_addr);
+    /// Returns the addresses of listeners which are bound and have not been dropped.
+    pub(crate) fn bound_listeners(&self) -> Vec<net::SocketAddr> {
+        let mut listeners: Vec<net::SocketAddr> = self
+            .endpoints
+            .iter()
+            .filter_map(|(addr, state)| match state {
+                ListenerState::Bound { tx, .. } if !tx.is_closed() => Some(*addr),
+                _ => None,
+            })
+            .collect();
+        listeners.sort();
+        listeners
+    }
+
+    /// Returns the source and destination of each connection which has not been dropped.
+    pub(crate) fn open_connections(&self) -> Vec<(net::SocketAddr, net::SocketAddr)> {
+        self.connections
+            .iter()
+            .filter(|c| !c.is_dropped())
+            .map(|c| (c.source(), c.dest()))
+            .collect()
+    }
+
+    /// Returns a snapshot of each connection which has not been dropped, in the order they were
+    /// established.
+    pub(crate) fn connection_info(&self) -> Vec<ConnectionInfo> {
+        self.connections
+            .iter()
+            .filter(|c| !c.is_dropped())
+            .map(Connection::info)
+            .collect()
+    }
+
+    /// Returns a snapshot of each listener address, in ascending order.
+    pub(crate) fn listener_info(&self) -> Vec<ListenerInfo> {
+        let mut listeners: Vec<ListenerInfo> = self
+            .endpoints
+            .iter()
+            .map(|(addr, state)| {
+                let (bound, max_connections) = match state {
+                    ListenerState::Bound { options, .. } => (true, options.max_connections),
+                    ListenerState::Unbound { .. } | ListenerState::Closed => (false, None),
+                };
+                ListenerInfo {
+                    addr: *addr,
+                    bound,
+                    connections: self
+                        .connections
+                        .to_dest(*addr)
+                        .filter(|c| !c.is_dropped())
+                        .count(),
+                    max_connections,
+                }
+            })
+            .collect();
+        listeners.sort_by_key(|listener| listener.addr);
+        listeners
+    }
+
+    /// Returns true if a listener is bound to the provided address.
+    pub(crate) fn is_bound(&self, addr: net::SocketAddr) -> bool {
+        match self.endpoints.get(&addr) {
+            Some(ListenerState::Bound { .. }) => true,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the listener bound to the provided address was dropped, and no listener
+    /// has been bound since.
+    fn is_closed(&self, addr: net::SocketAddr) -> bool {
+        match self.endpoints.get(&addr) {
+            Some(ListenerState::Closed) => true,
+            _ => false,
+        }
+    }
+
+    /// Closes the addresses of a dropped listener, allowing them to be bound again. Ports
+    /// allocated to listeners bound to port 0 are returned to their host.
+    pub(crate) fn close_listener(&mut self, listener: u64) {
+        if let Some(addr) = self.ephemeral_listeners.remove(&listener) {
+            self.ports.free(addr);
+        }
+        for (addr, state) in self.endpoints.iter_mut() {
+            match state {
+                ListenerState::Bound { listener: id, .. } if *id == listener => {
+                    trace!("closing listener for {}", addr);
+                    *state = ListenerState::Closed;
+                    self.handle
+                        .events()
+                        .emit(SimulationEvent::ListenerClosed(*addr));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Checks if a new connection to `dest` would exceed the maximum number of live connections
+    /// for the listener bound to `dest`. If the listener is configured to queue new connections,
+    /// the current task is notified once an existing connection is dropped.
+    pub(crate) fn poll_connection_slot(
+        &mut self,
+        cx: &mut Context<'_>,
+        dest: net::SocketAddr,
+    ) -> Poll<Result<(), io::Error>> {
         self.gc_dropped();
-        match self.endpoints.remove(&bind_addr) {
-            Some(listener_state) => {
-                if let ListenerState::Unbound { tx, rx } = listener_state {
-                    let listener = Listener::new(bind_addr, rx);
-                    let new_state = ListenerState::Bound { tx };
-                    self.endpoints.insert(bind_addr, new_state);
-                    Ok(listener)
-                } else {
-                    self.endpoints.insert(bind_addr, listener_state);
-                    Err(io::ErrorKind::AddrInUse.into())
+        let (max, policy) = match self.endpoints.get(&dest) {
+            Some(ListenerState::Bound { options, .. }) => match options.max_connections {
+                Some(max) => (max, options.limit_policy),
+                None => return Poll::Ready(Ok(())),
+            },
+            _ => return Poll::Ready(Ok(())),
+        };
+        let live: Vec<&Connection> = self
+            .connections
+            .to_dest(dest)
+            .filter(|c| !c.is_dropped())
+            .collect();
+        if live.len() < max {
+            return Poll::Ready(Ok(()));
+        }
+        match policy {
+            LimitPolicy::Refuse => {
+                trace!("refusing connection to {}, listener is at capacity", dest);
+                self.handle
+                    .events()
+                    .emit(SimulationEvent::ConnectionRefused { dest });
+                Poll::Ready(Err(io::ErrorKind::ConnectionRefused.into()))
+            }
+            LimitPolicy::Queue => {
+                trace!("queueing connection to {}, listener is at capacity", dest);
+                for connection in live {
+                    connection.register_drop_waker(cx.waker());
                 }
+                Poll::Pending
             }
+        }
+    }
+
+    /// Binds a listener to `bind_addr`. Dual-stack listeners are provided the IPv4 address of
+    /// their host as `mapped`, and are also bound to the same port of that address. Addresses
+    /// whose listener was dropped can be bound again.
+    pub fn listen(
+        &mut self,
+        bind_addr: net::SocketAddr,
+        mapped: Option<net::IpAddr>,
+        options: ListenerOptions,
+        inner: sync::Weak<sync::Mutex<Inner>>,
+    ) -> Result<Listener, io::Error> {
+        trace!("registering listener for {}", bind_addr);
+        self.gc_dropped();
+        if self.hosts.is_removed(self.hosts.host_of(bind_addr.ip())) {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        let ephemeral = bind_addr.port() == 0;
+        let bind_addr = if ephemeral {
+            self.ephemeral_endpoint(bind_addr.ip(), mapped)?
+        } else {
+            bind_addr
+        };
+        let mapped = mapped.map(|ip| net::SocketAddr::new(ip, bind_addr.port()));
+        if self.is_bound(bind_addr) || mapped.map_or(false, |mapped| self.is_bound(mapped)) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let listener = self.next_listener_id;
+        self.next_listener_id += 1;
+        if ephemeral {
+            self.ephemeral_listeners.insert(listener, bind_addr);
+        }
+        let (rx, queued) = self.claim_endpoint(bind_addr, listener, options.clone());
+        let host = self.hosts.host_of(bind_addr.ip());
+        let resources = self.resources_of(bind_addr.ip());
+        let mut incoming = Incoming::new(rx, queued, listener, host, resources, inner);
+        if let Some(mapped) = mapped {
+            let (rx, queued) = self.claim_endpoint(mapped, listener, options);
+            incoming = incoming.with_mapped(rx, queued);
+        }
+        Ok(Listener::new(bind_addr, incoming, self.handle.clone()))
+    }
+
+    /// Marks an endpoint which is not bound as bound, returning the queue of connections made
+    /// to it, including those made before it was bound.
+    fn claim_endpoint(
+        &mut self,
+        endpoint: net::SocketAddr,
+        listener: u64,
+        options: ListenerOptions,
+    ) -> (mpsc::Receiver<Accepted>, sync::Arc<atomic::AtomicUsize>) {
+        let (tx, rx, queued) = match self.endpoints.remove(&endpoint) {
+            Some(ListenerState::Unbound { tx, rx, queued }) => (tx, rx, queued),
             _ => {
                 let (tx, rx) = mpsc::channel(1);
-                let state = ListenerState::Bound { tx };
-                self.endpoints.insert(bind_addr, state);
-                let listener = Listener::new(bind_addr, rx);
-                Ok(listener)
+                (tx, rx, sync::Arc::default())
             }
+        };
+        let state = ListenerState::Bound {
+            tx,
+            options,
+            queued: sync::Arc::clone(&queued),
+            listener,
+        };
+        self.endpoints.insert(endpoint, state);
+        self.handle
+            .events()
+            .emit(SimulationEvent::ListenerBound(endpoint));
+        self.wake_bind_wakers(endpoint);
+        (rx, queued)
+    }
+
+    /// Allocates an ephemeral port of the provided host which no listener address is using,
+    /// on the IPv4 address `mapped` of dual-stack listeners as well. Addresses whose listener
+    /// was dropped can be allocated again, while ports skipped because another address is using
+    /// them are returned to the host once a port is found.
+    fn ephemeral_endpoint(
+        &mut self,
+        addr: net::IpAddr,
+        mapped: Option<net::IpAddr>,
+    ) -> Result<net::SocketAddr, io::Error> {
+        let mut skipped = vec![];
+        let allocated = loop {
+            let port = match self.ports.allocate(addr) {
+                Ok(port) => port,
+                Err(e) => break Err(e),
+            };
+            let endpoint = net::SocketAddr::new(addr, port);
+            let mapped_free = mapped.map_or(true, |mapped| {
+                self.is_available(net::SocketAddr::new(mapped, port))
+            });
+            if self.is_available(endpoint) && mapped_free {
+                trace!("allocated ephemeral listener address {}", endpoint);
+                break Ok(endpoint);
+            }
+            skipped.push(endpoint);
+        };
+        for endpoint in skipped {
+            self.ports.free(endpoint);
+        }
+        allocated
+    }
+
+    /// Returns true if no listener is bound to the provided address and no connection is
+    /// waiting for one to be bound.
+    fn is_available(&self, addr: net::SocketAddr) -> bool {
+        match self.endpoints.get(&addr) {
+            None | Some(ListenerState::Closed) => true,
+            _ => false,
+        }
+    }
+
+    fn wake_bind_wakers(&mut self, addr: net::SocketAddr) {
+        for waker in self.bind_wakers.remove(&addr).unwrap_or_default() {
+            waker.wake()
         }
     }
 
+    /// Binds a UDP socket to the provided address, returning its id and the receiver for
+    /// datagrams sent to it.
+    pub(crate) fn bind_udp(
+        &mut self,
+        bind_addr: net::SocketAddr,
+    ) -> Result<(u64, mpsc::Receiver<Datagram>), io::Error> {
+        trace!("binding udp socket to {}", bind_addr);
+        if self.hosts.is_removed(bind_addr.ip()) {
+            return Err(io::ErrorKind::AddrNotAvailable.into());
+        }
+        self.datagrams.bind(bind_addr)
+    }
+
+    pub(crate) fn unbind_udp(&mut self, addr: net::SocketAddr, id: u64) {
+        self.datagrams.unbind(addr, id);
+    }
+
+    /// Sends a datagram from `source` to `dest`. Datagrams between unreachable hosts are dropped.
+    pub(crate) fn send_datagram(
+        &mut self,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        payload: Bytes,
+    ) {
+        if self.hosts.is_removed(source.ip())
+            || self.hosts.is_removed(dest.ip())
+            || !self.hosts.is_reachable(source.ip(), dest.ip())
+            || self.partitions.mode(source.ip(), dest.ip()).is_some()
+        {
+            trace!("dropped datagram {} -> {}, host unreachable", source, dest);
+            return;
+        }
+        self.datagrams.deliver(&self.handle, source, dest, payload);
+    }
+
+    pub(crate) fn set_datagram_faults(
+        &mut self,
+        faults: Option<DatagramFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        self.datagrams.set_faults(faults, random);
+    }
+
     /// Determines if a connection should be clogged based on the state of clogged connections.
     fn should_clog(&self, source: net::SocketAddr, dest: net::SocketAddr) -> bool {
         let source_ip = source.ip();
@@ -157,13 +1478,13 @@ impl Inner {
         let clog_source = clog.source();
         let clog_dest = clog.dest();
         self.clogged.insert(clog);
-        for connection in self.connections.iter_mut() {
+        self.connections.modify(|connection| {
             let source_ip = connection.source().ip();
             let dest_ip = connection.dest().ip();
             if source_ip == clog_source && dest_ip == clog_dest {
                 connection.clog();
             }
-        }
+        });
     }
 
     /// Unclog all new connection between two IP addresses. If there are any existing connections which
@@ -173,12 +1494,12 @@ impl Inner {
         let clog_source = unclog.source();
         let clog_dest = unclog.dest();
         self.clogged.remove(&unclog);
-        for connection in self.connections.iter_mut() {
+        self.connections.modify(|connection| {
             let source_ip = connection.source().ip();
             let dest_ip = connection.dest().ip();
             if source_ip == clog_source && dest_ip == clog_dest {
                 connection.unclog();
             }
-        }
+        });
     }
 }
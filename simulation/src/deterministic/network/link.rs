@@ -0,0 +1,218 @@
+//! Faults scoped to the link between two hosts.
+//!
+//! A [`FaultyTcpStreamHandle`] controls a single stream, which is of little use when the
+//! connections under test are opened deep inside a client library. A [`LinkFaultHandle`]
+//! instead configures the faults of the data sent from one host to another, and its settings
+//! apply to every connection between the hosts, whether it was established before or after
+//! the handle was created. Changes made through the handle take effect on the next write of
+//! each connection. Fault decisions are drawn from a stream derived from the seed for each
+//! link, so faulting one link does not disturb the decisions made for the others.
+//!
+//! [`FaultyTcpStreamHandle`]:`super::socket::FaultyTcpStreamHandle`
+//! [`LinkFaultHandle`]:`LinkFaultHandle`
+use super::fault::FaultIds;
+use crate::deterministic::DeterministicRandomHandle;
+use std::{net, sync, time};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Settings {
+    delay: time::Duration,
+    drop: f64,
+    retransmit_timeout: time::Duration,
+    reset: f64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            delay: time::Duration::from_millis(0),
+            drop: 0.0,
+            retransmit_timeout: time::Duration::from_millis(0),
+            reset: 0.0,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    settings: Settings,
+    random: DeterministicRandomHandle,
+}
+
+/// What happens to a write sent over a faulted link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct WriteFate {
+    /// Time added before the next write can be sent.
+    pub(crate) delay: time::Duration,
+    /// Whether the connection is reset once the write has been sent.
+    pub(crate) reset: bool,
+}
+
+/// Faults of the data sent from one host to another, shared by the streams sending over the
+/// link.
+#[derive(Debug, Clone)]
+pub(crate) struct LinkFaults {
+    state: sync::Arc<sync::Mutex<State>>,
+    fault_ids: FaultIds,
+}
+
+impl LinkFaults {
+    pub(crate) fn new(random: DeterministicRandomHandle, fault_ids: FaultIds) -> Self {
+        let state = State {
+            settings: Settings::default(),
+            random,
+        };
+        Self {
+            state: sync::Arc::new(sync::Mutex::new(state)),
+            fault_ids,
+        }
+    }
+
+    pub(crate) fn fault_ids(&self) -> &FaultIds {
+        &self.fault_ids
+    }
+
+    /// Decides the fate of a write sent over the link.
+    pub(crate) fn on_write(&self) -> WriteFate {
+        let state = self.state.lock().unwrap();
+        let settings = state.settings;
+        let mut delay = settings.delay;
+        if state.random.should_fault(settings.drop) {
+            delay += settings.retransmit_timeout;
+        }
+        WriteFate {
+            delay,
+            reset: state.random.should_fault(settings.reset),
+        }
+    }
+
+    fn update<F>(&self, f: F)
+    where
+        F: FnOnce(&mut Settings),
+    {
+        f(&mut self.state.lock().unwrap().settings);
+    }
+}
+
+/// Handle configuring the faults of the data sent from `source` to `dest`, see
+/// [`DeterministicRuntime::fault_link`]. Settings apply to every current and future connection
+/// between the hosts, and remain in place after the handle is dropped.
+///
+/// [`DeterministicRuntime::fault_link`]:`crate::deterministic::DeterministicRuntime::fault_link`
+#[derive(Debug, Clone)]
+pub struct LinkFaultHandle {
+    source: net::IpAddr,
+    dest: net::IpAddr,
+    faults: LinkFaults,
+}
+
+impl LinkFaultHandle {
+    pub(crate) fn new(source: net::IpAddr, dest: net::IpAddr, faults: LinkFaults) -> Self {
+        Self {
+            source,
+            dest,
+            faults,
+        }
+    }
+
+    pub fn source(&self) -> net::IpAddr {
+        self.source
+    }
+
+    pub fn dest(&self) -> net::IpAddr {
+        self.dest
+    }
+
+    /// Delays each write sent over the link by `delay`, on top of any other latency.
+    pub fn set_delay(&self, delay: time::Duration) {
+        self.faults.update(|settings| settings.delay = delay);
+    }
+
+    /// Drops writes sent over the link with the provided probability. As connections are
+    /// reliable, a dropped write is retransmitted after `retransmit_timeout`.
+    pub fn set_drop_probability(&self, probability: f64, retransmit_timeout: time::Duration) {
+        self.faults.update(|settings| {
+            settings.drop = probability;
+            settings.retransmit_timeout = retransmit_timeout;
+        });
+    }
+
+    /// Resets the connection after a write sent over the link with the provided probability.
+    /// Pending and further reads and writes on both halves fail with `ConnectionReset`.
+    pub fn set_reset_probability(&self, probability: f64) {
+        self.faults.update(|settings| settings.reset = probability);
+    }
+
+    /// Removes every fault of the link.
+    pub fn clear(&self) {
+        self.faults
+            .update(|settings| *settings = Settings::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{DeterministicRuntime, DeterministicRuntimeHandle, Socket};
+    use crate::{Environment, TcpListener};
+    use std::{io, net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Writes three single byte messages to `socket`, returning the virtual time taken to flush
+    /// them.
+    async fn send(handle: &DeterministicRuntimeHandle, socket: &mut Socket) -> time::Duration {
+        let start = handle.now();
+        for _ in 0..3 {
+            socket.write_all(b"x").await.unwrap();
+        }
+        socket.flush().await.unwrap();
+        handle.now() - start
+    }
+
+    #[test]
+    /// Test that the faults of a link apply to connections established before and after the
+    /// link was faulted, and that clearing them restores the link.
+    fn fault_link() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let a: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let handle_a = runtime.handle(a);
+        let handle_b = runtime.handle(b);
+        runtime.block_on(async {
+            let addr = net::SocketAddr::new(b, 9092);
+            let mut listener = handle_b.bind(addr).await.unwrap();
+            let server = handle_b.clone();
+            handle_b.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    server.spawn(async move {
+                        let mut buf = [0u8; 16];
+                        while let Ok(n) = socket.read(&mut buf).await {
+                            if n == 0 {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+
+            let mut existing = handle_a.connect(addr).await.unwrap();
+            let link = handle_a.fault_link(a, b);
+            assert_eq!((link.source(), link.dest()), (a, b));
+            link.set_delay(time::Duration::from_secs(1));
+            let elapsed = send(&handle_a, &mut existing).await;
+            assert!(elapsed >= time::Duration::from_secs(2), "{:?}", elapsed);
+            let mut new = handle_a.connect(addr).await.unwrap();
+            let elapsed = send(&handle_a, &mut new).await;
+            assert!(elapsed >= time::Duration::from_secs(2), "{:?}", elapsed);
+
+            link.clear();
+            let elapsed = send(&handle_a, &mut existing).await;
+            assert!(elapsed < time::Duration::from_secs(1), "{:?}", elapsed);
+
+            handle_a.fault_link(a, b).set_reset_probability(1.0);
+            new.write_all(b"x").await.unwrap();
+            let mut buf = [0u8; 1];
+            let err = new.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        });
+    }
+}
@@ -1,26 +1,344 @@
-use super::{FaultyTcpStream, SocketHalf};
-use crate::TcpStream;
+use super::resources::{self, Descriptor, HostResources};
+use super::{hosts, ConnectionIdExt, FaultyTcpStream, Inner, SocketHalf};
+use crate::deterministic::events::{Events, SimulationEvent};
+use crate::deterministic::task::{self, WaitResource};
+use crate::deterministic::{DeterministicRandomHandle, DeterministicTimeHandle};
 use async_trait::async_trait;
-use futures::{channel::mpsc, Poll, Stream, StreamExt};
-use std::{fmt, io, net, pin::Pin, task::Context};
+use futures::{channel::mpsc, Future, FutureExt, Poll, Stream, StreamExt};
+use std::{
+    fmt, io, net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+    time,
+};
+use tokio::timer::Delay;
 use tracing::trace;
 
+/// Policy applied to new connections once a listener has reached its maximum number of
+/// live connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitPolicy {
+    /// Refuse new connections with `ConnectionRefused`.
+    Refuse,
+    /// Queue new connections until an existing connection is dropped.
+    Queue,
+}
+
+impl Default for LimitPolicy {
+    fn default() -> Self {
+        LimitPolicy::Refuse
+    }
+}
+
+/// Options which control how a listener accepts new connections.
+#[derive(Debug, Clone, Default)]
+pub struct ListenerOptions {
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) limit_policy: LimitPolicy,
+    pub(crate) backlog: Option<usize>,
+    pub(crate) only_v6: bool,
+}
+
+impl ListenerOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the number of live connections originating from the listener. Once the cap is
+    /// reached, new connections are handled according to the provided `LimitPolicy`.
+    pub fn max_connections(mut self, max: usize, policy: LimitPolicy) -> Self {
+        self.max_connections.replace(max);
+        self.limit_policy = policy;
+        self
+    }
+
+    /// Caps the number of established connections waiting to be accepted, as with the backlog
+    /// passed to `listen`. Once the backlog is full, new connections are refused with
+    /// `ConnectionRefused` until the listener accepts a queued connection.
+    pub fn backlog(mut self, backlog: usize) -> Self {
+        self.backlog.replace(backlog);
+        self
+    }
+
+    /// Restricts a listener bound to the IPv6 wildcard address to IPv6 connections, as with
+    /// `IPV6_V6ONLY`. By default such listeners are dual-stack, also accepting connections to
+    /// the IPv4 address of their host on the same port and reporting their addresses as
+    /// IPv4-mapped IPv6 addresses.
+    pub fn only_v6(mut self, only_v6: bool) -> Self {
+        self.only_v6 = only_v6;
+        self
+    }
+}
+
+/// Faults injected into the accepts of the listeners of a host, modeling servers which are slow
+/// to accept or overloaded. Clients of such servers see connects succeed, and then wait for
+/// responses which arrive late or never.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AcceptFaults {
+    /// Simulated time an accept takes to yield each connection once it takes the connection from
+    /// the queue of the listener.
+    pub accept_delay: time::Duration,
+    /// Probability that an accept fails with `EMFILE`, as when the process has transiently run
+    /// out of descriptors. The connection remains queued for the next accept.
+    pub accept_error: f64,
+    /// Number of connections which can wait to be accepted before the listener is overloaded.
+    /// Accepts of an overloaded listener reset and drop the oldest queued connections until the
+    /// queue is back within the limit, as when a server sheds load.
+    pub overload_queue: Option<usize>,
+}
+
+impl Default for AcceptFaults {
+    fn default() -> Self {
+        Self {
+            accept_delay: time::Duration::from_millis(0),
+            accept_error: 0.0,
+            overload_queue: None,
+        }
+    }
+}
+
+/// Accept faults of a host, along with the source of their fault decisions.
+pub(crate) type AcceptFaultState = (AcceptFaults, DeterministicRandomHandle);
+
+/// Snapshot of a listener address and the connections established to it. With the `serde`
+/// feature enabled, snapshots can be serialized and compared against golden files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ListenerInfo {
+    pub(crate) addr: net::SocketAddr,
+    pub(crate) bound: bool,
+    pub(crate) connections: usize,
+    pub(crate) max_connections: Option<usize>,
+}
+
+impl ListenerInfo {
+    pub fn addr(&self) -> net::SocketAddr {
+        self.addr
+    }
+
+    /// Returns true if a listener is accepting connections on the address. Connections made
+    /// while the address is unbound are queued until a listener is bound.
+    pub fn is_bound(&self) -> bool {
+        self.bound
+    }
+
+    /// Returns the number of open connections established to the address.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Returns the cap on live connections configured for the listener, if any.
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+}
+
+/// A connection delivered to a listener, along with the address of the connecting peer taken
+/// from the connection's record in the network.
+pub(crate) type Accepted = (FaultyTcpStream<SocketHalf>, net::SocketAddr);
+
+/// The connections delivered to a listener. Dual-stack listeners are also delivered the
+/// connections made to the IPv4 address of their host, whose addresses are reported as
+/// IPv4-mapped IPv6 addresses once accepted. Each queue tracks the connections delivered to it
+/// which have not been accepted. Dropping the queues closes the addresses of the listener.
+pub(crate) struct Incoming {
+    native: (mpsc::Receiver<Accepted>, sync::Arc<atomic::AtomicUsize>),
+    mapped: Option<(mpsc::Receiver<Accepted>, sync::Arc<atomic::AtomicUsize>)>,
+    listener: u64,
+    /// Host of the listener, whose accept faults apply to its accepts.
+    host: net::IpAddr,
+    /// Resources of the host of the listener, which accepted connections take a descriptor of.
+    resources: HostResources,
+    inner: sync::Weak<sync::Mutex<Inner>>,
+}
+
+impl Incoming {
+    pub(crate) fn new(
+        rx: mpsc::Receiver<Accepted>,
+        queued: sync::Arc<atomic::AtomicUsize>,
+        listener: u64,
+        host: net::IpAddr,
+        resources: HostResources,
+        inner: sync::Weak<sync::Mutex<Inner>>,
+    ) -> Self {
+        Self {
+            native: (rx, queued),
+            mapped: None,
+            listener,
+            host,
+            resources,
+            inner,
+        }
+    }
+
+    /// Also accepts the connections delivered to the IPv4 address of a dual-stack listener.
+    pub(crate) fn with_mapped(
+        mut self,
+        rx: mpsc::Receiver<Accepted>,
+        queued: sync::Arc<atomic::AtomicUsize>,
+    ) -> Self {
+        self.mapped.replace((rx, queued));
+        self
+    }
+
+    /// Takes a descriptor for the next accepted connection, failing with `EMFILE` while the
+    /// host has none left without taking a connection from the queue.
+    fn open_descriptor(&self) -> io::Result<Descriptor> {
+        self.resources.open()
+    }
+
+    /// Returns the accept faults of the host of the listener, if any.
+    fn accept_faults(&self) -> Option<AcceptFaultState> {
+        let inner = self.inner.upgrade()?;
+        let lock = inner.lock().unwrap();
+        lock.accept_faults(self.host)
+    }
+
+    /// Returns the number of connections waiting to be accepted.
+    fn queued(&self) -> usize {
+        let mapped = self.mapped.as_ref();
+        self.native.1.load(atomic::Ordering::SeqCst)
+            + mapped.map_or(0, |(_, queued)| queued.load(atomic::Ordering::SeqCst))
+    }
+
+    /// Polls for the next connection, returning `None` once every queue has closed.
+    fn poll_next(&mut self, cx: &mut Context<'_>) -> Poll<Option<Accepted>> {
+        let native = match self.native.0.poll_next_unpin(cx) {
+            Poll::Ready(Some(accepted)) => {
+                self.native.1.fetch_sub(1, atomic::Ordering::SeqCst);
+                return Poll::Ready(Some(accepted));
+            }
+            poll => poll,
+        };
+        let mapped = match self.mapped.as_mut() {
+            Some((rx, queued)) => match rx.poll_next_unpin(cx) {
+                Poll::Ready(Some((stream, peer))) => {
+                    queued.fetch_sub(1, atomic::Ordering::SeqCst);
+                    let local = crate::TcpStream::local_addr(&stream)
+                        .expect("simulated streams have a local address");
+                    let peer = hosts::mapped(peer);
+                    stream.report_addrs(hosts::mapped(local), peer);
+                    return Poll::Ready(Some((stream, peer)));
+                }
+                poll => poll,
+            },
+            None => Poll::Ready(None),
+        };
+        match (native, mapped) {
+            (Poll::Ready(None), Poll::Ready(None)) => Poll::Ready(None),
+            _ => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for Incoming {
+    fn drop(&mut self) {
+        // Close the queues first, so connects waiting on a full queue are refused.
+        self.native.0.close();
+        if let Some((rx, _)) = self.mapped.as_mut() {
+            rx.close();
+        }
+        if let Some(inner) = self.inner.upgrade() {
+            if let Ok(mut inner) = inner.lock() {
+                inner.close_listener(self.listener);
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 /// ListenerState represents both the bound and unbound state of a Listener.
 /// This allows supporting late binding of Listeners to sockets.
+/// Both states track the number of connections delivered to the listener which it has not yet
+/// accepted.
 pub(crate) enum ListenerState {
     Unbound {
-        tx: mpsc::Sender<FaultyTcpStream<SocketHalf>>,
-        rx: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+        tx: mpsc::Sender<Accepted>,
+        rx: mpsc::Receiver<Accepted>,
+        queued: sync::Arc<atomic::AtomicUsize>,
     },
     Bound {
-        tx: mpsc::Sender<FaultyTcpStream<SocketHalf>>,
+        tx: mpsc::Sender<Accepted>,
+        options: ListenerOptions,
+        queued: sync::Arc<atomic::AtomicUsize>,
+        /// Identifies the listener bound to the address, which is shared by both addresses of
+        /// a dual-stack listener.
+        listener: u64,
     },
+    /// The listener bound to the address was dropped. Connections are refused until another
+    /// listener is bound.
+    Closed,
+}
+
+/// Future which resolves once a connection to `dest` would not exceed the maximum number of
+/// live connections configured for the listener.
+pub(crate) struct ConnectionSlot {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    dest: net::SocketAddr,
+}
+
+impl ConnectionSlot {
+    pub(crate) fn new(inner: sync::Arc<sync::Mutex<Inner>>, dest: net::SocketAddr) -> Self {
+        Self { inner, dest }
+    }
+}
+
+impl Future for ConnectionSlot {
+    type Output = Result<(), io::Error>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut lock = self.inner.lock().unwrap();
+        let poll = lock.poll_connection_slot(cx, self.dest);
+        if poll.is_pending() {
+            task::wait_on(WaitResource::Listener(self.dest));
+        }
+        poll
+    }
+}
+
+/// Future which resolves once a listener is bound to `dest`, failing with `ConnectionRefused`
+/// if no listener is bound before the grace period configured for unbound addresses elapses.
+pub(crate) struct BindGrace {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    dest: net::SocketAddr,
+    grace: Delay,
+}
+
+impl BindGrace {
+    pub(crate) fn new(
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        dest: net::SocketAddr,
+        grace: Delay,
+    ) -> Self {
+        Self { inner, dest, grace }
+    }
+}
+
+impl Future for BindGrace {
+    type Output = Result<(), io::Error>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut lock = this.inner.lock().unwrap();
+        if lock.is_bound(this.dest) {
+            return Poll::Ready(Ok(()));
+        }
+        if this.grace.poll_unpin(cx).is_ready() {
+            return Poll::Ready(Err(lock.refuse_unbound(this.dest)));
+        }
+        lock.register_bind_waker(this.dest, cx.waker());
+        task::wait_on(WaitResource::Listener(this.dest));
+        Poll::Pending
+    }
 }
 
 pub struct Listener {
     local_addr: net::SocketAddr,
-    incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+    incoming: Incoming,
+    /// Connection taken from the queue which has not been yielded by an accept yet, along with
+    /// the delay it is yielded after once an accept is slowed by accept faults.
+    pending: Option<(Accepted, Option<Delay>)>,
+    time_handle: DeterministicTimeHandle,
+    events: Events,
 }
 
 impl fmt::Debug for Listener {
@@ -31,15 +349,26 @@ impl fmt::Debug for Listener {
 }
 
 impl Listener {
-    pub fn new(
+    pub(crate) fn new(
         local_addr: net::SocketAddr,
-        incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+        incoming: Incoming,
+        time_handle: DeterministicTimeHandle,
     ) -> Self {
+        let events = time_handle.events().clone();
         Self {
             local_addr,
             incoming,
+            pending: None,
+            time_handle,
+            events,
         }
     }
+
+    /// Reports the provided address as the address of the listener, as when bound to the
+    /// wildcard address.
+    pub(crate) fn set_local_ip(&mut self, ip: net::IpAddr) {
+        self.local_addr.set_ip(ip);
+    }
 }
 
 impl Listener {
@@ -48,28 +377,105 @@ impl Listener {
     async fn accept(
         &mut self,
     ) -> Result<(FaultyTcpStream<SocketHalf>, net::SocketAddr), io::Error> {
-        if let Some(next) = self.incoming.next().await {
-            let addr = next.peer_addr()?;
-            trace!("accepted new connection from {}", addr);
-            Ok((next, addr))
-        } else {
-            trace!("listener no longer connected");
-            Err(io::ErrorKind::NotConnected.into())
+        let local_addr = self.local_addr;
+        let next = futures::future::poll_fn(|cx| {
+            task::hold(WaitResource::Listener(local_addr));
+            let poll = self.poll_accept(cx);
+            if poll.is_pending() {
+                task::wait_on(WaitResource::Accept(local_addr));
+            }
+            poll
+        })
+        .await;
+        match next {
+            Some(accepted) => accepted,
+            None => {
+                trace!("listener no longer connected");
+                Err(io::ErrorKind::NotConnected.into())
+            }
         }
     }
+
+    /// Polls for the next accepted connection, applying the accept faults of the host of the
+    /// listener. Returns `None` once the listener is no longer connected to the network.
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<Accepted>>> {
+        let descriptor = match self.incoming.open_descriptor() {
+            Ok(descriptor) => descriptor,
+            Err(e) => return Poll::Ready(Some(Err(e))),
+        };
+        let faults = self.incoming.accept_faults();
+        if self.pending.is_none() {
+            let accepted = loop {
+                let accepted = match futures::ready!(self.incoming.poll_next(cx)) {
+                    Some(accepted) => accepted,
+                    None => return Poll::Ready(None),
+                };
+                let overload = faults
+                    .as_ref()
+                    .and_then(|(faults, _)| faults.overload_queue);
+                match overload {
+                    Some(max) if self.incoming.queued() >= max => {
+                        trace!(
+                            "dropping connection from {}, listener is overloaded",
+                            accepted.1
+                        );
+                        accepted.0.fault_handle().reset_now();
+                    }
+                    _ => break accepted,
+                }
+            };
+            if let Some((faults, random)) = faults.as_ref() {
+                if random.should_fault(faults.accept_error) {
+                    trace!("injecting accept error on {}", self.local_addr);
+                    self.pending.replace((accepted, None));
+                    return Poll::Ready(Some(Err(io::Error::from_raw_os_error(resources::EMFILE))));
+                }
+            }
+            self.pending.replace((accepted, None));
+        }
+        let accept_delay = faults.map_or(time::Duration::from_millis(0), |(f, _)| f.accept_delay);
+        if let Some((_, delay)) = self.pending.as_mut() {
+            if delay.is_none() && accept_delay > time::Duration::from_millis(0) {
+                delay.replace(
+                    self.time_handle
+                        .delay(self.time_handle.now() + accept_delay),
+                );
+            }
+            if let Some(delay) = delay.as_mut() {
+                futures::ready!(delay.poll_unpin(cx));
+            }
+        }
+        let ((mut stream, peer), _) = self.pending.take().expect("a connection is pending");
+        trace!("accepted new connection from {}", peer);
+        stream.get_mut().set_descriptor(descriptor);
+        emit_accepted(&self.events, &stream, self.local_addr, peer);
+        Poll::Ready(Some(Ok((stream, peer))))
+    }
 }
 
-struct ListenerStream {
-    incoming: mpsc::Receiver<FaultyTcpStream<SocketHalf>>,
+/// Emits the acceptance of a connection by the listener bound to `local`.
+fn emit_accepted(
+    events: &Events,
+    stream: &FaultyTcpStream<SocketHalf>,
+    local: net::SocketAddr,
+    peer: net::SocketAddr,
+) {
+    events.emit(SimulationEvent::ConnectionAccepted {
+        id: stream.connection_id(),
+        local,
+        peer,
+    });
 }
 
-impl Stream for ListenerStream {
-    type Item = Result<FaultyTcpStream<SocketHalf>, io::Error>;
+/// Accepted connections along with the address of their peer, ending once the listener is no
+/// longer connected to the network. The fault handle of each connection is available from
+/// [`FaultyTcpStream::fault_handle`] as soon as it is accepted.
+///
+/// [`FaultyTcpStream::fault_handle`]:`FaultyTcpStream::fault_handle`
+impl Stream for Listener {
+    type Item = Result<(FaultyTcpStream<SocketHalf>, net::SocketAddr), io::Error>;
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        match futures::ready!(self.incoming.poll_next_unpin(cx)) {
-            Some(item) => Poll::Ready(Some(Ok(item))),
-            None => Poll::Ready(None),
-        }
+        self.poll_accept(cx)
     }
 }
 
@@ -89,7 +495,6 @@ impl crate::TcpListener for Listener {
         Ok(())
     }
     fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>> {
-        let Listener { incoming, .. } = self;
-        Box::pin(ListenerStream { incoming })
+        Box::pin(self.map(|accepted| accepted.map(|(stream, _)| stream)))
     }
 }
@@ -5,15 +5,46 @@
 //!
 //! The network can inject partitions between machines.
 
-use std::{io, net, sync};
+use std::{io, net, sync, time};
+mod bandwidth;
+mod connect_limit;
 pub(crate) mod fault;
+mod hosts;
 mod inner;
+mod link;
 mod listen;
+mod partition;
+mod ports;
+mod profile;
+mod resources;
 pub(crate) mod socket;
+mod table;
+mod tap;
+mod udp;
+mod unconsumed;
+use fault::FaultPermit;
+pub use fault::{
+    ConnectionId, ConnectionInfo, ConnectionSide, FaultAction, FaultBudget, FaultId, FaultKind,
+    FaultProvenance, FaultProvenanceExt, FaultSchedule, FaultScheduleBuilder, FaultTarget,
+    ScheduledFault,
+};
+pub use hosts::MigrationPolicy;
 pub(crate) use inner::Inner;
-pub use listen::Listener;
-use listen::ListenerState;
+pub use link::LinkFaultHandle;
+use listen::{AcceptFaultState, Accepted, BindGrace, ConnectionSlot, Incoming, ListenerState};
+pub use listen::{AcceptFaults, LimitPolicy, Listener, ListenerInfo, ListenerOptions};
+pub use partition::PartitionMode;
+pub use ports::ConnectOptions;
+use profile::LinkConditions;
+pub use profile::{LatencyModel, NetworkProfile};
+pub use resources::{ResourceLimits, ResourceUsage};
+pub use socket::{
+    ConnectionIdExt, FaultyTcpStreamHandle, InjectionPoint, Keepalive, SegmentFaults, SocketBuffers,
+};
 use socket::{FaultyTcpStream, SocketHalf};
+pub use tap::{MessageTap, TappedMessage};
+pub use udp::{DatagramFaults, UdpSocket};
+pub use unconsumed::{UnconsumedData, UnconsumedDataPolicy};
 
 pub type Socket = FaultyTcpStream<SocketHalf>;
 pub struct DeterministicNetwork {
@@ -32,7 +63,225 @@ impl DeterministicNetwork {
     where
         T: Into<net::IpAddr>,
     {
-        DeterministicNetworkHandle::new(local_addr.into(), sync::Arc::clone(&self.inner))
+        let local_addr = local_addr.into();
+        self.inner.lock().unwrap().register_host(local_addr);
+        DeterministicNetworkHandle::new(local_addr, sync::Arc::clone(&self.inner))
+    }
+
+    /// Sets the TIME_WAIT duration for closed connections. Connections which have been closed
+    /// keep their source address occupied until the TIME_WAIT duration has elapsed, causing
+    /// new connections to be assigned a different source port.
+    pub fn set_time_wait(&self, time_wait: Option<time::Duration>) {
+        self.inner.lock().unwrap().set_time_wait(time_wait);
+    }
+
+    /// Limits the bytes each half of new connections can write before its peer reads, see
+    /// [`SocketBuffers`]. By default writes are buffered without limit.
+    ///
+    /// [`SocketBuffers`]:`SocketBuffers`
+    pub fn set_socket_buffers(&self, buffers: Option<SocketBuffers>) {
+        self.inner.lock().unwrap().set_socket_buffers(buffers);
+    }
+
+    /// Limits the connections and memory of the provided host, see [`ResourceLimits`].
+    /// Passing `None` removes the limits.
+    ///
+    /// [`ResourceLimits`]:`ResourceLimits`
+    pub fn set_resource_limits(&self, host: net::IpAddr, limits: Option<ResourceLimits>) {
+        self.inner.lock().unwrap().set_resource_limits(host, limits);
+    }
+
+    /// Applies the provided faults to the accepts of the listeners of `host`, sampling fault
+    /// decisions from `random`. Passing `None` removes the faults.
+    pub(crate) fn set_accept_faults(
+        &self,
+        host: net::IpAddr,
+        faults: Option<AcceptFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_accept_faults(host, faults, random);
+    }
+
+    /// Refuses connections to addresses without a bound listener with `ConnectionRefused` once
+    /// `grace` has elapsed without a listener being bound. By default, connections to unbound
+    /// addresses wait for a listener to be bound indefinitely.
+    pub fn set_unbound_grace(&self, grace: Option<time::Duration>) {
+        self.inner.lock().unwrap().set_unbound_grace(grace);
+    }
+
+    /// Sets the range of source ports allocated to outgoing connections. Applies to hosts
+    /// which have not yet made a connection.
+    pub fn set_ephemeral_ports(&self, range: std::ops::RangeInclusive<u16>) {
+        self.inner.lock().unwrap().ports.set_range(range);
+    }
+
+    /// Arms connection crossing for the provided window. While armed, the server halves of two
+    /// connections to the same listener established within the window are swapped.
+    pub fn set_crossing_window(&self, window: Option<time::Duration>) {
+        self.inner.lock().unwrap().set_crossing_window(window);
+    }
+
+    /// Applies the provided profile to every connection which does not have a link specific
+    /// profile, sampling delays from `random`.
+    pub(crate) fn set_profile(
+        &self,
+        profile: Option<NetworkProfile>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let conditions = profile.map(|profile| LinkConditions::new(profile, random));
+        self.inner.lock().unwrap().set_conditions(conditions);
+    }
+
+    /// Applies the provided segment faults to the bytes received by every connection, drawing
+    /// segments from `random`.
+    pub(crate) fn set_segment_faults(
+        &self,
+        faults: Option<SegmentFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_segment_faults(faults, random);
+    }
+
+    /// Applies the provided faults to datagrams sent between UDP sockets, sampling from `random`.
+    pub(crate) fn set_datagram_faults(
+        &self,
+        faults: Option<DatagramFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_datagram_faults(faults, random);
+    }
+
+    /// Applies the provided profile to connections between hosts `a` and `b`, sampling delays
+    /// from `random`.
+    pub(crate) fn set_link_profile(
+        &self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        profile: Option<NetworkProfile>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let conditions = profile.map(|profile| LinkConditions::new(profile, random));
+        self.inner
+            .lock()
+            .unwrap()
+            .set_link_conditions(a, b, conditions);
+    }
+
+    /// Limits the bandwidth of the link between hosts `a` and `b`, shared by every connection
+    /// between them.
+    pub(crate) fn set_link_bandwidth(
+        &self,
+        a: net::IpAddr,
+        b: net::IpAddr,
+        bytes_per_second: Option<u64>,
+    ) {
+        self.inner
+            .lock()
+            .unwrap()
+            .set_link_bandwidth(a, b, bytes_per_second);
+    }
+
+    /// Applies the provided profile to data sent from `source` to `dest`, sampling delays from
+    /// `random`.
+    pub(crate) fn set_directed_link_profile(
+        &self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        profile: Option<NetworkProfile>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let conditions = profile.map(|profile| LinkConditions::new(profile, random));
+        self.inner
+            .lock()
+            .unwrap()
+            .set_directed_link_conditions(source, dest, conditions);
+    }
+
+    /// Returns a handle configuring the faults of the data sent from `source` to `dest`, drawing
+    /// fault decisions from `random` if the link has not been faulted before.
+    pub(crate) fn fault_link(
+        &self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) -> LinkFaultHandle {
+        let faults = self.inner.lock().unwrap().fault_link(source, dest, random);
+        LinkFaultHandle::new(source, dest, faults)
+    }
+
+    /// Taps connections to the provided listener address established from now on, decoding the
+    /// bytes written in either direction with `codec`.
+    pub fn tap<C>(&self, addr: net::SocketAddr, codec: C) -> MessageTap<C::Item>
+    where
+        C: tokio::codec::Decoder + Clone + Send + 'static,
+        C::Item: Send + 'static,
+    {
+        let (tap, stream_tap) = tap::new_tap(codec);
+        self.inner.lock().unwrap().add_tap(addr, stream_tap);
+        tap
+    }
+
+    /// Caps the number of connects in progress across the whole network. Connects beyond the
+    /// cap wait for an in-progress connect to complete, and are then delayed by `queue_delay`.
+    pub fn set_connect_limit(&self, max: Option<usize>, queue_delay: time::Duration) {
+        self.inner
+            .lock()
+            .unwrap()
+            .connect_limit
+            .set(max, queue_delay);
+    }
+
+    /// Returns the number of connects which were queued behind the connect limit.
+    pub fn queued_connects(&self) -> u64 {
+        self.inner.lock().unwrap().connect_limit.queued()
+    }
+
+    /// Installs a fault budget, constraining every fault applied to the network.
+    pub fn set_fault_budget(&self, budget: FaultBudget) {
+        let lock = self.inner.lock().unwrap();
+        lock.budget.set(budget, lock.now());
+    }
+
+    /// Begins recording the faults applied to the network.
+    pub fn record_faults(&self) {
+        self.inner.lock().unwrap().record_faults();
+    }
+
+    /// Returns the faults applied to the network since recording began.
+    pub fn fault_schedule(&self) -> FaultSchedule {
+        self.inner.lock().unwrap().fault_schedule()
+    }
+
+    /// Sets the action taken when a connection is closed with unread bytes.
+    pub fn set_unconsumed_data_policy(&self, policy: UnconsumedDataPolicy) {
+        self.inner.lock().unwrap().close_monitor.set_policy(policy);
+    }
+
+    /// Returns the connection halves which were closed with unread bytes, if recording is
+    /// enabled by the unconsumed data policy.
+    pub fn unconsumed_data(&self) -> Vec<UnconsumedData> {
+        self.inner.lock().unwrap().close_monitor.records()
+    }
+
+    pub(crate) fn watermarks(&self) -> crate::deterministic::watermark::Watermarks {
+        self.inner.lock().unwrap().watermarks.clone()
+    }
+
+    pub(crate) fn bound_listeners(&self) -> Vec<net::SocketAddr> {
+        self.inner.lock().unwrap().bound_listeners()
+    }
+
+    pub(crate) fn open_connections(&self) -> Vec<(net::SocketAddr, net::SocketAddr)> {
+        self.inner.lock().unwrap().open_connections()
     }
 
     pub(crate) fn clone_inner(&self) -> sync::Arc<sync::Mutex<Inner>> {
@@ -54,30 +303,268 @@ impl DeterministicNetworkHandle {
         DeterministicNetworkHandle { local_addr, inner }
     }
 
-    pub async fn bind(&self, mut bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
-        bind_addr.set_ip(self.local_addr);
+    pub async fn bind(&self, bind_addr: net::SocketAddr) -> Result<Listener, io::Error> {
+        self.bind_with_options(bind_addr, ListenerOptions::default())
+            .await
+    }
+
+    /// Binds a listener to the provided port of this host, on the address of the host in the
+    /// family of the provided address. Binding to port 0 allocates an unused port, and a
+    /// listener bound to the wildcard address reports the wildcard address as its local
+    /// address. Listeners bound to the IPv6 wildcard address are dual-stack unless configured
+    /// with `only_v6`. Binding fails with `AddrNotAvailable` if the host has no address in the
+    /// family.
+    pub async fn bind_with_options(
+        &self,
+        mut bind_addr: net::SocketAddr,
+        options: ListenerOptions,
+    ) -> Result<Listener, io::Error> {
+        let requested = bind_addr.ip();
         let mut lock = self.inner.lock().unwrap();
-        lock.listen(bind_addr)
+        let host = lock.current_address(self.local_addr);
+        let ip = hosts::address_in(host, requested).ok_or(io::ErrorKind::AddrNotAvailable)?;
+        bind_addr.set_ip(ip);
+        let mapped = match requested {
+            net::IpAddr::V6(v6) if v6.is_unspecified() && !options.only_v6 => {
+                hosts::address_in(host, net::Ipv4Addr::UNSPECIFIED.into())
+            }
+            _ => None,
+        };
+        let inner = sync::Arc::downgrade(&self.inner);
+        let mut listener = lock.listen(bind_addr, mapped, options, inner)?;
+        if requested.is_unspecified() {
+            listener.set_local_ip(requested);
+        }
+        Ok(listener)
     }
 
     pub async fn connect(
         &self,
         dest: net::SocketAddr,
     ) -> Result<FaultyTcpStream<SocketHalf>, io::Error> {
+        self.connect_with_options(dest, ConnectOptions::default())
+            .await
+    }
+
+    /// Connects to `dest`, binding the connection as configured by the provided options. See
+    /// [`ConnectOptions`].
+    ///
+    /// [`ConnectOptions`]:`ConnectOptions`
+    pub async fn connect_with_options(
+        &self,
+        dest: net::SocketAddr,
+        options: ConnectOptions,
+    ) -> Result<FaultyTcpStream<SocketHalf>, io::Error> {
+        let connect_limit = self.inner.lock().unwrap().connect_limit.clone();
+        let _permit = connect_limit.acquire().await;
+        let dest = hosts::unmapped(dest);
+        ConnectionSlot::new(sync::Arc::clone(&self.inner), dest).await?;
+        let grace = self.inner.lock().unwrap().unbound_grace(dest);
+        if let Some(grace) = grace {
+            BindGrace::new(sync::Arc::clone(&self.inner), dest, grace).await?;
+        }
         let connfut = {
             let mut lock = self.inner.lock().unwrap();
-            let ret = lock.connect(self.local_addr, dest);
+            let source = lock.current_address(self.local_addr);
+            let ret = lock.connect(source, dest, &options);
             drop(lock);
             ret
         };
         connfut.await
     }
+
+    /// Binds a UDP socket to the provided port on this host.
+    pub async fn bind_udp(&self, mut bind_addr: net::SocketAddr) -> Result<UdpSocket, io::Error> {
+        let mut lock = self.inner.lock().unwrap();
+        bind_addr.set_ip(lock.current_address(self.local_addr));
+        let (id, incoming) = lock.bind_udp(bind_addr)?;
+        drop(lock);
+        Ok(UdpSocket::new(
+            id,
+            bind_addr,
+            sync::Arc::clone(&self.inner),
+            incoming,
+        ))
+    }
+
+    pub(crate) fn watermarks(&self) -> crate::deterministic::watermark::Watermarks {
+        self.inner.lock().unwrap().watermarks.clone()
+    }
+
+    pub(crate) fn connections(&self) -> Vec<ConnectionInfo> {
+        self.inner.lock().unwrap().connection_info()
+    }
+
+    pub(crate) fn listeners(&self) -> Vec<ListenerInfo> {
+        self.inner.lock().unwrap().listener_info()
+    }
+
+    pub(crate) fn is_bound(&self, addr: net::SocketAddr) -> bool {
+        self.inner.lock().unwrap().is_bound(addr)
+    }
+
+    pub(crate) fn local_addr(&self) -> net::IpAddr {
+        self.local_addr
+    }
+
+    /// Returns the address this host currently uses, which differs from the address the handle
+    /// was scoped to once the host has migrated.
+    pub(crate) fn current_addr(&self) -> net::IpAddr {
+        self.inner.lock().unwrap().current_address(self.local_addr)
+    }
+
+    /// Returns the IPv6 address this host currently uses, if it has one.
+    pub(crate) fn current_addr_v6(&self) -> Option<net::Ipv6Addr> {
+        match hosts::address_in(self.current_addr(), net::Ipv6Addr::UNSPECIFIED.into()) {
+            Some(net::IpAddr::V6(v6)) => Some(v6),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn migrate_host(
+        &self,
+        from: net::IpAddr,
+        to: net::IpAddr,
+        policy: MigrationPolicy,
+    ) -> bool {
+        self.inner.lock().unwrap().migrate_host(from, to, policy)
+    }
+
+    /// Adds a host with a newly allocated address, returning a handle scoped to it.
+    pub(crate) fn add_host(&self) -> DeterministicNetworkHandle {
+        let addr = self.inner.lock().unwrap().add_host();
+        DeterministicNetworkHandle::new(addr, sync::Arc::clone(&self.inner))
+    }
+
+    pub(crate) fn remove_host(&self, addr: net::IpAddr) -> bool {
+        self.inner.lock().unwrap().remove_host(addr)
+    }
+
+    pub(crate) fn register_host(&self, addr: net::IpAddr) {
+        self.inner.lock().unwrap().register_host(addr);
+    }
+
+    pub(crate) fn hosts(&self) -> Vec<net::IpAddr> {
+        self.inner.lock().unwrap().hosts()
+    }
+
+    pub(crate) fn hosts_in(&self, domain: &str) -> Vec<net::IpAddr> {
+        self.inner.lock().unwrap().hosts_in(domain)
+    }
+
+    pub(crate) fn drain_host(&self, addr: net::IpAddr) -> bool {
+        self.inner.lock().unwrap().drain_host(addr)
+    }
+
+    pub(crate) fn partition(
+        &self,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+        mode: PartitionMode,
+    ) -> bool {
+        self.inner.lock().unwrap().try_partition(a, b, mode)
+    }
+
+    pub(crate) fn heal(&self) {
+        self.inner.lock().unwrap().heal();
+    }
+
+    /// Attempts to acquire a permit for a fault applied outside of the network, such as killing
+    /// a host. The fault counts as active until the permit is dropped.
+    pub(crate) fn try_acquire_fault(&self, kind: FaultKind) -> Option<FaultPermit> {
+        let lock = self.inner.lock().unwrap();
+        lock.budget.try_acquire(kind, lock.now())
+    }
+
+    /// Applies a fault to the network, recording it as with scheduled faults. Returns false if
+    /// the fault budget refused the fault.
+    pub(crate) fn apply_fault(&self, action: FaultAction) -> bool {
+        self.inner.lock().unwrap().apply_fault(action)
+    }
+
+    pub(crate) fn join_domain(&self, domain: String) {
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.join_domain(addr, domain);
+    }
+
+    pub(crate) fn leave_domain(&self, domain: &str) {
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.leave_domain(addr, domain);
+    }
+
+    pub(crate) fn domains(&self) -> Vec<String> {
+        let lock = self.inner.lock().unwrap();
+        lock.domains(lock.current_address(self.local_addr))
+    }
+
+    pub(crate) fn name_host(&self, name: String, addr: net::IpAddr) {
+        self.inner.lock().unwrap().name_host(name, addr);
+    }
+
+    pub(crate) fn resolve_host(&self, name: &str) -> Option<net::IpAddr> {
+        self.inner.lock().unwrap().resolve_host(name)
+    }
+
+    pub(crate) fn resolve_stale_host(&self, name: &str) -> Option<net::IpAddr> {
+        self.inner.lock().unwrap().resolve_stale_host(name)
+    }
+
+    /// Limits the connections and memory of this host.
+    pub(crate) fn set_resource_limits(&self, limits: Option<ResourceLimits>) {
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.set_resource_limits(addr, limits);
+    }
+
+    /// Applies the provided faults to the accepts of the listeners of this host.
+    pub(crate) fn set_accept_faults(
+        &self,
+        faults: Option<AcceptFaults>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.set_accept_faults(addr, faults, random);
+    }
+
+    pub(crate) fn resource_usage(&self) -> ResourceUsage {
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.resource_usage(addr)
+    }
+
+    /// Applies the provided profile to all connections to or from this host.
+    pub(crate) fn set_host_profile(
+        &self,
+        profile: Option<NetworkProfile>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let conditions = profile.map(|profile| LinkConditions::new(profile, random));
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.set_host_conditions(addr, conditions);
+    }
+
+    /// Applies the provided profile to connections between this host and `peer`.
+    pub(crate) fn set_link_profile(
+        &self,
+        peer: net::IpAddr,
+        profile: Option<NetworkProfile>,
+        random: crate::deterministic::DeterministicRandomHandle,
+    ) {
+        let conditions = profile.map(|profile| LinkConditions::new(profile, random));
+        let mut lock = self.inner.lock().unwrap();
+        let addr = lock.current_address(self.local_addr);
+        lock.set_link_conditions(addr, peer, conditions);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::{Environment, TcpListener};
+    use crate::{Environment, TcpListener, TcpStream};
     use futures::{SinkExt, StreamExt};
     use std::net;
     use tokio::codec::{Framed, LinesCodec};
@@ -150,6 +637,319 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Test that connections beyond a listener's cap are refused until an existing connection
+    /// is dropped.
+    fn test_max_connections_refuse() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let options = ListenerOptions::new().max_connections(1, LimitPolicy::Refuse);
+            let mut listener = server.bind_with_options(addr, options).await.unwrap();
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((conn, _)) = listener.accept().await {
+                    accepted.push(conn);
+                }
+            });
+            let first = client.connect(addr).await.unwrap();
+            let err = client.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            drop(first);
+            assert!(
+                client.connect(addr).await.is_ok(),
+                "expected connect to succeed once the first connection was dropped"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that connections beyond a listener's cap are queued until an existing connection
+    /// is dropped.
+    fn test_max_connections_queue() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let options = ListenerOptions::new().max_connections(1, LimitPolicy::Queue);
+            let mut listener = server.bind_with_options(addr, options).await.unwrap();
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((conn, _)) = listener.accept().await {
+                    accepted.push(conn);
+                }
+            });
+            let first = client.connect(addr).await.unwrap();
+            let second = client.connect(addr);
+            futures::pin_mut!(second);
+            tokio_test::assert_pending!(
+                futures::poll!(second.as_mut()),
+                "expected connect to be queued while the listener is at capacity"
+            );
+            drop(first);
+            assert!(second.await.is_ok(), "expected queued connect to complete");
+        });
+    }
+
+    #[test]
+    /// Test that connections beyond a listener's backlog are refused until the listener accepts
+    /// a queued connection.
+    fn test_backlog() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let options = ListenerOptions::new().backlog(2);
+            let mut listener = server.bind_with_options(addr, options).await.unwrap();
+            let _first = client.connect(addr).await.unwrap();
+            let _second = client.connect(addr).await.unwrap();
+            let err = client.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let _accepted = listener.accept().await.unwrap();
+            assert!(
+                client.connect(addr).await.is_ok(),
+                "expected connect to succeed once a queued connection was accepted"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that connections to an unbound address are refused once the grace period elapses,
+    /// while a listener bound within the grace period accepts the connection.
+    fn test_unbound_grace() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        network.set_unbound_grace(Some(time::Duration::from_secs(1)));
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let start = handle.now();
+            let err = client
+                .connect("10.0.0.1:9092".parse().unwrap())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            assert_eq!(handle.now() - start, time::Duration::from_secs(1));
+
+            let addr = "10.0.0.1:9093".parse().unwrap();
+            let binder = handle.clone();
+            handle.spawn(async move {
+                binder.delay_from(time::Duration::from_millis(500)).await;
+                let mut listener = server.bind(addr).await.unwrap();
+                let _accepted = listener.accept().await;
+            });
+            let start = handle.now();
+            client.connect(addr).await.unwrap();
+            assert_eq!(handle.now() - start, time::Duration::from_millis(500));
+        });
+    }
+
+    #[test]
+    /// Test that listeners bound to port 0 of the wildcard address are allocated distinct ports
+    /// and accept connections to the address of their host.
+    fn test_bind_wildcard_port_zero() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let mut v4 = server.bind("0.0.0.0:0".parse().unwrap()).await.unwrap();
+            let v6 = server.bind("[::]:0".parse().unwrap()).await.unwrap();
+            let v4_addr = v4.local_addr().unwrap();
+            let v6_addr = v6.local_addr().unwrap();
+            assert!(v4_addr.ip().is_unspecified() && v6_addr.ip().is_unspecified());
+            assert_ne!(v4_addr.port(), 0);
+            assert_ne!(v6_addr.port(), 0);
+            assert_ne!(v4_addr.port(), v6_addr.port());
+
+            let dest = net::SocketAddr::new(server.local_addr(), v4_addr.port());
+            let socket = client.connect(dest).await.unwrap();
+            let (_, peer) = v4.accept().await.unwrap();
+            assert_eq!(peer, socket.local_addr().unwrap());
+        });
+    }
+
+    #[test]
+    /// Test that hosts have separate IPv4 and IPv6 port spaces, and that listeners bound to the
+    /// IPv6 wildcard address accept IPv4 connections as IPv4-mapped addresses unless they are
+    /// restricted to IPv6.
+    fn test_dual_stack() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let server_v6 = server.current_addr_v6().unwrap();
+            assert_eq!(server_v6, "fd00::a00:1".parse::<net::Ipv6Addr>().unwrap());
+
+            let mut dual = server.bind("[::]:80".parse().unwrap()).await.unwrap();
+            let err = server
+                .bind("0.0.0.0:80".parse().unwrap())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+            let socket = client
+                .connect(net::SocketAddr::new(server_v6.into(), 80))
+                .await
+                .unwrap();
+            let (accepted, peer) = dual.accept().await.unwrap();
+            assert_eq!(peer, socket.local_addr().unwrap());
+            assert!(peer.is_ipv6());
+            assert_eq!(accepted.peer_addr().unwrap(), peer);
+
+            let socket = client
+                .connect("10.0.0.1:80".parse().unwrap())
+                .await
+                .unwrap();
+            let (accepted, peer) = dual.accept().await.unwrap();
+            let client_v4 = match socket.local_addr().unwrap().ip() {
+                net::IpAddr::V4(v4) => v4,
+                net::IpAddr::V6(_) => panic!("connected over IPv6"),
+            };
+            assert_eq!(peer.ip(), net::IpAddr::V6(client_v4.to_ipv6_mapped()));
+            assert_eq!(accepted.peer_addr().unwrap(), peer);
+            assert_eq!(
+                accepted.local_addr().unwrap(),
+                "[::ffff:10.0.0.1]:80".parse().unwrap()
+            );
+
+            let options = ListenerOptions::new().only_v6(true);
+            let v6 = "[::]:81".parse().unwrap();
+            let _v6 = server.bind_with_options(v6, options).await.unwrap();
+            let mut v4 = server.bind("0.0.0.0:81".parse().unwrap()).await.unwrap();
+            client
+                .connect("10.0.0.1:81".parse().unwrap())
+                .await
+                .unwrap();
+            assert!(v4.accept().await.unwrap().1.is_ipv4());
+
+            let v6_only = network.scoped("2001:db8::1".parse::<net::IpAddr>().unwrap());
+            let err = v6_only
+                .bind("0.0.0.0:80".parse().unwrap())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+            let err = v6_only
+                .connect("10.0.0.1:80".parse().unwrap())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+        });
+    }
+
+    #[test]
+    /// Test that dropping a listener refuses connects to its address until another listener is
+    /// bound to it.
+    fn test_rebind_dropped_listener() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let listener = server.bind(addr).await.unwrap();
+            drop(listener);
+            assert!(!server.is_bound(addr));
+            let err = client.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+            let mut listener = server.bind(addr).await.unwrap();
+            let socket = client.connect(addr).await.unwrap();
+            let (_, peer) = listener.accept().await.unwrap();
+            assert_eq!(peer, socket.local_addr().unwrap());
+        });
+    }
+
+    #[test]
+    /// Test that closed connections keep their source port occupied for the TIME_WAIT duration.
+    fn test_time_wait() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        network.set_time_wait(Some(time::Duration::from_secs(60)));
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((conn, _)) = listener.accept().await {
+                    accepted.push(conn);
+                }
+            });
+            let first = client.connect(addr).await.unwrap();
+            let first_port = first.local_addr().unwrap().port();
+            drop(first);
+            let second = client.connect(addr).await.unwrap();
+            assert_ne!(
+                second.local_addr().unwrap().port(),
+                first_port,
+                "expected source port to be occupied during TIME_WAIT"
+            );
+            handle.delay_from(time::Duration::from_secs(61)).await;
+            let third = client.connect(addr).await.unwrap();
+            assert_eq!(
+                third.local_addr().unwrap().port(),
+                first_port,
+                "expected source port to be reused after TIME_WAIT"
+            );
+        });
+    }
+
+    #[test]
+    /// Test that connections established within the crossing window have their server halves
+    /// swapped.
+    fn test_connection_crossing() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        network.set_crossing_window(Some(time::Duration::from_secs(1)));
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client1 = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let client2 = network.scoped(net::Ipv4Addr::new(10, 0, 0, 3));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            let crossed = crate::spawn_with_result(&handle, async move {
+                let mut crossed = 0;
+                for _ in 0..2 {
+                    let (conn, peer) = listener.accept().await.unwrap();
+                    let mut transport = Framed::new(conn, LinesCodec::new());
+                    let message = transport.next().await.unwrap().unwrap();
+                    if message != peer.ip().to_string() {
+                        crossed += 1;
+                    }
+                }
+                crossed
+            });
+            let (conn1, conn2) = futures::join!(client1.connect(addr), client2.connect(addr));
+            let mut transport1 = Framed::new(conn1.unwrap(), LinesCodec::new());
+            let mut transport2 = Framed::new(conn2.unwrap(), LinesCodec::new());
+            transport1.send(String::from("10.0.0.2")).await.unwrap();
+            transport2.send(String::from("10.0.0.3")).await.unwrap();
+            assert_eq!(
+                crossed.await,
+                2,
+                "expected both server halves to receive bytes from the other client"
+            );
+        });
+    }
+
     #[test]
     fn test_scoped_registration() {
         let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
@@ -169,4 +969,183 @@ mod tests {
             )
         });
     }
+
+    #[test]
+    /// Test that accepting a connection yields the address of the connecting peer.
+    fn test_accept_peer_addr() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            for _ in 0..2 {
+                let (conn, accepted) = futures::join!(client.connect(addr), listener.accept());
+                let conn = conn.unwrap();
+                let (_, peer) = accepted.unwrap();
+                assert_eq!(peer, conn.local_addr().unwrap());
+            }
+        });
+    }
+
+    #[test]
+    /// Test that a listener polled as a stream yields the address of each connecting peer, and
+    /// that faults can be injected through the fault handle of an accepted connection.
+    fn test_listener_stream() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            let (conn, accepted) = futures::join!(client.connect(addr), listener.next());
+            let conn = conn.unwrap();
+            let (accepted, peer) = accepted.unwrap().unwrap();
+            assert_eq!(peer, conn.local_addr().unwrap());
+
+            let fault_handle = accepted.fault_handle();
+            fault_handle.disconnect();
+            let mut transport = Framed::new(accepted, LinesCodec::new());
+            assert!(transport.send(String::from("hello")).await.is_err());
+            assert!(!fault_handle.is_dropped());
+            drop(transport);
+            assert!(fault_handle.is_dropped());
+        });
+    }
+
+    #[test]
+    /// Test that both halves of a connection share an id which is carried by fault provenance.
+    fn test_connection_ids() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            let mut ids = vec![];
+            let mut connections = vec![];
+            for _ in 0..2 {
+                let (conn, accepted) = futures::join!(client.connect(addr), listener.accept());
+                let conn = conn.unwrap();
+                let (accepted, _) = accepted.unwrap();
+                assert_eq!(conn.connection_id(), accepted.connection_id());
+                ids.push(conn.connection_id().unwrap());
+                connections.push((conn, accepted));
+            }
+            assert_ne!(ids[0], ids[1]);
+            let provenance = network
+                .inner
+                .lock()
+                .unwrap()
+                .connections
+                .iter()
+                .nth(1)
+                .unwrap()
+                .fault_handle(ConnectionSide::Client)
+                .disconnect();
+            assert_eq!(provenance.connection_id(), Some(ids[1]));
+            assert_eq!(ids[1].to_string(), "conn#1");
+        });
+    }
+
+    #[test]
+    /// Test that listener snapshots report live connections, and that snapshots of network state
+    /// round trip through serde.
+    fn test_listener_info() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+            let options = ListenerOptions::new().max_connections(4, LimitPolicy::Refuse);
+            let mut listener = server.bind_with_options(addr, options).await.unwrap();
+            let mut connections = vec![];
+            for _ in 0..2 {
+                let (conn, accepted) = futures::join!(client.connect(addr), listener.accept());
+                connections.push((conn.unwrap(), accepted.unwrap()));
+            }
+            drop(connections.pop());
+            let listeners = network.listeners();
+            assert_eq!(listeners.len(), 1);
+            assert_eq!(listeners[0].addr(), addr);
+            assert!(listeners[0].is_bound());
+            assert_eq!(listeners[0].connections(), 1);
+            assert_eq!(listeners[0].max_connections(), Some(4));
+
+            #[cfg(feature = "serde")]
+            {
+                let golden = r#"[{"addr":"10.0.0.1:9092","bound":true,"connections":1,"max_connections":4}]"#;
+                assert_eq!(serde_json::to_string(&listeners).unwrap(), golden);
+                let connections = network.connections();
+                let json = serde_json::to_string(&connections).unwrap();
+                let restored: Vec<ConnectionInfo> = serde_json::from_str(&json).unwrap();
+                assert_eq!(restored, connections);
+            }
+        });
+    }
+
+    #[test]
+    /// Test that accept faults delay accepted connections, fail accepts without losing the
+    /// queued connection, and reset the oldest queued connections of an overloaded listener.
+    fn test_accept_faults() {
+        use tokio::io::AsyncReadExt;
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let host = net::Ipv4Addr::new(10, 0, 0, 1);
+            let server = network.scoped(host);
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            let set_faults = |faults| {
+                network.set_accept_faults(host.into(), faults, handle.random_handle());
+            };
+
+            let delay = time::Duration::from_secs(1);
+            set_faults(Some(AcceptFaults {
+                accept_delay: delay,
+                ..AcceptFaults::default()
+            }));
+            let _delayed = client.connect(addr).await.unwrap();
+            let start = handle.now();
+            listener.accept().await.unwrap();
+            assert_eq!(handle.now() - start, delay);
+
+            set_faults(Some(AcceptFaults {
+                accept_error: 1.0,
+                ..AcceptFaults::default()
+            }));
+            let queued = client.connect(addr).await.unwrap();
+            let err = listener.accept().await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(super::resources::EMFILE));
+            set_faults(None);
+            let (accepted, _) = listener.accept().await.unwrap();
+            assert_eq!(accepted.peer_addr().unwrap(), queued.local_addr().unwrap());
+
+            set_faults(Some(AcceptFaults {
+                overload_queue: Some(1),
+                ..AcceptFaults::default()
+            }));
+            let mut shed = vec![];
+            for _ in 0..2 {
+                shed.push(client.connect(addr).await.unwrap());
+            }
+            let kept = client.connect(addr).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            assert_eq!(accepted.peer_addr().unwrap(), kept.local_addr().unwrap());
+            for mut socket in shed {
+                let err = socket.read(&mut [0u8; 1]).await.unwrap_err();
+                assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            }
+        });
+    }
 }
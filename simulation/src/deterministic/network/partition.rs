@@ -0,0 +1,179 @@
+//! Network partitions between groups of hosts.
+use super::fault::FaultProvenance;
+use std::{collections, net};
+
+/// Behavior of connections crossing a network partition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionMode {
+    /// Connections across the partition stall, as when packets are silently dropped. Reads and
+    /// writes resume once the partition heals, and new connections stall until then.
+    Stall,
+    /// Connections across the partition are reset, as when a firewall rejects packets. New
+    /// connections across the partition are refused until it heals.
+    Reset,
+}
+
+/// Ordered pairs of hosts separated by a partition.
+#[derive(Debug, Default)]
+pub(crate) struct Partitions {
+    /// The mode of each partitioned pair, along with the provenance of the partition which
+    /// separated it.
+    pairs: collections::HashMap<(net::IpAddr, net::IpAddr), (PartitionMode, FaultProvenance)>,
+}
+
+impl Partitions {
+    /// Separates every host of `a` from every host of `b` in both directions, returning the
+    /// pairs which were not already partitioned.
+    pub(crate) fn add(
+        &mut self,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+        mode: PartitionMode,
+        provenance: FaultProvenance,
+    ) -> Vec<(net::IpAddr, net::IpAddr)> {
+        let mut added = vec![];
+        for x in a {
+            for y in b.iter().filter(|y| *y != x) {
+                for pair in [(*x, *y), (*y, *x)].iter() {
+                    if !self.pairs.contains_key(pair) {
+                        self.pairs.insert(*pair, (mode, provenance.clone()));
+                        added.push(*pair);
+                    }
+                }
+            }
+        }
+        added
+    }
+
+    /// Returns the mode of the partition separating `source` from `dest`, if any.
+    pub(crate) fn mode(&self, source: net::IpAddr, dest: net::IpAddr) -> Option<PartitionMode> {
+        self.pairs.get(&(source, dest)).map(|(mode, _)| *mode)
+    }
+
+    /// Returns the provenance of the partition separating `source` from `dest`, if any.
+    pub(crate) fn provenance(
+        &self,
+        source: net::IpAddr,
+        dest: net::IpAddr,
+    ) -> Option<&FaultProvenance> {
+        self.pairs
+            .get(&(source, dest))
+            .map(|(_, provenance)| provenance)
+    }
+
+    /// Removes the partitions between the hosts of `a` and the hosts of `b`, returning the
+    /// pairs which were partitioned.
+    pub(crate) fn remove(
+        &mut self,
+        a: &[net::IpAddr],
+        b: &[net::IpAddr],
+    ) -> Vec<((net::IpAddr, net::IpAddr), PartitionMode)> {
+        let mut removed = vec![];
+        for x in a {
+            for y in b {
+                for pair in [(*x, *y), (*y, *x)].iter() {
+                    if let Some((mode, _)) = self.pairs.remove(pair) {
+                        removed.push((*pair, mode));
+                    }
+                }
+            }
+        }
+        removed
+    }
+
+    /// Removes every partition, returning the pairs which were partitioned.
+    pub(crate) fn heal(&mut self) -> Vec<((net::IpAddr, net::IpAddr), PartitionMode)> {
+        let mut healed: Vec<_> = self
+            .pairs
+            .drain()
+            .map(|(pair, (mode, _))| (pair, mode))
+            .collect();
+        healed.sort_by_key(|(pair, _)| *pair);
+        healed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::{DeterministicRuntime, FaultBudget, FaultKind, FaultProvenanceExt};
+    use crate::{Environment, TcpListener};
+    use std::{io, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that stalled partitions hold connections until healed, while reset partitions fail
+    /// existing connections and refuse new ones with errors carrying the provenance of the
+    /// partition.
+    fn partition_and_heal() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let a = handle.add_host();
+            let b = handle.add_host();
+            let b_addr = net::SocketAddr::new(b.local_addr(), 80);
+            let mut listener = b.bind(b_addr).await.unwrap();
+            let server = b.clone();
+            b.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    server.spawn(async move {
+                        let mut buf = [0u8; 1];
+                        while let Ok(1) = socket.read(&mut buf).await {
+                            if socket.write_all(&buf).await.is_err() {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+            let mut socket = a.connect(b_addr).await.unwrap();
+            let timeout = time::Duration::from_secs(10);
+
+            // The write is buffered before the partition, but the server cannot read it.
+            socket.write_all(b"x").await.unwrap();
+            handle.partition(&[a.local_addr()], &[b.local_addr()], PartitionMode::Stall);
+            let mut buf = [0u8; 1];
+            assert!(a.timeout(socket.read(&mut buf), timeout).await.is_err());
+            let mut stalled = a.connect(b_addr).await.unwrap();
+            handle.heal();
+            socket.read_exact(&mut buf).await.unwrap();
+            stalled.write_all(b"y").await.unwrap();
+            stalled.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"y");
+
+            handle.partition(&[a.local_addr()], &[b.local_addr()], PartitionMode::Reset);
+            let err = socket.write_all(b"z").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            let reset = err.fault_provenance().unwrap().clone();
+            assert_eq!(reset.kind(), FaultKind::Partition);
+            assert!(reset.connection_id().is_some());
+            let err = a.connect(b_addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+            let refused = err.fault_provenance().unwrap();
+            assert_eq!(refused.kind(), FaultKind::Partition);
+            assert_eq!(refused.id(), reset.id());
+            handle.heal();
+            a.connect(b_addr).await.unwrap();
+        });
+    }
+
+    #[test]
+    /// Test that partitions count against the fault budget until they heal.
+    fn budgeted_partitions() {
+        let runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_fault_budget(FaultBudget::new().max_active(FaultKind::Partition, 1));
+        let handle = runtime.localhost_handle();
+        let (a, b, c) = (
+            handle.add_host().local_addr(),
+            handle.add_host().local_addr(),
+            handle.add_host().local_addr(),
+        );
+        assert!(handle.partition(&[a], &[b], PartitionMode::Stall));
+        assert!(
+            !handle.partition(&[a], &[c], PartitionMode::Reset),
+            "expected a second concurrent partition to be refused"
+        );
+        handle.heal();
+        assert!(handle.partition(&[a], &[c], PartitionMode::Reset));
+    }
+}
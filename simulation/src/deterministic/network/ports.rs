@@ -0,0 +1,275 @@
+//! Ephemeral port allocation for outgoing connections.
+//!
+//! Each host allocates the source ports of its outgoing connections from the top of the
+//! ephemeral range downward. Ports are returned to the host once their connection has been
+//! collected, and the highest returned port is reused first, so a host which opens and closes
+//! connections one at a time keeps reusing the same port. Once every port in the range is
+//! occupied, connects fail with `AddrNotAvailable`.
+//!
+//! Connects can instead use a fixed source port, see [`ConnectOptions`]. A fixed port is in use,
+//! failing the connect with `AddrInUse`, while a listener is bound to it or a connection of the
+//! host uses it as its source, including closed connections in TIME_WAIT. With
+//! `reuse_address`, as with `SO_REUSEADDR`, connections in TIME_WAIT only occupy the port for
+//! connects to the same destination. Fixed ports in the ephemeral range are skipped by the
+//! allocator until their connections have been collected.
+//!
+//! Listeners bound to port 0 are allocated a port in the same way, skipping ports which another
+//! listener address is using, and return it to the host once they are dropped.
+//!
+//! [`ConnectOptions`]:`ConnectOptions`
+use std::{collections, io, net, ops};
+
+/// Options applied to an outgoing connection.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ConnectOptions {
+    pub(crate) local_addr: Option<net::SocketAddr>,
+    pub(crate) reuse_address: bool,
+}
+
+impl ConnectOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Binds the connection to the provided source address before connecting. The address
+    /// must be unspecified or one of the addresses of the host in the family of the
+    /// destination, and an ephemeral port is allocated if its port is zero.
+    pub fn local_addr(mut self, addr: net::SocketAddr) -> Self {
+        self.local_addr.replace(addr);
+        self
+    }
+
+    /// Allows binding to a source port held by closed connections in TIME_WAIT, as with
+    /// `SO_REUSEADDR`, unless one of them was connected to the same destination.
+    pub fn reuse_address(mut self, reuse: bool) -> Self {
+        self.reuse_address = reuse;
+        self
+    }
+}
+
+/// Ports allocated by a single host.
+#[derive(Debug)]
+struct HostPorts {
+    in_use: collections::HashSet<u16>,
+    /// Highest port which has not been allocated yet, or `None` once every port in the range
+    /// has been allocated at least once.
+    next: Option<u16>,
+    /// Ports above `next` which have been returned.
+    freed: collections::BTreeSet<u16>,
+}
+
+#[derive(Debug)]
+pub(crate) struct EphemeralPorts {
+    range: ops::RangeInclusive<u16>,
+    hosts: collections::HashMap<net::IpAddr, HostPorts>,
+}
+
+impl Default for EphemeralPorts {
+    fn default() -> Self {
+        Self {
+            range: 1..=65535,
+            hosts: collections::HashMap::new(),
+        }
+    }
+}
+
+impl EphemeralPorts {
+    /// Sets the range of ports allocated to hosts which have not allocated a port yet.
+    pub(crate) fn set_range(&mut self, range: ops::RangeInclusive<u16>) {
+        self.range = range;
+    }
+
+    fn host(&mut self, addr: net::IpAddr) -> &mut HostPorts {
+        let (start, end) = (*self.range.start(), *self.range.end());
+        self.hosts.entry(addr).or_insert_with(|| HostPorts {
+            in_use: collections::HashSet::new(),
+            next: if start <= end { Some(end) } else { None },
+            freed: collections::BTreeSet::new(),
+        })
+    }
+
+    /// Allocates the highest unoccupied port of the provided host.
+    pub(crate) fn allocate(&mut self, addr: net::IpAddr) -> Result<u16, io::Error> {
+        let start = *self.range.start();
+        let host = self.host(addr);
+        let port = match host.freed.iter().next_back().cloned() {
+            Some(port) => {
+                host.freed.remove(&port);
+                port
+            }
+            None => loop {
+                let port = host.next.ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::AddrNotAvailable,
+                        format!("ephemeral ports of {} are exhausted", addr),
+                    )
+                })?;
+                host.next = if port > start { Some(port - 1) } else { None };
+                // Skip ports claimed by connects with a fixed source port.
+                if !host.in_use.contains(&port) {
+                    break port;
+                }
+            },
+        };
+        host.in_use.insert(port);
+        Ok(port)
+    }
+
+    /// Marks a fixed source port of the provided address as occupied, so it is not allocated
+    /// until freed.
+    pub(crate) fn claim(&mut self, addr: net::SocketAddr) {
+        let host = self.host(addr.ip());
+        host.freed.remove(&addr.port());
+        host.in_use.insert(addr.port());
+    }
+
+    /// Returns the port of the provided address to its host.
+    pub(crate) fn free(&mut self, addr: net::SocketAddr) {
+        let in_range = self.range.contains(&addr.port());
+        if let Some(host) = self.hosts.get_mut(&addr.ip()) {
+            // Fixed ports outside of the range are never allocated, while ports the allocator
+            // has not reached yet are allocated in turn.
+            let reached = in_range && host.next.map_or(true, |next| addr.port() > next);
+            if host.in_use.remove(&addr.port()) && reached {
+                host.freed.insert(addr.port());
+            }
+        }
+    }
+
+    /// Moves the ports of a host which has migrated to a new address.
+    pub(crate) fn migrate(&mut self, from: net::IpAddr, to: net::IpAddr) {
+        if let Some(host) = self.hosts.remove(&from) {
+            self.hosts.insert(to, host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ConnectOptions;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener, TcpStream};
+    use std::{io, net, time};
+
+    #[test]
+    /// Test that more sequential connections than there are ports can be opened as closed
+    /// connections return their ports, and that holding every port fails further connects
+    /// with `AddrNotAvailable`.
+    fn exhaust_and_reuse() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move { while let Ok(_) = listener.accept().await {} });
+            for _ in 0..70_000 {
+                let socket = handle.connect(addr).await.unwrap();
+                assert_eq!(socket.local_addr().unwrap().port(), 65535);
+            }
+        });
+
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_ephemeral_ports(60000..=60002);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok(socket) = listener.accept().await {
+                    accepted.push(socket);
+                }
+            });
+            let mut held = vec![];
+            for port in (60000..=60002).rev() {
+                let socket = handle.connect(addr).await.unwrap();
+                assert_eq!(socket.local_addr().unwrap().port(), port);
+                held.push(socket);
+            }
+            let err = handle.connect(addr).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::AddrNotAvailable);
+            held.remove(1);
+            let socket = handle.connect(addr).await.unwrap();
+            assert_eq!(socket.local_addr().unwrap().port(), 60001);
+        });
+    }
+
+    #[test]
+    /// Test that listeners bound to port 0 return their port once dropped, so more listeners
+    /// than there are ports can be bound in turn, and that ports skipped because a listener is
+    /// bound to them remain available to connects.
+    fn rebind_port_zero() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_ephemeral_ports(60000..=60002);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let any: net::SocketAddr = "127.0.0.1:0".parse().unwrap();
+            for _ in 0..10 {
+                let listener = handle.bind(any).await.unwrap();
+                assert_eq!(listener.local_addr().unwrap().port(), 60002);
+            }
+
+            let fixed: net::SocketAddr = "127.0.0.1:60002".parse().unwrap();
+            let mut listener = handle.bind(fixed).await.unwrap();
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok(socket) = listener.accept().await {
+                    accepted.push(socket);
+                }
+            });
+            for _ in 0..10 {
+                let listener = handle.bind(any).await.unwrap();
+                assert_eq!(listener.local_addr().unwrap().port(), 60001);
+            }
+            let mut held = vec![];
+            for port in (60000..=60002).rev() {
+                let socket = handle.connect(fixed).await.unwrap();
+                assert_eq!(socket.local_addr().unwrap().port(), port);
+                held.push(socket);
+            }
+        });
+    }
+
+    #[test]
+    /// Test that connects from a fixed source port fail with `AddrInUse` while the port is
+    /// held, including by connections in TIME_WAIT unless the address is reused, and that the
+    /// allocator skips fixed ports.
+    fn fixed_source_port() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_ephemeral_ports(60000..=60001);
+        runtime.set_time_wait(Some(time::Duration::from_secs(60)));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (a, b): (net::SocketAddr, net::SocketAddr) = (
+                "127.0.0.1:9092".parse().unwrap(),
+                "127.0.0.1:9093".parse().unwrap(),
+            );
+            for addr in vec![a, b] {
+                let mut listener = handle.bind(addr).await.unwrap();
+                handle.spawn(async move {
+                    let mut accepted = vec![];
+                    while let Ok(socket) = listener.accept().await {
+                        accepted.push(socket);
+                    }
+                });
+            }
+            let source: net::SocketAddr = "0.0.0.0:60001".parse().unwrap();
+            let fixed = ConnectOptions::new().local_addr(source);
+            let socket = handle.connect_with_options(a, fixed.clone()).await.unwrap();
+            assert_eq!(socket.local_addr().unwrap().port(), 60001);
+            let ephemeral = handle.connect(a).await.unwrap();
+            assert_eq!(ephemeral.local_addr().unwrap().port(), 60000);
+            let err = handle.connect_with_options(b, fixed.clone()).await;
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+
+            drop(socket);
+            let err = handle.connect_with_options(b, fixed.clone()).await;
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+            let reuse = fixed.reuse_address(true);
+            let err = handle.connect_with_options(a, reuse.clone()).await;
+            assert_eq!(err.unwrap_err().kind(), io::ErrorKind::AddrInUse);
+            let socket = handle.connect_with_options(b, reuse).await.unwrap();
+            assert_eq!(socket.local_addr().unwrap().port(), 60001);
+        });
+    }
+}
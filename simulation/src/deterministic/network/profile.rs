@@ -0,0 +1,452 @@
+//! Network profiles bundling the conditions of a class of network link.
+use crate::deterministic::DeterministicRandomHandle;
+use std::{cmp, time};
+
+/// Minimum retransmission timeout applied when a write is lost.
+const MIN_RETRANSMIT_TIMEOUT: time::Duration = time::Duration::from_millis(200);
+
+/// Distribution of the one-way latency of a network link.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LatencyModel {
+    /// Every write is delayed by the same latency.
+    Fixed(time::Duration),
+    /// Latency is sampled uniformly from `min` up to, but excluding, `max`.
+    Uniform {
+        min: time::Duration,
+        max: time::Duration,
+    },
+    /// Latency follows a log-normal distribution with the provided median, where `sigma` is the
+    /// standard deviation of the logarithm of the latency. Real networks tend to have a long
+    /// tail of slow deliveries which a uniform distribution does not capture, a `sigma` of
+    /// around 0.5 gives a p99 roughly three times the median.
+    LogNormal { median: time::Duration, sigma: f64 },
+}
+
+impl LatencyModel {
+    /// Lowest latency the model can produce.
+    fn min(&self) -> time::Duration {
+        match *self {
+            LatencyModel::Fixed(latency) => latency,
+            LatencyModel::Uniform { min, .. } => min,
+            LatencyModel::LogNormal { .. } => time::Duration::from_millis(0),
+        }
+    }
+
+    /// Typical latency of the model, used to determine the round trip time of the link.
+    fn median(&self) -> time::Duration {
+        match *self {
+            LatencyModel::Fixed(latency) => latency,
+            LatencyModel::Uniform { min, max } => (min + cmp::max(min, max)) / 2,
+            LatencyModel::LogNormal { median, .. } => median,
+        }
+    }
+
+    /// Samples a latency from the model. Fixed latencies do not consume randomness.
+    fn sample(&self, random: &DeterministicRandomHandle) -> time::Duration {
+        match *self {
+            LatencyModel::Fixed(latency) => latency,
+            LatencyModel::Uniform { min, max } if min < max => random.gen_range(min..max),
+            LatencyModel::Uniform { min, .. } => min,
+            LatencyModel::LogNormal { median, sigma } => {
+                let factor = (sigma * random.normal_dist(0.0, 1.0)).exp();
+                time::Duration::from_secs_f64(median.as_secs_f64() * factor)
+            }
+        }
+    }
+}
+
+/// Conditions of a network link. Connections using a profile are delayed by a latency sampled
+/// from the profile's [`LatencyModel`] plus uniformly distributed jitter, are paced according to the profile bandwidth,
+/// and have writes lost with the profile loss probability. As connections are reliable, a lost
+/// write is retransmitted after a retransmission timeout rather than dropped.
+///
+/// By default each write is delayed by the profile latency. Profiles with a window instead allow
+/// up to a window of bytes to be in flight, with bytes acknowledged one round trip after they
+/// were transmitted, so throughput is bounded by the window divided by the round trip time.
+///
+/// [`LatencyModel`]:`LatencyModel`
+#[derive(Debug, Clone, PartialEq)]
+pub struct NetworkProfile {
+    latency: LatencyModel,
+    jitter: time::Duration,
+    bandwidth: Option<u64>,
+    loss: f64,
+    window: Option<Window>,
+}
+
+/// Size of the in-flight window of a profile.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Window {
+    BandwidthDelay,
+    Fixed(u64),
+}
+
+impl NetworkProfile {
+    /// Creates a profile with the provided base latency, no jitter, unlimited bandwidth and
+    /// no loss.
+    pub fn new(latency: time::Duration) -> Self {
+        Self::with_latency_model(LatencyModel::Fixed(latency))
+    }
+
+    /// Creates a profile with latencies sampled from the provided model, no jitter, unlimited
+    /// bandwidth and no loss.
+    pub fn with_latency_model(latency: LatencyModel) -> Self {
+        Self {
+            latency,
+            jitter: time::Duration::from_millis(0),
+            bandwidth: None,
+            loss: 0.0,
+            window: None,
+        }
+    }
+
+    /// A local area network: sub-millisecond latency and gigabit bandwidth.
+    pub fn lan() -> Self {
+        Self::new(time::Duration::from_micros(200))
+            .jitter(time::Duration::from_micros(50))
+            .bandwidth(125_000_000)
+    }
+
+    /// A cross-region wide area network.
+    pub fn wan() -> Self {
+        Self::new(time::Duration::from_millis(40))
+            .jitter(time::Duration::from_millis(10))
+            .bandwidth(12_500_000)
+            .loss(0.001)
+    }
+
+    /// A 3G mobile network.
+    pub fn mobile_3g() -> Self {
+        Self::new(time::Duration::from_millis(150))
+            .jitter(time::Duration::from_millis(50))
+            .bandwidth(250_000)
+            .loss(0.01)
+    }
+
+    /// A geostationary satellite link.
+    pub fn satellite() -> Self {
+        Self::new(time::Duration::from_millis(600))
+            .jitter(time::Duration::from_millis(50))
+            .bandwidth(1_250_000)
+            .loss(0.005)
+    }
+
+    /// Sets the upper bound of the uniformly distributed jitter added to the latency.
+    pub fn jitter(mut self, jitter: time::Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the bandwidth of the link in bytes per second.
+    pub fn bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.bandwidth = Some(bytes_per_second);
+        self
+    }
+
+    /// Sets the probability of a write being lost and retransmitted.
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability;
+        self
+    }
+
+    /// Limits the bytes in flight on each connection to the bandwidth-delay product of the link,
+    /// so a single connection can saturate the link bandwidth but no more. Has no effect on
+    /// profiles with unlimited bandwidth.
+    pub fn bdp_window(mut self) -> Self {
+        self.window = Some(Window::BandwidthDelay);
+        self
+    }
+
+    /// Limits the bytes in flight on each connection to the provided window, modeling a
+    /// receive window smaller than the bandwidth-delay product of the link.
+    pub fn window(mut self, bytes: u64) -> Self {
+        self.window = Some(Window::Fixed(bytes));
+        self
+    }
+
+    /// Round trip time of the link at the median latency, excluding jitter.
+    pub fn rtt(&self) -> time::Duration {
+        self.latency.median() * 2
+    }
+
+    /// Returns the number of bytes which can be transmitted during one round trip, or `None`
+    /// if the profile has unlimited bandwidth.
+    pub fn bandwidth_delay_product(&self) -> Option<u64> {
+        let bandwidth = self.bandwidth.filter(|bandwidth| *bandwidth > 0)?;
+        let bytes = u128::from(bandwidth) * self.rtt().as_nanos() / 1_000_000_000;
+        Some(bytes as u64)
+    }
+
+    /// Returns the in-flight window of connections using this profile, if windowed.
+    fn window_size(&self) -> Option<u64> {
+        match self.window? {
+            Window::BandwidthDelay => self.bandwidth_delay_product().map(|bdp| bdp.max(1)),
+            Window::Fixed(bytes) => Some(bytes.max(1)),
+        }
+    }
+
+    /// Time taken to transmit the provided number of bytes at the profile bandwidth.
+    fn transmission(&self, len: usize) -> time::Duration {
+        match self.bandwidth {
+            Some(bandwidth) if bandwidth > 0 => {
+                let nanos = len as u128 * 1_000_000_000 / u128::from(bandwidth);
+                time::Duration::from_nanos(nanos as u64)
+            }
+            _ => time::Duration::from_millis(0),
+        }
+    }
+
+    /// Samples the delay incurred by writing the provided number of bytes to a link with this
+    /// profile, on top of the lowest latency of the latency model.
+    pub(crate) fn sample_delay(
+        &self,
+        len: usize,
+        random: &DeterministicRandomHandle,
+    ) -> time::Duration {
+        let mut delay = self.transmission(len);
+        delay += self.latency.sample(random) - self.latency.min();
+        if self.jitter > time::Duration::from_millis(0) {
+            delay += random.gen_range(time::Duration::from_millis(0)..self.jitter);
+        }
+        if self.loss > 0.0 && random.should_fault(self.loss.min(1.0)) {
+            delay += cmp::max(MIN_RETRANSMIT_TIMEOUT, self.rtt());
+        }
+        delay
+    }
+}
+
+/// A network profile applied to a stream, along with the source of randomness used to
+/// sample its delays.
+#[derive(Debug, Clone)]
+pub(crate) struct LinkConditions {
+    profile: NetworkProfile,
+    random: DeterministicRandomHandle,
+}
+
+impl LinkConditions {
+    pub(crate) fn new(profile: NetworkProfile, random: DeterministicRandomHandle) -> Self {
+        Self { profile, random }
+    }
+
+    /// Returns the lowest latency of the link, applied to every write.
+    pub(crate) fn latency(&self) -> time::Duration {
+        self.profile.latency.min()
+    }
+
+    pub(crate) fn rtt(&self) -> time::Duration {
+        self.profile.rtt()
+    }
+
+    pub(crate) fn window(&self) -> Option<u64> {
+        self.profile.window_size()
+    }
+
+    pub(crate) fn sample_delay(&self, len: usize) -> time::Duration {
+        self.profile.sample_delay(len, &self.random)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that bandwidth limits pace writes.
+    fn transmission() {
+        let profile = NetworkProfile::new(time::Duration::from_millis(0)).bandwidth(1000);
+        assert_eq!(profile.transmission(500), time::Duration::from_millis(500));
+        assert_eq!(
+            NetworkProfile::lan().transmission(125_000_000),
+            time::Duration::from_secs(1)
+        );
+    }
+
+    /// Writes `messages` single byte messages from a client to a server, returning the virtual
+    /// time taken for the server to read them.
+    fn transfer_time(profile: Option<NetworkProfile>, messages: usize) -> time::Duration {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        if let Some(profile) = profile {
+            runtime.set_network_profile(Some(profile));
+        }
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let start = handle.now();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; messages];
+                socket.read_exact(&mut buf).await.unwrap();
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            for _ in 0..messages {
+                socket.write_all(&[1]).await.unwrap();
+            }
+            socket.flush().await.unwrap();
+            handle.now() - start
+        })
+    }
+
+    #[test]
+    /// Test that applying a profile to the whole network slows down connections.
+    fn network_profile() {
+        let unconstrained = transfer_time(None, 10);
+        let wan = transfer_time(Some(NetworkProfile::wan()), 10);
+        let satellite = transfer_time(Some(NetworkProfile::satellite()), 10);
+        assert_eq!(unconstrained, time::Duration::from_millis(0));
+        assert!(wan >= time::Duration::from_millis(400));
+        assert!(satellite > wan);
+    }
+
+    #[test]
+    /// Test that latency models sample within their bounds, with log-normal latencies centered
+    /// on the median and a tail above it.
+    fn latency_models() {
+        let random = crate::deterministic::Seed::new(7).random();
+        let ms = time::Duration::from_millis;
+        assert_eq!(LatencyModel::Fixed(ms(5)).sample(&random), ms(5));
+        let uniform = LatencyModel::Uniform {
+            min: ms(10),
+            max: ms(20),
+        };
+        for _ in 0..100 {
+            let latency = uniform.sample(&random);
+            assert!(latency >= ms(10) && latency < ms(20), "{:?}", latency);
+        }
+        let log_normal = LatencyModel::LogNormal {
+            median: ms(50),
+            sigma: 0.5,
+        };
+        let mut samples: Vec<_> = (0..1000).map(|_| log_normal.sample(&random)).collect();
+        samples.sort();
+        assert!(
+            samples[500] > ms(45) && samples[500] < ms(55),
+            "{:?}",
+            samples[500]
+        );
+        assert!(samples[990] > ms(100), "{:?}", samples[990]);
+        let profile = NetworkProfile::with_latency_model(log_normal);
+        assert_eq!(profile.rtt(), ms(100));
+    }
+
+    #[test]
+    /// Test that directed link profiles only delay data sent in one direction.
+    fn directed_link_profile() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let a: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let uplink = LatencyModel::Uniform {
+            min: time::Duration::from_millis(100),
+            max: time::Duration::from_millis(200),
+        };
+        runtime.set_directed_link_profile(a, b, Some(NetworkProfile::with_latency_model(uplink)));
+        let handle_a = runtime.handle(a);
+        let handle_b = runtime.handle(b);
+        runtime.block_on(async {
+            let addr = net::SocketAddr::new(b, 9092);
+            let mut listener = handle_b.bind(addr).await.unwrap();
+            let server = handle_b.clone();
+            let (elapsed_tx, elapsed_rx) = futures::channel::oneshot::channel();
+            handle_b.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let start = server.now();
+                socket.write_all(&[1]).await.unwrap();
+                socket.write_all(&[2]).await.unwrap();
+                let _ = elapsed_tx.send(server.now() - start);
+                socket.read_exact(&mut [0u8; 2]).await.unwrap();
+            });
+            let mut socket = handle_a.connect(addr).await.unwrap();
+            let start = handle_a.now();
+            socket.write_all(&[1]).await.unwrap();
+            socket.write_all(&[2]).await.unwrap();
+            assert!(handle_a.now() - start >= time::Duration::from_millis(100));
+            assert_eq!(elapsed_rx.await.unwrap(), time::Duration::from_millis(0));
+        });
+    }
+
+    #[test]
+    /// Test that link profiles only apply to connections between the provided hosts.
+    fn link_profile() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let a: net::IpAddr = "10.0.0.1".parse().unwrap();
+        let b: net::IpAddr = "10.0.0.2".parse().unwrap();
+        let c: net::IpAddr = "10.0.0.3".parse().unwrap();
+        runtime.set_link_profile(a, b, Some(NetworkProfile::satellite()));
+        let handle_a = runtime.handle(a);
+        let handle_b = runtime.handle(b);
+        let handle_c = runtime.handle(c);
+        runtime.block_on(async {
+            let addr_b = net::SocketAddr::new(b, 9092);
+            let addr_c = net::SocketAddr::new(c, 9092);
+            for (handle, addr) in vec![(handle_b, addr_b), (handle_c, addr_c)] {
+                let mut listener = handle.bind(addr).await.unwrap();
+                handle.clone().spawn(async move {
+                    while let Ok((mut socket, _)) = listener.accept().await {
+                        let mut buf = [0u8; 2];
+                        socket.read_exact(&mut buf).await.unwrap();
+                    }
+                });
+            }
+            let start = handle_a.now();
+            let mut socket = handle_a.connect(addr_c).await.unwrap();
+            socket.write_all(&[1]).await.unwrap();
+            socket.write_all(&[2]).await.unwrap();
+            assert_eq!(handle_a.now(), start);
+
+            let mut socket = handle_a.connect(addr_b).await.unwrap();
+            socket.write_all(&[1]).await.unwrap();
+            socket.write_all(&[2]).await.unwrap();
+            assert!(handle_a.now() - start >= time::Duration::from_millis(600));
+        });
+    }
+
+    /// Writes `len` bytes in 10KB chunks over a link with the provided profile, returning the
+    /// virtual time taken to write them.
+    fn bulk_transfer_time(profile: NetworkProfile, len: usize) -> time::Duration {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_network_profile(Some(profile));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; len];
+                socket.read_exact(&mut buf).await.unwrap();
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            let start = handle.now();
+            let chunk = vec![1u8; 10_000];
+            for _ in 0..len / chunk.len() {
+                socket.write_all(&chunk).await.unwrap();
+            }
+            socket.flush().await.unwrap();
+            handle.now() - start
+        })
+    }
+
+    #[test]
+    /// Test that windowed connections top out at the lesser of the bandwidth and the window
+    /// divided by the round trip time.
+    fn windows() {
+        let profile = NetworkProfile::new(time::Duration::from_millis(50)).bandwidth(1_000_000);
+        assert_eq!(profile.bandwidth_delay_product(), Some(100_000));
+        assert_eq!(profile.clone().bdp_window().window_size(), Some(100_000));
+        assert_eq!(profile.window_size(), None);
+
+        let bdp = bulk_transfer_time(profile.clone().bdp_window(), 1_000_000);
+        assert!(bdp >= time::Duration::from_millis(900), "{:?}", bdp);
+        assert!(bdp < time::Duration::from_secs(2), "{:?}", bdp);
+
+        let small_window = bulk_transfer_time(profile.window(10_000), 1_000_000);
+        assert!(
+            small_window >= time::Duration::from_secs(9),
+            "{:?}",
+            small_window
+        );
+    }
+}
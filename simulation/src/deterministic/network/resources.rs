@@ -0,0 +1,185 @@
+//! Per-host limits on connections and buffered memory.
+//!
+//! Real hosts run out of file descriptors and memory long before they run out of ports, and
+//! services are expected to degrade gracefully when they do. A host can be given
+//! [`ResourceLimits`], capping the number of connections it holds open and the bytes its
+//! connections buffer.
+//!
+//! Each half of a connection holds a descriptor of its host from the time it is connected or
+//! accepted until it is dropped. Connects and accepts which would exceed the cap fail with
+//! `EMFILE`, as when a process runs out of file descriptors. An accept is refused before a
+//! connection is taken from the queue, so the connection remains queued until a descriptor is
+//! freed.
+//!
+//! Bytes written to a connection are charged to the memory budget of the writing host until the
+//! peer reads them or is dropped. Writes are shortened to the remaining budget, and writes made
+//! once the budget is exhausted fail with `ENOMEM`.
+//!
+//! [`ResourceLimits`]:`ResourceLimits`
+use std::{io, sync};
+
+/// Error returned when a host has no descriptor left for a connection.
+pub(super) const EMFILE: i32 = 24;
+/// Error returned when a host has exhausted its memory budget.
+const ENOMEM: i32 = 12;
+
+/// Limits on the resources of a host, see the [module documentation](self). Hosts are not
+/// limited by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceLimits {
+    max_connections: Option<usize>,
+    memory_budget: Option<usize>,
+}
+
+impl ResourceLimits {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the number of connections the host can hold open at once.
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections.replace(max);
+        self
+    }
+
+    /// Caps the bytes written by the host which its peers have not read yet.
+    pub fn memory_budget(mut self, bytes: usize) -> Self {
+        self.memory_budget.replace(bytes);
+        self
+    }
+}
+
+/// Resources in use by a host.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResourceUsage {
+    connections: usize,
+    memory: usize,
+}
+
+impl ResourceUsage {
+    /// Returns the number of connections the host holds open.
+    pub fn connections(&self) -> usize {
+        self.connections
+    }
+
+    /// Returns the bytes written by the host which its peers have not read yet.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    limits: ResourceLimits,
+    usage: ResourceUsage,
+}
+
+/// Account of the resources of a host, shared by its connections.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct HostResources {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl HostResources {
+    /// Replaces the limits of the host. Resources already in use are not reclaimed, but new
+    /// connections and writes fail until usage falls below the limits.
+    pub(crate) fn set_limits(&self, limits: Option<ResourceLimits>) {
+        self.state.lock().unwrap().limits = limits.unwrap_or_default();
+    }
+
+    pub(crate) fn usage(&self) -> ResourceUsage {
+        self.state.lock().unwrap().usage
+    }
+
+    /// Takes a descriptor for a connection, which is returned to the host once dropped.
+    pub(crate) fn open(&self) -> io::Result<Descriptor> {
+        let mut state = self.state.lock().unwrap();
+        let max = state.limits.max_connections;
+        if max.map_or(false, |max| state.usage.connections >= max) {
+            return Err(io::Error::from_raw_os_error(EMFILE));
+        }
+        state.usage.connections += 1;
+        Ok(Descriptor {
+            resources: self.clone(),
+        })
+    }
+
+    /// Charges up to `len` written bytes to the memory budget, returning the number of bytes
+    /// charged.
+    pub(crate) fn reserve(&self, len: usize) -> io::Result<usize> {
+        let mut state = self.state.lock().unwrap();
+        let available = match state.limits.memory_budget {
+            Some(budget) => budget.saturating_sub(state.usage.memory),
+            None => len,
+        };
+        if available == 0 && len > 0 {
+            return Err(io::Error::from_raw_os_error(ENOMEM));
+        }
+        let len = std::cmp::min(len, available);
+        state.usage.memory += len;
+        Ok(len)
+    }
+
+    /// Returns bytes which have been read, or will never be read, to the memory budget.
+    pub(crate) fn release(&self, len: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.usage.memory = state.usage.memory.saturating_sub(len);
+    }
+}
+
+/// A connection held open by a host.
+#[derive(Debug)]
+pub(crate) struct Descriptor {
+    resources: HostResources,
+}
+
+impl Drop for Descriptor {
+    fn drop(&mut self) {
+        let mut state = self.resources.state.lock().unwrap();
+        state.usage.connections -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that connects and accepts beyond the connection cap fail with `EMFILE` until a
+    /// connection is dropped, and that writes beyond the memory budget are shortened and then
+    /// fail with `ENOMEM` until the peer reads.
+    fn limits() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let addr: net::SocketAddr = "10.0.0.1:9092".parse().unwrap();
+        let server = runtime.handle(addr.ip());
+        let client = runtime.localhost_handle();
+        runtime.set_resource_limits(addr.ip(), Some(ResourceLimits::new().max_connections(1)));
+        client.set_resource_limits(Some(ResourceLimits::new().memory_budget(8)));
+        runtime.block_on(async move {
+            let mut listener = server.bind(addr).await.unwrap();
+            let _first = client.connect(addr).await.unwrap();
+            let (accepted, _) = listener.accept().await.unwrap();
+            let mut second = client.connect(addr).await.unwrap();
+            let err = listener.accept().await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(EMFILE));
+            assert_eq!(server.resource_usage().connections(), 1);
+            drop(accepted);
+            let (mut accepted, _) = listener.accept().await.unwrap();
+
+            assert_eq!(second.write(b"0123456789").await.unwrap(), 8);
+            let err = second.write(b"89").await.unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(ENOMEM));
+            assert_eq!(client.resource_usage().memory(), 8);
+            let mut buf = [0u8; 4];
+            accepted.read_exact(&mut buf).await.unwrap();
+            assert_eq!(client.resource_usage().memory(), 4);
+            assert_eq!(second.write(b"89").await.unwrap(), 2);
+            drop(accepted);
+            assert_eq!(client.resource_usage().memory(), 0);
+        });
+    }
+}
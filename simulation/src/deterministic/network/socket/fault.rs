@@ -1,11 +1,154 @@
 //! Fault injection for AsyncRead/AsyncWrite types.
 
+use crate::deterministic::network::bandwidth::Throttle;
+use crate::deterministic::network::fault::{ConnectionId, FaultIds, FaultKind, FaultProvenance};
+use crate::deterministic::network::link::LinkFaults;
+use crate::deterministic::network::profile::LinkConditions;
+use crate::deterministic::network::socket::SocketHalf;
+use crate::deterministic::network::table::DroppedConnections;
+use crate::deterministic::network::tap::StreamTap;
+use crate::deterministic::{Breakpoint, DecisionPoint, DeterministicRandomHandle, SimulationEvent};
 use crate::TcpStream;
+use bytes::{Bytes, BytesMut};
 use futures::{task::Waker, FutureExt, Poll};
 use std::time;
-use std::{io, net, pin::Pin, sync, task::Context};
+use std::{collections, io, net, pin::Pin, sync, task::Context};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::timer::Delay;
+use tracing::trace;
+
+/// Point in the byte stream at which injected bytes will be delivered to the reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InjectionPoint {
+    /// Deliver the injected bytes before any further bytes from the peer.
+    Prepend,
+    /// Deliver the injected bytes once the provided number of bytes from the peer have been read.
+    Offset(usize),
+    /// Deliver the injected bytes once the peer has closed the connection.
+    AfterClose,
+}
+
+impl InjectionPoint {
+    /// Returns the stream offset at which this injection point is due, if any.
+    fn offset(self) -> Option<usize> {
+        match self {
+            InjectionPoint::Prepend => Some(0),
+            InjectionPoint::Offset(offset) => Some(offset),
+            InjectionPoint::AfterClose => None,
+        }
+    }
+}
+
+/// Schedule of the keepalive probes sent on an idle stream, modelled on TCP keepalive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    idle: time::Duration,
+    interval: time::Duration,
+    probes: u32,
+}
+
+impl Keepalive {
+    /// Probes the peer once the stream has been idle for `idle`, then every `interval` while
+    /// probes go unanswered. The stream fails with `TimedOut` once `probes` consecutive probes
+    /// have gone unanswered.
+    pub fn new(idle: time::Duration, interval: time::Duration, probes: u32) -> Self {
+        Self {
+            idle,
+            interval,
+            probes,
+        }
+    }
+
+    pub fn idle(&self) -> time::Duration {
+        self.idle
+    }
+
+    pub fn interval(&self) -> time::Duration {
+        self.interval
+    }
+
+    pub fn probes(&self) -> u32 {
+        self.probes
+    }
+}
+
+/// Segment level faults applied to the bytes a stream receives.
+///
+/// TCP reassembles segments which arrive out of order or more than once, so the bytes of a
+/// stream are never reordered or duplicated. What readers observe instead is each read
+/// returning an arbitrary part of what the peer wrote, and the rest of the stream stalling
+/// while a reordered or retransmitted segment is awaited. Readers which assume that one read
+/// returns one write of the peer break under these faults.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SegmentFaults {
+    max_segment: Option<usize>,
+    hold_probability: f64,
+    max_hold: time::Duration,
+}
+
+impl Default for SegmentFaults {
+    fn default() -> Self {
+        Self {
+            max_segment: None,
+            hold_probability: 0.0,
+            max_hold: time::Duration::from_millis(0),
+        }
+    }
+}
+
+impl SegmentFaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Splits received bytes into segments of between one and `max_segment` bytes, each of
+    /// which is returned by a separate read.
+    pub fn max_segment(mut self, max_segment: usize) -> Self {
+        self.max_segment = Some(max_segment).filter(|max| *max > 0);
+        self
+    }
+
+    /// Holds back segments with the provided probability for a random delay of up to
+    /// `max_delay`, as when a segment arrives after those sent after it, or is lost and
+    /// retransmitted.
+    pub fn hold_back(mut self, probability: f64, max_delay: time::Duration) -> Self {
+        self.hold_probability = probability;
+        self.max_hold = max_delay;
+        self
+    }
+
+    /// Draws the size of the next segment, and how long it is held back.
+    fn next_segment(&self, random: &DeterministicRandomHandle) -> (usize, time::Duration) {
+        let len = match self.max_segment {
+            Some(max) => random.gen_range(1..max + 1),
+            None => usize::max_value(),
+        };
+        let mut held = time::Duration::from_millis(0);
+        if self.hold_probability > 0.0
+            && self.max_hold > held
+            && random.should_fault(self.hold_probability)
+        {
+            held = random.gen_range(held..self.max_hold);
+        }
+        (len, held)
+    }
+}
+
+/// State of the keepalive probes of a stream.
+#[derive(Debug)]
+struct Probing {
+    keepalive: Keepalive,
+    /// Incremented whenever keepalive is reconfigured, invalidating scheduled probes.
+    generation: u64,
+    unanswered: u32,
+    last_probe: Option<time::Instant>,
+    timed_out: bool,
+    /// Wakers of pending operations, notified once the stream times out.
+    wakers: Vec<Waker>,
+}
+
+/// Number of previously delivered bytes retained for replay faults.
+const REPLAY_HISTORY: usize = 64 * 1024;
 
 #[derive(Debug)]
 struct FaultState {
@@ -17,20 +160,417 @@ struct FaultState {
     send_waker: Option<Waker>,
     receive_clogged: bool,
     receive_waker: Option<Waker>,
-    disconnected: bool,
+    /// Provenance of the injected disconnect, if the stream has been disconnected.
+    disconnected: Option<FaultProvenance>,
+    /// Wakers to notify once the stream has been dropped.
+    drop_wakers: Vec<Waker>,
+    /// Time at which the stream was dropped.
+    closed_at: Option<time::Instant>,
+    /// Bytes which will be injected into the stream at the provided injection points.
+    injections: Vec<(InjectionPoint, Bytes)>,
+    /// Injected bytes which have been partially read.
+    injecting: Option<Bytes>,
+    /// Number of bytes read from the peer.
+    delivered: usize,
+    /// The most recently delivered bytes, retained for replay faults.
+    history: BytesMut,
+    /// Conditions of the link the stream sends over, if a network profile applies.
+    conditions: Option<LinkConditions>,
+    /// Identifier of the connection the stream belongs to, if registered with a network.
+    connection: Option<ConnectionId>,
+    /// Connections dropped since the network last collected them, notified once the stream is
+    /// dropped.
+    drops: Option<DroppedConnections>,
+    /// Unacknowledged writes on windowed links, along with the time they are acknowledged.
+    in_flight: collections::VecDeque<(time::Instant, usize)>,
+    in_flight_bytes: usize,
+    /// Ratio of the bytes transmitted over the link to the bytes written, used to model streams
+    /// which shrink their payload before it reaches the wire.
+    wire_ratio: f64,
+    /// Taps recording the bytes written to the stream.
+    taps: Vec<sync::Arc<dyn StreamTap>>,
+    /// Total number of bytes written to the stream.
+    written: u64,
+    /// Local and peer addresses of the stream, once it has migrated to new addresses.
+    migrated: Option<(net::SocketAddr, net::SocketAddr)>,
+    /// Fault state of the other half of the connection, used by keepalive probes to determine
+    /// if the peer is reachable.
+    peer: Option<sync::Weak<sync::Mutex<FaultState>>>,
+    /// Time at which bytes were last read from or written to the stream.
+    last_activity: time::Instant,
+    probing: Option<Probing>,
+    keepalive_generation: u64,
+    /// Bandwidth limit of the stream itself.
+    bandwidth: Option<sync::Arc<Throttle>>,
+    /// Bandwidth limit shared by every connection sending over the same link.
+    shared_link: Option<sync::Arc<Throttle>>,
+    /// Faults of the link the stream sends over, configured through a link fault handle.
+    link: Option<LinkFaults>,
+    /// Wakers of reads and writes pending on the underlying stream.
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+    /// Provenance of the injected shutdown, if writes to the stream have been shut down.
+    write_shutdown: Option<FaultProvenance>,
+    /// Delay before the peer observes the stream being closed.
+    close_delay: time::Duration,
+    /// Time at which the close of the peer becomes visible to reads, once the peer has shut
+    /// down writes or been closed.
+    peer_closed: Option<time::Instant>,
+    close_timer: Delay,
+    /// Segment faults applied to received bytes, along with the source of randomness used to
+    /// draw segments.
+    segments: Option<(SegmentFaults, DeterministicRandomHandle)>,
+    /// Bytes of the current segment which have not been read yet.
+    segment_remaining: usize,
 }
 
+impl FaultState {
+    /// Takes any injected bytes which are due at the current read offset.
+    fn take_due_injection(&mut self) -> Option<Bytes> {
+        if let Some(bytes) = self.injecting.take() {
+            return Some(bytes);
+        }
+        let delivered = self.delivered;
+        let position = self
+            .injections
+            .iter()
+            .position(|(point, _)| match point.offset() {
+                Some(offset) => offset <= delivered,
+                None => false,
+            })?;
+        Some(self.injections.remove(position).1)
+    }
+
+    /// Returns true if injected bytes are due at the current read offset.
+    fn has_due_injection(&self) -> bool {
+        let delivered = self.delivered;
+        self.injecting.is_some()
+            || self
+                .injections
+                .iter()
+                .any(|(point, _)| point.offset().map_or(false, |offset| offset <= delivered))
+    }
+
+    /// Takes the first injection which is due after the peer has closed the connection.
+    fn take_close_injection(&mut self) -> Option<Bytes> {
+        let position = self
+            .injections
+            .iter()
+            .position(|(point, _)| *point == InjectionPoint::AfterClose)?;
+        Some(self.injections.remove(position).1)
+    }
+
+    /// Returns the maximum number of bytes which can be read from the peer without skipping
+    /// over an injection point.
+    fn read_limit(&self, len: usize) -> usize {
+        let delivered = self.delivered;
+        self.injections
+            .iter()
+            .filter_map(|(point, _)| point.offset())
+            .filter(|offset| *offset > delivered)
+            .map(|offset| offset - delivered)
+            .fold(len, std::cmp::min)
+    }
+
+    /// Returns the number of bytes which can be read before the end of the current segment,
+    /// starting a new segment once the previous one has been read. A new segment which is held
+    /// back delays receives until it arrives.
+    fn segment_limit(&mut self, len: usize, now: time::Instant) -> usize {
+        let (faults, random) = match self.segments.as_ref() {
+            Some(segments) => segments,
+            None => return len,
+        };
+        if self.segment_remaining == 0 {
+            let (segment, held) = faults.next_segment(random);
+            self.segment_remaining = segment;
+            if held > time::Duration::from_millis(0) {
+                trace!("holding back segment for {:?}", held);
+                let deadline = std::cmp::max(self.receive_delay.deadline(), now + held);
+                self.receive_delay.reset(deadline);
+            }
+        }
+        std::cmp::min(len, self.segment_remaining)
+    }
+
+    /// Record bytes read from the peer, retaining up to `REPLAY_HISTORY` bytes for replay.
+    fn record_delivered(&mut self, bytes: &[u8]) {
+        self.delivered += bytes.len();
+        self.history.extend_from_slice(bytes);
+        if self.history.len() > REPLAY_HISTORY {
+            let excess = self.history.len() - REPLAY_HISTORY;
+            let _ = self.history.split_to(excess);
+        }
+    }
+
+    /// Releases the window held by writes acknowledged by the provided instant.
+    fn acknowledge(&mut self, now: time::Instant) {
+        while let Some((acked_at, len)) = self.in_flight.front().copied() {
+            if acked_at > now {
+                break;
+            }
+            self.in_flight.pop_front();
+            self.in_flight_bytes -= len;
+        }
+    }
+
+    /// Copies injected bytes into `dst`, staging any bytes which do not fit.
+    /// Returns the error returned by operations on a disconnected stream.
+    fn disconnected_error(&self) -> Option<io::Error> {
+        if self.probing.as_ref().map_or(false, |p| p.timed_out) {
+            return Some(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "keepalive probes went unanswered",
+            ));
+        }
+        self.disconnected.as_ref().map(|provenance| {
+            let kind = match provenance.kind() {
+                FaultKind::Reset | FaultKind::Partition => io::ErrorKind::ConnectionReset,
+                _ => io::ErrorKind::BrokenPipe,
+            };
+            provenance.clone().into_io_error(kind)
+        })
+    }
+
+    /// Returns the error returned by writes once writes to the stream have been shut down.
+    fn write_shutdown_error(&self) -> Option<io::Error> {
+        self.write_shutdown
+            .as_ref()
+            .map(|provenance| provenance.clone().into_io_error(io::ErrorKind::BrokenPipe))
+    }
+
+    /// Wakes every operation pending on the stream, so that it observes an injected fault.
+    fn wake_pending(&mut self) {
+        let wakers = self
+            .read_waker
+            .take()
+            .into_iter()
+            .chain(self.write_waker.take())
+            .chain(self.send_waker.take())
+            .chain(self.receive_waker.take());
+        for waker in wakers {
+            waker.wake()
+        }
+    }
+
+    /// Registers a waker to be notified if keepalive probing times out the stream.
+    fn register_keepalive_waker(&mut self, waker: &Waker) {
+        if let Some(probing) = self.probing.as_mut() {
+            if !probing.wakers.iter().any(|w| w.will_wake(waker)) {
+                probing.wakers.push(waker.clone());
+            }
+        }
+    }
+
+    /// Returns true if either direction of the stream is clogged, so probes or their
+    /// acknowledgements are lost.
+    fn is_clogged(&self) -> bool {
+        self.send_clogged || self.receive_clogged
+    }
+
+    /// Returns the number of bytes transmitted over the link for `len` written bytes.
+    fn wire_bytes(&self, len: usize) -> usize {
+        (len as f64 * self.wire_ratio).ceil() as usize
+    }
+
+    fn read_injected(&mut self, bytes: Bytes, dst: &mut [u8]) -> usize {
+        let to_write = std::cmp::min(dst.len(), bytes.len());
+        dst[..to_write].copy_from_slice(&bytes[..to_write]);
+        let remaining = bytes.slice_from(to_write);
+        if !remaining.is_empty() {
+            self.injecting.replace(remaining);
+        }
+        to_write
+    }
+}
+
+/// Handle injecting faults into a single stream, obtained from the stream itself or from the
+/// network when the connection is registered.
 #[derive(Debug, Clone)]
 pub struct FaultyTcpStreamHandle {
     inner: sync::Arc<sync::Mutex<FaultState>>,
+    time_handle: crate::deterministic::DeterministicTimeHandle,
+    fault_ids: FaultIds,
 }
 
 impl FaultyTcpStreamHandle {
     pub fn is_dropped(&self) -> bool {
-        sync::Arc::strong_count(&self.inner) <= 1
+        self.inner.lock().unwrap().closed_at.is_some()
     }
-    pub fn disconnect(&self) {
-        self.inner.lock().unwrap().disconnected = true;
+    /// Returns the time at which the stream was dropped, if it has been dropped.
+    pub fn closed_at(&self) -> Option<time::Instant> {
+        self.inner.lock().unwrap().closed_at
+    }
+    /// Disconnects the stream, causing further reads and writes to fail with an error carrying
+    /// the provenance of the disconnect.
+    pub fn disconnect(&self) -> FaultProvenance {
+        let provenance = self.provenance(FaultKind::Disconnect);
+        self.inner
+            .lock()
+            .unwrap()
+            .disconnected
+            .replace(provenance.clone());
+        provenance
+    }
+
+    /// Resets the stream, causing further operations to fail with `ConnectionReset`.
+    pub(crate) fn reset(&self) -> FaultProvenance {
+        self.reset_by(self.provenance(FaultKind::Reset))
+    }
+
+    /// Resets the stream as [`reset`] does, attributing the reset to an existing fault such as
+    /// the partition which separated its hosts.
+    ///
+    /// [`reset`]:`FaultyTcpStreamHandle::reset`
+    pub(crate) fn reset_by(&self, provenance: FaultProvenance) -> FaultProvenance {
+        let connection = self.inner.lock().unwrap().connection;
+        let provenance = provenance.with_connection(connection);
+        self.inner
+            .lock()
+            .unwrap()
+            .disconnected
+            .replace(provenance.clone());
+        self.emit_reset(&provenance);
+        provenance
+    }
+
+    /// Resets the connection immediately, as when the peer sends an RST. Pending and further
+    /// reads and writes on both halves of the connection fail with `ConnectionReset`, even if
+    /// unread bytes remain.
+    pub fn reset_now(&self) -> FaultProvenance {
+        let provenance = self.provenance(FaultKind::Reset);
+        let peer = {
+            let mut lock = self.inner.lock().unwrap();
+            lock.disconnected.replace(provenance.clone());
+            lock.wake_pending();
+            lock.peer.as_ref().and_then(sync::Weak::upgrade)
+        };
+        if let Some(peer) = peer {
+            let mut lock = peer.lock().unwrap();
+            lock.disconnected.replace(provenance.clone());
+            lock.wake_pending();
+        }
+        self.emit_reset(&provenance);
+        provenance
+    }
+
+    fn emit_reset(&self, provenance: &FaultProvenance) {
+        let id = provenance.connection_id();
+        self.time_handle
+            .events()
+            .emit(SimulationEvent::ConnectionReset { id });
+    }
+
+    /// Shuts down writes to the stream, leaving reads open. Further writes fail with
+    /// `BrokenPipe`, and the peer reads EOF once it has read the bytes already written.
+    pub fn shutdown_write(&self) -> FaultProvenance {
+        let provenance = self.provenance(FaultKind::Shutdown);
+        {
+            let mut lock = self.inner.lock().unwrap();
+            lock.write_shutdown.replace(provenance.clone());
+            if let Some(waker) = lock.write_waker.take() {
+                waker.wake()
+            }
+        }
+        notify_closed(&self.inner, self.time_handle.now());
+        provenance
+    }
+
+    /// Delays the peer observing the stream being closed or shut down by `delay`, as when the
+    /// FIN is delayed or lost and retransmitted. Bytes written before the close are delivered
+    /// as usual.
+    pub fn delay_close(&self, delay: time::Duration) {
+        self.inner.lock().unwrap().close_delay = delay;
+    }
+
+    /// Links the stream to the other half of its connection.
+    pub(crate) fn set_peer(&self, peer: &FaultyTcpStreamHandle) {
+        self.inner
+            .lock()
+            .unwrap()
+            .peer
+            .replace(sync::Arc::downgrade(&peer.inner));
+    }
+
+    /// Enables keepalive probing of the peer, or disables it if `keepalive` is `None`.
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) {
+        set_keepalive(&self.inner, &self.time_handle, keepalive);
+    }
+
+    /// Limits sends to the provided number of bytes per second of virtual time, or removes the
+    /// limit if `bytes_per_second` is `None`.
+    pub fn set_bandwidth(&self, bytes_per_second: Option<u64>) {
+        self.inner.lock().unwrap().bandwidth =
+            bytes_per_second.map(|b| sync::Arc::new(Throttle::new(b)));
+    }
+
+    /// Paces sends according to the bandwidth of the link the stream sends over, shared with
+    /// the other connections over the link.
+    pub(crate) fn set_shared_link(&self, link: Option<sync::Arc<Throttle>>) {
+        self.inner.lock().unwrap().shared_link = link;
+    }
+
+    /// Applies the faults of the link the stream sends over to its writes.
+    pub(crate) fn set_link_faults(&self, link: Option<LinkFaults>) {
+        self.inner.lock().unwrap().link = link;
+    }
+
+    /// Returns the total number of bytes written to the stream.
+    pub fn bytes_written(&self) -> u64 {
+        self.inner.lock().unwrap().written
+    }
+
+    /// Overrides the local and peer addresses reported by the stream.
+    pub(crate) fn migrate(&self, local: net::SocketAddr, peer: net::SocketAddr) {
+        self.inner.lock().unwrap().migrated.replace((local, peer));
+    }
+
+    /// Creates a provenance record for a fault of the provided kind injected now.
+    fn provenance(&self, kind: FaultKind) -> FaultProvenance {
+        let connection = self.inner.lock().unwrap().connection;
+        let observers = self.time_handle.events().observers();
+        observers.decision(DecisionPoint::Fault(kind));
+        FaultProvenance::new(
+            self.fault_ids.next(),
+            kind,
+            self.time_handle.now(),
+            connection,
+        )
+    }
+    /// Attaches a tap which records bytes subsequently written to the stream.
+    pub(crate) fn add_tap(&self, tap: sync::Arc<dyn StreamTap>) {
+        self.inner.lock().unwrap().taps.push(tap);
+    }
+    pub(crate) fn set_connection_id(&self, id: ConnectionId) {
+        self.inner.lock().unwrap().connection.replace(id);
+    }
+    /// Reports the connection of the stream to `drops` once the stream is dropped.
+    pub(crate) fn report_drops(&self, drops: DroppedConnections) {
+        self.inner.lock().unwrap().drops.replace(drops);
+    }
+    /// Registers a waker which will be notified when the stream is dropped.
+    pub fn register_drop_waker(&self, waker: &Waker) {
+        let mut lock = self.inner.lock().unwrap();
+        if !lock.drop_wakers.iter().any(|w| w.will_wake(waker)) {
+            lock.drop_wakers.push(waker.clone());
+        }
+    }
+    /// Injects the provided bytes into the stream, delivering them to the reader at the
+    /// provided injection point.
+    pub fn inject(&self, bytes: Bytes, point: InjectionPoint) {
+        self.inner.lock().unwrap().injections.push((point, bytes));
+    }
+    /// Re-delivers up to `len` of the most recently read bytes before any further bytes from
+    /// the peer. Returns the number of bytes which will be replayed.
+    pub fn replay(&self, len: usize) -> usize {
+        let mut lock = self.inner.lock().unwrap();
+        let start = lock.history.len().saturating_sub(len);
+        let replayed = Bytes::from(&lock.history[start..]);
+        let replayed_len = replayed.len();
+        if replayed_len > 0 {
+            lock.injections
+                .insert(0, (InjectionPoint::Prepend, replayed));
+        }
+        replayed_len
     }
     pub fn set_send_latency(&self, duration: time::Duration) {
         self.inner.lock().unwrap().send_latency = duration;
@@ -38,6 +578,29 @@ impl FaultyTcpStreamHandle {
     pub fn set_receive_latency(&self, duration: time::Duration) {
         self.inner.lock().unwrap().receive_latency = duration;
     }
+    /// Applies the provided link conditions to sends, replacing the send latency with the
+    /// latency of the link. Windowed links are not delayed per write, and instead wait for
+    /// acknowledgements once the window is full.
+    pub(crate) fn set_conditions(&self, conditions: Option<LinkConditions>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.send_latency = match conditions.as_ref() {
+            Some(conditions) if conditions.window().is_none() => conditions.latency(),
+            _ => time::Duration::from_millis(0),
+        };
+        lock.conditions = conditions;
+    }
+
+    /// Applies the provided segment faults to the bytes received by the stream, drawing
+    /// segments from `random`. Passing `None` removes the faults.
+    pub(crate) fn set_segment_faults(
+        &self,
+        faults: Option<SegmentFaults>,
+        random: DeterministicRandomHandle,
+    ) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.segments = faults.map(|faults| (faults, random));
+        lock.segment_remaining = 0;
+    }
 
     pub fn is_fully_clogged(&self) -> bool {
         let lock = self.inner.lock().unwrap();
@@ -79,6 +642,7 @@ pub struct FaultyTcpStream<T> {
     handle: crate::deterministic::DeterministicTimeHandle,
     inner: T,
     fault_state: sync::Arc<sync::Mutex<FaultState>>,
+    fault_ids: FaultIds,
 }
 
 impl<T> FaultyTcpStream<T> {
@@ -87,6 +651,16 @@ impl<T> FaultyTcpStream<T> {
     pub fn wrap(
         handle: crate::deterministic::DeterministicTimeHandle,
         inner: T,
+    ) -> (FaultyTcpStream<T>, FaultyTcpStreamHandle) {
+        FaultyTcpStream::wrap_with_fault_ids(handle, inner, FaultIds::default())
+    }
+
+    /// Wrap the provided TcpStream with fault injection support, allocating identifiers for
+    /// injected faults from `fault_ids`.
+    pub(crate) fn wrap_with_fault_ids(
+        handle: crate::deterministic::DeterministicTimeHandle,
+        inner: T,
+        fault_ids: FaultIds,
     ) -> (FaultyTcpStream<T>, FaultyTcpStreamHandle) {
         let send_latency = time::Duration::from_millis(0);
         let send_delay = handle.delay_from(send_latency);
@@ -101,27 +675,100 @@ impl<T> FaultyTcpStream<T> {
             send_waker: None,
             receive_clogged: false,
             receive_waker: None,
-            disconnected: false,
+            disconnected: None,
+            drop_wakers: vec![],
+            closed_at: None,
+            injections: vec![],
+            injecting: None,
+            delivered: 0,
+            history: BytesMut::new(),
+            conditions: None,
+            connection: None,
+            drops: None,
+            in_flight: collections::VecDeque::new(),
+            in_flight_bytes: 0,
+            wire_ratio: 1.0,
+            taps: vec![],
+            written: 0,
+            migrated: None,
+            peer: None,
+            last_activity: handle.now(),
+            probing: None,
+            keepalive_generation: 0,
+            bandwidth: None,
+            shared_link: None,
+            link: None,
+            read_waker: None,
+            write_waker: None,
+            write_shutdown: None,
+            close_delay: time::Duration::from_millis(0),
+            peer_closed: None,
+            close_timer: handle.delay_from(time::Duration::from_millis(0)),
+            segments: None,
+            segment_remaining: 0,
         };
         let fault_state = sync::Arc::new(sync::Mutex::new(fault_state));
 
+        let stream_handle = FaultyTcpStreamHandle {
+            inner: sync::Arc::clone(&fault_state),
+            time_handle: handle.clone(),
+            fault_ids: fault_ids.clone(),
+        };
         let wrapped_stream = FaultyTcpStream {
             handle,
             inner,
-            fault_state: sync::Arc::clone(&fault_state),
-        };
-        let handle = FaultyTcpStreamHandle {
-            inner: sync::Arc::clone(&fault_state),
+            fault_state,
+            fault_ids,
         };
-        (wrapped_stream, handle)
+        (wrapped_stream, stream_handle)
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
     }
 
-    fn poll_send_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    /// Returns a handle injecting faults into the stream, as when accepting a connection whose
+    /// peer should be clogged, delayed or disconnected. Holding the handle does not keep the
+    /// connection open once the stream is dropped.
+    pub fn fault_handle(&self) -> FaultyTcpStreamHandle {
+        FaultyTcpStreamHandle {
+            inner: sync::Arc::clone(&self.fault_state),
+            time_handle: self.handle.clone(),
+            fault_ids: self.fault_ids.clone(),
+        }
+    }
+
+    /// Enables keepalive probing of the peer, see [`Keepalive`]. Once probes go unanswered,
+    /// as when the peer has become unreachable in either direction, reads and writes fail with
+    /// `TimedOut`. Passing `None` disables probing.
+    ///
+    /// [`Keepalive`]:`Keepalive`
+    pub fn set_keepalive(&self, keepalive: Option<Keepalive>) {
+        set_keepalive(&self.fault_state, &self.handle, keepalive);
+    }
+
+    /// Limits writes to the provided number of bytes per second of virtual time. Once the bytes
+    /// written have used up the bandwidth, further writes are pending until they would have
+    /// been transmitted. Passing `None` removes the limit.
+    pub fn set_bandwidth(&self, bytes_per_second: Option<u64>) {
+        self.fault_state.lock().unwrap().bandwidth =
+            bytes_per_second.map(|b| sync::Arc::new(Throttle::new(b)));
+    }
+
+    /// Scales the bytes written to the stream by `ratio` when pacing them over the link.
+    pub(crate) fn set_wire_ratio(&self, ratio: f64) {
+        self.fault_state.lock().unwrap().wire_ratio = ratio;
+    }
+
+    /// Polls until sends are no longer delayed by faults. If `reset` is set, the send latency is
+    /// applied again to subsequent calls once the current delay has elapsed.
+    fn poll_send_delay(&self, cx: &mut Context<'_>, reset: bool) -> Poll<Result<(), io::Error>> {
         let mut lock = self.fault_state.lock().unwrap();
         let send_latency = lock.send_latency;
-        if lock.disconnected {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        if let Some(err) = lock.disconnected_error() {
+            return Poll::Ready(Err(err));
         }
+        lock.register_keepalive_waker(cx.waker());
         // If sends are clogged, register a waker to be notified when sends are unclogged
         // and return pending.
         if lock.send_clogged {
@@ -132,18 +779,23 @@ impl<T> FaultyTcpStream<T> {
         // that future calls to poll_send_delay also reflect the latency.
         let deadline = lock.send_delay.deadline();
         futures::ready!(lock.send_delay.poll_unpin(cx));
-        lock.send_delay.reset(deadline + send_latency);
+        if reset {
+            lock.send_delay.reset(deadline + send_latency);
+        }
         // since the latency delay has elapsed, the socket is not disconnected, and it's not clogged, we can
         // return Ready.
         Poll::Ready(Ok(()))
     }
 
-    fn poll_receive_delay(&self, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
+    /// Polls until receives are no longer delayed by faults. If `reset` is set, the receive
+    /// latency is applied again to subsequent calls once the current delay has elapsed.
+    fn poll_receive_delay(&self, cx: &mut Context<'_>, reset: bool) -> Poll<Result<(), io::Error>> {
         let mut lock = self.fault_state.lock().unwrap();
         let receive_latency = lock.receive_latency;
-        if lock.disconnected {
-            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        if let Some(err) = lock.disconnected_error() {
+            return Poll::Ready(Err(err));
         }
+        lock.register_keepalive_waker(cx.waker());
         // If receives are clogged, register a waker to be notified when receives are unclogged
         // and return pending.
         if lock.receive_clogged {
@@ -154,11 +806,114 @@ impl<T> FaultyTcpStream<T> {
         // that future calls to poll_receive_delay also reflect the latency.
         let deadline = lock.receive_delay.deadline();
         futures::ready!(lock.receive_delay.poll_unpin(cx));
-        lock.receive_delay.reset(deadline + receive_latency);
+        if reset {
+            lock.receive_delay.reset(deadline + receive_latency);
+        }
         // since the latency delay has elapsed, the socket is not disconnected, and it's not clogged, we can
         // return Ready.
         Poll::Ready(Ok(()))
     }
+
+    /// Polls until the close of the peer is visible to reads, returning false if the peer has
+    /// not closed the stream.
+    fn poll_peer_closed(&self, cx: &mut Context<'_>) -> Poll<bool> {
+        let mut lock = self.fault_state.lock().unwrap();
+        let visible_at = match lock.peer_closed {
+            Some(visible_at) => visible_at,
+            None => return Poll::Ready(false),
+        };
+        if visible_at > self.handle.now() {
+            if lock.close_timer.deadline() != visible_at {
+                lock.close_timer.reset(visible_at);
+            }
+            futures::ready!(lock.close_timer.poll_unpin(cx));
+        }
+        Poll::Ready(true)
+    }
+
+    /// Polls until the window of a windowed link has room, returning the number of bytes which
+    /// can be written without exceeding the window. Links without a window are unlimited.
+    fn poll_window(&self, cx: &mut Context<'_>, len: usize) -> Poll<usize> {
+        let mut lock = self.fault_state.lock().unwrap();
+        let window = match lock.conditions.as_ref().and_then(LinkConditions::window) {
+            Some(window) => window as usize,
+            None => return Poll::Ready(len),
+        };
+        loop {
+            lock.acknowledge(self.handle.now());
+            if lock.in_flight_bytes < window {
+                let room = (window - lock.in_flight_bytes) as f64 / lock.wire_ratio;
+                return Poll::Ready(std::cmp::min(len, std::cmp::max(room as usize, 1)));
+            }
+            let (acked_at, _) = *lock.in_flight.front().unwrap();
+            lock.send_delay.reset(acked_at);
+            futures::ready!(lock.send_delay.poll_unpin(cx));
+        }
+    }
+
+    /// Records a write on a windowed link, which holds the window until acknowledged one round
+    /// trip after it has been transmitted.
+    fn record_in_flight(&self, len: usize) {
+        let mut lock = self.fault_state.lock().unwrap();
+        let rtt = match lock.conditions.as_ref() {
+            Some(conditions) if conditions.window().is_some() => conditions.rtt(),
+            _ => return,
+        };
+        let transmitted = std::cmp::max(self.handle.now(), lock.send_delay.deadline());
+        let len = lock.wire_bytes(len);
+        lock.in_flight.push_back((transmitted + rtt, len));
+        lock.in_flight_bytes += len;
+    }
+}
+
+impl FaultyTcpStream<SocketHalf> {
+    /// Overrides the local and peer addresses reported by the stream, as for connections accepted
+    /// by dual-stack listeners.
+    pub(crate) fn report_addrs(&self, local: net::SocketAddr, peer: net::SocketAddr) {
+        self.fault_state
+            .lock()
+            .unwrap()
+            .migrated
+            .replace((local, peer));
+    }
+
+    /// Polls the stream for read readiness. Once ready, a subsequent read will not return
+    /// `Poll::Pending`, though it may return an error or EOF. Polling for readiness does not
+    /// consume the latency applied to reads.
+    pub fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        futures::ready!(self.poll_receive_delay(cx, false))?;
+        if self.fault_state.lock().unwrap().has_due_injection() {
+            return Poll::Ready(Ok(()));
+        }
+        self.inner.poll_read_ready(cx)
+    }
+
+    /// Polls the stream for write readiness. Once ready, a subsequent write will not return
+    /// `Poll::Pending` unless the stream is faulted in the meantime.
+    pub fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if let Some(err) = self.fault_state.lock().unwrap().write_shutdown_error() {
+            return Poll::Ready(Err(err));
+        }
+        futures::ready!(self.poll_send_delay(cx, false))?;
+        futures::ready!(self.poll_window(cx, 1));
+        self.inner.poll_write_ready(cx)
+    }
+}
+
+impl<T> Drop for FaultyTcpStream<T> {
+    fn drop(&mut self) {
+        {
+            let mut lock = self.fault_state.lock().unwrap();
+            lock.closed_at.replace(self.handle.now());
+            for waker in lock.drop_wakers.drain(..) {
+                waker.wake()
+            }
+            if let (Some(drops), Some(id)) = (lock.drops.as_ref(), lock.connection) {
+                drops.push(id);
+            }
+        }
+        notify_closed(&self.fault_state, self.handle.now());
+    }
 }
 
 impl<T> AsyncRead for FaultyTcpStream<T>
@@ -170,10 +925,54 @@ where
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<io::Result<usize>> {
-        if let Err(e) = futures::ready!(self.poll_receive_delay(cx)) {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if let Err(e) = futures::ready!(self.poll_receive_delay(cx, true)) {
             return Poll::Ready(Err(e));
         }
-        Pin::new(&mut self.inner).poll_read(cx, buf)
+        let limit = {
+            let mut lock = self.fault_state.lock().unwrap();
+            if let Some(bytes) = lock.take_due_injection() {
+                return Poll::Ready(Ok(lock.read_injected(bytes, buf)));
+            }
+            let limit = lock.read_limit(buf.len());
+            let delayed = lock.receive_delay.deadline();
+            let limit = lock.segment_limit(limit, self.handle.now());
+            if lock.receive_delay.deadline() > delayed {
+                // The segment has been held back, wait for it to arrive.
+                drop(lock);
+                return self.poll_read(cx, buf);
+            }
+            limit
+        };
+        let read = match Pin::new(&mut self.inner).poll_read(cx, &mut buf[..limit]) {
+            Poll::Ready(result) => result?,
+            Poll::Pending => {
+                // A peer which shut down writes leaves the underlying stream open, so report
+                // EOF once its buffered bytes have been read.
+                if futures::ready!(self.poll_peer_closed(cx)) {
+                    return Poll::Ready(Ok(0));
+                }
+                let mut lock = self.fault_state.lock().unwrap();
+                lock.read_waker.replace(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        if read > 0 {
+            let mut lock = self.fault_state.lock().unwrap();
+            lock.segment_remaining = lock.segment_remaining.saturating_sub(read);
+            lock.record_delivered(&buf[..read]);
+            lock.last_activity = self.handle.now();
+            return Poll::Ready(Ok(read));
+        }
+        // The underlying stream reached EOF, deliver any bytes injected after the close first.
+        futures::ready!(self.poll_peer_closed(cx));
+        let mut lock = self.fault_state.lock().unwrap();
+        match lock.take_close_injection() {
+            Some(bytes) => Poll::Ready(Ok(lock.read_injected(bytes, buf))),
+            None => Poll::Ready(Ok(0)),
+        }
     }
 }
 
@@ -186,13 +985,72 @@ where
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
-        if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
+        if let Some(err) = self.fault_state.lock().unwrap().write_shutdown_error() {
+            return Poll::Ready(Err(err));
+        }
+        if let Err(e) = futures::ready!(self.poll_send_delay(cx, true)) {
             return Poll::Ready(Err(e));
         }
-        Pin::new(&mut self.inner).poll_write(cx, buf)
+        let limit = futures::ready!(self.poll_window(cx, buf.len()));
+        let written = match Pin::new(&mut self.inner).poll_write(cx, &buf[..limit]) {
+            Poll::Ready(written) => written?,
+            Poll::Pending => {
+                let mut lock = self.fault_state.lock().unwrap();
+                lock.write_waker.replace(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        // Pace subsequent sends according to the conditions and faults of the link.
+        let reset = {
+            let mut lock = self.fault_state.lock().unwrap();
+            let wire = lock.wire_bytes(written);
+            if let Some(delay) = lock.conditions.as_ref().map(|c| c.sample_delay(wire)) {
+                let deadline = lock.send_delay.deadline();
+                lock.send_delay.reset(deadline + delay);
+            }
+            let fate = lock.link.as_ref().map(LinkFaults::on_write);
+            if let Some(fate) = fate {
+                let deadline = lock.send_delay.deadline();
+                lock.send_delay.reset(deadline + fate.delay);
+            }
+            let now = self.handle.now();
+            let transmitted = lock
+                .bandwidth
+                .iter()
+                .chain(lock.shared_link.iter())
+                .map(|throttle| throttle.reserve(now, wire))
+                .max();
+            if let Some(transmitted) = transmitted {
+                if transmitted > lock.send_delay.deadline() {
+                    lock.send_delay.reset(transmitted);
+                }
+            }
+            fate.map_or(false, |fate| fate.reset)
+        };
+        self.record_in_flight(written);
+        let mut lock = self.fault_state.lock().unwrap();
+        lock.written += written as u64;
+        lock.last_activity = self.handle.now();
+        if !lock.taps.is_empty() {
+            let addrs = match lock.migrated {
+                Some((local, peer)) => (Ok(local), Ok(peer)),
+                None => (self.inner.local_addr(), self.inner.peer_addr()),
+            };
+            if let (Ok(source), Ok(dest)) = addrs {
+                let now = self.handle.now();
+                for tap in lock.taps.iter() {
+                    tap.record(lock.connection, source, dest, now, &buf[..written]);
+                }
+            }
+        }
+        drop(lock);
+        if reset {
+            reset_from_link(&self.fault_state, &self.handle);
+        }
+        Poll::Ready(Ok(written))
     }
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
-        if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
+        if let Err(e) = futures::ready!(self.poll_send_delay(cx, true)) {
             return Poll::Ready(Err(e));
         }
         Pin::new(&mut self.inner).poll_flush(cx)
@@ -201,10 +1059,181 @@ where
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
     ) -> Poll<Result<(), io::Error>> {
-        if let Err(e) = futures::ready!(self.poll_send_delay(cx)) {
+        if let Err(e) = futures::ready!(self.poll_send_delay(cx, true)) {
             return Poll::Ready(Err(e));
         }
-        Pin::new(&mut self.inner).poll_shutdown(cx)
+        futures::ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        notify_closed(&self.fault_state, self.handle.now());
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Informs the peer of the stream that the stream has been closed. The peer observes the close
+/// once the close delay of the stream has elapsed.
+fn notify_closed(state: &sync::Arc<sync::Mutex<FaultState>>, now: time::Instant) {
+    let (peer, visible_at) = {
+        let lock = state.lock().unwrap();
+        let peer = lock.peer.as_ref().and_then(sync::Weak::upgrade);
+        (peer, now + lock.close_delay)
+    };
+    if let Some(peer) = peer {
+        let mut lock = peer.lock().unwrap();
+        if lock.peer_closed.is_none() {
+            lock.peer_closed.replace(visible_at);
+        }
+        if let Some(waker) = lock.read_waker.take() {
+            waker.wake()
+        }
+    }
+}
+
+fn set_keepalive(
+    state: &sync::Arc<sync::Mutex<FaultState>>,
+    handle: &crate::deterministic::DeterministicTimeHandle,
+    keepalive: Option<Keepalive>,
+) {
+    let mut lock = state.lock().unwrap();
+    lock.keepalive_generation += 1;
+    let generation = lock.keepalive_generation;
+    let wakers = lock
+        .probing
+        .take()
+        .map(|probing| probing.wakers)
+        .unwrap_or_default();
+    if let Some(keepalive) = keepalive {
+        lock.probing.replace(Probing {
+            keepalive,
+            generation,
+            unanswered: 0,
+            last_probe: None,
+            timed_out: false,
+            wakers,
+        });
+        drop(lock);
+        schedule_probe(state, handle, handle.now() + keepalive.idle, generation);
+    }
+}
+
+/// Resets the connection of a stream after a write, as decided by the faults of its link.
+fn reset_from_link(
+    state: &sync::Arc<sync::Mutex<FaultState>>,
+    handle: &crate::deterministic::DeterministicTimeHandle,
+) {
+    let (provenance, peer) = {
+        let mut lock = state.lock().unwrap();
+        let id = match lock.link.as_ref() {
+            Some(link) => link.fault_ids().next(),
+            None => return,
+        };
+        let provenance = FaultProvenance::new(id, FaultKind::Reset, handle.now(), lock.connection);
+        trace!(
+            "resetting {:?} after a write over a faulted link",
+            lock.connection
+        );
+        lock.disconnected.replace(provenance.clone());
+        lock.wake_pending();
+        (provenance, lock.peer.as_ref().and_then(sync::Weak::upgrade))
+    };
+    if let Some(peer) = peer {
+        let mut lock = peer.lock().unwrap();
+        lock.disconnected.replace(provenance.clone());
+        lock.wake_pending();
+    }
+    let id = provenance.connection_id();
+    let events = handle.events();
+    events
+        .observers()
+        .decision(DecisionPoint::Fault(FaultKind::Reset));
+    events.emit(SimulationEvent::ConnectionReset { id });
+}
+
+/// Schedules a keepalive probe of the stream at the provided instant.
+fn schedule_probe(
+    state: &sync::Arc<sync::Mutex<FaultState>>,
+    handle: &crate::deterministic::DeterministicTimeHandle,
+    at: time::Instant,
+    generation: u64,
+) {
+    let state = sync::Arc::downgrade(state);
+    let probe_handle = handle.clone();
+    handle
+        .events()
+        .add_breakpoint(Breakpoint::at(at), move |_| {
+            if let Some(state) = state.upgrade() {
+                probe(&state, &probe_handle, generation);
+            }
+        });
+}
+
+/// Probes the peer of an idle stream. Probes are answered if neither half of the connection
+/// is clogged in either direction, and the stream times out once the probes configured by its
+/// keepalive have gone unanswered.
+fn probe(
+    state: &sync::Arc<sync::Mutex<FaultState>>,
+    handle: &crate::deterministic::DeterministicTimeHandle,
+    generation: u64,
+) {
+    let now = handle.now();
+    let (keepalive, peer) = {
+        let mut lock = state.lock().unwrap();
+        let last_activity = lock.last_activity;
+        let probing = match lock.probing.as_mut() {
+            Some(probing) if probing.generation == generation && !probing.timed_out => probing,
+            _ => return,
+        };
+        // Bytes from the peer since the last probe answer it.
+        if probing
+            .last_probe
+            .map_or(false, |last| last_activity > last)
+        {
+            probing.unanswered = 0;
+        }
+        let keepalive = probing.keepalive;
+        if probing.unanswered == 0 && last_activity + keepalive.idle > now {
+            drop(lock);
+            schedule_probe(state, handle, last_activity + keepalive.idle, generation);
+            return;
+        }
+        if probing.unanswered >= keepalive.probes {
+            trace!("keepalive timed out after {} probes", probing.unanswered);
+            probing.timed_out = true;
+            for waker in probing.wakers.drain(..) {
+                waker.wake();
+            }
+            return;
+        }
+        (keepalive, lock.peer.as_ref().and_then(sync::Weak::upgrade))
+    };
+    let reachable = !state.lock().unwrap().is_clogged()
+        && peer.map_or(true, |peer| !peer.lock().unwrap().is_clogged());
+    let mut lock = state.lock().unwrap();
+    let probing = match lock.probing.as_mut() {
+        Some(probing) => probing,
+        None => return,
+    };
+    probing.last_probe.replace(now);
+    let next = if reachable {
+        probing.unanswered = 0;
+        now + keepalive.idle
+    } else {
+        probing.unanswered += 1;
+        trace!("keepalive probe {} went unanswered", probing.unanswered);
+        now + keepalive.interval
+    };
+    drop(lock);
+    schedule_probe(state, handle, next, generation);
+}
+
+/// Extension trait exposing the identifier of the simulated connection a stream belongs to.
+pub trait ConnectionIdExt {
+    /// Returns the identifier of the connection, or `None` if the stream was not created by a
+    /// simulated network.
+    fn connection_id(&self) -> Option<ConnectionId>;
+}
+
+impl<T> ConnectionIdExt for FaultyTcpStream<T> {
+    fn connection_id(&self) -> Option<ConnectionId> {
+        self.fault_state.lock().unwrap().connection
     }
 }
 
@@ -213,22 +1242,30 @@ where
     T: TcpStream,
 {
     fn local_addr(&self) -> io::Result<net::SocketAddr> {
-        T::local_addr(&self.inner)
+        match self.fault_state.lock().unwrap().migrated {
+            Some((local, _)) => Ok(local),
+            None => T::local_addr(&self.inner),
+        }
     }
     fn peer_addr(&self) -> io::Result<net::SocketAddr> {
-        T::peer_addr(&self.inner)
+        match self.fault_state.lock().unwrap().migrated {
+            Some((_, peer)) => Ok(peer),
+            None => T::peer_addr(&self.inner),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::deterministic::network::fault::FaultProvenanceExt;
     use crate::deterministic::network::socket::new_socket_pair;
-    use crate::Environment;
+    use crate::{Environment, TcpListener};
 
     use futures::{SinkExt, StreamExt};
     use std::time;
     use tokio::codec::{Framed, LinesCodec};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
     #[test]
     /// Test that injecting delay and disconnect faults causes the socket to delay and disconnect reads.
@@ -314,6 +1351,97 @@ mod tests {
         });
     }
 
+    #[test]
+    /// Test that keepalive probes time out reads once the peer becomes unreachable in one
+    /// direction, while bytes from the peer keep the stream alive.
+    fn keepalive_detects_half_open() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            let (mut server_conn, server_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), server_conn);
+            client_handle.set_peer(&server_handle);
+            server_handle.set_peer(&client_handle);
+            client_conn.set_keepalive(Some(Keepalive::new(
+                time::Duration::from_secs(60),
+                time::Duration::from_secs(10),
+                3,
+            )));
+
+            // Traffic from the server before the keepalive idle time defers probing.
+            handle.delay_from(time::Duration::from_secs(30)).await;
+            server_conn.write_all(b"ping").await.unwrap();
+            let start = handle.now();
+            client_conn.read_exact(&mut [0u8; 4]).await.unwrap();
+
+            // The server can no longer send to the client.
+            server_handle.clog_sends();
+            let err = client_conn.read(&mut [0u8; 4]).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+            assert_eq!(handle.now() - start, time::Duration::from_secs(90));
+
+            // The server did not enable keepalive, so it remains unaware of the failure.
+            let read = handle.timeout(
+                server_conn.read(&mut [0u8; 4]),
+                time::Duration::from_secs(600),
+            );
+            assert!(read.await.is_err(), "expected server read to hang");
+        });
+    }
+
+    #[test]
+    /// Test that a half-closed stream delivers EOF to the peer after the close delay while
+    /// still reading, and that a reset fails a pending read with `ConnectionReset`.
+    fn reset_and_half_close() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            let (mut server_conn, server_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), server_conn);
+            client_handle.set_peer(&server_handle);
+            server_handle.set_peer(&client_handle);
+
+            client_conn.write_all(b"request").await.unwrap();
+            client_handle.delay_close(time::Duration::from_secs(2));
+            let start = handle.now();
+            let shutdown = client_handle.shutdown_write();
+            let err = client_conn.write_all(b"more").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            assert_eq!(err.fault_provenance(), Some(&shutdown));
+
+            let mut request = vec![];
+            server_conn.read_to_end(&mut request).await.unwrap();
+            assert_eq!(request, b"request".to_vec());
+            assert_eq!(handle.now() - start, time::Duration::from_secs(2));
+
+            // The client can still read the response.
+            server_conn.write_all(b"response").await.unwrap();
+            let mut buf = [0u8; 8];
+            client_conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"response");
+
+            let read = client_conn.read(&mut buf);
+            futures::pin_mut!(read);
+            tokio_test::assert_pending!(futures::poll!(read.as_mut()));
+            let reset = server_handle.reset_now();
+            let err = read.await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            assert_eq!(err.fault_provenance(), Some(&reset));
+            let err = server_conn.write_all(b"late").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        });
+    }
+
     #[test]
     /// Test that injecting no faults allows the socket to behave normally.
     fn inactive_faults() {
@@ -360,4 +1488,174 @@ mod tests {
             );
         });
     }
+
+    #[test]
+    /// Test that injected bytes are delivered to the reader at their injection points, and that
+    /// zero-length reads complete immediately without consuming them.
+    fn byte_injection() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            client_handle.inject(Bytes::from_static(b"<"), InjectionPoint::Prepend);
+            client_handle.inject(Bytes::from_static(b"|"), InjectionPoint::Offset(3));
+            client_handle.inject(Bytes::from_static(b">"), InjectionPoint::AfterClose);
+            assert_eq!(client_conn.read(&mut []).await.unwrap(), 0);
+
+            handle.spawn(async move {
+                server_conn.write_all(b"abcdef").await.unwrap();
+            });
+
+            let mut received = vec![];
+            let mut buf = [0u8; 16];
+            while let Ok(read) = client_conn.read(&mut buf).await {
+                if read == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..read]);
+            }
+            assert_eq!(received, b"<abc|def>".to_vec());
+        });
+    }
+
+    #[test]
+    /// Test that replaying previously read bytes re-delivers them before new bytes.
+    fn replay_bytes() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, mut server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            handle.spawn(async move {
+                server_conn.write_all(b"abcd").await.unwrap();
+            });
+
+            let mut buf = [0u8; 3];
+            let read = client_conn.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..read], b"abc");
+            assert_eq!(client_handle.replay(2), 2);
+            let read = client_conn.read(&mut buf).await.unwrap();
+            assert_eq!(
+                &buf[..read],
+                b"bc",
+                "expected replayed bytes to be read first"
+            );
+            let read = client_conn.read(&mut buf).await.unwrap();
+            assert_eq!(&buf[..read], b"d");
+        });
+    }
+
+    #[test]
+    /// Test that errors caused by an injected disconnect carry the provenance of the fault.
+    fn disconnect_provenance() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, _server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            handle.delay_from(time::Duration::from_secs(5)).await;
+            let injected = client_handle.disconnect();
+            let mut buf = [0u8; 8];
+            let err = client_conn.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            let provenance = err
+                .fault_provenance()
+                .expect("expected error to carry fault provenance");
+            assert_eq!(provenance, &injected);
+            assert_eq!(provenance.kind(), FaultKind::Disconnect);
+            assert_eq!(provenance.injected_at(), handle.now());
+        });
+    }
+
+    #[test]
+    /// Test that readiness reflects staged bytes and latency without consuming the latency
+    /// applied to reads.
+    fn readiness() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (client_conn, server_conn) = new_socket_pair(client_addr, server_addr);
+            let (mut client_conn, client_handle) =
+                FaultyTcpStream::wrap(handle.time_handle(), client_conn);
+            let (mut server_conn, _) = FaultyTcpStream::wrap(handle.time_handle(), server_conn);
+
+            let waker = futures::task::noop_waker();
+            let mut cx = Context::from_waker(&waker);
+            assert!(client_conn.poll_read_ready(&mut cx).is_pending());
+
+            futures::future::poll_fn(|cx| server_conn.poll_write_ready(cx))
+                .await
+                .unwrap();
+            server_conn.write_all(b"ping").await.unwrap();
+
+            client_handle.set_receive_latency(time::Duration::from_secs(10));
+            let start = handle.now();
+            futures::future::poll_fn(|cx| client_conn.poll_read_ready(cx))
+                .await
+                .unwrap();
+            let mut buf = [0u8; 4];
+            client_conn.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"ping");
+            assert_eq!(
+                handle.now(),
+                start,
+                "expected readiness not to delay the read"
+            );
+
+            drop(server_conn);
+            futures::future::poll_fn(|cx| client_conn.poll_read_ready(cx))
+                .await
+                .unwrap();
+            assert_eq!(client_conn.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    /// Test that segment faults split a write across several reads and hold segments back,
+    /// without reordering the bytes of the stream.
+    fn segment_faults() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new_with_seed(7).unwrap();
+        runtime.set_segment_faults(Some(
+            SegmentFaults::new()
+                .max_segment(8)
+                .hold_back(0.5, time::Duration::from_millis(100)),
+        ));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let messages: Vec<u8> = (0..64).collect();
+            let written = messages.clone();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(&written).await.unwrap();
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            let start = handle.now();
+            let mut received = vec![];
+            let mut reads = 0;
+            let mut buf = [0u8; 64];
+            while received.len() < messages.len() {
+                let read = socket.read(&mut buf).await.unwrap();
+                assert!(read > 0 && read <= 8);
+                received.extend_from_slice(&buf[..read]);
+                reads += 1;
+            }
+            assert_eq!(received, messages);
+            assert!(reads >= 8);
+            assert!(handle.now() > start, "no segments were held back");
+        });
+    }
 }
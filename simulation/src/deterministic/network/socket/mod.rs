@@ -1,21 +1,104 @@
+use super::resources::{Descriptor, HostResources};
+use super::unconsumed::CloseMonitor;
+use crate::deterministic::events::{Events, SimulationEvent};
+use crate::deterministic::task::{self, WaitResource};
+use crate::deterministic::watermark::{Buffer, Watermarks};
 use bytes::{Buf, Bytes, IntoBuf};
-use futures::{channel::mpsc, Future, Poll, Sink, SinkExt, Stream};
-use std::{fmt, io, net, pin::Pin, task::Context};
+use futures::{channel::mpsc, task::AtomicWaker, Future, Poll, Sink, SinkExt, Stream};
+use std::{
+    fmt, io, net,
+    pin::Pin,
+    sync::{self, atomic},
+    task::Context,
+};
 use tokio::io::{AsyncRead, AsyncWrite};
 pub mod fault;
-pub use fault::{FaultyTcpStream, FaultyTcpStreamHandle};
+pub use fault::{
+    ConnectionIdExt, FaultyTcpStream, FaultyTcpStreamHandle, InjectionPoint, Keepalive,
+    SegmentFaults,
+};
 use tracing::{span, trace, Level};
 
+/// Sizes of the send and receive buffers of each half of a connection. Data written by one
+/// half occupies its send buffer and the receive buffer of its peer until the peer reads it,
+/// so a half can have at most `send + receive` unread bytes in flight. Writes beyond that
+/// return `Pending` until the peer reads, as with TCP flow control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SocketBuffers {
+    pub send: usize,
+    pub receive: usize,
+}
+
+impl SocketBuffers {
+    fn capacity(self) -> usize {
+        self.send + self.receive
+    }
+}
+
+/// Flow control of the bytes written by one half of a connection.
+#[derive(Default)]
+struct Window {
+    /// Bytes which can be written before the peer reads, or `None` if writes are unbounded.
+    capacity: Option<usize>,
+    /// Waker of the writer waiting for the peer to read.
+    writer: AtomicWaker,
+    /// Resources of the writing host, charged for the bytes the peer has not read yet.
+    memory: sync::Mutex<Option<HostResources>>,
+}
+
+impl Window {
+    fn reserve(&self, len: usize) -> io::Result<usize> {
+        match self.memory.lock().unwrap().as_ref() {
+            Some(memory) => memory.reserve(len),
+            None => Ok(len),
+        }
+    }
+
+    fn release(&self, len: usize) {
+        if let Some(memory) = self.memory.lock().unwrap().as_ref() {
+            memory.release(len);
+        }
+    }
+}
+
 /// Returns a client/server socket pair, along with a SocketHandle which can be used to close
 /// either side of the socket halfs.
 pub fn new_socket_pair(
     client_addr: net::SocketAddr,
     server_addr: net::SocketAddr,
+) -> (SocketHalf, SocketHalf) {
+    new_socket_pair_with_buffers(client_addr, server_addr, None)
+}
+
+/// Returns a client/server socket pair whose writes are limited by the provided buffer sizes,
+/// see [`SocketBuffers`]. Writes are unbounded when no buffer sizes are provided.
+///
+/// [`SocketBuffers`]:`SocketBuffers`
+pub fn new_socket_pair_with_buffers(
+    client_addr: net::SocketAddr,
+    server_addr: net::SocketAddr,
+    buffers: Option<SocketBuffers>,
 ) -> (SocketHalf, SocketHalf) {
     let (client_tx, client_rx) = mpsc::channel(8);
     let (server_tx, server_rx) = mpsc::channel(8);
-    let client_socket = SocketHalf::new(client_addr, server_addr, client_tx, server_rx);
-    let server_socket = SocketHalf::new(server_addr, client_addr, server_tx, client_rx);
+    let reset = sync::Arc::new(atomic::AtomicBool::new(false));
+    let mut client_socket = SocketHalf::new(client_addr, server_addr, client_tx, server_rx);
+    let mut server_socket = SocketHalf::new(server_addr, client_addr, server_tx, client_rx);
+    client_socket.reset = sync::Arc::clone(&reset);
+    server_socket.reset = reset;
+    server_socket.received = sync::Arc::clone(&client_socket.sent);
+    client_socket.received = sync::Arc::clone(&server_socket.sent);
+    let window = || {
+        sync::Arc::new(Window {
+            capacity: buffers.map(SocketBuffers::capacity),
+            writer: AtomicWaker::new(),
+            memory: sync::Mutex::default(),
+        })
+    };
+    client_socket.send_window = window();
+    server_socket.send_window = window();
+    server_socket.receive_window = sync::Arc::clone(&client_socket.send_window);
+    client_socket.receive_window = sync::Arc::clone(&server_socket.send_window);
     (client_socket, server_socket)
 }
 
@@ -24,8 +107,25 @@ pub struct SocketHalf {
     rx: mpsc::Receiver<Bytes>,
     staged: Option<Bytes>,
     shutdown: bool,
+    /// Set once a write has been discarded because the peer closed the connection. As when the
+    /// peer answers such a write with a reset, further writes fail with `BrokenPipe`.
+    refused: bool,
     local_addr: net::SocketAddr,
     peer_addr: net::SocketAddr,
+    /// Set once either half of the connection has been reset.
+    reset: sync::Arc<atomic::AtomicBool>,
+    close_monitor: Option<CloseMonitor>,
+    /// Bytes written by this half which the peer has not read yet.
+    sent: sync::Arc<atomic::AtomicUsize>,
+    /// Bytes written by the peer which this half has not read yet.
+    received: sync::Arc<atomic::AtomicUsize>,
+    send_window: sync::Arc<Window>,
+    /// Window of the peer, whose writer is woken as this half reads.
+    receive_window: sync::Arc<Window>,
+    watermarks: Option<Watermarks>,
+    events: Option<Events>,
+    /// Descriptor of the host holding this half open.
+    descriptor: Option<Descriptor>,
 }
 
 impl fmt::Debug for SocketHalf {
@@ -53,8 +153,18 @@ impl SocketHalf {
             rx,
             staged: None,
             shutdown: false,
+            refused: false,
             local_addr,
             peer_addr,
+            reset: sync::Arc::default(),
+            close_monitor: None,
+            sent: sync::Arc::default(),
+            received: sync::Arc::default(),
+            send_window: sync::Arc::default(),
+            receive_window: sync::Arc::default(),
+            watermarks: None,
+            events: None,
+            descriptor: None,
         }
     }
     pub fn local_addr(&self) -> net::SocketAddr {
@@ -63,9 +173,131 @@ impl SocketHalf {
     pub fn peer_addr(&self) -> net::SocketAddr {
         self.peer_addr
     }
+    /// Swap the underlying channels of two socket halves, causing each half to exchange bytes
+    /// with the peer of the other.
+    pub(crate) fn cross(&mut self, other: &mut SocketHalf) {
+        std::mem::swap(&mut self.tx, &mut other.tx);
+        std::mem::swap(&mut self.rx, &mut other.rx);
+        std::mem::swap(&mut self.staged, &mut other.staged);
+        std::mem::swap(&mut self.reset, &mut other.reset);
+        std::mem::swap(&mut self.sent, &mut other.sent);
+        std::mem::swap(&mut self.received, &mut other.received);
+        std::mem::swap(&mut self.send_window, &mut other.send_window);
+        std::mem::swap(&mut self.receive_window, &mut other.receive_window);
+    }
     pub(crate) fn connected(&self) -> bool {
         !self.tx.is_closed()
     }
+    /// Report bytes which remain unread when this half is closed to the provided monitor.
+    pub(crate) fn set_close_monitor(&mut self, monitor: CloseMonitor) {
+        self.close_monitor.replace(monitor);
+    }
+    /// Report the levels of the buffers of this half to the provided watermark monitor.
+    pub(crate) fn set_watermarks(&mut self, watermarks: Watermarks) {
+        self.watermarks.replace(watermarks);
+    }
+    /// Emits the bytes written by this half as simulation events.
+    pub(crate) fn set_events(&mut self, events: Events) {
+        self.events.replace(events);
+    }
+    /// Holds the provided descriptor of the host of this half until it is dropped.
+    pub(crate) fn set_descriptor(&mut self, descriptor: Descriptor) {
+        self.descriptor.replace(descriptor);
+    }
+    /// Charges the bytes written by this half to the memory budget of the provided host.
+    pub(crate) fn set_resources(&mut self, resources: HostResources) {
+        self.send_window.memory.lock().unwrap().replace(resources);
+    }
+    /// Records bytes written by this half, which remain buffered until the peer reads them.
+    fn record_sent(&self, len: usize) {
+        let level = self.sent.fetch_add(len, atomic::Ordering::SeqCst) + len;
+        self.report_buffered(self.local_addr, self.peer_addr, level);
+    }
+    /// Records bytes read by this half, draining the buffer of bytes sent by the peer.
+    fn record_read(&self, len: usize) {
+        let level = self.received.fetch_sub(len, atomic::Ordering::SeqCst) - len;
+        self.report_buffered(self.peer_addr, self.local_addr, level);
+        self.receive_window.release(len);
+        self.receive_window.writer.wake();
+    }
+    fn report_buffered(&self, from: net::SocketAddr, to: net::SocketAddr, level: usize) {
+        if let Some(watermarks) = self.watermarks.as_ref() {
+            watermarks.record(Buffer::Connection { from, to }, level);
+        }
+    }
+    fn is_reset(&self) -> bool {
+        self.reset.load(atomic::Ordering::SeqCst)
+    }
+    /// Polls for room in the buffers of the connection, returning the number of bytes which can
+    /// be written before the peer reads. Writes are not limited once the peer has gone away, as
+    /// they fail immediately.
+    fn poll_capacity(&self, cx: &mut Context<'_>) -> Poll<usize> {
+        let capacity = match self.send_window.capacity {
+            Some(capacity) if !self.tx.is_closed() => capacity,
+            _ => return Poll::Ready(usize::max_value()),
+        };
+        let available = || capacity.saturating_sub(self.sent.load(atomic::Ordering::SeqCst));
+        if available() > 0 {
+            return Poll::Ready(available());
+        }
+        self.send_window.writer.register(cx.waker());
+        // The peer may have read between checking and registering.
+        match available() {
+            0 => Poll::Pending,
+            available => Poll::Ready(available),
+        }
+    }
+    /// Returns the number of received bytes which have not been read.
+    fn unread(&mut self) -> usize {
+        let mut unread = self.staged.as_ref().map_or(0, Bytes::len);
+        while let Ok(Some(bytes)) = self.rx.try_next() {
+            unread += bytes.len();
+        }
+        unread
+    }
+    /// Polls for read readiness, staging the next received bytes. Readiness is also signaled
+    /// once the peer has closed the connection, as reads will then complete immediately.
+    pub(crate) fn poll_read_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if self.staged.is_some() {
+            return Poll::Ready(Ok(()));
+        }
+        let poll = Pin::new(&mut self.rx).poll_next(cx);
+        if poll.is_pending() {
+            task::wait_on(WaitResource::Read {
+                from: self.peer_addr,
+                to: self.local_addr,
+            });
+        }
+        if let Some(bytes) = futures::ready!(poll) {
+            self.staged.replace(bytes);
+        }
+        Poll::Ready(Ok(()))
+    }
+    /// Polls for write readiness, resolving once the peer has capacity for further bytes.
+    pub(crate) fn poll_write_ready(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if self.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        let poll = match self.poll_capacity(cx) {
+            Poll::Ready(_) => Pin::new(&mut self.tx).poll_ready(cx),
+            Poll::Pending => Poll::Pending,
+        };
+        if poll.is_pending() {
+            task::wait_on(WaitResource::Write {
+                from: self.local_addr,
+                to: self.peer_addr,
+            });
+        }
+        match futures::ready!(poll) {
+            Ok(()) => Poll::Ready(Ok(())),
+            // The next write is discarded rather than failing.
+            Err(_) if !self.refused && !self.shutdown => Poll::Ready(Ok(())),
+            Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+        }
+    }
     /// Attempt to read any staged bytes into `dst`. Returns the number of bytes read, or None if
     /// no bytes were staged.
     fn read_staged(&mut self, dst: &mut [u8]) -> Option<usize> {
@@ -93,23 +325,40 @@ impl AsyncRead for SocketHalf {
     ) -> Poll<io::Result<usize>> {
         // span! macro seems to trip up clippy here
         #![allow(clippy::cognitive_complexity)]
+        task::hold(WaitResource::Write {
+            from: self.peer_addr,
+            to: self.local_addr,
+        });
+        if self.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
         span!(Level::TRACE, "AsyncRead::poll_read", "{:?}", self).in_scope(|| loop {
             trace!("attempting to read {} bytes", dst.len());
             if let Some(bytes_read) = self.read_staged(dst) {
                 trace!("read {} bytes", bytes_read);
+                self.record_read(bytes_read);
                 return Poll::Ready(Ok(bytes_read));
             }
 
             trace!("no bytes staged");
             let stream = Pin::new(&mut self.rx);
-            match futures::ready!(stream.poll_next(cx)) {
+            let poll = stream.poll_next(cx);
+            if poll.is_pending() {
+                task::wait_on(WaitResource::Read {
+                    from: self.peer_addr,
+                    to: self.local_addr,
+                });
+            }
+            match futures::ready!(poll) {
                 Some(new_bytes) => {
                     trace!("found staged bytes");
                     self.staged.replace(new_bytes)
                 }
                 None => {
+                    // Every byte the peer wrote has been read, and the peer has closed the
+                    // connection or shut down writes.
                     trace!("socket disconnected");
-                    return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+                    return Poll::Ready(Ok(0));
                 }
             };
         })
@@ -122,21 +371,73 @@ impl AsyncWrite for SocketHalf {
         cx: &mut Context<'_>,
         buf: &[u8],
     ) -> Poll<Result<usize, io::Error>> {
+        let (local_addr, peer_addr) = (self.local_addr, self.peer_addr);
+        task::hold(WaitResource::Read {
+            from: local_addr,
+            to: peer_addr,
+        });
+        if self.is_reset() {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
         span!(Level::TRACE, "AsyncWrite::poll_write", "{:?}", self).in_scope(|| {
-            let size = buf.len();
-            let bytes: Bytes = buf.into();
+            let available = self.poll_capacity(cx);
+            if available.is_pending() {
+                trace!("socket buffers are full");
+                task::wait_on(WaitResource::Write {
+                    from: local_addr,
+                    to: peer_addr,
+                });
+            }
+            let size = std::cmp::min(buf.len(), futures::ready!(available));
+            let size = self.send_window.reserve(size)?;
+            let bytes: Bytes = buf[..size].into();
             trace!("writing {} bytes", size);
-            let send = self.tx.send(bytes);
-            futures::pin_mut!(send);
-            match futures::ready!(send.poll(cx)) {
-                Ok(()) => Poll::Ready(Ok(size)),
-                Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            let poll = {
+                let send = self.tx.send(bytes);
+                futures::pin_mut!(send);
+                send.poll(cx)
+            };
+            if poll.is_pending() {
+                self.send_window.release(size);
+                task::wait_on(WaitResource::Write {
+                    from: local_addr,
+                    to: peer_addr,
+                });
+            }
+            match futures::ready!(poll) {
+                Ok(()) => {
+                    self.record_sent(size);
+                    if let Some(events) = self.events.as_ref() {
+                        events.emit(SimulationEvent::BytesSent {
+                            from: local_addr,
+                            to: peer_addr,
+                            bytes: size,
+                        });
+                    }
+                    Poll::Ready(Ok(size))
+                }
+                Err(_) if !self.refused && !self.shutdown => {
+                    // The peer has closed the connection. The first write afterwards is
+                    // accepted and discarded, and its reset fails any further writes.
+                    trace!("discarding {} bytes written to a closed peer", size);
+                    self.send_window.release(size);
+                    self.refused = true;
+                    Poll::Ready(Ok(size))
+                }
+                Err(_) => {
+                    self.send_window.release(size);
+                    Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()))
+                }
             }
         })
     }
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), io::Error>> {
         span!(Level::TRACE, "AsyncWrite::poll_flush", "{:?}", self).in_scope(|| {
             trace!("flushing");
+            if self.tx.is_closed() {
+                // Bytes written to a closed peer are discarded, so there is nothing to flush.
+                return Poll::Ready(Ok(()));
+            }
             let stream = &mut self.tx;
             futures::pin_mut!(stream);
             stream
@@ -150,13 +451,40 @@ impl AsyncWrite for SocketHalf {
     ) -> Poll<Result<(), io::Error>> {
         span!(Level::TRACE, "AsyncWrite::poll_flush", "{:?}", self).in_scope(|| {
             trace!("shutting down");
-            Pin::new(&mut self.tx)
-                .poll_close(cx)
-                .map_err(|_| io::ErrorKind::BrokenPipe.into())
+            match futures::ready!(Pin::new(&mut self.tx).poll_close(cx)) {
+                Ok(()) => {
+                    self.shutdown = true;
+                    Poll::Ready(Ok(()))
+                }
+                Err(_) => Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            }
         })
     }
 }
 
+impl Drop for SocketHalf {
+    fn drop(&mut self) {
+        // A peer waiting for this half to read fails its write once it is woken.
+        self.receive_window.writer.wake();
+        // Bytes sent to this half will never be read, so return them to the budget of the peer.
+        self.receive_window
+            .release(self.received.load(atomic::Ordering::SeqCst));
+        if let Some(watermarks) = self.watermarks.take() {
+            // Bytes sent to this half will never be read, so stop tracking them.
+            watermarks.clear(&Buffer::Connection {
+                from: self.peer_addr,
+                to: self.local_addr,
+            });
+        }
+        if let Some(monitor) = self.close_monitor.take() {
+            let unread = self.unread();
+            if monitor.closed(self.local_addr, self.peer_addr, unread) {
+                self.reset.store(true, atomic::Ordering::SeqCst);
+            }
+        }
+    }
+}
+
 impl crate::TcpStream for SocketHalf {
     fn local_addr(&self) -> io::Result<net::SocketAddr> {
         Ok(self.local_addr)
@@ -214,8 +542,8 @@ mod tests {
     }
 
     #[test]
-    /// Tests that disconnecting the server and client will cause both the server and client to fail further
-    /// reads/writes with an error.
+    /// Tests that closing the server causes the client to read EOF, and to fail writes after the
+    /// first write to the closed server.
     fn test_disconnect() {
         let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
         let handle = runtime.localhost_handle();
@@ -234,20 +562,108 @@ mod tests {
                 match msg_num {
                     num if num < 2 => {
                         // since the server closes at 3 requests, 2 or less should be fine
-                        assert!(send_result.is_ok(), "expected sends to succeed because the server is not closed");
+                        assert!(
+                            send_result.is_ok(),
+                            "expected sends to succeed because the server is not closed"
+                        );
                         let receive_result = transport.next().await;
-                        assert_eq!(receive_result.unwrap().unwrap(), String::from("pong"), "expected received to succeed");
+                        assert_eq!(
+                            receive_result.unwrap().unwrap(),
+                            String::from("pong"),
+                            "expected received to succeed"
+                        );
                     }
                     num if num == 2 => {
                         assert!(send_result.is_ok(), "expected send to succeed");
-                        assert!(transport.next().await.unwrap().is_err(), "msg num 2 should cause the server to close, resulting in an err returned by the receive")
+                        assert!(
+                            transport.next().await.is_none(),
+                            "msg num 2 should cause the server to close, resulting in EOF"
+                        )
+                    }
+                    num if num == 3 => {
+                        assert!(
+                            send_result.is_ok(),
+                            "the first send after the server closed should be discarded"
+                        );
                     }
                     _ => {
-                        assert!(send_result.is_err(), "now that the server is closed, sends should always fail");
+                        assert!(
+                            send_result.is_err(),
+                            "now that the server is closed, sends should always fail"
+                        );
                     }
                 }
             }
             server_status.await.unwrap();
         });
     }
+
+    #[test]
+    /// Test that writes return `Pending` once the socket buffers are full, and resume as the
+    /// peer reads.
+    fn test_socket_buffers() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let buffers = SocketBuffers {
+                send: 4,
+                receive: 4,
+            };
+            let (mut client, mut server) =
+                new_socket_pair_with_buffers(client_addr, server_addr, Some(buffers));
+            assert_eq!(client.write(&[0u8; 10]).await.unwrap(), 8);
+
+            let written = sync::Arc::new(atomic::AtomicBool::new(false));
+            let writer_done = sync::Arc::clone(&written);
+            handle.spawn(async move {
+                client.write_all(&[1u8; 8]).await.unwrap();
+                writer_done.store(true, atomic::Ordering::SeqCst);
+            });
+            handle.delay_from(std::time::Duration::from_secs(1)).await;
+            assert!(!written.load(atomic::Ordering::SeqCst));
+
+            let mut buf = [0u8; 16];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf[..8], &[0u8; 8]);
+            assert_eq!(&buf[8..], &[1u8; 8]);
+            assert!(written.load(atomic::Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    /// Test that a peer dropped with bytes in flight is read to EOF after the buffered bytes,
+    /// that readers waiting on the peer observe EOF once it is dropped, and that writes to the
+    /// dropped peer fail with `BrokenPipe` after the first.
+    fn test_graceful_close() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr = "127.0.0.1:9092".parse().unwrap();
+            let client_addr = "127.0.0.1:35255".parse().unwrap();
+            let (mut client, mut server) = new_socket_pair(client_addr, server_addr);
+            server.write_all(b"hello").await.unwrap();
+            server.write_all(b"world").await.unwrap();
+            drop(server);
+            let mut received = vec![];
+            client.read_to_end(&mut received).await.unwrap();
+            assert_eq!(received, b"helloworld".to_vec());
+            assert_eq!(client.read(&mut [0u8; 1]).await.unwrap(), 0);
+
+            assert_eq!(client.write(b"ping").await.unwrap(), 4);
+            let err = client.write(b"ping").await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+
+            let (mut client, server) = new_socket_pair(client_addr, server_addr);
+            let read = crate::spawn_with_result(&handle, async move {
+                client.read(&mut [0u8; 1]).await.unwrap()
+            });
+            handle.delay_from(std::time::Duration::from_secs(1)).await;
+            drop(server);
+            assert_eq!(read.await, 0);
+        });
+    }
 }
@@ -0,0 +1,278 @@
+//! The table of connections registered with a network.
+//!
+//! Simulations of large clusters hold many thousands of connections, and connects, accepts and
+//! listener limits look up the connections to a destination or from a source far more often
+//! than connections are dropped. The [`ConnectionTable`] keeps connections keyed by their id,
+//! iterating them in the order they were registered, which fault injectors choosing a random
+//! connection rely on for determinism, and indexes their ids by destination and by source so
+//! that lookups only visit the connections of a single address.
+//!
+//! Streams report their connection to the table when they are dropped, so collecting dropped
+//! connections only visits the connections which were reported or are lingering in TIME_WAIT,
+//! rather than checking every connection on every connect. Removing a connection only removes
+//! its id from the entries of its own addresses, so the cost of churning connections does not
+//! grow with the number of connections held open.
+//!
+//! [`ConnectionTable`]:`ConnectionTable`
+use super::fault::{Connection, ConnectionId, ConnectionSide};
+use std::{collections, net, sync, time};
+
+/// Connections dropped since the table last collected them, shared with their streams.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct DroppedConnections {
+    inner: sync::Arc<sync::Mutex<Vec<ConnectionId>>>,
+}
+
+impl DroppedConnections {
+    pub(crate) fn push(&self, id: ConnectionId) {
+        self.inner.lock().unwrap().push(id);
+    }
+
+    fn take(&self) -> Vec<ConnectionId> {
+        std::mem::replace(&mut *self.inner.lock().unwrap(), vec![])
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionTable {
+    /// Connections keyed by their id, which are assigned in the order connections are
+    /// registered.
+    connections: collections::BTreeMap<ConnectionId, Connection>,
+    /// Ids of the connections to each destination.
+    by_dest: collections::HashMap<net::SocketAddr, collections::BTreeSet<ConnectionId>>,
+    /// Ids of the connections from each source.
+    by_source: collections::HashMap<net::SocketAddr, collections::BTreeSet<ConnectionId>>,
+    dropped: DroppedConnections,
+    /// Dropped connections retained in TIME_WAIT. They are checked again once the earliest of
+    /// them expires, or once the TIME_WAIT duration they were retained with changes.
+    lingering: collections::BTreeSet<ConnectionId>,
+    next_expiry: Option<time::Instant>,
+    time_wait: Option<time::Duration>,
+}
+
+impl ConnectionTable {
+    pub(crate) fn push(&mut self, connection: Connection) {
+        for side in [ConnectionSide::Client, ConnectionSide::Server].iter() {
+            connection
+                .fault_handle(*side)
+                .report_drops(self.dropped.clone());
+        }
+        self.index(connection.id(), connection.source(), connection.dest());
+        self.connections.insert(connection.id(), connection);
+    }
+
+    /// Returns the connections, in registration order.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = &Connection> {
+        self.connections.values()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.connections.is_empty()
+    }
+
+    /// Returns the connections to `dest`, in registration order.
+    pub(crate) fn to_dest(&self, dest: net::SocketAddr) -> impl Iterator<Item = &Connection> {
+        self.at(self.by_dest.get(&dest))
+    }
+
+    /// Returns the connections from `source`, in registration order.
+    pub(crate) fn from_source(&self, source: net::SocketAddr) -> impl Iterator<Item = &Connection> {
+        self.at(self.by_source.get(&source))
+    }
+
+    fn at<'a>(
+        &'a self,
+        ids: Option<&'a collections::BTreeSet<ConnectionId>>,
+    ) -> impl Iterator<Item = &'a Connection> {
+        ids.into_iter()
+            .flatten()
+            .map(move |id| &self.connections[id])
+    }
+
+    /// Swaps the server halves of the first connections from `a` and from `b`.
+    pub(crate) fn swap_server(&mut self, a: net::SocketAddr, b: net::SocketAddr) {
+        let first = self
+            .by_source
+            .get(&a)
+            .and_then(|ids| ids.iter().next())
+            .cloned();
+        let second = self
+            .by_source
+            .get(&b)
+            .and_then(|ids| ids.iter().next())
+            .cloned();
+        if let (Some(first), Some(second)) = (first, second) {
+            if first == second {
+                return;
+            }
+            let mut connection = self.connections.remove(&first).unwrap();
+            connection.swap_server(self.connections.get_mut(&second).unwrap());
+            self.connections.insert(first, connection);
+        }
+    }
+
+    /// Applies `f` to every connection, moving connections whose addresses `f` changes to
+    /// their new addresses in the indexes.
+    pub(crate) fn modify<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&mut Connection),
+    {
+        let mut moved = vec![];
+        for (id, connection) in self.connections.iter_mut() {
+            let (source, dest) = (connection.source(), connection.dest());
+            f(connection);
+            if (source, dest) != (connection.source(), connection.dest()) {
+                moved.push((
+                    *id,
+                    (source, dest),
+                    (connection.source(), connection.dest()),
+                ));
+            }
+        }
+        for (id, (source, dest), (new_source, new_dest)) in moved {
+            self.deindex(id, source, dest);
+            self.index(id, new_source, new_dest);
+        }
+    }
+
+    /// Removes dropped connections, retaining any connections which are still in TIME_WAIT, and
+    /// returns the sources of the removed connections.
+    pub(crate) fn collect(
+        &mut self,
+        now: time::Instant,
+        time_wait: Option<time::Duration>,
+    ) -> Vec<net::SocketAddr> {
+        let mut candidates = self.dropped.take();
+        if time_wait != self.time_wait || self.next_expiry.map_or(false, |expiry| expiry <= now) {
+            candidates.extend(self.lingering.iter().cloned());
+            self.lingering.clear();
+            self.next_expiry = None;
+            self.time_wait = time_wait;
+        }
+        candidates.sort();
+        candidates.dedup();
+
+        let mut sources = vec![];
+        for id in candidates {
+            // Connections are reported by both of their streams, and may have been collected
+            // already.
+            let connection = match self.connections.get(&id) {
+                Some(connection) => connection,
+                None => continue,
+            };
+            match (time_wait, connection.closed_at()) {
+                (Some(time_wait), Some(closed_at)) if closed_at + time_wait > now => {
+                    let expiry = closed_at + time_wait;
+                    self.lingering.insert(id);
+                    self.next_expiry = Some(self.next_expiry.map_or(expiry, |e| e.min(expiry)));
+                }
+                _ => {
+                    let (source, dest) = (connection.source(), connection.dest());
+                    self.connections.remove(&id);
+                    self.deindex(id, source, dest);
+                    sources.push(source);
+                }
+            }
+        }
+        sources
+    }
+
+    fn index(&mut self, id: ConnectionId, source: net::SocketAddr, dest: net::SocketAddr) {
+        self.by_dest.entry(dest).or_default().insert(id);
+        self.by_source.entry(source).or_default().insert(id);
+    }
+
+    fn deindex(&mut self, id: ConnectionId, source: net::SocketAddr, dest: net::SocketAddr) {
+        deindex(&mut self.by_dest, dest, id);
+        deindex(&mut self.by_source, source, id);
+    }
+}
+
+/// Removes the id from the entry of the address, removing the entry once it is empty.
+fn deindex(
+    index: &mut collections::HashMap<net::SocketAddr, collections::BTreeSet<ConnectionId>>,
+    addr: net::SocketAddr,
+    id: ConnectionId,
+) {
+    if let Some(ids) = index.get_mut(&addr) {
+        ids.remove(&id);
+        if ids.is_empty() {
+            index.remove(&addr);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::DeterministicNetwork;
+    use super::*;
+    use crate::{Environment, TcpListener};
+
+    #[test]
+    /// Test that dropped connections are removed from the table and its indexes once collected,
+    /// including connections whose fault handles are still referenced.
+    fn collect_dropped() {
+        let mut runtime = crate::deterministic::DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let network = DeterministicNetwork::new(handle.time_handle());
+        runtime.block_on(async {
+            let server = network.scoped(net::Ipv4Addr::new(10, 0, 0, 1));
+            let client = network.scoped(net::Ipv4Addr::new(10, 0, 0, 2));
+            let addr = "10.0.0.1:9092".parse().unwrap();
+            let other = "10.0.0.1:9093".parse().unwrap();
+            let mut listener = server.bind(addr).await.unwrap();
+            let mut other_listener = server.bind(other).await.unwrap();
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((conn, _)) = listener.accept().await {
+                    accepted.push(conn);
+                }
+            });
+            handle.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((conn, _)) = other_listener.accept().await {
+                    accepted.push(conn);
+                }
+            });
+            let mut sockets = vec![];
+            for _ in 0..100 {
+                sockets.push(client.connect(addr).await.unwrap());
+            }
+            let _other = client.connect(other).await.unwrap();
+            let referenced = {
+                let lock = network.inner.lock().unwrap();
+                assert_eq!(lock.connections.to_dest(addr).count(), 100);
+                assert_eq!(lock.connections.to_dest(other).count(), 1);
+                lock.connections
+                    .iter()
+                    .next()
+                    .unwrap()
+                    .fault_handle(ConnectionSide::Client)
+                    .clone()
+            };
+            sockets.truncate(10);
+
+            let _next = client.connect(other).await.unwrap();
+            {
+                let lock = network.inner.lock().unwrap();
+                assert_eq!(lock.connections.len(), 12);
+                assert_eq!(lock.connections.to_dest(other).count(), 2);
+            }
+            let source = sockets[0].local_addr().unwrap();
+            drop(sockets.remove(0));
+            assert!(referenced.is_dropped());
+            let _next = client.connect(other).await.unwrap();
+            let lock = network.inner.lock().unwrap();
+            assert_eq!(lock.connections.len(), 12);
+            assert_eq!(lock.connections.to_dest(addr).count(), 9);
+            assert!(!lock
+                .connections
+                .from_source(source)
+                .any(|c| c.dest() == addr));
+        });
+    }
+}
@@ -0,0 +1,308 @@
+//! Message level taps on simulated connections.
+//!
+//! A tap decodes the bytes written to connections to a listener with a user supplied codec,
+//! recording each decoded message along with the virtual time it was written. Tests can then
+//! assert on protocol traffic, such as a node never sending a stale term, without modifying the
+//! application under test.
+use super::ConnectionId;
+use bytes::BytesMut;
+use std::{collections, fmt, net, sync, time};
+use tokio::codec::Decoder;
+use tracing::trace;
+
+/// Type erased tap attached to the streams of a connection.
+pub(crate) trait StreamTap: Send + Sync {
+    fn record(
+        &self,
+        connection: Option<ConnectionId>,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        at: time::Instant,
+        bytes: &[u8],
+    );
+}
+
+impl fmt::Debug for dyn StreamTap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "StreamTap")
+    }
+}
+
+/// A message decoded from the bytes written to a tapped connection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TappedMessage<M> {
+    at: time::Instant,
+    connection: Option<ConnectionId>,
+    source: net::SocketAddr,
+    dest: net::SocketAddr,
+    message: M,
+}
+
+impl<M> TappedMessage<M> {
+    /// Returns the time at which the final byte of the message was written.
+    pub fn at(&self) -> time::Instant {
+        self.at
+    }
+
+    pub fn connection_id(&self) -> Option<ConnectionId> {
+        self.connection
+    }
+
+    /// Returns the address of the stream which wrote the message.
+    pub fn source(&self) -> net::SocketAddr {
+        self.source
+    }
+
+    pub fn dest(&self) -> net::SocketAddr {
+        self.dest
+    }
+
+    pub fn message(&self) -> &M {
+        &self.message
+    }
+}
+
+impl<M: fmt::Debug> fmt::Display for TappedMessage<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(connection) = self.connection {
+            write!(f, "{} ", connection)?;
+        }
+        write!(
+            f,
+            "{} -> {} at {:?}: {:?}",
+            self.source, self.dest, self.at, self.message
+        )
+    }
+}
+
+struct Decoders<C> {
+    codec: C,
+    /// Undecoded bytes and a decoder for each direction of each tapped connection.
+    streams: collections::HashMap<(Option<ConnectionId>, net::SocketAddr), (C, BytesMut)>,
+}
+
+struct TapState<C: Decoder> {
+    decoders: sync::Mutex<Decoders<C>>,
+    messages: sync::Mutex<Vec<TappedMessage<C::Item>>>,
+}
+
+impl<C> StreamTap for TapState<C>
+where
+    C: Decoder + Clone + Send + 'static,
+    C::Item: Send,
+{
+    fn record(
+        &self,
+        connection: Option<ConnectionId>,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        at: time::Instant,
+        bytes: &[u8],
+    ) {
+        let mut decoders = self.decoders.lock().unwrap();
+        let codec = decoders.codec.clone();
+        let (codec, buf) = decoders
+            .streams
+            .entry((connection, source))
+            .or_insert_with(|| (codec, BytesMut::new()));
+        buf.extend_from_slice(bytes);
+        let mut messages = self.messages.lock().unwrap();
+        loop {
+            match codec.decode(buf) {
+                Ok(Some(message)) => messages.push(TappedMessage {
+                    at,
+                    connection,
+                    source,
+                    dest,
+                    message,
+                }),
+                Ok(None) => break,
+                Err(_) => {
+                    // Stop decoding the stream, as the codec cannot resynchronize.
+                    trace!("tap failed to decode bytes from {} to {}", source, dest);
+                    buf.clear();
+                    break;
+                }
+            }
+        }
+    }
+}
+
+/// Log of the messages written to connections to a listener, in the order they were written.
+pub struct MessageTap<M> {
+    messages: sync::Arc<dyn Messages<M>>,
+}
+
+/// Access to the recorded messages, independent of the codec type.
+trait Messages<M>: Send + Sync {
+    fn with(&self, f: &mut dyn FnMut(&[TappedMessage<M>]));
+}
+
+impl<C> Messages<C::Item> for TapState<C>
+where
+    C: Decoder + Send,
+    C::Item: Send,
+{
+    fn with(&self, f: &mut dyn FnMut(&[TappedMessage<C::Item>])) {
+        f(&self.messages.lock().unwrap())
+    }
+}
+
+impl<M> Clone for MessageTap<M> {
+    fn clone(&self) -> Self {
+        Self {
+            messages: sync::Arc::clone(&self.messages),
+        }
+    }
+}
+
+impl<M> fmt::Debug for MessageTap<M> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut len = 0;
+        self.messages
+            .with(&mut |messages: &[TappedMessage<M>]| len = messages.len());
+        write!(f, "MessageTap {{ messages: {} }}", len)
+    }
+}
+
+impl<M> MessageTap<M> {
+    /// Returns the number of recorded messages matching the predicate.
+    pub fn count<F>(&self, mut predicate: F) -> usize
+    where
+        F: FnMut(&TappedMessage<M>) -> bool,
+    {
+        let mut count = 0;
+        self.messages.with(&mut |messages: &[TappedMessage<M>]| {
+            count = messages.iter().filter(|m| predicate(m)).count()
+        });
+        count
+    }
+
+    /// Returns the number of recorded messages.
+    pub fn len(&self) -> usize {
+        self.count(|_| true)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<M: Clone> MessageTap<M> {
+    /// Returns a copy of the recorded messages.
+    pub fn messages(&self) -> Vec<TappedMessage<M>> {
+        let mut copy = vec![];
+        self.messages
+            .with(&mut |messages: &[TappedMessage<M>]| copy = messages.to_vec());
+        copy
+    }
+
+    /// Returns the messages written by streams bound to the provided host.
+    pub fn sent_by(&self, host: net::IpAddr) -> Vec<TappedMessage<M>> {
+        self.messages()
+            .into_iter()
+            .filter(|m| m.source.ip() == host)
+            .collect()
+    }
+}
+
+impl<M: fmt::Debug> MessageTap<M> {
+    /// Panics if any recorded message matches the predicate, reporting the first offending
+    /// message along with the provided description of the violated property.
+    pub fn assert_never<F>(&self, description: &str, mut predicate: F)
+    where
+        F: FnMut(&TappedMessage<M>) -> bool,
+    {
+        let mut violation = None;
+        self.messages.with(&mut |messages: &[TappedMessage<M>]| {
+            violation = messages
+                .iter()
+                .find(|m| predicate(m))
+                .map(ToString::to_string)
+        });
+        if let Some(violation) = violation {
+            panic!(
+                "tap assertion \"{}\" violated by {}",
+                description, violation
+            );
+        }
+    }
+
+    /// Panics unless every recorded message matches the predicate.
+    pub fn assert_always<F>(&self, description: &str, mut predicate: F)
+    where
+        F: FnMut(&TappedMessage<M>) -> bool,
+    {
+        self.assert_never(description, |m| !predicate(m))
+    }
+}
+
+/// Creates a tap along with the type erased handle attached to tapped streams.
+pub(crate) fn new_tap<C>(codec: C) -> (MessageTap<C::Item>, sync::Arc<dyn StreamTap>)
+where
+    C: Decoder + Clone + Send + 'static,
+    C::Item: Send + 'static,
+{
+    let state = sync::Arc::new(TapState {
+        decoders: sync::Mutex::new(Decoders {
+            codec,
+            streams: collections::HashMap::new(),
+        }),
+        messages: sync::Mutex::new(vec![]),
+    });
+    let tap = MessageTap {
+        messages: sync::Arc::clone(&state) as sync::Arc<dyn Messages<C::Item>>,
+    };
+    (tap, state)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use futures::{SinkExt, StreamExt};
+    use std::net;
+    use tokio::codec::{Framed, LinesCodec};
+
+    #[test]
+    /// Test that messages in both directions are decoded and attributed to their sender.
+    fn tap() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let tap = runtime.tap(addr, LinesCodec::new());
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                while let Some(Ok(line)) = transport.next().await {
+                    transport.send(format!("ack {}", line)).await.unwrap();
+                }
+            });
+            let socket = handle.connect(addr).await.unwrap();
+            let mut transport = Framed::new(socket, LinesCodec::new());
+            for term in 3..6 {
+                transport.send(format!("term {}", term)).await.unwrap();
+                transport.next().await.unwrap().unwrap();
+            }
+        });
+        assert_eq!(tap.len(), 6);
+        let server_messages: Vec<String> = tap
+            .messages()
+            .into_iter()
+            .filter(|m| m.source() == addr)
+            .map(|m| m.message().clone())
+            .collect();
+        assert_eq!(
+            server_messages,
+            vec!["ack term 3", "ack term 4", "ack term 5"]
+        );
+        tap.assert_never("client sent a term below 3", |m| {
+            m.dest() == addr && m.message().as_str() < "term 3"
+        });
+        let connections: std::collections::HashSet<_> =
+            tap.messages().iter().map(|m| m.connection_id()).collect();
+        assert_eq!(connections.len(), 1);
+    }
+}
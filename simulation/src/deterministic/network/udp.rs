@@ -0,0 +1,450 @@
+//! Simulated UDP sockets.
+//!
+//! Datagrams are delivered to the socket bound to their destination address. As with UDP,
+//! datagrams sent to addresses without a bound socket, to unreachable hosts, or to sockets
+//! whose receive buffer is full are silently dropped, and datagrams larger than the buffer
+//! provided to `recv_from` are truncated. [`DatagramFaults`] additionally drop, duplicate and
+//! reorder datagrams in transit, as lossy networks do.
+//!
+//! [`DatagramFaults`]:`DatagramFaults`
+use super::Inner;
+use crate::deterministic::{Breakpoint, DeterministicRandomHandle, DeterministicTimeHandle};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::{channel::mpsc, StreamExt};
+use std::{collections, io, net, sync, time};
+use tracing::trace;
+
+/// Number of datagrams buffered by a socket before further datagrams are dropped.
+const RECEIVE_BUFFER: usize = 64;
+
+#[derive(Debug)]
+pub(crate) struct Datagram {
+    source: net::SocketAddr,
+    payload: Bytes,
+}
+
+/// Faults applied to every datagram sent across the network.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DatagramFaults {
+    drop_probability: f64,
+    duplicate_probability: f64,
+    reorder_probability: f64,
+    max_reorder_delay: time::Duration,
+}
+
+impl Default for DatagramFaults {
+    fn default() -> Self {
+        Self {
+            drop_probability: 0.0,
+            duplicate_probability: 0.0,
+            reorder_probability: 0.0,
+            max_reorder_delay: time::Duration::from_millis(0),
+        }
+    }
+}
+
+impl DatagramFaults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drops datagrams with the provided probability.
+    pub fn drop_probability(mut self, probability: f64) -> Self {
+        self.drop_probability = probability;
+        self
+    }
+
+    /// Delivers datagrams twice with the provided probability. Each copy is independently
+    /// subject to reordering.
+    pub fn duplicate_probability(mut self, probability: f64) -> Self {
+        self.duplicate_probability = probability;
+        self
+    }
+
+    /// Holds back datagrams with the provided probability for a random delay of up to
+    /// `max_delay`, allowing datagrams sent after them to arrive first.
+    pub fn reorder(mut self, probability: f64, max_delay: time::Duration) -> Self {
+        self.reorder_probability = probability;
+        self.max_reorder_delay = max_delay;
+        self
+    }
+}
+
+/// Registry of bound UDP sockets.
+#[derive(Debug, Default)]
+pub(crate) struct Datagrams {
+    next_id: u64,
+    sockets: collections::HashMap<net::SocketAddr, (u64, mpsc::Sender<Datagram>)>,
+    faults: Option<(DatagramFaults, DeterministicRandomHandle)>,
+}
+
+impl Datagrams {
+    /// Registers a socket bound to `addr`, returning its id and the receiver for datagrams
+    /// sent to it.
+    pub(crate) fn bind(
+        &mut self,
+        addr: net::SocketAddr,
+    ) -> Result<(u64, mpsc::Receiver<Datagram>), io::Error> {
+        if self.sockets.contains_key(&addr) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = mpsc::channel(RECEIVE_BUFFER);
+        self.sockets.insert(addr, (id, tx));
+        Ok((id, rx))
+    }
+
+    /// Removes the socket with the provided id, if it is still bound to `addr`.
+    pub(crate) fn unbind(&mut self, addr: net::SocketAddr, id: u64) {
+        if let Some((bound, _)) = self.sockets.get(&addr) {
+            if *bound == id {
+                self.sockets.remove(&addr);
+            }
+        }
+    }
+
+    /// Removes all sockets bound to the provided host.
+    pub(crate) fn unbind_host(&mut self, host: net::IpAddr) {
+        self.sockets.retain(|addr, _| addr.ip() != host);
+    }
+
+    /// Applies the provided faults to datagrams sent from now on, sampling from `random`.
+    pub(crate) fn set_faults(
+        &mut self,
+        faults: Option<DatagramFaults>,
+        random: DeterministicRandomHandle,
+    ) {
+        self.faults = faults.map(|faults| (faults, random));
+    }
+
+    /// Delivers a datagram to the socket bound to `dest`, dropping it if no socket is bound or
+    /// the socket's receive buffer is full. Datagrams held back by a reorder fault are delivered
+    /// to the socket bound when they were sent.
+    pub(crate) fn deliver(
+        &mut self,
+        handle: &DeterministicTimeHandle,
+        source: net::SocketAddr,
+        dest: net::SocketAddr,
+        payload: Bytes,
+    ) {
+        let mut delays = vec![None];
+        if let Some((faults, random)) = &self.faults {
+            if faults.drop_probability > 0.0 && random.should_fault(faults.drop_probability) {
+                trace!("injected drop of datagram {} -> {}", source, dest);
+                return;
+            }
+            if faults.duplicate_probability > 0.0
+                && random.should_fault(faults.duplicate_probability)
+            {
+                trace!("injected duplicate of datagram {} -> {}", source, dest);
+                delays.push(None);
+            }
+            for delay in delays.iter_mut() {
+                if faults.reorder_probability > 0.0
+                    && faults.max_reorder_delay > time::Duration::from_millis(0)
+                    && random.should_fault(faults.reorder_probability)
+                {
+                    let held =
+                        random.gen_range(time::Duration::from_millis(0)..faults.max_reorder_delay);
+                    *delay = Some(held).filter(|held| *held > time::Duration::from_millis(0));
+                }
+            }
+        }
+        let tx = match self.sockets.get_mut(&dest) {
+            Some((_, tx)) => tx,
+            None => {
+                trace!("dropped datagram {} -> {}", source, dest);
+                return;
+            }
+        };
+        for delay in delays {
+            let datagram = Datagram {
+                source,
+                payload: payload.clone(),
+            };
+            match delay {
+                Some(delay) => {
+                    trace!(
+                        "holding back datagram {} -> {} for {:?}",
+                        source,
+                        dest,
+                        delay
+                    );
+                    let mut pending = Some((tx.clone(), datagram));
+                    handle.events().add_breakpoint(
+                        Breakpoint::at(handle.now() + delay),
+                        move |_| {
+                            if let Some((mut tx, datagram)) = pending.take() {
+                                if tx.try_send(datagram).is_err() {
+                                    trace!("dropped datagram {} -> {}", source, dest);
+                                }
+                            }
+                        },
+                    );
+                }
+                None => {
+                    if tx.try_send(datagram).is_err() {
+                        trace!("dropped datagram {} -> {}", source, dest);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// A simulated UDP socket.
+#[derive(Debug)]
+pub struct UdpSocket {
+    id: u64,
+    local_addr: net::SocketAddr,
+    peer_addr: Option<net::SocketAddr>,
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    incoming: mpsc::Receiver<Datagram>,
+}
+
+impl UdpSocket {
+    pub(crate) fn new(
+        id: u64,
+        local_addr: net::SocketAddr,
+        inner: sync::Arc<sync::Mutex<Inner>>,
+        incoming: mpsc::Receiver<Datagram>,
+    ) -> Self {
+        Self {
+            id,
+            local_addr,
+            peer_addr: None,
+            inner,
+            incoming,
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        Ok(self.local_addr)
+    }
+
+    /// Returns the address of the peer this socket is connected to.
+    pub fn peer_addr(&self) -> io::Result<net::SocketAddr> {
+        self.peer_addr
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+
+    /// Connects the socket to the provided address. Connected sockets send to the peer with
+    /// [`send`] and only receive datagrams originating from the peer, discarding datagrams from
+    /// other sources. Connecting again replaces the peer.
+    ///
+    /// [`send`]:`UdpSocket::send`
+    pub fn connect(&mut self, addr: net::SocketAddr) -> io::Result<()> {
+        self.peer_addr.replace(addr);
+        Ok(())
+    }
+
+    /// Sends a datagram to the provided address, returning the number of bytes sent.
+    pub async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize> {
+        self.inner
+            .lock()
+            .unwrap()
+            .send_datagram(self.local_addr, target, Bytes::from(buf));
+        Ok(buf.len())
+    }
+
+    /// Sends a datagram to the connected peer.
+    pub async fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let peer = self.peer_addr()?;
+        self.send_to(buf, peer).await
+    }
+
+    /// Receives a datagram, returning the number of bytes read and the source address. Bytes
+    /// which do not fit in `buf` are discarded.
+    pub async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        loop {
+            let datagram = match self.incoming.next().await {
+                Some(datagram) => datagram,
+                None => return Err(io::ErrorKind::NotConnected.into()),
+            };
+            if let Some(peer) = self.peer_addr {
+                if datagram.source != peer {
+                    trace!(
+                        "discarding datagram from {}, connected to {}",
+                        datagram.source,
+                        peer
+                    );
+                    continue;
+                }
+            }
+            let len = std::cmp::min(buf.len(), datagram.payload.len());
+            buf[..len].copy_from_slice(&datagram.payload[..len]);
+            return Ok((len, datagram.source));
+        }
+    }
+
+    /// Receives a datagram from the connected peer.
+    pub async fn recv(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.peer_addr()?;
+        let (len, _) = self.recv_from(buf).await?;
+        Ok(len)
+    }
+}
+
+#[async_trait]
+impl crate::UdpSocket for UdpSocket {
+    fn local_addr(&self) -> io::Result<net::SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+    async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize> {
+        UdpSocket::send_to(self, buf, target).await
+    }
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        UdpSocket::recv_from(self, buf).await
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        if let Ok(mut lock) = self.inner.lock() {
+            lock.unbind_udp(self.local_addr, self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DatagramFaults;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::{io, net, time};
+
+    #[test]
+    /// Test that datagrams are exchanged between unconnected sockets and truncated to the
+    /// receive buffer.
+    fn send_to_recv_from() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let a_addr: net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+            let b_addr: net::SocketAddr = "127.0.0.1:5001".parse().unwrap();
+            let mut a = handle.bind_udp(a_addr).await.unwrap();
+            let mut b = handle.bind_udp(b_addr).await.unwrap();
+            assert_eq!(
+                handle.bind_udp(a_addr).await.unwrap_err().kind(),
+                io::ErrorKind::AddrInUse
+            );
+            a.send_to(b"hello", b_addr).await.unwrap();
+            a.send_to(b"unbound", "127.0.0.1:5002".parse().unwrap())
+                .await
+                .unwrap();
+            let mut buf = [0u8; 3];
+            let (len, source) = b.recv_from(&mut buf).await.unwrap();
+            assert_eq!((len, source), (3, a_addr));
+            assert_eq!(&buf, b"hel");
+        });
+    }
+
+    #[test]
+    /// Test that connected sockets send to their peer and filter datagrams from other sources.
+    fn connect_filters_sources() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let server_addr: net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+            let peer_addr: net::SocketAddr = "127.0.0.1:5001".parse().unwrap();
+            let mut server = handle.bind_udp(server_addr).await.unwrap();
+            let mut peer = handle.bind_udp(peer_addr).await.unwrap();
+            let mut other = handle
+                .bind_udp("127.0.0.1:5002".parse::<net::SocketAddr>().unwrap())
+                .await
+                .unwrap();
+
+            let mut buf = [0u8; 16];
+            assert_eq!(
+                server.send(b"nowhere").await.unwrap_err().kind(),
+                io::ErrorKind::NotConnected
+            );
+            assert_eq!(
+                server.recv(&mut buf).await.unwrap_err().kind(),
+                io::ErrorKind::NotConnected
+            );
+
+            server.connect(peer_addr).unwrap();
+            assert_eq!(server.peer_addr().unwrap(), peer_addr);
+            other.send_to(b"spoofed", server_addr).await.unwrap();
+            peer.send_to(b"genuine", server_addr).await.unwrap();
+            let len = server.recv(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"genuine");
+
+            server.send(b"reply").await.unwrap();
+            let (len, source) = peer.recv_from(&mut buf).await.unwrap();
+            assert_eq!(&buf[..len], b"reply");
+            assert_eq!(source, server_addr);
+        });
+    }
+
+    #[test]
+    /// Test that datagram faults drop and reorder datagrams deterministically for a seed.
+    fn dropped_and_reordered_datagrams() {
+        fn received(seed: u64) -> Vec<u8> {
+            let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+            runtime.set_datagram_faults(Some(
+                DatagramFaults::new()
+                    .drop_probability(0.2)
+                    .reorder(0.3, time::Duration::from_millis(50)),
+            ));
+            let handle = runtime.localhost_handle();
+            runtime.block_on(async {
+                let a_addr: net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+                let b_addr: net::SocketAddr = "127.0.0.1:5001".parse().unwrap();
+                let mut a = handle.bind_udp(a_addr).await.unwrap();
+                let mut b = handle.bind_udp(b_addr).await.unwrap();
+                for i in 0..32u8 {
+                    a.send_to(&[i], b_addr).await.unwrap();
+                }
+                a.send_to(&[u8::max_value()], b_addr).await.unwrap();
+                let mut received = vec![];
+                let mut buf = [0u8; 1];
+                let deadline = time::Duration::from_millis(100);
+                while let Ok(Ok(_)) = handle.timeout(b.recv_from(&mut buf), deadline).await {
+                    received.push(buf[0]);
+                }
+                received
+            })
+        }
+        let datagrams = received(3);
+        assert_eq!(datagrams, received(3));
+        assert!(datagrams.len() < 33, "no datagrams were dropped");
+        let mut sorted = datagrams.clone();
+        sorted.sort();
+        assert_ne!(datagrams, sorted, "no datagrams were reordered");
+    }
+
+    #[test]
+    /// Test that duplicated datagrams are received more than once.
+    fn duplicated_datagrams() {
+        let mut runtime = DeterministicRuntime::new_with_seed(5).unwrap();
+        runtime.set_datagram_faults(Some(
+            DatagramFaults::new()
+                .duplicate_probability(0.5)
+                .reorder(0.3, time::Duration::from_millis(50)),
+        ));
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let a_addr: net::SocketAddr = "127.0.0.1:5000".parse().unwrap();
+            let b_addr: net::SocketAddr = "127.0.0.1:5001".parse().unwrap();
+            let mut a = handle.bind_udp(a_addr).await.unwrap();
+            let mut b = handle.bind_udp(b_addr).await.unwrap();
+            for i in 0..16u8 {
+                a.send_to(&[i], b_addr).await.unwrap();
+            }
+            let mut received = vec![];
+            let mut buf = [0u8; 1];
+            let deadline = time::Duration::from_millis(100);
+            while let Ok(Ok(_)) = handle.timeout(b.recv_from(&mut buf), deadline).await {
+                received.push(buf[0]);
+            }
+            assert!(received.len() > 16, "no datagrams were duplicated");
+            received.sort();
+            received.dedup();
+            assert_eq!(received, (0..16u8).collect::<Vec<_>>());
+        });
+    }
+}
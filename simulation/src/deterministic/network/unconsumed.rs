@@ -0,0 +1,167 @@
+//! Detection of connections which are closed while received bytes remain unread.
+use crate::deterministic::events::{Events, SimulationEvent};
+use std::{net, sync};
+use tracing::warn;
+
+/// Action taken when a connection is closed while received bytes remain unread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnconsumedDataPolicy {
+    /// Unread bytes are silently discarded.
+    Ignore,
+    /// Unread bytes are discarded and the close is recorded.
+    Record,
+    /// The close is recorded and the connection is reset, causing further reads and writes by
+    /// the peer to fail with `ConnectionReset`, as occurs when closing a TCP socket with unread
+    /// data.
+    Reset,
+}
+
+impl Default for UnconsumedDataPolicy {
+    fn default() -> Self {
+        UnconsumedDataPolicy::Ignore
+    }
+}
+
+/// A connection half which was closed while received bytes remained unread.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnconsumedData {
+    local_addr: net::SocketAddr,
+    peer_addr: net::SocketAddr,
+    bytes: usize,
+}
+
+impl UnconsumedData {
+    /// Address of the connection half which was closed.
+    pub fn local_addr(&self) -> net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Address of the peer which sent the unread bytes.
+    pub fn peer_addr(&self) -> net::SocketAddr {
+        self.peer_addr
+    }
+
+    /// Number of bytes which were never read.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    policy: UnconsumedDataPolicy,
+    records: Vec<UnconsumedData>,
+}
+
+/// Shared by socket halves to report bytes which remain unread when they are closed.
+#[derive(Debug, Clone)]
+pub(crate) struct CloseMonitor {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+    events: Events,
+}
+
+impl CloseMonitor {
+    pub(crate) fn new(events: Events) -> Self {
+        Self {
+            inner: sync::Arc::default(),
+            events,
+        }
+    }
+
+    pub(crate) fn set_policy(&self, policy: UnconsumedDataPolicy) {
+        self.inner.lock().unwrap().policy = policy;
+    }
+
+    /// Returns the connection halves which have been closed with unread bytes.
+    pub(crate) fn records(&self) -> Vec<UnconsumedData> {
+        self.inner.lock().unwrap().records.clone()
+    }
+
+    /// Report that a socket half was closed with the provided number of unread bytes. Returns
+    /// true if the connection should be reset.
+    pub(crate) fn closed(
+        &self,
+        local_addr: net::SocketAddr,
+        peer_addr: net::SocketAddr,
+        bytes: usize,
+    ) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        if bytes == 0 || lock.policy == UnconsumedDataPolicy::Ignore {
+            return false;
+        }
+        warn!(
+            "{} closed with {} unread bytes from {}",
+            local_addr, bytes, peer_addr
+        );
+        lock.records.push(UnconsumedData {
+            local_addr,
+            peer_addr,
+            bytes,
+        });
+        self.events.emit(SimulationEvent::UnconsumedData {
+            local_addr,
+            peer_addr,
+            bytes,
+        });
+        lock.policy == UnconsumedDataPolicy::Reset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::{io, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Sends a request which the server only partially reads before closing the connection,
+    /// returning the result of the client's subsequent read.
+    fn truncated_read(policy: UnconsumedDataPolicy) -> (Vec<UnconsumedData>, io::Result<usize>) {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_unconsumed_data_policy(policy);
+        let handle = runtime.localhost_handle();
+        let result = runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.write_all(b"head").await.unwrap();
+            socket.write_all(b"trailer").await.unwrap();
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            let mut buf = [0u8; 1];
+            socket.read(&mut buf).await
+        });
+        (runtime.unconsumed_data(), result)
+    }
+
+    #[test]
+    /// Test that unread bytes are ignored by default.
+    fn ignore_unconsumed() {
+        let (records, result) = truncated_read(UnconsumedDataPolicy::Ignore);
+        assert!(records.is_empty());
+        assert_eq!(result.unwrap(), 0);
+    }
+
+    #[test]
+    /// Test that closing a connection with unread bytes is recorded.
+    fn record_unconsumed() {
+        let (records, result) = truncated_read(UnconsumedDataPolicy::Record);
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].bytes(), 7);
+        assert_eq!(records[0].local_addr().port(), 9092);
+        assert_eq!(result.unwrap(), 0);
+    }
+
+    #[test]
+    /// Test that closing a connection with unread bytes resets the connection.
+    fn reset_unconsumed() {
+        let (records, result) = truncated_read(UnconsumedDataPolicy::Reset);
+        assert_eq!(records.len(), 1);
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+    }
+}
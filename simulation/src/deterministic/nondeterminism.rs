@@ -0,0 +1,352 @@
+//! Detection of nondeterminism leaking into a simulation.
+//!
+//! A simulation is only reproducible while everything it does flows through the runtime. Tasks
+//! which spawn OS threads or open real sockets escape it, and are caught by measuring the
+//! process around each poll, in the same way as [`BlockingCall`]s. Reads of the real clock or of
+//! `thread_rng` leave no such trace, so they are caught by their effect instead:
+//! [`check_determinism`] runs a simulation twice with the same seed and reports the first
+//! event at which the two runs diverged, along with the task polled when they did.
+//!
+//! [`BlockingCall`]:`super::BlockingCall`
+//! [`check_determinism`]:`check_determinism`
+use super::blocking;
+use super::event_log::{LogEntry, LogEvent};
+use super::{DeterministicRuntimeHandle, Simulation, TaskId};
+use futures::Future;
+use std::{fmt, sync, time};
+use tracing::warn;
+
+/// The way a poll escaped the simulation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NondeterminismKind {
+    /// The poll spawned the provided number of OS threads.
+    SpawnedThreads(usize),
+    /// The poll opened the provided number of real sockets.
+    OpenedSockets(usize),
+}
+
+/// A poll which escaped the simulation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NondeterministicCall {
+    task: TaskId,
+    name: Option<String>,
+    poll: u64,
+    at: time::Instant,
+    kind: NondeterminismKind,
+}
+
+impl NondeterministicCall {
+    pub fn task(&self) -> TaskId {
+        self.task
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(String::as_str)
+    }
+
+    /// Returns the number of the offending poll of the task, starting from 1.
+    pub fn poll(&self) -> u64 {
+        self.poll
+    }
+
+    /// Returns the virtual time at which the offending poll started.
+    pub fn at(&self) -> time::Instant {
+        self.at
+    }
+
+    pub fn kind(&self) -> NondeterminismKind {
+        self.kind
+    }
+}
+
+impl fmt::Display for NondeterministicCall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.name {
+            Some(name) => write!(f, "{} {}", self.task, name)?,
+            None => write!(f, "{}", self.task)?,
+        }
+        match self.kind {
+            NondeterminismKind::SpawnedThreads(threads) => write!(
+                f,
+                " spawned {} OS threads during poll {}",
+                threads, self.poll
+            ),
+            NondeterminismKind::OpenedSockets(sockets) => write!(
+                f,
+                " opened {} real sockets during poll {}",
+                sockets, self.poll
+            ),
+        }
+    }
+}
+
+/// Measurements taken at the start of a poll.
+pub(crate) struct Probe {
+    threads: Option<usize>,
+    sockets: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    enabled: bool,
+    calls: Vec<NondeterministicCall>,
+}
+
+/// Shared configuration and findings of nondeterminism detection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct NondeterminismDetector {
+    inner: sync::Arc<sync::Mutex<Inner>>,
+}
+
+impl NondeterminismDetector {
+    pub(crate) fn set_enabled(&self, enabled: bool) {
+        self.inner.lock().unwrap().enabled = enabled;
+    }
+
+    pub(crate) fn calls(&self) -> Vec<NondeterministicCall> {
+        self.inner.lock().unwrap().calls.clone()
+    }
+
+    /// Takes measurements before a poll, if detection is enabled.
+    pub(crate) fn start(&self) -> Option<Probe> {
+        if !self.inner.lock().unwrap().enabled {
+            return None;
+        }
+        Some(Probe {
+            threads: blocking::thread_count(),
+            sockets: socket_count(),
+        })
+    }
+
+    /// Compares measurements after a poll against those taken by `start`, recording the poll
+    /// if it spawned threads or opened sockets.
+    pub(crate) fn finish(
+        &self,
+        probe: Probe,
+        task: TaskId,
+        name: Option<String>,
+        poll: u64,
+        at: time::Instant,
+    ) {
+        let increase = |before: Option<usize>, after: Option<usize>| match (before, after) {
+            (Some(before), Some(after)) if after > before => Some(after - before),
+            _ => None,
+        };
+        let mut kinds = vec![];
+        if let Some(threads) = increase(probe.threads, blocking::thread_count()) {
+            kinds.push(NondeterminismKind::SpawnedThreads(threads));
+        }
+        if let Some(sockets) = increase(probe.sockets, socket_count()) {
+            kinds.push(NondeterminismKind::OpenedSockets(sockets));
+        }
+        let mut lock = self.inner.lock().unwrap();
+        for kind in kinds {
+            let call = NondeterministicCall {
+                task,
+                name: name.clone(),
+                poll,
+                at,
+                kind,
+            };
+            warn!("{}", call);
+            lock.calls.push(call);
+        }
+    }
+}
+
+/// Returns the number of sockets open in the current process, where it can be determined.
+#[cfg(target_os = "linux")]
+fn socket_count() -> Option<usize> {
+    let entries = std::fs::read_dir("/proc/self/fd").ok()?;
+    let sockets = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| std::fs::read_link(entry.path()).ok())
+        .filter(|target| target.to_string_lossy().starts_with("socket:"))
+        .count();
+    Some(sockets)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn socket_count() -> Option<usize> {
+    None
+}
+
+/// The first point at which two runs of the same seed diverged.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Divergence {
+    seq: u64,
+    expected: Option<LogEntry>,
+    actual: Option<LogEntry>,
+    task: Option<TaskId>,
+    history: Vec<LogEntry>,
+}
+
+impl Divergence {
+    /// Returns the position in the event logs at which the runs diverged.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Returns the entry recorded by the first run, or `None` if the first run ended first.
+    pub fn expected(&self) -> Option<&LogEntry> {
+        self.expected.as_ref()
+    }
+
+    /// Returns the entry recorded by the second run, or `None` if the second run ended first.
+    pub fn actual(&self) -> Option<&LogEntry> {
+        self.actual.as_ref()
+    }
+
+    /// Returns the task being polled in the second run when it diverged, if any.
+    pub fn task(&self) -> Option<TaskId> {
+        self.task
+    }
+
+    /// Returns the entries of the diverging task in the second run up to the divergence,
+    /// tracing what the task did before it diverged.
+    pub fn history(&self) -> &[LogEntry] {
+        &self.history
+    }
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |entry: &Option<LogEntry>| match entry {
+            Some(entry) => format!("{:?} at {:?}", entry.event, entry.elapsed),
+            None => String::from("end of run"),
+        };
+        write!(
+            f,
+            "runs diverged at event {}, expected {} but found {}",
+            self.seq,
+            describe(&self.expected),
+            describe(&self.actual)
+        )?;
+        if let Some(task) = self.task {
+            write!(f, " while polling {}", task)?;
+            for entry in self.history.iter() {
+                write!(f, "\n  {:?} at {:?}", entry.event, entry.elapsed)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Finds the first entry at which two event logs differ.
+fn diverge(expected: Vec<LogEntry>, actual: Vec<LogEntry>) -> Option<Divergence> {
+    let len = std::cmp::max(expected.len(), actual.len());
+    let seq = (0..len).find(|seq| expected.get(*seq) != actual.get(*seq))?;
+    // Events emitted during a poll are logged before the poll itself.
+    let task = actual[seq..].iter().find_map(|entry| match entry.event {
+        LogEvent::TaskPolled { task, .. } => Some(task),
+        _ => None,
+    });
+    let history = match task {
+        Some(task) => actual[..=std::cmp::min(seq, actual.len() - 1)]
+            .iter()
+            .filter(|entry| match entry.event {
+                LogEvent::TaskSpawned { task: t, .. }
+                | LogEvent::TaskPolled { task: t, .. }
+                | LogEvent::TaskDropped { task: t } => t == task,
+                _ => false,
+            })
+            .cloned()
+            .collect(),
+        None => vec![],
+    };
+    Some(Divergence {
+        seq: seq as u64,
+        expected: expected.get(seq).cloned(),
+        actual: actual.get(seq).cloned(),
+        task,
+        history,
+    })
+}
+
+/// Runs the future returned by `run` in two simulations created from `seed`, recording every
+/// event of each, and returns the first point at which the runs diverged. Runs which read the
+/// real clock, draw from `thread_rng` or otherwise depend on the world outside the simulation
+/// diverge as soon as those reads influence what the simulation does.
+pub fn check_determinism<F, Fut>(seed: u64, mut run: F) -> Result<(), Divergence>
+where
+    F: FnMut(DeterministicRuntimeHandle) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut record = || {
+        let mut simulation = Simulation::new(seed).expect("failed to create simulation");
+        let log = simulation.runtime().record_events();
+        let handle = simulation.handle();
+        simulation.run(run(handle));
+        log.entries()
+    };
+    let expected = record();
+    let actual = record();
+    match diverge(expected, actual) {
+        Some(divergence) => Err(divergence),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use rand::Rng;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    /// Test that tasks which spawn OS threads or open real sockets are reported.
+    fn escaped_polls() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.detect_nondeterminism(true);
+        let handle = runtime.localhost_handle();
+        let (tx, rx) = sync::mpsc::channel::<()>();
+        let socket = runtime.block_on(async move {
+            handle.delay_for(time::Duration::from_secs(1)).await;
+            std::thread::spawn(move || {
+                let _ = rx.recv();
+            });
+            std::net::UdpSocket::bind("127.0.0.1:0").unwrap()
+        });
+        let calls = runtime.nondeterministic_calls();
+        drop((tx, socket));
+        // Threads spawned concurrently by the test harness may also be reported.
+        let reported = |kind: fn(NondeterminismKind) -> bool| {
+            calls
+                .iter()
+                .any(|call| kind(call.kind()) && call.name() == Some("main") && call.poll() == 2)
+        };
+        assert!(reported(|kind| match kind {
+            NondeterminismKind::SpawnedThreads(threads) => threads > 0,
+            _ => false,
+        }));
+        assert!(reported(|kind| match kind {
+            NondeterminismKind::OpenedSockets(sockets) => sockets > 0,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    /// Test that runs which depend on `thread_rng` diverge, and that deterministic runs do not.
+    fn divergent_runs() {
+        let run = |leak: bool| {
+            move |handle: DeterministicRuntimeHandle| async move {
+                let delay = if leak {
+                    rand::thread_rng().gen_range(1, 1_000_000)
+                } else {
+                    handle.rng().gen_range(1..1_000_000)
+                };
+                let task = handle.clone();
+                handle.spawn_named("sleeper", async move {
+                    task.delay_for(time::Duration::from_millis(delay)).await;
+                });
+                handle.delay_for(time::Duration::from_secs(2000)).await;
+            }
+        };
+        assert_eq!(check_determinism(3, run(false)), Ok(()));
+        let divergence = check_determinism(3, run(true)).unwrap_err();
+        assert!(divergence.expected().is_some() && divergence.actual().is_some());
+        assert!(divergence.to_string().contains("runs diverged"));
+    }
+}
@@ -0,0 +1,218 @@
+//! Instrumentation hooks for external tools.
+//!
+//! Visualizers, checkers and exporters need to follow what the runtime is doing without
+//! depending on its internals. An [`Observer`] registered with the runtime is notified as tasks
+//! are spawned, polled and dropped, as timers are armed and fire, and as simulation events such
+//! as connections being established are emitted. Every method has an empty default, so
+//! observers only implement the hooks they need and continue to compile as hooks are added.
+//!
+//! [`Observer`]:`Observer`
+use super::time::Now;
+use super::{DecisionPoint, SimulationEvent, TaskId};
+use std::{cmp, collections, fmt, sync, time};
+
+/// Receives notifications of the activity of a deterministic runtime.
+///
+/// Hooks are invoked synchronously from within the runtime, so they are invoked in the same
+/// order for every run with the same seed. Hooks must not call back into the runtime.
+pub trait Observer: Send + Sync {
+    /// A task was spawned with the provided name.
+    fn task_spawned(&self, _at: time::Instant, _task: TaskId, _name: Option<&str>) {}
+
+    /// A task was polled, returning `Ready` if `ready` is set.
+    fn task_polled(&self, _at: time::Instant, _task: TaskId, _ready: bool) {}
+
+    /// A task was dropped, either after completing or after being aborted.
+    fn task_dropped(&self, _at: time::Instant, _task: TaskId) {}
+
+    /// A timer was armed with the provided deadline.
+    fn timer_armed(&self, _at: time::Instant, _deadline: time::Instant) {}
+
+    /// Simulated time reached the deadline of a timer. Timers which were dropped before their
+    /// deadline are reported as well.
+    fn timer_fired(&self, _deadline: time::Instant) {}
+
+    /// A simulation event was emitted, such as a connection being established or refused.
+    fn event(&self, _at: time::Instant, _event: &SimulationEvent) {}
+
+    /// The runtime made a decision which can steer the run onto a rare path, see
+    /// [`DecisionPoint`].
+    ///
+    /// [`DecisionPoint`]:`DecisionPoint`
+    fn decision(&self, _at: time::Instant, _point: &DecisionPoint) {}
+}
+
+#[derive(Default)]
+struct State {
+    observers: Vec<sync::Arc<dyn Observer>>,
+    /// Deadlines of timers armed while observers are registered, which have not yet fired.
+    armed: collections::BinaryHeap<cmp::Reverse<time::Instant>>,
+}
+
+/// Observers registered with a runtime.
+#[derive(Clone)]
+pub(crate) struct Observers {
+    state: sync::Arc<sync::Mutex<State>>,
+    now: Now,
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lock = self.state.lock().unwrap();
+        write!(f, "Observers {{ observers: {} }}", lock.observers.len())
+    }
+}
+
+impl Observers {
+    pub(crate) fn new(now: Now) -> Self {
+        Self {
+            state: sync::Arc::default(),
+            now,
+        }
+    }
+
+    pub(crate) fn add(&self, observer: sync::Arc<dyn Observer>) {
+        self.state.lock().unwrap().observers.push(observer);
+    }
+
+    /// Invokes `f` with each registered observer, in the order they were registered, along
+    /// with the current time. Must not be called while the clock is locked.
+    pub(crate) fn notify<F>(&self, f: F)
+    where
+        F: Fn(&dyn Observer, time::Instant),
+    {
+        let observers = self.state.lock().unwrap().observers.clone();
+        if observers.is_empty() {
+            return;
+        }
+        let at = tokio_timer::clock::Now::now(&self.now);
+        for observer in observers.iter() {
+            f(&**observer, at);
+        }
+    }
+
+    pub(crate) fn decision(&self, point: DecisionPoint) {
+        self.notify(|observer, at| observer.decision(at, &point));
+    }
+
+    pub(crate) fn timer_armed(&self, deadline: time::Instant) {
+        {
+            let mut lock = self.state.lock().unwrap();
+            if lock.observers.is_empty() {
+                return;
+            }
+            lock.armed.push(cmp::Reverse(deadline));
+        }
+        self.notify(|observer, at| observer.timer_armed(at, deadline));
+    }
+
+    /// Reports the timers whose deadlines have been reached once time has advanced.
+    pub(crate) fn advanced(&self) {
+        let mut fired = vec![];
+        {
+            let mut lock = self.state.lock().unwrap();
+            if lock.armed.is_empty() {
+                return;
+            }
+            let now = tokio_timer::clock::Now::now(&self.now);
+            while let Some(cmp::Reverse(deadline)) = lock.armed.peek().cloned() {
+                if deadline > now {
+                    break;
+                }
+                lock.armed.pop();
+                fired.push(deadline);
+            }
+        }
+        for deadline in fired {
+            self.notify(|observer, _| observer.timer_fired(deadline));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::net;
+
+    #[derive(Default)]
+    struct Recorder {
+        log: sync::Mutex<Vec<String>>,
+        fired: sync::Mutex<Vec<time::Instant>>,
+    }
+
+    impl Recorder {
+        fn push(&self, entry: String) {
+            self.log.lock().unwrap().push(entry);
+        }
+    }
+
+    impl Observer for Recorder {
+        fn task_spawned(&self, _: time::Instant, task: TaskId, name: Option<&str>) {
+            self.push(format!("spawned {} {}", task, name.unwrap_or("-")));
+        }
+
+        fn task_polled(&self, _: time::Instant, task: TaskId, ready: bool) {
+            self.push(format!("polled {} {}", task, ready));
+        }
+
+        fn task_dropped(&self, _: time::Instant, task: TaskId) {
+            self.push(format!("dropped {}", task));
+        }
+
+        fn timer_fired(&self, deadline: time::Instant) {
+            self.fired.lock().unwrap().push(deadline);
+        }
+
+        fn event(&self, _: time::Instant, event: &SimulationEvent) {
+            if let SimulationEvent::ConnectionEstablished { .. } = event {
+                self.push(String::from("connected"));
+            }
+        }
+    }
+
+    #[test]
+    /// Test that observers follow a task through its lifetime, along with the timers and
+    /// connections it creates.
+    fn observe_task() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let recorder = sync::Arc::new(Recorder::default());
+        runtime.add_observer(recorder.clone());
+        let handle = runtime.localhost_handle();
+        let start = handle.now();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let _listener = handle.bind(addr).await.unwrap();
+            let sleeper = handle.clone();
+            handle.spawn_named("sleeper", async move {
+                sleeper.delay_from(time::Duration::from_secs(1)).await;
+                sleeper.connect(addr).await.unwrap();
+            });
+            handle.delay_from(time::Duration::from_secs(2)).await;
+        });
+        let log = recorder.log.lock().unwrap();
+        let spawned = log
+            .iter()
+            .find(|entry| entry.ends_with(" sleeper"))
+            .expect("sleeper was not reported as spawned");
+        let task = spawned.split(' ').nth(1).unwrap();
+        let lifetime: Vec<&str> = log
+            .iter()
+            .map(String::as_str)
+            .filter(|entry| entry.split(' ').nth(1) == Some(task) || *entry == "connected")
+            .collect();
+        let polled = format!("polled {} false", task);
+        let completed = format!("polled {} true", task);
+        let dropped = format!("dropped {}", task);
+        assert_eq!(lifetime[0], spawned.as_str());
+        assert_eq!(lifetime[1], polled.as_str());
+        assert_eq!(
+            &lifetime[lifetime.len() - 3..],
+            &["connected", completed.as_str(), dropped.as_str()]
+        );
+        let fired = recorder.fired.lock().unwrap();
+        assert!(fired.contains(&(start + time::Duration::from_secs(1))));
+        assert!(fired.contains(&(start + time::Duration::from_secs(2))));
+    }
+}
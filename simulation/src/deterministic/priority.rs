@@ -0,0 +1,144 @@
+//! Priority classes for tasks spawned on the deterministic runtime.
+//!
+//! Tasks can be spawned into a named class, such as `"heartbeat"` or `"bulk"`, and each class is
+//! assigned a priority on the runtime. The underlying executor polls runnable tasks in the order
+//! they were woken, so priorities are enforced by having a task yield, without being polled,
+//! while a task of a higher priority is runnable. Because classes are mapped to priorities on
+//! the runtime rather than at the spawn site, a test can invert the priorities of two classes to
+//! create a priority inversion without changing the code under test.
+use super::TaskId;
+use std::collections;
+
+/// Policy the deterministic scheduler uses to order runnable tasks of different priorities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PriorityPolicy {
+    /// Ignore priorities, polling runnable tasks in the order they were woken.
+    Ignore,
+    /// Never poll a task while a task of a higher priority is runnable. Low priority tasks can be
+    /// starved indefinitely.
+    Strict,
+    /// Like `Strict`, but a task which has yielded the provided number of times in a row is
+    /// polled regardless of priority, bounding starvation.
+    Aging(u32),
+}
+
+impl Default for PriorityPolicy {
+    fn default() -> Self {
+        PriorityPolicy::Ignore
+    }
+}
+
+/// Priority of tasks which were not spawned into a class, or whose class has no priority.
+pub(crate) const DEFAULT_PRIORITY: i32 = 0;
+
+#[derive(Debug, Default)]
+pub(crate) struct Priorities {
+    policy: PriorityPolicy,
+    classes: collections::HashMap<String, i32>,
+    /// Number of consecutive times each task has yielded to a higher priority task.
+    deferrals: collections::HashMap<TaskId, u32>,
+}
+
+impl Priorities {
+    pub(crate) fn set_policy(&mut self, policy: PriorityPolicy) {
+        self.policy = policy;
+    }
+
+    pub(crate) fn set_class(&mut self, class: String, priority: i32) {
+        self.classes.insert(class, priority);
+    }
+
+    pub(crate) fn priority(&self, class: Option<&str>) -> i32 {
+        class
+            .and_then(|class| self.classes.get(class))
+            .copied()
+            .unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    /// Returns true if the task should yield rather than be polled, given its priority and the
+    /// priorities of other runnable tasks.
+    pub(crate) fn should_defer(
+        &mut self,
+        id: TaskId,
+        priority: i32,
+        mut runnable: impl Iterator<Item = i32>,
+    ) -> bool {
+        if self.policy == PriorityPolicy::Ignore {
+            return false;
+        }
+        if !runnable.any(|other| other > priority) {
+            self.deferrals.remove(&id);
+            return false;
+        }
+        let deferrals = self.deferrals.entry(id).or_insert(0);
+        match self.policy {
+            PriorityPolicy::Aging(limit) if *deferrals >= limit => {
+                *deferrals = 0;
+                false
+            }
+            _ => {
+                *deferrals += 1;
+                true
+            }
+        }
+    }
+
+    pub(crate) fn remove(&mut self, id: TaskId) {
+        self.deferrals.remove(&id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::{sync, time::Duration};
+
+    fn first_polls(policy: PriorityPolicy) -> Vec<&'static str> {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_priority_class("heartbeat", 10);
+        runtime.set_priority_class("bulk", -10);
+        runtime.set_priority_policy(policy);
+        let handle = runtime.localhost_handle();
+        let order = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async {
+            let tasks = [("bulk", "bulk-1"), ("bulk", "bulk-2"), ("heartbeat", "hb")];
+            for &(class, name) in tasks.iter() {
+                let order = sync::Arc::clone(&order);
+                handle.spawn_in_class(class, name, async move {
+                    order.lock().unwrap().push(name);
+                });
+            }
+            handle.delay_from(Duration::from_secs(1)).await;
+        });
+        let order = order.lock().unwrap();
+        order.clone()
+    }
+
+    #[test]
+    /// Test that strict priorities poll higher priority tasks first.
+    fn strict_priority() {
+        assert_eq!(
+            first_polls(PriorityPolicy::Ignore),
+            vec!["bulk-1", "bulk-2", "hb"]
+        );
+        assert_eq!(
+            first_polls(PriorityPolicy::Strict),
+            vec!["hb", "bulk-1", "bulk-2"]
+        );
+    }
+
+    #[test]
+    /// Test that aging polls a starved task once it has yielded enough times in a row.
+    fn aging() {
+        let mut priorities = Priorities::default();
+        priorities.set_policy(PriorityPolicy::Aging(2));
+        let id = TaskId(1);
+        let runnable = || vec![5].into_iter();
+        assert!(priorities.should_defer(id, 0, runnable()));
+        assert!(priorities.should_defer(id, 0, runnable()));
+        assert!(!priorities.should_defer(id, 0, runnable()));
+        assert!(priorities.should_defer(id, 0, runnable()));
+        assert!(!priorities.should_defer(id, 5, runnable()));
+    }
+}
@@ -1,18 +1,274 @@
-use rand::{distributions::uniform::SampleUniform, rngs, Rng};
-
+use super::replay::Tape;
+use super::TaskId;
+use rand::{distributions::uniform::SampleUniform, rngs, Rng, RngCore};
 use rand_distr::{Distribution, Normal};
-use std::{ops, sync};
+use std::{collections, fmt, net, ops, sync};
+
+/// The algorithm used to generate deterministic randomness from a seed.
+///
+/// Saved seeds only reproduce a run if the stream of random values they produce is unchanged.
+/// `SmallRng` is the algorithm used by prior releases and is kept so existing seed corpora
+/// continue to reproduce, but its stream is defined by the `rand` crate and may differ between
+/// `rand` releases and platform word sizes. `Xoshiro256` is implemented by this crate, and its
+/// stream of raw values is fixed for a given version, see [`RngAlgorithm::version`].
+///
+/// [`RngAlgorithm::version`]:`RngAlgorithm::version`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RngAlgorithm {
+    /// The `rand` crate `SmallRng`, compatible with the streams of prior releases.
+    SmallRng,
+    /// xoshiro256** seeded through splitmix64.
+    Xoshiro256,
+}
+
+impl Default for RngAlgorithm {
+    fn default() -> Self {
+        RngAlgorithm::SmallRng
+    }
+}
+
+impl RngAlgorithm {
+    /// Returns the version of the stream produced by this algorithm. The version is
+    /// incremented whenever a change to this crate would change the stream for a seed.
+    pub fn version(self) -> u32 {
+        match self {
+            RngAlgorithm::SmallRng => 1,
+            RngAlgorithm::Xoshiro256 => 1,
+        }
+    }
+}
+
+impl fmt::Display for RngAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RngAlgorithm::SmallRng => write!(f, "small-rng/v{}", self.version()),
+            RngAlgorithm::Xoshiro256 => write!(f, "xoshiro256**/v{}", self.version()),
+        }
+    }
+}
+
+/// A seed for deterministic randomness. Independent sub-seeds can be derived by label, giving
+/// each subsystem its own stream of randomness which is unaffected by the number of values
+/// drawn by other subsystems.
+///
+/// ```rust
+/// use simulation::deterministic::Seed;
+/// let seed = Seed::new(42);
+/// let workload = seed.derive("workload");
+/// assert_eq!(workload, Seed::new(42).derive("workload"));
+/// assert_ne!(workload, seed.derive("chaos"));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Seed(u64);
+
+impl Seed {
+    pub fn new(value: u64) -> Self {
+        Seed(value)
+    }
+
+    pub fn value(self) -> u64 {
+        self.0
+    }
+
+    /// Derives a sub-seed for the provided label. Derivation only depends on this seed and the
+    /// label, so sub-seeds are stable across runs, platforms and releases. Derived seeds can be
+    /// derived from again to form namespaces, such as `seed.derive("chaos").derive("network")`.
+    pub fn derive(self, label: &str) -> Seed {
+        // FNV-1a over the label, keyed by the parent seed, followed by a splitmix64 finalizer to
+        // spread similar labels across the seed space.
+        let mut hash = 0xcbf2_9ce4_8422_2325 ^ self.0;
+        for byte in label.as_bytes() {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Seed(hash ^ (hash >> 31))
+    }
+
+    /// Returns a source of randomness seeded with this seed, using the default algorithm.
+    pub fn random(self) -> DeterministicRandomHandle {
+        self.random_with(RngAlgorithm::default())
+    }
+
+    /// Returns a source of randomness seeded with this seed, using the provided algorithm.
+    pub fn random_with(self, algorithm: RngAlgorithm) -> DeterministicRandomHandle {
+        DeterministicRandom::new_with_algorithm(self.0, algorithm).handle()
+    }
+}
+
+impl From<u64> for Seed {
+    fn from(value: u64) -> Self {
+        Seed(value)
+    }
+}
+
+impl std::fmt::Display for Seed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// xoshiro256** as described by Blackman and Vigna, with the state expanded from the seed
+/// using splitmix64.
+#[derive(Debug)]
+struct Xoshiro256 {
+    state: [u64; 4],
+}
+
+impl Xoshiro256 {
+    fn new(seed: u64) -> Self {
+        let mut splitmix = seed;
+        let mut state = [0u64; 4];
+        for word in state.iter_mut() {
+            splitmix = splitmix.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = splitmix;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *word = z ^ (z >> 31);
+        }
+        Self { state }
+    }
+}
+
+impl RngCore for Xoshiro256 {
+    fn next_u32(&mut self) -> u32 {
+        (self.next_u64() >> 32) as u32
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let s = &mut self.state;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        for chunk in dest.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+enum SimRng {
+    SmallRng(rngs::SmallRng),
+    Xoshiro256(Xoshiro256),
+}
+
+impl RngCore for SimRng {
+    fn next_u32(&mut self) -> u32 {
+        match self {
+            SimRng::SmallRng(rng) => rng.next_u32(),
+            SimRng::Xoshiro256(rng) => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        match self {
+            SimRng::SmallRng(rng) => rng.next_u64(),
+            SimRng::Xoshiro256(rng) => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        match self {
+            SimRng::SmallRng(rng) => rng.fill_bytes(dest),
+            SimRng::Xoshiro256(rng) => rng.fill_bytes(dest),
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        match self {
+            SimRng::SmallRng(rng) => rng.try_fill_bytes(dest),
+            SimRng::Xoshiro256(rng) => rng.try_fill_bytes(dest),
+        }
+    }
+}
+
+/// A generator whose raw values are recorded to or replayed from a tape, if one was installed
+/// when the generator was created.
+#[derive(Debug)]
+struct TapedRng {
+    rng: SimRng,
+    seed: u64,
+    tape: Option<Tape>,
+}
+
+impl RngCore for TapedRng {
+    fn next_u32(&mut self) -> u32 {
+        let rng = &mut self.rng;
+        match self.tape.as_ref() {
+            Some(tape) => tape.draw(self.seed, || u64::from(rng.next_u32())) as u32,
+            None => rng.next_u32(),
+        }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        let rng = &mut self.rng;
+        match self.tape.as_ref() {
+            Some(tape) => tape.draw(self.seed, || rng.next_u64()),
+            None => rng.next_u64(),
+        }
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let (rng, seed) = (&mut self.rng, self.seed);
+        let tape = match self.tape.as_ref() {
+            Some(tape) => tape,
+            None => {
+                rng.fill_bytes(dest);
+                return;
+            }
+        };
+        for chunk in dest.chunks_mut(8) {
+            let len = chunk.len();
+            let value = tape.draw(seed, || {
+                let mut bytes = [0u8; 8];
+                rng.fill_bytes(&mut bytes[..len]);
+                u64::from_le_bytes(bytes)
+            });
+            chunk.copy_from_slice(&value.to_le_bytes()[..len]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.fill_bytes(dest);
+        Ok(())
+    }
+}
 
 #[derive(Debug)]
 /// DeterministicRandom provides a deterministic RNG.
 struct Inner {
-    rng: rngs::SmallRng,
+    rng: TapedRng,
+    algorithm: RngAlgorithm,
 }
 
 impl Inner {
-    fn new_with_seed(seed: u64) -> Self {
-        let rng = rand::SeedableRng::seed_from_u64(seed);
-        Self { rng }
+    fn new_with_algorithm(seed: u64, algorithm: RngAlgorithm) -> Self {
+        let rng = match algorithm {
+            RngAlgorithm::SmallRng => SimRng::SmallRng(rand::SeedableRng::seed_from_u64(seed)),
+            RngAlgorithm::Xoshiro256 => SimRng::Xoshiro256(Xoshiro256::new(seed)),
+        };
+        let rng = TapedRng {
+            rng,
+            seed,
+            tape: Tape::current(),
+        };
+        Self { rng, algorithm }
     }
 }
 
@@ -26,7 +282,10 @@ impl DeterministicRandom {
         DeterministicRandom::new_with_seed(0)
     }
     pub(crate) fn new_with_seed(seed: u64) -> Self {
-        let inner = Inner::new_with_seed(seed);
+        DeterministicRandom::new_with_algorithm(seed, RngAlgorithm::default())
+    }
+    pub(crate) fn new_with_algorithm(seed: u64, algorithm: RngAlgorithm) -> Self {
+        let inner = Inner::new_with_algorithm(seed, algorithm);
         let inner = sync::Arc::new(sync::Mutex::new(inner));
         Self { inner }
     }
@@ -42,6 +301,18 @@ pub struct DeterministicRandomHandle {
 }
 
 impl DeterministicRandomHandle {
+    /// Returns the algorithm generating this source of randomness.
+    pub fn algorithm(&self) -> RngAlgorithm {
+        self.inner.lock().unwrap().algorithm
+    }
+
+    /// Returns the next raw value from the stream. Unlike values sampled from a range or
+    /// distribution, raw values do not depend on the sampling implementation of the `rand`
+    /// crate.
+    pub fn next_u64(&self) -> u64 {
+        self.inner.lock().unwrap().rng.next_u64()
+    }
+
     pub fn normal_dist(&self, mean: f64, dev: f64) -> f64 {
         let normal = Normal::new(mean, dev).unwrap_or_else(|_| {
             panic!("illegal normal params, mean: {}, deviation: {}", mean, dev)
@@ -62,4 +333,190 @@ impl DeterministicRandomHandle {
         let mut lock = self.inner.lock().unwrap();
         lock.rng.gen_range(range.start, range.end)
     }
+
+    /// Returns an element chosen uniformly at random, or `None` if `items` is empty.
+    pub fn choose<'a, T>(&self, items: &'a [T]) -> Option<&'a T> {
+        if items.is_empty() {
+            return None;
+        }
+        items.get(self.gen_range(0..items.len()))
+    }
+
+    /// Returns an element chosen with probability proportional to its weight. Elements with a
+    /// weight of zero or less are never chosen, and `None` is returned if no element has a
+    /// positive weight.
+    pub fn choose_weighted<'a, T, F>(&self, items: &'a [T], weight: F) -> Option<&'a T>
+    where
+        F: Fn(&T) -> f64,
+    {
+        let weights: Vec<f64> = items.iter().map(|item| weight(item).max(0.0)).collect();
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut target = self.gen_range(0.0..total);
+        let mut chosen = None;
+        for (item, weight) in items.iter().zip(weights) {
+            if weight <= 0.0 {
+                continue;
+            }
+            // Rounding may leave the target just past the final weight, in which case the last
+            // element with a positive weight is chosen.
+            chosen = Some(item);
+            if target < weight {
+                break;
+            }
+            target -= weight;
+        }
+        chosen
+    }
+}
+
+/// Allows handles to be used wherever the `rand` crate expects a generator, such as with
+/// `Rng::gen` or `SliceRandom::shuffle`.
+impl RngCore for DeterministicRandomHandle {
+    fn next_u32(&mut self) -> u32 {
+        self.inner.lock().unwrap().rng.next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.inner.lock().unwrap().rng.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.inner.lock().unwrap().rng.fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand::Error> {
+        self.inner.lock().unwrap().rng.try_fill_bytes(dest)
+    }
+}
+
+/// Substreams of randomness of the hosts of a runtime and the tasks running on them, created
+/// on first use.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Substreams {
+    streams: sync::Arc<
+        sync::Mutex<collections::HashMap<(net::IpAddr, Option<TaskId>), DeterministicRandomHandle>>,
+    >,
+}
+
+impl Substreams {
+    pub(crate) fn get(
+        &self,
+        host: net::IpAddr,
+        task: Option<TaskId>,
+        seed: Seed,
+        algorithm: RngAlgorithm,
+    ) -> DeterministicRandomHandle {
+        let mut streams = self.streams.lock().unwrap();
+        streams
+            .entry((host, task))
+            .or_insert_with(|| {
+                let seed = seed.derive("rng").derive(&host.to_string());
+                let seed = match task {
+                    Some(task) => seed.derive(&task.to_string()),
+                    None => seed,
+                };
+                seed.random_with(algorithm)
+            })
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Test that values drawn from a derived seed do not depend on draws from its siblings.
+    fn derived_seeds_are_independent() {
+        fn draws(seed: Seed, chaos_draws: usize) -> Vec<u64> {
+            let chaos = seed.derive("chaos").random();
+            let workload = seed.derive("workload").random();
+            for _ in 0..chaos_draws {
+                chaos.gen_range(0..100u64);
+            }
+            (0..8)
+                .map(|_| workload.gen_range(0..u64::max_value()))
+                .collect()
+        }
+        let seed = Seed::new(7);
+        assert_eq!(draws(seed, 0), draws(seed, 100));
+        assert_ne!(draws(seed, 0), draws(Seed::new(8), 0));
+        assert_ne!(seed.derive("a").derive("b"), seed.derive("b").derive("a"));
+    }
+
+    #[test]
+    /// Test that each task draws from its own reproducible substream of the runtime seed,
+    /// unaffected by the draws of other tasks and hosts.
+    fn task_substreams() {
+        use crate::deterministic::DeterministicRuntime;
+        use crate::Environment;
+
+        fn run(other_draws: usize) -> (Vec<u32>, u64) {
+            let mut runtime = DeterministicRuntime::new_with_seed(11).unwrap();
+            let handle = runtime.localhost_handle();
+            let other_host = handle.add_host();
+            runtime.block_on(async {
+                let other = handle.clone();
+                let (tx, rx) = futures::channel::oneshot::channel();
+                handle.spawn(async move {
+                    let mut rng = other.rng();
+                    for _ in 0..other_draws {
+                        rng.gen::<u64>();
+                    }
+                    let _ = tx.send(());
+                });
+                let host = other_host.clone();
+                other_host.spawn(async move {
+                    host.rng().gen::<u64>();
+                });
+                rx.await.unwrap();
+                assert_ne!(handle.rng().next_u64(), other_host.rng().next_u64());
+                let mut rng = handle.rng();
+                let draws = (0..4).map(|_| rng.gen_range(0..1000)).collect();
+                (draws, handle.rng().next_u64())
+            })
+        }
+        assert_eq!(run(0), run(100));
+    }
+
+    #[test]
+    /// Test that weighted choices never pick elements without weight and favour heavier ones.
+    fn choose_weighted() {
+        let random = Seed::new(3).random();
+        let items = [("idle", 0.0), ("light", 1.0), ("heavy", 9.0)];
+        let mut heavy = 0;
+        for _ in 0..1000 {
+            match random.choose_weighted(&items, |(_, weight)| *weight) {
+                Some(("heavy", _)) => heavy += 1,
+                Some(("light", _)) => (),
+                other => panic!("unexpected choice {:?}", other),
+            }
+        }
+        assert!(heavy > 800, "heavy chosen {} times", heavy);
+        assert_eq!(random.choose_weighted(&items[..1], |(_, w)| *w), None);
+        assert_eq!(random.choose::<u8>(&[]), None);
+    }
+
+    #[test]
+    /// Test that the xoshiro256** stream matches its recorded values, guarding against changes
+    /// which would invalidate saved seeds.
+    fn xoshiro_stream_is_stable() {
+        let random = Seed::new(42).random_with(RngAlgorithm::Xoshiro256);
+        let values: Vec<u64> = (0..3).map(|_| random.next_u64()).collect();
+        assert_eq!(
+            values,
+            vec![
+                0x1578_0b2e_0c2e_c716,
+                0x6104_d986_6d11_3a7e,
+                0xae17_5332_39e4_99a1
+            ]
+        );
+        assert_eq!(random.algorithm().to_string(), "xoshiro256**/v1");
+        let legacy = Seed::new(42).random();
+        assert_eq!(legacy.algorithm(), RngAlgorithm::SmallRng);
+        assert_ne!(legacy.next_u64(), values[0]);
+    }
 }
@@ -0,0 +1,367 @@
+//! Recording and replaying the random decisions of a run.
+//!
+//! Every nondeterministic choice the runtime makes, from the order of shuffled tasks to fault
+//! coin flips and latency draws, is derived from raw values drawn from seeded streams. A
+//! recording runtime writes down each raw value drawn from each stream, keyed by the seed of
+//! the stream, to a [`DecisionTrace`]. A replaying runtime feeds the recorded values back in
+//! place of generating them, so a failing run can be shared as a compact binary file and
+//! re-executed on a machine whose `rand` crate or word size would generate different streams.
+//! Because values are keyed by stream, code changes which add or remove streams, or which draw
+//! differently from one stream, leave the decisions of the other streams intact. Draws beyond
+//! the end of a recorded stream fall back to generating values and are counted as overruns.
+//!
+//! A branching runtime replays the values recorded up to a [`Snapshot`], then draws values
+//! derived from a branch seed in place of the recorded continuation, recording both.
+//!
+//! [`DecisionTrace`]:`DecisionTrace`
+//! [`Snapshot`]:`super::Snapshot`
+use super::{RngAlgorithm, Seed};
+use std::{cell, collections, convert::TryInto, fs, io, path, sync};
+
+const MAGIC: &[u8; 8] = b"SIMTRACE";
+const VERSION: u32 = 1;
+
+thread_local! {
+    /// Tape of the recording or replaying runtime owning this thread, if any.
+    static CURRENT: cell::RefCell<Option<Tape>> = cell::RefCell::new(None);
+}
+
+/// The raw values drawn from each stream of randomness of a run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecisionTrace {
+    seed: Seed,
+    algorithm: RngAlgorithm,
+    streams: collections::BTreeMap<u64, Vec<u64>>,
+}
+
+impl DecisionTrace {
+    fn new(seed: Seed, algorithm: RngAlgorithm) -> Self {
+        Self {
+            seed,
+            algorithm,
+            streams: collections::BTreeMap::new(),
+        }
+    }
+
+    /// Returns the seed of the recorded run.
+    pub fn seed(&self) -> Seed {
+        self.seed
+    }
+
+    pub fn algorithm(&self) -> RngAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the number of raw values recorded across every stream.
+    pub fn decisions(&self) -> usize {
+        self.streams.values().map(Vec::len).sum()
+    }
+
+    /// Encodes the trace in its binary format.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(32 + 8 * self.decisions());
+        bytes.extend_from_slice(MAGIC);
+        bytes.extend_from_slice(&VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.seed.value().to_le_bytes());
+        bytes.push(match self.algorithm {
+            RngAlgorithm::SmallRng => 0,
+            RngAlgorithm::Xoshiro256 => 1,
+        });
+        bytes.extend_from_slice(&(self.streams.len() as u64).to_le_bytes());
+        for (stream, values) in self.streams.iter() {
+            bytes.extend_from_slice(&stream.to_le_bytes());
+            bytes.extend_from_slice(&(values.len() as u64).to_le_bytes());
+            for value in values.iter() {
+                bytes.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Decodes a trace encoded by [`to_bytes`].
+    ///
+    /// [`to_bytes`]:`DecisionTrace::to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader { bytes };
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("not a decision trace"));
+        }
+        let version = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid("unsupported decision trace version"));
+        }
+        let seed = Seed::new(reader.u64()?);
+        let algorithm = match reader.take(1)?[0] {
+            0 => RngAlgorithm::SmallRng,
+            1 => RngAlgorithm::Xoshiro256,
+            _ => return Err(invalid("unknown rng algorithm")),
+        };
+        let mut trace = DecisionTrace::new(seed, algorithm);
+        for _ in 0..reader.u64()? {
+            let stream = reader.u64()?;
+            let len = reader.u64()?;
+            let values = (0..len).map(|_| reader.u64()).collect::<io::Result<_>>()?;
+            trace.streams.insert(stream, values);
+        }
+        if !reader.bytes.is_empty() {
+            return Err(invalid("trailing bytes after decision trace"));
+        }
+        Ok(trace)
+    }
+
+    pub fn save(&self, path: impl AsRef<path::Path>) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: impl AsRef<path::Path>) -> io::Result<Self> {
+        DecisionTrace::from_bytes(&fs::read(path)?)
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.bytes.len() < len {
+            return Err(invalid("truncated decision trace"));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+}
+
+#[derive(Debug)]
+enum Mode {
+    Record,
+    Replay {
+        cursors: collections::HashMap<u64, usize>,
+        overruns: u64,
+    },
+    Branch {
+        prefix: DecisionTrace,
+        cursors: collections::HashMap<u64, usize>,
+        branch: Seed,
+    },
+}
+
+#[derive(Debug)]
+struct State {
+    trace: DecisionTrace,
+    mode: Mode,
+}
+
+/// Records or replays the values drawn by the streams created on a thread.
+#[derive(Debug, Clone)]
+pub(crate) struct Tape {
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Tape {
+    pub(crate) fn record(seed: Seed, algorithm: RngAlgorithm) -> Self {
+        Self::new(DecisionTrace::new(seed, algorithm), Mode::Record)
+    }
+
+    pub(crate) fn replay(trace: DecisionTrace) -> Self {
+        let mode = Mode::Replay {
+            cursors: collections::HashMap::new(),
+            overruns: 0,
+        };
+        Self::new(trace, mode)
+    }
+
+    /// Creates a tape replaying the values of `prefix`, then drawing values derived from
+    /// `branch` for each stream.
+    pub(crate) fn branch(prefix: DecisionTrace, branch: Seed) -> Self {
+        let trace = DecisionTrace::new(prefix.seed, prefix.algorithm);
+        let mode = Mode::Branch {
+            prefix,
+            cursors: collections::HashMap::new(),
+            branch,
+        };
+        Self::new(trace, mode)
+    }
+
+    fn new(trace: DecisionTrace, mode: Mode) -> Self {
+        Self {
+            state: sync::Arc::new(sync::Mutex::new(State { trace, mode })),
+        }
+    }
+
+    /// Returns the tape streams created on this thread should draw through.
+    pub(crate) fn current() -> Option<Tape> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    /// Makes this the tape of streams created on this thread from now on.
+    pub(crate) fn install(&self) {
+        CURRENT.with(|current| current.borrow_mut().replace(self.clone()));
+    }
+
+    /// Removes this tape from the thread, if it is still installed.
+    pub(crate) fn uninstall(&self) {
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            let installed = current
+                .as_ref()
+                .map_or(false, |tape| sync::Arc::ptr_eq(&tape.state, &self.state));
+            if installed {
+                current.take();
+            }
+        });
+    }
+
+    pub(crate) fn trace(&self) -> DecisionTrace {
+        self.state.lock().unwrap().trace.clone()
+    }
+
+    /// Returns the values drawn so far by a recording or branching tape.
+    pub(crate) fn recorded(&self) -> Option<DecisionTrace> {
+        let lock = self.state.lock().unwrap();
+        match lock.mode {
+            Mode::Record | Mode::Branch { .. } => Some(lock.trace.clone()),
+            Mode::Replay { .. } => None,
+        }
+    }
+
+    /// Returns the number of values which could not be replayed from the trace.
+    pub(crate) fn overruns(&self) -> u64 {
+        match self.state.lock().unwrap().mode {
+            Mode::Record | Mode::Branch { .. } => 0,
+            Mode::Replay { overruns, .. } => overruns,
+        }
+    }
+
+    /// Draws the next value of the stream with the provided seed, recording the value produced
+    /// by `generate` or replaying the recorded value.
+    pub(crate) fn draw<F>(&self, stream: u64, generate: F) -> u64
+    where
+        F: FnOnce() -> u64,
+    {
+        let mut lock = self.state.lock().unwrap();
+        let State { trace, mode } = &mut *lock;
+        match mode {
+            Mode::Record => {
+                let value = generate();
+                trace.streams.entry(stream).or_default().push(value);
+                value
+            }
+            Mode::Replay { cursors, overruns } => {
+                let cursor = cursors.entry(stream).or_insert(0);
+                let recorded = trace
+                    .streams
+                    .get(&stream)
+                    .and_then(|values| values.get(*cursor));
+                match recorded {
+                    Some(value) => {
+                        *cursor += 1;
+                        *value
+                    }
+                    None => {
+                        *overruns += 1;
+                        generate()
+                    }
+                }
+            }
+            Mode::Branch {
+                prefix,
+                cursors,
+                branch,
+            } => {
+                let cursor = cursors.entry(stream).or_insert(0);
+                let recorded = prefix
+                    .streams
+                    .get(&stream)
+                    .and_then(|values| values.get(*cursor));
+                let value = match recorded {
+                    Some(value) => *value,
+                    None => branch.derive(&format!("{} {}", stream, cursor)).value(),
+                };
+                *cursor += 1;
+                trace.streams.entry(stream).or_default().push(value);
+                value
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Runs an echo exchange under latency faults, returning the values drawn by the client
+    /// and the time at which each reply arrived.
+    fn run(runtime: &mut DeterministicRuntime) -> (Vec<u64>, Vec<time::Duration>) {
+        let handle = runtime.localhost_handle();
+        let faults = runtime.latency_fault();
+        runtime.block_on(async move {
+            handle.spawn(faults.run());
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1];
+                while let Ok(1) = socket.read(&mut buf).await {
+                    socket.write_all(&buf).await.unwrap();
+                }
+            });
+            let start = handle.now();
+            let mut socket = handle.connect(addr).await.unwrap();
+            let mut draws = vec![];
+            let mut arrivals = vec![];
+            for _ in 0..10 {
+                draws.push(handle.rng().next_u64());
+                socket.write_all(&[1]).await.unwrap();
+                socket.read_exact(&mut [0u8; 1]).await.unwrap();
+                arrivals.push(handle.now() - start);
+                handle.delay_from(time::Duration::from_secs(1)).await;
+            }
+            (draws, arrivals)
+        })
+    }
+
+    #[test]
+    /// Test that replaying a recorded trace reproduces the run after a round trip through the
+    /// binary format, and that replayed values are taken from the trace.
+    fn record_and_replay() {
+        let mut recording = DeterministicRuntime::new_recording(5).unwrap();
+        let recorded = run(&mut recording);
+        let trace = recording.decision_trace().unwrap();
+        drop(recording);
+        assert!(trace.decisions() > 0);
+        let trace = DecisionTrace::from_bytes(&trace.to_bytes()).unwrap();
+        assert!(DecisionTrace::from_bytes(&trace.to_bytes()[..20]).is_err());
+
+        let mut replaying = DeterministicRuntime::new_replaying(trace.clone()).unwrap();
+        assert_eq!(run(&mut replaying), recorded);
+        assert_eq!(replaying.replay_overruns(), Some(0));
+        drop(replaying);
+
+        let mut tampered = trace;
+        let client = tampered
+            .streams
+            .values_mut()
+            .find(|values| values.contains(&recorded.0[0]))
+            .unwrap();
+        for value in client.iter_mut() {
+            *value = 42;
+        }
+        let mut replaying = DeterministicRuntime::new_replaying(tampered).unwrap();
+        let (draws, _) = run(&mut replaying);
+        assert!(draws.iter().all(|draw| *draw == 42));
+    }
+}
@@ -0,0 +1,550 @@
+//! Structured end-to-end test scripts.
+//!
+//! Larger simulation tests tend to share a shape: bring up a cluster, drive a workload against it
+//! while a nemesis injects faults, then check invariants once things settle. A [`Scenario`] names
+//! these phases explicitly rather than leaving them implicit in a pile of spawned tasks.
+//!
+//! Phases are separated by barriers on virtual time. Setup steps run one after another. Once
+//! setup completes, every workload and nemesis starts at the same instant. The nemeses are
+//! stopped once every workload has completed, and after an optional quiescence period the
+//! validation steps run one after another.
+//!
+//! Each phase can be given a budget of virtual time. Convergence regressions, such as a cluster
+//! taking minutes rather than seconds to elect a leader once a partition heals, then fail the
+//! scenario along with a dump of what every task was doing when the budget ran out.
+//!
+//! [`Scenario`]:`Scenario`
+use super::{DeterministicRuntime, DeterministicRuntimeHandle, SimTimeoutError};
+use crate::Environment;
+use futures::{future, Future, FutureExt};
+use std::{cell, collections, error, fmt, pin::Pin, rc, time};
+use tracing::{trace, warn};
+
+type Step = Box<dyn FnOnce(DeterministicRuntimeHandle) -> Pin<Box<dyn Future<Output = ()>>>>;
+
+fn step<F, U>(f: F) -> Step
+where
+    F: FnOnce(DeterministicRuntimeHandle) -> U + 'static,
+    U: Future<Output = ()> + 'static,
+{
+    Box::new(move |env| Box::pin(f(env)) as Pin<Box<dyn Future<Output = ()>>>)
+}
+
+/// A phase of a [`Scenario`].
+///
+/// [`Scenario`]:`Scenario`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Setup,
+    Workload,
+    Nemesis,
+    Validation,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Phase::Setup => write!(f, "setup"),
+            Phase::Workload => write!(f, "workload"),
+            Phase::Nemesis => write!(f, "nemesis"),
+            Phase::Validation => write!(f, "validation"),
+        }
+    }
+}
+
+/// Builder for a simulation test organized into setup, workload, nemesis and validation phases.
+pub struct Scenario {
+    name: String,
+    setup: Vec<(String, Step)>,
+    workloads: Vec<(String, Step)>,
+    nemeses: Vec<(String, Step)>,
+    validations: Vec<(String, Step)>,
+    quiesce: time::Duration,
+    phase_timeout: Option<time::Duration>,
+    budgets: collections::HashMap<Phase, time::Duration>,
+}
+
+impl fmt::Debug for Scenario {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |steps: &[(String, Step)]| -> Vec<String> {
+            steps.iter().map(|(name, _)| name.clone()).collect()
+        };
+        f.debug_struct("Scenario")
+            .field("name", &self.name)
+            .field("setup", &names(&self.setup))
+            .field("workloads", &names(&self.workloads))
+            .field("nemeses", &names(&self.nemeses))
+            .field("validations", &names(&self.validations))
+            .field("quiesce", &self.quiesce)
+            .field("phase_timeout", &self.phase_timeout)
+            .field("budgets", &self.budgets)
+            .finish()
+    }
+}
+
+impl Scenario {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            setup: vec![],
+            workloads: vec![],
+            nemeses: vec![],
+            validations: vec![],
+            quiesce: time::Duration::from_secs(0),
+            phase_timeout: None,
+            budgets: collections::HashMap::new(),
+        }
+    }
+
+    /// Adds a setup step. Setup steps run in the order they were added, and tasks they spawn
+    /// keep running for the remainder of the scenario.
+    pub fn setup<F, U>(mut self, name: impl Into<String>, f: F) -> Self
+    where
+        F: FnOnce(DeterministicRuntimeHandle) -> U + 'static,
+        U: Future<Output = ()> + 'static,
+    {
+        self.setup.push((name.into(), step(f)));
+        self
+    }
+
+    /// Adds a workload. Workloads run concurrently, and the workload phase ends once all of them
+    /// have completed.
+    pub fn workload<F, U>(mut self, name: impl Into<String>, f: F) -> Self
+    where
+        F: FnOnce(DeterministicRuntimeHandle) -> U + 'static,
+        U: Future<Output = ()> + 'static,
+    {
+        self.workloads.push((name.into(), step(f)));
+        self
+    }
+
+    /// Adds a nemesis, which runs alongside the workloads and is dropped once every workload has
+    /// completed. Faults a nemesis leaves in place, such as a partition, remain in place during
+    /// validation unless a validation step heals them.
+    pub fn nemesis<F, U>(mut self, name: impl Into<String>, f: F) -> Self
+    where
+        F: FnOnce(DeterministicRuntimeHandle) -> U + 'static,
+        U: Future<Output = ()> + 'static,
+    {
+        self.nemeses.push((name.into(), step(f)));
+        self
+    }
+
+    /// Adds a validation step. Validation steps run in the order they were added, once the
+    /// workload phase and quiescence period have ended.
+    pub fn validate<F, U>(mut self, name: impl Into<String>, f: F) -> Self
+    where
+        F: FnOnce(DeterministicRuntimeHandle) -> U + 'static,
+        U: Future<Output = ()> + 'static,
+    {
+        self.validations.push((name.into(), step(f)));
+        self
+    }
+
+    /// Sets the virtual time to wait between the end of the workload phase and the start of
+    /// validation, allowing retries and in-flight messages to settle.
+    pub fn quiesce(mut self, quiesce: time::Duration) -> Self {
+        self.quiesce = quiesce;
+        self
+    }
+
+    /// Fails the scenario if any setup or validation step, or the workload phase as a whole,
+    /// takes longer than the provided virtual duration.
+    pub fn phase_timeout(mut self, timeout: time::Duration) -> Self {
+        self.phase_timeout.replace(timeout);
+        self
+    }
+
+    /// Fails the scenario if the provided phase as a whole takes longer than `budget` of
+    /// virtual time. Unlike the phase timeout, which bounds each setup and validation step, a
+    /// budget bounds every step of the phase together.
+    ///
+    /// The nemesis phase ends along with the workload phase, so it cannot be given a budget of
+    /// its own.
+    pub fn phase_budget(mut self, phase: Phase, budget: time::Duration) -> Self {
+        assert!(
+            phase != Phase::Nemesis,
+            "the nemesis phase ends with the workload phase, budget the workload phase instead"
+        );
+        self.budgets.insert(phase, budget);
+        self
+    }
+
+    /// Runs the scenario to completion on the provided runtime, with every step using a handle
+    /// scoped to the local host.
+    pub fn run(self, runtime: &mut DeterministicRuntime) -> Result<ScenarioReport, ScenarioError> {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(self.execute(handle))
+    }
+
+    async fn execute(
+        self,
+        handle: DeterministicRuntimeHandle,
+    ) -> Result<ScenarioReport, ScenarioError> {
+        let Scenario {
+            name,
+            setup,
+            workloads,
+            nemeses,
+            validations,
+            quiesce,
+            phase_timeout,
+            budgets,
+        } = self;
+        let started = handle.now();
+        let elapsed = || handle.now() - started;
+        let mut report = ScenarioReport {
+            name: name.clone(),
+            phases: vec![],
+        };
+        let fail = |phase, steps, timeout, budget| {
+            let error = ScenarioError {
+                scenario: name.clone(),
+                phase,
+                steps,
+                timeout,
+                budget,
+                diagnostics: format!("{}\n{}", handle.profile(), handle.wait_for_graph()),
+            };
+            warn!("{}\n{}", error, error.diagnostics);
+            error
+        };
+        let budget = |phase| budgets.get(&phase).cloned();
+        // Names of the steps which have not yet completed in the current phase.
+        let pending = cell::RefCell::new(vec![]);
+
+        let phase_started = elapsed();
+        within(
+            &handle,
+            budget(Phase::Setup),
+            run_steps(&handle, &name, &pending, phase_timeout, setup),
+        )
+        .await
+        .map_err(|timeout| {
+            fail(
+                Phase::Setup,
+                pending.replace(vec![]),
+                timeout,
+                budget(Phase::Setup),
+            )
+        })?
+        .map_err(|(step, timeout)| fail(Phase::Setup, vec![step], timeout, None))?;
+        report.record(Phase::Setup, phase_started, elapsed());
+
+        let phase_started = elapsed();
+        trace!("scenario {}: starting {} workloads", name, workloads.len());
+        pending.replace(workloads.iter().map(|(name, _)| name.clone()).collect());
+        let workloads = future::join_all(workloads.into_iter().map(|(step, f)| {
+            let pending = &pending;
+            let workload = f(handle.clone());
+            async move {
+                workload.await;
+                pending.borrow_mut().retain(|pending| *pending != step);
+            }
+        }));
+        let nemesis_finished = rc::Rc::new(cell::Cell::new(None));
+        let nemeses = {
+            let nemesis_finished = rc::Rc::clone(&nemesis_finished);
+            let nemeses = future::join_all(nemeses.into_iter().map(|(_, f)| f(handle.clone())));
+            let handle = handle.clone();
+            async move {
+                nemeses.await;
+                nemesis_finished.set(Some(handle.now() - started));
+                future::pending::<()>().await;
+            }
+        };
+        let phase = future::select(Box::pin(workloads), Box::pin(nemeses)).map(|_| ());
+        within(
+            &handle,
+            budget(Phase::Workload),
+            within(&handle, phase_timeout, phase),
+        )
+        .await
+        .map_err(|timeout| (timeout, budget(Phase::Workload)))
+        .and_then(|result| result.map_err(|timeout| (timeout, None)))
+        .map_err(|(timeout, budget)| {
+            fail(Phase::Workload, pending.replace(vec![]), timeout, budget)
+        })?;
+        let workload_finished = elapsed();
+        report.record(Phase::Workload, phase_started, workload_finished);
+        let nemesis_finished = nemesis_finished.get().unwrap_or(workload_finished);
+        report.record(Phase::Nemesis, phase_started, nemesis_finished);
+
+        if quiesce > time::Duration::from_secs(0) {
+            trace!("scenario {}: quiescing for {:?}", name, quiesce);
+            handle.delay_from(quiesce).await;
+        }
+
+        let phase_started = elapsed();
+        within(
+            &handle,
+            budget(Phase::Validation),
+            run_steps(&handle, &name, &pending, phase_timeout, validations),
+        )
+        .await
+        .map_err(|timeout| {
+            fail(
+                Phase::Validation,
+                pending.replace(vec![]),
+                timeout,
+                budget(Phase::Validation),
+            )
+        })?
+        .map_err(|(step, timeout)| fail(Phase::Validation, vec![step], timeout, None))?;
+        report.record(Phase::Validation, phase_started, elapsed());
+        Ok(report)
+    }
+}
+
+/// Runs the provided steps one after another, returning the name of the first step to exceed
+/// the step timeout. The name of the running step is kept in `pending`.
+async fn run_steps(
+    handle: &DeterministicRuntimeHandle,
+    scenario: &str,
+    pending: &cell::RefCell<Vec<String>>,
+    timeout: Option<time::Duration>,
+    steps: Vec<(String, Step)>,
+) -> Result<(), (String, SimTimeoutError)> {
+    for (step, f) in steps {
+        trace!("scenario {}: running step {}", scenario, step);
+        pending.replace(vec![step.clone()]);
+        within(handle, timeout, f(handle.clone()))
+            .await
+            .map_err(|timeout| (step, timeout))?;
+    }
+    pending.replace(vec![]);
+    Ok(())
+}
+
+async fn within<F>(
+    handle: &DeterministicRuntimeHandle,
+    timeout: Option<time::Duration>,
+    future: F,
+) -> Result<F::Output, SimTimeoutError>
+where
+    F: Future,
+{
+    match timeout {
+        Some(timeout) => handle.sim_timeout(timeout, future).await,
+        None => Ok(future.await),
+    }
+}
+
+/// Virtual times at which each phase of a completed [`Scenario`] started and finished, relative
+/// to the start of the scenario.
+///
+/// [`Scenario`]:`Scenario`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioReport {
+    name: String,
+    phases: Vec<(Phase, time::Duration, time::Duration)>,
+}
+
+impl ScenarioReport {
+    fn record(&mut self, phase: Phase, started: time::Duration, finished: time::Duration) {
+        self.phases.push((phase, started, finished));
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the time at which the provided phase started.
+    pub fn started_at(&self, phase: Phase) -> Option<time::Duration> {
+        self.phases
+            .iter()
+            .find(|(p, _, _)| *p == phase)
+            .map(|(_, started, _)| *started)
+    }
+
+    /// Returns the time at which the provided phase finished. A nemesis which is still running
+    /// when the workloads complete finishes along with them.
+    pub fn finished_at(&self, phase: Phase) -> Option<time::Duration> {
+        self.phases
+            .iter()
+            .find(|(p, _, _)| *p == phase)
+            .map(|(_, _, finished)| *finished)
+    }
+}
+
+impl fmt::Display for ScenarioReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "scenario {}", self.name)?;
+        for (phase, started, finished) in &self.phases {
+            writeln!(f, "  {}: {:?} - {:?}", phase, started, finished)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned when a phase of a [`Scenario`] exceeds the phase timeout or its budget.
+///
+/// [`Scenario`]:`Scenario`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScenarioError {
+    scenario: String,
+    phase: Phase,
+    steps: Vec<String>,
+    timeout: SimTimeoutError,
+    budget: Option<time::Duration>,
+    diagnostics: String,
+}
+
+impl ScenarioError {
+    pub fn phase(&self) -> Phase {
+        self.phase
+    }
+
+    /// Returns the names of the steps which had not completed when the phase timed out.
+    pub fn steps(&self) -> &[String] {
+        &self.steps
+    }
+
+    pub fn timeout(&self) -> &SimTimeoutError {
+        &self.timeout
+    }
+
+    /// Returns the budget of the phase if the phase failed by exceeding it, rather than by a
+    /// step exceeding the phase timeout.
+    pub fn budget(&self) -> Option<time::Duration> {
+        self.budget
+    }
+
+    /// Returns the virtual time profile and wait-for graph of the runtime's tasks at the moment
+    /// the phase failed. The diagnostics are also logged when the scenario fails.
+    pub fn diagnostics(&self) -> &str {
+        &self.diagnostics
+    }
+}
+
+impl fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "scenario {} {} phase ", self.scenario, self.phase)?;
+        match self.budget {
+            Some(budget) => write!(f, "exceeded its budget of {:?}", budget)?,
+            None => write!(f, "timed out")?,
+        }
+        write!(
+            f,
+            " with [{}] incomplete: {}",
+            self.steps.join(", "),
+            self.timeout
+        )
+    }
+}
+
+impl error::Error for ScenarioError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.timeout)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, TcpListener};
+    use std::{net, sync};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that phases are separated by barriers, stopping the nemesis once workloads complete.
+    fn phases() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+        let echoed = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+        let injected = rc::Rc::new(cell::Cell::new(0));
+        let report = Scenario::new("echo")
+            .setup("server", move |env| async move {
+                let mut listener = env.bind(addr).await.unwrap();
+                env.spawn(async move {
+                    while let Ok((mut socket, _)) = listener.accept().await {
+                        let mut buf = [0u8; 1];
+                        socket.read_exact(&mut buf).await.unwrap();
+                        socket.write_all(&buf).await.unwrap();
+                    }
+                });
+            })
+            .workload("client", {
+                let echoed = sync::Arc::clone(&echoed);
+                move |env| async move {
+                    for _ in 0..5 {
+                        let mut socket = env.connect(addr).await.unwrap();
+                        let mut buf = [7u8; 1];
+                        socket.write_all(&buf).await.unwrap();
+                        socket.read_exact(&mut buf).await.unwrap();
+                        env.delay_from(time::Duration::from_secs(10)).await;
+                        echoed.fetch_add(1, sync::atomic::Ordering::SeqCst);
+                    }
+                }
+            })
+            .nemesis("ticker", {
+                let injected = rc::Rc::clone(&injected);
+                move |env| async move {
+                    loop {
+                        env.delay_from(time::Duration::from_secs(1)).await;
+                        injected.set(injected.get() + 1);
+                    }
+                }
+            })
+            .quiesce(time::Duration::from_secs(30))
+            .validate("all echoed", {
+                let echoed = sync::Arc::clone(&echoed);
+                move |_| async move {
+                    assert_eq!(echoed.load(sync::atomic::Ordering::SeqCst), 5);
+                }
+            })
+            .run(&mut runtime)
+            .unwrap();
+        let workload_finished = report.finished_at(Phase::Workload).unwrap();
+        assert!(workload_finished >= time::Duration::from_secs(50));
+        assert_eq!(report.finished_at(Phase::Nemesis), Some(workload_finished));
+        assert_eq!(
+            report.started_at(Phase::Validation),
+            Some(workload_finished + time::Duration::from_secs(30))
+        );
+        assert!(injected.get() >= 40);
+    }
+
+    #[test]
+    /// Test that a phase exceeding the phase timeout reports the incomplete steps.
+    fn phase_timeout() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let err = Scenario::new("stuck")
+            .workload("fast", |_| async {})
+            .workload("slow", |env| async move {
+                env.delay_from(time::Duration::from_secs(3600)).await;
+            })
+            .phase_timeout(time::Duration::from_secs(60))
+            .run(&mut runtime)
+            .unwrap_err();
+        assert_eq!(err.phase(), Phase::Workload);
+        assert_eq!(err.steps(), &[String::from("slow")]);
+        assert_eq!(err.timeout().duration(), time::Duration::from_secs(60));
+    }
+
+    #[test]
+    /// Test that a phase exceeding its budget fails with diagnostics, even though every step
+    /// finishes within the phase timeout.
+    fn phase_budget() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let err = Scenario::new("slow recovery")
+            .workload("partition", |_| async {})
+            .validate("healed", |env| async move {
+                env.delay_from(time::Duration::from_secs(20)).await;
+            })
+            .validate("converged", |env| async move {
+                env.delay_from(time::Duration::from_secs(50)).await;
+            })
+            .phase_timeout(time::Duration::from_secs(60))
+            .phase_budget(Phase::Validation, time::Duration::from_secs(60))
+            .run(&mut runtime)
+            .unwrap_err();
+        assert_eq!(err.phase(), Phase::Validation);
+        assert_eq!(err.budget(), Some(time::Duration::from_secs(60)));
+        assert_eq!(err.steps(), &[String::from("converged")]);
+        assert!(err.diagnostics().contains("digraph"));
+        assert!(err
+            .to_string()
+            .starts_with("scenario slow recovery validation phase exceeded its budget of 60s"));
+    }
+}
@@ -0,0 +1,284 @@
+//! Seeded entry point for simulation tests.
+//!
+//! A [`Simulation`] owns a [`DeterministicRuntime`] created from a single seed, from which the
+//! randomness of the runtime, its fault injectors and its host and task substreams are all
+//! derived. Running the same test with the same seed replays the same execution in any process,
+//! so when a run panics the seed is printed before the panic continues, allowing the failure to
+//! be reproduced with `Simulation::new(seed)`. Runs can be bounded in both simulated and real
+//! time with [`run_with_timeout`], so a bug which stops time from advancing fails the run
+//! rather than hanging it. Properties of the system under test can be checked throughout a run
+//! with [`register_invariant`].
+//!
+//! [`Simulation`]:`Simulation`
+//! [`DeterministicRuntime`]:`DeterministicRuntime`
+//! [`run_with_timeout`]:`Simulation::run_with_timeout`
+//! [`register_invariant`]:`Simulation::register_invariant`
+use super::network::fault::FaultScheduleInjector;
+use super::{DeterministicRuntime, DeterministicRuntimeHandle, InvariantCheck, Seed};
+use crate::{Environment, Error};
+use futures::Future;
+use std::{panic, time};
+
+/// A deterministic runtime created from a single seed, which reports the seed of failed runs.
+pub struct Simulation {
+    runtime: DeterministicRuntime,
+    /// Faults applied from the start of the next run.
+    faults: Option<FaultScheduleInjector>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64) -> Result<Self, Error> {
+        Ok(Self {
+            runtime: DeterministicRuntime::new_with_seed(seed)?,
+            faults: None,
+        })
+    }
+
+    /// Returns the seed the simulation was created with.
+    pub fn seed(&self) -> Seed {
+        self.runtime.seed()
+    }
+
+    /// Returns a handle scoped to localhost.
+    pub fn handle(&self) -> DeterministicRuntimeHandle {
+        self.runtime.localhost_handle()
+    }
+
+    /// Returns a handle scoped to the host registered under the provided name.
+    pub fn host(&self, name: &str) -> Option<DeterministicRuntimeHandle> {
+        let addr = self.handle().resolve_host(name)?;
+        Some(self.runtime.handle(addr))
+    }
+
+    /// Applies the faults of the provided injector from the start of the next run.
+    pub(crate) fn inject_on_run(&mut self, injector: FaultScheduleInjector) {
+        self.faults.replace(injector);
+    }
+
+    /// Returns the runtime driving the simulation, for configuring the network, fault injectors
+    /// and hosts before running.
+    pub fn runtime(&mut self) -> &mut DeterministicRuntime {
+        &mut self.runtime
+    }
+
+    /// Runs the provided future to completion. If the future or any task spawned by it panics,
+    /// the seed of the simulation is printed to stderr before the panic is resumed.
+    pub fn run<F>(&mut self, f: F) -> F::Output
+    where
+        F: Future,
+    {
+        let seed = self.seed();
+        let faults = self.faults.take();
+        let handle = self.handle();
+        let f = async move {
+            if let Some(faults) = faults {
+                handle.spawn(faults.run());
+            }
+            f.await
+        };
+        let runtime = &mut self.runtime;
+        match panic::catch_unwind(panic::AssertUnwindSafe(|| runtime.block_on(f))) {
+            Ok(output) => output,
+            Err(payload) => {
+                eprintln!(
+                    "simulation failed with seed {}, reproduce with Simulation::new({})",
+                    seed.value(),
+                    seed.value()
+                );
+                panic::resume_unwind(payload)
+            }
+        }
+    }
+
+    /// Runs the provided future to completion as with [`run`], panicking with a profile of
+    /// every task if the run takes more than `simulated` time or more than `real` time. If a
+    /// single poll never returns, the process is aborted shortly after `real` has elapsed.
+    ///
+    /// [`run`]:`Simulation::run`
+    pub fn run_with_timeout<F>(
+        &mut self,
+        simulated: time::Duration,
+        real: time::Duration,
+        f: F,
+    ) -> F::Output
+    where
+        F: Future,
+    {
+        let description = format!("simulation with seed {}", self.seed().value());
+        let now = self.handle().now();
+        let _armed = self
+            .runtime
+            .watchdog()
+            .arm(now, simulated, real, description);
+        self.run(f)
+    }
+
+    /// Registers an invariant evaluated after task polls at the points selected by `check`. An
+    /// invariant returning an error fails the run with its name, the error, the seed of the
+    /// simulation and the last events recorded by the runtime.
+    pub fn register_invariant<F>(&mut self, name: impl Into<String>, check: InvariantCheck, f: F)
+    where
+        F: FnMut() -> Result<(), String> + Send + 'static,
+    {
+        let invariants = self.runtime.invariants();
+        if !invariants.has_context() {
+            let log = self.runtime.record_events();
+            invariants.set_context(self.runtime.seed(), log);
+        }
+        let now = self.handle().now();
+        invariants.register(name.into(), check, now, f);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, TcpListener};
+    use std::{net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Runs an echo exchange under latency faults, returning the random values drawn by the
+    /// client and the time at which each reply arrived.
+    fn trace(seed: u64) -> (Vec<u64>, Vec<time::Duration>) {
+        let mut simulation = Simulation::new(seed).unwrap();
+        let handle = simulation.handle();
+        let faults = simulation.runtime().latency_fault();
+        simulation.run(async move {
+            handle.spawn(faults.run());
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1];
+                while let Ok(1) = socket.read(&mut buf).await {
+                    socket.write_all(&buf).await.unwrap();
+                }
+            });
+            let start = handle.now();
+            let mut socket = handle.connect(addr).await.unwrap();
+            let mut draws = vec![];
+            let mut arrivals = vec![];
+            for _ in 0..20 {
+                draws.push(handle.rng().next_u64());
+                socket.write_all(&[1]).await.unwrap();
+                socket.read_exact(&mut [0u8; 1]).await.unwrap();
+                arrivals.push(handle.now() - start);
+                handle.delay_from(time::Duration::from_secs(1)).await;
+            }
+            (draws, arrivals)
+        })
+    }
+
+    #[test]
+    /// Test that simulations created with the same seed replay the same randomness and fault
+    /// timing, and that panics raised by a run are resumed after the seed is reported.
+    fn same_seed_same_run() {
+        assert_eq!(trace(7), trace(7));
+        assert_ne!(trace(7).0, trace(8).0);
+
+        let result = panic::catch_unwind(|| {
+            let mut simulation = Simulation::new(7).unwrap();
+            simulation.run(async { panic!("invariant violated") })
+        });
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"invariant violated"));
+    }
+
+    #[test]
+    /// Test that runs which exceed their simulated or real time budget fail with a diagnostic,
+    /// and that runs within their budget complete.
+    fn run_with_timeout() {
+        let failure = |result: std::thread::Result<()>| {
+            let payload = result.unwrap_err();
+            payload.downcast_ref::<String>().unwrap().clone()
+        };
+        let budget = time::Duration::from_secs(10);
+
+        let mut simulation = Simulation::new(1).unwrap();
+        let handle = simulation.handle();
+        simulation.run_with_timeout(budget, budget, async move {
+            handle.delay_from(time::Duration::from_secs(5)).await;
+        });
+
+        let result = panic::catch_unwind(|| {
+            let mut simulation = Simulation::new(1).unwrap();
+            let handle = simulation.handle();
+            simulation.run_with_timeout(budget, budget, async move {
+                loop {
+                    handle.delay_from(time::Duration::from_secs(1)).await;
+                }
+            })
+        });
+        let message = failure(result);
+        assert!(message.contains("simulated time budget of 10s exceeded"));
+
+        let result = panic::catch_unwind(|| {
+            let mut simulation = Simulation::new(1).unwrap();
+            let real = time::Duration::from_millis(50);
+            simulation.run_with_timeout(budget, real, async {
+                // Yield forever without advancing simulated time.
+                futures::future::poll_fn(|cx| {
+                    cx.waker().wake_by_ref();
+                    futures::Poll::<()>::Pending
+                })
+                .await
+            })
+        });
+        let message = failure(result);
+        assert!(message.contains("real time budget of 50ms exceeded"));
+    }
+
+    #[test]
+    /// Test that invariants are evaluated at the points they were registered for, and that a
+    /// violated invariant fails the run with the seed and the tail of the event log.
+    fn invariants() {
+        use std::sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        };
+        let counter = Arc::new(AtomicUsize::new(0));
+        let steps = Arc::new(AtomicUsize::new(0));
+        let quiescent = Arc::new(AtomicUsize::new(0));
+        let mut simulation = Simulation::new(5).unwrap();
+        let (c, s, q) = (counter.clone(), steps.clone(), quiescent.clone());
+        simulation.register_invariant("steps", InvariantCheck::EveryStep, move || {
+            s.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        simulation.register_invariant("quiescence", InvariantCheck::OnQuiescence, move || {
+            q.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        });
+        let every = InvariantCheck::Every(time::Duration::from_secs(1));
+        simulation.register_invariant("bounded", every, move || match c.load(Ordering::SeqCst) {
+            count if count > 3 => Err(format!("counter reached {}", count)),
+            _ => Ok(()),
+        });
+        let handle = simulation.handle();
+        let bounded = counter.clone();
+        simulation.run(async move {
+            for _ in 0..3 {
+                bounded.fetch_add(1, Ordering::SeqCst);
+                handle.delay_from(time::Duration::from_secs(1)).await;
+            }
+        });
+        assert!(steps.load(Ordering::SeqCst) >= 4);
+        assert!(quiescent.load(Ordering::SeqCst) >= 3);
+
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let handle = simulation.handle();
+            simulation.run(async move {
+                for _ in 0..3 {
+                    counter.fetch_add(1, Ordering::SeqCst);
+                    handle.delay_from(time::Duration::from_secs(1)).await;
+                }
+            })
+        }));
+        let payload = result.unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("invariant bounded violated after polling"));
+        assert!(message.contains("counter reached 4"));
+        assert!(message.contains("seed 5"));
+        assert!(message.contains("TaskPolled"));
+    }
+}
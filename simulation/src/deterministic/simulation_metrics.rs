@@ -0,0 +1,285 @@
+//! Counters of the activity of a simulation.
+//!
+//! Many failures only show up in aggregate: a retry storm opening thousands of connections, a
+//! client which keeps being refused, or a chatty protocol moving far more bytes than expected.
+//! A [`MetricsRecorder`] installed with [`DeterministicRuntime::record_metrics`] counts the
+//! connections, bytes, faults, timers and polls of the simulation from then on, and
+//! [`MetricsRecorder::snapshot`] returns them broken down by host and by connection, ready to be
+//! asserted on at the end of a test. With the `metrics` feature, snapshots can also be exported
+//! to the recorder installed for the [`metrics`] crate.
+//!
+//! [`MetricsRecorder`]:`MetricsRecorder`
+//! [`DeterministicRuntime::record_metrics`]:`super::DeterministicRuntime::record_metrics`
+//! [`MetricsRecorder::snapshot`]:`MetricsRecorder::snapshot`
+//! [`metrics`]: https://docs.rs/metrics
+use super::{ConnectionId, Observer, SimulationEvent, TaskId};
+use std::{collections, net, sync, time};
+
+/// Counters of the connections to and from a host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct HostMetrics {
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Connections established by the host to other hosts.
+    pub connections_opened: u64,
+    /// Connections established by other hosts to listeners of the host.
+    pub connections_accepted: u64,
+    /// Connections to listeners of the host which were refused.
+    pub connections_refused: u64,
+    /// Connections to or from the host which were reset by an injected fault.
+    pub connections_reset: u64,
+}
+
+/// Counters of a single connection, from the point of view of the host which opened it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionMetrics {
+    pub source: net::SocketAddr,
+    pub dest: net::SocketAddr,
+    /// Bytes written by `source` to `dest`.
+    pub bytes_sent: u64,
+    /// Bytes written by `dest` to `source`.
+    pub bytes_received: u64,
+    pub reset: bool,
+}
+
+/// A snapshot of the counters of a simulation.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SimulationMetrics {
+    pub hosts: collections::BTreeMap<net::IpAddr, HostMetrics>,
+    pub connections: collections::BTreeMap<ConnectionId, ConnectionMetrics>,
+    pub bytes_sent: u64,
+    pub connections_opened: u64,
+    pub connections_refused: u64,
+    pub connections_reset: u64,
+    /// Faults applied to the network, such as partitions and latency changes.
+    pub faults_injected: u64,
+    /// Timers which reached their deadline, among those armed since recording started.
+    pub timers_fired: u64,
+    pub tasks_spawned: u64,
+    pub tasks_polled: u64,
+}
+
+impl SimulationMetrics {
+    /// Returns the counters of the provided host, which are zero if it saw no activity.
+    pub fn host(&self, addr: net::IpAddr) -> HostMetrics {
+        self.hosts.get(&addr).cloned().unwrap_or_default()
+    }
+
+    pub fn connection(&self, id: ConnectionId) -> Option<&ConnectionMetrics> {
+        self.connections.get(&id)
+    }
+
+    /// Records the counters of the snapshot with the recorder installed for the `metrics` crate.
+    /// Totals are recorded as `simulation.<counter>`, and the counters of each host as
+    /// `simulation.host.<counter>` labelled with the address of the host.
+    #[cfg(feature = "metrics")]
+    pub fn export(&self) {
+        metrics::gauge!("simulation.bytes_sent", self.bytes_sent as i64);
+        metrics::gauge!(
+            "simulation.connections_opened",
+            self.connections_opened as i64
+        );
+        metrics::gauge!(
+            "simulation.connections_refused",
+            self.connections_refused as i64
+        );
+        metrics::gauge!(
+            "simulation.connections_reset",
+            self.connections_reset as i64
+        );
+        metrics::gauge!("simulation.faults_injected", self.faults_injected as i64);
+        metrics::gauge!("simulation.timers_fired", self.timers_fired as i64);
+        metrics::gauge!("simulation.tasks_spawned", self.tasks_spawned as i64);
+        metrics::gauge!("simulation.tasks_polled", self.tasks_polled as i64);
+        for (addr, host) in self.hosts.iter() {
+            let counters = [
+                ("simulation.host.bytes_sent", host.bytes_sent),
+                ("simulation.host.bytes_received", host.bytes_received),
+                (
+                    "simulation.host.connections_opened",
+                    host.connections_opened,
+                ),
+                (
+                    "simulation.host.connections_accepted",
+                    host.connections_accepted,
+                ),
+                (
+                    "simulation.host.connections_refused",
+                    host.connections_refused,
+                ),
+                ("simulation.host.connections_reset", host.connections_reset),
+            ];
+            for (name, value) in counters.iter() {
+                metrics::gauge!(*name, *value as i64, "host" => addr.to_string());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    metrics: SimulationMetrics,
+    /// Connections by the addresses of their halves, in the direction they were established.
+    addrs: collections::HashMap<(net::SocketAddr, net::SocketAddr), ConnectionId>,
+}
+
+impl State {
+    fn host(&mut self, addr: net::SocketAddr) -> &mut HostMetrics {
+        self.metrics.hosts.entry(addr.ip()).or_default()
+    }
+
+    fn bytes_sent(&mut self, from: net::SocketAddr, to: net::SocketAddr, bytes: u64) {
+        self.metrics.bytes_sent += bytes;
+        self.host(from).bytes_sent += bytes;
+        self.host(to).bytes_received += bytes;
+        if let Some(id) = self.addrs.get(&(from, to)) {
+            if let Some(connection) = self.metrics.connections.get_mut(id) {
+                connection.bytes_sent += bytes;
+            }
+        } else if let Some(id) = self.addrs.get(&(to, from)) {
+            if let Some(connection) = self.metrics.connections.get_mut(id) {
+                connection.bytes_received += bytes;
+            }
+        }
+    }
+
+    fn reset(&mut self, id: ConnectionId) {
+        let (source, dest) = match self.metrics.connections.get_mut(&id) {
+            // Both halves of a connection report its reset.
+            Some(connection) if !connection.reset => {
+                connection.reset = true;
+                (connection.source, connection.dest)
+            }
+            _ => return,
+        };
+        self.metrics.connections_reset += 1;
+        self.host(source).connections_reset += 1;
+        self.host(dest).connections_reset += 1;
+    }
+}
+
+/// Observer counting the activity of a simulation, see [`SimulationMetrics`].
+///
+/// [`SimulationMetrics`]:`SimulationMetrics`
+#[derive(Debug, Default)]
+pub struct MetricsRecorder {
+    state: sync::Mutex<State>,
+}
+
+impl MetricsRecorder {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the counters recorded so far.
+    pub fn snapshot(&self) -> SimulationMetrics {
+        self.state.lock().unwrap().metrics.clone()
+    }
+}
+
+impl Observer for MetricsRecorder {
+    fn task_spawned(&self, _: time::Instant, _: TaskId, _: Option<&str>) {
+        self.state.lock().unwrap().metrics.tasks_spawned += 1;
+    }
+
+    fn task_polled(&self, _: time::Instant, _: TaskId, _: bool) {
+        self.state.lock().unwrap().metrics.tasks_polled += 1;
+    }
+
+    fn timer_fired(&self, _: time::Instant) {
+        self.state.lock().unwrap().metrics.timers_fired += 1;
+    }
+
+    fn event(&self, _: time::Instant, event: &SimulationEvent) {
+        let mut state = self.state.lock().unwrap();
+        match event {
+            SimulationEvent::ConnectionEstablished { id, source, dest } => {
+                state.metrics.connections_opened += 1;
+                state.host(*source).connections_opened += 1;
+                state.host(*dest).connections_accepted += 1;
+                state.addrs.insert((*source, *dest), *id);
+                let connection = ConnectionMetrics {
+                    source: *source,
+                    dest: *dest,
+                    bytes_sent: 0,
+                    bytes_received: 0,
+                    reset: false,
+                };
+                state.metrics.connections.insert(*id, connection);
+            }
+            SimulationEvent::ConnectionRefused { dest } => {
+                state.metrics.connections_refused += 1;
+                state.host(*dest).connections_refused += 1;
+            }
+            SimulationEvent::ConnectionReset { id: Some(id) } => state.reset(*id),
+            SimulationEvent::BytesSent { from, to, bytes } => {
+                state.bytes_sent(*from, *to, *bytes as u64);
+            }
+            SimulationEvent::FaultApplied(_) => state.metrics.faults_injected += 1,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::{ConnectionIdExt, DeterministicRuntime, FaultScheduleBuilder};
+    use crate::{Environment, TcpListener};
+    use std::{io, net, time};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that connections, refusals, resets and bytes are counted for each host and each
+    /// connection, along with the polls and timers of the run.
+    fn count_activity() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let recorder = runtime.record_metrics();
+        let handle = runtime.localhost_handle();
+        let server = handle.spawn_host("server");
+        let server_addr = net::SocketAddr::new(server.addr(), 9000);
+        let reset =
+            FaultScheduleBuilder::new().reset_host(time::Duration::from_secs(1), server.addr());
+        let injector = runtime.schedule_faults(reset);
+        let id = runtime.block_on(async {
+            handle.spawn(injector.run());
+            let mut listener = server.handle().bind(server_addr).await.unwrap();
+            server.spawn(async move {
+                while let Ok((mut socket, _)) = listener.accept().await {
+                    socket.write_all(b"hello").await.unwrap();
+                }
+            });
+            let refused = net::SocketAddr::new(server.addr(), 9001);
+            let err = handle.connect(refused).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+
+            let mut socket = handle.connect(server_addr).await.unwrap();
+            socket.write_all(b"hi").await.unwrap();
+            let mut buf = [0u8; 5];
+            socket.read_exact(&mut buf).await.unwrap();
+            handle.delay_from(time::Duration::from_secs(2)).await;
+            let err = socket.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+            socket.connection_id().unwrap()
+        });
+
+        let metrics = recorder.snapshot();
+        assert_eq!(metrics.connections_opened, 1);
+        assert_eq!(metrics.connections_refused, 1);
+        assert_eq!(metrics.connections_reset, 1);
+        assert_eq!(metrics.faults_injected, 1);
+        assert_eq!(metrics.bytes_sent, 7);
+        let server_metrics = metrics.host(server.addr());
+        assert_eq!(server_metrics.connections_accepted, 1);
+        assert_eq!(server_metrics.connections_refused, 1);
+        assert_eq!(server_metrics.bytes_sent, 5);
+        assert_eq!(server_metrics.bytes_received, 2);
+        let connection = metrics.connection(id).unwrap();
+        assert_eq!((connection.bytes_sent, connection.bytes_received), (2, 5));
+        assert!(connection.reset);
+        assert!(metrics.tasks_polled >= metrics.tasks_spawned && metrics.tasks_spawned >= 2);
+        assert!(metrics.timers_fired >= 1);
+    }
+}
@@ -0,0 +1,293 @@
+//! Sleeps, intervals and timeouts on the simulated clock.
+//!
+//! Timers created through [`DeterministicRuntimeHandle::delay_for`],
+//! [`DeterministicRuntimeHandle::interval`] and [`DeterministicRuntimeHandle::timeout`] fire
+//! in a well-defined order: by deadline, and timers sharing a deadline in the order they were
+//! created. Expired timers wake their tasks in that order, so the tasks are polled in that
+//! order unless shuffle scheduling is enabled. Unlike `tokio::time`, which refers to the real
+//! clock, these utilities only ever observe simulated time.
+//!
+//! [`DeterministicRuntimeHandle::delay_for`]:`super::DeterministicRuntimeHandle::delay_for`
+//! [`DeterministicRuntimeHandle::interval`]:`super::DeterministicRuntimeHandle::interval`
+//! [`DeterministicRuntimeHandle::timeout`]:`super::DeterministicRuntimeHandle::timeout`
+use super::{events::BreakpointId, Breakpoint, DeterministicTimeHandle};
+use futures::{task::Waker, Future, FutureExt, Poll, Stream};
+use std::{collections, error, fmt, pin::Pin, sync, task::Context, time};
+
+#[derive(Debug, Default)]
+struct Entry {
+    fired: bool,
+    waker: Option<Waker>,
+}
+
+#[derive(Debug, Default)]
+struct Queue {
+    next_seq: u64,
+    /// Pending timers, ordered by deadline and then by creation.
+    pending: collections::BTreeMap<(time::Instant, u64), Entry>,
+    /// Breakpoint firing the timers of each pending deadline.
+    breakpoints: collections::HashMap<time::Instant, BreakpointId>,
+}
+
+/// Ordered timers shared by the handles of a runtime.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Timers {
+    queue: sync::Arc<sync::Mutex<Queue>>,
+}
+
+impl Timers {
+    /// Creates a timer firing at the provided deadline, which must already be quantized.
+    pub(crate) fn sleep_until(
+        &self,
+        time_handle: &DeterministicTimeHandle,
+        deadline: time::Instant,
+    ) -> Sleep {
+        let mut queue = self.queue.lock().unwrap();
+        let seq = queue.next_seq;
+        queue.next_seq += 1;
+        let fired = deadline <= time_handle.now();
+        queue
+            .pending
+            .insert((deadline, seq), Entry { fired, waker: None });
+        if !fired && !queue.breakpoints.contains_key(&deadline) {
+            let timers = self.clone();
+            let id = time_handle
+                .events()
+                .add_breakpoint(Breakpoint::at(deadline), move |_| timers.fire(deadline));
+            queue.breakpoints.insert(deadline, id);
+        }
+        Sleep {
+            timers: self.clone(),
+            time_handle: time_handle.clone(),
+            key: (deadline, seq),
+        }
+    }
+
+    /// Fires every timer with the provided deadline, waking them in creation order.
+    fn fire(&self, deadline: time::Instant) {
+        let wakers: Vec<Waker> = {
+            let mut queue = self.queue.lock().unwrap();
+            queue.breakpoints.remove(&deadline);
+            let end = (deadline, u64::max_value());
+            queue
+                .pending
+                .range_mut((deadline, 0)..=end)
+                .filter_map(|(_, entry)| {
+                    entry.fired = true;
+                    entry.waker.take()
+                })
+                .collect()
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+
+    /// Removes a timer, along with the breakpoint of its deadline once no timers remain at it.
+    fn remove(&self, time_handle: &DeterministicTimeHandle, key: (time::Instant, u64)) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.pending.remove(&key).is_none() {
+            return;
+        }
+        let (deadline, _) = key;
+        let end = (deadline, u64::max_value());
+        if queue.pending.range((deadline, 0)..=end).next().is_none() {
+            if let Some(id) = queue.breakpoints.remove(&deadline) {
+                time_handle.events().remove_breakpoint(id);
+            }
+        }
+    }
+}
+
+/// Future returned by [`DeterministicRuntimeHandle::delay_for`], completing once its deadline
+/// has been reached in simulated time.
+///
+/// [`DeterministicRuntimeHandle::delay_for`]:`super::DeterministicRuntimeHandle::delay_for`
+#[derive(Debug)]
+pub struct Sleep {
+    timers: Timers,
+    time_handle: DeterministicTimeHandle,
+    key: (time::Instant, u64),
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> time::Instant {
+        self.key.0
+    }
+
+    /// Returns true once the deadline has been reached.
+    pub fn is_elapsed(&self) -> bool {
+        let queue = self.timers.queue.lock().unwrap();
+        queue
+            .pending
+            .get(&self.key)
+            .map_or(true, |entry| entry.fired)
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut queue = self.timers.queue.lock().unwrap();
+        match queue.pending.get_mut(&self.key) {
+            Some(entry) if !entry.fired => {
+                entry.waker.replace(cx.waker().clone());
+                Poll::Pending
+            }
+            _ => Poll::Ready(()),
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        self.timers.remove(&self.time_handle, self.key);
+    }
+}
+
+/// Stream returned by [`DeterministicRuntimeHandle::interval`], yielding the instant of each
+/// tick. Ticks which are missed because the stream was not polled in time are yielded
+/// immediately once it is.
+///
+/// [`DeterministicRuntimeHandle::interval`]:`super::DeterministicRuntimeHandle::interval`
+#[derive(Debug)]
+pub struct Interval {
+    sleep: Sleep,
+    period: time::Duration,
+}
+
+impl Interval {
+    pub(crate) fn new(sleep: Sleep, period: time::Duration) -> Self {
+        assert!(
+            period > time::Duration::from_millis(0),
+            "interval period must be non-zero"
+        );
+        Self { sleep, period }
+    }
+
+    pub fn period(&self) -> time::Duration {
+        self.period
+    }
+}
+
+impl Stream for Interval {
+    type Item = time::Instant;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        futures::ready!(self.sleep.poll_unpin(cx));
+        let tick = self.sleep.deadline();
+        let next = self
+            .sleep
+            .timers
+            .sleep_until(&self.sleep.time_handle, tick + self.period);
+        self.sleep = next;
+        Poll::Ready(Some(tick))
+    }
+}
+
+/// Error returned by [`Timeout`] once its deadline elapsed before the future completed.
+///
+/// [`Timeout`]:`Timeout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed(());
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "deadline has elapsed")
+    }
+}
+
+impl error::Error for Elapsed {}
+
+/// Future returned by [`DeterministicRuntimeHandle::timeout`], completing with the output of
+/// the wrapped future, or with [`Elapsed`] once the timeout elapses first.
+///
+/// [`DeterministicRuntimeHandle::timeout`]:`super::DeterministicRuntimeHandle::timeout`
+/// [`Elapsed`]:`Elapsed`
+#[derive(Debug)]
+pub struct Timeout<F> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F> Timeout<F> {
+    pub(crate) fn new(future: F, sleep: Sleep) -> Self {
+        Self {
+            future: Box::pin(future),
+            sleep,
+        }
+    }
+}
+
+impl<F> Future for Timeout<F>
+where
+    F: Future,
+{
+    type Output = Result<F::Output, Elapsed>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        if let Poll::Ready(output) = self.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        futures::ready!(self.sleep.poll_unpin(cx));
+        Poll::Ready(Err(Elapsed(())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use futures::StreamExt;
+    use std::{sync, time};
+
+    #[test]
+    /// Test that timers sharing a deadline fire in the order they were created, that intervals
+    /// tick every period, and that timeouts elapse on the simulated clock.
+    fn ordered_timers() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let fired = sync::Arc::new(sync::Mutex::new(vec![]));
+            let mut tasks = vec![];
+            for i in 0..8 {
+                let (handle, fired) = (handle.clone(), fired.clone());
+                let (tx, rx) = futures::channel::oneshot::channel();
+                // Timers alternate between two deadlines, and fire by deadline then creation.
+                let delay = time::Duration::from_secs(if i % 2 == 0 { 2 } else { 1 });
+                handle.clone().spawn(async move {
+                    handle.delay_for(delay).await;
+                    fired.lock().unwrap().push(i);
+                    tx.send(()).unwrap();
+                });
+                tasks.push(rx);
+            }
+            for task in tasks {
+                task.await.unwrap();
+            }
+            assert_eq!(*fired.lock().unwrap(), vec![1, 3, 5, 7, 0, 2, 4, 6]);
+
+            let start = handle.now();
+            let ticks: Vec<_> = handle
+                .interval(time::Duration::from_secs(5))
+                .take(3)
+                .collect()
+                .await;
+            let ticks: Vec<_> = ticks.into_iter().map(|tick| tick - start).collect();
+            assert_eq!(
+                ticks,
+                vec![
+                    time::Duration::from_secs(0),
+                    time::Duration::from_secs(5),
+                    time::Duration::from_secs(10)
+                ]
+            );
+
+            let start = handle.now();
+            let never = futures::future::pending::<()>();
+            let result = handle.timeout(never, time::Duration::from_secs(3)).await;
+            assert!(result.is_err());
+            assert_eq!(handle.now() - start, time::Duration::from_secs(3));
+            let ready = handle.timeout(async { 7 }, time::Duration::from_secs(3));
+            assert_eq!(ready.await, Ok(7));
+        });
+    }
+}
@@ -0,0 +1,99 @@
+//! Snapshots of a run, from which runs exploring different decisions can be branched.
+//!
+//! Questions such as "what if the leader crashed right after this write" are expensive to
+//! answer by searching seeds, as most seeds take the run somewhere else long before the point
+//! of interest. A recording runtime can instead take a [`Snapshot`] at that point with
+//! [`DeterministicRuntimeHandle::snapshot`], and [`DeterministicRuntime::new_branching`]
+//! creates runtimes which reach the same point and then diverge.
+//!
+//! Futures cannot be copied, so the state of the simulation at the snapshot, from the clock and
+//! the tasks to the network and the streams of randomness, is rebuilt by re-running the same
+//! future from the start while replaying the decisions recorded up to the snapshot. Decisions
+//! made after the snapshot are drawn from the branch seed instead, so each branch explores a
+//! different continuation, such as different fault decisions, while every branch shares the
+//! history up to the snapshot. Branched runtimes record their own decisions, so a branch can
+//! itself be snapshotted and branched.
+//!
+//! [`Snapshot`]:`Snapshot`
+//! [`DeterministicRuntimeHandle::snapshot`]:`super::DeterministicRuntimeHandle::snapshot`
+//! [`DeterministicRuntime::new_branching`]:`super::DeterministicRuntime::new_branching`
+use super::DecisionTrace;
+use std::time;
+
+/// The decisions made by a recording run up to a point, taken with
+/// [`DeterministicRuntimeHandle::snapshot`].
+///
+/// [`DeterministicRuntimeHandle::snapshot`]:`super::DeterministicRuntimeHandle::snapshot`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    trace: DecisionTrace,
+    at: time::Instant,
+}
+
+impl Snapshot {
+    pub(crate) fn new(trace: DecisionTrace, at: time::Instant) -> Self {
+        Self { trace, at }
+    }
+
+    /// Returns the decisions made up to the snapshot.
+    pub fn trace(&self) -> &DecisionTrace {
+        &self.trace
+    }
+
+    /// Returns the virtual time at which the snapshot was taken.
+    pub fn at(&self) -> time::Instant {
+        self.at
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::collections::HashSet;
+
+    /// Draws a delay and sleeps for it, snapshots, then draws again, returning both draws and
+    /// the snapshot.
+    fn run(runtime: &mut DeterministicRuntime) -> (u64, Snapshot, u64) {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async move {
+            let before = handle.rng().next_u64();
+            handle
+                .delay_from(time::Duration::from_millis(before % 1000))
+                .await;
+            let snapshot = handle.snapshot().unwrap();
+            assert_eq!(snapshot.at(), handle.now());
+            let after = handle.rng().next_u64();
+            (before, snapshot, after)
+        })
+    }
+
+    #[test]
+    /// Test that branches reproduce the run up to the snapshot, diverge after it, and are
+    /// reproducible for the same branch seed.
+    fn branch() {
+        let mut recording = DeterministicRuntime::new_recording(3).unwrap();
+        let (before, snapshot, after) = run(&mut recording);
+        drop(recording);
+        let mut plain = DeterministicRuntime::new().unwrap();
+        let handle = plain.localhost_handle();
+        assert!(plain.block_on(async move { handle.snapshot() }).is_none());
+        drop(plain);
+
+        let mut continuations = HashSet::new();
+        for branch in 0..8 {
+            let mut branching = DeterministicRuntime::new_branching(&snapshot, branch).unwrap();
+            let (branch_before, branch_snapshot, branch_after) = run(&mut branching);
+            drop(branching);
+            assert_eq!(branch_before, before);
+            assert_eq!(branch_snapshot, snapshot);
+            continuations.insert(branch_after);
+
+            let mut again = DeterministicRuntime::new_branching(&snapshot, branch).unwrap();
+            assert_eq!(run(&mut again).2, branch_after);
+        }
+        assert!(continuations.len() > 1);
+        assert!(!continuations.contains(&after));
+    }
+}
@@ -0,0 +1,175 @@
+//! Diagnostics explaining why a simulation is not making progress.
+use super::{TaskId, WaitResource};
+use std::{fmt, time};
+
+/// A set of tasks waiting on the same condition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StallCondition {
+    resource: Option<WaitResource>,
+    detail: Option<String>,
+    tasks: Vec<(TaskId, Option<String>)>,
+}
+
+impl StallCondition {
+    /// Returns the resource the tasks are waiting on, or `None` if the tasks are waiting on
+    /// timers or resources which are not instrumented.
+    pub fn resource(&self) -> Option<&WaitResource> {
+        self.resource.as_ref()
+    }
+
+    /// Returns additional detail about the state of the resource.
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_ref().map(String::as_str)
+    }
+
+    /// Returns the id and name of each waiting task.
+    pub fn tasks(&self) -> &[(TaskId, Option<String>)] {
+        &self.tasks
+    }
+}
+
+impl fmt::Display for StallCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let count = self.tasks.len();
+        let plural = if count == 1 { "task" } else { "tasks" };
+        match &self.resource {
+            Some(resource) => write!(f, "{} {} waiting on {}", count, plural, resource)?,
+            None => write!(
+                f,
+                "{} {} waiting on timers or uninstrumented resources",
+                count, plural
+            )?,
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, " which {}", detail)?;
+        }
+        let tasks: Vec<String> = self
+            .tasks
+            .iter()
+            .map(|(id, name)| match name {
+                Some(name) => format!("{} {}", id, name),
+                None => id.to_string(),
+            })
+            .collect();
+        write!(f, " ({})", tasks.join(", "))
+    }
+}
+
+/// Report of the conditions a stalled simulation is waiting on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StallReport {
+    conditions: Vec<StallCondition>,
+    next_timer: Option<time::Duration>,
+}
+
+impl StallReport {
+    /// Group the provided tasks by the resource they are waiting on, describing each resource
+    /// with `describe`.
+    pub(crate) fn new<F>(
+        tasks: Vec<(TaskId, Option<String>, Option<WaitResource>)>,
+        next_timer: Option<time::Duration>,
+        describe: F,
+    ) -> Self
+    where
+        F: Fn(&WaitResource) -> Option<String>,
+    {
+        let mut conditions: Vec<StallCondition> = vec![];
+        for (id, name, resource) in tasks {
+            match conditions.iter_mut().find(|c| c.resource == resource) {
+                Some(condition) => condition.tasks.push((id, name)),
+                None => conditions.push(StallCondition {
+                    detail: resource.as_ref().and_then(|r| describe(r)),
+                    resource,
+                    tasks: vec![(id, name)],
+                }),
+            }
+        }
+        // List instrumented resources first, followed by tasks waiting on anything else.
+        conditions.sort_by_key(|c| c.resource.is_none());
+        Self {
+            conditions,
+            next_timer,
+        }
+    }
+
+    pub fn conditions(&self) -> &[StallCondition] {
+        &self.conditions
+    }
+
+    /// Returns the duration until the next timer fires, or `None` if there are no pending
+    /// timers.
+    pub fn next_timer(&self) -> Option<time::Duration> {
+        self.next_timer
+    }
+}
+
+impl fmt::Display for StallReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for condition in self.conditions.iter() {
+            writeln!(f, "{}", condition)?;
+        }
+        match self.next_timer {
+            Some(next_timer) => write!(f, "next timer in {:?}", next_timer),
+            None => write!(f, "no pending timers"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment, TcpListener};
+    use std::{net, time::Duration};
+    use tokio::io::AsyncReadExt;
+
+    #[test]
+    /// Test that stalled tasks are grouped by the resource they are waiting on.
+    fn explain_stall() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "10.0.0.5:80".parse().unwrap();
+            for name in &["client1", "client2", "client3"] {
+                let client_handle = handle.clone();
+                handle.spawn_named(*name, async move {
+                    let _ = client_handle.connect(addr).await;
+                });
+            }
+            let sleeper_handle = handle.clone();
+            handle.spawn_named("sleeper", async move {
+                sleeper_handle.delay_from(Duration::from_secs(300)).await;
+            });
+            let report = handle.explain_stall().await;
+            assert_eq!(report.next_timer(), Some(Duration::from_secs(300)));
+            let rendered = report.to_string();
+            assert!(
+                rendered.contains(
+                    "2 tasks waiting on listener 10.0.0.5:80 which is not bound \
+                     (task#2 client2, task#3 client3)"
+                ),
+                "unexpected report {}",
+                rendered
+            );
+            assert!(rendered.contains("1 task waiting on timers or uninstrumented resources"));
+            assert!(rendered.ends_with("next timer in 300s"));
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "waiting on read 127.0.0.1:9092 -> 127.0.0.1:65535")]
+    /// Test that a simulation in which every task waits on another fails with a report of what
+    /// each task is waiting on, rather than hanging.
+    fn detect_deadlock() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn_named("server", async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let _ = socket.read(&mut [0u8; 1]).await;
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            let _ = socket.read(&mut [0u8; 1]).await;
+        });
+    }
+}
@@ -0,0 +1,460 @@
+//! Dependency ordered startup of simulated services.
+//!
+//! Boot order races, such as a cache coming up before the database it fills from, only show up
+//! when services start in an unlucky order or one of them is slow to start. A [`StartupGraph`]
+//! declares each service along with the services it depends on, then starts every service on a
+//! host of its own, registered under the service name. Services start once their dependencies
+//! have started, after a startup delay drawn from the runtime seed, and injected startup
+//! failures are retried. [`StartupOrder::Shuffled`] ignores the declared dependencies, starting
+//! services in a seeded order to check that the system under test tolerates any boot order.
+//!
+//! [`StartupGraph`]:`StartupGraph`
+//! [`StartupOrder::Shuffled`]:`StartupOrder::Shuffled`
+use super::DeterministicRuntimeHandle;
+use crate::Environment;
+use futures::{channel::oneshot, future, Future};
+use std::{collections, error, fmt, pin::Pin, time};
+use tracing::trace;
+
+type Start = Box<
+    dyn FnOnce(DeterministicRuntimeHandle) -> Pin<Box<dyn Future<Output = Result<(), String>>>>,
+>;
+
+/// Order in which a [`StartupGraph`] starts its services.
+///
+/// [`StartupGraph`]:`StartupGraph`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StartupOrder {
+    /// Start services once all of their dependencies have started.
+    Dependencies,
+    /// Start every service concurrently, ignoring dependencies.
+    Shuffled,
+}
+
+/// Outcome of starting a service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceOutcome {
+    /// The service started after the provided number of attempts, at the provided time since
+    /// the start of the graph.
+    Started { at: time::Duration, attempts: u32 },
+    /// Every attempt to start the service failed, the last with the provided error.
+    Failed { attempts: u32, error: String },
+    /// The service was not started as the provided dependency failed to start.
+    Blocked { dependency: String },
+}
+
+/// Error returned when a [`StartupGraph`] is malformed.
+///
+/// [`StartupGraph`]:`StartupGraph`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StartupError {
+    UnknownDependency { service: String, dependency: String },
+    Cycle { services: Vec<String> },
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::UnknownDependency {
+                service,
+                dependency,
+            } => write!(f, "{} depends on unknown service {}", service, dependency),
+            StartupError::Cycle { services } => {
+                write!(f, "dependency cycle between {}", services.join(", "))
+            }
+        }
+    }
+}
+
+impl error::Error for StartupError {}
+
+struct Service {
+    name: String,
+    dependencies: Vec<String>,
+    start: Start,
+}
+
+/// Builder declaring services and their startup dependencies.
+pub struct StartupGraph {
+    services: Vec<Service>,
+    order: StartupOrder,
+    max_delay: time::Duration,
+    failure_probability: f64,
+    max_attempts: u32,
+    retry_delay: time::Duration,
+}
+
+impl fmt::Debug for StartupGraph {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let services: Vec<(&str, &[String])> = self
+            .services
+            .iter()
+            .map(|s| (s.name.as_str(), s.dependencies.as_slice()))
+            .collect();
+        f.debug_struct("StartupGraph")
+            .field("services", &services)
+            .field("order", &self.order)
+            .field("max_delay", &self.max_delay)
+            .field("failure_probability", &self.failure_probability)
+            .field("max_attempts", &self.max_attempts)
+            .field("retry_delay", &self.retry_delay)
+            .finish()
+    }
+}
+
+impl Default for StartupGraph {
+    fn default() -> Self {
+        Self {
+            services: vec![],
+            order: StartupOrder::Dependencies,
+            max_delay: time::Duration::from_secs(0),
+            failure_probability: 0.0,
+            max_attempts: 3,
+            retry_delay: time::Duration::from_secs(1),
+        }
+    }
+}
+
+impl StartupGraph {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Declares a service which starts once each of `dependencies` has started. `start` is
+    /// invoked with a handle scoped to the host of the service, and the service has started
+    /// once the returned future completes successfully.
+    pub fn service<F, U>(mut self, name: impl Into<String>, dependencies: &[&str], start: F) -> Self
+    where
+        F: FnOnce(DeterministicRuntimeHandle) -> U + 'static,
+        U: Future<Output = Result<(), String>> + 'static,
+    {
+        self.services.push(Service {
+            name: name.into(),
+            dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+            start: Box::new(move |env| {
+                Box::pin(start(env)) as Pin<Box<dyn Future<Output = Result<(), String>>>>
+            }),
+        });
+        self
+    }
+
+    pub fn order(mut self, order: StartupOrder) -> Self {
+        self.order = order;
+        self
+    }
+
+    /// Delays each attempt to start a service by a seeded duration of up to `max_delay`.
+    pub fn startup_delay(mut self, max_delay: time::Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Fails each attempt to start a service with the provided probability, before the service
+    /// is started. Failed attempts are retried after the retry delay, up to the maximum number
+    /// of attempts.
+    pub fn failure_probability(mut self, probability: f64) -> Self {
+        assert!(
+            probability >= 0.0 && probability <= 1.0,
+            "failure probability must be within [0, 1]"
+        );
+        self.failure_probability = probability;
+        self
+    }
+
+    /// Sets the number of attempts made to start each service. Defaults to 3.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        assert!(attempts > 0, "services must be attempted at least once");
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Sets the delay between attempts to start a service. Defaults to 1 second.
+    pub fn retry_delay(mut self, delay: time::Duration) -> Self {
+        self.retry_delay = delay;
+        self
+    }
+
+    /// Checks that every dependency is declared and that dependencies are acyclic.
+    fn validate(&self) -> Result<(), StartupError> {
+        let names: collections::HashSet<&str> =
+            self.services.iter().map(|s| s.name.as_str()).collect();
+        for service in &self.services {
+            for dependency in &service.dependencies {
+                if !names.contains(dependency.as_str()) {
+                    return Err(StartupError::UnknownDependency {
+                        service: service.name.clone(),
+                        dependency: dependency.clone(),
+                    });
+                }
+            }
+        }
+        // Repeatedly remove services whose dependencies have all been removed. Any services
+        // which remain form a cycle.
+        let mut remaining: Vec<&Service> = self.services.iter().collect();
+        let mut started = collections::HashSet::new();
+        loop {
+            let (ready, blocked): (Vec<&Service>, Vec<&Service>) = remaining
+                .into_iter()
+                .partition(|s| s.dependencies.iter().all(|d| started.contains(d.as_str())));
+            if ready.is_empty() {
+                remaining = blocked;
+                break;
+            }
+            started.extend(ready.iter().map(|s| s.name.as_str()));
+            remaining = blocked;
+        }
+        if remaining.is_empty() {
+            Ok(())
+        } else {
+            Err(StartupError::Cycle {
+                services: remaining.iter().map(|s| s.name.clone()).collect(),
+            })
+        }
+    }
+
+    /// Starts every service, each on a newly added host, returning once every service has
+    /// started or failed.
+    pub async fn start(
+        self,
+        handle: &DeterministicRuntimeHandle,
+    ) -> Result<StartupReport, StartupError> {
+        self.validate()?;
+        let StartupGraph {
+            services,
+            order,
+            max_delay,
+            failure_probability,
+            max_attempts,
+            retry_delay,
+        } = self;
+        let random = handle.random_handle();
+        let started = handle.now();
+
+        // Each service notifies its dependents through a channel per dependency edge, sending
+        // `None` once started and the name of the failed service otherwise.
+        let mut senders: collections::HashMap<String, Vec<oneshot::Sender<Option<String>>>> =
+            collections::HashMap::new();
+        let mut receivers = collections::HashMap::new();
+        for service in &services {
+            if order == StartupOrder::Shuffled {
+                continue;
+            }
+            for dependency in &service.dependencies {
+                let (tx, rx) = oneshot::channel();
+                senders.entry(dependency.clone()).or_default().push(tx);
+                receivers
+                    .entry(service.name.clone())
+                    .or_insert_with(Vec::new)
+                    .push(rx);
+            }
+        }
+
+        let mut tasks = vec![];
+        for service in services {
+            let host = handle.add_host();
+            handle
+                .network_handle
+                .name_host(service.name.clone(), host.local_addr());
+            let dependents = senders.remove(&service.name).unwrap_or_default();
+            let dependencies = receivers.remove(&service.name).unwrap_or_default();
+            let random = random.clone();
+            tasks.push(async move {
+                let name = service.name;
+                let mut blocked = None;
+                for dependency in dependencies {
+                    if let Ok(Some(failed)) = dependency.await {
+                        blocked.get_or_insert(failed);
+                    }
+                }
+                let outcome = match blocked {
+                    Some(dependency) => ServiceOutcome::Blocked { dependency },
+                    None => {
+                        let mut attempts = 0;
+                        loop {
+                            attempts += 1;
+                            if max_delay > time::Duration::from_secs(0) {
+                                let delay =
+                                    random.gen_range(time::Duration::from_secs(0)..max_delay);
+                                host.delay_from(delay).await;
+                            }
+                            if failure_probability > 0.0 && random.should_fault(failure_probability)
+                            {
+                                trace!("injected startup failure of {}", name);
+                                if attempts >= max_attempts {
+                                    break ServiceOutcome::Failed {
+                                        attempts,
+                                        error: String::from("injected startup failure"),
+                                    };
+                                }
+                                host.delay_from(retry_delay).await;
+                                continue;
+                            }
+                            break match (service.start)(host.clone()).await {
+                                Ok(()) => ServiceOutcome::Started {
+                                    at: host.now() - started,
+                                    attempts,
+                                },
+                                Err(error) => ServiceOutcome::Failed { attempts, error },
+                            };
+                        }
+                    }
+                };
+                trace!("startup of {}: {:?}", name, outcome);
+                let failed = match &outcome {
+                    ServiceOutcome::Started { .. } => None,
+                    ServiceOutcome::Failed { .. } => Some(name.clone()),
+                    ServiceOutcome::Blocked { dependency } => Some(dependency.clone()),
+                };
+                for dependent in dependents {
+                    let _ = dependent.send(failed.clone());
+                }
+                (name, outcome)
+            });
+        }
+        let outcomes = future::join_all(tasks).await;
+        Ok(StartupReport { outcomes })
+    }
+}
+
+/// Outcome of starting each service of a [`StartupGraph`].
+///
+/// [`StartupGraph`]:`StartupGraph`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StartupReport {
+    outcomes: Vec<(String, ServiceOutcome)>,
+}
+
+impl StartupReport {
+    pub fn outcome(&self, service: &str) -> Option<&ServiceOutcome> {
+        self.outcomes
+            .iter()
+            .find(|(name, _)| name == service)
+            .map(|(_, outcome)| outcome)
+    }
+
+    /// Returns the services which started, in the order they started.
+    pub fn start_order(&self) -> Vec<&str> {
+        let mut started: Vec<(time::Duration, &str)> = self
+            .outcomes
+            .iter()
+            .filter_map(|(name, outcome)| match outcome {
+                ServiceOutcome::Started { at, .. } => Some((*at, name.as_str())),
+                _ => None,
+            })
+            .collect();
+        started.sort();
+        started.into_iter().map(|(_, name)| name).collect()
+    }
+
+    /// Returns true if every service started.
+    pub fn all_started(&self) -> bool {
+        self.outcomes.iter().all(|(_, outcome)| match outcome {
+            ServiceOutcome::Started { .. } => true,
+            _ => false,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    fn graph() -> StartupGraph {
+        let start = |_: DeterministicRuntimeHandle| async { Ok::<(), String>(()) };
+        StartupGraph::new()
+            .service("cache", &["db"], start)
+            .service("api", &["cache", "db"], start)
+            .service("db", &[], start)
+            .startup_delay(time::Duration::from_secs(10))
+    }
+
+    #[test]
+    /// Test that services start after their dependencies, and are registered under their names.
+    fn dependency_order() {
+        let mut runtime = DeterministicRuntime::new_with_seed(3).unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let report = graph().start(&handle).await.unwrap();
+            assert!(report.all_started());
+            assert_eq!(report.start_order(), vec!["db", "cache", "api"]);
+            assert!(handle.resolve_host("cache").is_some());
+        });
+    }
+
+    #[test]
+    /// Test that failures block dependents, and that shuffled startup ignores dependencies.
+    fn failures_and_shuffling() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let report = graph()
+                .failure_probability(1.0)
+                .max_attempts(2)
+                .start(&handle)
+                .await
+                .unwrap();
+            assert_eq!(
+                report.outcome("db"),
+                Some(&ServiceOutcome::Failed {
+                    attempts: 2,
+                    error: String::from("injected startup failure")
+                })
+            );
+            assert_eq!(
+                report.outcome("api"),
+                Some(&ServiceOutcome::Blocked {
+                    dependency: String::from("db")
+                })
+            );
+
+            let orders = (0..8).map(|_| async {
+                let report = graph()
+                    .order(StartupOrder::Shuffled)
+                    .start(&handle)
+                    .await
+                    .unwrap();
+                report
+                    .start_order()
+                    .into_iter()
+                    .map(String::from)
+                    .collect::<Vec<_>>()
+            });
+            let orders: collections::HashSet<Vec<String>> =
+                future::join_all(orders).await.into_iter().collect();
+            assert!(orders.len() > 1, "expected shuffled startup orders to vary");
+        });
+    }
+
+    #[test]
+    /// Test that cycles and unknown dependencies are rejected.
+    fn malformed() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let start = |_: DeterministicRuntimeHandle| async { Ok::<(), String>(()) };
+            let err = StartupGraph::new()
+                .service("a", &["b"], start)
+                .service("b", &["a"], start)
+                .start(&handle)
+                .await
+                .unwrap_err();
+            match err {
+                StartupError::Cycle { services } => assert_eq!(services, vec!["a", "b"]),
+                err => panic!("expected a cycle, got {}", err),
+            }
+            let err = StartupGraph::new()
+                .service("a", &["missing"], start)
+                .start(&handle)
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err,
+                StartupError::UnknownDependency {
+                    service: String::from("a"),
+                    dependency: String::from("missing"),
+                }
+            );
+        });
+    }
+}
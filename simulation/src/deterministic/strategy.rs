@@ -0,0 +1,369 @@
+//! `proptest` strategies generating topologies and fault schedules.
+//!
+//! A failing schedule of hundreds of faults is hard to act on, and minimizing it by hand is
+//! slow. Generating schedules with the strategies of this module instead lets `proptest` shrink
+//! a failing schedule automatically, dropping faults, moving them to the start of the run and
+//! shortening partitions and latency spikes until only the faults needed to reproduce the
+//! failure remain. [`topology`] generates a number of hosts, [`FaultStrategy`] configures the
+//! faults generated against them with [`FaultStrategy::schedule`], and
+//! [`topology_with_schedule`] generates both. Requires the `proptest` feature.
+//!
+//! [`topology`]:`topology`
+//! [`FaultStrategy`]:`FaultStrategy`
+//! [`FaultStrategy::schedule`]:`FaultStrategy::schedule`
+//! [`topology_with_schedule`]:`topology_with_schedule`
+use super::{
+    DeterministicRuntime, DeterministicRuntimeHandle, FaultAction, FaultSchedule, PartitionMode,
+    ScheduledFault,
+};
+use proptest::strategy::{BoxedStrategy, Just, Strategy, Union};
+use std::{net, ops, time};
+
+/// Hosts generated by [`topology`], with addresses starting at `10.0.0.1`.
+///
+/// [`topology`]:`topology`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topology {
+    hosts: Vec<net::IpAddr>,
+}
+
+impl Topology {
+    pub fn new(hosts: usize) -> Self {
+        let first = u32::from(net::Ipv4Addr::new(10, 0, 0, 1));
+        Self {
+            hosts: (0..hosts as u32)
+                .map(|n| net::IpAddr::V4(net::Ipv4Addr::from(first + n)))
+                .collect(),
+        }
+    }
+
+    /// Returns the addresses of the hosts.
+    pub fn hosts(&self) -> &[net::IpAddr] {
+        &self.hosts
+    }
+
+    /// Registers the hosts with the runtime, returning a handle scoped to each.
+    pub fn handles(&self, runtime: &DeterministicRuntime) -> Vec<DeterministicRuntimeHandle> {
+        self.hosts
+            .iter()
+            .map(|host| runtime.handle(*host))
+            .collect()
+    }
+}
+
+/// Returns a strategy generating topologies with a number of hosts in the provided range,
+/// shrinking towards fewer hosts.
+pub fn topology(hosts: ops::RangeInclusive<usize>) -> impl Strategy<Value = Topology> {
+    hosts.prop_map(Topology::new)
+}
+
+/// A fault before it is resolved against the hosts of a topology. Offsets and durations are in
+/// milliseconds, so that they shrink towards zero.
+#[derive(Debug, Clone)]
+enum GeneratedFault {
+    /// Partitions the hosts selected by `split` from the others.
+    Partition {
+        at: u64,
+        duration: u64,
+        split: Vec<bool>,
+        mode: PartitionMode,
+    },
+    ResetHost {
+        at: u64,
+        host: usize,
+    },
+    /// Slows the writes of a host for `duration`.
+    SendLatency {
+        at: u64,
+        duration: u64,
+        host: usize,
+        latency: u64,
+    },
+}
+
+impl GeneratedFault {
+    fn resolve(self, hosts: &[net::IpAddr], faults: &mut Vec<ScheduledFault>) {
+        let millis = time::Duration::from_millis;
+        match self {
+            GeneratedFault::Partition {
+                at,
+                duration,
+                split,
+                mode,
+            } => {
+                let (mut a, mut b): (Vec<_>, Vec<_>) =
+                    hosts.iter().zip(split).partition(|(_, side)| *side);
+                if a.is_empty() {
+                    a.push(b.remove(0));
+                } else if b.is_empty() {
+                    b.push(a.remove(0));
+                }
+                let a: Vec<_> = a.into_iter().map(|(host, _)| *host).collect();
+                let b: Vec<_> = b.into_iter().map(|(host, _)| *host).collect();
+                faults.push(ScheduledFault::new(
+                    millis(at),
+                    FaultAction::Partition {
+                        a: a.clone(),
+                        b: b.clone(),
+                        mode,
+                    },
+                ));
+                faults.push(ScheduledFault::new(
+                    millis(at + duration),
+                    FaultAction::Heal { a, b },
+                ));
+            }
+            GeneratedFault::ResetHost { at, host } => {
+                let host = hosts[host];
+                faults.push(ScheduledFault::new(
+                    millis(at),
+                    FaultAction::ResetHost { host },
+                ));
+            }
+            GeneratedFault::SendLatency {
+                at,
+                duration,
+                host,
+                latency,
+            } => {
+                let host = hosts[host];
+                let action = |latency| FaultAction::SendLatency { host, latency };
+                faults.push(ScheduledFault::new(millis(at), action(millis(latency))));
+                faults.push(ScheduledFault::new(
+                    millis(at + duration),
+                    action(millis(0)),
+                ));
+            }
+        }
+    }
+}
+
+/// Configures the fault schedules generated by [`FaultStrategy::schedule`]. By default up to
+/// 16 partitions, host resets and latency spikes are generated within the first minute of the
+/// run, each partition and spike lasting up to 10 seconds.
+///
+/// [`FaultStrategy::schedule`]:`FaultStrategy::schedule`
+#[derive(Debug, Clone)]
+pub struct FaultStrategy {
+    max_faults: usize,
+    horizon: time::Duration,
+    max_duration: time::Duration,
+    partitions: bool,
+    resets: bool,
+    max_latency: Option<time::Duration>,
+}
+
+impl Default for FaultStrategy {
+    fn default() -> Self {
+        Self {
+            max_faults: 16,
+            horizon: time::Duration::from_secs(60),
+            max_duration: time::Duration::from_secs(10),
+            partitions: true,
+            resets: true,
+            max_latency: Some(time::Duration::from_secs(1)),
+        }
+    }
+}
+
+impl FaultStrategy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the maximum number of faults in a schedule. Partitions and latency spikes count as
+    /// a single fault, although they are applied and lifted by separate actions.
+    pub fn max_faults(mut self, max_faults: usize) -> Self {
+        self.max_faults = max_faults;
+        self
+    }
+
+    /// Sets the offset from the start of the run before which every fault is applied.
+    pub fn horizon(mut self, horizon: time::Duration) -> Self {
+        self.horizon = horizon;
+        self
+    }
+
+    /// Sets the maximum duration of partitions and latency spikes.
+    pub fn max_duration(mut self, max_duration: time::Duration) -> Self {
+        self.max_duration = max_duration;
+        self
+    }
+
+    /// Sets whether partitions are generated. Partitions require at least two hosts.
+    pub fn partitions(mut self, enabled: bool) -> Self {
+        self.partitions = enabled;
+        self
+    }
+
+    /// Sets whether host resets are generated.
+    pub fn resets(mut self, enabled: bool) -> Self {
+        self.resets = enabled;
+        self
+    }
+
+    /// Sets the maximum latency of latency spikes, or disables them with `None`.
+    pub fn max_latency(mut self, max_latency: Option<time::Duration>) -> Self {
+        self.max_latency = max_latency;
+        self
+    }
+
+    /// Returns a strategy generating schedules of faults against the provided hosts. Failing
+    /// schedules shrink towards fewer faults, applied earlier and lasting less time.
+    pub fn schedule(&self, hosts: &[net::IpAddr]) -> BoxedStrategy<FaultSchedule> {
+        assert!(
+            !hosts.is_empty(),
+            "fault schedules require at least one host"
+        );
+        let horizon = self.horizon.as_millis() as u64 + 1;
+        let duration = self.max_duration.as_millis() as u64 + 1;
+        let mut kinds: Vec<BoxedStrategy<GeneratedFault>> = vec![];
+        if self.partitions && hosts.len() > 1 {
+            let split = proptest::collection::vec(proptest::bool::ANY, hosts.len());
+            let mode = Union::new(vec![Just(PartitionMode::Stall), Just(PartitionMode::Reset)]);
+            kinds.push(
+                (0..horizon, 0..duration, split, mode)
+                    .prop_map(|(at, duration, split, mode)| GeneratedFault::Partition {
+                        at,
+                        duration,
+                        split,
+                        mode,
+                    })
+                    .boxed(),
+            );
+        }
+        if self.resets {
+            kinds.push(
+                (0..horizon, 0..hosts.len())
+                    .prop_map(|(at, host)| GeneratedFault::ResetHost { at, host })
+                    .boxed(),
+            );
+        }
+        if let Some(max_latency) = self.max_latency {
+            let latency = max_latency.as_millis() as u64 + 1;
+            kinds.push(
+                (0..horizon, 0..duration, 0..hosts.len(), 0..latency)
+                    .prop_map(
+                        |(at, duration, host, latency)| GeneratedFault::SendLatency {
+                            at,
+                            duration,
+                            host,
+                            latency,
+                        },
+                    )
+                    .boxed(),
+            );
+        }
+        assert!(!kinds.is_empty(), "every kind of fault is disabled");
+        let hosts = hosts.to_vec();
+        proptest::collection::vec(Union::new(kinds), 0..=self.max_faults)
+            .prop_map(move |generated| {
+                let mut faults = vec![];
+                for fault in generated {
+                    fault.resolve(&hosts, &mut faults);
+                }
+                FaultSchedule::from(faults)
+            })
+            .boxed()
+    }
+}
+
+/// Returns a strategy generating a topology with a number of hosts in the provided range, along
+/// with a schedule of faults against its hosts.
+pub fn topology_with_schedule(
+    hosts: ops::RangeInclusive<usize>,
+    faults: FaultStrategy,
+) -> impl Strategy<Value = (Topology, FaultSchedule)> {
+    topology(hosts).prop_flat_map(move |topology| {
+        let schedule = faults.schedule(topology.hosts());
+        (Just(topology), schedule)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Environment, TcpListener};
+    use proptest::strategy::ValueTree;
+    use proptest::test_runner::{Config, TestCaseError, TestError, TestRunner};
+    use tokio::io::AsyncReadExt;
+
+    fn runner() -> TestRunner {
+        TestRunner::new(Config {
+            failure_persistence: None,
+            ..Config::default()
+        })
+    }
+
+    #[test]
+    /// Test that a failing schedule shrinks to the single fault causing the failure, lasting no
+    /// longer than needed to reproduce it.
+    fn shrink() {
+        let topology = Topology::new(3);
+        let strategy = FaultStrategy::new().schedule(topology.hosts());
+        let result = runner().run(&strategy, |schedule| {
+            let late = schedule.iter().any(|fault| match fault.action() {
+                FaultAction::Heal { .. } => fault.at() >= time::Duration::from_secs(2),
+                _ => false,
+            });
+            if late {
+                return Err(TestCaseError::fail("partition healed late"));
+            }
+            Ok(())
+        });
+        let schedule = match result {
+            Err(TestError::Fail(_, schedule)) => schedule,
+            result => panic!("expected a failing schedule, found {:?}", result),
+        };
+        let faults: Vec<_> = schedule.iter().cloned().collect();
+        assert_eq!(faults.len(), 2, "{:?}", faults);
+        assert_eq!(faults[1].at(), time::Duration::from_secs(2));
+        match faults[0].action() {
+            FaultAction::Partition { a, b, mode } => {
+                assert_eq!(a, &topology.hosts()[..1]);
+                assert_eq!(b, &topology.hosts()[1..]);
+                assert_eq!(*mode, PartitionMode::Stall);
+            }
+            action => panic!("expected a partition, found {:?}", action),
+        }
+    }
+
+    #[test]
+    /// Test that generated topologies can be registered with a runtime and subjected to their
+    /// generated schedules.
+    fn apply() {
+        let faults = FaultStrategy::new()
+            .partitions(false)
+            .max_latency(None)
+            .max_faults(1);
+        let strategy = topology_with_schedule(2..=2, faults);
+        let mut runner = runner();
+        let (topology, schedule) = loop {
+            let (topology, schedule) = strategy.new_tree(&mut runner).unwrap().current();
+            if schedule.len() == 1 {
+                break (topology, schedule);
+            }
+        };
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handles = topology.handles(&runtime);
+        let (client, server) = (handles[0].clone(), handles[1].clone());
+        let reset = schedule.iter().next().unwrap().at();
+        let injector = runtime.fault_schedule_injector(schedule);
+        runtime.block_on(async move {
+            let addr = net::SocketAddr::new(server.local_addr(), 80);
+            let mut listener = server.bind(addr).await.unwrap();
+            server.spawn(async move {
+                let mut accepted = vec![];
+                while let Ok((socket, _)) = listener.accept().await {
+                    accepted.push(socket);
+                }
+            });
+            let mut socket = client.connect(addr).await.unwrap();
+            let start = client.now();
+            client.spawn(injector.run());
+            let err = socket.read(&mut [0u8; 1]).await.unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+            assert_eq!(client.now() - start, reset);
+        });
+    }
+}
@@ -0,0 +1,916 @@
+//! Task instrumentation for the deterministic runtime.
+//!
+//! Futures spawned on the deterministic runtime are wrapped with [`Instrumented`], which records
+//! the currently polled task in a thread local. Simulated resources use this to record which
+//! resource a parked task is waiting on, and which task last made progress on the resource,
+//! allowing a wait-for graph to be built for deadlock and bottleneck analysis.
+//!
+//! Each task is also profiled in virtual time, accumulating the time spent runnable but not
+//! polled and the time spent waiting to be woken by timers or by other tasks.
+//!
+//! The underlying executor polls runnable tasks in the order they were woken, exploring a single
+//! interleaving per test. When shuffling is enabled, a task which is about to be polled while
+//! other tasks are runnable yields at random instead, moving it behind them in the run queue, so
+//! each seed explores a different interleaving.
+//!
+//! [`Instrumented`]:`Instrumented`
+use super::blocking::BlockingDetector;
+use super::cpu::Cpus;
+use super::invariant::Invariants;
+use super::nondeterminism::NondeterminismDetector;
+use super::priority::{Priorities, PriorityPolicy};
+use super::watchdog::Watchdog;
+use super::{DecisionPoint, DeterministicRandomHandle, DeterministicTimeHandle};
+use futures::{
+    task::{ArcWake, Waker},
+    Future, Poll,
+};
+use std::{cell, collections, fmt, fmt::Write, net, pin::Pin, sync, task::Context, time};
+
+thread_local! {
+    static CURRENT: cell::RefCell<Option<(TaskId, Tasks)>> = cell::RefCell::new(None);
+    /// Set while the timer is firing expired timers.
+    static FIRING_TIMERS: cell::Cell<bool> = cell::Cell::new(false);
+}
+
+/// Identifies a task spawned on the deterministic runtime.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TaskId(pub(crate) u64);
+
+impl fmt::Display for TaskId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "task#{}", self.0)
+    }
+}
+
+/// A simulated resource which a task can wait on.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum WaitResource {
+    /// Bytes sent from one address to another.
+    Read {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+    },
+    /// Capacity to send bytes from one address to another.
+    Write {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+    },
+    /// A new connection to a listener.
+    Accept(net::SocketAddr),
+    /// A listener accepting a new connection.
+    Listener(net::SocketAddr),
+    /// Messages on the named intra-host channel.
+    Channel(String),
+}
+
+impl fmt::Display for WaitResource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WaitResource::Read { from, to } => write!(f, "read {} -> {}", from, to),
+            WaitResource::Write { from, to } => write!(f, "write {} -> {}", from, to),
+            WaitResource::Accept(addr) => write!(f, "accept {}", addr),
+            WaitResource::Listener(addr) => write!(f, "listener {}", addr),
+            WaitResource::Channel(name) => write!(f, "channel {}", name),
+        }
+    }
+}
+
+/// Virtual time profile of a task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskProfile {
+    id: TaskId,
+    name: Option<String>,
+    polls: u64,
+    runnable: time::Duration,
+    timer_wait: time::Duration,
+    io_wait: time::Duration,
+}
+
+impl TaskProfile {
+    fn new(id: TaskId, name: Option<String>) -> Self {
+        Self {
+            id,
+            name,
+            polls: 0,
+            runnable: time::Duration::from_secs(0),
+            timer_wait: time::Duration::from_secs(0),
+            io_wait: time::Duration::from_secs(0),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_ref().map(String::as_str)
+    }
+
+    /// Returns the number of times the task was polled.
+    pub fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    /// Returns the time the task spent runnable, but not yet polled.
+    pub fn runnable(&self) -> time::Duration {
+        self.runnable
+    }
+
+    /// Returns the time the task spent waiting to be woken by a timer.
+    pub fn timer_wait(&self) -> time::Duration {
+        self.timer_wait
+    }
+
+    /// Returns the time the task spent waiting to be woken by other tasks, such as on
+    /// simulated IO.
+    pub fn io_wait(&self) -> time::Duration {
+        self.io_wait
+    }
+}
+
+/// Virtual time profiles of every task spawned on a runtime.
+#[derive(Debug, Clone)]
+pub struct ProfileReport {
+    tasks: Vec<TaskProfile>,
+}
+
+impl ProfileReport {
+    pub fn tasks(&self) -> &[TaskProfile] {
+        &self.tasks
+    }
+
+    /// Returns the profiles of all tasks with the provided name.
+    pub fn named<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a TaskProfile> + 'a {
+        self.tasks
+            .iter()
+            .filter(move |profile| profile.name() == Some(name))
+    }
+}
+
+impl fmt::Display for ProfileReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>8} {:>14} {:>14} {:>14}",
+            "task", "polls", "runnable", "timer wait", "io wait"
+        )?;
+        for profile in self.tasks.iter() {
+            let task = match profile.name() {
+                Some(name) => format!("{} {}", profile.id, name),
+                None => profile.id.to_string(),
+            };
+            writeln!(
+                f,
+                "{:<24} {:>8} {:>14?} {:>14?} {:>14?}",
+                task, profile.polls, profile.runnable, profile.timer_wait, profile.io_wait
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TaskState {
+    name: Option<String>,
+    /// Priority class the task was spawned into.
+    class: Option<String>,
+    /// Host the task was spawned on, if it was spawned through a handle.
+    host: Option<net::IpAddr>,
+    waiting_on: Option<WaitResource>,
+    profile: TaskProfile,
+    /// Time at which the task was woken, if it is runnable.
+    woken_at: Option<time::Instant>,
+    /// Time at which the task returned `Pending`, if it is waiting to be woken.
+    pending_since: Option<time::Instant>,
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    next_id: u64,
+    tasks: collections::BTreeMap<TaskId, TaskState>,
+    /// Profiles of tasks which have completed.
+    completed: Vec<TaskProfile>,
+    /// The task which last made progress on each resource, and is expected to wake tasks
+    /// waiting on it.
+    holders: collections::HashMap<WaitResource, TaskId>,
+    priorities: Priorities,
+    /// Source of the yields which shuffle the polling order, if shuffling is enabled.
+    shuffle: Option<DeterministicRandomHandle>,
+    /// Number of consecutive times each task has yielded to shuffle the polling order.
+    shuffled: collections::HashMap<TaskId, u32>,
+}
+
+/// Maximum number of consecutive shuffling yields of a task, bounding the polls spent yielding
+/// before a runnable task makes progress.
+const MAX_SHUFFLE_YIELDS: u32 = 4;
+
+/// Registry of the tasks spawned on a deterministic runtime.
+#[derive(Debug, Clone)]
+pub(crate) struct Tasks {
+    inner: sync::Arc<sync::Mutex<Registry>>,
+    time_handle: DeterministicTimeHandle,
+    cpus: Cpus,
+    blocking: BlockingDetector,
+    nondeterminism: NondeterminismDetector,
+    watchdog: Watchdog,
+    invariants: Invariants,
+}
+
+impl Tasks {
+    pub(crate) fn new(time_handle: DeterministicTimeHandle, cpus: Cpus) -> Self {
+        Self {
+            inner: sync::Arc::default(),
+            time_handle,
+            cpus,
+            blocking: BlockingDetector::default(),
+            nondeterminism: NondeterminismDetector::default(),
+            watchdog: Watchdog::default(),
+            invariants: Invariants::default(),
+        }
+    }
+
+    pub(crate) fn blocking(&self) -> &BlockingDetector {
+        &self.blocking
+    }
+
+    pub(crate) fn nondeterminism(&self) -> &NondeterminismDetector {
+        &self.nondeterminism
+    }
+
+    pub(crate) fn watchdog(&self) -> &Watchdog {
+        &self.watchdog
+    }
+
+    /// Fails the run if it has exceeded the bounds of the watchdog, reporting the profile of
+    /// every task.
+    fn check_watchdog(&self, id: TaskId) {
+        if let Some(exceeded) = self.watchdog.check(self.time_handle.now()) {
+            panic!("{} while polling {}\n{}", exceeded, id, self.profile());
+        }
+    }
+
+    pub(crate) fn invariants(&self) -> &Invariants {
+        &self.invariants
+    }
+
+    /// Fails the run if an invariant due after the poll of the provided task is violated.
+    fn check_invariants(&self, id: TaskId) {
+        let quiescent = !self.any_runnable();
+        let now = self.time_handle.now();
+        if let Some(violation) = self.invariants.check(id, now, quiescent) {
+            panic!("{}", violation);
+        }
+    }
+
+    /// Returns the name of the provided task and the number of times it has been polled.
+    fn poll_count(&self, id: TaskId) -> (Option<String>, u64) {
+        let lock = self.inner.lock().unwrap();
+        match lock.tasks.get(&id) {
+            Some(task) => (task.name.clone(), task.profile.polls),
+            None => (None, 0),
+        }
+    }
+
+    /// Register a new task, wrapping the provided future so that it is tracked while polled.
+    pub(crate) fn instrument<F>(&self, name: Option<String>, future: F) -> Instrumented<F> {
+        let now = self.time_handle.now();
+        let mut lock = self.inner.lock().unwrap();
+        let id = TaskId(lock.next_id);
+        lock.next_id += 1;
+        lock.tasks.insert(
+            id,
+            TaskState {
+                profile: TaskProfile::new(id, name.clone()),
+                name: name.clone(),
+                class: None,
+                host: None,
+                waiting_on: None,
+                woken_at: Some(now),
+                pending_since: None,
+            },
+        );
+        drop(lock);
+        let observers = self.time_handle.events().observers();
+        observers.notify(|observer, at| {
+            observer.task_spawned(at, id, name.as_ref().map(String::as_str))
+        });
+        Instrumented {
+            id,
+            tasks: self.clone(),
+            core: None,
+            waker: sync::Arc::new(TaskWaker {
+                id,
+                tasks: self.clone(),
+                waker: sync::Mutex::new(None),
+            }),
+            future: Box::pin(future),
+        }
+    }
+
+    /// Records the host a task was spawned on.
+    pub(crate) fn set_host(&self, id: TaskId, host: net::IpAddr) {
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            task.host.replace(host);
+        }
+    }
+
+    /// Returns the name and host of a live task.
+    fn identity(&self, id: TaskId) -> Option<(Option<String>, Option<net::IpAddr>)> {
+        let lock = self.inner.lock().unwrap();
+        let task = lock.tasks.get(&id)?;
+        Some((task.name.clone(), task.host))
+    }
+
+    /// Assigns a task to a priority class.
+    pub(crate) fn set_class(&self, id: TaskId, class: String) {
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            task.class.replace(class);
+        }
+    }
+
+    pub(crate) fn set_priority_class(&self, class: String, priority: i32) {
+        self.inner
+            .lock()
+            .unwrap()
+            .priorities
+            .set_class(class, priority);
+    }
+
+    pub(crate) fn set_priority_policy(&self, policy: PriorityPolicy) {
+        self.inner.lock().unwrap().priorities.set_policy(policy);
+    }
+
+    /// Enables shuffling of the polling order, drawing from the provided source of randomness,
+    /// or disables it.
+    pub(crate) fn set_shuffle(&self, random: Option<DeterministicRandomHandle>) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.shuffle = random;
+        lock.shuffled.clear();
+    }
+
+    /// Chooses which of `waiters` waiting on a synchronization primitive to wake, drawing from
+    /// the source of randomness shuffling the polling order if shuffling is enabled.
+    fn choose_waiter(&self, waiters: usize) -> usize {
+        match self.inner.lock().unwrap().shuffle.as_ref() {
+            Some(random) => random.gen_range(0..waiters),
+            None => 0,
+        }
+    }
+
+    /// Returns true if the task should yield instead of being polled, either to a runnable task
+    /// of a higher priority or to shuffle the polling order.
+    fn should_defer(&self, id: TaskId) -> bool {
+        let mut lock = self.inner.lock().unwrap();
+        let registry = &mut *lock;
+        let priorities = &mut registry.priorities;
+        let priority = match registry.tasks.get(&id) {
+            Some(task) => priorities.priority(task.class.as_ref().map(String::as_str)),
+            None => return false,
+        };
+        let runnable = registry
+            .tasks
+            .iter()
+            .filter(|(other, task)| **other != id && task.woken_at.is_some())
+            .map(|(_, task)| task.class.as_ref().map(String::as_str));
+        let runnable: Vec<i32> = runnable.map(|class| priorities.priority(class)).collect();
+        let others_runnable = !runnable.is_empty();
+        if priorities.should_defer(id, priority, runnable.into_iter()) {
+            return true;
+        }
+        let random = match registry.shuffle.as_ref() {
+            Some(random) if others_runnable => random,
+            _ => return false,
+        };
+        let yields = registry.shuffled.entry(id).or_insert(0);
+        let yielded = if *yields < MAX_SHUFFLE_YIELDS && random.should_fault(0.5) {
+            *yields += 1;
+            true
+        } else {
+            *yields = 0;
+            false
+        };
+        let task = registry.tasks.get(&id).and_then(|task| task.name.clone());
+        drop(lock);
+        let observers = self.time_handle.events().observers();
+        observers.decision(DecisionPoint::Yield { task, yielded });
+        yielded
+    }
+
+    /// Charges the cost of a poll to the host of the task, returning the time at which the
+    /// poll can run if the task has to wait for a core.
+    fn charge_poll(&self, id: TaskId) -> Option<time::Instant> {
+        let host = self.inner.lock().unwrap().tasks.get(&id)?.host?;
+        self.cpus.charge_poll(host, self.time_handle.now())
+    }
+
+    /// Record that a woken task is waiting for a core to run its poll. The task is not
+    /// runnable until the poll can run, so that it does not hold back the tasks it would
+    /// otherwise take priority over.
+    fn wait_for_core(&self, id: TaskId) {
+        let now = self.time_handle.now();
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            if let Some(woken_at) = task.woken_at.take() {
+                task.profile.runnable += now - woken_at;
+                task.pending_since.replace(now);
+            }
+        }
+    }
+
+    /// Record the start of a poll, accumulating the time the task spent runnable.
+    fn poll_started(&self, id: TaskId) {
+        let now = self.time_handle.now();
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            task.waiting_on = None;
+            task.profile.polls += 1;
+            if let Some(woken_at) = task.woken_at.take() {
+                task.profile.runnable += now - woken_at;
+            }
+        }
+    }
+
+    /// Record that a poll returned `Pending`. Tasks which were woken during the poll remain
+    /// runnable.
+    fn poll_pending(&self, id: TaskId) {
+        let now = self.time_handle.now();
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            if task.woken_at.is_none() {
+                task.pending_since.replace(now);
+            }
+        }
+    }
+
+    /// Record a wake, accumulating the time the task spent waiting.
+    fn woken(&self, id: TaskId) {
+        let now = self.time_handle.now();
+        let by_timer = FIRING_TIMERS.with(|firing| firing.get());
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            if task.woken_at.is_some() {
+                return;
+            }
+            if let Some(pending_since) = task.pending_since.take() {
+                if by_timer {
+                    task.profile.timer_wait += now - pending_since;
+                } else {
+                    task.profile.io_wait += now - pending_since;
+                }
+            }
+            task.woken_at.replace(now);
+        }
+    }
+
+    /// Returns true if any task has been woken and not yet polled.
+    pub(crate) fn any_runnable(&self) -> bool {
+        let lock = self.inner.lock().unwrap();
+        lock.tasks.values().any(|task| task.woken_at.is_some())
+    }
+
+    /// Returns the name and wait resource of each live task other than the provided task.
+    pub(crate) fn waiting(
+        &self,
+        exclude: Option<TaskId>,
+    ) -> Vec<(TaskId, Option<String>, Option<WaitResource>)> {
+        let lock = self.inner.lock().unwrap();
+        lock.tasks
+            .iter()
+            .filter(|(id, _)| Some(**id) != exclude)
+            .map(|(id, task)| (*id, task.name.clone(), task.waiting_on.clone()))
+            .collect()
+    }
+
+    /// Returns the virtual time profile of all tasks, ordered by task id.
+    pub(crate) fn profile(&self) -> ProfileReport {
+        let lock = self.inner.lock().unwrap();
+        let mut tasks: Vec<_> = lock
+            .tasks
+            .values()
+            .map(|task| task.profile.clone())
+            .chain(lock.completed.iter().cloned())
+            .collect();
+        tasks.sort_by_key(|profile| profile.id);
+        ProfileReport { tasks }
+    }
+
+    fn set_waiting(&self, id: TaskId, resource: Option<WaitResource>) {
+        if let Some(task) = self.inner.lock().unwrap().tasks.get_mut(&id) {
+            task.waiting_on = resource;
+        }
+    }
+
+    fn waiting_on(&self, id: TaskId) -> Option<WaitResource> {
+        let lock = self.inner.lock().unwrap();
+        lock.tasks.get(&id).and_then(|task| task.waiting_on.clone())
+    }
+
+    /// Returns the task which last made progress on the provided resource, along with its name.
+    pub(crate) fn holder(&self, resource: &WaitResource) -> Option<(TaskId, Option<String>)> {
+        let lock = self.inner.lock().unwrap();
+        let id = *lock.holders.get(resource)?;
+        let name = lock.tasks.get(&id).and_then(|task| task.name.clone());
+        Some((id, name))
+    }
+
+    fn set_holder(&self, id: TaskId, resource: WaitResource) {
+        self.inner.lock().unwrap().holders.insert(resource, id);
+    }
+
+    fn remove(&self, id: TaskId) {
+        let mut lock = self.inner.lock().unwrap();
+        if let Some(task) = lock.tasks.remove(&id) {
+            lock.completed.push(task.profile);
+        }
+        lock.priorities.remove(id);
+        lock.shuffled.remove(&id);
+        lock.holders.retain(|_, holder| *holder != id);
+        drop(lock);
+        let observers = self.time_handle.events().observers();
+        observers.notify(|observer, at| observer.task_dropped(at, id));
+    }
+
+    /// Export the wait-for graph of all live tasks in DOT format. Parked tasks have an edge to
+    /// the resource they are waiting on, and resources have an edge to the task which last
+    /// made progress on them.
+    pub(crate) fn wait_for_graph(&self) -> String {
+        let lock = self.inner.lock().unwrap();
+        let mut dot = String::from("digraph wait_for {\n");
+        for (id, task) in lock.tasks.iter() {
+            if let Some(name) = &task.name {
+                writeln!(dot, "    \"{}\" [label=\"{} {}\"];", id, id, name).unwrap();
+            } else {
+                writeln!(dot, "    \"{}\";", id).unwrap();
+            }
+        }
+        let mut resources: Vec<_> = lock
+            .tasks
+            .iter()
+            .filter_map(|(id, task)| task.waiting_on.as_ref().map(|r| (*id, r)))
+            .collect();
+        for (id, resource) in resources.iter() {
+            writeln!(dot, "    \"{}\" -> \"{}\";", id, resource).unwrap();
+        }
+        resources.sort_by_key(|(_, resource)| resource.to_string());
+        resources.dedup_by_key(|(_, resource)| resource.to_string());
+        for (_, resource) in resources {
+            writeln!(dot, "    \"{}\" [shape=box];", resource).unwrap();
+            if let Some(holder) = lock.holders.get(resource) {
+                writeln!(dot, "    \"{}\" -> \"{}\";", resource, holder).unwrap();
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// Returns the id of the currently polled task.
+pub(crate) fn current() -> Option<TaskId> {
+    CURRENT.with(|current| current.borrow().as_ref().map(|(id, _)| *id))
+}
+
+/// Returns the id, name and host of the currently polled task.
+pub(crate) fn current_identity() -> Option<(TaskId, Option<String>, Option<net::IpAddr>)> {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let (id, tasks) = current.as_ref()?;
+        let (name, host) = tasks.identity(*id)?;
+        Some((*id, name, host))
+    })
+}
+
+/// Returns the position of the waiter to wake among `waiters` waiting on a primitive of
+/// `crate::sync`, in the order they started waiting. The first waiter is woken unless the
+/// runtime polling the current task shuffles its scheduling, in which case the choice is drawn
+/// from the seed.
+pub(crate) fn choose_waiter(waiters: usize) -> usize {
+    CURRENT.with(|current| match current.borrow().as_ref() {
+        Some((_, tasks)) if waiters > 1 => tasks.choose_waiter(waiters),
+        _ => 0,
+    })
+}
+
+/// Record that the currently polled task is waiting on the provided resource.
+pub(crate) fn wait_on(resource: WaitResource) {
+    CURRENT.with(|current| {
+        if let Some((id, tasks)) = current.borrow().as_ref() {
+            tasks.set_waiting(*id, Some(resource));
+        }
+    })
+}
+
+/// Takes the resource the currently polled task has recorded waiting on during this poll.
+pub(crate) fn take_waiting() -> Option<WaitResource> {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let (id, tasks) = current.as_ref()?;
+        let resource = tasks.waiting_on(*id);
+        tasks.set_waiting(*id, None);
+        resource
+    })
+}
+
+/// Replaces the resource the currently polled task is waiting on.
+pub(crate) fn set_waiting(resource: Option<WaitResource>) {
+    CURRENT.with(|current| {
+        if let Some((id, tasks)) = current.borrow().as_ref() {
+            tasks.set_waiting(*id, resource);
+        }
+    })
+}
+
+/// Returns the task which last made progress on the provided resource.
+pub(crate) fn holder(resource: &WaitResource) -> Option<(TaskId, Option<String>)> {
+    CURRENT.with(|current| {
+        let current = current.borrow();
+        let (_, tasks) = current.as_ref()?;
+        tasks.holder(resource)
+    })
+}
+
+/// Returns true while the timer is firing expired timers.
+pub(crate) fn is_firing_timers() -> bool {
+    FIRING_TIMERS.with(|firing| firing.get())
+}
+
+/// Record that the currently polled task made progress on the provided resource, and is
+/// expected to wake any tasks waiting on it.
+pub(crate) fn hold(resource: WaitResource) {
+    CURRENT.with(|current| {
+        if let Some((id, tasks)) = current.borrow().as_ref() {
+            tasks.set_holder(*id, resource);
+        }
+    })
+}
+
+/// Future wrapper which records that the polling task is waiting on a resource whenever the
+/// wrapped future returns `Pending`.
+pub(crate) struct WaitingOn<F> {
+    resource: WaitResource,
+    future: F,
+}
+
+impl<F> WaitingOn<F> {
+    pub(crate) fn new(resource: WaitResource, future: F) -> Self {
+        Self { resource, future }
+    }
+}
+
+impl<F> Future for WaitingOn<F>
+where
+    F: Future + Unpin,
+{
+    type Output = F::Output;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let poll = Pin::new(&mut self.future).poll(cx);
+        if poll.is_pending() {
+            wait_on(self.resource.clone());
+        }
+        poll
+    }
+}
+
+/// Marks wakes which occur while running the provided closure as caused by timers.
+pub(crate) fn firing_timers<F, R>(f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let previous = FIRING_TIMERS.with(|firing| firing.replace(true));
+    let result = f();
+    FIRING_TIMERS.with(|firing| firing.set(previous));
+    result
+}
+
+/// Waker which records wakes before waking the executor's waker for the task.
+struct TaskWaker {
+    id: TaskId,
+    tasks: Tasks,
+    waker: sync::Mutex<Option<Waker>>,
+}
+
+impl ArcWake for TaskWaker {
+    fn wake_by_ref(arc_self: &sync::Arc<Self>) {
+        arc_self.tasks.woken(arc_self.id);
+        if let Some(waker) = arc_self.waker.lock().unwrap().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+/// Restores the previously polled task once the current task has been polled.
+struct CurrentGuard {
+    previous: Option<(TaskId, Tasks)>,
+}
+
+impl Drop for CurrentGuard {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        CURRENT.with(|current| current.replace(previous));
+    }
+}
+
+/// Future wrapper which tracks a task in the registry while it is polled.
+pub(crate) struct Instrumented<F> {
+    id: TaskId,
+    tasks: Tasks,
+    /// Completes once the cost of the next poll has run on a core of the host of the task.
+    core: Option<tokio_timer::Delay>,
+    waker: sync::Arc<TaskWaker>,
+    future: Pin<Box<F>>,
+}
+
+impl<F> Instrumented<F> {
+    pub(crate) fn id(&self) -> TaskId {
+        self.id
+    }
+}
+
+impl<F> Future for Instrumented<F>
+where
+    F: Future,
+{
+    type Output = F::Output;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        {
+            let mut waker = this.waker.waker.lock().unwrap();
+            if !waker.as_ref().map_or(false, |w| w.will_wake(cx.waker())) {
+                waker.replace(cx.waker().clone());
+            }
+        }
+        if this.tasks.should_defer(this.id) {
+            // Yield to higher priority tasks or to shuffle, remaining runnable.
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        if this.core.is_none() {
+            if let Some(finish) = this.tasks.charge_poll(this.id) {
+                this.core.replace(this.tasks.time_handle.delay(finish));
+            }
+        }
+        if let Some(core) = this.core.as_mut() {
+            let waker = futures::task::waker_ref(&this.waker);
+            if Pin::new(core)
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+            {
+                this.tasks.wait_for_core(this.id);
+                return Poll::Pending;
+            }
+            this.core.take();
+        }
+        this.tasks.poll_started(this.id);
+        let at = this.tasks.time_handle.now();
+        let probe = this.tasks.blocking.start();
+        let escape_probe = this.tasks.nondeterminism.start();
+        let poll = {
+            let previous =
+                CURRENT.with(|current| current.replace(Some((this.id, this.tasks.clone()))));
+            let _guard = CurrentGuard { previous };
+            let waker = futures::task::waker_ref(&this.waker);
+            let mut cx = Context::from_waker(&waker);
+            this.future.as_mut().poll(&mut cx)
+        };
+        if let Some(probe) = probe {
+            let (name, polls) = this.tasks.poll_count(this.id);
+            this.tasks.blocking.finish(probe, this.id, name, polls, at);
+        }
+        if let Some(probe) = escape_probe {
+            let (name, polls) = this.tasks.poll_count(this.id);
+            this.tasks
+                .nondeterminism
+                .finish(probe, this.id, name, polls, at);
+        }
+        if poll.is_pending() {
+            this.tasks.poll_pending(this.id);
+        }
+        let (id, ready) = (this.id, poll.is_ready());
+        let observers = this.tasks.time_handle.events().observers();
+        observers.notify(|observer, at| observer.task_polled(at, id, ready));
+        // Breakpoints matched while polling suspend the simulation before any other task runs.
+        this.tasks.time_handle.events().dispatch();
+        this.tasks.check_watchdog(id);
+        this.tasks.check_invariants(id);
+        poll
+    }
+}
+
+impl<F> Drop for Instrumented<F> {
+    fn drop(&mut self) {
+        self.tasks.remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{deterministic::DeterministicRuntime, Environment, TcpListener};
+    use futures::channel::oneshot;
+    use std::{net, sync, time::Duration};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that time spent waiting is attributed to timers or other tasks.
+    fn profile_waits() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let sleeper_handle = handle.clone();
+            handle.spawn_named("sleeper", async move {
+                sleeper_handle.delay_from(Duration::from_secs(10)).await;
+            });
+            let (tx, rx) = oneshot::channel();
+            handle.spawn_named("receiver", async move {
+                rx.await.unwrap();
+            });
+            let sender_handle = handle.clone();
+            handle.spawn_named("sender", async move {
+                sender_handle.delay_from(Duration::from_secs(5)).await;
+                tx.send(()).unwrap();
+            });
+            handle.delay_from(Duration::from_secs(20)).await;
+            let report = handle.profile();
+            let sleeper = report.named("sleeper").next().unwrap();
+            assert_eq!(sleeper.timer_wait(), Duration::from_secs(10));
+            assert_eq!(sleeper.io_wait(), Duration::from_secs(0));
+            let receiver = report.named("receiver").next().unwrap();
+            assert_eq!(receiver.io_wait(), Duration::from_secs(5));
+            assert_eq!(receiver.timer_wait(), Duration::from_secs(0));
+            assert_eq!(receiver.polls(), 2);
+        });
+    }
+
+    #[test]
+    /// Test that tasks blocked reading from each other form a cycle in the wait-for graph.
+    fn wait_for_cycle() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            handle.spawn_named("server", async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 2];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(b"hi").await.unwrap();
+                let _ = socket.read(&mut buf).await;
+            });
+            let client_handle = handle.clone();
+            handle.spawn_named("client", async move {
+                let mut socket = client_handle.connect(addr).await.unwrap();
+                socket.write_all(b"hi").await.unwrap();
+                let mut buf = [0u8; 2];
+                socket.read_exact(&mut buf).await.unwrap();
+                let _ = socket.read(&mut buf).await;
+            });
+            handle.delay_from(Duration::from_secs(1)).await;
+            let graph = handle.wait_for_graph();
+            let client_to_server = "read 127.0.0.1:65535 -> 127.0.0.1:9092";
+            let server_to_client = "read 127.0.0.1:9092 -> 127.0.0.1:65535";
+            for edge in &[
+                format!("\"task#1\" -> \"{}\"", client_to_server),
+                format!("\"{}\" -> \"task#2\"", client_to_server),
+                format!("\"task#2\" -> \"{}\"", server_to_client),
+                format!("\"{}\" -> \"task#1\"", server_to_client),
+            ] {
+                assert!(
+                    graph.contains(edge.as_str()),
+                    "missing {} in {}",
+                    edge,
+                    graph
+                );
+            }
+            assert!(graph.contains("[label=\"task#1 server\"]"));
+        });
+    }
+
+    /// Returns the order in which spawned tasks are first polled.
+    fn poll_order(seed: u64, shuffle: bool) -> Vec<usize> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        runtime.set_shuffle_scheduling(shuffle);
+        let handle = runtime.localhost_handle();
+        let order = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async {
+            for task in 0..8 {
+                let order = sync::Arc::clone(&order);
+                handle.spawn(async move { order.lock().unwrap().push(task) });
+            }
+            handle.delay_from(Duration::from_secs(1)).await;
+        });
+        let order = order.lock().unwrap();
+        order.clone()
+    }
+
+    #[test]
+    /// Test that shuffled scheduling polls runnable tasks in an order drawn from the seed,
+    /// while tasks are polled in the order they were woken by default.
+    fn shuffled_scheduling() {
+        assert_eq!(poll_order(1, false), (0..8).collect::<Vec<_>>());
+        assert_eq!(poll_order(1, true), poll_order(1, true));
+        let orders: std::collections::HashSet<_> =
+            (0..8).map(|seed| poll_order(seed, true)).collect();
+        assert!(orders.len() > 1);
+        for order in orders {
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, (0..8).collect::<Vec<_>>());
+        }
+    }
+}
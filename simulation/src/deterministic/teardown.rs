@@ -0,0 +1,155 @@
+//! Ordered teardown of simulated components.
+//!
+//! Background services such as connection pools and gossip loops keep tasks, timers and
+//! connections alive until the simulation is dropped, which hides genuine leaks among
+//! intentional ones. Components register a teardown hook on their host along with the
+//! components they depend on. When the host is stopped, or the simulation ends, each hook runs
+//! once every component depending on it has been torn down, so a cache is torn down before the
+//! database it reads from. Hooks without dependents run concurrently.
+use futures::{future, Future};
+use std::{collections, fmt, net, pin::Pin, sync};
+use tracing::trace;
+
+type Hook = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ()> + Send>> + Send>;
+
+struct Registration {
+    host: net::IpAddr,
+    name: String,
+    dependencies: Vec<String>,
+    hook: Hook,
+}
+
+impl fmt::Debug for Registration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registration")
+            .field("host", &self.host)
+            .field("name", &self.name)
+            .field("dependencies", &self.dependencies)
+            .finish()
+    }
+}
+
+/// Teardown hooks registered by the hosts of a runtime.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Teardowns {
+    hooks: sync::Arc<sync::Mutex<Vec<Registration>>>,
+}
+
+impl Teardowns {
+    pub(crate) fn register<F, U>(
+        &self,
+        host: net::IpAddr,
+        name: String,
+        dependencies: &[&str],
+        hook: F,
+    ) where
+        F: FnOnce() -> U + Send + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        self.hooks.lock().unwrap().push(Registration {
+            host,
+            name,
+            dependencies: dependencies.iter().map(|d| String::from(*d)).collect(),
+            hook: Box::new(move || Box::pin(hook())),
+        });
+    }
+
+    /// Returns true if no hooks are registered.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.hooks.lock().unwrap().is_empty()
+    }
+
+    /// Runs the hooks registered by `host`, or by every host if `host` is `None`. Hooks run in
+    /// stages, each made up of the hooks which no remaining hook of the same host depends on.
+    /// Hooks registered while the teardown is in progress run with a later teardown.
+    pub(crate) async fn run(&self, host: Option<net::IpAddr>) {
+        let mut remaining: Vec<Registration> = {
+            let mut hooks = self.hooks.lock().unwrap();
+            let (taken, kept) = hooks
+                .drain(..)
+                .partition(|r| host.map_or(true, |host| r.host == host));
+            *hooks = kept;
+            taken
+        };
+        while !remaining.is_empty() {
+            let depended_on: collections::HashSet<(net::IpAddr, &str)> = remaining
+                .iter()
+                .flat_map(|r| r.dependencies.iter().map(move |d| (r.host, d.as_str())))
+                .collect();
+            let ready: Vec<bool> = remaining
+                .iter()
+                .map(|r| !depended_on.contains(&(r.host, r.name.as_str())))
+                .collect();
+            let (stage, blocked): (Vec<Registration>, Vec<Registration>) =
+                if ready.iter().any(|ready| *ready) {
+                    let mut ready = ready.into_iter();
+                    remaining.into_iter().partition(|_| ready.next().unwrap())
+                } else {
+                    // The remaining hooks depend on each other, so tear them down together.
+                    trace!("teardown dependency cycle between {:?}", remaining);
+                    (remaining, vec![])
+                };
+            let hooks = stage.into_iter().map(|r| {
+                trace!("tearing down {} on {}", r.name, r.host);
+                (r.hook)()
+            });
+            future::join_all(hooks).await;
+            remaining = blocked;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use futures::channel::oneshot;
+    use std::{net, sync, time};
+
+    #[test]
+    /// Test that teardown hooks run after the components depending on them, and that the
+    /// services they stop are not reported by the leak check.
+    fn dependency_ordered_teardown() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.set_leak_check(true);
+        let handle = runtime.localhost_handle();
+        let order = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async {
+            let start = handle.now();
+            let addr: net::SocketAddr = "127.0.0.1:5432".parse().unwrap();
+            let listener = handle.bind(addr).await.unwrap();
+            let (stop_tx, stop_rx) = oneshot::channel::<()>();
+            let (done_tx, done_rx) = oneshot::channel();
+            handle.spawn(async move {
+                let _ = stop_rx.await;
+                drop(listener);
+                let _ = done_tx.send(());
+            });
+            // Stopping the database closes its listener.
+            let mut stop = Some((stop_tx, done_rx));
+            let components = vec![
+                ("db", vec![]),
+                ("api", vec!["cache", "db"]),
+                ("cache", vec!["db"]),
+            ];
+            for (name, dependencies) in components {
+                let order = sync::Arc::clone(&order);
+                let host = handle.clone();
+                let stop = if name == "db" { stop.take() } else { None };
+                handle.on_teardown(name, &dependencies, move || async move {
+                    host.delay_from(time::Duration::from_millis(10)).await;
+                    if let Some((stop_tx, done_rx)) = stop {
+                        let _ = stop_tx.send(());
+                        let _ = done_rx.await;
+                    }
+                    order.lock().unwrap().push((name, host.now() - start));
+                });
+            }
+        });
+        let ms = time::Duration::from_millis;
+        assert_eq!(
+            *order.lock().unwrap(),
+            vec![("api", ms(10)), ("cache", ms(20)), ("db", ms(30))]
+        );
+    }
+}
@@ -0,0 +1,128 @@
+//! Host templates, allowing identical hosts to be instantiated on demand.
+use super::{DeterministicRuntimeHandle, NetworkProfile};
+use futures::{Future, FutureExt};
+use std::{fmt, pin::Pin, sync};
+
+type Setup =
+    dyn Fn(DeterministicRuntimeHandle) -> Pin<Box<dyn Future<Output = ()> + Send>> + Send + Sync;
+
+/// A template from which hosts can be instantiated while the simulation is running. Each
+/// instance is allocated a new address, registered under the name `{template}-{n}`, and runs
+/// the template's setup future as a task named after the instance.
+#[derive(Clone)]
+pub struct HostTemplate {
+    name: String,
+    setup: sync::Arc<Setup>,
+    profile: Option<NetworkProfile>,
+    instances: sync::Arc<sync::atomic::AtomicUsize>,
+}
+
+impl fmt::Debug for HostTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HostTemplate")
+            .field("name", &self.name)
+            .field("profile", &self.profile)
+            .finish()
+    }
+}
+
+impl HostTemplate {
+    /// Creates a template with the provided name. `setup` is invoked with a handle scoped to
+    /// each new instance, and the returned future is spawned on the runtime.
+    pub fn new<F, U>(name: impl Into<String>, setup: F) -> Self
+    where
+        F: Fn(DeterministicRuntimeHandle) -> U + Send + Sync + 'static,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            name: name.into(),
+            setup: sync::Arc::new(move |handle| setup(handle).boxed()),
+            profile: None,
+            instances: sync::Arc::new(sync::atomic::AtomicUsize::new(0)),
+        }
+    }
+
+    /// Applies the provided network profile to all connections to or from instances.
+    pub fn profile(mut self, profile: NetworkProfile) -> Self {
+        self.profile = Some(profile);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the number of hosts which have been instantiated from this template.
+    pub fn instances(&self) -> usize {
+        self.instances.load(sync::atomic::Ordering::SeqCst)
+    }
+
+    /// Allocates the name of the next instance.
+    pub(crate) fn next_instance_name(&self) -> String {
+        let instance = self.instances.fetch_add(1, sync::atomic::Ordering::SeqCst);
+        format!("{}-{}", self.name, instance)
+    }
+
+    pub(crate) fn network_profile(&self) -> Option<NetworkProfile> {
+        self.profile.clone()
+    }
+
+    pub(crate) fn setup(
+        &self,
+        handle: DeterministicRuntimeHandle,
+    ) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        (self.setup)(handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use futures::{SinkExt, StreamExt};
+    use std::{net, time};
+    use tokio::codec::{Framed, LinesCodec};
+
+    /// A template for hosts which reply to each connection with their instance address.
+    fn web_template() -> HostTemplate {
+        HostTemplate::new("web", |handle: DeterministicRuntimeHandle| async move {
+            let addr = net::SocketAddr::new(handle.local_addr(), 80);
+            let mut listener = handle.bind(addr).await.unwrap();
+            while let Ok((socket, _)) = listener.accept().await {
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                let _ = transport.send(format!("{}", addr.ip())).await;
+            }
+        })
+    }
+
+    #[test]
+    /// Test that a controller can scale out instances of a template while the simulation is
+    /// running, and that instances are resolvable by name.
+    fn scale_out() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let template =
+                web_template().profile(NetworkProfile::new(time::Duration::from_millis(5)));
+            for _ in 0..3 {
+                handle.instantiate(&template);
+                handle.delay_from(time::Duration::from_secs(1)).await;
+            }
+            assert_eq!(template.instances(), 3);
+            assert_eq!(handle.hosts().len(), 4);
+            for instance in 0..3 {
+                let name = format!("web-{}", instance);
+                let ip = handle.resolve_host(&name).unwrap();
+                let socket = handle.connect(net::SocketAddr::new(ip, 80)).await.unwrap();
+                let mut transport = Framed::new(socket, LinesCodec::new());
+                let reply = transport.next().await.unwrap().unwrap();
+                assert_eq!(reply, format!("{}", ip));
+            }
+            let decommissioned = handle.resolve_host("web-1").unwrap();
+            handle.remove_host(decommissioned);
+            assert_eq!(handle.resolve_host("web-1"), None);
+            assert!(handle.profile().named("web-0").next().is_some());
+        });
+    }
+}
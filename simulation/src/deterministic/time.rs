@@ -1,6 +1,29 @@
 //! A mock source of time, allowing for determinstic control of the progress
 //! of time.
-use std::{sync, time};
+use super::events::Events;
+use super::observer::Observers;
+use super::sleep::{Sleep, Timers};
+use crate::calendar::{DateTime, TimeZone};
+use futures::channel::oneshot;
+use std::{cmp, collections, fmt, sync, time};
+
+/// Unix timestamp of 2020-01-01T00:00:00Z, the default start of the mock wall clock.
+const DEFAULT_SYSTEM_TIME: u64 = 1_577_836_800;
+
+/// Real time the executor waits for wakes from outside the simulation, such as from OS threads,
+/// before concluding that a simulation with no pending timers is deadlocked.
+const DEFAULT_DEADLOCK_GRACE: time::Duration = time::Duration::from_millis(100);
+
+/// Describes the tasks of a simulation once no task can make progress, or returns `None` if a
+/// task is runnable.
+#[derive(Clone)]
+pub(crate) struct DeadlockReporter(pub(crate) sync::Arc<dyn Fn() -> Option<String> + Send + Sync>);
+
+impl fmt::Debug for DeadlockReporter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DeadlockReporter")
+    }
+}
 
 #[derive(Debug)]
 struct Inner {
@@ -8,6 +31,25 @@ struct Inner {
     base: time::Instant,
     /// The amount of mock time which has elapsed.
     advance: time::Duration,
+    /// Wall clock time at `base`.
+    system_base: time::SystemTime,
+    /// Wall clock times at which the wall clock is stepped back by one second.
+    leap_seconds: Vec<time::SystemTime>,
+    time_zone: TimeZone,
+    /// Granularity which timer deadlines are rounded up to.
+    resolution: Option<time::Duration>,
+    /// Notified with the duration until the next timer once the executor is next idle.
+    idle_waiters: Vec<oneshot::Sender<Option<time::Duration>>>,
+    /// Deadlines of timers created through handles. Timers which are dropped before their
+    /// deadline remain until the deadline passes.
+    deadlines: collections::BinaryHeap<cmp::Reverse<time::Instant>>,
+    /// Fails the simulation once every task is waiting and no timers are pending, after
+    /// waiting for the grace period in real time. Detection is disabled without a grace period.
+    deadlock_reporter: Option<DeadlockReporter>,
+    deadlock_grace: Option<time::Duration>,
+    /// Set while time is paused, holding the instant up to which time has been released. Time
+    /// does not advance past it while paused.
+    paused: Option<time::Instant>,
 }
 
 impl Inner {
@@ -15,6 +57,15 @@ impl Inner {
         Self {
             base: time::Instant::now(),
             advance: time::Duration::from_millis(0),
+            system_base: time::UNIX_EPOCH + time::Duration::from_secs(DEFAULT_SYSTEM_TIME),
+            leap_seconds: vec![],
+            time_zone: TimeZone::utc(),
+            resolution: None,
+            idle_waiters: vec![],
+            deadlines: collections::BinaryHeap::new(),
+            deadlock_reporter: None,
+            deadlock_grace: Some(DEFAULT_DEADLOCK_GRACE),
+            paused: None,
         }
     }
 
@@ -25,6 +76,97 @@ impl Inner {
     fn now(&self) -> time::Instant {
         self.base + self.advance
     }
+
+    /// Round the provided deadline up to the next multiple of the timer resolution, measured
+    /// from the time basis.
+    fn quantize(&self, deadline: time::Instant) -> time::Instant {
+        let resolution = match self.resolution {
+            Some(resolution) if resolution.as_nanos() > 0 => resolution.as_nanos(),
+            _ => return deadline,
+        };
+        if deadline <= self.base {
+            return deadline;
+        }
+        let elapsed = (deadline - self.base).as_nanos();
+        let rounded = (elapsed + resolution - 1) / resolution * resolution;
+        self.base + time::Duration::from_nanos(rounded as u64)
+    }
+
+    /// Quantize the provided deadline and track it as a pending timer.
+    fn register_deadline(&mut self, deadline: time::Instant) -> time::Instant {
+        let deadline = self.quantize(deadline);
+        self.prune_deadlines(self.now());
+        self.deadlines.push(cmp::Reverse(deadline));
+        deadline
+    }
+
+    /// Discard tracked deadlines which are earlier than the provided instant.
+    fn prune_deadlines(&mut self, before: time::Instant) {
+        while let Some(cmp::Reverse(deadline)) = self.deadlines.peek() {
+            if *deadline >= before {
+                break;
+            }
+            self.deadlines.pop();
+        }
+    }
+
+    /// Returns the duration until the next timer fires. The timer wheel only reports a lower
+    /// bound on the next deadline, which is refined using the tracked deadlines.
+    fn next_timer(&mut self, lower_bound: Option<time::Duration>) -> Option<time::Duration> {
+        let lower_bound = match lower_bound {
+            Some(lower_bound) => lower_bound,
+            None => {
+                self.deadlines.clear();
+                return None;
+            }
+        };
+        let now = self.now();
+        self.prune_deadlines(now + lower_bound);
+        match self.deadlines.peek() {
+            Some(cmp::Reverse(deadline)) => Some(*deadline - now),
+            None => Some(lower_bound),
+        }
+    }
+
+    /// Returns how much of the provided duration time may advance by, which is limited by the
+    /// time released while paused.
+    fn allowance(&self, duration: time::Duration) -> time::Duration {
+        match self.paused {
+            Some(released) if released > self.now() => cmp::min(duration, released - self.now()),
+            Some(_) => time::Duration::from_millis(0),
+            None => duration,
+        }
+    }
+
+    /// Releases paused time up to the provided deadline, rounded up to the millisecond
+    /// granularity of the timer wheel so that timers at the deadline fire.
+    fn release(&mut self, deadline: time::Instant) {
+        let released = match self.paused {
+            Some(released) => released,
+            None => return,
+        };
+        let elapsed = deadline.duration_since(self.base);
+        let millis = (elapsed.as_nanos() + 999_999) / 1_000_000;
+        let deadline = self.base + time::Duration::from_millis(millis as u64);
+        self.paused = Some(cmp::max(released, deadline));
+    }
+
+    fn system_time(&mut self) -> time::SystemTime {
+        loop {
+            let system_time = self.system_base + self.advance;
+            match self.leap_seconds.first() {
+                Some(leap_second) if *leap_second <= system_time => {
+                    self.leap_seconds.remove(0);
+                    self.system_base -= time::Duration::from_secs(1);
+                }
+                _ => return system_time,
+            }
+        }
+    }
+
+    fn set_system_time(&mut self, system_time: time::SystemTime) {
+        self.system_base = system_time - self.advance;
+    }
 }
 
 /// A mock source of time, providing deterministic control of time.
@@ -33,6 +175,8 @@ pub struct DeterministicTime<P> {
     park: tokio_timer::Timer<DeterministicPark<P>, Now>,
     inner: sync::Arc<sync::Mutex<Inner>>,
     timer_handle: tokio_timer::timer::Handle,
+    events: Events,
+    timers: Timers,
 }
 
 impl<P> DeterministicTime<P>
@@ -47,13 +191,16 @@ where
         let inner = Inner::new();
         let inner = sync::Arc::new(sync::Mutex::new(inner));
         let now = Now::new(sync::Arc::clone(&inner));
-        let inner_park = DeterministicPark::new(park, sync::Arc::clone(&inner));
+        let events = Events::new(Observers::new(now.clone()));
+        let inner_park = DeterministicPark::new(park, sync::Arc::clone(&inner), events.clone());
         let timer = tokio_timer::Timer::new_with_now(inner_park, now);
         let timer_handle = timer.handle();
         Self {
             inner,
             park: timer,
             timer_handle,
+            events,
+            timers: Timers::default(),
         }
     }
 
@@ -62,6 +209,8 @@ where
         DeterministicTimeHandle {
             inner,
             timer_handle: self.timer_handle.clone(),
+            events: self.events.clone(),
+            timers: self.timers.clone(),
         }
     }
 }
@@ -70,18 +219,51 @@ where
 pub struct DeterministicTimeHandle {
     inner: sync::Arc<sync::Mutex<Inner>>,
     timer_handle: tokio_timer::timer::Handle,
+    events: Events,
+    timers: Timers,
 }
 
 impl DeterministicTimeHandle {
     /// Advances the internal clock for the provided duration.
     pub(crate) fn advance(&self, duration: time::Duration) {
         self.inner.lock().unwrap().advance(duration);
+        self.events.observers().advanced();
     }
     /// Return time now.
     pub(crate) fn now(&self) -> time::Instant {
         self.inner.lock().unwrap().now()
     }
 
+    /// Return the wall clock time now.
+    pub(crate) fn system_time(&self) -> time::SystemTime {
+        self.inner.lock().unwrap().system_time()
+    }
+
+    /// Return the local date and time now, according to the configured time zone.
+    pub(crate) fn local_time(&self) -> DateTime {
+        let mut lock = self.inner.lock().unwrap();
+        let system_time = lock.system_time();
+        lock.time_zone.local(system_time)
+    }
+
+    /// Set the wall clock to the provided time. The wall clock continues to advance
+    /// alongside mock time from the new value.
+    pub(crate) fn set_system_time(&self, system_time: time::SystemTime) {
+        self.inner.lock().unwrap().set_system_time(system_time);
+    }
+
+    /// Schedule a leap second. Once the wall clock reaches the provided time it is stepped
+    /// back by one second, repeating the final second before it.
+    pub(crate) fn insert_leap_second(&self, at: time::SystemTime) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.leap_seconds.push(at);
+        lock.leap_seconds.sort();
+    }
+
+    pub(crate) fn set_time_zone(&self, time_zone: TimeZone) {
+        self.inner.lock().unwrap().time_zone = time_zone;
+    }
+
     /// Creates an instance of `Now` from this deterministic time source.
     ///
     /// [`Now`]:[tokio_timer::clock::Now]
@@ -95,18 +277,91 @@ impl DeterministicTimeHandle {
         tokio_timer::clock::Clock::new_with_now(self.clone_now())
     }
 
+    /// Returns a receiver which is notified once the executor has no runnable tasks, with the
+    /// duration until the next timer fires, or `None` if there are no pending timers. Time is
+    /// not advanced while notifying idle waiters.
+    pub(crate) fn wait_idle(&self) -> oneshot::Receiver<Option<time::Duration>> {
+        let (tx, rx) = oneshot::channel();
+        self.inner.lock().unwrap().idle_waiters.push(tx);
+        rx
+    }
+
+    /// Set the granularity timer deadlines are rounded up to, coalescing timers with nearby
+    /// deadlines. Timers are not quantized by default.
+    pub(crate) fn set_resolution(&self, resolution: Option<time::Duration>) {
+        self.inner.lock().unwrap().resolution = resolution;
+    }
+
+    /// Pauses or resumes time. While paused, time only advances when released with
+    /// `advance_paused`, and the simulation is deadlocked once every task is waiting on a timer
+    /// beyond the released time.
+    pub(crate) fn set_paused(&self, paused: bool) {
+        let mut lock = self.inner.lock().unwrap();
+        lock.paused = if paused { Some(lock.now()) } else { None };
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.inner.lock().unwrap().paused.is_some()
+    }
+
+    /// Returns a delay completing once `duration` has elapsed, releasing paused time up to its
+    /// deadline.
+    pub(crate) fn advance_paused(&self, duration: time::Duration) -> tokio_timer::Delay {
+        let deadline = {
+            let mut lock = self.inner.lock().unwrap();
+            let deadline = lock.now() + duration;
+            let deadline = lock.register_deadline(deadline);
+            lock.release(deadline);
+            deadline
+        };
+        self.events.observers().timer_armed(deadline);
+        self.timer_handle.delay(deadline)
+    }
+
+    /// Installs the reporter invoked once the simulation is deadlocked, or removes it.
+    pub(crate) fn set_deadlock_reporter(&self, reporter: Option<DeadlockReporter>) {
+        self.inner.lock().unwrap().deadlock_reporter = reporter;
+    }
+
+    /// Sets the real time waited for wakes from outside the simulation before failing a
+    /// deadlocked simulation, or disables deadlock detection if `None`.
+    pub(crate) fn set_deadlock_grace(&self, grace: Option<time::Duration>) {
+        self.inner.lock().unwrap().deadlock_grace = grace;
+    }
+
+    /// Returns the breakpoint registry through which simulation events are emitted.
+    pub(crate) fn events(&self) -> &Events {
+        &self.events
+    }
+
     pub fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay {
+        let deadline = self.inner.lock().unwrap().register_deadline(deadline);
+        self.events.observers().timer_armed(deadline);
         self.timer_handle.delay(deadline)
     }
 
     pub fn delay_from(&self, duration: time::Duration) -> tokio_timer::Delay {
-        self.timer_handle.delay(self.now() + duration)
+        self.delay(self.now() + duration)
     }
 
     pub fn timeout<T>(&self, value: T, timeout: time::Duration) -> tokio_timer::Timeout<T> {
+        let timeout = {
+            let mut lock = self.inner.lock().unwrap();
+            let now = lock.now();
+            lock.register_deadline(now + timeout) - now
+        };
+        self.events.observers().timer_armed(self.now() + timeout);
         self.timer_handle.timeout(value, timeout)
     }
 
+    /// Returns a timer firing at the provided deadline, ordered after the timers created
+    /// before it with the same deadline.
+    pub(crate) fn sleep_until(&self, deadline: time::Instant) -> Sleep {
+        let deadline = self.inner.lock().unwrap().register_deadline(deadline);
+        self.events.observers().timer_armed(deadline);
+        self.timers.sleep_until(self, deadline)
+    }
+
     pub fn clone_timer_handle(&self) -> tokio_timer::timer::Handle {
         self.timer_handle.clone()
     }
@@ -116,11 +371,16 @@ impl DeterministicTimeHandle {
 struct DeterministicPark<P> {
     park: P,
     inner: sync::Arc<sync::Mutex<Inner>>,
+    events: Events,
 }
 
 impl<P> DeterministicPark<P> {
-    fn new(park: P, inner: sync::Arc<sync::Mutex<Inner>>) -> Self {
-        Self { park, inner }
+    fn new(park: P, inner: sync::Arc<sync::Mutex<Inner>>, events: Events) -> Self {
+        Self {
+            park,
+            inner,
+            events,
+        }
     }
 }
 
@@ -134,15 +394,95 @@ where
         self.park.unpark()
     }
     fn park(&mut self) -> Result<(), Self::Error> {
-        self.park.park()
+        if self.notify_idle(None) {
+            return self.park.park_timeout(time::Duration::from_millis(0));
+        }
+        // With no pending timers, time is only advanced to reach a time breakpoint.
+        let paused = self.inner.lock().unwrap().paused.is_some();
+        if !paused && self.advance_to_breakpoint(None).is_some() {
+            return self.park.park_timeout(time::Duration::from_millis(0));
+        }
+        self.wait_deadlocked("no timers are pending")
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
-        let mut lock = self.inner.lock().unwrap();
-        lock.advance(duration);
+        if !self.notify_idle(Some(duration)) {
+            let allowed = self.inner.lock().unwrap().allowance(duration);
+            if duration > allowed && allowed == time::Duration::from_millis(0) {
+                return self.wait_deadlocked("time is paused");
+            }
+            // Stop short at any time breakpoint, the timer will park again for the remainder.
+            if self.advance_to_breakpoint(Some(allowed)).is_none() {
+                self.inner.lock().unwrap().advance(allowed);
+                self.events.observers().advanced();
+            }
+        }
         self.park.park_timeout(time::Duration::from_millis(0))
     }
 }
 
+impl<P> DeterministicPark<P>
+where
+    P: tokio_executor::park::Park,
+{
+    /// Waits for wakes from outside the simulation once time cannot advance, failing the
+    /// simulation with the provided reason if every task is still waiting after the grace
+    /// period.
+    fn wait_deadlocked(&mut self, reason: &str) -> Result<(), P::Error> {
+        let (grace, reporter) = {
+            let lock = self.inner.lock().unwrap();
+            (lock.deadlock_grace, lock.deadlock_reporter.clone())
+        };
+        let (grace, reporter) = match (grace, reporter) {
+            (Some(grace), Some(reporter)) => (grace, reporter),
+            _ => return self.park.park(),
+        };
+        self.park.park_timeout(grace)?;
+        if let Some(report) = (reporter.0)() {
+            panic!(
+                "simulation deadlocked, every task is waiting and {}:\n{}",
+                reason, report
+            );
+        }
+        Ok(())
+    }
+}
+
+impl<P> DeterministicPark<P> {
+    /// Advance time to the earliest time breakpoint within the provided duration, or the
+    /// earliest time breakpoint at all if no duration is provided, invoking its callbacks.
+    /// Returns the instant reached, if any.
+    fn advance_to_breakpoint(&self, within: Option<time::Duration>) -> Option<time::Instant> {
+        let now = self.inner.lock().unwrap().now();
+        let until = match within {
+            Some(within) => now + within,
+            None => now + time::Duration::from_secs(u64::from(u32::MAX)),
+        };
+        let at = self.events.next_time_breakpoint(now, until)?;
+        self.inner.lock().unwrap().advance(at - now);
+        self.events.observers().advanced();
+        self.events.reach_time(at);
+        self.events.dispatch();
+        Some(at)
+    }
+
+    /// Notify any idle waiters, returning true if any were notified.
+    fn notify_idle(&self, next_timer: Option<time::Duration>) -> bool {
+        let (waiters, next_timer) = {
+            let mut lock = self.inner.lock().unwrap();
+            if lock.idle_waiters.is_empty() {
+                return false;
+            }
+            let waiters: Vec<_> = lock.idle_waiters.drain(..).collect();
+            (waiters, lock.next_timer(next_timer))
+        };
+        let notified = !waiters.is_empty();
+        for waiter in waiters {
+            let _ = waiter.send(next_timer);
+        }
+        notified
+    }
+}
+
 impl<P> tokio_executor::park::Park for DeterministicTime<P>
 where
     P: tokio_executor::park::Park,
@@ -153,10 +493,12 @@ where
         self.park.unpark()
     }
     fn park(&mut self) -> Result<(), Self::Error> {
-        self.park.park()
+        let park = &mut self.park;
+        super::task::firing_timers(|| park.park())
     }
     fn park_timeout(&mut self, duration: time::Duration) -> Result<(), Self::Error> {
-        self.park.park_timeout(duration)
+        let park = &mut self.park;
+        super::task::firing_timers(|| park.park_timeout(duration))
     }
 }
 
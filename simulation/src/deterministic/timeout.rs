@@ -0,0 +1,287 @@
+//! Timeouts which explain why the wrapped future did not complete.
+//!
+//! A plain timeout reports that a future hung, but not what it was waiting for. [`SimTimeout`]
+//! uses the task instrumentation to record the simulated resource the wrapped future was last
+//! parked on, the task expected to make progress on that resource, and what last woke the
+//! wrapped future, reporting them in a [`SimTimeoutError`] once the timeout elapses.
+//!
+//! [`SimTimeout`]:`SimTimeout`
+//! [`SimTimeoutError`]:`SimTimeoutError`
+use super::task::{self, TaskId, WaitResource};
+use super::DeterministicTimeHandle;
+use futures::{
+    task::{ArcWake, Waker},
+    Future, FutureExt, Poll,
+};
+use std::{error, fmt, pin::Pin, sync, task::Context, time};
+
+/// The cause of a wake of the future wrapped by a [`SimTimeout`].
+///
+/// [`SimTimeout`]:`SimTimeout`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeSource {
+    /// An expired timer.
+    Timer,
+    /// The provided task, for example by writing to a socket or sending on a channel.
+    Task(TaskId),
+    /// A wake from outside of any task.
+    External,
+}
+
+impl fmt::Display for WakeSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WakeSource::Timer => write!(f, "a timer"),
+            WakeSource::Task(id) => write!(f, "{}", id),
+            WakeSource::External => write!(f, "a wake outside of any task"),
+        }
+    }
+}
+
+/// Error returned once a [`SimTimeout`] elapses, describing what the wrapped future was doing.
+///
+/// [`SimTimeout`]:`SimTimeout`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimTimeoutError {
+    duration: time::Duration,
+    polls: u64,
+    waiting_on: Option<WaitResource>,
+    holder: Option<(TaskId, Option<String>)>,
+    last_wake: Option<(time::Duration, WakeSource)>,
+}
+
+impl SimTimeoutError {
+    pub fn duration(&self) -> time::Duration {
+        self.duration
+    }
+
+    /// Returns the number of times the wrapped future was polled.
+    pub fn polls(&self) -> u64 {
+        self.polls
+    }
+
+    /// Returns the simulated resource the wrapped future was last parked on. Futures parked on
+    /// timers, channels or other resources outside of the simulated network have no resource.
+    pub fn waiting_on(&self) -> Option<&WaitResource> {
+        self.waiting_on.as_ref()
+    }
+
+    /// Returns the task which last made progress on the resource the wrapped future was
+    /// waiting on, and was expected to wake it.
+    pub fn holder(&self) -> Option<TaskId> {
+        self.holder.as_ref().map(|(id, _)| *id)
+    }
+
+    /// Returns the time since the timeout started at which the wrapped future was last woken,
+    /// along with the cause of the wake.
+    pub fn last_wake(&self) -> Option<(time::Duration, WakeSource)> {
+        self.last_wake
+    }
+}
+
+impl fmt::Display for SimTimeoutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timed out after {:?} ({} polls), ",
+            self.duration, self.polls
+        )?;
+        match &self.waiting_on {
+            Some(resource) => write!(f, "last waiting on {}", resource)?,
+            None => write!(
+                f,
+                "last waiting on a timer, channel or other resource outside of the network"
+            )?,
+        }
+        match &self.holder {
+            Some((id, Some(name))) => write!(f, ", held by {} {}", id, name)?,
+            Some((id, None)) => write!(f, ", held by {}", id)?,
+            None => {}
+        }
+        match &self.last_wake {
+            Some((at, source)) => write!(f, "; last woken by {} after {:?}", source, at),
+            None => write!(f, "; never woken"),
+        }
+    }
+}
+
+impl error::Error for SimTimeoutError {}
+
+/// Records wakes of the wrapped future before waking the task.
+struct TimeoutWaker {
+    started: time::Instant,
+    time_handle: DeterministicTimeHandle,
+    waker: sync::Mutex<Option<Waker>>,
+    last_wake: sync::Mutex<Option<(time::Duration, WakeSource)>>,
+}
+
+impl ArcWake for TimeoutWaker {
+    fn wake_by_ref(arc_self: &sync::Arc<Self>) {
+        let source = if task::is_firing_timers() {
+            WakeSource::Timer
+        } else {
+            task::current().map_or(WakeSource::External, WakeSource::Task)
+        };
+        let at = arc_self.time_handle.now() - arc_self.started;
+        arc_self.last_wake.lock().unwrap().replace((at, source));
+        if let Some(waker) = arc_self.waker.lock().unwrap().as_ref() {
+            waker.wake_by_ref();
+        }
+    }
+}
+
+/// Future returned by [`DeterministicRuntimeHandle::sim_timeout`].
+///
+/// [`DeterministicRuntimeHandle::sim_timeout`]:`super::DeterministicRuntimeHandle::sim_timeout`
+pub struct SimTimeout<F> {
+    future: Pin<Box<F>>,
+    delay: tokio_timer::Delay,
+    duration: time::Duration,
+    waker: sync::Arc<TimeoutWaker>,
+    polls: u64,
+    waiting_on: Option<WaitResource>,
+}
+
+impl<F> SimTimeout<F> {
+    pub(crate) fn new(
+        time_handle: DeterministicTimeHandle,
+        duration: time::Duration,
+        future: F,
+    ) -> Self {
+        let started = time_handle.now();
+        let delay = time_handle.delay(started + duration);
+        let waker = TimeoutWaker {
+            started,
+            time_handle,
+            waker: sync::Mutex::new(None),
+            last_wake: sync::Mutex::new(None),
+        };
+        Self {
+            future: Box::pin(future),
+            delay,
+            duration,
+            waker: sync::Arc::new(waker),
+            polls: 0,
+            waiting_on: None,
+        }
+    }
+}
+
+impl<F> Future for SimTimeout<F>
+where
+    F: Future,
+{
+    type Output = Result<F::Output, SimTimeoutError>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        this.waker.waker.lock().unwrap().replace(cx.waker().clone());
+        this.polls += 1;
+        // Only resources recorded while polling the wrapped future are attributed to it.
+        let previous = task::take_waiting();
+        let poll = {
+            let waker = futures::task::waker_ref(&this.waker);
+            let mut cx = Context::from_waker(&waker);
+            this.future.as_mut().poll(&mut cx)
+        };
+        if let Poll::Ready(output) = poll {
+            return Poll::Ready(Ok(output));
+        }
+        match task::take_waiting() {
+            Some(resource) => {
+                this.waiting_on.replace(resource.clone());
+                task::set_waiting(Some(resource));
+            }
+            None => {
+                this.waiting_on = None;
+                task::set_waiting(previous);
+            }
+        }
+        futures::ready!(this.delay.poll_unpin(cx));
+        let holder = this.waiting_on.as_ref().and_then(task::holder);
+        Poll::Ready(Err(SimTimeoutError {
+            duration: this.duration,
+            polls: this.polls,
+            waiting_on: this.waiting_on.take(),
+            holder,
+            last_wake: *this.waker.last_wake.lock().unwrap(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, Environment, TcpListener};
+    use std::net;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that a timed out read reports the socket it was waiting on and the peer task.
+    fn reports_socket() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let server_handle = handle.clone();
+            handle.spawn_named("server", async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                socket.write_all(b"a").await.unwrap();
+                // Never respond again, keeping the connection open.
+                server_handle
+                    .delay_from(time::Duration::from_secs(3600))
+                    .await;
+                drop(socket);
+            });
+            let mut socket = handle.connect(addr).await.unwrap();
+            let client_addr = crate::TcpStream::local_addr(&socket).unwrap();
+            let mut buf = [0u8; 2];
+            let err = handle
+                .sim_timeout(time::Duration::from_secs(10), socket.read_exact(&mut buf))
+                .await
+                .unwrap_err();
+            assert_eq!(
+                err.waiting_on(),
+                Some(&WaitResource::Read {
+                    from: addr,
+                    to: client_addr
+                })
+            );
+            assert!(err.holder().is_some());
+            assert!(
+                err.to_string().contains("server"),
+                "expected the holding task to be named: {}",
+                err
+            );
+        });
+    }
+
+    #[test]
+    /// Test that futures which complete in time are returned, and that futures waiting on
+    /// timers report their last wake.
+    fn completes_and_reports_timers() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let value = handle
+                .sim_timeout(time::Duration::from_secs(10), async { 5 })
+                .await
+                .unwrap();
+            assert_eq!(value, 5);
+
+            let sleeper = handle.clone();
+            let err = handle
+                .sim_timeout(time::Duration::from_secs(10), async move {
+                    sleeper.delay_from(time::Duration::from_secs(1)).await;
+                    sleeper.delay_from(time::Duration::from_secs(60)).await;
+                })
+                .await
+                .unwrap_err();
+            assert_eq!(err.waiting_on(), None);
+            assert_eq!(
+                err.last_wake(),
+                Some((time::Duration::from_secs(1), WakeSource::Timer))
+            );
+        });
+    }
+}
@@ -0,0 +1,319 @@
+//! Integration with `tracing` subscribers, stamping output with simulated time.
+//!
+//! Logs of a simulation interleave the output of every simulated host, and timestamps taken
+//! from the real clock cannot be correlated with anything the simulation did. A
+//! [`SimulationLayer`], created with [`DeterministicRuntime::tracing_layer`], is a
+//! `tracing_subscriber` layer which formats each event and span with the simulated time elapsed
+//! since the layer was created, followed by the host and the task which emitted it. Formatted
+//! lines are kept by the layer, and can also be written to stderr.
+//!
+//! As the output only depends on the simulation, two runs of the same seed should produce
+//! identical output. [`check_log_determinism`] asserts this, reporting the first line at which
+//! they differ. Requires the `tracing-subscriber` feature.
+//!
+//! [`SimulationLayer`]:`SimulationLayer`
+//! [`DeterministicRuntime::tracing_layer`]:`super::DeterministicRuntime::tracing_layer`
+//! [`check_log_determinism`]:`check_log_determinism`
+use super::{task, DeterministicRuntimeHandle, DeterministicTimeHandle, Simulation};
+use futures::Future;
+use std::{collections, fmt, fmt::Write, sync, sync::atomic, time};
+use tracing::{field, span, Event, Metadata, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+
+#[derive(Debug, Default)]
+struct State {
+    lines: Vec<String>,
+    /// Names of the spans which have not been closed.
+    spans: collections::HashMap<span::Id, &'static str>,
+    /// Spans entered and not yet exited, innermost last.
+    entered: Vec<span::Id>,
+}
+
+/// Formats the fields of an event or span, leading with its message.
+#[derive(Default)]
+struct Fields {
+    message: String,
+    fields: String,
+}
+
+impl field::Visit for Fields {
+    fn record_debug(&mut self, field: &field::Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            let _ = write!(self.message, "{:?}", value);
+        } else {
+            let _ = write!(self.fields, " {}={:?}", field.name(), value);
+        }
+    }
+}
+
+/// Layer formatting spans and events with simulated time and the identity of the simulated
+/// task which emitted them, see the [module documentation](self). Clones share their output.
+#[derive(Clone)]
+pub struct SimulationLayer {
+    time_handle: DeterministicTimeHandle,
+    start: time::Instant,
+    stderr: bool,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl fmt::Debug for SimulationLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self.state.lock().unwrap().lines.len();
+        write!(f, "SimulationLayer {{ lines: {} }}", lines)
+    }
+}
+
+impl SimulationLayer {
+    pub(crate) fn new(time_handle: DeterministicTimeHandle) -> Self {
+        let start = time_handle.now();
+        Self {
+            time_handle,
+            start,
+            stderr: false,
+            state: sync::Arc::default(),
+        }
+    }
+
+    /// Writes each formatted line to stderr, in addition to keeping it.
+    pub fn with_stderr(mut self, enabled: bool) -> Self {
+        self.stderr = enabled;
+        self
+    }
+
+    /// Returns every line formatted so far.
+    pub fn lines(&self) -> Vec<String> {
+        self.state.lock().unwrap().lines.clone()
+    }
+
+    /// Returns a subscriber formatting with this layer, for use with
+    /// `tracing::subscriber::with_default` when no other subscriber is needed.
+    pub fn subscriber(&self) -> impl Subscriber + Send + Sync {
+        self.clone().with_subscriber(Spans::default())
+    }
+
+    /// Formats a line with the simulated time and the identity of the current task.
+    fn line(&self, metadata: &Metadata<'_>, kind: &str, fields: Fields) {
+        let elapsed = self.time_handle.now().duration_since(self.start);
+        let mut line = format!("{}.{:06}", elapsed.as_secs(), elapsed.subsec_micros());
+        match task::current_identity() {
+            Some((id, name, host)) => {
+                let host = host.map_or_else(|| String::from("-"), |host| host.to_string());
+                let _ = write!(line, " {} {}", host, id);
+                if let Some(name) = name {
+                    let _ = write!(line, " {}", name);
+                }
+            }
+            None => line.push_str(" - -"),
+        }
+        let mut state = self.state.lock().unwrap();
+        let _ = write!(line, " {} {}", metadata.level(), metadata.target());
+        let scope: Vec<&str> = state
+            .entered
+            .iter()
+            .filter_map(|id| state.spans.get(id).cloned())
+            .collect();
+        if !scope.is_empty() {
+            let _ = write!(line, " {}", scope.join(":"));
+        }
+        let _ = write!(line, ": {}{}{}", kind, fields.message, fields.fields);
+        if self.stderr {
+            eprintln!("{}", line);
+        }
+        state.lines.push(line);
+    }
+}
+
+impl<S> Layer<S> for SimulationLayer
+where
+    S: Subscriber,
+{
+    fn new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _: Context<'_, S>) {
+        let metadata = attrs.metadata();
+        let mut fields = Fields::default();
+        attrs.record(&mut fields);
+        let kind = format!("new span {}", metadata.name());
+        let kind = if fields.message.is_empty() {
+            kind
+        } else {
+            kind + " "
+        };
+        self.line(metadata, &kind, fields);
+        let mut state = self.state.lock().unwrap();
+        state.spans.insert(id.clone(), metadata.name());
+    }
+
+    fn on_event(&self, event: &Event<'_>, _: Context<'_, S>) {
+        let mut fields = Fields::default();
+        event.record(&mut fields);
+        self.line(event.metadata(), "", fields);
+    }
+
+    fn on_enter(&self, id: &span::Id, _: Context<'_, S>) {
+        self.state.lock().unwrap().entered.push(id.clone());
+    }
+
+    fn on_exit(&self, id: &span::Id, _: Context<'_, S>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(position) = state.entered.iter().rposition(|entered| entered == id) {
+            state.entered.remove(position);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, _: Context<'_, S>) {
+        self.state.lock().unwrap().spans.remove(&id);
+    }
+}
+
+/// Minimal subscriber assigning ids to spans, on top of which a [`SimulationLayer`] can be
+/// used on its own.
+///
+/// [`SimulationLayer`]:`SimulationLayer`
+#[derive(Debug, Default)]
+struct Spans {
+    next_id: atomic::AtomicU64,
+}
+
+impl Subscriber for Spans {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+        span::Id::from_u64(self.next_id.fetch_add(1, atomic::Ordering::Relaxed) + 1)
+    }
+
+    fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+
+    fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+
+    fn event(&self, _: &Event<'_>) {}
+
+    fn enter(&self, _: &span::Id) {}
+
+    fn exit(&self, _: &span::Id) {}
+}
+
+/// The first line at which the output of two runs of the same seed differed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogMismatch {
+    line: usize,
+    expected: Option<String>,
+    actual: Option<String>,
+}
+
+impl LogMismatch {
+    /// Returns the position of the first differing line.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the line output by the first run, or `None` if its output ended first.
+    pub fn expected(&self) -> Option<&str> {
+        self.expected.as_ref().map(String::as_str)
+    }
+
+    /// Returns the line output by the second run, or `None` if its output ended first.
+    pub fn actual(&self) -> Option<&str> {
+        self.actual.as_ref().map(String::as_str)
+    }
+}
+
+impl fmt::Display for LogMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let describe = |line: &Option<String>| match line {
+            Some(line) => format!("{:?}", line),
+            None => String::from("end of output"),
+        };
+        write!(
+            f,
+            "log output diverged at line {}, expected {} but found {}",
+            self.line,
+            describe(&self.expected),
+            describe(&self.actual)
+        )
+    }
+}
+
+/// Runs the future returned by `run` in two simulations created from `seed`, formatting the
+/// `tracing` output of each with a [`SimulationLayer`], and returns the first line at which the
+/// outputs differ.
+///
+/// [`SimulationLayer`]:`SimulationLayer`
+pub fn check_log_determinism<F, Fut>(seed: u64, mut run: F) -> Result<(), LogMismatch>
+where
+    F: FnMut(DeterministicRuntimeHandle) -> Fut,
+    Fut: Future<Output = ()>,
+{
+    let mut record = || {
+        let mut simulation = Simulation::new(seed).expect("failed to create simulation");
+        let layer = simulation.runtime().tracing_layer();
+        let handle = simulation.handle();
+        let future = run(handle);
+        tracing::subscriber::with_default(layer.subscriber(), || simulation.run(future));
+        layer.lines()
+    };
+    let expected = record();
+    let actual = record();
+    let len = std::cmp::max(expected.len(), actual.len());
+    match (0..len).find(|line| expected.get(*line) != actual.get(*line)) {
+        Some(line) => Err(LogMismatch {
+            line,
+            expected: expected.get(line).cloned(),
+            actual: actual.get(line).cloned(),
+        }),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use rand::Rng;
+    use tracing::{info, info_span};
+    use tracing_futures::Instrument;
+
+    #[test]
+    /// Test that lines are stamped with simulated time, host, task and span, and that output
+    /// is compared across runs of the same seed.
+    fn stamped_output() {
+        let mut simulation = Simulation::new(1).unwrap();
+        let layer = simulation.runtime().tracing_layer();
+        let handle = simulation.handle();
+        tracing::subscriber::with_default(layer.subscriber(), || {
+            simulation.run(async move {
+                let server = handle.spawn_host("server");
+                let task = server.handle().clone();
+                server.handle().spawn_named(
+                    "worker",
+                    async move {
+                        task.delay_from(time::Duration::from_millis(1500)).await;
+                        info!(attempt = 2, "retrying");
+                    }
+                    .instrument(info_span!("request")),
+                );
+                handle.delay_from(time::Duration::from_secs(2)).await;
+            })
+        });
+        let lines = layer.lines();
+        let line = lines.iter().find(|line| line.contains("retrying")).unwrap();
+        assert!(line.starts_with("1.500000 "), "{}", line);
+        assert!(line.contains(" worker "), "{}", line);
+        assert!(line.contains(" request: retrying attempt=2"), "{}", line);
+
+        let run = |leak: bool| {
+            move |handle: DeterministicRuntimeHandle| async move {
+                let delay = if leak {
+                    rand::thread_rng().gen_range(1, 1_000_000)
+                } else {
+                    handle.rng().gen_range(1..1_000_000)
+                };
+                handle.delay_from(time::Duration::from_millis(delay)).await;
+                info!(delay, "woke");
+            }
+        };
+        assert_eq!(check_log_determinism(3, run(false)), Ok(()));
+        let mismatch = check_log_determinism(3, run(true)).unwrap_err();
+        assert!(mismatch.expected().unwrap().contains("woke"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Bounds on the simulated and real time taken by a run.
+//!
+//! A bug which stops simulated time from advancing, such as tasks which keep waking each other
+//! without ever waiting on a timer, leaves a run spinning forever and the CI job running it
+//! hung. An armed [`Watchdog`] is checked after every poll, and fails the run with a profile of
+//! the tasks once either the simulated time or the real time elapsed since it was armed exceeds
+//! its bound. A poll which never returns cannot be interrupted from the executor thread, so a
+//! separate thread aborts the process if the run outlives its real time bound by
+//! [`STUCK_POLL_GRACE`].
+//!
+//! [`Watchdog`]:`Watchdog`
+//! [`STUCK_POLL_GRACE`]:`STUCK_POLL_GRACE`
+use std::{process, sync, thread, time};
+
+/// Real time a run may exceed its real time bound by before the process is aborted.
+const STUCK_POLL_GRACE: time::Duration = time::Duration::from_secs(5);
+
+#[derive(Debug)]
+struct Limits {
+    started: time::Instant,
+    simulated: time::Duration,
+    real_started: time::Instant,
+    real: time::Duration,
+}
+
+/// Bounds checked by the tasks of a runtime after every poll.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Watchdog {
+    limits: sync::Arc<sync::Mutex<Option<Limits>>>,
+}
+
+impl Watchdog {
+    /// Bounds the run to `simulated` time from the simulated instant `now`, and to `real` time
+    /// from now. The bounds apply until the returned guard is dropped. `description` prefixes
+    /// the message printed if the process is aborted.
+    pub(crate) fn arm(
+        &self,
+        now: time::Instant,
+        simulated: time::Duration,
+        real: time::Duration,
+        description: String,
+    ) -> Armed {
+        self.limits.lock().unwrap().replace(Limits {
+            started: now,
+            simulated,
+            real_started: time::Instant::now(),
+            real,
+        });
+        let (finished, wait) = sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            if let Err(sync::mpsc::RecvTimeoutError::Timeout) =
+                wait.recv_timeout(real + STUCK_POLL_GRACE)
+            {
+                eprintln!(
+                    "{}: a poll has not returned {:?} after the real time budget of {:?} \
+                     elapsed, aborting",
+                    description, STUCK_POLL_GRACE, real
+                );
+                process::abort();
+            }
+        });
+        Armed {
+            watchdog: self.clone(),
+            _finished: finished,
+        }
+    }
+
+    fn disarm(&self) {
+        self.limits.lock().unwrap().take();
+    }
+
+    /// Returns a description of the exceeded bound if the run has exceeded either bound,
+    /// disarming the watchdog so that it fires once.
+    pub(crate) fn check(&self, now: time::Instant) -> Option<String> {
+        let mut lock = self.limits.lock().unwrap();
+        let exceeded = {
+            let limits = lock.as_ref()?;
+            let simulated = now - limits.started;
+            let real = limits.real_started.elapsed();
+            if simulated > limits.simulated {
+                format!(
+                    "simulated time budget of {:?} exceeded after {:?}, {:?} in real time",
+                    limits.simulated, simulated, real
+                )
+            } else if real > limits.real {
+                format!(
+                    "real time budget of {:?} exceeded after {:?}, {:?} in simulated time",
+                    limits.real, real, simulated
+                )
+            } else {
+                return None;
+            }
+        };
+        lock.take();
+        Some(exceeded)
+    }
+}
+
+/// Keeps a watchdog armed, disarming it and stopping its abort thread once dropped.
+#[derive(Debug)]
+pub(crate) struct Armed {
+    watchdog: Watchdog,
+    _finished: sync::mpsc::Sender<()>,
+}
+
+impl Drop for Armed {
+    fn drop(&mut self) {
+        self.watchdog.disarm();
+    }
+}
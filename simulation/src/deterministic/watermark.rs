@@ -0,0 +1,236 @@
+//! High-watermark monitoring of simulated buffers.
+//!
+//! Flow control bugs rarely fail a test outright, instead a queue grows without bound until the
+//! process runs out of memory in production. Once a [`HighWatermark`] is configured, the runtime
+//! tracks the bytes buffered in each direction of every connection and the messages queued on
+//! each instrumented channel, emitting a [`SimulationEvent::HighWatermark`] when a buffer stays
+//! above the watermark for the configured duration of virtual time. Tests can match these
+//! events with [`Breakpoint::high_watermark`] to assert that queues stayed bounded.
+//!
+//! [`HighWatermark`]:`HighWatermark`
+//! [`SimulationEvent::HighWatermark`]:`super::SimulationEvent::HighWatermark`
+//! [`Breakpoint::high_watermark`]:`super::Breakpoint::high_watermark`
+use super::events::{Breakpoint, SimulationEvent};
+use super::DeterministicTimeHandle;
+use std::{cmp, collections, fmt, net, sync, time};
+use tracing::trace;
+
+/// A buffer monitored for high-watermark violations.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Buffer {
+    /// Bytes written by the connection half bound to `from` which have not yet been read by
+    /// its peer bound to `to`.
+    Connection {
+        from: net::SocketAddr,
+        to: net::SocketAddr,
+    },
+    /// Messages sent on the named channel which have not yet been received.
+    Channel(String),
+}
+
+impl fmt::Display for Buffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Buffer::Connection { from, to } => write!(f, "connection {} -> {}", from, to),
+            Buffer::Channel(name) => write!(f, "channel {}", name),
+        }
+    }
+}
+
+/// Threshold a buffer must not exceed for longer than a duration of virtual time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HighWatermark {
+    level: usize,
+    sustained: time::Duration,
+}
+
+impl HighWatermark {
+    /// Creates a watermark violated once a buffer holds more than `level` bytes, or messages for
+    /// channels, for at least `sustained`.
+    pub fn new(level: usize, sustained: time::Duration) -> Self {
+        Self { level, sustained }
+    }
+
+    pub fn level(&self) -> usize {
+        self.level
+    }
+
+    pub fn sustained(&self) -> time::Duration {
+        self.sustained
+    }
+}
+
+/// A period during which a buffer has been above its watermark.
+#[derive(Debug)]
+struct Episode {
+    id: u64,
+    since: time::Instant,
+    peak: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    connection: Option<HighWatermark>,
+    channel: Option<HighWatermark>,
+    episodes: collections::HashMap<Buffer, Episode>,
+    next_episode: u64,
+}
+
+/// Shared by connections and channels to report the level of their buffers.
+#[derive(Debug, Clone)]
+pub(crate) struct Watermarks {
+    handle: DeterministicTimeHandle,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl Watermarks {
+    pub(crate) fn new(handle: DeterministicTimeHandle) -> Self {
+        Self {
+            handle,
+            state: sync::Arc::default(),
+        }
+    }
+
+    pub(crate) fn set_connection(&self, watermark: Option<HighWatermark>) {
+        self.state.lock().unwrap().connection = watermark;
+    }
+
+    pub(crate) fn set_channel(&self, watermark: Option<HighWatermark>) {
+        self.state.lock().unwrap().channel = watermark;
+    }
+
+    /// Records the current level of a buffer, starting an episode once it rises above its
+    /// watermark and ending the episode once it falls back.
+    pub(crate) fn record(&self, buffer: Buffer, level: usize) {
+        let mut state = self.state.lock().unwrap();
+        let watermark = match buffer {
+            Buffer::Connection { .. } => state.connection,
+            Buffer::Channel(_) => state.channel,
+        };
+        let watermark = match watermark {
+            Some(watermark) if level > watermark.level => watermark,
+            _ => {
+                state.episodes.remove(&buffer);
+                return;
+            }
+        };
+        if let Some(episode) = state.episodes.get_mut(&buffer) {
+            episode.peak = cmp::max(episode.peak, level);
+            return;
+        }
+        let id = state.next_episode;
+        state.next_episode += 1;
+        let since = self.handle.now();
+        trace!("{} rose above its watermark with {}", buffer, level);
+        state.episodes.insert(
+            buffer.clone(),
+            Episode {
+                id,
+                since,
+                peak: level,
+            },
+        );
+        drop(state);
+        if watermark.sustained == time::Duration::from_secs(0) {
+            self.report(&buffer, id);
+        } else {
+            let watermarks = self.clone();
+            self.handle
+                .events()
+                .add_breakpoint(Breakpoint::at(since + watermark.sustained), move |_| {
+                    watermarks.report(&buffer, id)
+                });
+        }
+    }
+
+    /// Stops tracking a buffer which will no longer be drained.
+    pub(crate) fn clear(&self, buffer: &Buffer) {
+        self.state.lock().unwrap().episodes.remove(buffer);
+    }
+
+    /// Emits a high-watermark event if the episode is still ongoing.
+    fn report(&self, buffer: &Buffer, id: u64) {
+        let event = match self.state.lock().unwrap().episodes.get(buffer) {
+            Some(episode) if episode.id == id => SimulationEvent::HighWatermark {
+                buffer: buffer.clone(),
+                peak: episode.peak,
+                since: episode.since,
+            },
+            _ => return,
+        };
+        self.handle.events().emit(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::{Environment, TcpListener};
+    use futures::{channel::oneshot, StreamExt};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[test]
+    /// Test that buffers which stay above their watermark emit an event once the sustained
+    /// duration has elapsed, while brief bursts do not.
+    fn high_watermarks() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime
+            .set_connection_watermark(Some(HighWatermark::new(100, time::Duration::from_secs(1))));
+        runtime.set_channel_watermark(Some(HighWatermark::new(2, time::Duration::from_secs(0))));
+        let events = sync::Arc::new(sync::Mutex::new(vec![]));
+        let recorded = sync::Arc::clone(&events);
+        runtime.add_breakpoint(Breakpoint::high_watermark(), move |event| {
+            recorded.lock().unwrap().push(event.clone());
+        });
+        let handle = runtime.localhost_handle();
+        let (start, client_addr) = runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let mut listener = handle.bind(addr).await.unwrap();
+            let server = handle.clone();
+            let (done_tx, done_rx) = oneshot::channel();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                server.delay_from(time::Duration::from_millis(500)).await;
+                socket.read_exact(&mut [0u8; 200]).await.unwrap();
+                server.delay_from(time::Duration::from_secs(5)).await;
+                socket.read_exact(&mut [0u8; 1000]).await.unwrap();
+                let _ = done_tx.send(());
+            });
+            let start = handle.now();
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.write_all(&[0u8; 200]).await.unwrap();
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            socket.write_all(&[0u8; 1000]).await.unwrap();
+            done_rx.await.unwrap();
+
+            let (mut tx, mut rx) = handle.channel("pipeline", 8);
+            for i in 0..3 {
+                tx.send(i).await.unwrap();
+            }
+            for _ in 0..3 {
+                rx.next().await.unwrap();
+            }
+            (start, crate::TcpStream::local_addr(&socket).unwrap())
+        });
+        let events = events.lock().unwrap();
+        assert_eq!(
+            *events,
+            vec![
+                SimulationEvent::HighWatermark {
+                    buffer: Buffer::Connection {
+                        from: client_addr,
+                        to: "127.0.0.1:9092".parse().unwrap(),
+                    },
+                    peak: 1000,
+                    since: start + time::Duration::from_secs(1),
+                },
+                SimulationEvent::HighWatermark {
+                    buffer: Buffer::Channel(String::from("pipeline")),
+                    peak: 3,
+                    since: start + time::Duration::from_millis(5500),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Modeled thread pools for blocking work.
+//!
+//! Services offload blocking calls such as synchronous file IO or compression to a pool of
+//! threads, which would pull real threads and real time into a simulation. Work spawned with
+//! [`DeterministicRuntimeHandle::spawn_blocking`] instead runs inline on the executor thread,
+//! so it observes the simulation deterministically, and its result is delivered once the
+//! modeled pool of its host would have completed it.
+//!
+//! A [`BlockingPool`] assigns a host a number of workers and the virtual time each call
+//! occupies a worker for. Once every worker is busy, further calls queue behind the earliest
+//! finishing worker, so bursts of blocking work delay each other as they would on a saturated
+//! pool. Hosts without a pool complete blocking work immediately.
+//!
+//! [`DeterministicRuntimeHandle::spawn_blocking`]:`super::DeterministicRuntimeHandle::spawn_blocking`
+//! [`BlockingPool`]:`BlockingPool`
+use std::{collections, net, sync, time};
+
+/// The blocking pool of a host, see the [module documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockingPool {
+    workers: usize,
+    delay: time::Duration,
+}
+
+impl Default for BlockingPool {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            delay: time::Duration::from_millis(0),
+        }
+    }
+}
+
+impl BlockingPool {
+    /// Creates a pool with a single worker, completing calls immediately.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the number of calls the pool runs in parallel.
+    pub fn workers(mut self, workers: usize) -> Self {
+        assert!(workers > 0, "blocking pools must have at least one worker");
+        self.workers = workers;
+        self
+    }
+
+    /// Sets the virtual time each call occupies a worker for.
+    pub fn delay(mut self, delay: time::Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+#[derive(Debug)]
+struct HostPool {
+    pool: BlockingPool,
+    /// Time at which each worker finishes the calls queued on it.
+    workers: Vec<time::Instant>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct WorkerPools {
+    hosts: sync::Arc<sync::Mutex<collections::HashMap<net::IpAddr, HostPool>>>,
+}
+
+impl WorkerPools {
+    /// Replaces the blocking pool of the host. Calls already queued on the previous pool keep
+    /// their completion times.
+    pub(crate) fn set(&self, host: net::IpAddr, pool: Option<BlockingPool>, now: time::Instant) {
+        let mut hosts = self.hosts.lock().unwrap();
+        match pool {
+            Some(pool) => {
+                let host = hosts.entry(host).or_insert_with(|| HostPool {
+                    pool,
+                    workers: vec![],
+                });
+                host.pool = pool;
+                host.workers.sort();
+                host.workers.resize(pool.workers, now);
+            }
+            None => {
+                hosts.remove(&host);
+            }
+        }
+    }
+
+    /// Queues a call on the pool of the host, returning the time at which it completes.
+    pub(crate) fn schedule(&self, host: net::IpAddr, now: time::Instant) -> time::Instant {
+        let mut hosts = self.hosts.lock().unwrap();
+        let host = match hosts.get_mut(&host) {
+            Some(host) => host,
+            None => return now,
+        };
+        let delay = host.pool.delay;
+        let worker = host
+            .workers
+            .iter_mut()
+            .min()
+            .expect("blocking pools have at least one worker");
+        let finish = std::cmp::max(*worker, now) + delay;
+        *worker = finish;
+        finish
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+
+    #[test]
+    /// Test that blocking calls run inline, and that calls beyond the workers of the pool queue
+    /// behind the earliest finishing worker.
+    fn queued_calls() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let delay = time::Duration::from_millis(100);
+        handle.set_blocking_pool(Some(BlockingPool::new().workers(2).delay(delay)));
+        let finished = sync::Arc::new(sync::Mutex::new(vec![]));
+        runtime.block_on(async {
+            let start = handle.now();
+            for n in 0..3 {
+                let handle = handle.clone();
+                let finished = sync::Arc::clone(&finished);
+                handle.clone().spawn(async move {
+                    let result = handle.spawn_blocking(move || n * 2).await;
+                    finished
+                        .lock()
+                        .unwrap()
+                        .push((result, handle.now() - start));
+                });
+            }
+            let other = handle.add_host();
+            assert_eq!(other.spawn_blocking(|| 1).await, 1);
+            assert_eq!(other.now(), start);
+            handle.delay_from(time::Duration::from_secs(1)).await;
+        });
+        let mut finished = finished.lock().unwrap().clone();
+        finished.sort();
+        assert_eq!(
+            finished,
+            vec![(0, delay), (2, delay), (4, delay * 2)],
+            "expected the third call to wait for a worker"
+        );
+    }
+}
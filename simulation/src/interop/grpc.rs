@@ -0,0 +1,135 @@
+//! Transport helpers for gRPC services built with tonic.
+//!
+//! tonic servers are served from a stream of accepted connections, which [`incoming`] builds
+//! from a simulated listener for `Server::serve_with_incoming`, or `serve_from_stream` in
+//! older releases of tonic. tonic clients connect through any `tower_service::Service<Uri>`,
+//! so a [`Connector`] can be handed to `Endpoint::connect_with_connector`. Clients which skip
+//! tonic's endpoint entirely can use [`connect`], which establishes an HTTP/2 connection and
+//! sets the origin of each request as tonic's channel would.
+//!
+//! [`incoming`]:`incoming`
+//! [`Connector`]:`Connector`
+//! [`connect`]:`connect`
+use super::hyper::{HttpConnector, Incoming};
+use crate::{Environment, TcpListener};
+use ::hyper::{body::Payload, client::conn::SendRequest};
+use futures::Poll;
+use http::{Request, Uri};
+use std::{error, task::Context};
+use tower_service::Service;
+
+/// Connects gRPC clients to the host and port of their endpoint URI.
+pub type Connector<E> = HttpConnector<E>;
+
+/// Returns the connections accepted by the listener, for serving a tonic server with
+/// `Server::serve_with_incoming`.
+pub fn incoming<L>(listener: L) -> Incoming<L::Stream>
+where
+    L: TcpListener,
+{
+    Incoming::new(listener)
+}
+
+/// Establishes an HTTP/2 connection to `origin`, returning a service which sends requests to
+/// it with the scheme and authority of `origin`, as expected by generated tonic clients.
+pub async fn connect<E, B>(
+    env: E,
+    origin: Uri,
+) -> Result<AddOrigin<SendRequest<B>>, Box<dyn error::Error + Send + Sync>>
+where
+    E: Environment + Sync,
+    B: Payload + Unpin + 'static,
+    B::Data: Unpin,
+{
+    let builder = ::hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .clone();
+    let mut connect = ::hyper::client::service::Connect::new(Connector::new(env), builder);
+    let send_request = connect.call(origin.clone()).await?;
+    Ok(AddOrigin::new(send_request, origin))
+}
+
+/// Sets the scheme and authority of each request to those of an origin, as tonic's channel
+/// does for the relative URIs of generated clients.
+#[derive(Debug)]
+pub struct AddOrigin<T> {
+    inner: T,
+    origin: Uri,
+}
+
+impl<T> AddOrigin<T> {
+    pub fn new(inner: T, origin: Uri) -> Self {
+        Self { inner, origin }
+    }
+}
+
+impl<T, ReqBody> Service<Request<ReqBody>> for AddOrigin<T>
+where
+    T: Service<Request<ReqBody>>,
+{
+    type Response = T::Response;
+    type Error = T::Error;
+    type Future = T::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request<ReqBody>) -> Self::Future {
+        let (mut head, body) = request.into_parts();
+        let mut uri: http::uri::Parts = head.uri.into();
+        let origin = self.origin.clone().into_parts();
+        uri.scheme = Some(origin.scheme.expect("origin has a scheme"));
+        uri.authority = Some(origin.authority.expect("origin has an authority"));
+        head.uri = Uri::from_parts(uri).expect("valid uri");
+        self.inner.call(Request::from_parts(head, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use ::hyper::{service, Body, Response, Server};
+    use futures::TryStreamExt;
+    use std::net;
+
+    #[test]
+    /// Test that a client connected with `connect` reaches an HTTP/2 server serving the
+    /// connections of `incoming`, with the origin set on its relative request URIs.
+    fn http2_request() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:50051".parse().unwrap();
+            let listener = handle.bind(addr).await.unwrap();
+            let make_service = service::make_service_fn(|_| async {
+                Ok::<_, ::hyper::Error>(service::service_fn(|request: Request<Body>| async move {
+                    let body = request.uri().to_string();
+                    Ok::<_, ::hyper::Error>(Response::new(Body::from(body)))
+                }))
+            });
+            let serve = Server::builder(incoming(listener))
+                .http2_only(true)
+                .serve(make_service);
+            handle.spawn(async move {
+                let _ = serve.await;
+            });
+
+            let origin = Uri::from_static("http://127.0.0.1:50051");
+            let mut client = connect::<_, Body>(handle.clone(), origin).await.unwrap();
+            futures::future::poll_fn(|cx| client.poll_ready(cx))
+                .await
+                .unwrap();
+            let request = Request::post("/helloworld.Greeter/SayHello")
+                .body(Body::empty())
+                .unwrap();
+            let response = client.call(request).await.unwrap();
+            let body = response.into_body().try_concat().await.unwrap();
+            assert_eq!(
+                &body[..],
+                &b"http://127.0.0.1:50051/helloworld.Greeter/SayHello"[..]
+            );
+        });
+    }
+}
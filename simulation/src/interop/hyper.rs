@@ -0,0 +1,209 @@
+//! Connectors and acceptors for hyper clients and servers.
+//!
+//! An [`HttpConnector`] opens connections to the authority of a request URI through an
+//! [`Environment`], as a hyper connector or any `tower_service::Service<Uri>` connector. An
+//! [`Incoming`] turns a listener into the stream of accepted connections a hyper server is
+//! built from. Hosts of URIs must be IP addresses unless the connector is given a resolver,
+//! such as one backed by [`DeterministicRuntimeHandle::resolve_host`].
+//!
+//! [`HttpConnector`]:`HttpConnector`
+//! [`Environment`]:`crate::Environment`
+//! [`Incoming`]:`Incoming`
+//! [`DeterministicRuntimeHandle::resolve_host`]:`crate::deterministic::DeterministicRuntimeHandle::resolve_host`
+use crate::{Environment, TcpListener};
+use ::hyper::client::connect::{Connect, Connected, Destination};
+use futures::{Future, Poll, Stream};
+use http::Uri;
+use std::{fmt, io, net, pin::Pin, sync, task::Context};
+
+type ConnectFuture<T> = Pin<Box<dyn Future<Output = io::Result<T>> + Send + 'static>>;
+type Resolver = sync::Arc<dyn Fn(&str) -> Option<net::IpAddr> + Send + Sync>;
+
+/// Connects to the host and port of URIs through an environment.
+#[derive(Clone)]
+pub struct HttpConnector<E> {
+    env: E,
+    resolver: Option<Resolver>,
+}
+
+impl<E> fmt::Debug for HttpConnector<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "HttpConnector {{ resolver: {} }}",
+            self.resolver.is_some()
+        )
+    }
+}
+
+impl<E> HttpConnector<E>
+where
+    E: Environment + Sync,
+{
+    pub fn new(env: E) -> Self {
+        Self {
+            env,
+            resolver: None,
+        }
+    }
+
+    /// Resolves hosts which are not IP addresses with the provided function.
+    pub fn with_resolver<F>(mut self, resolver: F) -> Self
+    where
+        F: Fn(&str) -> Option<net::IpAddr> + Send + Sync + 'static,
+    {
+        self.resolver = Some(sync::Arc::new(resolver));
+        self
+    }
+
+    /// Resolves the host to an address, stripping the brackets of IPv6 literals.
+    fn resolve(&self, host: &str) -> io::Result<net::IpAddr> {
+        let literal = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = literal.parse() {
+            return Ok(ip);
+        }
+        self.resolver
+            .as_ref()
+            .and_then(|resolver| resolver(host))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("cannot resolve host {}", host),
+                )
+            })
+    }
+
+    fn connect_to(
+        &self,
+        host: Option<&str>,
+        port: Option<u16>,
+        scheme: Option<&str>,
+    ) -> ConnectFuture<E::TcpStream> {
+        let host = host
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI is missing a host"));
+        let addr = host.and_then(|host| self.resolve(host)).map(|ip| {
+            let port = port.unwrap_or(match scheme {
+                Some("https") => 443,
+                _ => 80,
+            });
+            net::SocketAddr::new(ip, port)
+        });
+        let env = self.env.clone();
+        Box::pin(async move { env.connect(addr?).await })
+    }
+}
+
+impl<E> tower_service::Service<Uri> for HttpConnector<E>
+where
+    E: Environment + Sync,
+{
+    type Response = E::TcpStream;
+    type Error = io::Error;
+    type Future = ConnectFuture<Self::Response>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        self.connect_to(uri.host(), uri.port_u16(), uri.scheme_str())
+    }
+}
+
+impl<E> Connect for HttpConnector<E>
+where
+    E: Environment + Sync,
+{
+    type Transport = E::TcpStream;
+    type Error = io::Error;
+    type Future = ConnectFuture<(Self::Transport, Connected)>;
+
+    fn connect(&self, dst: Destination) -> Self::Future {
+        let connect = self.connect_to(Some(dst.host()), dst.port(), Some(dst.scheme()));
+        Box::pin(async move { Ok((connect.await?, Connected::new())) })
+    }
+}
+
+/// The connections accepted by a listener, for serving with hyper or tonic.
+pub struct Incoming<S> {
+    stream: Pin<Box<dyn Stream<Item = io::Result<S>> + Send>>,
+}
+
+impl<S> fmt::Debug for Incoming<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Incoming")
+    }
+}
+
+impl<S> Incoming<S> {
+    pub fn new<L>(listener: L) -> Self
+    where
+        L: TcpListener<Stream = S>,
+    {
+        Self {
+            stream: listener.into_stream(),
+        }
+    }
+}
+
+impl<S> Stream for Incoming<S> {
+    type Item = io::Result<S>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+impl<S> ::hyper::server::accept::Accept for Incoming<S> {
+    type Conn = S;
+    type Error = io::Error;
+
+    fn poll_accept(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        self.poll_next(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use ::hyper::{service, Body, Client, Request, Response, Server};
+    use futures::TryStreamExt;
+
+    #[test]
+    /// Test that a hyper server accepting from a simulated listener answers a hyper client
+    /// which connects through the simulated network by host name.
+    fn request_response() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        let server = handle.spawn_host("server");
+        runtime.block_on(async {
+            let addr = net::SocketAddr::new(server.addr(), 80);
+            let listener = server.handle().bind(addr).await.unwrap();
+            let make_service = service::make_service_fn(|_| async {
+                Ok::<_, ::hyper::Error>(service::service_fn(|request: Request<Body>| async move {
+                    let body = format!("hello {}", request.uri().path());
+                    Ok::<_, ::hyper::Error>(Response::new(Body::from(body)))
+                }))
+            });
+            let serve = Server::builder(Incoming::new(listener)).serve(make_service);
+            server.spawn(async move {
+                let _ = serve.await;
+            });
+
+            let resolver = handle.clone();
+            let connector = HttpConnector::new(handle.clone())
+                .with_resolver(move |host| resolver.resolve_host(host));
+            let client = Client::builder().build::<_, Body>(connector);
+            let response = client
+                .get("http://server/world".parse().unwrap())
+                .await
+                .unwrap();
+            let body = response.into_body().try_concat().await.unwrap();
+            assert_eq!(&body[..], b"hello /world");
+        });
+    }
+}
@@ -0,0 +1,9 @@
+//! Adapters for running services built on hyper and tonic over the simulated network.
+//!
+//! Enabled by the `interop` feature. The adapters are generic over [`Environment`], so the
+//! same service code can be served over real sockets in production and over the simulated
+//! network, with its faults, under test.
+//!
+//! [`Environment`]:`crate::Environment`
+pub mod grpc;
+pub mod hyper;
@@ -157,11 +157,22 @@
 //! [Timeout]:[tokio_timer::Timeout]
 use async_trait::async_trait;
 use futures::{Future, FutureExt, Stream};
-use std::{io, net, fmt, error, pin::Pin, time};
+use std::{error, fmt, io, net, pin::Pin};
 use tokio::io::{AsyncRead, AsyncWrite};
 
+pub mod calendar;
+pub mod compat;
 pub mod deterministic;
+#[cfg(feature = "interop")]
+pub mod interop;
+pub mod pool;
+pub mod rate_limit;
 pub mod singlethread;
+pub mod sync;
+pub mod test_harness;
+pub mod time;
+
+pub use deterministic::Simulation;
 
 #[derive(Debug)]
 pub enum Error {
@@ -187,13 +198,13 @@ impl fmt::Display for Error {
 }
 
 impl error::Error for Error {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> { 
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
         match self {
             Error::Spawn { source } => Some(source),
             Error::RuntimeBuild { source } => Some(source),
             Error::CurrentThreadRun { source } => Some(source),
         }
-     }
+    }
 }
 
 #[async_trait]
@@ -219,6 +230,7 @@ pub trait Network {
 pub trait Environment: Unpin + Sized + Clone + Send + 'static {
     type TcpStream: TcpStream + Send + 'static + Unpin;
     type TcpListener: TcpListener + Send + 'static + Unpin;
+    type UdpSocket: UdpSocket + Send + 'static + Unpin;
 
     /// Spawn a task on the runtime provided by this [`Environment`].
     fn spawn<F>(&self, future: F)
@@ -226,6 +238,8 @@ pub trait Environment: Unpin + Sized + Clone + Send + 'static {
         F: Future<Output = ()> + Send + 'static;
     /// Return the time now according to the executor.
     fn now(&self) -> time::Instant;
+    /// Return the wall clock time now according to the executor.
+    fn system_time(&self) -> time::SystemTime;
     /// Returns a delay future which completes after the provided instant.
     fn delay(&self, deadline: time::Instant) -> tokio_timer::Delay;
     /// Returns a delay future which completes at some time from now.
@@ -248,6 +262,39 @@ pub trait Environment: Unpin + Sized + Clone + Send + 'static {
     async fn connect<A>(&self, addr: A) -> io::Result<Self::TcpStream>
     where
         A: Into<net::SocketAddr> + Send + Sync;
+
+    /// Binds a [`UdpSocket`] to the provided address, which can be used to send and receive
+    /// datagrams.
+    ///
+    /// [`UdpSocket`]:`UdpSocket`
+    async fn bind_udp<A>(&self, addr: A) -> io::Result<Self::UdpSocket>
+    where
+        A: Into<net::SocketAddr> + Send + Sync;
+
+    /// Runs a closure which blocks, such as synchronous IO, off of the tasks of the runtime and
+    /// returns its result.
+    async fn spawn_blocking<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static;
+}
+
+/// A runtime which can drive futures to completion, providing handles implementing
+/// [`Environment`]. Code written against `Runtime` can be run under the
+/// [`DeterministicRuntime`] for simulation, and against reality as a smoke check with the
+/// [`RealRuntime`].
+///
+/// [`Environment`]:`Environment`
+/// [`DeterministicRuntime`]:`deterministic::DeterministicRuntime`
+/// [`RealRuntime`]:`singlethread::RealRuntime`
+pub trait Runtime {
+    type Handle: Environment;
+    /// Returns a handle scoped to the local host.
+    fn localhost_handle(&self) -> Self::Handle;
+    /// Runs the provided future to completion, along with any tasks it spawns.
+    fn block_on<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future;
 }
 
 pub trait TcpStream: AsyncRead + AsyncWrite + Unpin + Send + 'static {
@@ -265,6 +312,15 @@ pub trait TcpListener {
     fn into_stream(self) -> Pin<Box<dyn Stream<Item = Result<Self::Stream, io::Error>> + Send>>;
 }
 
+#[async_trait]
+pub trait UdpSocket {
+    fn local_addr(&self) -> io::Result<net::SocketAddr>;
+    /// Sends a datagram to the provided address, returning the number of bytes sent.
+    async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize>;
+    /// Receives a datagram, returning the number of bytes read and the source address.
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)>;
+}
+
 pub fn spawn_with_result<F, E, U>(env: &E, future: F) -> impl Future<Output = U>
 where
     F: Future<Output = U> + Send + 'static,
@@ -0,0 +1,518 @@
+//! A bounded connection pool which can be driven by any [`Environment`].
+//!
+//! Client libraries commonly pool connections, and pools tend to fail in the same ways under
+//! faults: handing out connections the peer has already closed, never reaping idle connections,
+//! or leaking capacity when a connection is dropped mid-request. [`Pool`] provides a reference
+//! implementation which can be used directly by code under test, along with [`PoolStats`] for
+//! asserting on pool behavior once a simulation has completed.
+//!
+//! Idle connections are reaped based on [`Environment::now`], so under the
+//! [`DeterministicRuntime`] idle timeouts elapse on virtual time.
+//!
+//! [`Environment`]:`crate::Environment`
+//! [`Environment::now`]:`crate::Environment::now`
+//! [`DeterministicRuntime`]:`crate::deterministic::DeterministicRuntime`
+use crate::Environment;
+use futures::{channel::oneshot, task::noop_waker_ref, Poll};
+use std::{
+    collections::VecDeque,
+    fmt, io, net,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    sync,
+    task::Context,
+    time,
+};
+use tokio::io::AsyncRead;
+use tracing::trace;
+
+#[derive(Debug, Clone)]
+pub struct PoolOptions {
+    max_size: usize,
+    idle_timeout: Option<time::Duration>,
+    health_check: bool,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            max_size: 8,
+            idle_timeout: None,
+            health_check: true,
+        }
+    }
+}
+
+impl PoolOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Caps the number of connections, both idle and checked out, held by the pool. Checkouts
+    /// wait for a connection to be returned once the cap is reached.
+    pub fn max_size(mut self, max_size: usize) -> Self {
+        assert!(max_size > 0, "pool size must be greater than zero");
+        self.max_size = max_size;
+        self
+    }
+
+    /// Closes connections which have been idle for longer than the provided timeout.
+    pub fn idle_timeout(mut self, timeout: time::Duration) -> Self {
+        self.idle_timeout.replace(timeout);
+        self
+    }
+
+    /// Enables checking idle connections before handing them out. A connection is unhealthy if
+    /// it has been closed by the peer, has failed, or has unread data buffered. Enabled by
+    /// default.
+    pub fn health_check(mut self, enabled: bool) -> Self {
+        self.health_check = enabled;
+        self
+    }
+}
+
+/// Counters describing the behavior of a [`Pool`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    created: usize,
+    reused: usize,
+    connect_failures: usize,
+    unhealthy: usize,
+    reaped: usize,
+    discarded: usize,
+    idle: usize,
+    checked_out: usize,
+    waiting: usize,
+}
+
+impl PoolStats {
+    /// Number of connections established by the pool.
+    pub fn created(&self) -> usize {
+        self.created
+    }
+    /// Number of checkouts satisfied by an idle connection.
+    pub fn reused(&self) -> usize {
+        self.reused
+    }
+    /// Number of failed attempts to establish a connection.
+    pub fn connect_failures(&self) -> usize {
+        self.connect_failures
+    }
+    /// Number of idle connections closed after failing a health check.
+    pub fn unhealthy(&self) -> usize {
+        self.unhealthy
+    }
+    /// Number of idle connections closed after exceeding the idle timeout.
+    pub fn reaped(&self) -> usize {
+        self.reaped
+    }
+    /// Number of checked out connections which were discarded rather than returned.
+    pub fn discarded(&self) -> usize {
+        self.discarded
+    }
+    /// Number of connections currently idle.
+    pub fn idle(&self) -> usize {
+        self.idle
+    }
+    /// Number of connections currently checked out.
+    pub fn checked_out(&self) -> usize {
+        self.checked_out
+    }
+    /// Number of checkouts waiting for capacity.
+    pub fn waiting(&self) -> usize {
+        self.waiting
+    }
+}
+
+impl fmt::Display for PoolStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "created: {}, reused: {}, connect failures: {}, unhealthy: {}, reaped: {}, \
+             discarded: {}, idle: {}, checked out: {}, waiting: {}",
+            self.created,
+            self.reused,
+            self.connect_failures,
+            self.unhealthy,
+            self.reaped,
+            self.discarded,
+            self.idle,
+            self.checked_out,
+            self.waiting
+        )
+    }
+}
+
+struct Idle<S> {
+    stream: S,
+    since: time::Instant,
+}
+
+/// Pool state. The environment is held behind the pool's lock so that pools can be shared
+/// between tasks regardless of whether the environment is `Sync`.
+struct State<E: Environment> {
+    env: E,
+    idle: VecDeque<Idle<E::TcpStream>>,
+    /// Number of connections checked out or being established.
+    checked_out: usize,
+    waiters: VecDeque<oneshot::Sender<()>>,
+    stats: PoolStats,
+}
+
+impl<E: Environment> State<E> {
+    fn size(&self) -> usize {
+        self.idle.len() + self.checked_out
+    }
+
+    /// Wakes the next checkout waiting for capacity.
+    fn notify_waiter(&mut self) {
+        while let Some(waiter) = self.waiters.pop_front() {
+            if waiter.send(()).is_ok() {
+                return;
+            }
+        }
+    }
+
+    /// Closes idle connections which have exceeded the idle timeout.
+    fn reap(&mut self, addr: net::SocketAddr, timeout: Option<time::Duration>) {
+        let timeout = match timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let now = self.env.now();
+        let before = self.idle.len();
+        self.idle.retain(|idle| now - idle.since < timeout);
+        let reaped = before - self.idle.len();
+        if reaped > 0 {
+            trace!("reaped {} idle connections to {}", reaped, addr);
+            self.stats.reaped += reaped;
+            for _ in 0..reaped {
+                self.notify_waiter();
+            }
+        }
+    }
+}
+
+struct Shared<E: Environment> {
+    addr: net::SocketAddr,
+    options: PoolOptions,
+    state: sync::Mutex<State<E>>,
+}
+
+impl<E: Environment> Shared<E> {
+    fn reap(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.reap(self.addr, self.options.idle_timeout);
+    }
+
+    fn release(&self, stream: Option<E::TcpStream>) {
+        let mut state = self.state.lock().unwrap();
+        state.checked_out -= 1;
+        match stream {
+            Some(stream) => {
+                let since = state.env.now();
+                state.idle.push_back(Idle { stream, since })
+            }
+            None => state.stats.discarded += 1,
+        }
+        state.notify_waiter();
+    }
+}
+
+/// Returns true if the stream has not been closed, has not failed and has no unread data.
+fn is_healthy<S: AsyncRead + Unpin>(stream: &mut S) -> bool {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut buf = [0u8; 1];
+    match Pin::new(stream).poll_read(&mut cx, &mut buf) {
+        Poll::Pending => true,
+        Poll::Ready(_) => false,
+    }
+}
+
+/// A bounded pool of connections to a single address.
+pub struct Pool<E: Environment> {
+    shared: sync::Arc<Shared<E>>,
+}
+
+impl<E: Environment> Clone for Pool<E> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: sync::Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<E: Environment> Pool<E> {
+    pub fn new(env: E, addr: net::SocketAddr, options: PoolOptions) -> Self {
+        let state = State {
+            env,
+            idle: VecDeque::new(),
+            checked_out: 0,
+            waiters: VecDeque::new(),
+            stats: PoolStats::default(),
+        };
+        let shared = Shared {
+            addr,
+            options,
+            state: sync::Mutex::new(state),
+        };
+        Self {
+            shared: sync::Arc::new(shared),
+        }
+    }
+
+    /// Checks out a connection, reusing the most recently returned healthy idle connection or
+    /// establishing a new one. Waits for a connection to be returned if the pool is at capacity.
+    pub async fn get(&self) -> io::Result<PooledConnection<E>> {
+        loop {
+            let waiter = {
+                let mut state = self.shared.state.lock().unwrap();
+                state.reap(self.shared.addr, self.shared.options.idle_timeout);
+                while let Some(mut idle) = state.idle.pop_back() {
+                    if self.shared.options.health_check && !is_healthy(&mut idle.stream) {
+                        trace!("closing unhealthy connection to {}", self.shared.addr);
+                        state.stats.unhealthy += 1;
+                        continue;
+                    }
+                    state.checked_out += 1;
+                    state.stats.reused += 1;
+                    return Ok(PooledConnection::new(idle.stream, &self.shared));
+                }
+                if state.size() < self.shared.options.max_size {
+                    state.checked_out += 1;
+                    None
+                } else {
+                    let (tx, rx) = oneshot::channel();
+                    state.waiters.push_back(tx);
+                    Some(rx)
+                }
+            };
+            match waiter {
+                Some(waiter) => {
+                    let _ = waiter.await;
+                }
+                None => return self.connect().await,
+            }
+        }
+    }
+
+    /// Establishes a new connection, the capacity for which has already been reserved.
+    async fn connect(&self) -> io::Result<PooledConnection<E>> {
+        let env = self.shared.state.lock().unwrap().env.clone();
+        let result = env.connect(self.shared.addr).await;
+        let mut state = self.shared.state.lock().unwrap();
+        match result {
+            Ok(stream) => {
+                state.stats.created += 1;
+                Ok(PooledConnection::new(stream, &self.shared))
+            }
+            Err(e) => {
+                state.checked_out -= 1;
+                state.stats.connect_failures += 1;
+                state.notify_waiter();
+                Err(e)
+            }
+        }
+    }
+
+    /// Closes idle connections which have exceeded the idle timeout.
+    pub fn reap(&self) {
+        self.shared.reap();
+    }
+
+    /// Returns a future which periodically reaps idle connections. The reaper runs until all
+    /// handles to the pool have been dropped. If no idle timeout is configured, the future
+    /// completes immediately.
+    pub fn reaper(&self) -> impl futures::Future<Output = ()> + Send + 'static {
+        let shared = sync::Arc::downgrade(&self.shared);
+        let timeout = self.shared.options.idle_timeout;
+        let env = self.shared.state.lock().unwrap().env.clone();
+        async move {
+            let timeout = match timeout {
+                Some(timeout) => timeout,
+                None => return,
+            };
+            loop {
+                env.delay_from(timeout).await;
+                match shared.upgrade() {
+                    Some(shared) => shared.reap(),
+                    None => return,
+                }
+            }
+        }
+    }
+
+    /// Returns the current pool statistics.
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.state.lock().unwrap();
+        let mut stats = state.stats.clone();
+        stats.idle = state.idle.len();
+        stats.checked_out = state.checked_out;
+        stats.waiting = state.waiters.iter().filter(|w| !w.is_canceled()).count();
+        stats
+    }
+
+    /// Panics if the pool holds more connections than its configured maximum.
+    pub fn assert_bounded(&self) {
+        let state = self.shared.state.lock().unwrap();
+        assert!(
+            state.size() <= self.shared.options.max_size,
+            "pool to {} holds {} connections, exceeding the maximum of {}",
+            self.shared.addr,
+            state.size(),
+            self.shared.options.max_size
+        );
+    }
+}
+
+/// A connection checked out from a [`Pool`]. The connection is returned to the pool when
+/// dropped, unless it has been discarded.
+pub struct PooledConnection<E: Environment> {
+    stream: Option<E::TcpStream>,
+    shared: sync::Arc<Shared<E>>,
+    discard: bool,
+}
+
+impl<E: Environment> PooledConnection<E> {
+    fn new(stream: E::TcpStream, shared: &sync::Arc<Shared<E>>) -> Self {
+        Self {
+            stream: Some(stream),
+            shared: sync::Arc::clone(shared),
+            discard: false,
+        }
+    }
+
+    /// Marks the connection as broken, closing it rather than returning it to the pool.
+    pub fn discard(mut self) {
+        self.discard = true;
+    }
+}
+
+impl<E: Environment> Deref for PooledConnection<E> {
+    type Target = E::TcpStream;
+    fn deref(&self) -> &Self::Target {
+        self.stream.as_ref().unwrap()
+    }
+}
+
+impl<E: Environment> DerefMut for PooledConnection<E> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.stream.as_mut().unwrap()
+    }
+}
+
+impl<E: Environment> Drop for PooledConnection<E> {
+    fn drop(&mut self) {
+        let stream = self.stream.take().filter(|_| !self.discard);
+        self.shared.release(stream);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{deterministic::DeterministicRuntime, TcpListener};
+    use futures::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        StreamExt,
+    };
+
+    /// Accepts connections, echoing each byte received. Connections are closed after `limit`
+    /// bytes.
+    async fn echo<E: Environment>(env: E, listener: E::TcpListener, limit: usize) {
+        use crate::compat::TokioAsyncReadCompatExt;
+        let mut incoming = listener.into_stream();
+        while let Some(Ok(socket)) = incoming.next().await {
+            env.spawn(async move {
+                let mut socket = socket.compat();
+                let mut buf = [0u8; 1];
+                for _ in 0..limit {
+                    if socket.read_exact(&mut buf).await.is_err() {
+                        return;
+                    }
+                    if socket.write_all(&buf).await.is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    async fn ping<E: Environment>(conn: &mut PooledConnection<E>) -> io::Result<()> {
+        use crate::compat::TokioAsyncReadCompatExt;
+        let mut stream = (&mut **conn).compat();
+        stream.write_all(b"x").await?;
+        let mut buf = [0u8; 1];
+        stream.read_exact(&mut buf).await
+    }
+
+    #[test]
+    /// Test that the pool reuses connections and waits for capacity once at its maximum size.
+    fn bounded_reuse() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let listener = handle.bind(addr).await.unwrap();
+            handle.spawn(echo(handle.clone(), listener, usize::max_value()));
+            let pool = Pool::new(handle.clone(), addr, PoolOptions::new().max_size(2));
+
+            let mut a = pool.get().await.unwrap();
+            let mut b = pool.get().await.unwrap();
+            ping(&mut a).await.unwrap();
+            ping(&mut b).await.unwrap();
+
+            let waiting = pool.clone();
+            let third = crate::spawn_with_result(&handle, async move {
+                let mut conn = waiting.get().await.unwrap();
+                ping(&mut conn).await.unwrap();
+            });
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            assert_eq!(pool.stats().waiting(), 1);
+            pool.assert_bounded();
+
+            drop(a);
+            third.await;
+            drop(b);
+            let stats = pool.stats();
+            assert_eq!(stats.created(), 2);
+            assert_eq!(stats.reused(), 1);
+            assert_eq!(stats.idle(), 2);
+            assert_eq!(stats.checked_out(), 0);
+        });
+    }
+
+    #[test]
+    /// Test that connections closed by the peer are not handed out, and that idle connections are
+    /// reaped after the idle timeout elapses on virtual time.
+    fn health_check_and_reaping() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let addr: net::SocketAddr = "127.0.0.1:9092".parse().unwrap();
+            let listener = handle.bind(addr).await.unwrap();
+            handle.spawn(echo(handle.clone(), listener, 1));
+            let options = PoolOptions::new()
+                .max_size(1)
+                .idle_timeout(time::Duration::from_secs(30));
+            let pool = Pool::new(handle.clone(), addr, options);
+
+            let mut conn = pool.get().await.unwrap();
+            ping(&mut conn).await.unwrap();
+            drop(conn);
+            // The server has closed the connection after the first byte.
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            let mut conn = pool.get().await.unwrap();
+            ping(&mut conn).await.unwrap();
+            drop(conn);
+            assert_eq!(pool.stats().unhealthy(), 1);
+            assert_eq!(pool.stats().created(), 2);
+
+            handle.spawn(pool.reaper());
+            handle.delay_from(time::Duration::from_secs(31)).await;
+            let stats = pool.stats();
+            assert_eq!(stats.reaped(), 1);
+            assert_eq!(stats.idle(), 0);
+        });
+    }
+}
@@ -0,0 +1,159 @@
+//! A token bucket rate limiter which can be driven by any [`Environment`].
+//!
+//! Admission control logic, such as API quotas and client side throttles, is difficult to test
+//! against real time as exercising it requires waiting for the bucket to refill. [`RateLimiter`]
+//! refills based on [`Environment::now`] and waits with [`Environment::delay`], so under the
+//! [`DeterministicRuntime`] a throttled client waits on virtual time and tests complete
+//! instantly.
+//!
+//! The limiter is implemented with the generic cell rate algorithm, which behaves like a token
+//! bucket holding up to `burst` tokens refilled at a constant rate, or equivalently a leaky
+//! bucket of capacity `burst`. Waiting acquisitions are admitted in the order they were made.
+//!
+//! [`Environment`]:`crate::Environment`
+//! [`Environment::now`]:`crate::Environment::now`
+//! [`Environment::delay`]:`crate::Environment::delay`
+//! [`RateLimiter`]:`RateLimiter`
+//! [`DeterministicRuntime`]:`crate::deterministic::DeterministicRuntime`
+use crate::Environment;
+use std::{cmp, sync, time};
+
+#[derive(Debug)]
+struct State {
+    /// Theoretical arrival time of the next token. The bucket is full once this is in the past.
+    tat: Option<time::Instant>,
+    admitted: u64,
+    rejected: u64,
+}
+
+/// Token bucket rate limiter. Clones share the same bucket.
+#[derive(Debug, Clone)]
+pub struct RateLimiter<E> {
+    env: E,
+    /// Time taken to refill a single token.
+    interval: time::Duration,
+    burst: u32,
+    state: sync::Arc<sync::Mutex<State>>,
+}
+
+impl<E> RateLimiter<E>
+where
+    E: Environment,
+{
+    /// Creates a limiter admitting `rate` tokens every `period`, with a full bucket of `burst`
+    /// tokens.
+    pub fn new(env: E, rate: u32, period: time::Duration, burst: u32) -> Self {
+        assert!(rate > 0, "rate must be greater than zero");
+        assert!(burst > 0, "burst must be greater than zero");
+        Self {
+            env,
+            interval: period / rate,
+            burst,
+            state: sync::Arc::new(sync::Mutex::new(State {
+                tat: None,
+                admitted: 0,
+                rejected: 0,
+            })),
+        }
+    }
+
+    /// Returns the deadline at which `tokens` acquired now would be admitted, and the
+    /// theoretical arrival time once they have been.
+    fn schedule(&self, state: &State, tokens: u32) -> (time::Instant, time::Instant) {
+        let now = self.env.now();
+        let tat = cmp::max(state.tat.unwrap_or(now), now) + self.interval * tokens;
+        let tolerance = self.interval * self.burst;
+        let admit_at = if tat > now + tolerance {
+            tat - tolerance
+        } else {
+            now
+        };
+        (admit_at, tat)
+    }
+
+    /// Acquires `tokens` if they are available now, returning false otherwise.
+    pub fn try_acquire(&self, tokens: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let (admit_at, tat) = self.schedule(&state, tokens);
+        if admit_at > self.env.now() {
+            state.rejected += 1;
+            return false;
+        }
+        state.tat.replace(tat);
+        state.admitted += 1;
+        true
+    }
+
+    /// Acquires `tokens`, waiting until they are available. Tokens are reserved when this is
+    /// called, so a later acquisition never overtakes an earlier one. Acquiring more tokens than
+    /// the burst waits for the bucket to go into debt rather than failing.
+    pub async fn acquire(&self, tokens: u32) {
+        let admit_at = {
+            let mut state = self.state.lock().unwrap();
+            let (admit_at, tat) = self.schedule(&state, tokens);
+            state.tat.replace(tat);
+            state.admitted += 1;
+            admit_at
+        };
+        if admit_at > self.env.now() {
+            self.env.delay(admit_at).await;
+        }
+    }
+
+    /// Returns the number of tokens which could be acquired now without waiting.
+    pub fn available(&self) -> u32 {
+        let state = self.state.lock().unwrap();
+        let now = self.env.now();
+        let used = match state.tat {
+            Some(tat) if tat > now => tat - now,
+            _ => return self.burst,
+        };
+        let remaining = (self.interval * self.burst).checked_sub(used);
+        remaining.map_or(0, |remaining| {
+            (remaining.as_nanos() / cmp::max(self.interval.as_nanos(), 1)) as u32
+        })
+    }
+
+    /// Returns the number of acquisitions admitted, including acquisitions still waiting.
+    pub fn admitted(&self) -> u64 {
+        self.state.lock().unwrap().admitted
+    }
+
+    /// Returns the number of `try_acquire` calls which were rejected.
+    pub fn rejected(&self) -> u64 {
+        self.state.lock().unwrap().rejected
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+
+    #[test]
+    /// Test that bursts are admitted immediately, and that further acquisitions are paced at
+    /// the refill rate on virtual time.
+    fn token_bucket() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let limiter = RateLimiter::new(handle.clone(), 10, time::Duration::from_secs(1), 5);
+            let start = handle.now();
+            assert_eq!(limiter.available(), 5);
+            for _ in 0..5 {
+                assert!(limiter.try_acquire(1));
+            }
+            assert!(!limiter.try_acquire(1));
+            assert_eq!(limiter.available(), 0);
+
+            limiter.acquire(1).await;
+            assert_eq!(handle.now() - start, time::Duration::from_millis(100));
+            limiter.acquire(3).await;
+            assert_eq!(handle.now() - start, time::Duration::from_millis(400));
+
+            handle.delay_from(time::Duration::from_secs(10)).await;
+            assert_eq!(limiter.available(), 5);
+            assert_eq!((limiter.admitted(), limiter.rejected()), (7, 1));
+        });
+    }
+}
@@ -1,11 +1,20 @@
 use crate::Error;
 use async_trait::async_trait;
-use futures::Future;
-use std::{io, net::SocketAddr, time};
+use futures::{channel::oneshot, Future};
+use std::{io, net::SocketAddr, thread, time};
 use tokio_executor::current_thread;
 use tokio_net::driver::Reactor;
 use tokio_timer::{clock::Clock, timer};
 mod net;
+
+/// Runtime backed by Tokio, OS sockets and the real clock, exposing the same handle APIs as the
+/// `DeterministicRuntime`. Application code written against [`Environment`] runs unchanged on
+/// either, binding and connecting TCP and UDP sockets on the host's network interfaces.
+///
+/// [`Environment`]:`crate::Environment`
+pub type RealRuntime = SingleThreadedRuntime;
+pub type RealRuntimeHandle = SingleThreadedRuntimeHandle;
+
 #[derive(Debug, Clone)]
 pub struct SingleThreadedRuntimeHandle {
     executor_handle: current_thread::Handle,
@@ -17,6 +26,7 @@ pub struct SingleThreadedRuntimeHandle {
 impl crate::Environment for SingleThreadedRuntimeHandle {
     type TcpStream = tokio::net::TcpStream;
     type TcpListener = tokio::net::TcpListener;
+    type UdpSocket = tokio::net::UdpSocket;
     fn spawn<F>(&self, future: F)
     where
         F: Future<Output = ()> + Send + 'static,
@@ -28,6 +38,9 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
     fn now(&self) -> time::Instant {
         self.clock_handle.now()
     }
+    fn system_time(&self) -> time::SystemTime {
+        time::SystemTime::now()
+    }
     fn delay(&self, deadline: time::Instant) -> tokio::timer::Delay {
         self.timer_handle.delay(deadline)
     }
@@ -46,6 +59,23 @@ impl crate::Environment for SingleThreadedRuntimeHandle {
     {
         tokio::net::TcpStream::connect(addr.into()).await
     }
+    async fn bind_udp<A>(&self, addr: A) -> Result<Self::UdpSocket, io::Error>
+    where
+        A: Into<SocketAddr> + Send + Sync,
+    {
+        tokio::net::UdpSocket::bind(addr.into()).await
+    }
+    async fn spawn_blocking<F, T>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        thread::spawn(move || {
+            let _ = tx.send(f());
+        });
+        rx.await.expect("blocking closure panicked")
+    }
 }
 
 pub struct SingleThreadedRuntime {
@@ -102,6 +132,12 @@ impl SingleThreadedRuntime {
         self.enter(|executor| executor.block_on(f))
     }
 
+    /// Returns a handle to the runtime. Handles of a real runtime are not scoped to an
+    /// address, and bind and connect using the host's network interfaces.
+    pub fn localhost_handle(&self) -> SingleThreadedRuntimeHandle {
+        self.handle()
+    }
+
     fn enter<F, R>(&mut self, f: F) -> R
     where
         F: FnOnce(&mut current_thread::CurrentThread<timer::Timer<Reactor>>) -> R,
@@ -121,3 +157,89 @@ impl SingleThreadedRuntime {
         })
     }
 }
+
+impl crate::Runtime for SingleThreadedRuntime {
+    type Handle = SingleThreadedRuntimeHandle;
+    fn localhost_handle(&self) -> Self::Handle {
+        self.handle()
+    }
+    fn block_on<F>(&mut self, future: F) -> F::Output
+    where
+        F: Future,
+    {
+        SingleThreadedRuntime::block_on(self, future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        deterministic::DeterministicRuntime, Environment, Runtime, TcpListener, UdpSocket,
+    };
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    /// Runs a client against an echo server on the provided runtime, returning the reply.
+    fn echo<R: Runtime>(mut runtime: R, addr: SocketAddr) -> Vec<u8> {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async move {
+            let mut listener = handle.bind(addr).await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            handle.spawn(async move {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4];
+                socket.read_exact(&mut buf).await.unwrap();
+                socket.write_all(&buf).await.unwrap();
+            });
+            handle.delay_from(time::Duration::from_millis(10)).await;
+            let mut socket = handle.connect(addr).await.unwrap();
+            socket.write_all(b"ping").await.unwrap();
+            let mut buf = vec![0u8; 4];
+            socket.read_exact(&mut buf).await.unwrap();
+            buf
+        })
+    }
+
+    #[test]
+    /// Test that the same code runs against both the deterministic and the real runtime.
+    fn simulated_and_real() {
+        let simulated = echo(
+            DeterministicRuntime::new().unwrap(),
+            "127.0.0.1:9092".parse().unwrap(),
+        );
+        let real = echo(RealRuntime::new().unwrap(), "127.0.0.1:0".parse().unwrap());
+        assert_eq!(simulated, b"ping");
+        assert_eq!(real, simulated);
+    }
+
+    /// Sends a datagram between two sockets bound on the provided runtime, returning what was
+    /// received.
+    fn datagram<R: Runtime>(mut runtime: R, addr: SocketAddr, sender_addr: SocketAddr) -> Vec<u8> {
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async move {
+            let mut receiver = handle.bind_udp(addr).await.unwrap();
+            let receiver_addr = receiver.local_addr().unwrap();
+            let mut sender = handle.bind_udp(sender_addr).await.unwrap();
+            sender.send_to(b"ping", receiver_addr).await.unwrap();
+            let mut buf = vec![0u8; 16];
+            let (len, source) = receiver.recv_from(&mut buf).await.unwrap();
+            assert_eq!(source, sender.local_addr().unwrap());
+            buf.truncate(len);
+            buf
+        })
+    }
+
+    #[test]
+    /// Test that datagrams are exchanged through the same code on both runtimes.
+    fn simulated_and_real_datagrams() {
+        let simulated = datagram(
+            DeterministicRuntime::new().unwrap(),
+            "127.0.0.1:9093".parse().unwrap(),
+            "127.0.0.1:9094".parse().unwrap(),
+        );
+        let any: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let real = datagram(RealRuntime::new().unwrap(), any, any);
+        assert_eq!(simulated, b"ping");
+        assert_eq!(real, simulated);
+    }
+}
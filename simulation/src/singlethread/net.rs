@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use futures::Stream;
 use std::{io, net, pin::Pin};
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
 
 impl crate::TcpStream for TcpStream {
     fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
@@ -31,3 +31,16 @@ impl crate::TcpListener for TcpListener {
         Box::pin(self.incoming())
     }
 }
+
+#[async_trait]
+impl crate::UdpSocket for UdpSocket {
+    fn local_addr(&self) -> Result<net::SocketAddr, io::Error> {
+        tokio::net::UdpSocket::local_addr(self)
+    }
+    async fn send_to(&mut self, buf: &[u8], target: net::SocketAddr) -> io::Result<usize> {
+        tokio::net::UdpSocket::send_to(self, buf, &target).await
+    }
+    async fn recv_from(&mut self, buf: &mut [u8]) -> io::Result<(usize, net::SocketAddr)> {
+        tokio::net::UdpSocket::recv_from(self, buf).await
+    }
+}
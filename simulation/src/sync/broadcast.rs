@@ -0,0 +1,295 @@
+//! A bounded multi-producer, multi-consumer channel delivering every message to every
+//! receiver.
+//!
+//! Sending never waits. Each receiver sees the messages sent after it subscribed, and a
+//! receiver which falls more than `capacity` messages behind skips the oldest messages. When a
+//! message is sent, the receivers waiting for it are woken in the order chosen by the
+//! scheduler.
+use super::Waiters;
+use futures::{Poll, Stream};
+use std::{collections::VecDeque, error, fmt, pin::Pin, sync, task::Context};
+
+/// Error returned when sending on a channel without receivers, carrying the message which
+/// could not be sent.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel has no receivers")
+    }
+}
+
+impl<T> error::Error for SendError<T> {}
+
+/// Error returned by [`Receiver::recv`].
+///
+/// [`Receiver::recv`]:`Receiver::recv`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvError {
+    /// Every sender has been dropped, and every message has been received.
+    Closed,
+    /// The receiver fell behind, skipping the provided number of messages.
+    Lagged(u64),
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecvError::Closed => write!(f, "channel closed"),
+            RecvError::Lagged(skipped) => write!(f, "receiver lagged by {} messages", skipped),
+        }
+    }
+}
+
+impl error::Error for RecvError {}
+
+struct Shared<T> {
+    /// Buffered messages, the first of which has sequence number `head`.
+    buffer: VecDeque<T>,
+    head: u64,
+    capacity: usize,
+    senders: usize,
+    receivers: usize,
+    /// Receivers waiting for the next message.
+    waiting: Waiters,
+}
+
+impl<T> Shared<T> {
+    fn tail(&self) -> u64 {
+        self.head + self.buffer.len() as u64
+    }
+}
+
+/// Creates a channel buffering up to `capacity` messages for its slowest receiver.
+pub fn channel<T: Clone>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be greater than zero");
+    let shared = sync::Arc::new(sync::Mutex::new(Shared {
+        buffer: VecDeque::with_capacity(capacity),
+        head: 0,
+        capacity,
+        senders: 1,
+        receivers: 1,
+        waiting: Waiters::default(),
+    }));
+    let receiver = Receiver {
+        shared: shared.clone(),
+        next: 0,
+        waiter: None,
+    };
+    (Sender { shared }, receiver)
+}
+
+/// Sending half of a channel, which can be cloned to send from several tasks.
+pub struct Sender<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sender {{ .. }}")
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Sends a message to every receiver, returning the number of receivers it was sent to.
+    pub fn send(&self, item: T) -> Result<usize, SendError<T>> {
+        let (receivers, wakers) = {
+            let mut lock = self.shared.lock().unwrap();
+            if lock.receivers == 0 {
+                return Err(SendError(item));
+            }
+            if lock.buffer.len() == lock.capacity {
+                lock.buffer.pop_front();
+                lock.head += 1;
+            }
+            lock.buffer.push_back(item);
+            (lock.receivers, lock.waiting.take_all())
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+        Ok(receivers)
+    }
+
+    /// Creates a receiver of the messages sent from now on.
+    pub fn subscribe(&self) -> Receiver<T> {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receivers += 1;
+        Receiver {
+            shared: self.shared.clone(),
+            next: lock.tail(),
+            waiter: None,
+        }
+    }
+
+    pub fn receiver_count(&self) -> usize {
+        self.shared.lock().unwrap().receivers
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut lock = self.shared.lock().unwrap();
+            lock.senders -= 1;
+            if lock.senders > 0 {
+                return;
+            }
+            lock.waiting.take_all()
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of a channel.
+pub struct Receiver<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+    /// Sequence number of the next message to receive.
+    next: u64,
+    /// Registration of the receiver with the receivers waiting for a message.
+    waiter: Option<u64>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Receiver {{ next: {} }}", self.next)
+    }
+}
+
+impl<T> Unpin for Receiver<T> {}
+
+impl<T: Clone> Receiver<T> {
+    /// Receives the next message.
+    pub async fn recv(&mut self) -> Result<T, RecvError> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Result<T, RecvError>> {
+        let mut lock = self.shared.lock().unwrap();
+        if let Some(waiter) = self.waiter.take() {
+            lock.waiting.remove(waiter);
+        }
+        if self.next < lock.head {
+            let skipped = lock.head - self.next;
+            self.next = lock.head;
+            return Poll::Ready(Err(RecvError::Lagged(skipped)));
+        }
+        if self.next < lock.tail() {
+            let item = lock.buffer[(self.next - lock.head) as usize].clone();
+            self.next += 1;
+            return Poll::Ready(Ok(item));
+        }
+        if lock.senders == 0 {
+            return Poll::Ready(Err(RecvError::Closed));
+        }
+        lock.waiting.register(&mut self.waiter, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl<T: Clone> Stream for Receiver<T> {
+    type Item = Result<T, RecvError>;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match futures::ready!(self.poll_recv(cx)) {
+            Err(RecvError::Closed) => Poll::Ready(None),
+            result => Poll::Ready(Some(result)),
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut lock = self.shared.lock().unwrap();
+        lock.receivers -= 1;
+        if let Some(waiter) = self.waiter.take() {
+            lock.waiting.remove(waiter);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::collections::HashSet;
+
+    /// Subscribes three receivers and returns the order in which they observe a message.
+    fn observe(seed: u64, shuffle: bool) -> Vec<u32> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        runtime.set_shuffle_scheduling(shuffle);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (tx, rx) = channel(4);
+            drop(rx);
+            let (done_tx, mut done_rx) = crate::sync::mpsc::channel(3);
+            for i in 0..3 {
+                let (mut rx, mut done_tx) = (tx.subscribe(), done_tx.clone());
+                handle.spawn(async move {
+                    rx.recv().await.unwrap();
+                    done_tx.send(i).await.unwrap();
+                });
+            }
+            drop(done_tx);
+            handle.delay_from(std::time::Duration::from_secs(1)).await;
+            assert_eq!(tx.send("go"), Ok(3));
+            let mut observed = vec![];
+            while let Some(i) = done_rx.recv().await {
+                observed.push(i);
+            }
+            observed
+        })
+    }
+
+    #[test]
+    /// Test that receivers are woken in the order they started waiting, and in an order drawn
+    /// from the seed under shuffle scheduling.
+    fn wake_order() {
+        assert_eq!(observe(0, false), vec![0, 1, 2]);
+        let orders: HashSet<Vec<u32>> = (0..32).map(|seed| observe(seed, true)).collect();
+        assert!(orders.len() > 1, "{:?}", orders);
+        assert_eq!(observe(5, true), observe(5, true));
+    }
+
+    #[test]
+    /// Test that every receiver sees every message, that slow receivers are told how many
+    /// messages they skipped, and that receivers observe the channel closing.
+    fn lag_and_close() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            let (tx, mut slow) = channel(2);
+            let mut fast = tx.subscribe();
+            for i in 0..3 {
+                tx.send(i).unwrap();
+                if i < 2 {
+                    assert_eq!(fast.recv().await, Ok(i));
+                }
+            }
+            assert_eq!(slow.recv().await, Err(RecvError::Lagged(1)));
+            assert_eq!(slow.recv().await, Ok(1));
+            assert_eq!(slow.recv().await, Ok(2));
+            drop(tx);
+            assert_eq!(fast.recv().await, Ok(2));
+            assert_eq!(fast.recv().await, Err(RecvError::Closed));
+            assert_eq!(slow.recv().await, Err(RecvError::Closed));
+        });
+    }
+}
@@ -0,0 +1,85 @@
+//! Channels whose wakeups are ordered by the scheduler of the runtime.
+//!
+//! Races between tasks exchanging messages are a common source of interleaving bugs: which of
+//! several blocked senders gets the freed slot, or which subscriber observes a broadcast first.
+//! The channels of `futures::channel` settle these races according to their internals, which
+//! leaves a simulation unable to explore them. The [`mpsc`], [`oneshot`] and [`broadcast`]
+//! channels of this module wake the tasks waiting on them in the order they started waiting,
+//! and under a [`DeterministicRuntime`] with shuffle scheduling enabled in an order drawn from
+//! the seed, so each seed explores a different resolution of the races.
+//!
+//! The channels can be used with any [`Environment`], and behave like their `futures`
+//! counterparts outside of the simulation.
+//!
+//! [`mpsc`]:`mpsc`
+//! [`oneshot`]:`oneshot`
+//! [`broadcast`]:`broadcast`
+//! [`DeterministicRuntime`]:`crate::deterministic::DeterministicRuntime`
+//! [`Environment`]:`crate::Environment`
+use crate::deterministic::choose_waiter;
+use futures::task::Waker;
+
+pub mod broadcast;
+pub mod mpsc;
+pub mod oneshot;
+
+/// Tasks waiting on a channel.
+#[derive(Debug, Default)]
+struct Waiters {
+    next_id: u64,
+    /// Waiters in the order they started waiting.
+    waiting: Vec<(u64, Waker)>,
+}
+
+impl Waiters {
+    /// Registers the waker of a waiter, updating it if the waiter identified by `id` is still
+    /// waiting.
+    fn register(&mut self, id: &mut Option<u64>, waker: &Waker) {
+        if let Some(id) = *id {
+            if let Some((_, registered)) = self.waiting.iter_mut().find(|(w, _)| *w == id) {
+                if !registered.will_wake(waker) {
+                    *registered = waker.clone();
+                }
+                return;
+            }
+        }
+        let next = self.next_id;
+        self.next_id += 1;
+        self.waiting.push((next, waker.clone()));
+        id.replace(next);
+    }
+
+    /// Returns true if the waiter is still waiting rather than woken.
+    fn contains(&self, id: u64) -> bool {
+        self.waiting.iter().any(|(w, _)| *w == id)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+
+    /// Removes a waiter, returning true if it was still waiting rather than woken.
+    fn remove(&mut self, id: u64) -> bool {
+        let len = self.waiting.len();
+        self.waiting.retain(|(w, _)| *w != id);
+        self.waiting.len() != len
+    }
+
+    /// Takes one waiter to wake, chosen by the scheduler.
+    fn take_one(&mut self) -> Option<Waker> {
+        if self.waiting.is_empty() {
+            return None;
+        }
+        let index = choose_waiter(self.waiting.len());
+        Some(self.waiting.remove(index).1)
+    }
+
+    /// Takes every waiter, in the order chosen by the scheduler for waking them.
+    fn take_all(&mut self) -> Vec<Waker> {
+        let mut wakers = Vec::with_capacity(self.waiting.len());
+        while let Some(waker) = self.take_one() {
+            wakers.push(waker);
+        }
+        wakers
+    }
+}
@@ -0,0 +1,414 @@
+//! A bounded multi-producer, single-consumer channel.
+//!
+//! Senders waiting for capacity are handed freed slots one at a time, in the order chosen by
+//! the scheduler. A slot handed to a woken sender is reserved for it until it runs, and senders
+//! which have not waited queue behind the waiting senders, so a sender is never overtaken once
+//! it starts waiting. Messages of a single sender are received in the order they were sent.
+use super::Waiters;
+use futures::{task::Waker, Future, Poll, Stream};
+use std::{collections::VecDeque, error, fmt, pin::Pin, sync, task::Context};
+
+/// Error returned when sending on a channel whose receiver has been dropped, carrying the
+/// message which could not be sent.
+#[derive(Clone, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+impl<T> fmt::Debug for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SendError(..)")
+    }
+}
+
+impl<T> fmt::Display for SendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel receiver dropped")
+    }
+}
+
+impl<T> error::Error for SendError<T> {}
+
+/// Error returned by [`Sender::try_send`], carrying the message which could not be sent.
+///
+/// [`Sender::try_send`]:`Sender::try_send`
+#[derive(Clone, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// The channel is at capacity.
+    Full(T),
+    /// The receiver has been dropped.
+    Closed(T),
+}
+
+impl<T> fmt::Debug for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "Full(..)"),
+            TrySendError::Closed(_) => write!(f, "Closed(..)"),
+        }
+    }
+}
+
+impl<T> fmt::Display for TrySendError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TrySendError::Full(_) => write!(f, "channel full"),
+            TrySendError::Closed(_) => write!(f, "channel receiver dropped"),
+        }
+    }
+}
+
+impl<T> error::Error for TrySendError<T> {}
+
+struct Shared<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    closed: bool,
+    receiver: Option<Waker>,
+    /// Senders waiting for capacity.
+    waiting: Waiters,
+    /// Free slots handed to woken senders which have not sent into them yet.
+    reserved: usize,
+}
+
+impl<T> Shared<T> {
+    /// Takes a waiting sender to hand a free slot to, if any, reserving the slot for it.
+    fn take_sender(&mut self) -> Option<Waker> {
+        if self.queue.len() + self.reserved >= self.capacity {
+            return None;
+        }
+        let waker = self.waiting.take_one()?;
+        self.reserved += 1;
+        Some(waker)
+    }
+
+    /// Returns true if a sender which holds no reserved slot can send without waiting.
+    fn has_capacity(&self) -> bool {
+        self.waiting.is_empty() && self.queue.len() + self.reserved < self.capacity
+    }
+}
+
+/// Creates a channel buffering up to `capacity` messages.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be greater than zero");
+    let shared = sync::Arc::new(sync::Mutex::new(Shared {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        closed: false,
+        receiver: None,
+        waiting: Waiters::default(),
+        reserved: 0,
+    }));
+    let sender = Sender {
+        shared: shared.clone(),
+    };
+    (sender, Receiver { shared })
+}
+
+/// Sending half of a channel, which can be cloned to send from several tasks.
+pub struct Sender<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sender {{ .. }}")
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.shared.lock().unwrap().senders += 1;
+        Self {
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T> Sender<T> {
+    /// Sends a message, waiting for capacity if the channel is full.
+    pub fn send(&mut self, item: T) -> Send<'_, T> {
+        Send {
+            sender: self,
+            item: Some(item),
+            waiter: None,
+        }
+    }
+
+    /// Sends a message if the channel has capacity which is not reserved for waiting senders.
+    pub fn try_send(&mut self, item: T) -> Result<(), TrySendError<T>> {
+        let waker = {
+            let mut lock = self.shared.lock().unwrap();
+            if lock.closed {
+                return Err(TrySendError::Closed(item));
+            }
+            if !lock.has_capacity() {
+                return Err(TrySendError::Full(item));
+            }
+            lock.queue.push_back(item);
+            lock.receiver.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns true once the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared.lock().unwrap().closed
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut lock = self.shared.lock().unwrap();
+            lock.senders -= 1;
+            if lock.senders > 0 {
+                return;
+            }
+            lock.receiver.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Future returned by [`Sender::send`].
+///
+/// [`Sender::send`]:`Sender::send`
+pub struct Send<'a, T> {
+    sender: &'a mut Sender<T>,
+    item: Option<T>,
+    /// Registration of the sender with the senders waiting for capacity.
+    waiter: Option<u64>,
+}
+
+impl<'a, T> fmt::Debug for Send<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Send {{ waiter: {:?} }}", self.waiter)
+    }
+}
+
+impl<'a, T> Unpin for Send<'a, T> {}
+
+impl<'a, T> Future for Send<'a, T> {
+    type Output = Result<(), SendError<T>>;
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let item = this.item.take().expect("polled after completion");
+        let (result, waker) = {
+            let mut lock = this.sender.shared.lock().unwrap();
+            // A sender which is no longer waiting was woken and handed a reserved slot.
+            let reserved = match this.waiter {
+                Some(waiter) if !lock.waiting.contains(waiter) => {
+                    this.waiter.take();
+                    lock.reserved -= 1;
+                    true
+                }
+                _ => false,
+            };
+            if lock.closed {
+                (Poll::Ready(Err(SendError(item))), None)
+            } else if reserved || (this.waiter.is_none() && lock.has_capacity()) {
+                lock.queue.push_back(item);
+                (Poll::Ready(Ok(())), lock.receiver.take())
+            } else {
+                lock.waiting.register(&mut this.waiter, cx.waker());
+                this.item.replace(item);
+                (Poll::Pending, None)
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        result
+    }
+}
+
+impl<'a, T> Drop for Send<'a, T> {
+    fn drop(&mut self) {
+        let waiter = match self.waiter.take() {
+            Some(waiter) => waiter,
+            None => return,
+        };
+        let waker = {
+            let mut lock = self.sender.shared.lock().unwrap();
+            if lock.waiting.remove(waiter) {
+                None
+            } else {
+                // The slot handed to this sender is passed on to another.
+                lock.reserved -= 1;
+                lock.take_sender()
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of a channel.
+pub struct Receiver<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Receiver {{ .. }}")
+    }
+}
+
+impl<T> Unpin for Receiver<T> {}
+
+impl<T> Receiver<T> {
+    /// Receives the next message, returning `None` once every sender has been dropped and the
+    /// buffered messages have been received.
+    pub async fn recv(&mut self) -> Option<T> {
+        futures::future::poll_fn(|cx| self.poll_recv(cx)).await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let (result, waker) = {
+            let mut lock = self.shared.lock().unwrap();
+            match lock.queue.pop_front() {
+                Some(item) => (Poll::Ready(Some(item)), lock.take_sender()),
+                None if lock.senders == 0 => (Poll::Ready(None), None),
+                None => {
+                    lock.receiver.replace(cx.waker().clone());
+                    (Poll::Pending, None)
+                }
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        result
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_recv(cx)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let wakers = {
+            let mut lock = self.shared.lock().unwrap();
+            lock.closed = true;
+            let wakers = lock.waiting.take_all();
+            // Woken senders release their reservation once they observe the closed channel.
+            lock.reserved += wakers.len();
+            wakers
+        };
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::collections::HashSet;
+
+    /// Blocks three senders on a full channel and returns the order in which their messages
+    /// are received.
+    fn contend(seed: u64, shuffle: bool) -> Vec<u32> {
+        let mut runtime = DeterministicRuntime::new_with_seed(seed).unwrap();
+        runtime.set_shuffle_scheduling(shuffle);
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = channel(1);
+            tx.send(0).await.unwrap();
+            for i in 1..4 {
+                let mut tx = tx.clone();
+                handle.spawn(async move {
+                    tx.send(i).await.unwrap();
+                });
+            }
+            drop(tx);
+            // Let every sender block on the full channel.
+            handle.delay_from(std::time::Duration::from_secs(1)).await;
+            let mut received = vec![];
+            while let Some(i) = rx.recv().await {
+                received.push(i);
+            }
+            received
+        })
+    }
+
+    #[test]
+    /// Test that blocked senders are handed capacity in the order they started waiting, and in
+    /// an order drawn from the seed under shuffle scheduling.
+    fn sender_order() {
+        assert_eq!(contend(0, false), vec![0, 1, 2, 3]);
+        let orders: HashSet<Vec<u32>> = (0..32).map(|seed| contend(seed, true)).collect();
+        assert!(orders.len() > 1, "{:?}", orders);
+        for order in orders {
+            let mut sorted = order.clone();
+            sorted.sort();
+            assert_eq!(sorted, vec![0, 1, 2, 3]);
+        }
+        assert_eq!(contend(7, true), contend(7, true));
+    }
+
+    #[test]
+    /// Test that a slot handed to a woken sender is not taken by a sender which has not waited,
+    /// and that fresh senders queue behind the waiting ones.
+    fn reserved_slot() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = channel(1);
+            tx.send(0).await.unwrap();
+            let mut waiting = tx.clone();
+            handle.spawn(async move {
+                waiting.send(1).await.unwrap();
+            });
+            handle.delay_from(std::time::Duration::from_secs(1)).await;
+            assert_eq!(rx.recv().await, Some(0));
+            // The freed slot belongs to the woken sender until it runs.
+            assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+            let mut fresh = tx.clone();
+            handle.spawn(async move {
+                fresh.send(3).await.unwrap();
+            });
+            drop(tx);
+            let mut received = vec![];
+            while let Some(i) = rx.recv().await {
+                received.push(i);
+            }
+            assert_eq!(received, vec![1, 3]);
+        });
+    }
+
+    #[test]
+    /// Test that senders observe a dropped receiver, and that the receiver drains buffered
+    /// messages once every sender is dropped.
+    fn closed() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        runtime.block_on(async {
+            let (mut tx, mut rx) = channel(2);
+            tx.try_send(1).unwrap();
+            tx.try_send(2).unwrap();
+            assert_eq!(tx.try_send(3), Err(TrySendError::Full(3)));
+            drop(tx);
+            assert_eq!(rx.recv().await, Some(1));
+            assert_eq!(rx.recv().await, Some(2));
+            assert_eq!(rx.recv().await, None);
+
+            let (mut tx, rx) = channel(1);
+            drop(rx);
+            assert!(tx.is_closed());
+            assert_eq!(tx.send("lost").await, Err(SendError("lost")));
+        });
+    }
+}
@@ -0,0 +1,202 @@
+//! A channel sending a single message.
+use futures::{task::Waker, Future, Poll};
+use std::{error, fmt, pin::Pin, sync, task::Context};
+
+/// Error returned by a [`Receiver`] whose sender was dropped without sending.
+///
+/// [`Receiver`]:`Receiver`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "channel sender dropped")
+    }
+}
+
+impl error::Error for RecvError {}
+
+struct Shared<T> {
+    item: Option<T>,
+    /// Set once the sender has sent or been dropped.
+    complete: bool,
+    /// Set once the receiver has been dropped.
+    closed: bool,
+    receiver: Option<Waker>,
+    sender: Option<Waker>,
+}
+
+/// Creates a channel sending a single message.
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let shared = sync::Arc::new(sync::Mutex::new(Shared {
+        item: None,
+        complete: false,
+        closed: false,
+        receiver: None,
+        sender: None,
+    }));
+    let sender = Sender {
+        shared: Some(shared.clone()),
+    };
+    (sender, Receiver { shared })
+}
+
+/// Sending half of a channel.
+pub struct Sender<T> {
+    shared: Option<sync::Arc<sync::Mutex<Shared<T>>>>,
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Sender {{ .. }}")
+    }
+}
+
+impl<T> Sender<T> {
+    /// Sends the message, returning it if the receiver has been dropped.
+    pub fn send(mut self, item: T) -> Result<(), T> {
+        let shared = self.shared.take().expect("sender already completed");
+        let waker = {
+            let mut lock = shared.lock().unwrap();
+            lock.complete = true;
+            if lock.closed {
+                return Err(item);
+            }
+            lock.item.replace(item);
+            lock.receiver.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// Returns true once the receiver has been dropped.
+    pub fn is_closed(&self) -> bool {
+        self.shared
+            .as_ref()
+            .map_or(true, |shared| shared.lock().unwrap().closed)
+    }
+
+    /// Waits for the receiver to be dropped, allowing work whose result is no longer wanted to
+    /// be abandoned.
+    pub async fn closed(&mut self) {
+        futures::future::poll_fn(|cx| match self.shared.as_ref() {
+            Some(shared) => {
+                let mut lock = shared.lock().unwrap();
+                if lock.closed {
+                    Poll::Ready(())
+                } else {
+                    lock.sender.replace(cx.waker().clone());
+                    Poll::Pending
+                }
+            }
+            None => Poll::Ready(()),
+        })
+        .await
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let shared = match self.shared.take() {
+            Some(shared) => shared,
+            None => return,
+        };
+        let waker = {
+            let mut lock = shared.lock().unwrap();
+            lock.complete = true;
+            lock.receiver.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// Receiving half of a channel, completing with the message once it has been sent.
+pub struct Receiver<T> {
+    shared: sync::Arc<sync::Mutex<Shared<T>>>,
+}
+
+impl<T> fmt::Debug for Receiver<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Receiver {{ .. }}")
+    }
+}
+
+impl<T> Unpin for Receiver<T> {}
+
+impl<T> Receiver<T> {
+    /// Returns the message if it has been sent, without waiting.
+    pub fn try_recv(&mut self) -> Option<T> {
+        self.shared.lock().unwrap().item.take()
+    }
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut lock = self.shared.lock().unwrap();
+        if let Some(item) = lock.item.take() {
+            return Poll::Ready(Ok(item));
+        }
+        if lock.complete {
+            return Poll::Ready(Err(RecvError));
+        }
+        lock.receiver.replace(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let waker = {
+            let mut lock = self.shared.lock().unwrap();
+            lock.closed = true;
+            lock.sender.take()
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use crate::Environment;
+    use std::time;
+
+    #[test]
+    /// Test that the message is received once sent, that dropping the sender fails the
+    /// receiver, and that the sender observes a dropped receiver.
+    fn send_and_close() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let (tx, rx) = channel();
+            let sender = handle.clone();
+            handle.spawn(async move {
+                sender.delay_from(time::Duration::from_secs(1)).await;
+                tx.send(42).unwrap();
+            });
+            assert_eq!(rx.await, Ok(42));
+
+            let (tx, rx) = channel::<()>();
+            drop(tx);
+            assert_eq!(rx.await, Err(RecvError));
+
+            let (mut tx, rx) = channel();
+            let receiver = handle.clone();
+            handle.spawn(async move {
+                receiver.delay_from(time::Duration::from_secs(1)).await;
+                drop(rx);
+            });
+            tx.closed().await;
+            assert!(tx.is_closed());
+            assert_eq!(tx.send(1), Err(1));
+        });
+    }
+}
@@ -0,0 +1,308 @@
+//! Runs a simulation across many seeds.
+//!
+//! A single seed only explores one trajectory through a distributed algorithm. A [`Harness`]
+//! runs the same test once for each seed in a range, optionally spread over several OS threads,
+//! and collects the seeds whose runs panicked. Each run gets its own [`Simulation`], so runs do
+//! not observe each other and a failure found on any thread reproduces with its seed alone. The
+//! lowest failing seed is reported as it is the one a regression test is usually written for.
+//!
+//! A harness can also record the [`Coverage`] of each run. Seeds which exercise decision points
+//! no other seed reached are worth running longer or keeping as regression seeds, and the report
+//! orders the seeds by the decision points they add, so a limited budget can be spent on the
+//! seeds leaving the happy path rather than on seeds re-exploring it.
+//!
+//! [`Harness`]:`Harness`
+//! [`Simulation`]:`crate::Simulation`
+//! [`Coverage`]:`crate::deterministic::Coverage`
+use crate::deterministic::{Coverage, CoverageRecorder};
+use crate::Simulation;
+use std::{any, fmt, ops, panic, sync, thread};
+
+/// A failed run of the harness.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SeedFailure {
+    pub seed: u64,
+    /// Message of the panic which failed the run.
+    pub message: String,
+}
+
+/// Outcome of running a harness over a range of seeds.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HarnessReport {
+    runs: u64,
+    /// Failures ordered by seed.
+    failures: Vec<SeedFailure>,
+    /// Coverage of each run ordered by seed, if coverage was recorded.
+    coverage: Vec<(u64, Coverage)>,
+}
+
+impl HarnessReport {
+    /// Returns the number of seeds which were run.
+    pub fn runs(&self) -> u64 {
+        self.runs
+    }
+
+    pub fn failures(&self) -> &[SeedFailure] {
+        &self.failures
+    }
+
+    /// Returns the lowest seed whose run failed.
+    pub fn minimal_failing_seed(&self) -> Option<u64> {
+        self.failures.first().map(|failure| failure.seed)
+    }
+
+    /// Returns the decision points exercised by any run, if coverage was recorded.
+    pub fn coverage(&self) -> Coverage {
+        let mut coverage = Coverage::new();
+        for (_, run) in self.coverage.iter() {
+            coverage.merge(run);
+        }
+        coverage
+    }
+
+    /// Returns the coverage of the run of the provided seed, if coverage was recorded.
+    pub fn seed_coverage(&self, seed: u64) -> Option<&Coverage> {
+        self.coverage
+            .iter()
+            .find(|(run, _)| *run == seed)
+            .map(|(_, coverage)| coverage)
+    }
+
+    /// Returns the seeds which exercised decision points, ordered so that each seed adds the most
+    /// decision points not exercised by the seeds before it, along with the number it adds. Ties
+    /// are broken by the lowest seed, and seeds which add nothing are omitted.
+    pub fn prioritized_seeds(&self) -> Vec<(u64, usize)> {
+        let mut seen = Coverage::new();
+        let mut remaining: Vec<&(u64, Coverage)> = self.coverage.iter().collect();
+        let mut prioritized = vec![];
+        loop {
+            let best = remaining
+                .iter()
+                .enumerate()
+                .map(|(position, (seed, coverage))| (coverage.novel(&seen).len(), *seed, position))
+                .max_by(|a, b| a.0.cmp(&b.0).then(b.1.cmp(&a.1)));
+            match best {
+                Some((novel, seed, position)) if novel > 0 => {
+                    seen.merge(&remaining.remove(position).1);
+                    prioritized.push((seed, novel));
+                }
+                _ => return prioritized,
+            }
+        }
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failures.is_empty()
+    }
+
+    /// Panics with the report if any run failed.
+    pub fn assert_success(&self) {
+        if !self.is_success() {
+            panic!("{}", self);
+        }
+    }
+}
+
+impl fmt::Display for HarnessReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} of {} seeds failed", self.failures.len(), self.runs)?;
+        if let Some(seed) = self.minimal_failing_seed() {
+            write!(f, ", minimal failing seed {}", seed)?;
+        }
+        for failure in self.failures.iter() {
+            write!(f, "\n  seed {}: {}", failure.seed, failure.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs a simulation once for each seed of a range.
+#[derive(Debug, Clone)]
+pub struct Harness {
+    seeds: ops::Range<u64>,
+    threads: usize,
+    record_coverage: bool,
+}
+
+impl Harness {
+    /// Creates a harness running each seed of the range on the calling thread.
+    pub fn new(seeds: ops::Range<u64>) -> Self {
+        Self {
+            seeds,
+            threads: 1,
+            record_coverage: false,
+        }
+    }
+
+    /// Spreads the runs over the provided number of OS threads.
+    pub fn threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Records the decision points exercised by each run, see [`HarnessReport::coverage`].
+    ///
+    /// [`HarnessReport::coverage`]:`HarnessReport::coverage`
+    pub fn record_coverage(mut self) -> Self {
+        self.record_coverage = true;
+        self
+    }
+
+    /// Runs the test once for each seed, passing it a simulation created with the seed. A run
+    /// fails if the test panics, in which case the remaining seeds are still run.
+    pub fn run<F>(self, test: F) -> HarnessReport
+    where
+        F: Fn(Simulation) + Send + Sync + 'static,
+    {
+        let runs = self.seeds.end.saturating_sub(self.seeds.start);
+        let test = sync::Arc::new(test);
+        let seeds = sync::Arc::new(sync::Mutex::new(self.seeds));
+        let record = self.record_coverage;
+        let results = if self.threads == 1 {
+            vec![run_seeds(&seeds, &*test, record)]
+        } else {
+            let workers: Vec<_> = (0..self.threads)
+                .map(|_| {
+                    let seeds = sync::Arc::clone(&seeds);
+                    let test = sync::Arc::clone(&test);
+                    thread::spawn(move || run_seeds(&seeds, &*test, record))
+                })
+                .collect();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().expect("harness worker panicked"))
+                .collect()
+        };
+        let (mut failures, mut coverage) = (vec![], vec![]);
+        for (worker_failures, worker_coverage) in results {
+            failures.extend(worker_failures);
+            coverage.extend(worker_coverage);
+        }
+        failures.sort_by_key(|failure| failure.seed);
+        coverage.sort_by_key(|(seed, _)| *seed);
+        HarnessReport {
+            runs,
+            failures,
+            coverage,
+        }
+    }
+}
+
+/// Runs seeds taken from the shared range until it is exhausted, returning the failed runs and
+/// the coverage of each run if `record` is set.
+fn run_seeds<F>(
+    seeds: &sync::Mutex<ops::Range<u64>>,
+    test: &F,
+    record: bool,
+) -> (Vec<SeedFailure>, Vec<(u64, Coverage)>)
+where
+    F: Fn(Simulation),
+{
+    let (mut failures, mut coverage) = (vec![], vec![]);
+    loop {
+        let seed = match seeds.lock().unwrap().next() {
+            Some(seed) => seed,
+            None => return (failures, coverage),
+        };
+        let recorder = if record {
+            Some(CoverageRecorder::new())
+        } else {
+            None
+        };
+        let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            let mut simulation = Simulation::new(seed).expect("failed to create simulation");
+            if let Some(recorder) = recorder.as_ref() {
+                simulation.runtime().add_observer(recorder.clone());
+            }
+            test(simulation)
+        }));
+        if let Some(recorder) = recorder {
+            coverage.push((seed, recorder.coverage()));
+        }
+        if let Err(payload) = result {
+            failures.push(SeedFailure {
+                seed,
+                message: panic_message(&*payload),
+            });
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        String::from(*message)
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("non-string panic payload")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Environment;
+    use std::time;
+
+    /// Fails roughly one in ten seeds, after letting simulated time pass.
+    fn flaky(mut simulation: Simulation) {
+        let handle = simulation.handle();
+        simulation.run(async move {
+            handle.delay_from(time::Duration::from_secs(1)).await;
+            let roll = handle.rng().gen_range(0..10);
+            assert_ne!(roll, 0, "rolled zero");
+        })
+    }
+
+    #[test]
+    /// Test that the harness collects the failing seeds of a range, reporting the same failures
+    /// and minimal failing seed whether the seeds run on one thread or several.
+    fn collect_failures() {
+        let report = Harness::new(0..64).run(flaky);
+        assert_eq!(report.runs(), 64);
+        assert!(!report.is_success());
+        let seed = report.minimal_failing_seed().unwrap();
+        assert!(report.failures()[0].message.contains("rolled zero"));
+        assert_eq!(Harness::new(0..64).threads(4).run(flaky), report);
+        assert_eq!(Harness::new(seed..seed + 1).run(flaky).runs(), 1);
+        assert!(!Harness::new(seed..seed + 1).run(flaky).is_success());
+        assert!(Harness::new(0..seed).run(flaky).is_success());
+    }
+
+    #[test]
+    /// Test that the harness records the coverage of each seed, ordering the seeds by the
+    /// decision points they add, and that recording does not change the runs.
+    fn prioritize_coverage() {
+        let buggified = |mut simulation: Simulation| {
+            let handle = simulation.handle();
+            simulation.run(async move {
+                for site in 0..8 {
+                    handle.buggify_site(&format!("site-{}", site), 0.5);
+                }
+            })
+        };
+        let report = Harness::new(0..32).record_coverage().run(buggified);
+        assert_eq!(
+            Harness::new(0..32)
+                .threads(4)
+                .record_coverage()
+                .run(buggified),
+            report
+        );
+        assert!(Harness::new(0..32).run(buggified).coverage().is_empty());
+
+        let prioritized = report.prioritized_seeds();
+        assert!(!prioritized.is_empty());
+        let mut covered = Coverage::new();
+        for window in prioritized.windows(2) {
+            assert!(
+                window[0].1 >= window[1].1,
+                "expected seeds ordered by added points"
+            );
+        }
+        for (seed, added) in prioritized.iter() {
+            assert_eq!(covered.merge(report.seed_coverage(*seed).unwrap()), *added);
+        }
+        assert_eq!(covered, report.coverage());
+    }
+}
@@ -0,0 +1,275 @@
+//! Time utilities which run against an [`Environment`]'s clock.
+//!
+//! When used with the deterministic runtime, schedules fire according to simulated time,
+//! allowing days of periodic jobs to be tested in milliseconds.
+//!
+//! [`Environment`]:`crate::Environment`
+use crate::{calendar::DateTime, Environment};
+use futures::Future;
+pub use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{error, fmt, str};
+
+/// Upper bound on how far ahead a schedule will search for its next firing.
+const MAX_SEARCH_SECONDS: i64 = 5 * 366 * 86_400;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ScheduleError {
+    /// The spec did not contain exactly five fields.
+    FieldCount { count: usize },
+    /// A field of the spec could not be parsed.
+    InvalidField { field: &'static str, value: String },
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScheduleError::FieldCount { count } => {
+                write!(f, "expected 5 schedule fields, found {}", count)
+            }
+            ScheduleError::InvalidField { field, value } => {
+                write!(f, "invalid {} field: {:?}", field, value)
+            }
+        }
+    }
+}
+
+impl error::Error for ScheduleError {}
+
+/// Set of values matched by a single schedule field.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field(u64);
+
+impl Field {
+    fn parse(name: &'static str, value: &str, min: u32, max: u32) -> Result<Self, ScheduleError> {
+        let invalid = || ScheduleError::InvalidField {
+            field: name,
+            value: value.to_string(),
+        };
+        let parse_value = |v: &str| -> Result<u32, ScheduleError> {
+            match v.parse::<u32>() {
+                Ok(v) if v >= min && v <= max => Ok(v),
+                _ => Err(invalid()),
+            }
+        };
+        let mut bits = 0;
+        for part in value.split(',') {
+            let mut split = part.splitn(2, '/');
+            let range = split.next().unwrap_or_default();
+            let step = match split.next() {
+                Some(step) => match step.parse::<u32>() {
+                    Ok(step) if step > 0 => Some(step),
+                    _ => return Err(invalid()),
+                },
+                None => None,
+            };
+            let (start, end) = if range == "*" {
+                (min, max)
+            } else if let Some(idx) = range.find('-') {
+                (parse_value(&range[..idx])?, parse_value(&range[idx + 1..])?)
+            } else {
+                let start = parse_value(range)?;
+                (start, if step.is_some() { max } else { start })
+            };
+            if start > end {
+                return Err(invalid());
+            }
+            for v in (start..=end).step_by(step.unwrap_or(1) as usize) {
+                bits |= 1 << v;
+            }
+        }
+        Ok(Field(bits))
+    }
+
+    fn contains(self, value: u32) -> bool {
+        self.0 & (1 << value) != 0
+    }
+}
+
+/// A cron-style schedule.
+///
+/// Schedules are specified with five whitespace separated fields: minute, hour, day of month,
+/// month and day of week, where Sunday is 0 or 7. Each field accepts `*`, single values,
+/// ranges such as `1-5`, lists such as `1,15` and steps such as `*/15`. As with cron, if both
+/// the day of month and day of week are restricted, a day matching either field matches.
+/// Schedules are evaluated against UTC wall clock time.
+///
+/// ```rust
+/// use simulation::time::Schedule;
+/// // Every weekday at 03:30.
+/// let schedule: Schedule = "30 3 * * 1-5".parse().unwrap();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+    days_restricted: bool,
+    weekdays_restricted: bool,
+}
+
+impl Schedule {
+    /// Returns the first time after the provided time matched by this schedule, or `None` if
+    /// the schedule does not match within the next five years.
+    pub fn next_after(&self, after: SystemTime) -> Option<SystemTime> {
+        let start = DateTime::from_system_time(after);
+        let mut seconds = start.unix_seconds() - i64::from(start.second()) + 60;
+        let limit = seconds + MAX_SEARCH_SECONDS;
+        while seconds < limit {
+            let t = DateTime::from_unix_seconds(seconds);
+            if !self.months.contains(t.month()) {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                seconds = DateTime::new(year, month, 1, 0, 0, 0)
+                    .unwrap()
+                    .unix_seconds();
+            } else if !self.matches_day(&t) {
+                seconds += 86_400 - i64::from(t.hour() * 3600 + t.minute() * 60);
+            } else if !self.hours.contains(t.hour()) {
+                seconds += 3600 - i64::from(t.minute() * 60);
+            } else if !self.minutes.contains(t.minute()) {
+                seconds += 60;
+            } else {
+                return Some(t.to_system_time());
+            }
+        }
+        None
+    }
+
+    fn matches_day(&self, t: &DateTime) -> bool {
+        let day = self.days.contains(t.day());
+        let weekday = self.weekdays.contains(t.weekday());
+        if self.days_restricted && self.weekdays_restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// Consumes this schedule, spawning the future returned by `task` each time the schedule
+    /// fires according to the environment's wall clock. Completes if the schedule will never
+    /// fire again.
+    pub async fn run<E, F, U>(self, env: E, mut task: F)
+    where
+        E: Environment,
+        F: FnMut() -> U + Send,
+        U: Future<Output = ()> + Send + 'static,
+    {
+        loop {
+            let now = env.system_time();
+            let next = match self.next_after(now) {
+                Some(next) => next,
+                None => return,
+            };
+            env.delay_from(next.duration_since(now).unwrap_or_default())
+                .await;
+            env.spawn(task());
+        }
+    }
+}
+
+impl str::FromStr for Schedule {
+    type Err = ScheduleError;
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = spec.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(ScheduleError::FieldCount {
+                count: fields.len(),
+            });
+        }
+        let mut weekdays = Field::parse("day of week", fields[4], 0, 7)?;
+        if weekdays.contains(7) {
+            weekdays.0 |= 1;
+        }
+        Ok(Schedule {
+            minutes: Field::parse("minute", fields[0], 0, 59)?,
+            hours: Field::parse("hour", fields[1], 0, 23)?,
+            days: Field::parse("day of month", fields[2], 1, 31)?,
+            months: Field::parse("month", fields[3], 1, 12)?,
+            weekdays,
+            days_restricted: !fields[2].starts_with('*'),
+            weekdays_restricted: !fields[4].starts_with('*'),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::deterministic::DeterministicRuntime;
+    use std::sync;
+
+    fn at(year: i32, month: u32, day: u32, hour: u32, minute: u32) -> SystemTime {
+        DateTime::new(year, month, day, hour, minute, 0)
+            .unwrap()
+            .to_system_time()
+    }
+
+    #[test]
+    /// Test that schedules find the next matching time.
+    fn next_after() {
+        let schedule: Schedule = "*/15 * * * *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2020, 1, 1, 0, 7)),
+            Some(at(2020, 1, 1, 0, 15))
+        );
+        let schedule: Schedule = "30 3 * * 1-5".parse().unwrap();
+        // 2020-01-03 is a Friday, the next weekday is Monday 2020-01-06.
+        assert_eq!(
+            schedule.next_after(at(2020, 1, 3, 4, 0)),
+            Some(at(2020, 1, 6, 3, 30))
+        );
+        let schedule: Schedule = "0 0 29 2 *".parse().unwrap();
+        assert_eq!(
+            schedule.next_after(at(2020, 3, 1, 0, 0)),
+            Some(at(2024, 2, 29, 0, 0))
+        );
+        let schedule: Schedule = "0 0 31 2 *".parse().unwrap();
+        assert_eq!(schedule.next_after(at(2020, 1, 1, 0, 0)), None);
+    }
+
+    #[test]
+    /// Test that invalid specs are rejected.
+    fn invalid_specs() {
+        assert_eq!(
+            "* * * *".parse::<Schedule>(),
+            Err(ScheduleError::FieldCount { count: 4 })
+        );
+        assert!("60 * * * *".parse::<Schedule>().is_err());
+        assert!("*/0 * * * *".parse::<Schedule>().is_err());
+        assert!("* 5-1 * * *".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    /// Test that scheduled tasks fire across days of simulated time.
+    fn run_schedule() {
+        let mut runtime = DeterministicRuntime::new().unwrap();
+        let handle = runtime.localhost_handle();
+        runtime.block_on(async {
+            let fired = sync::Arc::new(sync::Mutex::new(vec![]));
+            let schedule: Schedule = "0 3 * * *".parse().unwrap();
+            let task_fired = sync::Arc::clone(&fired);
+            let task_handle = handle.clone();
+            handle.spawn(schedule.run(handle.clone(), move || {
+                let fired = sync::Arc::clone(&task_fired);
+                let handle = task_handle.clone();
+                async move {
+                    fired.lock().unwrap().push(handle.system_time());
+                }
+            }));
+            handle.delay_from(Duration::from_secs(3 * 86_400)).await;
+            assert_eq!(
+                *fired.lock().unwrap(),
+                vec![
+                    at(2020, 1, 1, 3, 0),
+                    at(2020, 1, 2, 3, 0),
+                    at(2020, 1, 3, 3, 0)
+                ]
+            );
+        });
+    }
+}